@@ -0,0 +1,165 @@
+use std::net::SocketAddr;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use rmqtt::broker::proxy_protocol::{self, Version, V1_MAX_LEN, V2_HEADER_LEN};
+use rmqtt::futures::future::LocalBoxFuture;
+use rmqtt::ntex::rt::net::TcpStream;
+use rmqtt::ntex::util::Ready;
+use rmqtt::ntex::{Service, ServiceFactory};
+use rmqtt::ntex_mqtt;
+use rmqtt::once_cell::sync::Lazy;
+use rmqtt::settings::listener::{Listener, ProxyProtocol};
+use rmqtt::socket2::{SockRef, TcpKeepalive};
+use rmqtt::tokio::{self, io::AsyncReadExt};
+use rmqtt::{log, DashMap, MqttError};
+
+//Entries of the connections closed before their handshake are ignored after this time
+const REMOTE_ADDR_TTL: Duration = Duration::from_secs(60);
+const MAX_REMOTE_ADDRS: usize = 100_000;
+
+//(load balancer addr, local port) => (client addr, received at)
+static REMOTE_ADDRS: Lazy<DashMap<(SocketAddr, u16), (SocketAddr, Instant)>> = Lazy::new(DashMap::default);
+
+///Address of the client sent in the PROXY header of the connection, or the peer address
+#[inline]
+pub fn remote_addr(peer_addr: SocketAddr, local_addr: SocketAddr) -> SocketAddr {
+    match REMOTE_ADDRS.remove(&(peer_addr, local_addr.port())) {
+        Some((_, (addr, at))) if at.elapsed() < REMOTE_ADDR_TTL => addr,
+        _ => peer_addr,
+    }
+}
+
+#[derive(Clone)]
+pub struct ProxyServer {
+    mode: ProxyProtocol,
+    timeout: Duration,
+    silent_health_probes: bool,
+    tcp_keepalive: Option<TcpKeepalive>,
+}
+
+impl ProxyServer {
+    pub fn new(listen_cfg: &Listener) -> Self {
+        ProxyServer {
+            mode: listen_cfg.proxy_protocol,
+            timeout: listen_cfg.proxy_protocol_timeout,
+            silent_health_probes: listen_cfg.silent_health_probes,
+            tcp_keepalive: listen_cfg.tcp_keepalive(),
+        }
+    }
+}
+
+impl ServiceFactory for ProxyServer {
+    type Request = TcpStream;
+    type Response = TcpStream;
+    type Error = ntex_mqtt::MqttError<MqttError>;
+    type Config = ();
+
+    type Service = ProxyServer;
+    type InitError = ();
+    type Future = Ready<Self::Service, Self::InitError>;
+
+    fn new_service(&self, _: ()) -> Self::Future {
+        Ready::Ok(self.clone())
+    }
+}
+
+impl Service for ProxyServer {
+    type Request = TcpStream;
+    type Response = TcpStream;
+    type Error = ntex_mqtt::MqttError<MqttError>;
+    type Future = LocalBoxFuture<'static, Result<TcpStream, Self::Error>>;
+
+    #[inline]
+    fn poll_ready(&self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    #[inline]
+    fn call(&self, io: TcpStream) -> Self::Future {
+        if let Some(keepalive) = self.tcp_keepalive.as_ref() {
+            if let Err(e) = SockRef::from(&io).set_tcp_keepalive(keepalive) {
+                log::warn!("failed to set the TCP keepalive, {:?}", e);
+            }
+        }
+        let this = self.clone();
+        Box::pin(async move {
+            if this.mode == ProxyProtocol::Off && !this.silent_health_probes {
+                return Ok(io);
+            }
+            match tokio::time::timeout(this.timeout, this.accept(io)).await {
+                Ok(Ok(Some(io))) => Ok(io),
+                Ok(Ok(None)) => Err(ntex_mqtt::MqttError::Service(MqttError::from("health probe"))),
+                Ok(Err(e)) => Err(ntex_mqtt::MqttError::Service(e)),
+                Err(_) => Err(ntex_mqtt::MqttError::HandshakeTimeout),
+            }
+        })
+    }
+}
+
+impl ProxyServer {
+    //None if the connection is a health probe, closed before sending any data
+    async fn accept(self, mut io: TcpStream) -> Result<Option<TcpStream>, MqttError> {
+        let peer_addr = io.peer_addr()?;
+        let local_addr = io.local_addr()?;
+        let version = match self.peek(&io).await? {
+            Some(first) => Version::detect(first).filter(|_| self.mode != ProxyProtocol::Off),
+            None => return self.probe(peer_addr),
+        };
+
+        let addr = match version {
+            Some(Version::V1) => {
+                let mut line = Vec::with_capacity(V1_MAX_LEN);
+                while !line.ends_with(b"\r\n") {
+                    if line.len() >= V1_MAX_LEN {
+                        return Err(MqttError::from("PROXY v1 header is too long"));
+                    }
+                    line.push(io.read_u8().await?);
+                }
+                proxy_protocol::parse_v1(&line)?
+            }
+            Some(Version::V2) => {
+                let mut header = [0u8; V2_HEADER_LEN];
+                io.read_exact(&mut header).await?;
+                let mut addrs = vec![0u8; proxy_protocol::v2_len(&header)?];
+                io.read_exact(&mut addrs).await?;
+                proxy_protocol::parse_v2(&header, &addrs)?
+            }
+            None if self.mode == ProxyProtocol::Required => {
+                return Err(MqttError::from(format!("PROXY header is required, peer addr: {}", peer_addr)));
+            }
+            None => return Ok(Some(io)),
+        };
+
+        //The LOCAL command of v2, the health checks of the load balancer
+        if self.silent_health_probes && self.peek(&io).await?.is_none() {
+            return self.probe(peer_addr);
+        }
+        if let Some(addr) = addr {
+            log::debug!("PROXY header, client addr: {}, peer addr: {}", addr, peer_addr);
+            if REMOTE_ADDRS.len() > MAX_REMOTE_ADDRS {
+                REMOTE_ADDRS.retain(|_, (_, at)| at.elapsed() < REMOTE_ADDR_TTL);
+            }
+            REMOTE_ADDRS.insert((peer_addr, local_addr.port()), (addr, Instant::now()));
+        }
+        Ok(Some(io))
+    }
+
+    //The first byte, none if the connection is closed
+    #[inline]
+    async fn peek(&self, io: &TcpStream) -> Result<Option<u8>, MqttError> {
+        let mut first = [0u8; 1];
+        let n = io.peek(&mut first).await?;
+        Ok(if n == 0 { None } else { Some(first[0]) })
+    }
+
+    #[inline]
+    fn probe(&self, peer_addr: SocketAddr) -> Result<Option<TcpStream>, MqttError> {
+        if self.silent_health_probes {
+            log::trace!("health probe, peer addr: {}", peer_addr);
+        } else {
+            log::debug!("connection closed before sending any data, peer addr: {}", peer_addr);
+        }
+        Ok(None)
+    }
+}
@@ -1,21 +1,34 @@
 #![deny(unsafe_code)]
 
+use std::collections::HashMap;
 use std::time::Duration;
 use std::{fs::File, io::BufReader};
 
 use rustls::internal::pemfile::{certs, rsa_private_keys};
 use rustls::{AllowAnyAuthenticatedClient, NoClientAuth, RootCertStore, ServerConfig};
 
+use rmqtt::broker::alarm::AlarmManager;
+use rmqtt::broker::health::HealthProbe;
+use rmqtt::broker::listeners::{ListenerCommand, ListenerManager};
+use rmqtt::broker::maintenance::Maintenance;
+use rmqtt::broker::origin::Origins;
+use rmqtt::broker::overload::Overload;
+use rmqtt::broker::packet_size::PacketSize;
+#[cfg(unix)]
+use rmqtt::broker::uds::{self, PeerCred, UdsPeers};
 use rmqtt::broker::{
     v3::control_message as control_message_v3, v3::handshake as handshake_v3, v3::publish as publish_v3,
     v5::control_message as control_message_v5, v5::handshake as handshake_v5, v5::publish as publish_v5,
 };
-use rmqtt::futures::{self, future::ok};
+use rmqtt::futures::future::ok;
+#[cfg(unix)]
+use rmqtt::ntex::rt::net::UnixStream;
 use rmqtt::ntex::{
     self,
     rt::net::TcpStream,
     server::rustls::Acceptor,
     server::rustls::TlsStream,
+    server::Server,
     {fn_factory_with_config, fn_service, pipeline_factory},
 };
 use rmqtt::ntex_mqtt::{
@@ -24,10 +37,14 @@ use rmqtt::ntex_mqtt::{
     v5::Handshake as HandshakeV5,
     {v3, v5, MqttServer},
 };
-use rmqtt::settings::{listener::Listener, Options, Settings};
+use rmqtt::settings::{
+    listener::{Listener, ListenerType},
+    Options, Settings,
+};
 use rmqtt::{log, structopt::StructOpt, tokio};
 use rmqtt::{logger::logger_init, runtime, MqttError, Result, Runtime, SessionState};
 
+mod proxy;
 mod ws;
 
 #[cfg(target_os = "linux")]
@@ -68,131 +85,211 @@ async fn main() {
     //hook, before startup
     Runtime::instance().extends.hook_mgr().await.before_startup().await;
 
-    //tcp
-    let mut tcp_listens = Vec::new();
-    for (_, listen_cfg) in Runtime::instance().settings.listeners.tcps.iter() {
-        let name = format!("{}/{:?}", &listen_cfg.name, &listen_cfg.addr);
-        tcp_listens.push(listen(name, listen_cfg));
-    }
+    //keepalive backstop, zombie session reaper
+    Runtime::instance().node.start_reaper();
 
-    //tls
-    let mut tls_listens = Vec::new();
-    for (_, listen_cfg) in Runtime::instance().settings.listeners.tlss.iter() {
-        let name = format!("{}/{:?}", &listen_cfg.name, &listen_cfg.addr);
-        tls_listens.push(listen_tls(name, listen_cfg));
-    }
+    //overload protection
+    Overload::instance().start();
 
-    //websocket
-    let mut ws_listens = Vec::new();
-    for (_, listen_cfg) in Runtime::instance().settings.listeners.wss.iter() {
-        let name = format!("{}/{:?}", &listen_cfg.name, &listen_cfg.addr);
-        ws_listens.push(listen_ws(name, listen_cfg));
-    }
+    //liveness and readiness probes
+    HealthProbe::instance().start();
 
-    //tls-websocket
-    let mut wss_listens = Vec::new();
-    for (_, listen_cfg) in Runtime::instance().settings.listeners.wsss.iter() {
-        let name = format!("{}/{:?}", &listen_cfg.name, &listen_cfg.addr);
-        wss_listens.push(listen_wss(name, listen_cfg));
+    //alarms
+    AlarmManager::instance().start();
+
+    //tcp, tls, websocket, tls-websocket and unix domain socket listeners
+    let mut servers = HashMap::new();
+    for (typ, listen_cfg) in Runtime::instance().settings.listeners.actives() {
+        if let Ok(server) = start_listener(typ, &listen_cfg) {
+            servers.insert((typ, listen_cfg.addr.port()), server);
+        }
+    }
+    HealthProbe::instance().set_started();
+    //the peers resend the messages held while this node was in maintenance or restarting
+    tokio::spawn(Maintenance::instance().announce());
+
+    //listeners added, modified or removed at runtime
+    let mut commands = ListenerManager::instance().commands().unwrap();
+    ListenerManager::instance().start_watch();
+
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            Some(cmd) = commands.recv() => execute_listener_command(&mut servers, cmd).await,
+            _ = &mut shutdown => {
+                //drain mode, the sessions go offline before shutdown
+                Runtime::instance().node.drain().await;
+                break;
+            }
+        }
     }
 
-    let _ = futures::future::join4(
-        futures::future::join_all(tcp_listens),
-        futures::future::join_all(tls_listens),
-        futures::future::join_all(ws_listens),
-        futures::future::join_all(wss_listens),
-    )
-    .await;
+    //hook, before shutdown
+    Runtime::instance().extends.hook_mgr().await.before_shutdown().await;
+
     tokio::time::sleep(Duration::from_secs(1)).await;
 }
 
-async fn listen(name: String, listen_cfg: &Listener) -> Result<()> {
-    async fn _listen(name: &str, listen_cfg: &Listener) -> Result<()> {
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => {
+                tokio::select! {
+                    _ = term.recv() => log::info!("SIGTERM received"),
+                    _ = tokio::signal::ctrl_c() => log::info!("SIGINT received"),
+                }
+            }
+            Err(e) => {
+                log::warn!("SIGTERM handler install failed, {:?}", e);
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        log::info!("Ctrl-C received");
+    }
+}
+
+fn start_listener(typ: ListenerType, listen_cfg: &Listener) -> Result<Server> {
+    let name = format!("{}/{:?}", &listen_cfg.name, &listen_cfg.addr);
+    let res = match typ {
+        ListenerType::Tcp => listen(name, listen_cfg),
+        ListenerType::Tls => listen_tls(name, listen_cfg),
+        ListenerType::Ws => listen_ws(name, listen_cfg),
+        ListenerType::Wss => listen_wss(name, listen_cfg),
+        ListenerType::Uds => listen_uds(name, listen_cfg),
+    };
+    HealthProbe::instance().listener_bound(
+        typ,
+        listen_cfg.addr.port(),
+        res.as_ref().err().map(|e| e.to_string()),
+    );
+    res
+}
+
+async fn execute_listener_command(servers: &mut HashMap<(ListenerType, u16), Server>, cmd: ListenerCommand) {
+    match cmd {
+        ListenerCommand::Start(typ, listen_cfg, reply) => {
+            let port = listen_cfg.addr.port();
+            let res = start_listener(typ, &listen_cfg).map(|server| {
+                servers.insert((typ, port), server);
+            });
+            let _ = reply.send(res);
+        }
+        ListenerCommand::Stop(typ, port, reply) => {
+            let res = match servers.remove(&(typ, port)) {
+                Some(server) => {
+                    server.stop(true).await;
+                    HealthProbe::instance().listener_stopped(typ, port);
+                    Ok(())
+                }
+                None => {
+                    Err(MqttError::from(format!("{} listener is not running, port: {}", typ.as_str(), port)))
+                }
+            };
+            let _ = reply.send(res);
+        }
+    }
+}
+
+fn listen(name: String, listen_cfg: &Listener) -> Result<Server> {
+    fn _listen(name: &str, listen_cfg: &Listener) -> Result<Server> {
         let max_inflight = listen_cfg.max_inflight.get() as usize;
         let handshake_timeout = listen_cfg.handshake_timeout();
-        let max_size = listen_cfg.max_packet_size.as_u32();
-        ntex::server::Server::build()
+        let max_size = PacketSize::codec_limit(listen_cfg);
+        let proxy = proxy::ProxyServer::new(listen_cfg);
+        let server = Server::build()
             .bind(name, listen_cfg.addr, move || {
-                MqttServer::new()
-                    .v3(v3::MqttServer::new(move |mut handshake: HandshakeV3<TcpStream>| async {
-                        let remote_addr = handshake.io().peer_addr()?;
-                        let local_addr = handshake.io().local_addr()?;
-                        let listen_cfg =
-                            Runtime::instance().settings.listeners.tcp(local_addr.port()).ok_or_else(
-                                || {
-                                    log::error!(
-                                        "tcp listener config is not found, local addr is {:?}",
-                                        local_addr
-                                    );
-                                    MqttError::ListenerConfigError
-                                },
-                            )?;
-                        handshake_v3(listen_cfg, handshake, remote_addr, local_addr).await
-                    })
-                    // .v3(v3::MqttServer::new(handshake_v3)
-                    .inflight(max_inflight)
-                    .handshake_timeout(handshake_timeout)
-                    .max_size(max_size)
-                    .publish(fn_factory_with_config(|session: v3::Session<SessionState>| {
-                        ok::<_, MqttError>(fn_service(move |req| publish_v3(session.clone(), req)))
-                    }))
-                    .control(fn_factory_with_config(
-                        |session: v3::Session<SessionState>| {
-                            ok::<_, MqttError>(fn_service(move |req| {
-                                control_message_v3(session.clone(), req)
-                            }))
-                        },
-                    )))
-                    .v5(v5::MqttServer::new(move |mut handshake: HandshakeV5<TcpStream>| async {
-                        let peer_addr = handshake.io().peer_addr()?;
-                        let local_addr = handshake.io().local_addr()?;
-                        let listen_cfg =
-                            Runtime::instance().settings.listeners.tcp(local_addr.port()).ok_or_else(
-                                || {
-                                    log::error!(
-                                        "tcp listener config is not found, local addr is {:?}",
-                                        local_addr
-                                    );
-                                    MqttError::ListenerConfigError
-                                },
-                            )?;
-                        handshake_v5(listen_cfg, handshake, peer_addr, local_addr).await
-                    })
-                    //v5::MqttServer::new(handshake_v5)
-                    .receive_max(max_inflight as u16)
-                    .handshake_timeout(handshake_timeout)
-                    .max_size(max_size)
-                    // .max_qos(max_qos)
-                    //.max_topic_alias(max_topic_alias),
-                    .publish(fn_factory_with_config(|session: v5::Session<SessionState>| {
-                        ok::<_, MqttError>(fn_service(move |req| publish_v5(session.clone(), req)))
-                    }))
-                    .control(fn_factory_with_config(
-                        |session: v5::Session<SessionState>| {
-                            ok::<_, MqttError>(fn_service(move |req| {
-                                control_message_v5(session.clone(), req)
-                            }))
-                        },
-                    )))
+                pipeline_factory(proxy.clone()).and_then(
+                    MqttServer::new()
+                        .v3(v3::MqttServer::new(move |mut handshake: HandshakeV3<TcpStream>| async {
+                            let remote_addr = handshake.io().peer_addr()?;
+                            let local_addr = handshake.io().local_addr()?;
+                            let remote_addr = proxy::remote_addr(remote_addr, local_addr);
+                            let listen_cfg =
+                                Runtime::instance().settings.listeners.tcp(local_addr.port()).ok_or_else(
+                                    || {
+                                        log::error!(
+                                            "tcp listener config is not found, local addr is {:?}",
+                                            local_addr
+                                        );
+                                        MqttError::ListenerConfigError
+                                    },
+                                )?;
+                            handshake_v3(listen_cfg, handshake, remote_addr, local_addr).await
+                        })
+                        // .v3(v3::MqttServer::new(handshake_v3)
+                        .inflight(max_inflight)
+                        .handshake_timeout(handshake_timeout)
+                        .max_size(max_size)
+                        .publish(fn_factory_with_config(|session: v3::Session<SessionState>| {
+                            ok::<_, MqttError>(fn_service(move |req| publish_v3(session.clone(), req)))
+                        }))
+                        .control(fn_factory_with_config(
+                            |session: v3::Session<SessionState>| {
+                                ok::<_, MqttError>(fn_service(move |req| {
+                                    control_message_v3(session.clone(), req)
+                                }))
+                            },
+                        )))
+                        .v5(v5::MqttServer::new(move |mut handshake: HandshakeV5<TcpStream>| async {
+                            let peer_addr = handshake.io().peer_addr()?;
+                            let local_addr = handshake.io().local_addr()?;
+                            let peer_addr = proxy::remote_addr(peer_addr, local_addr);
+                            let listen_cfg =
+                                Runtime::instance().settings.listeners.tcp(local_addr.port()).ok_or_else(
+                                    || {
+                                        log::error!(
+                                            "tcp listener config is not found, local addr is {:?}",
+                                            local_addr
+                                        );
+                                        MqttError::ListenerConfigError
+                                    },
+                                )?;
+                            handshake_v5(listen_cfg, handshake, peer_addr, local_addr).await
+                        })
+                        //v5::MqttServer::new(handshake_v5)
+                        .receive_max(max_inflight as u16)
+                        .handshake_timeout(handshake_timeout)
+                        .max_size(max_size)
+                        // .max_qos(max_qos)
+                        //.max_topic_alias(max_topic_alias),
+                        .publish(fn_factory_with_config(|session: v5::Session<SessionState>| {
+                            ok::<_, MqttError>(fn_service(move |req| publish_v5(session.clone(), req)))
+                        }))
+                        .control(fn_factory_with_config(
+                            |session: v5::Session<SessionState>| {
+                                ok::<_, MqttError>(fn_service(move |req| {
+                                    control_message_v5(session.clone(), req)
+                                }))
+                            },
+                        ))),
+                )
             })?
             .workers(listen_cfg.workers)
             .maxconn(listen_cfg.max_connections / listen_cfg.workers)
             .backlog(listen_cfg.backlog)
             .reuseaddr(listen_cfg.reuseaddr)
             .reuseport(listen_cfg.reuseport)
-            .run()
-            .await?;
-        Ok(())
+            //SIGTERM and SIGINT are handled by the drain mode
+            .disable_signals()
+            .run();
+        Ok(server)
     }
 
-    _listen(&format!("tcp: {}", name), listen_cfg).await.map_err(|e| {
+    _listen(&format!("tcp: {}", name), listen_cfg).map_err(|e| {
         log::error!("Listen {:?} failed on {}, {:?}", name, listen_cfg.addr, e);
         e
     })
 }
 
-async fn listen_tls(name: String, listen_cfg: &Listener) -> Result<()> {
-    async fn _listen_tls(name: &str, listen_cfg: &Listener) -> Result<()> {
+fn listen_tls(name: String, listen_cfg: &Listener) -> Result<Server> {
+    fn _listen_tls(name: &str, listen_cfg: &Listener) -> Result<Server> {
         let cert_file = &mut BufReader::new(File::open(listen_cfg.cert.as_ref().unwrap())?);
         let key_file = &mut BufReader::new(File::open(listen_cfg.key.as_ref().unwrap())?);
 
@@ -213,14 +310,18 @@ async fn listen_tls(name: String, listen_cfg: &Listener) -> Result<()> {
         tls_config.set_single_cert(cert_chain, keys.remove(0)).map_err(|e| MqttError::from(e.to_string()))?;
 
         let tls_acceptor = Acceptor::new(tls_config);
+        let proxy = proxy::ProxyServer::new(listen_cfg);
 
         let max_inflight = listen_cfg.max_inflight.get() as usize;
         let handshake_timeout = listen_cfg.handshake_timeout();
-        let max_size = listen_cfg.max_packet_size.as_u32();
-        ntex::server::Server::build()
+        let max_size = PacketSize::codec_limit(listen_cfg);
+        let server = Server::build()
             .bind(name, listen_cfg.addr, move || {
-                pipeline_factory(tls_acceptor.clone())
-                    .map_err(|e| ntex_mqtt::MqttError::Service(MqttError::from(e)))
+                pipeline_factory(proxy.clone())
+                    .and_then(
+                        pipeline_factory(tls_acceptor.clone())
+                            .map_err(|e| ntex_mqtt::MqttError::Service(MqttError::from(e))),
+                    )
                     .and_then(
                         MqttServer::new()
                             .v3(v3::MqttServer::new(
@@ -228,6 +329,7 @@ async fn listen_tls(name: String, listen_cfg: &Listener) -> Result<()> {
                                     let (io, _) = handshake.io().get_ref();
                                     let peer_addr = io.peer_addr()?;
                                     let local_addr = io.local_addr()?;
+                                    let peer_addr = proxy::remote_addr(peer_addr, local_addr);
                                     let listen_cfg = Runtime::instance()
                                         .settings
                                         .listeners
@@ -264,6 +366,7 @@ async fn listen_tls(name: String, listen_cfg: &Listener) -> Result<()> {
                                         let (io, _) = handshake.io().get_ref();
                                         let peer_addr = io.peer_addr()?;
                                         let local_addr = io.local_addr()?;
+                                        let peer_addr = proxy::remote_addr(peer_addr, local_addr);
                                         let listen_cfg = Runtime::instance()
                                             .settings
                                             .listeners
@@ -303,12 +406,13 @@ async fn listen_tls(name: String, listen_cfg: &Listener) -> Result<()> {
             .backlog(listen_cfg.backlog)
             .reuseaddr(listen_cfg.reuseaddr)
             .reuseport(listen_cfg.reuseport)
-            .run()
-            .await?;
-        Ok(())
+            //SIGTERM and SIGINT are handled by the drain mode
+            .disable_signals()
+            .run();
+        Ok(server)
     }
 
-    _listen_tls(&format!("tls: {}", name), listen_cfg).await.map_err(|e| {
+    _listen_tls(&format!("tls: {}", name), listen_cfg).map_err(|e| {
         log::error!(
             "Listen_tls {:?} failed on {}, cert: {:?}, key: {:?}, {:?}",
             name,
@@ -321,99 +425,121 @@ async fn listen_tls(name: String, listen_cfg: &Listener) -> Result<()> {
     })
 }
 
-async fn listen_ws(name: String, listen_cfg: &Listener) -> Result<()> {
-    async fn _listen_ws(name: &str, listen_cfg: &Listener) -> Result<()> {
+fn listen_ws(name: String, listen_cfg: &Listener) -> Result<Server> {
+    fn _listen_ws(name: &str, listen_cfg: &Listener) -> Result<Server> {
         let max_inflight = listen_cfg.max_inflight.get() as usize;
         let handshake_timeout = listen_cfg.handshake_timeout();
-        let max_size = listen_cfg.max_packet_size.as_u32();
-        ntex::server::Server::build()
+        let max_size = PacketSize::codec_limit(listen_cfg);
+        let proxy = proxy::ProxyServer::new(listen_cfg);
+        let ws_listen_cfg = listen_cfg.clone();
+        let server = Server::build()
             .bind(name, listen_cfg.addr, move || {
-                pipeline_factory(ws::WSServer::new(Duration::from_secs(handshake_timeout as u64))).and_then(
-                    MqttServer::new()
-                        .v3(v3::MqttServer::new(
-                            move |mut handshake: HandshakeV3<ws::WsStream<TcpStream>>| async {
-                                let io = handshake.io().get_ref();
-                                let remote_addr = io.peer_addr()?;
-                                let local_addr = io.local_addr()?;
-                                let listen_cfg =
-                                    Runtime::instance().settings.listeners.ws(local_addr.port()).ok_or_else(
-                                        || {
+                pipeline_factory(proxy.clone())
+                    .and_then(ws::WSServer::new(
+                        Duration::from_secs(handshake_timeout as u64),
+                        ws_listen_cfg.clone(),
+                    ))
+                    .and_then(
+                        MqttServer::new()
+                            .v3(v3::MqttServer::new(
+                                move |mut handshake: HandshakeV3<ws::WsStream<TcpStream>>| async {
+                                    let io = handshake.io().get_ref();
+                                    let remote_addr = io.peer_addr()?;
+                                    let local_addr = io.local_addr()?;
+                                    let remote_addr = proxy::remote_addr(remote_addr, local_addr);
+                                    let listen_cfg = Runtime::instance()
+                                        .settings
+                                        .listeners
+                                        .ws(local_addr.port())
+                                        .ok_or_else(|| {
                                             log::error!(
                                                 "ws listener config is not found, local addr is {:?}",
                                                 local_addr
                                             );
                                             MqttError::ListenerConfigError
-                                        },
-                                    )?;
-                                handshake_v3(listen_cfg, handshake, remote_addr, local_addr).await
-                            },
-                        )
-                        .inflight(max_inflight)
-                        .handshake_timeout(handshake_timeout)
-                        .max_size(max_size)
-                        .publish(fn_factory_with_config(|session: v3::Session<SessionState>| {
-                            ok::<_, MqttError>(fn_service(move |req| publish_v3(session.clone(), req)))
-                        }))
-                        .control(fn_factory_with_config(
-                            |session: v3::Session<SessionState>| {
-                                ok::<_, MqttError>(fn_service(move |req| {
-                                    control_message_v3(session.clone(), req)
-                                }))
-                            },
-                        )))
-                        .v5(v5::MqttServer::new(
-                            move |mut handshake: HandshakeV5<ws::WsStream<TcpStream>>| async {
-                                let io = handshake.io().get_ref();
-                                let remote_addr = io.peer_addr()?;
-                                let local_addr = io.local_addr()?;
-                                let listen_cfg =
-                                    Runtime::instance().settings.listeners.ws(local_addr.port()).ok_or_else(
-                                        || {
+                                        })?;
+                                    let _origin = Origins::instance().connecting(
+                                        remote_addr,
+                                        local_addr,
+                                        handshake.io().origin(),
+                                    );
+                                    handshake_v3(listen_cfg, handshake, remote_addr, local_addr).await
+                                },
+                            )
+                            .inflight(max_inflight)
+                            .handshake_timeout(handshake_timeout)
+                            .max_size(max_size)
+                            .publish(fn_factory_with_config(|session: v3::Session<SessionState>| {
+                                ok::<_, MqttError>(fn_service(move |req| publish_v3(session.clone(), req)))
+                            }))
+                            .control(fn_factory_with_config(
+                                |session: v3::Session<SessionState>| {
+                                    ok::<_, MqttError>(fn_service(move |req| {
+                                        control_message_v3(session.clone(), req)
+                                    }))
+                                },
+                            )))
+                            .v5(v5::MqttServer::new(
+                                move |mut handshake: HandshakeV5<ws::WsStream<TcpStream>>| async {
+                                    let io = handshake.io().get_ref();
+                                    let remote_addr = io.peer_addr()?;
+                                    let local_addr = io.local_addr()?;
+                                    let remote_addr = proxy::remote_addr(remote_addr, local_addr);
+                                    let listen_cfg = Runtime::instance()
+                                        .settings
+                                        .listeners
+                                        .ws(local_addr.port())
+                                        .ok_or_else(|| {
                                             log::error!(
                                                 "ws listener config is not found, local addr is {:?}",
                                                 local_addr
                                             );
                                             MqttError::ListenerConfigError
-                                        },
-                                    )?;
-                                handshake_v5(listen_cfg, handshake, remote_addr, local_addr).await
-                            },
-                        )
-                        .receive_max(max_inflight as u16)
-                        .handshake_timeout(handshake_timeout)
-                        .max_size(max_size)
-                        // .max_qos(max_qos)
-                        //.max_topic_alias(max_topic_alias),
-                        .publish(fn_factory_with_config(|session: v5::Session<SessionState>| {
-                            ok::<_, MqttError>(fn_service(move |req| publish_v5(session.clone(), req)))
-                        }))
-                        .control(fn_factory_with_config(
-                            |session: v5::Session<SessionState>| {
-                                ok::<_, MqttError>(fn_service(move |req| {
-                                    control_message_v5(session.clone(), req)
-                                }))
-                            },
-                        ))),
-                )
+                                        })?;
+                                    let _origin = Origins::instance().connecting(
+                                        remote_addr,
+                                        local_addr,
+                                        handshake.io().origin(),
+                                    );
+                                    handshake_v5(listen_cfg, handshake, remote_addr, local_addr).await
+                                },
+                            )
+                            .receive_max(max_inflight as u16)
+                            .handshake_timeout(handshake_timeout)
+                            .max_size(max_size)
+                            // .max_qos(max_qos)
+                            //.max_topic_alias(max_topic_alias),
+                            .publish(fn_factory_with_config(|session: v5::Session<SessionState>| {
+                                ok::<_, MqttError>(fn_service(move |req| publish_v5(session.clone(), req)))
+                            }))
+                            .control(fn_factory_with_config(
+                                |session: v5::Session<SessionState>| {
+                                    ok::<_, MqttError>(fn_service(move |req| {
+                                        control_message_v5(session.clone(), req)
+                                    }))
+                                },
+                            ))),
+                    )
             })?
             .workers(listen_cfg.workers)
             .maxconn(listen_cfg.max_connections / listen_cfg.workers)
             .backlog(listen_cfg.backlog)
             .reuseaddr(listen_cfg.reuseaddr)
             .reuseport(listen_cfg.reuseport)
-            .run()
-            .await?;
-        Ok(())
+            //SIGTERM and SIGINT are handled by the drain mode
+            .disable_signals()
+            .run();
+        Ok(server)
     }
 
-    _listen_ws(&format!("ws: {}", name), listen_cfg).await.map_err(|e| {
+    _listen_ws(&format!("ws: {}", name), listen_cfg).map_err(|e| {
         log::error!("Listen {:?} failed on {}, {:?}", name, listen_cfg.addr, e);
         e
     })
 }
 
-async fn listen_wss(name: String, listen_cfg: &Listener) -> Result<()> {
-    async fn _listen_wss(name: &str, listen_cfg: &Listener) -> Result<()> {
+fn listen_wss(name: String, listen_cfg: &Listener) -> Result<Server> {
+    fn _listen_wss(name: &str, listen_cfg: &Listener) -> Result<Server> {
         let cert_file = &mut BufReader::new(File::open(listen_cfg.cert.as_ref().unwrap())?);
         let key_file = &mut BufReader::new(File::open(listen_cfg.key.as_ref().unwrap())?);
 
@@ -434,15 +560,23 @@ async fn listen_wss(name: String, listen_cfg: &Listener) -> Result<()> {
         tls_config.set_single_cert(cert_chain, keys.remove(0)).map_err(|e| MqttError::from(e.to_string()))?;
 
         let tls_acceptor = Acceptor::new(tls_config);
+        let proxy = proxy::ProxyServer::new(listen_cfg);
+        let ws_listen_cfg = listen_cfg.clone();
 
         let max_inflight = listen_cfg.max_inflight.get() as usize;
         let handshake_timeout = listen_cfg.handshake_timeout();
-        let max_size = listen_cfg.max_packet_size.as_u32();
-        ntex::server::Server::build()
+        let max_size = PacketSize::codec_limit(listen_cfg);
+        let server = Server::build()
             .bind(name, listen_cfg.addr, move || {
-                pipeline_factory(tls_acceptor.clone())
-                    .map_err(|e| ntex_mqtt::MqttError::Service(MqttError::from(e)))
-                    .and_then(ws::WSServer::new(Duration::from_secs(handshake_timeout as u64)))
+                pipeline_factory(proxy.clone())
+                    .and_then(
+                        pipeline_factory(tls_acceptor.clone())
+                            .map_err(|e| ntex_mqtt::MqttError::Service(MqttError::from(e))),
+                    )
+                    .and_then(ws::WSServer::new(
+                        Duration::from_secs(handshake_timeout as u64),
+                        ws_listen_cfg.clone(),
+                    ))
                     .and_then(
                         MqttServer::new()
                             .v3(v3::MqttServer::new(
@@ -450,6 +584,7 @@ async fn listen_wss(name: String, listen_cfg: &Listener) -> Result<()> {
                                     let (io, _) = handshake.io().get_ref().get_ref();
                                     let peer_addr = io.peer_addr()?;
                                     let local_addr = io.local_addr()?;
+                                    let peer_addr = proxy::remote_addr(peer_addr, local_addr);
                                     let listen_cfg = Runtime::instance()
                                         .settings
                                         .listeners
@@ -462,6 +597,11 @@ async fn listen_wss(name: String, listen_cfg: &Listener) -> Result<()> {
                                             MqttError::ListenerConfigError
                                         })?;
 
+                                    let _origin = Origins::instance().connecting(
+                                        peer_addr,
+                                        local_addr,
+                                        handshake.io().origin(),
+                                    );
                                     handshake_v3(listen_cfg, handshake, peer_addr, local_addr).await
                                 },
                             )
@@ -483,6 +623,7 @@ async fn listen_wss(name: String, listen_cfg: &Listener) -> Result<()> {
                                     let (io, _) = handshake.io().get_ref().get_ref();
                                     let peer_addr = io.peer_addr()?;
                                     let local_addr = io.local_addr()?;
+                                    let peer_addr = proxy::remote_addr(peer_addr, local_addr);
                                     let listen_cfg = Runtime::instance()
                                         .settings
                                         .listeners
@@ -494,6 +635,11 @@ async fn listen_wss(name: String, listen_cfg: &Listener) -> Result<()> {
                                             );
                                             MqttError::ListenerConfigError
                                         })?;
+                                    let _origin = Origins::instance().connecting(
+                                        peer_addr,
+                                        local_addr,
+                                        handshake.io().origin(),
+                                    );
                                     handshake_v5(listen_cfg, handshake, peer_addr, local_addr).await
                                 },
                             )
@@ -519,12 +665,13 @@ async fn listen_wss(name: String, listen_cfg: &Listener) -> Result<()> {
             .backlog(listen_cfg.backlog)
             .reuseaddr(listen_cfg.reuseaddr)
             .reuseport(listen_cfg.reuseport)
-            .run()
-            .await?;
-        Ok(())
+            //SIGTERM and SIGINT are handled by the drain mode
+            .disable_signals()
+            .run();
+        Ok(server)
     }
 
-    _listen_wss(&format!("wss: {}", name), listen_cfg).await.map_err(|e| {
+    _listen_wss(&format!("wss: {}", name), listen_cfg).map_err(|e| {
         log::error!(
             "listen_wss {:?} failed on {}, cert: {:?}, key: {:?}, {:?}",
             name,
@@ -536,3 +683,85 @@ async fn listen_wss(name: String, listen_cfg: &Listener) -> Result<()> {
         e
     })
 }
+
+#[cfg(unix)]
+fn listen_uds(name: String, listen_cfg: &Listener) -> Result<Server> {
+    fn _listen_uds(name: &str, listen_cfg: &Listener) -> Result<Server> {
+        let max_inflight = listen_cfg.max_inflight.get() as usize;
+        let handshake_timeout = listen_cfg.handshake_timeout();
+        let max_size = PacketSize::codec_limit(listen_cfg);
+        let port = listen_cfg.addr.port();
+        let lst = uds::bind(listen_cfg)?;
+        let server = Server::build()
+            .listen_uds(name, lst, move || {
+                MqttServer::new()
+                    .v3(v3::MqttServer::new(move |mut handshake: HandshakeV3<UnixStream>| async move {
+                        let cred = handshake.io().peer_cred().ok().map(PeerCred::from);
+                        let listen_cfg =
+                            Runtime::instance().settings.listeners.uds(port).ok_or_else(|| {
+                                log::error!("uds listener config is not found, port is {:?}", port);
+                                MqttError::ListenerConfigError
+                            })?;
+                        let local_addr = listen_cfg.addr;
+                        let remote_addr = UdsPeers::instance().next_remote_addr();
+                        let _peer = UdsPeers::instance().connecting(remote_addr, local_addr, cred);
+                        handshake_v3(listen_cfg, handshake, remote_addr, local_addr).await
+                    })
+                    .inflight(max_inflight)
+                    .handshake_timeout(handshake_timeout)
+                    .max_size(max_size)
+                    .publish(fn_factory_with_config(|session: v3::Session<SessionState>| {
+                        ok::<_, MqttError>(fn_service(move |req| publish_v3(session.clone(), req)))
+                    }))
+                    .control(fn_factory_with_config(
+                        |session: v3::Session<SessionState>| {
+                            ok::<_, MqttError>(fn_service(move |req| {
+                                control_message_v3(session.clone(), req)
+                            }))
+                        },
+                    )))
+                    .v5(v5::MqttServer::new(move |mut handshake: HandshakeV5<UnixStream>| async move {
+                        let cred = handshake.io().peer_cred().ok().map(PeerCred::from);
+                        let listen_cfg =
+                            Runtime::instance().settings.listeners.uds(port).ok_or_else(|| {
+                                log::error!("uds listener config is not found, port is {:?}", port);
+                                MqttError::ListenerConfigError
+                            })?;
+                        let local_addr = listen_cfg.addr;
+                        let remote_addr = UdsPeers::instance().next_remote_addr();
+                        let _peer = UdsPeers::instance().connecting(remote_addr, local_addr, cred);
+                        handshake_v5(listen_cfg, handshake, remote_addr, local_addr).await
+                    })
+                    .receive_max(max_inflight as u16)
+                    .handshake_timeout(handshake_timeout)
+                    .max_size(max_size)
+                    .publish(fn_factory_with_config(|session: v5::Session<SessionState>| {
+                        ok::<_, MqttError>(fn_service(move |req| publish_v5(session.clone(), req)))
+                    }))
+                    .control(fn_factory_with_config(
+                        |session: v5::Session<SessionState>| {
+                            ok::<_, MqttError>(fn_service(move |req| {
+                                control_message_v5(session.clone(), req)
+                            }))
+                        },
+                    )))
+            })?
+            .workers(listen_cfg.workers)
+            .maxconn(listen_cfg.max_connections / listen_cfg.workers)
+            //SIGTERM and SIGINT are handled by the drain mode
+            .disable_signals()
+            .run();
+        Ok(server)
+    }
+
+    _listen_uds(&format!("uds: {}", name), listen_cfg).map_err(|e| {
+        log::error!("Listen {:?} failed on {}, {:?}", name, listen_cfg.path, e);
+        e
+    })
+}
+
+#[cfg(not(unix))]
+fn listen_uds(name: String, _listen_cfg: &Listener) -> Result<Server> {
+    log::error!("Listen {:?} failed, Unix domain sockets are not supported on this platform", name);
+    Err(MqttError::from("Unix domain sockets are not supported on this platform"))
+}
@@ -1,5 +1,7 @@
+use std::cell::RefCell;
 use std::future::Future;
 use std::pin::Pin;
+use std::rc::Rc;
 use std::task::{Context, Poll};
 use std::{
     io::{self, ErrorKind},
@@ -7,6 +9,7 @@ use std::{
     time::Duration,
 };
 
+use rmqtt::broker::origin::{Origins, Rejection};
 use rmqtt::futures::{ready, FutureExt, Sink, Stream};
 use rmqtt::ntex::codec::ReadBuf;
 use rmqtt::ntex::codec::{AsyncRead, AsyncWrite};
@@ -15,8 +18,10 @@ use rmqtt::ntex::util::Ready;
 use rmqtt::ntex::{Service, ServiceFactory};
 use rmqtt::ntex_mqtt;
 use rmqtt::pin_project_lite;
+use rmqtt::settings::listener::Listener;
 use rmqtt::tokio_tungstenite::accept_hdr_async;
 use rmqtt::tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use rmqtt::tokio_tungstenite::tungstenite::http::StatusCode;
 use rmqtt::tokio_tungstenite::tungstenite::Error as WSError;
 use rmqtt::tokio_tungstenite::tungstenite::Message;
 use rmqtt::tokio_tungstenite::WebSocketStream;
@@ -24,18 +29,19 @@ use rmqtt::{log, MqttError};
 
 pub struct WSServer<T> {
     timeout: Duration,
+    listen_cfg: Listener,
     io: marker::PhantomData<T>,
 }
 
 impl<T: AsyncRead + AsyncWrite> WSServer<T> {
-    pub fn new(timeout: Duration) -> Self {
-        WSServer { timeout, io: marker::PhantomData }
+    pub fn new(timeout: Duration, listen_cfg: Listener) -> Self {
+        WSServer { timeout, listen_cfg, io: marker::PhantomData }
     }
 }
 
 impl<T> Clone for WSServer<T> {
     fn clone(&self) -> Self {
-        Self { timeout: self.timeout, io: marker::PhantomData }
+        Self { timeout: self.timeout, listen_cfg: self.listen_cfg.clone(), io: marker::PhantomData }
     }
 }
 
@@ -50,13 +56,18 @@ impl<T: AsyncRead + AsyncWrite + Unpin + 'static> ServiceFactory for WSServer<T>
     type Future = Ready<Self::Service, Self::InitError>;
 
     fn new_service(&self, _: ()) -> Self::Future {
-        Ready::Ok(WSService { timeout: self.timeout, io: marker::PhantomData })
+        Ready::Ok(WSService {
+            timeout: self.timeout,
+            listen_cfg: self.listen_cfg.clone(),
+            io: marker::PhantomData,
+        })
     }
 }
 
 pub struct WSService<T> {
     io: marker::PhantomData<T>,
     timeout: Duration,
+    listen_cfg: Listener,
 }
 
 impl<T: AsyncRead + AsyncWrite + Unpin + 'static> Service for WSService<T> {
@@ -72,9 +83,16 @@ impl<T: AsyncRead + AsyncWrite + Unpin + 'static> Service for WSService<T> {
 
     #[inline]
     fn call(&self, req: Self::Request) -> Self::Future {
+        let listen_cfg = self.listen_cfg.clone();
+        let origin = Rc::new(RefCell::new(None));
+        let origin1 = origin.clone();
+        let callback = move |req: &Request, response: Response| {
+            on_handshake(&listen_cfg, req, response, &mut origin1.borrow_mut())
+        };
         WSServiceFut {
-            fut: accept_hdr_async(req, on_handshake).boxed_local(),
+            fut: accept_hdr_async(req, callback).boxed_local(),
             delay: if self.timeout == Duration::ZERO { None } else { Some(sleep(self.timeout)) },
+            origin,
         }
     }
 }
@@ -91,6 +109,7 @@ pin_project_lite::pin_project! {
         fut: WebSocketStreamType<T>,
         #[pin]
         delay: Option<Sleep>,
+        origin: Rc<RefCell<Option<String>>>,
     }
 }
 
@@ -107,14 +126,14 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Future for WSServiceFut<T> {
             }
         }
         match Pin::new(&mut this.fut).poll(cx) {
-            Poll::Ready(Ok(io)) => Poll::Ready(Ok(WsStream(io))),
+            Poll::Ready(Ok(io)) => Poll::Ready(Ok(WsStream(io, this.origin.borrow_mut().take()))),
             Poll::Ready(Err(e)) => Poll::Ready(Err(ntex_mqtt::MqttError::Service(MqttError::from(e)))),
             Poll::Pending => Poll::Pending,
         }
     }
 }
 
-pub struct WsStream<S>(WebSocketStream<S>);
+pub struct WsStream<S>(WebSocketStream<S>, Option<String>);
 
 impl<S> WsStream<S>
 where
@@ -124,6 +143,12 @@ where
     pub fn get_ref(&self) -> &S {
         self.0.get_ref()
     }
+
+    ///Origin header of the WebSocket handshake
+    #[inline]
+    pub fn origin(&self) -> Option<&str> {
+        self.1.as_deref()
+    }
 }
 
 impl<S> AsyncRead for WsStream<S>
@@ -189,8 +214,25 @@ fn to_error(e: WSError) -> io::Error {
     }
 }
 
-fn on_handshake(req: &Request, mut response: Response) -> std::result::Result<Response, ErrorResponse> {
+fn on_handshake(
+    listen_cfg: &Listener,
+    req: &Request,
+    mut response: Response,
+    origin: &mut Option<String>,
+) -> std::result::Result<Response, ErrorResponse> {
     const PROTOCOL_ERROR: &str = "No \"Sec-WebSocket-Protocol: mqtt\" in client request";
+    *origin = req.headers().get("Origin").and_then(|v| v.to_str().ok()).map(|v| v.to_owned());
+    if let Err(rejection) = Origins::instance().check(listen_cfg, origin.as_deref()) {
+        log::debug!("WebSocket handshake rejected, origin: {:?}, {:?}", origin, rejection);
+        let (status, reason) = match rejection {
+            Rejection::Missing => (StatusCode::FORBIDDEN, "Origin is required"),
+            Rejection::NotAllowed => (StatusCode::FORBIDDEN, "Origin is not allowed"),
+            Rejection::RateLimited => (StatusCode::TOO_MANY_REQUESTS, "Too many requests of the origin"),
+        };
+        let mut response = ErrorResponse::new(Some(reason.into()));
+        *response.status_mut() = status;
+        return Err(response);
+    }
     let mqtt_protocol = req
         .headers()
         .get("Sec-WebSocket-Protocol")
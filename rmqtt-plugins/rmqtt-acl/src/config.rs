@@ -1,21 +1,17 @@
 use std::str::FromStr;
-use std::sync::Arc;
 
 use serde::de::{self, Deserialize, Deserializer};
 use serde::ser::{self, Serialize};
 
+use rmqtt::broker::acl::{AclPermission, AclRuleAction, AclRules, AclTopic};
 use rmqtt::broker::hook::Priority;
-use rmqtt::broker::topic::TopicTree;
 use rmqtt::{
-    ahash, dashmap, log,
+    log,
     serde_json::{self, Value},
-    tokio::sync::RwLock,
     Id,
 };
 use rmqtt::{ClientId, MqttError, Password, Result, Superuser, Topic, UserName};
 
-type DashSet<V> = dashmap::DashSet<V, ahash::RandomState>;
-
 pub const PH_C: &str = "%c";
 pub const PH_U: &str = "%u";
 
@@ -86,6 +82,28 @@ impl PluginConfig {
     pub fn to_json(&self) -> Result<serde_json::Value> {
         Ok(serde_json::to_value(self)?)
     }
+
+    ///The publish and subscribe rules of a client, compiled, the placeholders replaced
+    #[inline]
+    pub fn acl_rules(&self, id: &Id, password: Option<&Password>) -> AclRules {
+        let mut acl = AclRules::default();
+        for rule in self.rules() {
+            let action = match rule.control {
+                Control::Connect => continue,
+                Control::Publish => AclRuleAction::Publish,
+                Control::Subscribe => AclRuleAction::Subscribe,
+                Control::Pubsub | Control::All => AclRuleAction::All,
+            };
+            let allow = matches!(rule.access, Access::Allow);
+            let (hit, _) = rule.user.hit(id, password, allow);
+            if !hit {
+                continue;
+            }
+            let permission = if allow { AclPermission::Allow } else { AclPermission::Deny };
+            rule.topics.compile(&mut acl, permission, action, id);
+        }
+        acl
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -96,20 +114,6 @@ pub struct Rule {
     pub topics: Topics,
 }
 
-impl Rule {
-    #[inline]
-    pub async fn add_topic_filter(&self, topic_filter: &str) -> Result<()> {
-        let t = Topic::from_str(topic_filter)?;
-        self.topics.tree.write().await.insert(&t, ());
-        Ok(())
-    }
-
-    #[inline]
-    pub fn add_topic_to_eqs(&self, topic: String) {
-        self.topics.eqs.insert(topic);
-    }
-}
-
 impl std::convert::TryFrom<&serde_json::Value> for Rule {
     type Error = MqttError;
     #[inline]
@@ -195,22 +199,33 @@ pub enum Control {
 #[derive(Debug, Clone)]
 pub struct Topics {
     pub all: bool,
-    pub eqs: Arc<DashSet<String>>,
+    pub eqs: Vec<String>,
     pub eq_placeholders: Vec<String>,
-    //"sensor/%u/ctrl", "sensor/%c/ctrl"
-    pub tree: Arc<RwLock<TopicTree<()>>>,
+    pub filters: Vec<String>,
     pub placeholders: Vec<String>, //"sensor/%u/ctrl", "sensor/%c/ctrl"
 }
 
 impl Topics {
-    pub async fn is_match(&self, topic_filter: &Topic, topic_filter_str: &str) -> bool {
+    //The topics of a rule are compiled in their order, each a rule of the client
+    #[inline]
+    fn compile(&self, acl: &mut AclRules, permission: AclPermission, action: AclRuleAction, id: &Id) {
         if self.all {
-            return true;
+            acl.push(permission, action, AclTopic::All);
+            return;
+        }
+        let replace = |t: &str| t.replace(PH_C, &id.client_id).replace(PH_U, id.username_ref());
+        for eq in &self.eqs {
+            acl.push(permission, action, AclTopic::Eq(eq));
         }
-        if self.eqs.contains(topic_filter_str) {
-            return true;
+        for eq_ph in &self.eq_placeholders {
+            acl.push(permission, action, AclTopic::Eq(&replace(eq_ph)));
+        }
+        let placeholders = self.placeholders.iter().map(|ph_tf| replace(ph_tf));
+        for tf in self.filters.iter().cloned().chain(placeholders) {
+            if !acl.push(permission, action, AclTopic::Filter(&tf)) {
+                log::warn!("{:?} ACL rule topic skipped, invalid topic filter: {:?}", id, tf);
+            }
         }
-        self.tree.read().await.is_match(topic_filter)
     }
 }
 
@@ -320,8 +335,8 @@ impl std::convert::TryFrom<Option<&serde_json::Value>> for Topics {
     fn try_from(topics_cfg: Option<&serde_json::Value>) -> Result<Self, Self::Error> {
         let err_msg = format!("ACL Rule config error, topics config is {:?}", topics_cfg);
         let mut all = false;
-        let eqs = DashSet::default();
-        let mut tree = TopicTree::default();
+        let mut eqs = Vec::new();
+        let mut filters = Vec::new();
         let mut placeholders = Vec::new();
         let mut eq_placeholders = Vec::new();
         match topics_cfg {
@@ -333,7 +348,8 @@ impl std::convert::TryFrom<Option<&serde_json::Value>> for Topics {
                             if topic.contains(PH_U) || topic.contains(PH_C) {
                                 placeholders.push(topic.clone());
                             } else {
-                                tree.insert(&Topic::from_str(topic.as_str())?, ());
+                                Topic::from_str(topic.as_str())?;
+                                filters.push(topic.clone());
                             }
                         }
                         Value::Object(eq_map) => match eq_map.get("eq") {
@@ -341,7 +357,7 @@ impl std::convert::TryFrom<Option<&serde_json::Value>> for Topics {
                                 if eq.contains(PH_U) || eq.contains(PH_C) {
                                     eq_placeholders.push(eq.clone());
                                 } else {
-                                    eqs.insert(eq.clone());
+                                    eqs.push(eq.clone());
                                 }
                             }
                             _ => return Err(MqttError::from(err_msg)),
@@ -352,12 +368,6 @@ impl std::convert::TryFrom<Option<&serde_json::Value>> for Topics {
             }
             _ => return Err(MqttError::from(err_msg)),
         }
-        Ok(Topics {
-            all,
-            eqs: Arc::new(eqs),
-            eq_placeholders,
-            tree: Arc::new(RwLock::new(tree)),
-            placeholders,
-        })
+        Ok(Topics { all, eqs, eq_placeholders, filters, placeholders })
     }
 }
@@ -5,20 +5,17 @@ extern crate serde;
 #[macro_use]
 extern crate rmqtt_macros;
 
-use std::str::FromStr;
 use std::sync::Arc;
 
-use config::{Access, Control, PluginConfig, PH_C, PH_U};
-use rmqtt::{
-    async_trait::async_trait,
-    log, serde_json,
-    tokio::{self, sync::RwLock},
-};
+use config::{Access, Control, PluginConfig};
+use rmqtt::{async_trait::async_trait, log, serde_json, tokio::sync::RwLock};
 use rmqtt::{
+    broker::acl::{AclRules, ACL_PUBLISH, ACL_SUBSCRIBE},
+    broker::acl_cache::AclCache,
     broker::hook::{Handler, HookResult, Parameter, Register, ReturnType, Type},
-    broker::types::{AuthResult, PublishAclResult, SubscribeAckReason, SubscribeAclResult, Topic},
+    broker::types::{AuthResult, PublishAclResult, SubscribeAckReason, SubscribeAclResult},
     plugin::{PackageInfo, Plugin},
-    register, Result, Runtime,
+    register, ClientId, DashMap, Id, Result, Runtime, Session,
 };
 
 mod config;
@@ -30,6 +27,8 @@ struct AclPlugin {
     runtime: &'static Runtime,
     register: Box<dyn Register>,
     cfg: Arc<RwLock<PluginConfig>>,
+    //client id => the compiled rules of the connected client
+    compiled: Arc<DashMap<ClientId, (Id, Arc<AclRules>)>>,
 }
 
 impl AclPlugin {
@@ -39,7 +38,7 @@ impl AclPlugin {
         let cfg = Arc::new(RwLock::new(runtime.settings.plugins.load_config::<PluginConfig>(&name)?));
         log::debug!("{} AclPlugin cfg: {:?}", name, cfg.read().await);
         let register = runtime.extends.hook_mgr().await.register();
-        Ok(Self { runtime, register, cfg })
+        Ok(Self { runtime, register, cfg, compiled: Arc::new(DashMap::default()) })
     }
 }
 
@@ -48,15 +47,22 @@ impl Plugin for AclPlugin {
     #[inline]
     async fn init(&mut self) -> Result<()> {
         log::info!("{} init", self.name());
-        let cfg = &self.cfg;
+        let (cfg, compiled) = (&self.cfg, &self.compiled);
         let priority = cfg.read().await.priority;
-        self.register.add_priority(Type::ClientConnected, priority, Box::new(AclHandler::new(cfg))).await;
-        self.register.add_priority(Type::ClientAuthenticate, priority, Box::new(AclHandler::new(cfg))).await;
         self.register
-            .add_priority(Type::ClientSubscribeCheckAcl, priority, Box::new(AclHandler::new(cfg)))
+            .add_priority(Type::ClientConnected, priority, Box::new(AclHandler::new(cfg, compiled)))
+            .await;
+        self.register
+            .add_priority(Type::ClientDisconnected, priority, Box::new(AclHandler::new(cfg, compiled)))
+            .await;
+        self.register
+            .add_priority(Type::ClientAuthenticate, priority, Box::new(AclHandler::new(cfg, compiled)))
+            .await;
+        self.register
+            .add_priority(Type::ClientSubscribeCheckAcl, priority, Box::new(AclHandler::new(cfg, compiled)))
             .await;
         self.register
-            .add_priority(Type::MessagePublishCheckAcl, priority, Box::new(AclHandler::new(cfg)))
+            .add_priority(Type::MessagePublishCheckAcl, priority, Box::new(AclHandler::new(cfg, compiled)))
             .await;
         Ok(())
     }
@@ -70,6 +76,9 @@ impl Plugin for AclPlugin {
     async fn load_config(&mut self) -> Result<()> {
         let new_cfg = self.runtime.settings.plugins.load_config::<PluginConfig>(self.name())?;
         *self.cfg.write().await = new_cfg;
+        //The rules changed, the compiled rules and the cached decisions are dropped
+        self.compiled.clear();
+        AclCache::instance().clear();
         log::debug!("load_config ok,  {:?}", self.cfg);
         Ok(())
     }
@@ -91,57 +100,39 @@ impl Plugin for AclPlugin {
 
 struct AclHandler {
     cfg: Arc<RwLock<PluginConfig>>,
+    compiled: Arc<DashMap<ClientId, (Id, Arc<AclRules>)>>,
 }
 
 impl AclHandler {
-    fn new(cfg: &Arc<RwLock<PluginConfig>>) -> Self {
-        Self { cfg: cfg.clone() }
+    fn new(cfg: &Arc<RwLock<PluginConfig>>, compiled: &Arc<DashMap<ClientId, (Id, Arc<AclRules>)>>) -> Self {
+        Self { cfg: cfg.clone(), compiled: compiled.clone() }
+    }
+
+    //The compiled rules of the session, compiled again if the client reconnected
+    async fn acl_rules(&self, session: &Session) -> Arc<AclRules> {
+        if let Some(entry) = self.compiled.get(&session.id.client_id) {
+            let (id, rules) = entry.value();
+            if *id == session.id {
+                return rules.clone();
+            }
+        }
+        let rules = Arc::new(self.cfg.read().await.acl_rules(&session.id, session.password()));
+        log::debug!("{:?} compiled ACL rules: {}", session.id, rules.len());
+        self.compiled.insert(session.id.client_id.clone(), (session.id.clone(), rules.clone()));
+        rules
     }
 }
 
 #[async_trait]
 impl Handler for AclHandler {
-    async fn hook(&self, param: &Parameter, acc: Option<HookResult>) -> ReturnType {
+    async fn hook(&self, param: &Parameter, acc: &mut Option<HookResult>) -> ReturnType {
         match param {
             Parameter::ClientConnected(session) => {
-                let cfg = self.cfg.clone();
-                let client_id = session.id.client_id.clone();
-                let username = session.id.username.clone();
-                let build_placeholders = async move {
-                    for rule in cfg.read().await.rules() {
-                        for ph_tf in &rule.topics.placeholders {
-                            let mut tf = ph_tf.replace(PH_C, &client_id);
-                            if let Some(un) = &username {
-                                tf = tf.replace(PH_U, un);
-                            } else {
-                                tf = tf.replace(PH_U, "");
-                            }
-                            if let Err(e) = rule.add_topic_filter(&tf).await {
-                                log::error!(
-                                    "acl config error, build_placeholders, add topic filter error, {:?}",
-                                    e
-                                );
-                            }
-                        }
-
-                        for eq_ph_t in &rule.topics.eq_placeholders {
-                            let mut t = eq_ph_t.replace(PH_C, &client_id);
-                            if let Some(un) = &username {
-                                t = t.replace(PH_U, un);
-                            } else {
-                                t = t.replace(PH_U, "");
-                            }
-                            rule.add_topic_to_eqs(t);
-                        }
+                self.acl_rules(session).await;
+            }
 
-                        log::debug!("rule.access: {:?}", rule.access);
-                        log::debug!("rule.user: {:?}", rule.user);
-                        log::debug!("rule.control: {:?}", rule.control);
-                        log::debug!("rule.topics.eqs: {:?}", rule.topics.eqs);
-                        log::debug!("rule.topics.tree: {:?}", rule.topics.tree.read().await.list(100));
-                    }
-                };
-                tokio::spawn(build_placeholders);
+            Parameter::ClientDisconnected(session, _) => {
+                self.compiled.remove_if(&session.id.client_id, |_, (id, _)| *id == session.id);
             }
 
             Parameter::ClientAuthenticate(connect_info) => {
@@ -151,7 +142,7 @@ impl Handler for AclHandler {
                     Some(HookResult::AuthResult(AuthResult::BadUsernameOrPassword))
                         | Some(HookResult::AuthResult(AuthResult::NotAuthorized))
                 ) {
-                    return (false, acc);
+                    return false;
                 }
 
                 for rule in self.cfg.read().await.rules() {
@@ -164,116 +155,76 @@ impl Handler for AclHandler {
                     if hit {
                         log::debug!("{:?} ClientAuthenticate, rule: {:?}", connect_info.id(), rule);
                         return if allow {
-                            (false, Some(HookResult::AuthResult(AuthResult::Allow(superuser))))
+                            *acc = Some(HookResult::AuthResult(AuthResult::Allow(superuser, None)));
+                            false
                         } else {
-                            (false, Some(HookResult::AuthResult(AuthResult::NotAuthorized)))
+                            *acc = Some(HookResult::AuthResult(AuthResult::NotAuthorized));
+                            false
                         };
                     }
                 }
-                return (false, Some(HookResult::AuthResult(AuthResult::NotAuthorized)));
+                *acc = Some(HookResult::AuthResult(AuthResult::NotAuthorized));
+                return false;
             }
 
             Parameter::ClientSubscribeCheckAcl(session, subscribe) => {
-                if let Some(HookResult::SubscribeAclResult(acl_result)) = &acc {
+                if let Some(HookResult::SubscribeAclResult(acl_result)) = &*acc {
                     if acl_result.failure() {
-                        return (false, acc);
+                        return false;
                     }
                 }
-                let topic =
-                    Topic::from_str(&subscribe.topic_filter).unwrap_or_else(|_| Topic::from(Vec::new()));
                 let topic_filter = &subscribe.topic_filter;
-                for (idx, rule) in self.cfg.read().await.rules().iter().enumerate() {
-                    if !matches!(rule.control, Control::Subscribe | Control::Pubsub | Control::All) {
-                        continue;
-                    }
-
-                    let allow = matches!(rule.access, Access::Allow);
-                    let (hit, _) = rule.user.hit(&session.id, session.password(), allow);
-                    if !hit {
-                        continue;
-                    }
-                    if !rule.topics.is_match(&topic, topic_filter).await {
-                        continue;
-                    }
-                    log::debug!(
-                        "{:?} ClientSubscribeCheckAcl, {}, is_match ok: topic_filter: {}",
-                        session.id,
-                        idx,
-                        topic_filter
-                    );
-                    return if allow {
-                        (
-                            false,
-                            Some(HookResult::SubscribeAclResult(SubscribeAclResult::new_success(
-                                subscribe.opts.qos(),
-                                None,
-                            ))),
-                        )
-                    } else {
-                        (
-                            false,
-                            Some(HookResult::SubscribeAclResult(SubscribeAclResult::new_failure(
-                                SubscribeAckReason::NotAuthorized,
-                            ))),
-                        )
-                    };
-                }
-                return (
-                    false,
-                    Some(HookResult::SubscribeAclResult(SubscribeAclResult::new_failure(
-                        SubscribeAckReason::NotAuthorized,
-                    ))),
+                let allow = self.acl_rules(session).await.check(topic_filter, ACL_SUBSCRIBE);
+                log::debug!(
+                    "{:?} ClientSubscribeCheckAcl, {:?}, topic_filter: {}",
+                    session.id,
+                    allow,
+                    topic_filter
                 );
+                //no rule matching, denied
+                return if allow.unwrap_or(false) {
+                    (
+                        false,
+                        Some(HookResult::SubscribeAclResult(SubscribeAclResult::new_success(
+                            subscribe.opts.qos(),
+                            None,
+                        ))),
+                    )
+                } else {
+                    (
+                        false,
+                        Some(HookResult::SubscribeAclResult(SubscribeAclResult::new_failure(
+                            SubscribeAckReason::NotAuthorized,
+                        ))),
+                    )
+                };
             }
 
             Parameter::MessagePublishCheckAcl(session, publish) => {
-                if let Some(HookResult::PublishAclResult(PublishAclResult::Rejected(_))) = &acc {
-                    return (false, acc);
+                if let Some(HookResult::PublishAclResult(PublishAclResult::Rejected(_))) = &*acc {
+                    return false;
                 }
                 let topic_str = publish.topic();
-                let topic = Topic::from_str(topic_str).unwrap_or_else(|_| Topic::from(Vec::new()));
-                let disconnect_if_pub_rejected = self.cfg.read().await.disconnect_if_pub_rejected;
-                for (idx, rule) in self.cfg.read().await.rules().iter().enumerate() {
-                    if !matches!(rule.control, Control::Publish | Control::Pubsub | Control::All) {
-                        continue;
-                    }
-
-                    let allow = matches!(rule.access, Access::Allow);
-                    let (hit, _) = rule.user.hit(&session.id, session.password(), allow);
-                    if !hit {
-                        continue;
-                    }
-                    if !rule.topics.is_match(&topic, topic_str).await {
-                        continue;
-                    }
-                    log::debug!(
-                        "{:?} MessagePublishCheckAcl, {}, is_match ok: topic_str: {}",
-                        session.id,
-                        idx,
-                        topic_str
-                    );
-                    return if allow {
-                        (false, Some(HookResult::PublishAclResult(PublishAclResult::Allow)))
-                    } else {
-                        (
-                            false,
-                            Some(HookResult::PublishAclResult(PublishAclResult::Rejected(
-                                disconnect_if_pub_rejected,
-                            ))),
-                        )
-                    };
-                }
-                return (
-                    false,
-                    Some(HookResult::PublishAclResult(PublishAclResult::Rejected(
-                        disconnect_if_pub_rejected,
-                    ))),
-                );
+                let allow = self.acl_rules(session).await.check(topic_str, ACL_PUBLISH);
+                log::debug!("{:?} MessagePublishCheckAcl, {:?}, topic_str: {}", session.id, allow, topic_str);
+                //no rule matching, rejected
+                return if allow.unwrap_or(false) {
+                    *acc = Some(HookResult::PublishAclResult(PublishAclResult::Allow));
+                    false
+                } else {
+                    let disconnect_if_pub_rejected = self.cfg.read().await.disconnect_if_pub_rejected;
+                    (
+                        false,
+                        Some(HookResult::PublishAclResult(PublishAclResult::Rejected(
+                            disconnect_if_pub_rejected,
+                        ))),
+                    )
+                };
             }
             _ => {
                 log::error!("parameter is: {:?}", param);
             }
         }
-        (true, acc)
+        true
     }
 }
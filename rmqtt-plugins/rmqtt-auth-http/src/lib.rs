@@ -19,9 +19,12 @@ use rmqtt::ntex::util::ByteString;
 use rmqtt::reqwest::Response;
 use rmqtt::{ahash, async_trait, chrono, log, once_cell::sync::Lazy, reqwest, serde_json, tokio, Id};
 use rmqtt::{
+    broker::acl::AclRule,
     broker::hook::{Handler, HookResult, Parameter, Register, ReturnType, Type},
+    broker::session::Session,
     broker::types::{
-        AuthResult, Password, PublishAclResult, SubscribeAckReason, SubscribeAclResult, Superuser,
+        AuthInfo, AuthResult, ConnectInfo, Password, PublishAclResult, SubscribeAckReason,
+        SubscribeAclResult, Superuser, UserProperty,
     },
     plugin::{PackageInfo, Plugin},
     register, MqttError, Result, Runtime, TopicName,
@@ -36,6 +39,8 @@ const SUPERUSER: &str = "X-Superuser";
 
 const CACHE_KEY: &str = "ACL-CACHE-MAP";
 
+const USER_PROPERTY_PREFIX: &str = "%{up.";
+
 #[derive(Clone, Debug, PartialEq, Eq, Hash, Copy)]
 enum ResponseResult {
     Allow(Superuser),
@@ -57,6 +62,17 @@ impl ResponseResult {
 
 type Cacheable = Option<i64>;
 
+//The result, the superuser flag, the cache timeout and the ACL rules of the client of an HTTP response
+type Responded = (ResponseResult, Superuser, Cacheable, Vec<AclRule>);
+
+//A JSON response body, {"result": "allow", "acl": [..]}, the rules attached to the client
+#[derive(Deserialize)]
+struct JsonResponse {
+    result: String,
+    #[serde(default)]
+    acl: Vec<AclRule>,
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Hash, Copy)]
 enum ACLType {
     Sub = 1,
@@ -153,7 +169,7 @@ impl AuthHandler {
         Self { cfg: cfg.clone() }
     }
 
-    async fn response_result(resp: Response) -> Result<(ResponseResult, Superuser, Cacheable)> {
+    async fn response_result(resp: Response) -> Result<Responded> {
         if resp.status().is_success() {
             let superuser = resp.headers().contains_key(SUPERUSER);
             let cache_timeout = if let Some(tm) = resp.headers().get(CACHEABLE).and_then(|v| v.to_str().ok())
@@ -170,9 +186,23 @@ impl AuthHandler {
             };
             log::debug!("Cache timeout is {:?}", cache_timeout);
             let body = resp.text().await.map_err(|e| MqttError::Msg(e.to_string()))?;
-            Ok((ResponseResult::from(body.as_str(), superuser), superuser, cache_timeout))
+            if body.trim_start().starts_with('{') {
+                return match serde_json::from_str::<JsonResponse>(&body) {
+                    Ok(r) => Ok((
+                        ResponseResult::from(r.result.as_str(), superuser),
+                        superuser,
+                        cache_timeout,
+                        r.acl,
+                    )),
+                    Err(e) => {
+                        log::warn!("Parse the JSON response error, {:?}", e);
+                        Ok((ResponseResult::Ignore, false, None, Vec::new()))
+                    }
+                };
+            }
+            Ok((ResponseResult::from(body.as_str(), superuser), superuser, cache_timeout, Vec::new()))
         } else {
-            Ok((ResponseResult::Ignore, false, None))
+            Ok((ResponseResult::Ignore, false, None, Vec::new()))
         }
     }
 
@@ -181,7 +211,7 @@ impl AuthHandler {
         body: &T,
         headers: HeaderMap,
         timeout: Duration,
-    ) -> Result<(ResponseResult, Superuser, Cacheable)> {
+    ) -> Result<Responded> {
         log::debug!("http_get_request, timeout: {:?}, url: {}", timeout, url);
         match HTTP_CLIENT.clone().get(url).headers(headers).timeout(timeout).query(body).send().await {
             Err(e) => {
@@ -198,7 +228,7 @@ impl AuthHandler {
         body: &T,
         headers: HeaderMap,
         timeout: Duration,
-    ) -> Result<(ResponseResult, Superuser, Cacheable)> {
+    ) -> Result<Responded> {
         log::debug!("http_form_request, method: {:?}, timeout: {:?}, url: {}", method, timeout, url);
         match HTTP_CLIENT
             .clone()
@@ -223,7 +253,7 @@ impl AuthHandler {
         body: &T,
         headers: HeaderMap,
         timeout: Duration,
-    ) -> Result<(ResponseResult, Superuser, Cacheable)> {
+    ) -> Result<Responded> {
         log::debug!("http_json_request, method: {:?}, timeout: {:?}, url: {}", method, timeout, url);
         match HTTP_CLIENT
             .clone()
@@ -246,6 +276,7 @@ impl AuthHandler {
         params: &mut HashMap<String, String>,
         id: &Id,
         password: Option<&Password>,
+        user_properties: &[UserProperty],
         sub_or_pub: Option<(ACLType, &TopicName)>,
     ) -> Result<()> {
         let password =
@@ -259,6 +290,9 @@ impl AuthHandler {
             *v = v.replace("%a", &remote_addr);
             *v = v.replace("%r", "mqtt");
             *v = v.replace("%P", &password);
+            if v.contains(USER_PROPERTY_PREFIX) {
+                *v = Self::replace_user_properties(v, user_properties);
+            }
             if let Some((ref acl_type, topic)) = sub_or_pub {
                 *v = v.replace("%A", acl_type.as_str());
                 *v = v.replace("%t", topic);
@@ -270,13 +304,35 @@ impl AuthHandler {
         Ok(())
     }
 
+    ///%{up.NAME}, the value of the user property NAME of the CONNECT packet, empty if not sent
+    fn replace_user_properties(v: &str, user_properties: &[UserProperty]) -> String {
+        let mut replaced = String::with_capacity(v.len());
+        let mut rest = v;
+        while let Some(start) = rest.find(USER_PROPERTY_PREFIX) {
+            replaced.push_str(&rest[..start]);
+            let name = &rest[start + USER_PROPERTY_PREFIX.len()..];
+            if let Some(end) = name.find('}') {
+                if let Some((_, value)) = user_properties.iter().find(|(k, _)| k == &name[..end]) {
+                    replaced.push_str(value);
+                }
+                rest = &name[end + 1..];
+            } else {
+                replaced.push_str(&rest[start..]);
+                rest = "";
+            }
+        }
+        replaced.push_str(rest);
+        replaced
+    }
+
     async fn request(
         &self,
         id: &Id,
         mut req_cfg: config::Req,
         password: Option<&Password>,
+        user_properties: &[UserProperty],
         sub_or_pub: Option<(ACLType, &TopicName)>,
-    ) -> Result<(ResponseResult, Cacheable)> {
+    ) -> Result<(ResponseResult, Cacheable, Vec<AclRule>)> {
         log::debug!("{:?} req_cfg.url.path(): {:?}", id, req_cfg.url.path());
         let (headers, timeout) = {
             let cfg = self.cfg.read().await;
@@ -293,51 +349,65 @@ impl AuthHandler {
             (headers, cfg.http_timeout)
         };
 
-        let (auth_result, superuser, cacheable) = if req_cfg.is_get() {
+        let (auth_result, superuser, cacheable, rules) = if req_cfg.is_get() {
             let body = &mut req_cfg.params;
-            Self::replaces(body, id, password, sub_or_pub)?;
+            Self::replaces(body, id, password, user_properties, sub_or_pub)?;
             Self::http_get_request(req_cfg.url, body, headers, timeout).await?
         } else if req_cfg.json_body() {
             let body = &mut req_cfg.params;
-            Self::replaces(body, id, password, sub_or_pub)?;
+            Self::replaces(body, id, password, user_properties, sub_or_pub)?;
             Self::http_json_request(req_cfg.url, req_cfg.method, body, headers, timeout).await?
         } else {
             //form body
             let body = &mut req_cfg.params;
-            Self::replaces(body, id, password, sub_or_pub)?;
+            Self::replaces(body, id, password, user_properties, sub_or_pub)?;
             Self::http_form_request(req_cfg.url, req_cfg.method, body, headers, timeout).await?
         };
-        log::debug!("auth_result: {:?}, superuser: {}, cacheable: {:?}", auth_result, superuser, cacheable);
-        Ok((auth_result, cacheable))
+        log::debug!(
+            "auth_result: {:?}, superuser: {}, cacheable: {:?}, rules: {:?}",
+            auth_result,
+            superuser,
+            cacheable,
+            rules
+        );
+        Ok((auth_result, cacheable, rules))
     }
 
-    async fn auth(&self, id: &Id, password: Option<&Password>) -> ResponseResult {
+    async fn auth(&self, connect_info: &ConnectInfo) -> (ResponseResult, Vec<AclRule>) {
+        let (id, password) = (connect_info.id(), connect_info.password());
         if let Some(req) = { self.cfg.read().await.http_auth_req.clone() } {
-            match self.request(id, req.clone(), password, None).await {
-                Ok((auth_res, _)) => {
+            match self.request(id, req.clone(), password, connect_info.user_properties(), None).await {
+                Ok((auth_res, _, rules)) => {
                     log::debug!("auth result: {:?}", auth_res);
-                    auth_res
+                    (auth_res, rules)
                 }
                 Err(e) => {
                     log::warn!("{:?} auth error, {:?}", id, e);
                     if self.cfg.read().await.deny_if_error {
-                        ResponseResult::Deny
+                        (ResponseResult::Deny, Vec::new())
                     } else {
-                        ResponseResult::Ignore
+                        (ResponseResult::Ignore, Vec::new())
                     }
                 }
             }
         } else {
-            ResponseResult::Ignore
+            (ResponseResult::Ignore, Vec::new())
         }
     }
 
-    async fn acl(&self, id: &Id, sub_or_pub: Option<(ACLType, &TopicName)>) -> (ResponseResult, Cacheable) {
+    async fn acl(
+        &self,
+        session: &Session,
+        sub_or_pub: Option<(ACLType, &TopicName)>,
+    ) -> (ResponseResult, Cacheable) {
+        let id = &session.id;
         if let Some(req) = { self.cfg.read().await.http_acl_req.clone() } {
-            match self.request(id, req.clone(), None, sub_or_pub).await {
-                Ok(acl_res) => {
+            let connect_info = session.connect_info().await.ok();
+            let user_properties = connect_info.as_ref().map(|c| c.user_properties()).unwrap_or_default();
+            match self.request(id, req.clone(), None, user_properties, sub_or_pub).await {
+                Ok((acl_res, cacheable, _)) => {
                     log::debug!("acl result: {:?}", acl_res);
-                    acl_res
+                    (acl_res, cacheable)
                 }
                 Err(e) => {
                     log::warn!("{:?} acl error, {:?}", id, e);
@@ -356,7 +426,7 @@ impl AuthHandler {
 
 #[async_trait]
 impl Handler for AuthHandler {
-    async fn hook(&self, param: &Parameter, acc: Option<HookResult>) -> ReturnType {
+    async fn hook(&self, param: &Parameter, acc: &mut Option<HookResult>) -> ReturnType {
         match param {
             Parameter::ClientAuthenticate(connect_info) => {
                 log::debug!("ClientAuthenticate auth-http");
@@ -365,29 +435,39 @@ impl Handler for AuthHandler {
                     Some(HookResult::AuthResult(AuthResult::BadUsernameOrPassword))
                         | Some(HookResult::AuthResult(AuthResult::NotAuthorized))
                 ) {
-                    return (false, acc);
+                    return false;
                 }
 
-                return match self.auth(connect_info.id(), connect_info.password()).await {
-                    ResponseResult::Allow(superuser) => {
-                        (false, Some(HookResult::AuthResult(AuthResult::Allow(superuser))))
+                return match self.auth(connect_info).await {
+                    (ResponseResult::Allow(superuser), rules) => {
+                        let auth_info = if rules.is_empty() {
+                            None
+                        } else {
+                            Some(AuthInfo { rules, ..Default::default() })
+                        };
+                        *acc = Some(HookResult::AuthResult(AuthResult::Allow(superuser, auth_info)));
+                        false
+                    }
+                    (ResponseResult::Deny, _) => {
+                        *acc = Some(HookResult::AuthResult(AuthResult::BadUsernameOrPassword));
+                        false
                     }
-                    ResponseResult::Deny => {
-                        (false, Some(HookResult::AuthResult(AuthResult::BadUsernameOrPassword)))
+                    (ResponseResult::Ignore, _) => {
+                        *acc = None;
+                        true
                     }
-                    ResponseResult::Ignore => (true, None),
                 };
             }
 
             Parameter::ClientSubscribeCheckAcl(session, subscribe) => {
-                if let Some(HookResult::SubscribeAclResult(acl_result)) = &acc {
+                if let Some(HookResult::SubscribeAclResult(acl_result)) = &*acc {
                     if acl_result.failure() {
-                        return (false, acc);
+                        return false;
                     }
                 }
 
                 //ResponseResult, Cacheable
-                let (acl_res, _) = self.acl(&session.id, Some((ACLType::Sub, &subscribe.topic_filter))).await;
+                let (acl_res, _) = self.acl(session, Some((ACLType::Sub, &subscribe.topic_filter))).await;
                 return match acl_res {
                     ResponseResult::Allow(_) => (
                         false,
@@ -402,14 +482,17 @@ impl Handler for AuthHandler {
                             SubscribeAckReason::NotAuthorized,
                         ))),
                     ),
-                    ResponseResult::Ignore => (true, None),
+                    ResponseResult::Ignore => {
+                        *acc = None;
+                        true
+                    }
                 };
             }
 
             Parameter::MessagePublishCheckAcl(session, publish) => {
                 log::debug!("MessagePublishCheckAcl");
-                if let Some(HookResult::PublishAclResult(PublishAclResult::Rejected(_))) = &acc {
-                    return (false, acc);
+                if let Some(HookResult::PublishAclResult(PublishAclResult::Rejected(_))) = &*acc {
+                    return false;
                 }
 
                 let acl_res = if let Some((acl_res, expire)) = session
@@ -432,8 +515,7 @@ impl Handler for AuthHandler {
                     acl_res
                 } else {
                     //ResponseResult, Cacheable
-                    let (acl_res, cacheable) =
-                        self.acl(&session.id, Some((ACLType::Pub, publish.topic()))).await;
+                    let (acl_res, cacheable) = self.acl(session, Some((ACLType::Pub, publish.topic()))).await;
                     if let Some(tm) = cacheable {
                         let expire = if tm < 0 { tm } else { chrono::Local::now().timestamp_millis() + tm };
                         if let Some(cache_map) = session
@@ -450,7 +532,8 @@ impl Handler for AuthHandler {
 
                 return match acl_res {
                     ResponseResult::Allow(_) => {
-                        (false, Some(HookResult::PublishAclResult(PublishAclResult::Allow)))
+                        *acc = Some(HookResult::PublishAclResult(PublishAclResult::Allow));
+                        false
                     }
                     ResponseResult::Deny => (
                         false,
@@ -458,14 +541,17 @@ impl Handler for AuthHandler {
                             self.cfg.read().await.disconnect_if_pub_rejected,
                         ))),
                     ),
-                    ResponseResult::Ignore => (true, None),
+                    ResponseResult::Ignore => {
+                        *acc = None;
+                        true
+                    }
                 };
             }
             _ => {
                 log::error!("unimplemented, {:?}", param)
             }
         }
-        (true, acc)
+        true
     }
 }
 
@@ -0,0 +1,362 @@
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use lapin::options::ConfirmSelectOptions;
+use lapin::options::{
+    BasicAckOptions, BasicConsumeOptions, BasicNackOptions, BasicPublishOptions, BasicQosOptions,
+};
+use lapin::types::FieldTable;
+use lapin::{BasicProperties, Channel, Connection, ConnectionProperties};
+
+use rmqtt::{
+    anyhow::anyhow,
+    futures::StreamExt,
+    log,
+    serde_json::{self, json},
+    timestamp_millis,
+    tokio::{self, sync::mpsc, sync::RwLock, task::JoinHandle},
+    DashMap,
+};
+use rmqtt::{
+    ClientId, From, FromType, Id, NodeId, Publish, PublishProperties, QoS, Result, Runtime, SessionState,
+    Topic, UserName,
+};
+
+use crate::config::{Bridge, IngressEntry, PluginConfig};
+
+type EntryIdx = usize;
+type Message = (EntryIdx, From, Publish);
+
+#[derive(Default)]
+pub(crate) struct Metrics {
+    connected: AtomicBool,
+    sents: AtomicUsize,
+    nacks: AtomicUsize,
+    fails: AtomicUsize,
+    drops: AtomicUsize,
+    receiveds: AtomicUsize,
+}
+
+impl Metrics {
+    #[inline]
+    fn to_json(&self) -> serde_json::Value {
+        json!({
+            "connected": self.connected.load(Ordering::SeqCst),
+            "sents": self.sents.load(Ordering::SeqCst),
+            "nacks": self.nacks.load(Ordering::SeqCst),
+            "fails": self.fails.load(Ordering::SeqCst),
+            "drops": self.drops.load(Ordering::SeqCst),
+            "receiveds": self.receiveds.load(Ordering::SeqCst),
+        })
+    }
+}
+
+pub(crate) struct Client {
+    cfg: Arc<Bridge>,
+    tx: mpsc::Sender<Message>,
+    metrics: Arc<Metrics>,
+}
+
+impl Client {
+    fn start(cfg: Bridge, node_id: NodeId) -> Client {
+        let (tx, rx) = mpsc::channel(cfg.queue_capacity);
+        let cfg = Arc::new(cfg);
+        let metrics = Arc::new(Metrics::default());
+        tokio::spawn(Self::run(cfg.clone(), node_id, rx, metrics.clone()));
+        Client { cfg, tx, metrics }
+    }
+
+    //Connection loop, reconnect after the connection is lost, exit when the egress queue is closed.
+    async fn run(cfg: Arc<Bridge>, node_id: NodeId, mut rx: mpsc::Receiver<Message>, metrics: Arc<Metrics>) {
+        loop {
+            match Connection::connect(&cfg.uri, ConnectionProperties::default()).await {
+                Ok(conn) => {
+                    log::info!("{} Successfully connected to {:?}", cfg.name, cfg.uri);
+                    metrics.connected.store(true, Ordering::SeqCst);
+                    let (err_tx, mut err_rx) = mpsc::channel(1);
+                    conn.on_error(move |e| {
+                        let _ = err_tx.try_send(e);
+                    });
+
+                    let consumers = Self::start_consumers(&cfg, &conn, node_id, &metrics).await;
+                    let exit = match Self::egress_channel(&cfg, &conn).await {
+                        Ok(channel) => loop {
+                            tokio::select! {
+                                msg = rx.recv() => match msg {
+                                    Some(msg) => Self::publish(&cfg, &channel, msg, &metrics).await,
+                                    None => break true,
+                                },
+                                e = err_rx.recv() => {
+                                    log::warn!("{} connection error, {:?}", cfg.name, e);
+                                    break false;
+                                }
+                            }
+                        },
+                        Err(e) => {
+                            log::warn!("{} create channel error, {:?}", cfg.name, e);
+                            false
+                        }
+                    };
+
+                    metrics.connected.store(false, Ordering::SeqCst);
+                    for c in consumers {
+                        c.abort();
+                    }
+                    if let Err(e) = conn.close(0, "").await {
+                        log::debug!("{} close connection error, {:?}", cfg.name, e);
+                    }
+                    if exit {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    log::warn!("{} Connect to {:?} fail, {:?}", cfg.name, cfg.uri, e);
+                }
+            }
+            if rx.is_closed() {
+                break;
+            }
+            tokio::time::sleep(cfg.reconnect_interval).await;
+        }
+        log::info!("{} Exit 'rmqtt-bridge-amqp' client", cfg.name);
+    }
+
+    async fn egress_channel(cfg: &Bridge, conn: &Connection) -> Result<Channel> {
+        let channel = conn.create_channel().await.map_err(|e| anyhow!(e))?;
+        if cfg.publisher_confirms {
+            channel.confirm_select(ConfirmSelectOptions::default()).await.map_err(|e| anyhow!(e))?;
+        }
+        Ok(channel)
+    }
+
+    async fn publish(cfg: &Bridge, channel: &Channel, msg: Message, metrics: &Metrics) {
+        let (entry_idx, f, p) = msg;
+        let entry = if let Some(entry) = cfg.egress.get(entry_idx) { entry } else { unreachable!() };
+        let routing_key = entry.remote.make_routing_key(&p.topic, &f.id.client_id);
+        let mut props = BasicProperties::default()
+            .with_app_id(f.id.client_id.to_string().into())
+            .with_timestamp(p.create_time as u64 / 1000);
+        if entry.remote.persistent {
+            props = props.with_delivery_mode(2);
+        }
+        if let Some(content_type) = p.properties.content_type.as_ref() {
+            props = props.with_content_type(content_type.to_string().into());
+        }
+
+        let res = async {
+            let confirm = channel
+                .basic_publish(
+                    &entry.remote.exchange,
+                    &routing_key,
+                    BasicPublishOptions::default(),
+                    p.payload.as_ref(),
+                    props,
+                )
+                .await?;
+            if cfg.publisher_confirms {
+                confirm.await
+            } else {
+                Ok(lapin::publisher_confirm::Confirmation::NotRequested)
+            }
+        }
+        .await;
+
+        match res {
+            Ok(confirm) if confirm.is_nack() => {
+                metrics.nacks.fetch_add(1, Ordering::SeqCst);
+                log::warn!("{} message is nacked by the broker, routing_key: {}", cfg.name, routing_key);
+            }
+            Ok(_) => {
+                metrics.sents.fetch_add(1, Ordering::SeqCst);
+            }
+            Err(e) => {
+                metrics.fails.fetch_add(1, Ordering::SeqCst);
+                log::warn!("{} publish error, routing_key: {}, {:?}", cfg.name, routing_key, e);
+            }
+        }
+    }
+
+    async fn start_consumers(
+        cfg: &Arc<Bridge>,
+        conn: &Connection,
+        node_id: NodeId,
+        metrics: &Arc<Metrics>,
+    ) -> Vec<JoinHandle<()>> {
+        let mut consumers = Vec::new();
+        for (entry_idx, entry) in cfg.ingress.iter().enumerate() {
+            let channel = match conn.create_channel().await {
+                Ok(channel) => channel,
+                Err(e) => {
+                    log::warn!("{} create consumer channel error, {:?}", cfg.name, e);
+                    continue;
+                }
+            };
+            let cfg = cfg.clone();
+            let metrics = metrics.clone();
+            let entry = entry.clone();
+            consumers.push(tokio::spawn(async move {
+                if let Err(e) = Self::consume(&cfg, channel, node_id, entry_idx, &entry, &metrics).await {
+                    log::warn!("{} consume {:?} error, {:?}", cfg.name, entry.remote.queue, e);
+                }
+            }));
+        }
+        consumers
+    }
+
+    async fn consume(
+        cfg: &Bridge,
+        channel: Channel,
+        node_id: NodeId,
+        entry_idx: EntryIdx,
+        entry: &IngressEntry,
+        metrics: &Metrics,
+    ) -> Result<()> {
+        //QoS 0 => auto ack, QoS 1/2 => ack after the message has been forwarded
+        let no_ack = matches!(entry.local.qos, QoS::AtMostOnce);
+        if entry.remote.prefetch > 0 {
+            channel
+                .basic_qos(entry.remote.prefetch, BasicQosOptions::default())
+                .await
+                .map_err(|e| anyhow!(e))?;
+        }
+        let client_id = ClientId::from(format!("{}:ingress:{}:{}", cfg.name, node_id, entry_idx));
+        let mut consumer = channel
+            .basic_consume(
+                &entry.remote.queue,
+                &client_id,
+                BasicConsumeOptions { no_ack, ..Default::default() },
+                FieldTable::default(),
+            )
+            .await
+            .map_err(|e| anyhow!(e))?;
+        log::info!("{} Successfully consumed from {:?}", cfg.name, entry.remote.queue);
+
+        let from = From::from_bridge(Id::new(
+            node_id,
+            None,
+            None,
+            client_id.clone(),
+            Some(UserName::from(cfg.name.as_str())),
+        ));
+        while let Some(delivery) = consumer.next().await {
+            let delivery = delivery.map_err(|e| anyhow!(e))?;
+            metrics.receiveds.fetch_add(1, Ordering::SeqCst);
+            let msg = Publish {
+                dup: false,
+                retain: entry.local.retain,
+                qos: entry.local.qos,
+                topic: entry.local.make_topic(delivery.routing_key.as_str()),
+                packet_id: None,
+                payload: delivery.data.clone().into(),
+                properties: PublishProperties::default(),
+                create_time: timestamp_millis(),
+            };
+            let res = send_publish(from.clone(), msg, entry).await;
+            if !no_ack {
+                let ack_res = if let Err(e) = res {
+                    log::warn!("{} forward error, {:?}", cfg.name, e);
+                    delivery.nack(BasicNackOptions { requeue: true, ..Default::default() }).await
+                } else {
+                    delivery.ack(BasicAckOptions::default()).await
+                };
+                ack_res.map_err(|e| anyhow!(e))?;
+            }
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn to_json(&self) -> serde_json::Value {
+        json!({
+            "name": self.cfg.name,
+            "uri": self.cfg.uri,
+            "queue_len": self.tx.max_capacity() - self.tx.capacity(),
+            "metrics": self.metrics.to_json(),
+        })
+    }
+}
+
+async fn send_publish(from: From, msg: Publish, entry: &IngressEntry) -> Result<()> {
+    log::debug!("from {:?}, message: {:?}", from, msg);
+    //hook, message_publish
+    let msg =
+        match Runtime::instance().extends.hook_mgr().await.message_publish(None, from.clone(), &msg).await {
+            Ok(modified) => modified.unwrap_or(msg),
+            //dropped by a handler
+            Err(_) => return Ok(()),
+        };
+
+    SessionState::forwards(
+        from,
+        msg,
+        entry.retain_available,
+        entry.storage_available,
+        Some(entry.expiry_interval),
+    )
+    .await?;
+    Ok(())
+}
+
+#[derive(Clone)]
+pub(crate) struct BridgeManager {
+    node_id: NodeId,
+    cfg: Arc<RwLock<PluginConfig>>,
+    clients: Arc<DashMap<String, Client>>,
+}
+
+impl BridgeManager {
+    pub fn new(node_id: NodeId, cfg: Arc<RwLock<PluginConfig>>) -> Self {
+        Self { node_id, cfg, clients: Arc::new(DashMap::default()) }
+    }
+
+    pub async fn start(&mut self) -> Result<()> {
+        let bridges = self.cfg.read().await.bridges.clone();
+        for b_cfg in bridges {
+            if !b_cfg.enable {
+                continue;
+            }
+            let name = b_cfg.name.clone();
+            self.clients.insert(name, Client::start(b_cfg, self.node_id));
+        }
+        Ok(())
+    }
+
+    pub async fn stop(&mut self) {
+        for entry in self.clients.iter() {
+            log::debug!("stop bridge_name: {:?}", entry.key());
+        }
+        //Dropping the sender closes the connection
+        self.clients.clear();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    #[inline]
+    pub(crate) fn send(&self, f: &From, p: &Publish) -> Result<()> {
+        //Messages received from the bridge itself are not sent back
+        if matches!(f.typ(), FromType::Bridge)
+            && self.clients.contains_key(f.id.username.as_deref().unwrap_or_default())
+        {
+            return Ok(());
+        }
+        let topic = Topic::from_str(&p.topic)?;
+        for client in self.clients.iter() {
+            for (entry_idx, entry) in client.cfg.egress.iter().enumerate() {
+                if !entry.local.is_match(&topic) {
+                    continue;
+                }
+                if let Err(e) = client.tx.try_send((entry_idx, f.clone(), p.clone())) {
+                    client.metrics.drops.fetch_add(1, Ordering::SeqCst);
+                    log::warn!("{} egress queue error, {}", client.cfg.name, e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[inline]
+    pub(crate) fn to_json(&self) -> Vec<serde_json::Value> {
+        self.clients.iter().map(|entry| entry.value().to_json()).collect()
+    }
+}
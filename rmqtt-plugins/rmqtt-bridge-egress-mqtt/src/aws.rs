@@ -0,0 +1,133 @@
+use hmac::{Hmac, Mac};
+use rsa::pkcs1v15::SigningKey;
+use rsa::pkcs8::DecodePrivateKey;
+use rsa::signature::{SignatureEncoding, Signer};
+use rsa::RsaPrivateKey;
+use sha2::{Digest, Sha256};
+
+use rmqtt::{
+    base64::{engine::general_purpose, Engine as _},
+    chrono::Utc,
+    serde_json::{self, json},
+    url::form_urlencoded,
+    MqttError, Result,
+};
+
+use crate::config::{CustomAuthorizer, SigV4};
+
+const SERVICE: &str = "iotdevicegateway";
+const ALGORITHM: &str = "AWS4-HMAC-SHA256";
+//Maximum levels of a topic, the forward slashes of "$aws/rules/<rule name>/" are not counted
+const MAX_TOPIC_SLASHES: usize = 7;
+const MAX_TOPIC_LEN: usize = 256;
+
+///WebSocket URL signed with AWS Signature Version 4, it is signed again on each connection
+pub(crate) fn presign_url(host: &str, cfg: &SigV4) -> String {
+    let now = Utc::now();
+    let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+    let date = now.format("%Y%m%d").to_string();
+    let scope = format!("{}/{}/{}/aws4_request", date, cfg.region, SERVICE);
+
+    let query = format!(
+        "X-Amz-Algorithm={}&X-Amz-Credential={}&X-Amz-Date={}&X-Amz-SignedHeaders=host",
+        ALGORITHM,
+        encode(&format!("{}/{}", cfg.access_key_id, scope)),
+        amz_date
+    );
+    let canonical_request =
+        format!("GET\n/mqtt\n{}\nhost:{}\n\nhost\n{}", query, host, hex(&Sha256::digest(b"")));
+    let string_to_sign = format!(
+        "{}\n{}\n{}\n{}",
+        ALGORITHM,
+        amz_date,
+        scope,
+        hex(&Sha256::digest(canonical_request.as_bytes()))
+    );
+
+    let key = [date.as_str(), cfg.region.as_str(), SERVICE, "aws4_request"]
+        .iter()
+        .fold(format!("AWS4{}", cfg.secret_access_key).into_bytes(), |key, part| hmac(&key, part.as_bytes()));
+    let signature = hex(&hmac(&key, string_to_sign.as_bytes()));
+
+    let mut url = format!("wss://{}/mqtt?{}&X-Amz-Signature={}", host, query, signature);
+    //AWS IoT expects the session token after the signature, it is not signed
+    if let Some(token) = cfg.session_token.as_ref() {
+        url.push_str(&format!("&X-Amz-Security-Token={}", encode(token)));
+    }
+    url
+}
+
+///Username carrying the custom authorizer name, the token and its signature
+pub(crate) fn custom_authorizer_username(username: Option<&str>, cfg: &CustomAuthorizer) -> Result<String> {
+    let mut params =
+        format!("x-amz-customauthorizer-name={}&{}={}", encode(&cfg.name), cfg.token_key, encode(&cfg.token));
+    if let Some(path) = cfg.signing_key.as_ref() {
+        let key = RsaPrivateKey::from_pkcs8_pem(&std::fs::read_to_string(path)?)
+            .map_err(|e| MqttError::from(format!("invalid signing key {}, {}", path, e)))?;
+        let signature = SigningKey::<Sha256>::new(key).sign(cfg.token.as_bytes()).to_bytes();
+        params.push_str(&format!(
+            "&x-amz-customauthorizer-signature={}",
+            encode(&general_purpose::STANDARD.encode(signature))
+        ));
+    }
+    Ok(format!("{}?{}", username.unwrap_or_default(), params))
+}
+
+///Checks the topic against the AWS IoT topic rules, the reserved topics can only be published to if
+///they are the shadow, jobs or basic ingest topics
+pub(crate) fn check_topic(topic: &str) -> std::result::Result<(), String> {
+    if topic.len() > MAX_TOPIC_LEN {
+        return Err(format!("longer than {} bytes", MAX_TOPIC_LEN));
+    }
+    let unprefixed = if let Some(rest) = topic.strip_prefix("$aws/rules/") {
+        //basic ingest, $aws/rules/<rule name>/<topic>
+        rest.split_once('/').map(|(_, t)| t).ok_or_else(|| "no topic after the rule name".to_owned())?
+    } else if let Some(rest) = topic.strip_prefix("$aws/things/") {
+        match rest.split('/').nth(1) {
+            Some("shadow") | Some("jobs") => topic,
+            _ => return Err("reserved topic, only the shadow and jobs topics of a thing are allowed".into()),
+        }
+    } else if topic.starts_with('$') {
+        return Err("reserved topic".into());
+    } else {
+        topic
+    };
+    if unprefixed.matches('/').count() > MAX_TOPIC_SLASHES {
+        return Err(format!("more than {} forward slashes", MAX_TOPIC_SLASHES));
+    }
+    Ok(())
+}
+
+///Update topic of the shadow, "" is the classic shadow
+#[inline]
+pub(crate) fn shadow_update_topic(thing: &str, shadow: &str) -> String {
+    if shadow.is_empty() {
+        format!("$aws/things/{}/shadow/update", thing)
+    } else {
+        format!("$aws/things/{}/shadow/name/{}/update", thing, shadow)
+    }
+}
+
+///The JSON payload as the reported state of a shadow update
+#[inline]
+pub(crate) fn shadow_reported(payload: &[u8]) -> Result<Vec<u8>> {
+    let reported: serde_json::Value = serde_json::from_slice(payload)?;
+    Ok(serde_json::to_vec(&json!({ "state": { "reported": reported } }))?)
+}
+
+#[inline]
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC can take key of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+#[inline]
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[inline]
+fn encode(s: &str) -> String {
+    form_urlencoded::byte_serialize(s.as_bytes()).collect()
+}
@@ -0,0 +1,430 @@
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use rumqttc::{AsyncClient, Event, EventLoop, MqttOptions, Packet, TlsConfiguration, Transport};
+
+use rmqtt::broker::idempotency::DeliveredKeys;
+use rmqtt::{
+    bytes::Bytes,
+    bytestring::ByteString,
+    futures, log,
+    serde_json::{self, json},
+    timestamp_millis,
+    tokio::{self, sync::RwLock, task::JoinHandle, time::Instant},
+    url::form_urlencoded,
+    DashMap,
+};
+use rmqtt::{
+    ClientId, From, FromType, Id, MqttError, NodeId, Publish, PublishProperties, QoS, Result, Runtime,
+    SessionState, Topic, TopicName, UserName, UserProperties,
+};
+
+use crate::aws;
+use crate::config::{Bridge, PluginConfig, Preset};
+use crate::token::{self, AzureSas, TokenProvider};
+
+#[derive(Default)]
+pub(crate) struct Metrics {
+    sents: AtomicUsize,
+    acks: AtomicUsize,
+    fails: AtomicUsize,
+    drops: AtomicUsize,
+    rejects: AtomicUsize,
+    receiveds: AtomicUsize,
+}
+
+impl Metrics {
+    #[inline]
+    fn to_json(&self) -> serde_json::Value {
+        json!({
+            "sents": self.sents.load(Ordering::SeqCst),
+            "acks": self.acks.load(Ordering::SeqCst),
+            "fails": self.fails.load(Ordering::SeqCst),
+            "drops": self.drops.load(Ordering::SeqCst),
+            "rejects": self.rejects.load(Ordering::SeqCst),
+            "receiveds": self.receiveds.load(Ordering::SeqCst),
+        })
+    }
+}
+
+pub(crate) struct Producer {
+    cfg: Arc<Bridge>,
+    client: AsyncClient,
+    //thing name of the shadow topics
+    thing: String,
+    //device-to-cloud topic of the Azure IoT Hub device
+    d2c_topic: String,
+    connected: Arc<AtomicBool>,
+    dedup: Option<Arc<DeliveredKeys>>,
+    metrics: Arc<Metrics>,
+    task: JoinHandle<()>,
+}
+
+impl Drop for Producer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl Producer {
+    fn connect(cfg: Bridge, node_id: NodeId) -> Result<Producer> {
+        let client_id =
+            cfg.client_id.clone().unwrap_or_else(|| format!("rmqtt:{}:egress:{}", cfg.name, node_id));
+        let thing = cfg.aws.thing_name.clone().unwrap_or_else(|| client_id.clone());
+        let d2c_topic = format!("devices/{}/messages/events/", client_id);
+        let provider: Option<Arc<dyn TokenProvider>> = match cfg.preset {
+            Preset::AzureIotHub => Some(Arc::new(AzureSas::new(&cfg.host_port().0, &client_id, &cfg.azure)?)),
+            _ => None,
+        };
+        let (opts, lifetime) = Self::options(&cfg, &client_id, provider.as_deref())?;
+        let (client, eventloop) = AsyncClient::new(opts, cfg.queue_capacity);
+        let dedup = DeliveredKeys::new(&cfg.dedup)?;
+
+        let cfg = Arc::new(cfg);
+        let connected = Arc::new(AtomicBool::new(false));
+        let metrics = Arc::new(Metrics::default());
+        let task = tokio::spawn(
+            EventLoopState {
+                cfg: cfg.clone(),
+                node_id,
+                client_id,
+                client: client.clone(),
+                provider,
+                connected: connected.clone(),
+                metrics: metrics.clone(),
+            }
+            .run(eventloop, lifetime),
+        );
+        Ok(Producer { cfg, client, thing, d2c_topic, connected, dedup, metrics, task })
+    }
+
+    ///The options of the next connection, and the lifetime of their token
+    fn options(
+        cfg: &Bridge,
+        client_id: &str,
+        provider: Option<&dyn TokenProvider>,
+    ) -> Result<(MqttOptions, Option<Duration>)> {
+        let (host, port) = cfg.host_port();
+        let sigv4 = cfg.aws.sigv4.as_ref().filter(|_| cfg.preset == Preset::AwsIot);
+        let mut opts = match sigv4 {
+            Some(sigv4) => MqttOptions::new(client_id, aws::presign_url(&host, sigv4), port),
+            None => MqttOptions::new(client_id, host.as_str(), port),
+        };
+        opts.set_keep_alive(cfg.keepalive).set_clean_session(cfg.clean_session);
+
+        let mut lifetime = None;
+        let username = match cfg.preset {
+            Preset::AwsIot if cfg.aws.custom_authorizer.is_some() => Some(aws::custom_authorizer_username(
+                cfg.username.as_deref(),
+                cfg.aws.custom_authorizer.as_ref().unwrap(),
+            )?),
+            Preset::AzureIotHub => {
+                Some(format!("{}/{}/?api-version={}", host, client_id, cfg.azure.api_version))
+            }
+            _ => cfg.username.clone(),
+        };
+        let password = match provider {
+            Some(provider) => {
+                let (token, ttl) = provider.token()?;
+                lifetime = Some(ttl);
+                Some(token)
+            }
+            None => cfg.password.clone(),
+        };
+        if let Some(username) = username {
+            opts.set_credentials(username, password.unwrap_or_default());
+        }
+
+        //Azure IoT Hub only accepts TLS connections
+        if cfg.tls.enable || sigv4.is_some() || cfg.preset == Preset::AzureIotHub {
+            let ca = cfg.tls.ca.as_ref().ok_or_else(|| MqttError::from("tls.ca is not configured"))?;
+            let client_auth = match (cfg.tls.cert.as_ref(), cfg.tls.key.as_ref()) {
+                (Some(cert), Some(key)) => Some((std::fs::read(cert)?, std::fs::read(key)?)),
+                _ => None,
+            };
+            let alpn = cfg.alpn();
+            let tls = TlsConfiguration::Simple {
+                ca: std::fs::read(ca)?,
+                alpn: if alpn.is_empty() {
+                    None
+                } else {
+                    Some(alpn.into_iter().map(String::into_bytes).collect())
+                },
+                client_auth,
+            };
+            opts.set_transport(if sigv4.is_some() { Transport::Wss(tls) } else { Transport::Tls(tls) });
+        }
+        Ok((opts, lifetime))
+    }
+
+    fn forward(&self, f: &From, p: &Publish, topic: &Topic) {
+        let key = self.dedup.as_ref().and_then(|d| d.key(p));
+        if let (Some(dedup), Some(key)) = (self.dedup.as_ref(), key) {
+            if dedup.is_delivered(key) {
+                log::debug!("{} skip the delivered message, topic: {}", self.cfg.name, p.topic);
+                return;
+            }
+        }
+        let preset = self.cfg.preset;
+        let mut queued = false;
+        for entry in self.cfg.entries.iter() {
+            if !entry.local.is_match(topic) {
+                continue;
+            }
+            let (remote_topic, payload) =
+                match entry.remote.shadow.as_ref().filter(|_| preset == Preset::AwsIot) {
+                    Some(shadow) => match aws::shadow_reported(&p.payload) {
+                        Ok(payload) => (aws::shadow_update_topic(&self.thing, shadow), payload),
+                        Err(e) => {
+                            self.metrics.fails.fetch_add(1, Ordering::SeqCst);
+                            log::warn!("{} shadow update error, topic: {}, {:?}", self.cfg.name, p.topic, e);
+                            continue;
+                        }
+                    },
+                    None if preset == Preset::AzureIotHub => (self.d2c_topic(p), p.payload.to_vec()),
+                    None => {
+                        (entry.remote.make_topic(&p.topic, &f.id.client_id, &self.thing), p.payload.to_vec())
+                    }
+                };
+            if preset == Preset::AwsIot {
+                if let Err(e) = aws::check_topic(&remote_topic) {
+                    self.metrics.rejects.fetch_add(1, Ordering::SeqCst);
+                    log::warn!("{} invalid AWS IoT topic {}, {}", self.cfg.name, remote_topic, e);
+                    continue;
+                }
+            }
+            let qos = match entry.remote.qos.unwrap_or(p.qos) {
+                QoS::AtMostOnce => rumqttc::QoS::AtMostOnce,
+                QoS::AtLeastOnce => rumqttc::QoS::AtLeastOnce,
+                QoS::ExactlyOnce if preset != Preset::Generic => rumqttc::QoS::AtLeastOnce,
+                QoS::ExactlyOnce => rumqttc::QoS::ExactlyOnce,
+            };
+            //Azure IoT Hub does not support retained messages
+            let retain = entry.remote.retain.unwrap_or(p.retain) && preset != Preset::AzureIotHub;
+            match self.client.try_publish(remote_topic, qos, retain, payload) {
+                Ok(()) => {
+                    self.metrics.sents.fetch_add(1, Ordering::SeqCst);
+                    queued = true;
+                }
+                Err(e) => {
+                    self.metrics.drops.fetch_add(1, Ordering::SeqCst);
+                    log::warn!("{} forward queue error, {}", self.cfg.name, e);
+                }
+            }
+        }
+        //Delivered once queued, the client resends the unacknowledged messages after a reconnection
+        if let (Some(dedup), Some(key), true) = (self.dedup.as_ref(), key, queued) {
+            dedup.delivered(key);
+        }
+    }
+
+    ///devices/{device id}/messages/events/{property bag}, the properties are the local topic and the
+    ///content type
+    #[inline]
+    fn d2c_topic(&self, p: &Publish) -> String {
+        let mut props = Vec::new();
+        if !self.cfg.azure.topic_property.is_empty() {
+            props.push(format!(
+                "{}={}",
+                token::encode(&self.cfg.azure.topic_property),
+                token::encode(&p.topic)
+            ));
+        }
+        if let Some(content_type) = p.properties.content_type.as_ref() {
+            props.push(format!("$.ct={}", token::encode(content_type)));
+        }
+        format!("{}{}", self.d2c_topic, props.join("&"))
+    }
+
+    #[inline]
+    fn to_json(&self) -> serde_json::Value {
+        json!({
+            "name": self.cfg.name,
+            "preset": self.cfg.preset,
+            "server": self.cfg.server,
+            "connected": self.connected.load(Ordering::SeqCst),
+            "metrics": self.metrics.to_json(),
+            "dedup": self.dedup.as_ref().map(|d| d.to_json()),
+        })
+    }
+}
+
+struct EventLoopState {
+    cfg: Arc<Bridge>,
+    node_id: NodeId,
+    client_id: String,
+    client: AsyncClient,
+    provider: Option<Arc<dyn TokenProvider>>,
+    connected: Arc<AtomicBool>,
+    metrics: Arc<Metrics>,
+}
+
+impl EventLoopState {
+    async fn run(self, mut eventloop: EventLoop, lifetime: Option<Duration>) {
+        let cfg = self.cfg.clone();
+        let c2d_prefix = format!("devices/{}/messages/devicebound/", self.client_id);
+        let mut renew_at = Self::renew_at(lifetime);
+        loop {
+            let renew = async move {
+                match renew_at {
+                    Some(at) => tokio::time::sleep_until(at).await,
+                    None => futures::future::pending().await,
+                }
+            };
+            tokio::select! {
+                ev = eventloop.poll() => match ev {
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        self.connected.store(true, Ordering::SeqCst);
+                        log::info!("{} connected to {:?}", cfg.name, cfg.server);
+                        if cfg.preset == Preset::AzureIotHub && cfg.azure.c2d.enable {
+                            let topic_filter = format!("{}#", c2d_prefix);
+                            if let Err(e) = self.client.try_subscribe(topic_filter, rumqttc::QoS::AtLeastOnce) {
+                                log::warn!("{} subscribe to the cloud-to-device messages error, {}", cfg.name, e);
+                            }
+                        }
+                    }
+                    Ok(Event::Incoming(Packet::PubAck(_))) | Ok(Event::Incoming(Packet::PubComp(_))) => {
+                        self.metrics.acks.fetch_add(1, Ordering::SeqCst);
+                    }
+                    Ok(Event::Incoming(Packet::Publish(p))) if p.topic.starts_with(&c2d_prefix) => {
+                        self.metrics.receiveds.fetch_add(1, Ordering::SeqCst);
+                        let props = p.topic[c2d_prefix.len()..].to_owned();
+                        tokio::spawn(c2d_publish(cfg.clone(), self.node_id, self.client_id.clone(), props, p.payload));
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        self.connected.store(false, Ordering::SeqCst);
+                        log::warn!("{} connection to {:?} error, {:?}", cfg.name, cfg.server, e);
+                        tokio::time::sleep(cfg.reconnect_interval).await;
+                        //The signed URL or the token may have expired, they are signed again
+                        if self.provider.is_some() || (cfg.preset == Preset::AwsIot && cfg.aws.sigv4.is_some()) {
+                            match Producer::options(&cfg, &self.client_id, self.provider.as_deref()) {
+                                Ok((opts, lifetime)) => {
+                                    eventloop.mqtt_options = opts;
+                                    renew_at = Self::renew_at(lifetime);
+                                }
+                                Err(e) => log::warn!("{} sign error, {:?}", cfg.name, e),
+                            }
+                        }
+                    }
+                },
+                _ = renew => {
+                    match Producer::options(&cfg, &self.client_id, self.provider.as_deref()) {
+                        Ok((opts, lifetime)) => {
+                            log::info!("{} token renewed, reconnecting", cfg.name);
+                            eventloop.mqtt_options = opts;
+                            //Reconnects with the new token
+                            eventloop.clean();
+                            renew_at = Self::renew_at(lifetime);
+                        }
+                        Err(e) => {
+                            log::warn!("{} renew the token error, {:?}", cfg.name, e);
+                            renew_at = Self::renew_at(Some(cfg.reconnect_interval));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    //at 80% of the token lifetime
+    #[inline]
+    fn renew_at(lifetime: Option<Duration>) -> Option<Instant> {
+        lifetime.map(|lifetime| Instant::now() + lifetime.mul_f64(0.8))
+    }
+}
+
+///Publishes a cloud-to-device message locally, the properties of its topic are the user properties
+async fn c2d_publish(cfg: Arc<Bridge>, node_id: NodeId, client_id: String, props: String, payload: Bytes) {
+    let c2d = &cfg.azure.c2d;
+    let from = From::from_bridge(Id::new(
+        node_id,
+        None,
+        None,
+        ClientId::from(client_id.as_str()),
+        Some(UserName::from(cfg.name.as_str())),
+    ));
+    let user_properties: UserProperties = form_urlencoded::parse(props.as_bytes())
+        .map(|(k, v)| (ByteString::from(k.as_ref()), ByteString::from(v.as_ref())))
+        .collect();
+    let msg = Publish {
+        dup: false,
+        retain: false,
+        qos: QoS::AtLeastOnce,
+        topic: TopicName::from(c2d.topic.replace("${azure.device}", &client_id)),
+        packet_id: None,
+        payload,
+        properties: PublishProperties::from(user_properties),
+        create_time: timestamp_millis(),
+    };
+
+    //hook, message_publish
+    let msg =
+        match Runtime::instance().extends.hook_mgr().await.message_publish(None, from.clone(), &msg).await {
+            Ok(modified) => modified.unwrap_or(msg),
+            //dropped by a handler
+            Err(_) => return,
+        };
+
+    if let Err(e) =
+        SessionState::forwards(from, msg, false, c2d.storage_available, Some(c2d.expiry_interval)).await
+    {
+        log::warn!("{} forward the cloud-to-device message error, {:?}", cfg.name, e);
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct BridgeManager {
+    node_id: NodeId,
+    cfg: Arc<RwLock<PluginConfig>>,
+    producers: Arc<DashMap<String, Producer>>,
+}
+
+impl BridgeManager {
+    pub fn new(node_id: NodeId, cfg: Arc<RwLock<PluginConfig>>) -> Self {
+        Self { node_id, cfg, producers: Arc::new(DashMap::default()) }
+    }
+
+    pub async fn start(&mut self) -> Result<()> {
+        let bridges = self.cfg.read().await.bridges.clone();
+        for b_cfg in bridges {
+            if !b_cfg.enable {
+                continue;
+            }
+            let name = b_cfg.name.clone();
+            let producer = Producer::connect(b_cfg, self.node_id)?;
+            self.producers.insert(name, producer);
+        }
+        Ok(())
+    }
+
+    pub async fn stop(&mut self) {
+        for entry in self.producers.iter() {
+            log::debug!("stop bridge_name: {:?}", entry.key());
+        }
+        //Dropping the producer ends the event loop
+        self.producers.clear();
+    }
+
+    #[inline]
+    pub(crate) fn send(&self, f: &From, p: &Publish) -> Result<()> {
+        let topic = Topic::from_str(&p.topic)?;
+        for producer in self.producers.iter() {
+            //Cloud-to-device messages of the bridge itself are not sent back
+            if matches!(f.typ(), FromType::Bridge)
+                && f.id.username.as_deref() == Some(producer.key().as_str())
+            {
+                continue;
+            }
+            producer.forward(f, p, &topic);
+        }
+        Ok(())
+    }
+
+    #[inline]
+    pub(crate) fn to_json(&self) -> Vec<serde_json::Value> {
+        self.producers.iter().map(|entry| entry.value().to_json()).collect()
+    }
+}
@@ -0,0 +1,373 @@
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::de::{self, Deserialize, Deserializer};
+use serde::ser::{self, Serialize};
+
+use rmqtt::broker::idempotency::DedupConfig;
+use rmqtt::broker::topic::TopicTree;
+use rmqtt::settings::deserialize_duration;
+use rmqtt::{ClientId, QoS, Topic};
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PluginConfig {
+    #[serde(default)]
+    pub bridges: Vec<Bridge>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Bridge {
+    #[serde(default)]
+    pub enable: bool,
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub preset: Preset,
+    ///Remote broker address, host:port, the AWS IoT endpoint for aws_iot
+    #[serde(default)]
+    pub server: String,
+    ///Client id, default: rmqtt:${bridge_name}:egress:${node_id}, the thing name for aws_iot, the
+    ///device id for azure_iot_hub
+    #[serde(default)]
+    pub client_id: Option<String>,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+
+    #[serde(default)]
+    pub tls: Tls,
+    ///AWS IoT authentication and shadow helpers, aws_iot only
+    #[serde(default)]
+    pub aws: Aws,
+    ///Azure IoT Hub authentication and cloud-to-device messages, azure_iot_hub only
+    #[serde(default)]
+    pub azure: Azure,
+
+    #[serde(default = "Bridge::keepalive_default", deserialize_with = "deserialize_duration")]
+    pub keepalive: Duration,
+    #[serde(default = "Bridge::reconnect_interval_default", deserialize_with = "deserialize_duration")]
+    pub reconnect_interval: Duration,
+    #[serde(default = "Bridge::clean_session_default")]
+    pub clean_session: bool,
+
+    ///Capacity of the forwarding queue, messages are dropped when the queue is full
+    #[serde(default = "Bridge::queue_capacity_default")]
+    pub queue_capacity: usize,
+
+    ///Skips the messages whose idempotency key was delivered
+    #[serde(default)]
+    pub dedup: DedupConfig,
+
+    #[serde(default)]
+    pub entries: Vec<Entry>,
+}
+
+impl Bridge {
+    fn keepalive_default() -> Duration {
+        Duration::from_secs(60)
+    }
+
+    fn reconnect_interval_default() -> Duration {
+        Duration::from_secs(5)
+    }
+
+    fn clean_session_default() -> bool {
+        true
+    }
+
+    fn queue_capacity_default() -> usize {
+        100_000
+    }
+
+    #[inline]
+    pub fn host_port(&self) -> (String, u16) {
+        let default_port = if self.tls.enable { 8883 } else { 1883 };
+        match self.server.rsplit_once(':') {
+            Some((host, port)) => (host.to_owned(), port.parse().unwrap_or(default_port)),
+            None => (self.server.clone(), default_port),
+        }
+    }
+
+    ///ALPN protocols, AWS IoT needs "x-amzn-mqtt-ca" for the X.509 client certificates on port 443,
+    ///and "mqtt" for the custom authorizers on port 443
+    #[inline]
+    pub fn alpn(&self) -> Vec<String> {
+        if !self.tls.alpn.is_empty() || self.preset != Preset::AwsIot || self.host_port().1 != 443 {
+            return self.tls.alpn.clone();
+        }
+        if self.aws.custom_authorizer.is_some() {
+            vec!["mqtt".into()]
+        } else if self.aws.sigv4.is_none() {
+            vec!["x-amzn-mqtt-ca".into()]
+        } else {
+            Vec::new()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub enum Preset {
+    ///Any MQTT 3.1.1 broker
+    #[default]
+    Generic,
+    ///AWS IoT Core, the topics are checked against the AWS IoT topic rules before being forwarded
+    AwsIot,
+    ///Azure IoT Hub, the messages are forwarded as device-to-cloud messages of the device
+    AzureIotHub,
+}
+
+impl<'de> Deserialize<'de> for Preset {
+    #[inline]
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        match String::deserialize(deserializer)?.to_ascii_lowercase().as_str() {
+            "generic" => Ok(Preset::Generic),
+            "aws_iot" => Ok(Preset::AwsIot),
+            "azure_iot_hub" => Ok(Preset::AzureIotHub),
+            s => Err(de::Error::custom(format!("invalid preset '{}', generic, aws_iot or azure_iot_hub", s))),
+        }
+    }
+}
+
+#[derive(Default, Debug, Clone, Deserialize, Serialize)]
+pub struct Tls {
+    #[serde(default)]
+    pub enable: bool,
+    ///Root certificate file, e.g. AmazonRootCA1.pem
+    #[serde(default)]
+    pub ca: Option<String>,
+    ///Client certificate and key file, X.509 mutual TLS
+    #[serde(default)]
+    pub cert: Option<String>,
+    #[serde(default)]
+    pub key: Option<String>,
+    ///ALPN protocols, the defaults of the aws_iot preset are used if empty
+    #[serde(default)]
+    pub alpn: Vec<String>,
+}
+
+#[derive(Default, Debug, Clone, Deserialize, Serialize)]
+pub struct Aws {
+    ///Thing name of the shadow topics, default: the client id
+    #[serde(default)]
+    pub thing_name: Option<String>,
+    #[serde(default)]
+    pub custom_authorizer: Option<CustomAuthorizer>,
+    ///MQTT over WebSocket, the URL is signed with AWS Signature Version 4
+    #[serde(default)]
+    pub sigv4: Option<SigV4>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CustomAuthorizer {
+    ///Authorizer name
+    pub name: String,
+    ///Token key name and value, passed to the authorizer
+    pub token_key: String,
+    pub token: String,
+    ///PEM private key (PKCS#8) signing the token, if the authorizer has token signing enabled
+    #[serde(default)]
+    pub signing_key: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SigV4 {
+    pub region: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    ///Session token of the temporary credentials
+    #[serde(default)]
+    pub session_token: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Azure {
+    ///Shared access key (base64) of the device, or of the shared access policy key_name
+    #[serde(default)]
+    pub shared_access_key: String,
+    #[serde(default)]
+    pub key_name: Option<String>,
+    ///Lifetime of the SAS tokens, a new token is signed and the bridge reconnects at 80% of it
+    #[serde(default = "Azure::token_ttl_default", deserialize_with = "deserialize_duration")]
+    pub token_ttl: Duration,
+    #[serde(default = "Azure::api_version_default")]
+    pub api_version: String,
+    ///Property of the device-to-cloud messages carrying the local topic, not set if empty
+    #[serde(default = "Azure::topic_property_default")]
+    pub topic_property: String,
+    #[serde(default)]
+    pub c2d: C2d,
+}
+
+impl Default for Azure {
+    fn default() -> Self {
+        Self {
+            shared_access_key: String::default(),
+            key_name: None,
+            token_ttl: Self::token_ttl_default(),
+            api_version: Self::api_version_default(),
+            topic_property: Self::topic_property_default(),
+            c2d: C2d::default(),
+        }
+    }
+}
+
+impl Azure {
+    fn token_ttl_default() -> Duration {
+        Duration::from_secs(3600)
+    }
+
+    fn api_version_default() -> String {
+        "2021-04-12".into()
+    }
+
+    fn topic_property_default() -> String {
+        "mqtt-topic".into()
+    }
+}
+
+///Cloud-to-device messages, published locally with their properties as user properties
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct C2d {
+    #[serde(default)]
+    pub enable: bool,
+    ///Local topic, supported placeholders: ${azure.device}
+    #[serde(default = "C2d::topic_default")]
+    pub topic: String,
+    #[serde(default)]
+    pub storage_available: bool,
+    #[serde(default = "C2d::expiry_interval_default", deserialize_with = "deserialize_duration")]
+    pub expiry_interval: Duration,
+}
+
+impl Default for C2d {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            topic: Self::topic_default(),
+            storage_available: false,
+            expiry_interval: Self::expiry_interval_default(),
+        }
+    }
+}
+
+impl C2d {
+    fn topic_default() -> String {
+        "cloud/${azure.device}/c2d".into()
+    }
+
+    fn expiry_interval_default() -> Duration {
+        Duration::from_secs(300)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Entry {
+    #[serde(default)]
+    pub local: Local,
+    #[serde(default)]
+    pub remote: Remote,
+}
+
+type TopicsType = (Arc<TopicTree<()>>, Vec<String>);
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Local {
+    ///Local topic filters to forward
+    #[serde(
+        default = "Local::topics_default",
+        deserialize_with = "Local::deserialize_topics",
+        serialize_with = "Local::serialize_topics"
+    )]
+    pub topics: TopicsType,
+}
+
+impl Default for Local {
+    fn default() -> Self {
+        Self { topics: Self::topics_default() }
+    }
+}
+
+impl Local {
+    fn topics_default() -> TopicsType {
+        (Arc::new(TopicTree::default()), Vec::new())
+    }
+
+    #[inline]
+    pub fn is_match(&self, topic: &Topic) -> bool {
+        self.topics.0.is_match(topic)
+    }
+
+    fn serialize_topics<S>(topics: &TopicsType, s: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: ser::Serializer,
+    {
+        topics.1.serialize(s)
+    }
+
+    fn deserialize_topics<'de, D>(deserializer: D) -> std::result::Result<TopicsType, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let topics_cfg: Vec<String> = Vec::deserialize(deserializer)?;
+        let mut topics = TopicTree::default();
+        for topic in topics_cfg.iter() {
+            topics.insert(&Topic::from_str(topic).map_err(|e| de::Error::custom(format!("{:?}", e)))?, ());
+        }
+        Ok((Arc::new(topics), topics_cfg))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Remote {
+    ///Remote topic, supported placeholders: ${local.topic}, ${local.clientid}, ${aws.thing}
+    #[serde(default = "Remote::topic_default")]
+    pub topic: String,
+    ///QoS of the remote message, the local QoS if not set, AWS IoT does not support QoS 2
+    #[serde(default, deserialize_with = "Remote::deserialize_qos")]
+    pub qos: Option<QoS>,
+    ///Retain flag of the remote message, the local one if not set
+    #[serde(default)]
+    pub retain: Option<bool>,
+    ///Shadow helper, aws_iot only, the message is forwarded to the update topic of the shadow, "" is
+    ///the classic shadow, its JSON payload is the reported state
+    #[serde(default)]
+    pub shadow: Option<String>,
+}
+
+impl Default for Remote {
+    fn default() -> Self {
+        Self { topic: Self::topic_default(), qos: None, retain: None, shadow: None }
+    }
+}
+
+impl Remote {
+    fn topic_default() -> String {
+        "${local.topic}".into()
+    }
+
+    #[inline]
+    pub fn make_topic(&self, topic: &str, clientid: &ClientId, thing: &str) -> String {
+        self.topic
+            .replace("${local.topic}", topic)
+            .replace("${local.clientid}", clientid)
+            .replace("${aws.thing}", thing)
+    }
+
+    #[inline]
+    pub fn deserialize_qos<'de, D>(deserializer: D) -> Result<Option<QoS>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        match u8::deserialize(deserializer)? {
+            0 => Ok(Some(QoS::AtMostOnce)),
+            1 => Ok(Some(QoS::AtLeastOnce)),
+            2 => Ok(Some(QoS::ExactlyOnce)),
+            _ => Err(de::Error::custom("invalid value")),
+        }
+    }
+}
@@ -0,0 +1,116 @@
+#![deny(unsafe_code)]
+#[macro_use]
+extern crate serde;
+
+#[macro_use]
+extern crate rmqtt_macros;
+
+use std::ops::Deref;
+use std::sync::Arc;
+
+use rmqtt::{
+    async_trait::async_trait,
+    log,
+    serde_json::{self, json},
+    tokio::sync::RwLock,
+};
+use rmqtt::{
+    broker::hook::{Handler, HookResult, Parameter, Register, ReturnType, Type},
+    plugin::{PackageInfo, Plugin},
+    register, Result, Runtime,
+};
+
+use bridge::BridgeManager;
+use config::PluginConfig;
+
+mod aws;
+mod bridge;
+mod config;
+mod token;
+
+register!(BridgeMqttEgressPlugin::new);
+
+#[derive(Plugin)]
+struct BridgeMqttEgressPlugin {
+    _runtime: &'static Runtime,
+    cfg: Arc<RwLock<PluginConfig>>,
+    register: Box<dyn Register>,
+    bridge_mgr: BridgeManager,
+}
+
+impl BridgeMqttEgressPlugin {
+    #[inline]
+    async fn new(runtime: &'static Runtime, name: &'static str) -> Result<Self> {
+        let cfg = Arc::new(RwLock::new(runtime.settings.plugins.load_config::<PluginConfig>(name)?));
+        log::info!("{} BridgeMqttEgressPlugin cfg: {:?}", name, cfg.read().await);
+        let register = runtime.extends.hook_mgr().await.register();
+        let bridge_mgr = BridgeManager::new(runtime.node.id(), cfg.clone());
+        Ok(Self { _runtime: runtime, cfg, register, bridge_mgr })
+    }
+}
+
+#[async_trait]
+impl Plugin for BridgeMqttEgressPlugin {
+    #[inline]
+    async fn init(&mut self) -> Result<()> {
+        log::info!("{} init", self.name());
+        self.register.add(Type::MessagePublish, Box::new(HookHandler::new(self.bridge_mgr.clone()))).await;
+        Ok(())
+    }
+
+    #[inline]
+    async fn start(&mut self) -> Result<()> {
+        log::info!("{} start", self.name());
+        self.bridge_mgr.start().await?;
+        self.register.start().await;
+        Ok(())
+    }
+
+    #[inline]
+    async fn stop(&mut self) -> Result<bool> {
+        log::info!("{} stop", self.name());
+        self.register.stop().await;
+        self.bridge_mgr.stop().await;
+        Ok(true)
+    }
+
+    #[inline]
+    async fn get_config(&self) -> Result<serde_json::Value> {
+        Ok(serde_json::to_value(self.cfg.read().await.deref())?)
+    }
+
+    #[inline]
+    async fn attrs(&self) -> serde_json::Value {
+        json!({
+            "bridges": self.bridge_mgr.to_json()
+        })
+    }
+}
+
+struct HookHandler {
+    bridge_mgr: BridgeManager,
+}
+
+impl HookHandler {
+    fn new(bridge_mgr: BridgeManager) -> Self {
+        Self { bridge_mgr }
+    }
+}
+
+#[async_trait]
+impl Handler for HookHandler {
+    async fn hook(&self, param: &Parameter, _acc: &mut Option<HookResult>) -> ReturnType {
+        match param {
+            Parameter::MessagePublish(_s, f, p) => {
+                log::debug!("{:?} MessagePublish, topic: {}", f.id, p.topic);
+                if let Err(e) = self.bridge_mgr.send(f, p) {
+                    log::warn!("{:?} forward message to the remote MQTT broker error, {:?}", f.id, e);
+                }
+            }
+            _ => {
+                log::error!("unimplemented, {:?}", param)
+            }
+        }
+        true
+    }
+}
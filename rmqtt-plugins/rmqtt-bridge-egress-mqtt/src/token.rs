@@ -0,0 +1,67 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use rmqtt::{
+    base64::{engine::general_purpose, Engine as _},
+    url::form_urlencoded,
+    MqttError, Result,
+};
+
+use crate::config::Azure;
+
+///Password of a cloud uplink, a token signed again before it expires, implemented per cloud
+pub(crate) trait TokenProvider: Send + Sync {
+    ///A new token and its lifetime
+    fn token(&self) -> Result<(String, Duration)>;
+}
+
+///Azure IoT Hub shared access signature, of the device or of a shared access policy
+pub(crate) struct AzureSas {
+    //{hub}.azure-devices.net/devices/{device id}
+    resource_uri: String,
+    key: Vec<u8>,
+    key_name: Option<String>,
+    ttl: Duration,
+}
+
+impl AzureSas {
+    pub(crate) fn new(hub: &str, device_id: &str, cfg: &Azure) -> Result<Self> {
+        let key = general_purpose::STANDARD
+            .decode(&cfg.shared_access_key)
+            .map_err(|e| MqttError::from(format!("invalid shared access key, {}", e)))?;
+        Ok(Self {
+            resource_uri: format!("{}/devices/{}", hub, device_id),
+            key,
+            key_name: cfg.key_name.clone(),
+            ttl: cfg.token_ttl,
+        })
+    }
+}
+
+impl TokenProvider for AzureSas {
+    fn token(&self) -> Result<(String, Duration)> {
+        let expiry = (SystemTime::now() + self.ttl).duration_since(UNIX_EPOCH).map_err(to_error)?.as_secs();
+        let resource_uri = encode(&self.resource_uri);
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).map_err(to_error)?;
+        mac.update(format!("{}\n{}", resource_uri, expiry).as_bytes());
+        let signature = general_purpose::STANDARD.encode(mac.finalize().into_bytes());
+        let mut token =
+            format!("SharedAccessSignature sr={}&sig={}&se={}", resource_uri, encode(&signature), expiry);
+        if let Some(key_name) = self.key_name.as_ref() {
+            token.push_str(&format!("&skn={}", encode(key_name)));
+        }
+        Ok((token, self.ttl))
+    }
+}
+
+#[inline]
+fn to_error<E: std::fmt::Display>(e: E) -> MqttError {
+    MqttError::from(e.to_string())
+}
+
+#[inline]
+pub(crate) fn encode(s: &str) -> String {
+    form_urlencoded::byte_serialize(s.as_bytes()).collect()
+}
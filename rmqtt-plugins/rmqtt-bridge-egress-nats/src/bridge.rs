@@ -0,0 +1,391 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_nats::connection::State;
+use async_nats::jetstream::{self, consumer::pull, consumer::AckPolicy, AckKind};
+use async_nats::{ConnectOptions, HeaderMap};
+
+use rmqtt::broker::idempotency::{DeliveredKeys, IdempotencyKey};
+use rmqtt::{
+    anyhow::anyhow,
+    futures::StreamExt,
+    log,
+    serde_json::{self, json},
+    timestamp_millis,
+    tokio::{self, sync::mpsc, sync::RwLock, task::JoinHandle},
+    DashMap,
+};
+use rmqtt::{
+    ClientId, From, FromType, Id, NodeId, Publish, PublishProperties, QoS, QoSEx, Result, Runtime,
+    SessionState, Topic, UserName,
+};
+
+use crate::config::{Bridge, IngressEntry, PluginConfig};
+
+type EntryIdx = usize;
+type Message = (EntryIdx, From, Publish);
+
+//Interval before the consumer is created again, after the stream or the consumer is not available
+const CONSUME_RETRY_INTERVAL: Duration = Duration::from_secs(3);
+
+#[derive(Default)]
+pub(crate) struct Metrics {
+    sents: AtomicUsize,
+    acks: AtomicUsize,
+    fails: AtomicUsize,
+    drops: AtomicUsize,
+    receiveds: AtomicUsize,
+    naks: AtomicUsize,
+}
+
+impl Metrics {
+    #[inline]
+    fn to_json(&self) -> serde_json::Value {
+        json!({
+            "sents": self.sents.load(Ordering::SeqCst),
+            "acks": self.acks.load(Ordering::SeqCst),
+            "fails": self.fails.load(Ordering::SeqCst),
+            "drops": self.drops.load(Ordering::SeqCst),
+            "receiveds": self.receiveds.load(Ordering::SeqCst),
+            "naks": self.naks.load(Ordering::SeqCst),
+        })
+    }
+}
+
+pub(crate) struct Client {
+    cfg: Arc<Bridge>,
+    client: async_nats::Client,
+    tx: mpsc::Sender<Message>,
+    dedup: Option<Arc<DeliveredKeys>>,
+    metrics: Arc<Metrics>,
+    consumers: Vec<JoinHandle<()>>,
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        for c in self.consumers.iter() {
+            c.abort();
+        }
+    }
+}
+
+impl Client {
+    async fn connect(cfg: Bridge, node_id: NodeId) -> Result<Client> {
+        let mut opts = if let Some(creds) = cfg.credentials.as_ref() {
+            ConnectOptions::with_credentials_file(PathBuf::from(creds)).await?
+        } else {
+            ConnectOptions::new()
+        };
+        let client_name =
+            cfg.client_name.clone().unwrap_or_else(|| format!("rmqtt:{}:egress:{}", cfg.name, node_id));
+        opts = opts.name(client_name).connection_timeout(cfg.connect_timeout).retry_on_initial_connect();
+        if let (Some(username), Some(password)) = (cfg.username.as_ref(), cfg.password.as_ref()) {
+            opts = opts.user_and_password(username.clone(), password.clone());
+        }
+        if let Some(token) = cfg.token.as_ref() {
+            opts = opts.token(token.clone());
+        }
+        if cfg.tls.enable {
+            opts = opts.require_tls(true);
+            if let Some(ca) = cfg.tls.ca.as_ref() {
+                opts = opts.add_root_certificates(PathBuf::from(ca));
+            }
+            if let (Some(cert), Some(key)) = (cfg.tls.cert.as_ref(), cfg.tls.key.as_ref()) {
+                opts = opts.add_client_certificate(PathBuf::from(cert), PathBuf::from(key));
+            }
+        }
+
+        let client = opts.connect(cfg.servers.as_str()).await.map_err(|e| anyhow!(e))?;
+        log::info!("{} connected to NATS {:?}", cfg.name, cfg.servers);
+
+        let (tx, rx) = mpsc::channel(cfg.queue_capacity);
+        let dedup = DeliveredKeys::new(&cfg.dedup)?;
+        let cfg = Arc::new(cfg);
+        let metrics = Arc::new(Metrics::default());
+        tokio::spawn(Self::forward_loop(cfg.clone(), client.clone(), rx, dedup.clone(), metrics.clone()));
+        let consumers = Self::start_consumers(&cfg, &client, node_id, &metrics);
+        Ok(Client { cfg, client, tx, dedup, metrics, consumers })
+    }
+
+    async fn forward_loop(
+        cfg: Arc<Bridge>,
+        client: async_nats::Client,
+        mut rx: mpsc::Receiver<Message>,
+        dedup: Option<Arc<DeliveredKeys>>,
+        metrics: Arc<Metrics>,
+    ) {
+        let js = jetstream::new(client.clone());
+        while let Some((entry_idx, f, p)) = rx.recv().await {
+            let entry = if let Some(entry) = cfg.entries.get(entry_idx) { entry } else { unreachable!() };
+            let subject = entry.remote.make_subject(&p.topic, &f.id.client_id);
+            let key = dedup.as_ref().and_then(|d| d.key(&p));
+            let res = if cfg.jetstream {
+                Self::publish_ack(&cfg, &js, subject, &f, &p, key).await
+            } else {
+                client
+                    .publish_with_headers(subject, Self::headers(&f, &p, key), p.payload.clone())
+                    .await
+                    .map_err(|e| anyhow!(e).into())
+            };
+            match res {
+                Ok(()) => {
+                    metrics.sents.fetch_add(1, Ordering::SeqCst);
+                    if cfg.jetstream {
+                        metrics.acks.fetch_add(1, Ordering::SeqCst);
+                    }
+                    if let (Some(dedup), Some(key)) = (dedup.as_ref(), key) {
+                        dedup.delivered(key);
+                    }
+                }
+                Err(e) => {
+                    metrics.fails.fetch_add(1, Ordering::SeqCst);
+                    log::warn!("{} forward to NATS error, topic: {}, {:?}", cfg.name, p.topic, e);
+                }
+            }
+        }
+        log::info!("{} exit NATS egress bridge", cfg.name);
+    }
+
+    //At-least-once, resend the message if JetStream does not acknowledge it.
+    async fn publish_ack(
+        cfg: &Bridge,
+        js: &jetstream::Context,
+        subject: String,
+        f: &From,
+        p: &Publish,
+        key: Option<IdempotencyKey>,
+    ) -> Result<()> {
+        let mut retries = 0;
+        loop {
+            let res = match js
+                .publish_with_headers(subject.clone(), Self::headers(f, p, key), p.payload.clone())
+                .await
+            {
+                Ok(ack_fut) => ack_fut.await.map(|_| ()).map_err(|e| anyhow!(e)),
+                Err(e) => Err(anyhow!(e)),
+            };
+            match res {
+                Ok(()) => return Ok(()),
+                Err(e) if retries < cfg.ack_retries => {
+                    retries += 1;
+                    log::debug!("{} JetStream ack error, retries: {}, {:?}", cfg.name, retries, e);
+                    tokio::time::sleep(cfg.ack_retry_interval).await;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    fn start_consumers(
+        cfg: &Arc<Bridge>,
+        client: &async_nats::Client,
+        node_id: NodeId,
+        metrics: &Arc<Metrics>,
+    ) -> Vec<JoinHandle<()>> {
+        let js = jetstream::new(client.clone());
+        cfg.ingress
+            .iter()
+            .enumerate()
+            .map(|(entry_idx, entry)| {
+                let (cfg, js, entry, metrics) = (cfg.clone(), js.clone(), entry.clone(), metrics.clone());
+                tokio::spawn(async move {
+                    loop {
+                        if let Err(e) = Self::consume(&cfg, &js, node_id, entry_idx, &entry, &metrics).await {
+                            log::warn!("{} consume {:?} error, {:?}", cfg.name, entry.remote.stream, e);
+                        }
+                        tokio::time::sleep(CONSUME_RETRY_INTERVAL).await;
+                    }
+                })
+            })
+            .collect()
+    }
+
+    async fn consume(
+        cfg: &Bridge,
+        js: &jetstream::Context,
+        node_id: NodeId,
+        entry_idx: EntryIdx,
+        entry: &IngressEntry,
+        metrics: &Metrics,
+    ) -> Result<()> {
+        //QoS 0 => no acknowledgements, QoS 1/2 => ack after the message has been forwarded, nak on error
+        let ack = !matches!(entry.local.qos, QoS::AtMostOnce);
+        let durable_name =
+            entry.remote.consumer.clone().unwrap_or_else(|| format!("rmqtt-{}-{}", cfg.name, entry_idx));
+        let stream = js.get_stream(&entry.remote.stream).await.map_err(|e| anyhow!(e))?;
+        let consumer = stream
+            .get_or_create_consumer(
+                &durable_name,
+                pull::Config {
+                    durable_name: Some(durable_name.clone()),
+                    filter_subject: entry.remote.subject.clone(),
+                    ack_policy: if ack { AckPolicy::Explicit } else { AckPolicy::None },
+                    max_ack_pending: entry.remote.max_ack_pending,
+                    ..Default::default()
+                },
+            )
+            .await
+            .map_err(|e| anyhow!(e))?;
+        let mut messages = consumer.messages().await.map_err(|e| anyhow!(e))?;
+        log::info!(
+            "{} Successfully consumed from {:?}, consumer: {}",
+            cfg.name,
+            entry.remote.stream,
+            durable_name
+        );
+
+        let client_id = ClientId::from(format!("{}:ingress:{}:{}", cfg.name, node_id, entry_idx));
+        let from = From::from_bridge(Id::new(
+            node_id,
+            None,
+            None,
+            client_id,
+            Some(UserName::from(cfg.name.as_str())),
+        ));
+        while let Some(msg) = messages.next().await {
+            let msg = msg.map_err(|e| anyhow!(e))?;
+            metrics.receiveds.fetch_add(1, Ordering::SeqCst);
+            let p = Publish {
+                dup: false,
+                retain: entry.local.retain,
+                qos: entry.local.qos,
+                topic: entry.local.make_topic(&msg.message.subject),
+                packet_id: None,
+                payload: msg.message.payload.to_vec().into(),
+                properties: PublishProperties::default(),
+                create_time: timestamp_millis(),
+            };
+            let res = send_publish(from.clone(), p, entry).await;
+            if ack {
+                let ack_res = if let Err(e) = res {
+                    metrics.naks.fetch_add(1, Ordering::SeqCst);
+                    log::warn!("{} forward error, {:?}", cfg.name, e);
+                    msg.ack_with(AckKind::Nak(None)).await
+                } else {
+                    msg.ack().await
+                };
+                ack_res.map_err(|e| anyhow!(e))?;
+            }
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn headers(f: &From, p: &Publish, key: Option<IdempotencyKey>) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("Mqtt-Topic", p.topic.as_ref());
+        headers.insert("Mqtt-Qos", p.qos.value().to_string().as_str());
+        headers.insert("Mqtt-Retain", if p.retain { "true" } else { "false" });
+        headers.insert("Mqtt-ClientId", f.id.client_id.as_ref());
+        //JetStream also deduplicates by the message id, within the duplicate window of the stream
+        if let Some(key) = key {
+            headers.insert("Nats-Msg-Id", key.to_string().as_str());
+        }
+        headers
+    }
+
+    #[inline]
+    fn to_json(&self) -> serde_json::Value {
+        json!({
+            "name": self.cfg.name,
+            "servers": self.cfg.servers,
+            "connected": matches!(self.client.connection_state(), State::Connected),
+            "jetstream": self.cfg.jetstream,
+            "queue_len": self.tx.max_capacity() - self.tx.capacity(),
+            "metrics": self.metrics.to_json(),
+            "dedup": self.dedup.as_ref().map(|d| d.to_json()),
+        })
+    }
+}
+
+async fn send_publish(from: From, msg: Publish, entry: &IngressEntry) -> Result<()> {
+    log::debug!("from {:?}, message: {:?}", from, msg);
+    //hook, message_publish
+    let msg =
+        match Runtime::instance().extends.hook_mgr().await.message_publish(None, from.clone(), &msg).await {
+            Ok(modified) => modified.unwrap_or(msg),
+            //dropped by a handler
+            Err(_) => return Ok(()),
+        };
+
+    SessionState::forwards(
+        from,
+        msg,
+        entry.retain_available,
+        entry.storage_available,
+        Some(entry.expiry_interval),
+    )
+    .await?;
+    Ok(())
+}
+
+#[derive(Clone)]
+pub(crate) struct BridgeManager {
+    node_id: NodeId,
+    cfg: Arc<RwLock<PluginConfig>>,
+    clients: Arc<DashMap<String, Client>>,
+}
+
+impl BridgeManager {
+    pub fn new(node_id: NodeId, cfg: Arc<RwLock<PluginConfig>>) -> Self {
+        Self { node_id, cfg, clients: Arc::new(DashMap::default()) }
+    }
+
+    pub async fn start(&mut self) -> Result<()> {
+        let bridges = self.cfg.read().await.bridges.clone();
+        for b_cfg in bridges {
+            if !b_cfg.enable {
+                continue;
+            }
+            let name = b_cfg.name.clone();
+            let client = Client::connect(b_cfg, self.node_id).await?;
+            self.clients.insert(name, client);
+        }
+        Ok(())
+    }
+
+    pub async fn stop(&mut self) {
+        for entry in self.clients.iter() {
+            log::debug!("stop bridge_name: {:?}", entry.key());
+        }
+        //Dropping the sender ends the forwarding task, the consumers are aborted
+        self.clients.clear();
+    }
+
+    #[inline]
+    pub(crate) fn send(&self, f: &From, p: &Publish) -> Result<()> {
+        //Messages received from the bridge itself are not sent back
+        if matches!(f.typ(), FromType::Bridge)
+            && self.clients.contains_key(f.id.username.as_deref().unwrap_or_default())
+        {
+            return Ok(());
+        }
+        let topic = Topic::from_str(&p.topic)?;
+        for client in self.clients.iter() {
+            if let Some(dedup) = client.dedup.as_ref() {
+                if dedup.key(p).map(|key| dedup.is_delivered(key)).unwrap_or(false) {
+                    log::debug!("{} skip the delivered message, topic: {}", client.cfg.name, p.topic);
+                    continue;
+                }
+            }
+            for (entry_idx, entry) in client.cfg.entries.iter().enumerate() {
+                if !entry.local.is_match(&topic) {
+                    continue;
+                }
+                if let Err(e) = client.tx.try_send((entry_idx, f.clone(), p.clone())) {
+                    client.metrics.drops.fetch_add(1, Ordering::SeqCst);
+                    log::warn!("{} forward queue error, {}", client.cfg.name, e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[inline]
+    pub(crate) fn to_json(&self) -> Vec<serde_json::Value> {
+        self.clients.iter().map(|entry| entry.value().to_json()).collect()
+    }
+}
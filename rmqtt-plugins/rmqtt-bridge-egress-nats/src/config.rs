@@ -0,0 +1,273 @@
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::de::{self, Deserialize, Deserializer};
+use serde::ser::{self, Serialize};
+
+use rmqtt::broker::idempotency::DedupConfig;
+use rmqtt::broker::topic::TopicTree;
+use rmqtt::settings::deserialize_duration;
+use rmqtt::{ClientId, QoS, Topic, TopicName};
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PluginConfig {
+    #[serde(default)]
+    pub bridges: Vec<Bridge>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Bridge {
+    #[serde(default)]
+    pub enable: bool,
+    #[serde(default)]
+    pub name: String,
+    ///NATS server addresses, e.g. "nats://127.0.0.1:4222,nats://127.0.0.1:4223"
+    #[serde(default = "Bridge::servers_default")]
+    pub servers: String,
+    #[serde(default)]
+    pub client_name: Option<String>,
+
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default)]
+    pub token: Option<String>,
+    ///NATS credentials file (JWT and NKey seed)
+    #[serde(default)]
+    pub credentials: Option<String>,
+
+    #[serde(default)]
+    pub tls: Tls,
+
+    #[serde(default = "Bridge::connect_timeout_default", deserialize_with = "deserialize_duration")]
+    pub connect_timeout: Duration,
+
+    ///Publish to JetStream and wait for the acknowledgement
+    #[serde(default)]
+    pub jetstream: bool,
+    ///Maximum number of retries if JetStream does not acknowledge the message
+    #[serde(default = "Bridge::ack_retries_default")]
+    pub ack_retries: usize,
+    #[serde(default = "Bridge::ack_retry_interval_default", deserialize_with = "deserialize_duration")]
+    pub ack_retry_interval: Duration,
+
+    ///Capacity of the forwarding queue, messages are dropped when the queue is full
+    #[serde(default = "Bridge::queue_capacity_default")]
+    pub queue_capacity: usize,
+
+    ///Skips the messages whose idempotency key was delivered
+    #[serde(default)]
+    pub dedup: DedupConfig,
+
+    #[serde(default)]
+    pub entries: Vec<Entry>,
+    ///JetStream consumers, NATS => MQTT
+    #[serde(default)]
+    pub ingress: Vec<IngressEntry>,
+}
+
+impl Bridge {
+    fn servers_default() -> String {
+        "nats://127.0.0.1:4222".into()
+    }
+
+    fn connect_timeout_default() -> Duration {
+        Duration::from_secs(10)
+    }
+
+    fn ack_retries_default() -> usize {
+        3
+    }
+
+    fn ack_retry_interval_default() -> Duration {
+        Duration::from_secs(1)
+    }
+
+    fn queue_capacity_default() -> usize {
+        100_000
+    }
+}
+
+#[derive(Default, Debug, Clone, Deserialize, Serialize)]
+pub struct Tls {
+    #[serde(default)]
+    pub enable: bool,
+    ///Root certificate file
+    #[serde(default)]
+    pub ca: Option<String>,
+    ///Client certificate and key file
+    #[serde(default)]
+    pub cert: Option<String>,
+    #[serde(default)]
+    pub key: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Entry {
+    #[serde(default)]
+    pub local: Local,
+    #[serde(default)]
+    pub remote: Remote,
+}
+
+type TopicsType = (Arc<TopicTree<()>>, Vec<String>);
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Local {
+    ///Local topic filters to forward
+    #[serde(
+        default = "Local::topics_default",
+        deserialize_with = "Local::deserialize_topics",
+        serialize_with = "Local::serialize_topics"
+    )]
+    pub topics: TopicsType,
+}
+
+impl Default for Local {
+    fn default() -> Self {
+        Self { topics: Self::topics_default() }
+    }
+}
+
+impl Local {
+    fn topics_default() -> TopicsType {
+        (Arc::new(TopicTree::default()), Vec::new())
+    }
+
+    #[inline]
+    pub fn is_match(&self, topic: &Topic) -> bool {
+        self.topics.0.is_match(topic)
+    }
+
+    fn serialize_topics<S>(topics: &TopicsType, s: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: ser::Serializer,
+    {
+        topics.1.serialize(s)
+    }
+
+    fn deserialize_topics<'de, D>(deserializer: D) -> std::result::Result<TopicsType, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let topics_cfg: Vec<String> = Vec::deserialize(deserializer)?;
+        let mut topics = TopicTree::default();
+        for topic in topics_cfg.iter() {
+            topics.insert(&Topic::from_str(topic).map_err(|e| de::Error::custom(format!("{:?}", e)))?, ());
+        }
+        Ok((Arc::new(topics), topics_cfg))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Remote {
+    ///NATS subject, supported placeholders: ${local.topic}, ${local.clientid}
+    #[serde(default = "Remote::subject_default")]
+    pub subject: String,
+}
+
+impl Default for Remote {
+    fn default() -> Self {
+        Self { subject: Self::subject_default() }
+    }
+}
+
+impl Remote {
+    fn subject_default() -> String {
+        "${local.topic}".into()
+    }
+
+    ///MQTT topic levels are mapped to NATS subject tokens, "a/b/c" => "a.b.c"
+    #[inline]
+    pub fn make_subject(&self, topic: &str, clientid: &ClientId) -> String {
+        self.subject
+            .replace("${local.topic}", &topic.replace(['.', ' '], "_").replace('/', "."))
+            .replace("${local.clientid}", clientid)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct IngressEntry {
+    #[serde(default)]
+    pub remote: IngressRemote,
+    #[serde(default)]
+    pub local: IngressLocal,
+
+    #[serde(default)]
+    pub retain_available: bool,
+    #[serde(default)]
+    pub storage_available: bool,
+    #[serde(default = "IngressEntry::expiry_interval_default", deserialize_with = "deserialize_duration")]
+    pub expiry_interval: Duration,
+}
+
+impl IngressEntry {
+    fn expiry_interval_default() -> Duration {
+        Duration::from_secs(300)
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct IngressRemote {
+    ///JetStream stream to consume from
+    #[serde(default)]
+    pub stream: String,
+    ///Durable pull consumer, shared by the nodes of the cluster, rmqtt-${bridge_name}-${entry_index} if not set
+    #[serde(default)]
+    pub consumer: Option<String>,
+    ///Subject filter of the consumer, all subjects of the stream if empty
+    #[serde(default)]
+    pub subject: String,
+    ///Maximum number of unacknowledged messages, 0 means the server default
+    #[serde(default)]
+    pub max_ack_pending: i64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct IngressLocal {
+    ///Local topic template, supported placeholders: ${remote.subject}
+    #[serde(default = "IngressLocal::topic_default")]
+    pub topic: String,
+    ///QoS 0 consumes without acknowledgements, QoS 1 and 2 acknowledge after the message has been forwarded
+    #[serde(default = "IngressLocal::qos_default", deserialize_with = "IngressLocal::deserialize_qos")]
+    pub qos: QoS,
+    #[serde(default)]
+    pub retain: bool,
+}
+
+impl Default for IngressLocal {
+    fn default() -> Self {
+        Self { topic: Self::topic_default(), qos: Self::qos_default(), retain: false }
+    }
+}
+
+impl IngressLocal {
+    fn topic_default() -> String {
+        "${remote.subject}".into()
+    }
+
+    fn qos_default() -> QoS {
+        QoS::AtLeastOnce
+    }
+
+    ///NATS subject tokens are mapped to MQTT topic levels, "a.b.c" => "a/b/c"
+    #[inline]
+    pub fn make_topic(&self, subject: &str) -> TopicName {
+        TopicName::from(self.topic.replace("${remote.subject}", &subject.replace('.', "/")))
+    }
+
+    #[inline]
+    pub fn deserialize_qos<'de, D>(deserializer: D) -> Result<QoS, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        match u8::deserialize(deserializer)? {
+            0 => Ok(QoS::AtMostOnce),
+            1 => Ok(QoS::AtLeastOnce),
+            2 => Ok(QoS::ExactlyOnce),
+            _ => Err(de::Error::custom("invalid value")),
+        }
+    }
+}
@@ -0,0 +1,312 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use pulsar::authentication::oauth2::{OAuth2Authentication, OAuth2Params};
+use pulsar::compression::{
+    Compression as PulsarCompression, CompressionLz4, CompressionSnappy, CompressionZlib, CompressionZstd,
+};
+use pulsar::producer::{self, SendFuture};
+use pulsar::{Authentication, ProducerOptions, Pulsar, TokioExecutor};
+
+use rmqtt::broker::idempotency::{DeliveredKeys, IdempotencyKey};
+use rmqtt::{
+    anyhow::anyhow,
+    futures::future::join_all,
+    log,
+    serde_json::{self, json},
+    tokio::{self, sync::mpsc, sync::RwLock, time::Instant},
+    DashMap,
+};
+use rmqtt::{From, NodeId, Publish, QoSEx, Result, Topic};
+
+use crate::config::{Auth, Bridge, Compression, PluginConfig};
+
+type EntryIdx = usize;
+type Message = (EntryIdx, From, Publish);
+
+#[derive(Default)]
+pub(crate) struct Metrics {
+    //Waiting in the forwarding queue
+    queued: AtomicUsize,
+    //Handed to the Pulsar producer, waiting for the receipt
+    pending: AtomicUsize,
+    sents: AtomicUsize,
+    fails: AtomicUsize,
+    drops: AtomicUsize,
+}
+
+impl Metrics {
+    #[inline]
+    fn to_json(&self) -> serde_json::Value {
+        let queued = self.queued.load(Ordering::SeqCst);
+        let pending = self.pending.load(Ordering::SeqCst);
+        json!({
+            "backlog": queued + pending,
+            "queued": queued,
+            "pending": pending,
+            "sents": self.sents.load(Ordering::SeqCst),
+            "fails": self.fails.load(Ordering::SeqCst),
+            "drops": self.drops.load(Ordering::SeqCst),
+        })
+    }
+}
+
+pub(crate) struct Producer {
+    cfg: Arc<Bridge>,
+    tx: mpsc::Sender<Message>,
+    dedup: Option<Arc<DeliveredKeys>>,
+    //One Pulsar producer per entry
+    metrics: Vec<Arc<Metrics>>,
+}
+
+impl Producer {
+    async fn connect(cfg: Bridge, node_id: NodeId) -> Result<Producer> {
+        let mut builder = Pulsar::builder(cfg.server.as_str(), TokioExecutor);
+        match &cfg.auth {
+            Auth::None => {}
+            Auth::Token { token } => {
+                builder = builder
+                    .with_auth(Authentication { name: "token".into(), data: token.as_bytes().to_vec() });
+            }
+            Auth::OAuth2 { issuer_url, credentials_url, audience, scope } => {
+                builder =
+                    builder.with_auth_provider(OAuth2Authentication::client_credentials(OAuth2Params {
+                        issuer_url: issuer_url.clone(),
+                        credentials_url: credentials_url.clone(),
+                        audience: audience.clone(),
+                        scope: scope.clone(),
+                    }));
+            }
+        }
+        let client: Pulsar<TokioExecutor> = builder.build().await.map_err(|e| anyhow!(e))?;
+        log::info!("{} connected to Pulsar {:?}", cfg.name, cfg.server);
+
+        let mut producers = Vec::with_capacity(cfg.entries.len());
+        for (entry_idx, entry) in cfg.entries.iter().enumerate() {
+            let producer = client
+                .producer()
+                .with_topic(entry.remote.topic.as_str())
+                .with_name(format!("rmqtt:{}:egress:{}:{}", cfg.name, node_id, entry_idx))
+                .with_options(ProducerOptions {
+                    batch_size: Some(cfg.batch_size.max(1) as u32),
+                    compression: Some(Self::compression(cfg.compression)),
+                    ..Default::default()
+                })
+                .build()
+                .await
+                .map_err(|e| anyhow!(e))?;
+            producers.push(producer);
+        }
+
+        let (tx, rx) = mpsc::channel(cfg.queue_capacity);
+        let dedup = DeliveredKeys::new(&cfg.dedup)?;
+        let cfg = Arc::new(cfg);
+        let metrics = (0..producers.len()).map(|_| Arc::new(Metrics::default())).collect::<Vec<_>>();
+        tokio::spawn(Self::forward_loop(cfg.clone(), producers, rx, dedup.clone(), metrics.clone()));
+        Ok(Producer { cfg, tx, dedup, metrics })
+    }
+
+    #[inline]
+    fn compression(c: Compression) -> PulsarCompression {
+        match c {
+            Compression::None => PulsarCompression::None,
+            Compression::Lz4 => PulsarCompression::Lz4(CompressionLz4::default()),
+            Compression::Zlib => PulsarCompression::Zlib(CompressionZlib::default()),
+            Compression::Zstd => PulsarCompression::Zstd(CompressionZstd::default()),
+            Compression::Snappy => PulsarCompression::Snappy(CompressionSnappy::default()),
+        }
+    }
+
+    async fn forward_loop(
+        cfg: Arc<Bridge>,
+        mut producers: Vec<pulsar::Producer<TokioExecutor>>,
+        mut rx: mpsc::Receiver<Message>,
+        dedup: Option<Arc<DeliveredKeys>>,
+        metrics: Vec<Arc<Metrics>>,
+    ) {
+        let batch_size = cfg.batch_size.max(1);
+        let mut batch = Vec::with_capacity(batch_size);
+        while let Some(msg) = rx.recv().await {
+            //Collect messages until the batch is full or the linger time has elapsed
+            batch.push(msg);
+            let deadline = Instant::now() + cfg.linger;
+            while batch.len() < batch_size {
+                match tokio::time::timeout_at(deadline, rx.recv()).await {
+                    Ok(Some(msg)) => batch.push(msg),
+                    Ok(None) | Err(_) => break,
+                }
+            }
+            Self::send_batch(&cfg, &mut producers, &dedup, &metrics, batch.drain(..)).await;
+        }
+        log::info!("{} exit Pulsar egress bridge", cfg.name);
+    }
+
+    async fn send_batch(
+        cfg: &Arc<Bridge>,
+        producers: &mut [pulsar::Producer<TokioExecutor>],
+        dedup: &Option<Arc<DeliveredKeys>>,
+        metrics: &[Arc<Metrics>],
+        batch: impl Iterator<Item = Message>,
+    ) {
+        let mut receipts: Vec<((EntryIdx, Option<IdempotencyKey>), SendFuture)> = Vec::new();
+        let mut used = vec![false; producers.len()];
+        for (entry_idx, f, p) in batch {
+            let m = &metrics[entry_idx];
+            m.queued.fetch_sub(1, Ordering::SeqCst);
+            let entry = &cfg.entries[entry_idx];
+            let key = dedup.as_ref().and_then(|d| d.key(&p));
+            let msg = producer::Message {
+                payload: p.payload.to_vec(),
+                properties: Self::properties(&f, &p, key),
+                partition_key: entry.remote.partition_key.make(&p.topic, &f.id.client_id),
+                ..Default::default()
+            };
+            match producers[entry_idx].send_non_blocking(msg).await {
+                Ok(receipt) => {
+                    m.pending.fetch_add(1, Ordering::SeqCst);
+                    used[entry_idx] = true;
+                    receipts.push(((entry_idx, key), receipt));
+                }
+                Err(e) => {
+                    m.fails.fetch_add(1, Ordering::SeqCst);
+                    log::warn!("{} forward to Pulsar error, topic: {}, {:?}", cfg.name, p.topic, e);
+                }
+            }
+        }
+
+        for (entry_idx, producer) in producers.iter_mut().enumerate() {
+            if used[entry_idx] {
+                if let Err(e) = producer.send_batch().await {
+                    log::warn!("{} flush Pulsar batch error, {:?}", cfg.name, e);
+                }
+            }
+        }
+
+        //Receipts are awaited in the background so the next batch can be collected meanwhile
+        let cfg = cfg.clone();
+        let dedup = dedup.clone();
+        let metrics = metrics.to_vec();
+        tokio::spawn(async move {
+            let (idxs, futs): (Vec<_>, Vec<_>) = receipts.into_iter().unzip();
+            for ((entry_idx, key), res) in idxs.into_iter().zip(join_all(futs).await) {
+                let m = &metrics[entry_idx];
+                m.pending.fetch_sub(1, Ordering::SeqCst);
+                match res {
+                    Ok(_) => {
+                        m.sents.fetch_add(1, Ordering::SeqCst);
+                        if let (Some(dedup), Some(key)) = (dedup.as_ref(), key) {
+                            dedup.delivered(key);
+                        }
+                    }
+                    Err(e) => {
+                        m.fails.fetch_add(1, Ordering::SeqCst);
+                        log::warn!("{} Pulsar send receipt error, {:?}", cfg.name, e);
+                    }
+                }
+            }
+        });
+    }
+
+    #[inline]
+    fn properties(f: &From, p: &Publish, key: Option<IdempotencyKey>) -> HashMap<String, String> {
+        let mut props = HashMap::default();
+        props.insert("mqtt_topic".into(), p.topic.to_string());
+        props.insert("mqtt_qos".into(), p.qos.value().to_string());
+        props.insert("mqtt_retain".into(), p.retain.to_string());
+        props.insert("mqtt_clientid".into(), f.id.client_id.to_string());
+        if let Some(key) = key {
+            props.insert("mqtt_idempotency_key".into(), key.to_string());
+        }
+        props
+    }
+
+    #[inline]
+    fn to_json(&self) -> serde_json::Value {
+        let producers = self
+            .cfg
+            .entries
+            .iter()
+            .zip(self.metrics.iter())
+            .map(|(entry, m)| {
+                json!({
+                    "topic": entry.remote.topic,
+                    "metrics": m.to_json(),
+                })
+            })
+            .collect::<Vec<_>>();
+        json!({
+            "name": self.cfg.name,
+            "server": self.cfg.server,
+            "queue_len": self.tx.max_capacity() - self.tx.capacity(),
+            "producers": producers,
+            "dedup": self.dedup.as_ref().map(|d| d.to_json()),
+        })
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct BridgeManager {
+    node_id: NodeId,
+    cfg: Arc<RwLock<PluginConfig>>,
+    producers: Arc<DashMap<String, Producer>>,
+}
+
+impl BridgeManager {
+    pub fn new(node_id: NodeId, cfg: Arc<RwLock<PluginConfig>>) -> Self {
+        Self { node_id, cfg, producers: Arc::new(DashMap::default()) }
+    }
+
+    pub async fn start(&mut self) -> Result<()> {
+        let bridges = self.cfg.read().await.bridges.clone();
+        for b_cfg in bridges {
+            if !b_cfg.enable {
+                continue;
+            }
+            let name = b_cfg.name.clone();
+            let producer = Producer::connect(b_cfg, self.node_id).await?;
+            self.producers.insert(name, producer);
+        }
+        Ok(())
+    }
+
+    pub async fn stop(&mut self) {
+        for entry in self.producers.iter() {
+            log::debug!("stop bridge_name: {:?}", entry.key());
+        }
+        //Dropping the sender ends the forwarding task
+        self.producers.clear();
+    }
+
+    #[inline]
+    pub(crate) fn send(&self, f: &From, p: &Publish) -> Result<()> {
+        let topic = Topic::from_str(&p.topic)?;
+        for producer in self.producers.iter() {
+            if let Some(dedup) = producer.dedup.as_ref() {
+                if dedup.key(p).map(|key| dedup.is_delivered(key)).unwrap_or(false) {
+                    log::debug!("{} skip the delivered message, topic: {}", producer.cfg.name, p.topic);
+                    continue;
+                }
+            }
+            for (entry_idx, entry) in producer.cfg.entries.iter().enumerate() {
+                if !entry.local.is_match(&topic) {
+                    continue;
+                }
+                let m = &producer.metrics[entry_idx];
+                m.queued.fetch_add(1, Ordering::SeqCst);
+                if let Err(e) = producer.tx.try_send((entry_idx, f.clone(), p.clone())) {
+                    m.queued.fetch_sub(1, Ordering::SeqCst);
+                    m.drops.fetch_add(1, Ordering::SeqCst);
+                    log::warn!("{} forward queue error, {}", producer.cfg.name, e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[inline]
+    pub(crate) fn to_json(&self) -> Vec<serde_json::Value> {
+        self.producers.iter().map(|entry| entry.value().to_json()).collect()
+    }
+}
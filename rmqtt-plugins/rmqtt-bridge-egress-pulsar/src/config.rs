@@ -0,0 +1,220 @@
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::de::{self, Deserialize, Deserializer};
+use serde::ser::{self, Serialize};
+
+use rmqtt::broker::idempotency::DedupConfig;
+use rmqtt::broker::topic::TopicTree;
+use rmqtt::settings::deserialize_duration;
+use rmqtt::{ClientId, Topic};
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PluginConfig {
+    #[serde(default)]
+    pub bridges: Vec<Bridge>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Bridge {
+    #[serde(default)]
+    pub enable: bool,
+    #[serde(default)]
+    pub name: String,
+    ///Pulsar service URL, e.g. "pulsar://127.0.0.1:6650"
+    #[serde(default = "Bridge::server_default")]
+    pub server: String,
+    #[serde(default)]
+    pub auth: Auth,
+
+    ///Maximum number of messages in a batch
+    #[serde(default = "Bridge::batch_size_default")]
+    pub batch_size: usize,
+    ///How long to wait for more messages before a partial batch is sent
+    #[serde(default = "Bridge::linger_default", deserialize_with = "deserialize_duration")]
+    pub linger: Duration,
+    ///none, lz4, zlib, zstd, snappy
+    #[serde(default)]
+    pub compression: Compression,
+
+    ///Capacity of the forwarding queue, messages are dropped when the queue is full
+    #[serde(default = "Bridge::queue_capacity_default")]
+    pub queue_capacity: usize,
+
+    ///Skips the messages whose idempotency key was delivered
+    #[serde(default)]
+    pub dedup: DedupConfig,
+
+    #[serde(default)]
+    pub entries: Vec<Entry>,
+}
+
+impl Bridge {
+    fn server_default() -> String {
+        "pulsar://127.0.0.1:6650".into()
+    }
+
+    fn batch_size_default() -> usize {
+        100
+    }
+
+    fn linger_default() -> Duration {
+        Duration::from_millis(10)
+    }
+
+    fn queue_capacity_default() -> usize {
+        100_000
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Auth {
+    #[default]
+    None,
+    Token {
+        token: String,
+    },
+    OAuth2 {
+        issuer_url: String,
+        credentials_url: String,
+        #[serde(default)]
+        audience: Option<String>,
+        #[serde(default)]
+        scope: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    Lz4,
+    Zlib,
+    Zstd,
+    Snappy,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Entry {
+    #[serde(default)]
+    pub local: Local,
+    #[serde(default)]
+    pub remote: Remote,
+}
+
+type TopicsType = (Arc<TopicTree<()>>, Vec<String>);
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Local {
+    ///Local topic filters to forward
+    #[serde(
+        default = "Local::topics_default",
+        deserialize_with = "Local::deserialize_topics",
+        serialize_with = "Local::serialize_topics"
+    )]
+    pub topics: TopicsType,
+}
+
+impl Default for Local {
+    fn default() -> Self {
+        Self { topics: Self::topics_default() }
+    }
+}
+
+impl Local {
+    fn topics_default() -> TopicsType {
+        (Arc::new(TopicTree::default()), Vec::new())
+    }
+
+    #[inline]
+    pub fn is_match(&self, topic: &Topic) -> bool {
+        self.topics.0.is_match(topic)
+    }
+
+    fn serialize_topics<S>(topics: &TopicsType, s: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: ser::Serializer,
+    {
+        topics.1.serialize(s)
+    }
+
+    fn deserialize_topics<'de, D>(deserializer: D) -> std::result::Result<TopicsType, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let topics_cfg: Vec<String> = Vec::deserialize(deserializer)?;
+        let mut topics = TopicTree::default();
+        for topic in topics_cfg.iter() {
+            topics.insert(&Topic::from_str(topic).map_err(|e| de::Error::custom(format!("{:?}", e)))?, ());
+        }
+        Ok((Arc::new(topics), topics_cfg))
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Remote {
+    ///Pulsar topic, e.g. "persistent://public/default/mqtt"
+    #[serde(default)]
+    pub topic: String,
+    ///none, clientid or topic_segment:{index}
+    #[serde(default)]
+    pub partition_key: PartitionKey,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub enum PartitionKey {
+    #[default]
+    None,
+    ClientId,
+    ///Zero-based level of the MQTT topic
+    TopicSegment(usize),
+}
+
+impl PartitionKey {
+    #[inline]
+    pub fn make(&self, topic: &str, clientid: &ClientId) -> Option<String> {
+        match self {
+            PartitionKey::None => None,
+            PartitionKey::ClientId => Some(clientid.to_string()),
+            PartitionKey::TopicSegment(idx) => topic.split('/').nth(*idx).map(String::from),
+        }
+    }
+}
+
+impl Serialize for PartitionKey {
+    #[inline]
+    fn serialize<S>(&self, s: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: ser::Serializer,
+    {
+        match self {
+            PartitionKey::None => "none".serialize(s),
+            PartitionKey::ClientId => "clientid".serialize(s),
+            PartitionKey::TopicSegment(idx) => format!("topic_segment:{}", idx).serialize(s),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for PartitionKey {
+    #[inline]
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let v = String::deserialize(deserializer)?;
+        match v.as_str() {
+            "none" | "" => Ok(PartitionKey::None),
+            "clientid" => Ok(PartitionKey::ClientId),
+            _ => {
+                let idx = v
+                    .strip_prefix("topic_segment:")
+                    .and_then(|idx| idx.parse::<usize>().ok())
+                    .ok_or_else(|| de::Error::custom(format!("invalid partition key, {}", v)))?;
+                Ok(PartitionKey::TopicSegment(idx))
+            }
+        }
+    }
+}
@@ -247,13 +247,12 @@ async fn send_publish(
         .unwrap_or(entry.expiry_interval);
 
     //hook, message_publish
-    let msg = Runtime::instance()
-        .extends
-        .hook_mgr()
-        .await
-        .message_publish(None, from.clone(), &msg)
-        .await
-        .unwrap_or(msg);
+    let msg =
+        match Runtime::instance().extends.hook_mgr().await.message_publish(None, from.clone(), &msg).await {
+            Ok(modified) => modified.unwrap_or(msg),
+            //dropped by a handler
+            Err(_) => return,
+        };
 
     if let Err(e) = SessionState::forwards(
         from,
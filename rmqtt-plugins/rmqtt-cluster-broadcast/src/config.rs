@@ -1,4 +1,4 @@
-use rmqtt::grpc::MessageType;
+use rmqtt::grpc::{discovery::DiscoveryConfig, MessageType};
 use rmqtt::serde_json;
 use rmqtt::settings::NodeAddr;
 use rmqtt::Result;
@@ -8,7 +8,12 @@ pub struct PluginConfig {
     #[serde(default = "PluginConfig::message_type_default")]
     pub message_type: MessageType,
 
+    #[serde(default)]
     pub node_grpc_addrs: Vec<NodeAddr>,
+
+    //Discovers node_grpc_addrs by DNS or the Kubernetes API
+    #[serde(default)]
+    pub discovery: DiscoveryConfig,
 }
 
 impl PluginConfig {
@@ -24,19 +24,20 @@ impl HookHandler {
 
 #[async_trait]
 impl Handler for HookHandler {
-    async fn hook(&self, param: &Parameter, acc: Option<HookResult>) -> ReturnType {
+    async fn hook(&self, param: &Parameter, acc: &mut Option<HookResult>) -> ReturnType {
         match param {
             Parameter::GrpcMessageReceived(typ, msg) => {
                 log::debug!("GrpcMessageReceived, type: {}, msg: {:?}", typ, msg);
                 if self.shared.message_type != *typ {
-                    return (true, acc);
+                    return true;
                 }
                 match msg {
                     Message::Forwards(from, publish) => {
                         let (shared_subs, subs_size) = forwards(from.clone(), publish.clone()).await;
                         let new_acc =
                             HookResult::GrpcMessageReply(Ok(MessageReply::Forwards(shared_subs, subs_size)));
-                        return (false, Some(new_acc));
+                        *acc = Some(new_acc);
+                        return false;
                     }
                     Message::ForwardsTo(from, publish, sub_rels) => {
                         if let Err(droppeds) =
@@ -44,7 +45,7 @@ impl Handler for HookHandler {
                         {
                             hook_message_dropped(droppeds).await;
                         }
-                        return (false, acc);
+                        return false;
                     }
                     Message::Kick(id, clean_start, clear_subscriptions, is_admin) => {
                         let entry = self.shared.inner().entry(id.clone());
@@ -64,21 +65,24 @@ impl Handler for HookHandler {
                                 HookResult::GrpcMessageReply(Err(e))
                             }
                         };
-                        return (false, Some(new_acc));
+                        *acc = Some(new_acc);
+                        return false;
                     }
                     Message::NumberOfClients => {
                         let new_acc = HookResult::GrpcMessageReply(Ok(MessageReply::NumberOfClients(
                             //self.shared.inner().clients().await,
                             Runtime::instance().stats.connections.count() as usize,
                         )));
-                        return (false, Some(new_acc));
+                        *acc = Some(new_acc);
+                        return false;
                     }
                     Message::NumberOfSessions => {
                         let new_acc = HookResult::GrpcMessageReply(Ok(MessageReply::NumberOfSessions(
                             //self.shared.inner().sessions().await,
                             Runtime::instance().stats.sessions.count() as usize,
                         )));
-                        return (false, Some(new_acc));
+                        *acc = Some(new_acc);
+                        return false;
                     }
                     Message::GetRetains(_topic_filter) => {
                         unreachable!()
@@ -92,13 +96,15 @@ impl Handler for HookHandler {
                                 .is_online(Runtime::instance().node.id(), clientid)
                                 .await,
                         )));
-                        return (false, Some(new_acc));
+                        *acc = Some(new_acc);
+                        return false;
                     }
                     Message::SubscriptionsSearch(q) => {
                         let new_acc = HookResult::GrpcMessageReply(Ok(MessageReply::SubscriptionsSearch(
                             self.shared.inner()._query_subscriptions(q).await,
                         )));
-                        return (false, Some(new_acc));
+                        *acc = Some(new_acc);
+                        return false;
                     }
                     Message::SubscriptionsGet(clientid) => {
                         let id = Id::from(Runtime::instance().node.id(), clientid.clone());
@@ -106,13 +112,15 @@ impl Handler for HookHandler {
                         let new_acc = HookResult::GrpcMessageReply(Ok(MessageReply::SubscriptionsGet(
                             entry.subscriptions().await,
                         )));
-                        return (false, Some(new_acc));
+                        *acc = Some(new_acc);
+                        return false;
                     }
                     Message::RoutesGet(limit) => {
                         let new_acc = HookResult::GrpcMessageReply(Ok(MessageReply::RoutesGet(
                             self.router._inner().gets(*limit).await,
                         )));
-                        return (false, Some(new_acc));
+                        *acc = Some(new_acc);
+                        return false;
                     }
                     Message::RoutesGetBy(topic) => {
                         let routes = match self.router._inner()._get_routes(topic).await {
@@ -120,12 +128,14 @@ impl Handler for HookHandler {
                             Err(e) => Err(e),
                         };
                         let new_acc = HookResult::GrpcMessageReply(routes);
-                        return (false, Some(new_acc));
+                        *acc = Some(new_acc);
+                        return false;
                     }
                     Message::SessionStatus(clientid) => {
                         let status = self.shared.inner().session_status(clientid).await;
                         let new_acc = HookResult::GrpcMessageReply(Ok(MessageReply::SessionStatus(status)));
-                        return (false, Some(new_acc));
+                        *acc = Some(new_acc);
+                        return false;
                     }
 
                     _ => {
@@ -137,7 +147,7 @@ impl Handler for HookHandler {
                 log::error!("unimplemented, {:?}", param)
             }
         }
-        (true, acc)
+        true
     }
 }
 
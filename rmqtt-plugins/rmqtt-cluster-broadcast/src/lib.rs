@@ -14,7 +14,7 @@ use rmqtt::{
     async_trait::async_trait,
     log,
     serde_json::{self, json},
-    tokio::sync::RwLock,
+    tokio::{sync::RwLock, task::JoinHandle},
 };
 use rmqtt::{
     broker::{
@@ -23,7 +23,11 @@ use rmqtt::{
         session::SessionOfflineInfo,
         types::{From, Publish, Reason, To},
     },
-    grpc::{GrpcClients, Message, MessageReply, MessageType},
+    grpc::{
+        discovery::{self, Nodes},
+        message_types::MessageTypes,
+        GrpcClients, Message, MessageReply, MessageType,
+    },
     plugin::{PackageInfo, Plugin},
     register, Result, Runtime,
 };
@@ -44,9 +48,10 @@ struct ClusterPlugin {
     runtime: &'static Runtime,
     register: Box<dyn Register>,
     cfg: Arc<RwLock<PluginConfig>>,
-    grpc_clients: GrpcClients,
     shared: &'static ClusterShared,
     router: &'static ClusterRouter,
+    discovered: Nodes,
+    discovery_task: Option<JoinHandle<()>>,
 }
 
 impl ClusterPlugin {
@@ -59,6 +64,16 @@ impl ClusterPlugin {
         log::debug!("{} ClusterPlugin cfg: {:?}", name, cfg.read().await);
 
         let register = runtime.extends.hook_mgr().await.register();
+        let discovered = {
+            let mut cfg = cfg.write().await;
+            if cfg.discovery.is_enable() {
+                let nodes = cfg.discovery.wait_for_nodes().await?;
+                cfg.node_grpc_addrs = cfg.discovery.grpc_addrs(&nodes);
+                nodes
+            } else {
+                Nodes::new()
+            }
+        };
         let mut grpc_clients = HashMap::default();
         let node_grpc_addrs = cfg.read().await.node_grpc_addrs.clone();
         for node_addr in &node_grpc_addrs {
@@ -73,7 +88,7 @@ impl ClusterPlugin {
         let message_type = cfg.read().await.message_type;
         let router = ClusterRouter::get_or_init(grpc_clients.clone(), message_type);
         let shared = ClusterShared::get_or_init(grpc_clients.clone(), message_type);
-        Ok(Self { runtime, register, cfg, grpc_clients, shared, router })
+        Ok(Self { runtime, register, cfg, shared, router, discovered, discovery_task: None })
     }
 }
 
@@ -82,6 +97,7 @@ impl Plugin for ClusterPlugin {
     #[inline]
     async fn init(&mut self) -> Result<()> {
         log::info!("{} init", self.name());
+        MessageTypes::instance().claim(self.name(), "cluster", self.shared.message_type)?;
         self.register
             .add(Type::GrpcMessageReceived, Box::new(HookHandler::new(self.shared, self.router)))
             .await;
@@ -99,6 +115,27 @@ impl Plugin for ClusterPlugin {
         self.register.start().await;
         *self.runtime.extends.shared_mut().await = Box::new(self.shared);
         *self.runtime.extends.router_mut().await = Box::new(self.router);
+
+        let discovery = self.cfg.read().await.discovery.clone();
+        if discovery.is_enable() {
+            let (shared, router) = (self.shared, self.router);
+            let grpc_discovery = discovery.clone();
+            let task = discovery.watch(self.discovered.clone(), move |nodes| {
+                let node_grpc_addrs = grpc_discovery.grpc_addrs(&nodes);
+                async move {
+                    match discovery::update_grpc_clients(&shared.grpc_clients(), &node_grpc_addrs).await {
+                        Ok(Some(grpc_clients)) => {
+                            log::info!("node_grpc_addrs changed, {:?}", node_grpc_addrs);
+                            router.set_grpc_clients(grpc_clients.clone());
+                            shared.set_grpc_clients(grpc_clients);
+                        }
+                        Ok(None) => {}
+                        Err(e) => log::warn!("update gRPC clients error, {:?}", e),
+                    }
+                }
+            });
+            self.discovery_task.replace(task);
+        }
         Ok(())
     }
 
@@ -111,10 +148,13 @@ impl Plugin for ClusterPlugin {
     #[inline]
     async fn attrs(&self) -> serde_json::Value {
         let mut nodes = HashMap::default();
-        for (id, (addr, c)) in self.grpc_clients.iter() {
+        for (id, (addr, c)) in self.shared.grpc_clients().iter() {
             let stats = json!({
                 "channel_tasks": c.channel_tasks(),
                 "active_tasks": c.active_tasks(),
+                "congested": c.is_congested(),
+                "dropped": c.dropped(),
+                "spilled": c.spilled(),
             });
             nodes.insert(format!("{}/{:?}", id, addr), stats);
         }
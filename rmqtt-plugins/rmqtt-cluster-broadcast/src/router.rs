@@ -1,7 +1,7 @@
 use itertools::Itertools;
 use once_cell::sync::OnceCell;
 
-use rmqtt::{async_trait::async_trait, itertools, log, once_cell, serde_json};
+use rmqtt::{async_trait::async_trait, itertools, log, once_cell, rust_box::std_ext::RwLock, serde_json};
 use rmqtt::{
     broker::{
         default::DefaultRouter,
@@ -15,7 +15,7 @@ use rmqtt::{
 
 pub(crate) struct ClusterRouter {
     inner: &'static DefaultRouter,
-    grpc_clients: GrpcClients,
+    grpc_clients: RwLock<GrpcClients>,
     message_type: MessageType,
 }
 
@@ -23,13 +23,27 @@ impl ClusterRouter {
     #[inline]
     pub(crate) fn get_or_init(grpc_clients: GrpcClients, message_type: MessageType) -> &'static Self {
         static INSTANCE: OnceCell<ClusterRouter> = OnceCell::new();
-        INSTANCE.get_or_init(|| Self { inner: DefaultRouter::instance(), grpc_clients, message_type })
+        INSTANCE.get_or_init(|| Self {
+            inner: DefaultRouter::instance(),
+            grpc_clients: RwLock::new(grpc_clients),
+            message_type,
+        })
     }
 
     #[inline]
     pub(crate) fn _inner(&self) -> &'static DefaultRouter {
         self.inner
     }
+
+    #[inline]
+    pub(crate) fn grpc_clients(&self) -> GrpcClients {
+        self.grpc_clients.read().clone()
+    }
+
+    #[inline]
+    pub(crate) fn set_grpc_clients(&self, grpc_clients: GrpcClients) {
+        *self.grpc_clients.write() = grpc_clients;
+    }
 }
 
 #[async_trait]
@@ -44,6 +58,16 @@ impl Router for &'static ClusterRouter {
         self.inner.remove(topic_filter, id).await
     }
 
+    #[inline]
+    async fn remove_client(&self, client_id: &str) -> Result<usize> {
+        self.inner.remove_client(client_id).await
+    }
+
+    #[inline]
+    async fn relations(&self, node_id: NodeId) -> Vec<(TopicFilter, Id)> {
+        self.inner.relations(node_id).await
+    }
+
     #[inline]
     async fn matches(&self, id: Id, topic: &TopicName) -> Result<SubRelationsMap> {
         self.inner.matches(id, topic).await
@@ -57,7 +81,7 @@ impl Router for &'static ClusterRouter {
     #[inline]
     async fn gets(&self, limit: usize) -> Vec<Route> {
         let mut routes = self.inner.gets(limit).await;
-        for (_id, (_addr, c)) in self.grpc_clients.iter() {
+        for (_id, (_addr, c)) in self.grpc_clients().iter() {
             if routes.len() < limit {
                 let reply = MessageSender::new(
                     c.clone(),
@@ -87,7 +111,7 @@ impl Router for &'static ClusterRouter {
         let routes = self.inner._get_routes(topic).await?;
 
         let mut replys = MessageBroadcaster::new(
-            self.grpc_clients.clone(),
+            self.grpc_clients(),
             self.message_type,
             Message::RoutesGetBy(TopicFilter::from(topic)),
         )
@@ -146,4 +170,9 @@ impl Router for &'static ClusterRouter {
     async fn list_relations(&self, top: usize) -> Vec<serde_json::Value> {
         self.inner.list_relations(top).await
     }
+
+    #[inline]
+    async fn memory_info(&self) -> serde_json::Value {
+        self.inner.memory_info().await
+    }
 }
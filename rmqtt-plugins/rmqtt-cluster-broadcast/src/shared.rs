@@ -1,12 +1,14 @@
 use std::convert::From as _f;
+use std::time::Duration;
 
 use once_cell::sync::OnceCell;
 
 use rmqtt::grpc::MessageSender;
-use rmqtt::{ahash, async_trait::async_trait, futures, log, once_cell, tokio};
+use rmqtt::{ahash, async_trait::async_trait, futures, log, once_cell, rust_box::std_ext::RwLock, tokio};
 use rmqtt::{
     broker::{
         default::DefaultShared,
+        maintenance::Maintenance,
         session::{Session, SessionOfflineInfo},
         types::{
             ClientId, From, Id, IsAdmin, IsOnline, NodeId, Publish, Reason, SessionStatus, SharedGroup,
@@ -23,6 +25,7 @@ use rmqtt::{
 use super::{hook_message_dropped, kick};
 
 type HashMap<K, V> = std::collections::HashMap<K, V, ahash::RandomState>;
+type HashSet<V> = std::collections::HashSet<V, ahash::RandomState>;
 
 pub struct ClusterLockEntry {
     inner: Box<dyn Entry>,
@@ -87,7 +90,7 @@ impl Entry for ClusterLockEntry {
         }
 
         match kick(
-            self.cluster_shared.grpc_clients.clone(),
+            self.cluster_shared.grpc_clients(),
             self.cluster_shared.message_type,
             Message::Kick(self.id(), clean_start, true, is_admin),
         )
@@ -118,7 +121,7 @@ impl Entry for ClusterLockEntry {
         }
 
         MessageBroadcaster::new(
-            self.cluster_shared.grpc_clients.clone(),
+            self.cluster_shared.grpc_clients(),
             self.cluster_shared.message_type,
             Message::Online(self.id().client_id.clone()),
         )
@@ -169,7 +172,7 @@ impl Entry for ClusterLockEntry {
             return Some(subs);
         }
         MessageBroadcaster::new(
-            self.cluster_shared.grpc_clients.clone(),
+            self.cluster_shared.grpc_clients(),
             self.cluster_shared.message_type,
             Message::SubscriptionsGet(self.id().client_id.clone()),
         )
@@ -187,7 +190,7 @@ impl Entry for ClusterLockEntry {
 
 pub struct ClusterShared {
     inner: &'static DefaultShared,
-    grpc_clients: GrpcClients,
+    grpc_clients: RwLock<GrpcClients>,
     pub message_type: MessageType,
 }
 
@@ -198,13 +201,27 @@ impl ClusterShared {
         message_type: MessageType,
     ) -> &'static ClusterShared {
         static INSTANCE: OnceCell<ClusterShared> = OnceCell::new();
-        INSTANCE.get_or_init(|| Self { inner: DefaultShared::instance(), grpc_clients, message_type })
+        INSTANCE.get_or_init(|| Self {
+            inner: DefaultShared::instance(),
+            grpc_clients: RwLock::new(grpc_clients),
+            message_type,
+        })
     }
 
     #[inline]
     pub(crate) fn inner(&self) -> &'static DefaultShared {
         self.inner
     }
+
+    #[inline]
+    pub(crate) fn grpc_clients(&self) -> GrpcClients {
+        self.grpc_clients.read().clone()
+    }
+
+    #[inline]
+    pub(crate) fn set_grpc_clients(&self, grpc_clients: GrpcClients) {
+        *self.grpc_clients.write() = grpc_clients;
+    }
 }
 
 #[async_trait]
@@ -267,7 +284,7 @@ impl Shared for &'static ClusterShared {
         log::debug!("forwards, from: {:?}, local_res: {:?}", from, local_res);
 
         //forwards to remote
-        let grpc_clients = self.grpc_clients.clone();
+        let grpc_clients = self.grpc_clients();
         let message_type = self.message_type;
         let inner = self.inner;
         let (sub_client_ids_tx, sub_client_ids_rx) = tokio::sync::oneshot::channel();
@@ -335,7 +352,7 @@ impl Shared for &'static ClusterShared {
 
             add_to_shared_sub_groups(&mut shared_sub_groups, shared_relations);
             let mut all_sub_client_ids = Vec::new();
-            for (_, reply) in replys {
+            for (node_id, reply) in replys {
                 match reply {
                     Ok(reply) => {
                         if let MessageReply::Forwards(mut o_relations_map, o_sub_client_ids) = reply {
@@ -367,6 +384,12 @@ impl Shared for &'static ClusterShared {
                             from,
                             e
                         );
+                        //the node is in maintenance, the message is resent when it leaves
+                        Maintenance::instance().hold(
+                            node_id,
+                            message_type,
+                            Message::Forwards(from.clone(), publish.clone()),
+                        );
                     }
                 }
             }
@@ -404,19 +427,17 @@ impl Shared for &'static ClusterShared {
             let mut delivers = Vec::new();
             for (id, (_addr, grpc_client)) in grpc_clients.iter() {
                 if let Some(sub_rels) = node_shared_subs.remove(id) {
-                    delivers.push(grpc_client.send_message(
-                        message_type,
-                        Message::ForwardsTo(from.clone(), publish.clone(), sub_rels),
-                    ));
+                    let msg = Message::ForwardsTo(from.clone(), publish.clone(), sub_rels);
+                    delivers.push(async move {
+                        if let Err(e) = grpc_client.send_message(message_type, msg.clone()).await {
+                            log::error!("deliver shared subscriptions error, {:?}", e);
+                            Maintenance::instance().hold(*id, message_type, msg);
+                        }
+                    });
                 }
             }
             if !delivers.is_empty() {
-                let ress = futures::future::join_all(delivers).await;
-                for res in ress {
-                    if let Err(e) = res {
-                        log::error!("deliver shared subscriptions error, {:?}", e);
-                    }
-                }
+                futures::future::join_all(delivers).await;
             }
         };
 
@@ -464,7 +485,7 @@ impl Shared for &'static ClusterShared {
             return Some(status);
         }
         MessageBroadcaster::new(
-            self.grpc_clients.clone(),
+            self.grpc_clients(),
             self.message_type,
             Message::SessionStatus(ClientId::from(client_id)),
         )
@@ -490,32 +511,62 @@ impl Shared for &'static ClusterShared {
     }
 
     #[inline]
-    async fn query_subscriptions(&self, mut q: SubsSearchParams) -> Vec<SubsSearchResult> {
-        let limit = q._limit;
-        let mut replys = self.inner.query_subscriptions(q.clone()).await;
+    async fn query_subscriptions(&self, q: SubsSearchParams) -> Vec<SubsSearchResult> {
+        self.query_subscriptions_cluster(q, Runtime::instance().settings.rpc.client_timeout).await
+    }
+
+    #[inline]
+    async fn query_subscriptions_cluster(
+        &self,
+        q: SubsSearchParams,
+        timeout: Duration,
+    ) -> Vec<SubsSearchResult> {
+        let node_id = Runtime::instance().node.id();
+        let (offset, limit) = (q._offset, q._limit);
+        //Each node returns its first offset + limit items, the page is taken after merging
+        let mut node_q = q;
+        node_q._offset = 0;
+        node_q._limit = offset.saturating_add(limit);
+
+        let mut replys = Vec::new();
+        if node_q.node_id.map(|id| id == node_id).unwrap_or(true) {
+            replys.push(self.inner.query_subscriptions(node_q.clone()).await);
+        }
 
         let grpc_clients = self.get_grpc_clients();
-        for c in grpc_clients.iter().map(|(_, (_, c))| c.clone()) {
-            if replys.len() < limit {
-                q._limit = limit - replys.len();
-                let reply = MessageSender::new(c, self.message_type, Message::SubscriptionsSearch(q.clone()))
-                    .send()
-                    .await;
-                match reply {
-                    Ok(MessageReply::SubscriptionsSearch(subs)) => {
-                        replys.extend(subs);
-                    }
-                    Err(e) => {
-                        log::warn!("query_subscriptions, error: {:?}", e);
-                    }
-                    _ => unreachable!(),
-                };
-            } else {
-                break;
+        let mut grpc_clients = grpc_clients
+            .iter()
+            .filter(|(id, _)| node_q.node_id.map(|node_id| node_id == **id).unwrap_or(true))
+            .map(|(id, (_, c))| (*id, c.clone()))
+            .collect::<Vec<_>>();
+        //Merged in the order of node ids, so that the pages are stable
+        grpc_clients.sort_by_key(|(id, _)| *id);
+        let futs = grpc_clients.into_iter().map(|(id, c)| {
+            let msg = Message::SubscriptionsSearch(node_q.clone());
+            async move {
+                (
+                    id,
+                    tokio::time::timeout(timeout, MessageSender::new(c, self.message_type, msg).send()).await,
+                )
+            }
+        });
+        for (id, reply) in futures::future::join_all(futs).await {
+            match reply {
+                Ok(Ok(MessageReply::SubscriptionsSearch(subs))) => replys.push(subs),
+                Ok(Ok(_)) => unreachable!(),
+                Ok(Err(e)) => log::warn!("query_subscriptions, node: {}, error: {:?}", id, e),
+                Err(_) => log::warn!("query_subscriptions, node: {}, timeout", id),
             }
         }
 
+        let mut exists = HashSet::default();
         replys
+            .into_iter()
+            .flatten()
+            .filter(|s| exists.insert((s.node_id, s.clientid.clone(), s.topic.clone())))
+            .skip(offset)
+            .take(limit)
+            .collect()
     }
 
     #[inline]
@@ -525,6 +576,6 @@ impl Shared for &'static ClusterShared {
 
     #[inline]
     fn get_grpc_clients(&self) -> GrpcClients {
-        self.grpc_clients.clone()
+        self.grpc_clients()
     }
 }
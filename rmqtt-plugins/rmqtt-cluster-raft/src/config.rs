@@ -7,10 +7,10 @@ use serde::de::{self, Deserialize, Deserializer};
 use serde::ser::Serializer;
 use serde::Serialize;
 
-use rmqtt::grpc::MessageType;
+use rmqtt::grpc::{discovery::DiscoveryConfig, MessageType};
 use rmqtt::settings::{deserialize_duration, deserialize_duration_option, NodeAddr, Options};
+use rmqtt::{broker::types::TopicFilter, MqttError, NodeId, Result};
 use rmqtt::{once_cell::sync::Lazy, serde_json};
-use rmqtt::{MqttError, NodeId, Result};
 
 pub(crate) static BACKOFF_STRATEGY: Lazy<ExponentialBackoff> = Lazy::new(|| {
     ExponentialBackoffBuilder::new()
@@ -27,10 +27,16 @@ pub struct PluginConfig {
     #[serde(default = "PluginConfig::message_type_default")]
     pub message_type: MessageType,
 
+    #[serde(default)]
     pub node_grpc_addrs: Vec<NodeAddr>,
 
+    #[serde(default)]
     pub raft_peer_addrs: Vec<NodeAddr>,
 
+    //Discovers node_grpc_addrs and raft_peer_addrs by DNS or the Kubernetes API
+    #[serde(default)]
+    pub discovery: DiscoveryConfig,
+
     #[serde(default)]
     pub leader_id: NodeId,
 
@@ -48,6 +54,20 @@ pub struct PluginConfig {
 
     #[serde(default = "PluginConfig::raft_default")]
     pub raft: RaftConfig,
+
+    //Split-brain detection, the node that loses quorum is quarantined
+    #[serde(default)]
+    pub partition: PartitionConfig,
+
+    //The routes of the non-shared subscriptions are replicated per topic filter and node, reference
+    //counted by the node, only the first subscriber and the last unsubscriber of a filter are proposed.
+    //Must be the same on all the nodes.
+    #[serde(default)]
+    pub route_refcount: bool,
+
+    //Pulls the routes of the peers at startup, before the listeners are started
+    #[serde(default)]
+    pub route_warmup: RouteWarmupConfig,
 }
 
 impl PluginConfig {
@@ -107,6 +127,100 @@ impl PluginConfig {
     }
 }
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PartitionMode {
+    //The routing table is frozen, subscriptions and connections are rejected,
+    //messages are still forwarded by the last known routes
+    #[default]
+    ReadOnly,
+    //Publishes are rejected as well
+    Reject,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PartitionConfig {
+    #[serde(default = "PartitionConfig::enable_default")]
+    pub enable: bool,
+    #[serde(default)]
+    pub mode: PartitionMode,
+    //Interval of the quorum check, a ping proposal must be committed within the check timeout
+    #[serde(default = "PartitionConfig::check_interval_default", deserialize_with = "deserialize_duration")]
+    pub check_interval: Duration,
+    #[serde(default = "PartitionConfig::check_timeout_default", deserialize_with = "deserialize_duration")]
+    pub check_timeout: Duration,
+    //The node is quarantined after the quorum has been lost for this long
+    #[serde(
+        default = "PartitionConfig::quorum_lost_timeout_default",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub quorum_lost_timeout: Duration,
+    //Maximum number of partition timelines kept in attrs()
+    #[serde(default = "PartitionConfig::max_timelines_default")]
+    pub max_timelines: usize,
+}
+
+impl Default for PartitionConfig {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            enable: Self::enable_default(),
+            mode: PartitionMode::default(),
+            check_interval: Self::check_interval_default(),
+            check_timeout: Self::check_timeout_default(),
+            quorum_lost_timeout: Self::quorum_lost_timeout_default(),
+            max_timelines: Self::max_timelines_default(),
+        }
+    }
+}
+
+impl PartitionConfig {
+    fn enable_default() -> bool {
+        true
+    }
+
+    fn check_interval_default() -> Duration {
+        Duration::from_secs(2)
+    }
+
+    fn check_timeout_default() -> Duration {
+        Duration::from_secs(3)
+    }
+
+    fn quorum_lost_timeout_default() -> Duration {
+        Duration::from_secs(6)
+    }
+
+    fn max_timelines_default() -> usize {
+        20
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RouteWarmupConfig {
+    #[serde(default)]
+    pub enable: bool,
+    //Only the topic filters starting with the prefix, all if not set
+    #[serde(default)]
+    pub prefix: Option<TopicFilter>,
+    //Number of topic filters per page
+    #[serde(default = "RouteWarmupConfig::page_size_default")]
+    pub page_size: usize,
+}
+
+impl Default for RouteWarmupConfig {
+    #[inline]
+    fn default() -> Self {
+        Self { enable: false, prefix: None, page_size: Self::page_size_default() }
+    }
+}
+
+impl RouteWarmupConfig {
+    fn page_size_default() -> usize {
+        1000
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize, Default)]
 pub struct RaftConfig {
     #[serde(default = "RaftConfig::grpc_reuseaddr_default")]
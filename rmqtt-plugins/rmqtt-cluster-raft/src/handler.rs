@@ -6,7 +6,7 @@ use rmqtt::{async_trait::async_trait, log, tokio, MqttError};
 use rmqtt::{
     broker::hook::{Handler, HookResult, Parameter, ReturnType},
     grpc::{Message as GrpcMessage, MessageReply},
-    Id, Runtime,
+    Id, PublishAclResult, Runtime,
 };
 
 use super::config::{retry, BACKOFF_STRATEGY};
@@ -26,7 +26,7 @@ impl HookHandler {
 
 #[async_trait]
 impl Handler for HookHandler {
-    async fn hook(&self, param: &Parameter, acc: Option<HookResult>) -> ReturnType {
+    async fn hook(&self, param: &Parameter, acc: &mut Option<HookResult>) -> ReturnType {
         log::debug!("hook, Parameter type: {:?}", param.get_type());
         match param {
             Parameter::ClientDisconnected(s, r) => {
@@ -93,16 +93,19 @@ impl Handler for HookHandler {
             Parameter::GrpcMessageReceived(typ, msg) => {
                 log::debug!("GrpcMessageReceived, type: {}, msg: {:?}", typ, msg);
                 if self.shared.message_type != *typ {
-                    return (true, acc);
+                    return true;
                 }
                 match msg {
                     GrpcMessage::ForwardsTo(from, publish, sub_rels) => {
-                        if let Err(droppeds) =
-                            self.shared.forwards_to(from.clone(), publish, sub_rels.clone()).await
+                        let mut sub_rels = sub_rels.clone();
+                        sub_rels.extend(
+                            self.shared.router().local_relations(from.id.clone(), &publish.topic).await,
+                        );
+                        if let Err(droppeds) = self.shared.forwards_to(from.clone(), publish, sub_rels).await
                         {
                             hook_message_dropped(droppeds).await;
                         }
-                        return (false, acc);
+                        return false;
                     }
                     GrpcMessage::Kick(id, clean_start, clear_subscriptions, is_admin) => {
                         let mut entry = self.shared.inner().entry(id.clone());
@@ -111,19 +114,28 @@ impl Handler for HookHandler {
                             Ok(None) => HookResult::GrpcMessageReply(Ok(MessageReply::Kick(None))),
                             Err(e) => HookResult::GrpcMessageReply(Err(e)),
                         };
-                        return (false, Some(new_acc));
+                        *acc = Some(new_acc);
+                        return false;
                     }
                     GrpcMessage::GetRetains(topic_filter) => {
                         log::debug!("[GrpcMessage::GetRetains] topic_filter: {:?}", topic_filter);
                         unreachable!()
                     }
+                    GrpcMessage::SubscriptionsSearch(q) => {
+                        let new_acc = HookResult::GrpcMessageReply(Ok(MessageReply::SubscriptionsSearch(
+                            self.shared.inner()._query_subscriptions(q).await,
+                        )));
+                        *acc = Some(new_acc);
+                        return false;
+                    }
                     GrpcMessage::SubscriptionsGet(clientid) => {
                         let id = Id::from(Runtime::instance().node.id(), clientid.clone());
                         let entry = self.shared.inner().entry(id);
                         let new_acc = HookResult::GrpcMessageReply(Ok(MessageReply::SubscriptionsGet(
                             entry.subscriptions().await,
                         )));
-                        return (false, Some(new_acc));
+                        *acc = Some(new_acc);
+                        return false;
                     }
                     GrpcMessage::Data(data) => {
                         let new_acc = match RaftGrpcMessage::decode(data) {
@@ -149,18 +161,41 @@ impl Handler for HookHandler {
                                     }
                                 }
                             }
+                            Ok(RaftGrpcMessage::GetRouteRefcount) => {
+                                let route_refcount = self.shared.router().route_refcount;
+                                match RaftGrpcMessageReply::GetRouteRefcount(route_refcount).encode() {
+                                    Ok(ress) => HookResult::GrpcMessageReply(Ok(MessageReply::Data(ress))),
+                                    Err(e) => {
+                                        HookResult::GrpcMessageReply(Ok(MessageReply::Error(e.to_string())))
+                                    }
+                                }
+                            }
                         };
-                        return (false, Some(new_acc));
+                        *acc = Some(new_acc);
+                        return false;
                     }
                     _ => {
                         log::error!("unimplemented, {:?}", param)
                     }
                 }
             }
+
+            Parameter::BeforeShutdown => {
+                //This node leaves the cluster, its node routes are removed
+                self.shared.router().remove_node_routes(Runtime::instance().node.id()).await;
+            }
+
+            Parameter::MessagePublishCheckAcl(s, _p) => {
+                if self.shared.router().partition.is_rejected() {
+                    log::debug!("{:?} publish rejected, cluster degraded", s.id);
+                    *acc = Some(HookResult::PublishAclResult(PublishAclResult::Rejected(false)));
+                    return false;
+                }
+            }
             _ => {
                 log::error!("unimplemented, {:?}", param)
             }
         }
-        (true, acc)
+        true
     }
 }
@@ -11,8 +11,9 @@ use std::net::{SocketAddr, ToSocketAddrs};
 use std::sync::Arc;
 use std::time::Duration;
 
-use config::PluginConfig;
+use config::{PartitionMode, PluginConfig};
 use handler::HookHandler;
+use message::{RaftGrpcMessage, RaftGrpcMessageReply};
 
 use rmqtt::anyhow::anyhow;
 use rmqtt::{
@@ -26,11 +27,18 @@ use rmqtt::{
     broker::{
         error::MqttError,
         hook::{Register, Type},
-        types::{From, Publish, Reason, To},
+        route_sync::RouteSync,
+        types::{From, NodeName, Publish, Reason, To},
+    },
+    grpc::{
+        client::NodeGrpcClient,
+        discovery::{self, Nodes},
+        message_types::MessageTypes,
+        GrpcClients, Message, MessageBroadcaster, MessageReply, MessageType,
     },
-    grpc::{client::NodeGrpcClient, GrpcClients, Message, MessageReply, MessageType},
     plugin::{PackageInfo, Plugin},
     register,
+    settings::NodeAddr,
     tokio::time::sleep,
     Result, Runtime,
 };
@@ -44,6 +52,7 @@ use shared::ClusterShared;
 mod config;
 mod handler;
 mod message;
+mod partition;
 mod router;
 mod shared;
 
@@ -56,11 +65,13 @@ struct ClusterPlugin {
     runtime: &'static Runtime,
     register: Box<dyn Register>,
     cfg: Arc<PluginConfig>,
-    grpc_clients: GrpcClients,
     shared: &'static ClusterShared,
 
     router: &'static ClusterRouter,
     raft_mailbox: Option<Mailbox>,
+    discovered: Nodes,
+    discovery_task: Option<tokio::task::JoinHandle<()>>,
+    partition_task: Option<tokio::task::JoinHandle<()>>,
 }
 
 impl ClusterPlugin {
@@ -74,9 +85,17 @@ impl ClusterPlugin {
 
         init_task_exec_queue(cfg.task_exec_queue_workers, cfg.task_exec_queue_max);
 
+        let discovered = if cfg.discovery.is_enable() {
+            let nodes = cfg.discovery.wait_for_nodes().await?;
+            cfg.node_grpc_addrs = cfg.discovery.grpc_addrs(&nodes);
+            cfg.raft_peer_addrs = cfg.discovery.raft_addrs(&nodes);
+            nodes
+        } else {
+            Nodes::new()
+        };
+
         let register = runtime.extends.hook_mgr().await.register();
         let mut grpc_clients = HashMap::default();
-        let mut node_names = HashMap::default();
 
         let node_grpc_addrs = cfg.node_grpc_addrs.clone();
         log::info!("node_grpc_addrs: {:?}", node_grpc_addrs);
@@ -87,14 +106,25 @@ impl ClusterPlugin {
                     (node_addr.addr.clone(), runtime.node.new_grpc_client(&node_addr.addr).await?),
                 );
             }
-            node_names.insert(node_addr.id, format!("{}@{}", node_addr.id, node_addr.addr));
         }
+        let node_names = node_names(&node_grpc_addrs);
         let grpc_clients = Arc::new(grpc_clients);
-        let router = ClusterRouter::get_or_init(cfg.try_lock_timeout);
+        let router =
+            ClusterRouter::get_or_init(cfg.try_lock_timeout, cfg.partition.clone(), cfg.route_refcount);
         let shared = ClusterShared::get_or_init(router, grpc_clients.clone(), node_names, cfg.message_type);
         let raft_mailbox = None;
         let cfg = Arc::new(cfg);
-        Ok(Self { runtime, register, cfg, grpc_clients, shared, router, raft_mailbox })
+        Ok(Self {
+            runtime,
+            register,
+            cfg,
+            shared,
+            router,
+            raft_mailbox,
+            discovered,
+            discovery_task: None,
+            partition_task: None,
+        })
     }
 
     //raft init ...
@@ -209,6 +239,55 @@ impl ClusterPlugin {
         self.register.add(typ, Box::new(HookHandler::new(self.shared, self.raft_mailbox()))).await;
     }
 
+    //The routes replicated by raft may not be applied yet, the routes of the sessions of the peers are
+    //pulled from them, the non-shared ones are local to their node with route_refcount
+    async fn route_warmup(&self) {
+        let cfg = &self.cfg.route_warmup;
+        let grpc_clients = self.shared.grpc_clients();
+        if !cfg.enable || grpc_clients.is_empty() {
+            return;
+        }
+        RouteSync::instance()
+            .warmup(grpc_clients, cfg.prefix.clone(), cfg.page_size, self.cfg.route_refcount)
+            .await;
+    }
+
+    //The non-shared routes are replicated or local to their node depending on route_refcount, a node
+    //with a different setting would lose or duplicate the routes, it does not join the cluster
+    async fn check_route_refcount(&self) -> Result<()> {
+        let grpc_clients = self.shared.grpc_clients();
+        if grpc_clients.is_empty() {
+            return Ok(());
+        }
+        let data = RaftGrpcMessage::GetRouteRefcount.encode()?;
+        let replys = MessageBroadcaster::new(grpc_clients, self.cfg.message_type, Message::Data(data))
+            .join_all()
+            .await;
+        for (node_id, reply) in replys {
+            match reply {
+                Ok(MessageReply::Data(data)) => {
+                    if let Ok(RaftGrpcMessageReply::GetRouteRefcount(route_refcount)) =
+                        RaftGrpcMessageReply::decode(&data)
+                    {
+                        if route_refcount != self.cfg.route_refcount {
+                            return Err(MqttError::from(format!(
+                                "route_refcount is {} on node {}, but {} on this node",
+                                route_refcount, node_id, self.cfg.route_refcount
+                            )));
+                        }
+                    }
+                }
+                Ok(reply) => {
+                    log::warn!("check route_refcount, node: {}, unexpected reply: {:?}", node_id, reply);
+                }
+                Err(e) => {
+                    log::warn!("check route_refcount, node: {}, error: {:?}", node_id, e);
+                }
+            }
+        }
+        Ok(())
+    }
+
     fn raft_mailbox(&self) -> Mailbox {
         if let Some(raft_mailbox) = &self.raft_mailbox {
             raft_mailbox.clone()
@@ -223,6 +302,8 @@ impl Plugin for ClusterPlugin {
     #[inline]
     async fn init(&mut self) -> Result<()> {
         log::info!("{} init", self.name());
+        MessageTypes::instance().claim(self.name(), "cluster", self.cfg.message_type)?;
+        self.check_route_refcount().await?;
 
         let raft_mailbox = Self::start_raft(self.cfg.clone(), self.router).await?;
 
@@ -247,6 +328,12 @@ impl Plugin for ClusterPlugin {
         self.hook_register(Type::ClientDisconnected).await;
         self.hook_register(Type::SessionTerminated).await;
         self.hook_register(Type::GrpcMessageReceived).await;
+        if self.cfg.partition.enable && self.cfg.partition.mode == PartitionMode::Reject {
+            self.hook_register(Type::MessagePublishCheckAcl).await;
+        }
+        if self.cfg.route_refcount {
+            self.hook_register(Type::BeforeShutdown).await;
+        }
 
         Ok(())
     }
@@ -263,6 +350,46 @@ impl Plugin for ClusterPlugin {
         *self.runtime.extends.router_mut().await = Box::new(self.router);
         *self.runtime.extends.shared_mut().await = Box::new(self.shared);
         self.register.start().await;
+
+        //The new nodes join the raft group by themselves, the gRPC clients follow the discovered nodes
+        if self.cfg.discovery.is_enable() {
+            let shared = self.shared;
+            let router = self.router;
+            let grpc_discovery = self.cfg.discovery.clone();
+            let task = self.cfg.discovery.clone().watch(self.discovered.clone(), move |nodes| {
+                let node_grpc_addrs = grpc_discovery.grpc_addrs(&nodes);
+                async move {
+                    let grpc_clients = shared.grpc_clients();
+                    match discovery::update_grpc_clients(&grpc_clients, &node_grpc_addrs).await {
+                        Ok(Some(new_grpc_clients)) => {
+                            log::info!("node_grpc_addrs changed, {:?}", node_grpc_addrs);
+                            shared.set_grpc_clients(new_grpc_clients, node_names(&node_grpc_addrs));
+                            //The node routes of the nodes that left the cluster are removed
+                            if router.route_refcount {
+                                let lefts = grpc_clients
+                                    .keys()
+                                    .filter(|id| !node_grpc_addrs.iter().any(|n| n.id == **id))
+                                    .copied()
+                                    .collect::<Vec<_>>();
+                                for node_id in lefts {
+                                    log::info!("node {} left the cluster, removing its node routes", node_id);
+                                    router.remove_node_routes(node_id).await;
+                                }
+                            }
+                        }
+                        Ok(None) => {}
+                        Err(e) => log::warn!("update gRPC clients error, {:?}", e),
+                    }
+                }
+            });
+            self.discovery_task.replace(task);
+        }
+
+        if self.cfg.partition.enable {
+            let task = self.router.partition.start(self.shared, raft_mailbox.clone());
+            self.partition_task.replace(task);
+        }
+
         let status = raft_mailbox.status().await.map_err(anyhow::Error::new)?;
         log::info!("raft status: {:?}", status);
         if !status.is_started() {
@@ -275,6 +402,7 @@ impl Plugin for ClusterPlugin {
                 Ok(reply) => match message::MessageReply::decode(&reply)? {
                     message::MessageReply::Ping => {
                         log::info!("ping ok");
+                        self.route_warmup().await;
                         return Ok(());
                     }
                     message::MessageReply::Error(e) => {
@@ -315,10 +443,13 @@ impl Plugin for ClusterPlugin {
         }
 
         let mut nodes = HashMap::default();
-        for (node_id, (_, c)) in self.grpc_clients.iter() {
+        for (node_id, (_, c)) in self.shared.grpc_clients().iter() {
             let stats = json!({
                 "channel_tasks": c.channel_tasks(),
                 "active_tasks": c.active_tasks(),
+                "congested": c.is_congested(),
+                "dropped": c.dropped(),
+                "spilled": c.spilled(),
             });
             nodes.insert(*node_id, stats);
         }
@@ -329,6 +460,8 @@ impl Plugin for ClusterPlugin {
             "raft_status": raft_status,
             "raft_pears": pears,
             "client_states": self.router.states_count(),
+            "partition": self.router.partition.to_json(),
+            "route_warmup": RouteSync::instance().to_json(),
             "task_exec_queue": {
                 "waiting_count": exec.waiting_count(),
                 "active_count": exec.active_count(),
@@ -406,6 +539,11 @@ impl MessageSender {
     }
 }
 
+#[inline]
+fn node_names(node_grpc_addrs: &[NodeAddr]) -> HashMap<NodeId, NodeName> {
+    node_grpc_addrs.iter().map(|n| (n.id, format!("{}@{}", n.id, n.addr))).collect()
+}
+
 #[inline]
 pub(crate) async fn hook_message_dropped(droppeds: Vec<(To, From, Publish, Reason)>) {
     for (to, from, publish, reason) in droppeds {
@@ -17,6 +17,11 @@ pub enum Message<'a> {
     //get client node id
     GetClientNodeId { client_id: &'a str },
     Ping,
+    //The node has subscribers of the topic filter, for route_refcount
+    AddNodeRoute { topic_filter: &'a str, node_id: NodeId },
+    RemoveNodeRoute { topic_filter: &'a str, node_id: NodeId },
+    //The node left the cluster, all its node routes are removed
+    RemoveNodeRoutes { node_id: NodeId },
 }
 
 impl<'a> Message<'a> {
@@ -59,7 +64,6 @@ pub(crate) async fn get_client_node_id(raft_mailbox: Mailbox, client_id: &str) -
     }
 }
 
-#[allow(dead_code)]
 #[inline]
 pub async fn ping(raft_mailbox: &Mailbox) -> Result<Option<MessageReply>> {
     let msg = Message::Ping.encode()?;
@@ -74,6 +78,8 @@ pub async fn ping(raft_mailbox: &Mailbox) -> Result<Option<MessageReply>> {
 #[derive(Serialize, Deserialize, Debug)]
 pub enum RaftGrpcMessage {
     GetRaftStatus,
+    //The route_refcount of the node, it must be the same on all nodes of the cluster
+    GetRouteRefcount,
 }
 
 impl RaftGrpcMessage {
@@ -90,6 +96,7 @@ impl RaftGrpcMessage {
 #[derive(Serialize, Deserialize, Debug)]
 pub enum RaftGrpcMessageReply {
     GetRaftStatus(Status),
+    GetRouteRefcount(bool),
 }
 
 impl RaftGrpcMessageReply {
@@ -0,0 +1,213 @@
+//! Split-brain detection, a node that can not commit a raft proposal has lost the quorum,
+//! after `quorum_lost_timeout` it is quarantined until the quorum is reachable again, then
+//! the routing table entries of the local sessions are resynchronized.
+
+use std::collections::{HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use rmqtt_raft::Mailbox;
+
+use rmqtt::rust_box::std_ext::RwLock;
+use rmqtt::{
+    anyhow, log,
+    serde_json::{self, json},
+    timestamp_millis,
+    tokio::{self, task::JoinHandle, time::Instant},
+};
+use rmqtt::{ClientId, Id, MqttError, Result, Runtime, TimestampMillis, TopicFilter};
+
+use super::config::{PartitionConfig, PartitionMode};
+use super::message::{self, Message, MessageReply};
+use super::shared::ClusterShared;
+
+#[derive(Debug, Clone, Serialize)]
+struct Timeline {
+    quorum_lost_at: TimestampMillis,
+    degraded_at: TimestampMillis,
+    restored_at: Option<TimestampMillis>,
+    reason: String,
+    resync_adds: usize,
+    resync_removes: usize,
+}
+
+pub(crate) struct Partition {
+    cfg: PartitionConfig,
+    degraded: AtomicBool,
+    timelines: RwLock<VecDeque<Timeline>>,
+}
+
+impl Partition {
+    pub(crate) fn new(cfg: PartitionConfig) -> Self {
+        Self { cfg, degraded: AtomicBool::new(false), timelines: RwLock::new(VecDeque::new()) }
+    }
+
+    ///The routing table is frozen
+    #[inline]
+    pub(crate) fn is_degraded(&self) -> bool {
+        self.degraded.load(Ordering::SeqCst)
+    }
+
+    ///Publishes are rejected as well
+    #[inline]
+    pub(crate) fn is_rejected(&self) -> bool {
+        self.cfg.mode == PartitionMode::Reject && self.is_degraded()
+    }
+
+    #[inline]
+    pub(crate) fn check(&self) -> Result<()> {
+        if self.is_degraded() {
+            Err(MqttError::from("cluster degraded, the quorum is lost"))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        json!({
+            "enable": self.cfg.enable,
+            "mode": self.cfg.mode,
+            "degraded": self.is_degraded(),
+            "timelines": self.timelines.read().iter().collect::<Vec<_>>(),
+        })
+    }
+
+    ///Periodically checks the quorum, quarantines this node when the quorum is lost and
+    ///resynchronizes when it is restored
+    pub(crate) fn start(&'static self, shared: &'static ClusterShared, mailbox: Mailbox) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut quorum_lost_at: Option<(Instant, TimestampMillis)> = None;
+            loop {
+                tokio::time::sleep(self.cfg.check_interval).await;
+                match (self.quorum_check(&mailbox).await, self.is_degraded()) {
+                    (Ok(()), false) => {
+                        if quorum_lost_at.take().is_some() {
+                            log::info!("raft quorum recovered before quarantine");
+                        }
+                    }
+                    (Ok(()), true) => {
+                        log::info!("raft quorum restored, resync routing table ...");
+                        let (adds, removes) = match resync(shared, &mailbox).await {
+                            Ok(res) => res,
+                            Err(e) => {
+                                log::warn!("resync routing table error, {:?}", e);
+                                continue;
+                            }
+                        };
+                        quorum_lost_at = None;
+                        self.restored(adds, removes).await;
+                    }
+                    (Err(e), false) => {
+                        let (lost_at, lost_at_millis) =
+                            *quorum_lost_at.get_or_insert_with(|| (Instant::now(), timestamp_millis()));
+                        log::warn!("raft quorum check failed, {}", e);
+                        if lost_at.elapsed() >= self.cfg.quorum_lost_timeout {
+                            self.degraded(lost_at_millis, e.to_string()).await;
+                        }
+                    }
+                    (Err(e), true) => {
+                        log::debug!("raft quorum check failed, {}", e);
+                    }
+                }
+            }
+        })
+    }
+
+    async fn quorum_check(&self, mailbox: &Mailbox) -> Result<()> {
+        let status = mailbox.status().await.map_err(anyhow::Error::new)?;
+        if status.leader_id == 0 {
+            return Err(MqttError::from("leader does not exist"));
+        }
+        match tokio::time::timeout(self.cfg.check_timeout, message::ping(mailbox)).await {
+            Ok(Ok(Some(MessageReply::Ping))) => Ok(()),
+            Ok(Ok(reply)) => Err(MqttError::from(format!("unexpected ping reply, {:?}", reply))),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(MqttError::from("ping proposal timeout")),
+        }
+    }
+
+    async fn degraded(&self, quorum_lost_at: TimestampMillis, reason: String) {
+        log::error!("cluster degraded, mode: {:?}, reason: {}", self.cfg.mode, reason);
+        self.degraded.store(true, Ordering::SeqCst);
+        {
+            let mut timelines = self.timelines.write();
+            while timelines.len() >= self.cfg.max_timelines.max(1) {
+                timelines.pop_front();
+            }
+            timelines.push_back(Timeline {
+                quorum_lost_at,
+                degraded_at: timestamp_millis(),
+                restored_at: None,
+                reason: reason.clone(),
+                resync_adds: 0,
+                resync_removes: 0,
+            });
+        }
+        //hook, cluster_degraded
+        Runtime::instance().extends.hook_mgr().await.cluster_degraded(true, reason).await;
+    }
+
+    async fn restored(&self, adds: usize, removes: usize) {
+        log::info!("cluster restored, resync adds: {}, removes: {}", adds, removes);
+        if let Some(timeline) = self.timelines.write().back_mut() {
+            timeline.restored_at = Some(timestamp_millis());
+            timeline.resync_adds = adds;
+            timeline.resync_removes = removes;
+        }
+        self.degraded.store(false, Ordering::SeqCst);
+        //hook, cluster_degraded
+        Runtime::instance().extends.hook_mgr().await.cluster_degraded(false, "quorum restored".into()).await;
+    }
+}
+
+///Proposes the subscriptions of the local sessions, and removes the routes of this node
+///whose sessions or subscriptions no longer exist, both may be lost during the partition
+async fn resync(shared: &'static ClusterShared, mailbox: &Mailbox) -> Result<(usize, usize)> {
+    let mut locals: HashSet<(TopicFilter, ClientId)> = HashSet::new();
+    let mut adds = 0;
+    let entries = shared.inner().iter().collect::<Vec<_>>();
+    for entry in entries {
+        let id = entry.id();
+        for sub in entry.subscriptions().await.unwrap_or_default() {
+            //With route_refcount, the non-shared subscriptions are routed by the node routes
+            if !shared.router().route_refcount || sub.opts.has_shared_group() {
+                let msg = Message::Add { topic_filter: &sub.topic, id: id.clone(), opts: sub.opts.clone() }
+                    .encode()?;
+                propose(mailbox, msg).await?;
+                adds += 1;
+            }
+            locals.insert((sub.topic, id.client_id.clone()));
+        }
+    }
+    //The node routes removed meanwhile are left, at worst a publish is forwarded here for nothing
+    for topic_filter in shared.router().route_filters() {
+        let msg =
+            Message::AddNodeRoute { topic_filter: &topic_filter, node_id: Runtime::instance().node.id() }
+                .encode()?;
+        propose(mailbox, msg).await?;
+        adds += 1;
+    }
+
+    let mut removes = 0;
+    let stales: Vec<(TopicFilter, Id)> = shared
+        .router()
+        .relations_of(Runtime::instance().node.id())
+        .into_iter()
+        .filter(|(topic_filter, id)| !locals.contains(&(topic_filter.clone(), id.client_id.clone())))
+        .collect();
+    for (topic_filter, id) in stales {
+        let msg = Message::Remove { topic_filter: &topic_filter, id }.encode()?;
+        propose(mailbox, msg).await?;
+        removes += 1;
+    }
+    Ok((adds, removes))
+}
+
+#[inline]
+async fn propose(mailbox: &Mailbox, msg: Vec<u8>) -> Result<()> {
+    tokio::time::timeout(Duration::from_secs(10), mailbox.send_proposal(msg))
+        .await
+        .map_err(|_| MqttError::from("proposal timeout"))?
+        .map_err(anyhow::Error::new)?;
+    Ok(())
+}
@@ -1,9 +1,10 @@
+use std::str::FromStr;
 use std::sync::Arc;
 use std::time::Duration;
 
 use once_cell::sync::OnceCell;
 use rmqtt_raft::{Error, Mailbox, Result as RaftResult, Store};
-use tokio::sync::RwLock;
+use tokio::sync::{mpsc, RwLock};
 
 use rmqtt::rust_box::task_exec_queue::SpawnExt;
 use rmqtt::{
@@ -12,25 +13,31 @@ use rmqtt::{
 use rmqtt::{
     broker::{
         default::DefaultRouter,
-        topic::TopicTree,
+        topic::{Topic, TopicTree},
         types::{
-            ClientId, Id, IsOnline, NodeId, Route, SubRelationsMap, SubscriptionOptions, TimestampMillis,
-            TopicFilter, TopicName,
+            ClientId, Id, IsOnline, NodeId, Route, SubRelations, SubRelationsMap, SubscriptionOptions,
+            TimestampMillis, TopicFilter, TopicName,
         },
         Router,
     },
     stats::Counter,
-    MqttError, Result,
+    MqttError, Result, Runtime,
 };
 
 use crate::task_exec_queue;
 
-use super::config::{retry, BACKOFF_STRATEGY};
+use super::config::{retry, PartitionConfig, BACKOFF_STRATEGY};
 use super::message::{Message, MessageReply};
+use super::partition::Partition;
 
 type HashMap<K, V> = std::collections::HashMap<K, V, ahash::RandomState>;
+type HashSet<K> = std::collections::HashSet<K, ahash::RandomState>;
 type DashMap<K, V> = dashmap::DashMap<K, V, ahash::RandomState>;
 
+type Relations = Vec<(TopicFilter, HashMap<ClientId, (Id, SubscriptionOptions)>)>;
+//The snapshot of the previous versions, without the node routes
+type PrevSnapshot = (TopicTree<()>, Relations, Vec<(ClientId, ClientStatus)>, Counter, Counter);
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub(crate) struct ClientStatus {
     pub id: Id,
@@ -56,17 +63,39 @@ pub(crate) struct ClusterRouter {
     raft_mailbox: Arc<RwLock<Option<Mailbox>>>,
     client_states: DashMap<ClientId, ClientStatus>,
     pub try_lock_timeout: Duration,
+    pub partition: Partition,
+    pub route_refcount: bool,
+    //Local subscribers of the non-shared topic filters, for route_refcount
+    route_refs: DashMap<TopicFilter, HashSet<ClientId>>,
+    //The 0 <-> 1 transitions of route_refs, proposed in order
+    route_tx: mpsc::UnboundedSender<(TopicFilter, bool)>,
+    route_rx: std::sync::Mutex<Option<mpsc::UnboundedReceiver<(TopicFilter, bool)>>>,
+    //Nodes subscribing to the non-shared topic filters, replicated, for route_refcount
+    node_routes: RwLock<TopicTree<NodeId>>,
 }
 
 impl ClusterRouter {
     #[inline]
-    pub(crate) fn get_or_init(try_lock_timeout: Duration) -> &'static Self {
+    pub(crate) fn get_or_init(
+        try_lock_timeout: Duration,
+        partition_cfg: PartitionConfig,
+        route_refcount: bool,
+    ) -> &'static Self {
         static INSTANCE: OnceCell<ClusterRouter> = OnceCell::new();
-        INSTANCE.get_or_init(|| Self {
-            inner: DefaultRouter::instance(),
-            raft_mailbox: Arc::new(RwLock::new(None)),
-            client_states: DashMap::default(),
-            try_lock_timeout,
+        INSTANCE.get_or_init(|| {
+            let (route_tx, route_rx) = mpsc::unbounded_channel();
+            Self {
+                inner: DefaultRouter::instance(),
+                raft_mailbox: Arc::new(RwLock::new(None)),
+                client_states: DashMap::default(),
+                try_lock_timeout,
+                partition: Partition::new(partition_cfg),
+                route_refcount,
+                route_refs: DashMap::default(),
+                route_tx,
+                route_rx: std::sync::Mutex::new(Some(route_rx)),
+                node_routes: RwLock::new(TopicTree::default()),
+            }
         })
     }
 
@@ -77,7 +106,131 @@ impl ClusterRouter {
 
     #[inline]
     pub(crate) async fn set_raft_mailbox(&self, raft_mailbox: Mailbox) {
-        self.raft_mailbox.write().await.replace(raft_mailbox);
+        self.raft_mailbox.write().await.replace(raft_mailbox.clone());
+        let route_rx = self.route_rx.lock().ok().and_then(|mut rx| rx.take());
+        if let Some(route_rx) = route_rx {
+            tokio::spawn(Self::propose_node_routes(raft_mailbox, route_rx));
+        }
+    }
+
+    //One at a time, so that the add and the remove of a topic filter are committed in order
+    async fn propose_node_routes(
+        raft_mailbox: Mailbox,
+        mut route_rx: mpsc::UnboundedReceiver<(TopicFilter, bool)>,
+    ) {
+        let node_id = Runtime::instance().node.id();
+        while let Some((topic_filter, add)) = route_rx.recv().await {
+            let msg = if add {
+                Message::AddNodeRoute { topic_filter: &topic_filter, node_id }.encode()
+            } else {
+                Message::RemoveNodeRoute { topic_filter: &topic_filter, node_id }.encode()
+            };
+            let msg = match msg {
+                Ok(msg) => msg,
+                Err(e) => {
+                    log::warn!("[Router.node_route] encode error, {:?}", e);
+                    continue;
+                }
+            };
+            if let Err(e) = retry(BACKOFF_STRATEGY.clone(), || async {
+                let msg = msg.clone();
+                let mailbox = raft_mailbox.clone();
+                let res = async move { mailbox.send_proposal(msg).await }
+                    .spawn(task_exec_queue())
+                    .result()
+                    .await
+                    .map_err(|_| MqttError::from("Router::node_route(..), task execution failure"))?
+                    .map_err(|e| MqttError::from(e.to_string()))?;
+                Ok(res)
+            })
+            .await
+            {
+                log::warn!(
+                    "[Router.node_route] Failed to propose, topic_filter: {}, add: {}, {:?}",
+                    topic_filter,
+                    add,
+                    e
+                );
+            }
+        }
+    }
+
+    ///The node left the cluster, its node routes are removed, for route_refcount
+    pub(crate) async fn remove_node_routes(&self, node_id: NodeId) {
+        let msg = match (Message::RemoveNodeRoutes { node_id }).encode() {
+            Ok(msg) => msg,
+            Err(e) => {
+                log::warn!("[Router.remove_node_routes] encode error, {:?}", e);
+                return;
+            }
+        };
+        let raft_mailbox = self.raft_mailbox().await;
+        if let Err(e) = retry(BACKOFF_STRATEGY.clone(), || async {
+            let msg = msg.clone();
+            let mailbox = raft_mailbox.clone();
+            let res = async move { mailbox.send_proposal(msg).await }
+                .spawn(task_exec_queue())
+                .result()
+                .await
+                .map_err(|_| MqttError::from("Router::remove_node_routes(..), task execution failure"))?
+                .map_err(|e| MqttError::from(e.to_string()))?;
+            Ok(res)
+        })
+        .await
+        {
+            log::warn!("[Router.remove_node_routes] Failed to propose, node_id: {}, {:?}", node_id, e);
+        }
+    }
+
+    ///Counts the local subscriber of the non-shared topic filter, the first one and the last one are
+    ///proposed
+    #[inline]
+    fn route_ref(&self, topic_filter: &str, client_id: &ClientId, add: bool) {
+        if add {
+            let mut clients = self.route_refs.entry(TopicFilter::from(topic_filter)).or_default();
+            if clients.insert(client_id.clone()) && clients.len() == 1 {
+                let _ = self.route_tx.send((TopicFilter::from(topic_filter), true));
+            }
+        } else if let dashmap::mapref::entry::Entry::Occupied(mut entry) =
+            self.route_refs.entry(TopicFilter::from(topic_filter))
+        {
+            if entry.get_mut().remove(client_id) && entry.get().is_empty() {
+                entry.remove();
+                let _ = self.route_tx.send((TopicFilter::from(topic_filter), false));
+            }
+        }
+    }
+
+    #[inline]
+    fn is_route_ref(&self, topic_filter: &str, client_id: &ClientId) -> bool {
+        self.route_refs.get(topic_filter).map(|clients| clients.contains(client_id)).unwrap_or(false)
+    }
+
+    ///The non-shared topic filters subscribed on this node, for route_refcount
+    #[inline]
+    pub(crate) fn route_filters(&self) -> Vec<TopicFilter> {
+        self.route_refs.iter().map(|entry| entry.key().clone()).collect()
+    }
+
+    ///With route_refcount, the publishes forwarded from the other nodes carry the shared subscription
+    ///relations only, the non-shared ones are matched here
+    #[inline]
+    pub(crate) async fn local_relations(&self, id: Id, topic: &TopicName) -> SubRelations {
+        if !self.route_refcount {
+            return SubRelations::new();
+        }
+        match self.inner.matches(id, topic).await {
+            Ok(mut relations_map) => relations_map
+                .remove(&Runtime::instance().node.id())
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|(_, _, _, _, group)| group.is_none())
+                .collect(),
+            Err(e) => {
+                log::warn!("local relations, topic:{:?}, error: {:?}", topic, e);
+                SubRelations::new()
+            }
+        }
     }
 
     #[inline]
@@ -105,6 +258,24 @@ impl ClusterRouter {
         self.client_states.get(client_id).map(|entry| entry.value().clone())
     }
 
+    ///Routes of the sessions on the node
+    #[inline]
+    pub(crate) fn relations_of(&self, node_id: NodeId) -> Vec<(TopicFilter, Id)> {
+        self.inner
+            .relations
+            .iter()
+            .flat_map(|entry| {
+                let topic_filter = entry.key().clone();
+                entry
+                    .value()
+                    .values()
+                    .filter(|(id, _)| id.node_id == node_id)
+                    .map(|(id, _)| (topic_filter.clone(), id.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
     #[inline]
     pub(crate) fn _handshakings(&self) -> usize {
         self.client_states.iter().filter_map(|entry| if entry.handshaking { Some(()) } else { None }).count()
@@ -116,6 +287,15 @@ impl Router for &'static ClusterRouter {
     #[inline]
     async fn add(&self, topic_filter: &str, id: Id, opts: SubscriptionOptions) -> Result<()> {
         log::debug!("[Router.add] topic_filter: {:?}, id: {:?}, opts: {:?}", topic_filter, id, opts);
+        self.partition.check()?;
+
+        //Local only, the node route is proposed by the first subscriber
+        if self.route_refcount && !opts.has_shared_group() {
+            let client_id = id.client_id.clone();
+            self.inner.add(topic_filter, id, opts).await?;
+            self.route_ref(topic_filter, &client_id, true);
+            return Ok(());
+        }
 
         let msg = Message::Add { topic_filter, id, opts }.encode()?;
         let mailbox = self.raft_mailbox().await;
@@ -130,6 +310,14 @@ impl Router for &'static ClusterRouter {
     #[inline]
     async fn remove(&self, topic_filter: &str, id: Id) -> Result<bool> {
         log::debug!("[Router.remove] topic_filter: {:?}, id: {:?}", topic_filter, id);
+        //The stale routes are removed by the resync after the quorum is restored
+        self.partition.check()?;
+        if self.route_refcount && self.is_route_ref(topic_filter, &id.client_id) {
+            let client_id = id.client_id.clone();
+            let removed = self.inner.remove(topic_filter, id).await?;
+            self.route_ref(topic_filter, &client_id, false);
+            return Ok(removed);
+        }
         let msg = Message::Remove { topic_filter, id: id.clone() }.encode()?;
         let raft_mailbox = self.raft_mailbox().await;
         tokio::spawn(async move {
@@ -152,9 +340,39 @@ impl Router for &'static ClusterRouter {
         Ok(true)
     }
 
+    #[inline]
+    async fn remove_client(&self, client_id: &str) -> Result<usize> {
+        let relations = self.inner.client_relations(client_id);
+        let mut removed = 0;
+        for (topic_filter, id) in relations {
+            if self.remove(&topic_filter, id).await? {
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    #[inline]
+    async fn relations(&self, node_id: NodeId) -> Vec<(TopicFilter, Id)> {
+        self.inner.relations(node_id).await
+    }
+
     #[inline]
     async fn matches(&self, id: Id, topic: &TopicName) -> Result<SubRelationsMap> {
-        self.inner.matches(id, topic).await
+        let mut relations_map = self.inner.matches(id, topic).await?;
+        if self.route_refcount {
+            //The other nodes subscribing, they match their own subscribers
+            let this_node_id = Runtime::instance().node.id();
+            let t = Topic::from_str(topic)?;
+            for (_, node_ids) in self.node_routes.read().await.matches(&t).iter() {
+                for node_id in node_ids {
+                    if *node_id != this_node_id {
+                        relations_map.entry(*node_id).or_default();
+                    }
+                }
+            }
+        }
+        Ok(relations_map)
     }
 
     ///Check online or offline
@@ -207,6 +425,11 @@ impl Router for &'static ClusterRouter {
     async fn list_relations(&self, top: usize) -> Vec<serde_json::Value> {
         self.inner.list_relations(top).await
     }
+
+    #[inline]
+    async fn memory_info(&self) -> serde_json::Value {
+        self.inner.memory_info().await
+    }
 }
 
 #[async_trait]
@@ -311,6 +534,26 @@ impl Store for &'static ClusterRouter {
                 return Ok(data);
             }
             Message::Ping => return MessageReply::Ping.encode().map_err(|_e| Error::Unknown),
+            Message::AddNodeRoute { topic_filter, node_id } => {
+                log::debug!("[Router.AddNodeRoute] topic_filter: {:?}, node_id: {}", topic_filter, node_id);
+                let topic =
+                    Topic::from_str(topic_filter).map_err(|e| Error::Other(Box::new(MqttError::from(e))))?;
+                self.node_routes.write().await.insert(&topic, node_id);
+            }
+            Message::RemoveNodeRoute { topic_filter, node_id } => {
+                log::debug!(
+                    "[Router.RemoveNodeRoute] topic_filter: {:?}, node_id: {}",
+                    topic_filter,
+                    node_id
+                );
+                let topic =
+                    Topic::from_str(topic_filter).map_err(|e| Error::Other(Box::new(MqttError::from(e))))?;
+                self.node_routes.write().await.remove(&topic, &node_id);
+            }
+            Message::RemoveNodeRoutes { node_id } => {
+                let removed = self.node_routes.write().await.remove_all(&node_id);
+                log::info!("[Router.RemoveNodeRoutes] node_id: {}, removed: {}", node_id, removed);
+            }
         }
 
         Ok(Vec::new())
@@ -332,6 +575,8 @@ impl Store for &'static ClusterRouter {
         Ok(Vec::new())
     }
 
+    ///The snapshot is (topics, relations, client_states, topics_count, relations_count, node_routes),
+    ///the node routes are added for route_refcount
     async fn snapshot(&self) -> RaftResult<Vec<u8>> {
         log::debug!("create snapshot ...");
         let relations = &self
@@ -348,6 +593,7 @@ impl Store for &'static ClusterRouter {
 
         let topics_count = &self.inner.topics_count;
         let relations_count = &self.inner.relations_count;
+        let node_routes = self.node_routes.read().await;
 
         let snapshot = bincode::serialize(&(
             self.inner.topics.read().await.as_ref(),
@@ -355,22 +601,59 @@ impl Store for &'static ClusterRouter {
             client_states,
             topics_count,
             relations_count,
+            node_routes.as_ref(),
         ))
         .map_err(|e| Error::Other(e))?;
         log::info!("create snapshot, len: {}", snapshot.len());
         Ok(snapshot)
     }
 
+    ///Restores the snapshot of snapshot(), or the 5-tuple one of the previous versions with no node routes
     async fn restore(&mut self, snapshot: &[u8]) -> RaftResult<()> {
         log::info!("restore, snapshot.len: {}", snapshot.len());
 
-        let (topics, relations, client_states, topics_count, relations_count): (
-            TopicTree<()>,
-            Vec<(TopicFilter, HashMap<ClientId, (Id, SubscriptionOptions)>)>,
-            Vec<(ClientId, ClientStatus)>,
-            Counter,
-            Counter,
-        ) = bincode::deserialize(snapshot).map_err(|e| Error::Other(e))?;
+        //The snapshots without the node routes, of the previous versions, are still restored
+        let ((topics, relations, client_states, topics_count, relations_count), node_routes) =
+            match bincode::deserialize::<(
+                TopicTree<()>,
+                Relations,
+                Vec<(ClientId, ClientStatus)>,
+                Counter,
+                Counter,
+                TopicTree<NodeId>,
+            )>(snapshot)
+            {
+                Ok((topics, relations, client_states, topics_count, relations_count, node_routes)) => {
+                    ((topics, relations, client_states, topics_count, relations_count), node_routes)
+                }
+                Err(_) => (
+                    bincode::deserialize::<PrevSnapshot>(snapshot).map_err(|e| Error::Other(e))?,
+                    TopicTree::default(),
+                ),
+            };
+
+        //The non-shared subscriptions are local with route_refcount, those of this node are kept
+        let locals = if self.route_refcount {
+            self.route_refs
+                .iter()
+                .flat_map(|entry| {
+                    let topic_filter = entry.key().clone();
+                    let relations = self.inner.relations.get(&topic_filter);
+                    entry
+                        .value()
+                        .iter()
+                        .filter_map(|client_id| {
+                            relations
+                                .as_ref()
+                                .and_then(|rels| rels.get(client_id))
+                                .map(|(id, opts)| (topic_filter.clone(), id.clone(), opts.clone()))
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>()
+        } else {
+            Vec::new()
+        };
 
         *self.inner.topics.write().await = topics;
         self.inner.topics_count.set(&topics_count);
@@ -386,6 +669,126 @@ impl Store for &'static ClusterRouter {
             self.client_states.insert(client_id, content);
         }
 
+        *self.node_routes.write().await = node_routes;
+
+        if self.route_refcount {
+            //The non-shared subscriptions of the other nodes, in the snapshot, are not routed here
+            let others = self
+                .inner
+                .relations
+                .iter()
+                .flat_map(|entry| {
+                    let topic_filter = entry.key().clone();
+                    entry
+                        .value()
+                        .values()
+                        .filter(|(_, opts)| !opts.has_shared_group())
+                        .map(|(id, _)| (topic_filter.clone(), id.clone()))
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>();
+            for (topic_filter, id) in others {
+                if let Err(e) = self.inner.remove(&topic_filter, id).await {
+                    log::warn!("restore, remove non-shared relation error, {:?}", e);
+                }
+            }
+            for (topic_filter, id, opts) in locals {
+                if let Err(e) = self.inner.add(&topic_filter, id, opts).await {
+                    log::warn!("restore, add local relation error, {:?}", e);
+                }
+            }
+        }
+
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    use rmqtt_raft::Store;
+
+    use rmqtt::bincode;
+    use rmqtt::broker::topic::{Topic, TopicTree};
+    use rmqtt::broker::types::{ClientId, Id, NodeId, QoS, SubOptionsV3, SubscriptionOptions};
+    use rmqtt::futures::executor::block_on;
+    use rmqtt::stats::Counter;
+
+    use super::super::config::PartitionConfig;
+    use super::super::message::Message;
+    use super::{ClientStatus, ClusterRouter, PrevSnapshot, Relations};
+
+    //The router is a singleton, the tests are serialized
+    static LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    #[test]
+    fn test_remove_node_routes() {
+        let _guard = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut router =
+            ClusterRouter::get_or_init(Duration::from_secs(10), PartitionConfig::default(), true);
+        block_on(async {
+            for (topic_filter, node_id) in [("a/b", 1), ("a/+", 2), ("a/#", 1), ("c", 1)] {
+                router
+                    .apply(&Message::AddNodeRoute { topic_filter, node_id }.encode().unwrap())
+                    .await
+                    .unwrap();
+            }
+            router.apply(&Message::RemoveNodeRoutes { node_id: 1 }.encode().unwrap()).await.unwrap();
+
+            let node_routes = router.node_routes.read().await;
+            assert_eq!(node_routes.values_size(), 1);
+            let t = Topic::from_str("a/b").unwrap();
+            let node_ids = node_routes
+                .matches(&t)
+                .iter()
+                .flat_map(|(_, ids)| ids.into_iter().copied())
+                .collect::<Vec<_>>();
+            assert_eq!(node_ids, [2]);
+            assert!(!node_routes.is_match(&Topic::from_str("c").unwrap()));
+        });
+    }
+
+    #[test]
+    fn test_restore_prev_snapshot() {
+        let _guard = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut router =
+            ClusterRouter::get_or_init(Duration::from_secs(10), PartitionConfig::default(), true);
+        block_on(async {
+            let shared_opts = SubscriptionOptions::V3(SubOptionsV3 {
+                qos: QoS::AtLeastOnce,
+                shared_group: Some("g".into()),
+            });
+            let adds = [
+                ("s/1", Id::from(2, ClientId::from("c1")), shared_opts),
+                ("n/1", Id::from(2, ClientId::from("c2")), SubscriptionOptions::default()),
+            ];
+            for (topic_filter, id, opts) in adds {
+                router.apply(&Message::Add { topic_filter, id, opts }.encode().unwrap()).await.unwrap();
+            }
+            router
+                .apply(&Message::AddNodeRoute { topic_filter: "n/1", node_id: 2 }.encode().unwrap())
+                .await
+                .unwrap();
+
+            //A snapshot of the previous versions, without the node routes
+            let snapshot = router.snapshot().await.unwrap();
+            let (topics, relations, client_states, topics_count, relations_count, _): (
+                TopicTree<()>,
+                Relations,
+                Vec<(ClientId, ClientStatus)>,
+                Counter,
+                Counter,
+                TopicTree<NodeId>,
+            ) = bincode::deserialize(&snapshot).unwrap();
+            let prev: PrevSnapshot = (topics, relations, client_states, topics_count, relations_count);
+            router.restore(&bincode::serialize(&prev).unwrap()).await.unwrap();
+
+            assert_eq!(router.node_routes.read().await.values_size(), 0);
+            assert!(router.inner.relations.get("s/1").map(|rels| rels.contains_key("c1")).unwrap_or(false));
+            //The non-shared subscription of the other node is not routed here with route_refcount
+            assert!(router.inner.relations.get("n/1").map(|rels| rels.is_empty()).unwrap_or(true));
+        });
+    }
+}
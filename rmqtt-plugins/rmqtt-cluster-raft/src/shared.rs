@@ -4,11 +4,13 @@ use std::time::Duration;
 
 use rmqtt::{
     anyhow, anyhow::Error, async_trait::async_trait, futures, futures::future::FutureExt, log,
-    once_cell::sync::OnceCell, rust_box::task_exec_queue::SpawnExt, serde_json, serde_json::json,
+    once_cell::sync::OnceCell, rust_box::std_ext::RwLock, rust_box::task_exec_queue::SpawnExt, serde_json,
+    serde_json::json,
 };
 use rmqtt::{
     broker::{
         default::DefaultShared,
+        maintenance::Maintenance,
         session::{Session, SessionOfflineInfo},
         types::{
             From, Id, IsAdmin, NodeId, NodeName, Publish, Reason, SessionStatus, SubRelations,
@@ -49,6 +51,7 @@ impl ClusterLockEntry {
 impl Entry for ClusterLockEntry {
     #[inline]
     async fn try_lock(&self) -> Result<Box<dyn Entry>> {
+        self.cluster_shared.router.partition.check()?;
         let msg = RaftMessage::HandshakeTryLock { id: self.id() }.encode()?;
         let raft_mailbox = self.cluster_shared.router.raft_mailbox().await;
         let reply = raft_mailbox.send_proposal(msg).await.map_err(anyhow::Error::new)?;
@@ -89,6 +92,7 @@ impl Entry for ClusterLockEntry {
 
     #[inline]
     async fn set(&mut self, session: Session, tx: Tx) -> Result<()> {
+        self.cluster_shared.router.partition.check()?;
         let msg = RaftMessage::Connected { id: session.id.clone() }.encode()?;
         let raft_mailbox = self.cluster_shared.router.raft_mailbox().await;
         let reply = raft_mailbox.send_proposal(msg).await.map_err(anyhow::Error::new)?;
@@ -271,8 +275,8 @@ impl Entry for ClusterLockEntry {
 pub struct ClusterShared {
     inner: &'static DefaultShared,
     router: &'static ClusterRouter,
-    grpc_clients: GrpcClients,
-    node_names: HashMap<NodeId, NodeName>,
+    grpc_clients: RwLock<GrpcClients>,
+    node_names: RwLock<HashMap<NodeId, NodeName>>,
     pub message_type: MessageType,
 }
 
@@ -288,8 +292,8 @@ impl ClusterShared {
         INSTANCE.get_or_init(|| Self {
             inner: DefaultShared::instance(),
             router,
-            grpc_clients,
-            node_names,
+            grpc_clients: RwLock::new(grpc_clients),
+            node_names: RwLock::new(node_names),
             message_type,
         })
     }
@@ -299,9 +303,25 @@ impl ClusterShared {
         self.inner
     }
 
+    #[inline]
+    pub(crate) fn router(&self) -> &'static ClusterRouter {
+        self.router
+    }
+
     #[inline]
     pub(crate) fn grpc_client(&self, node_id: u64) -> Option<NodeGrpcClient> {
-        self.grpc_clients.get(&node_id).map(|(_, c)| c.clone())
+        self.grpc_clients.read().get(&node_id).map(|(_, c)| c.clone())
+    }
+
+    #[inline]
+    pub(crate) fn grpc_clients(&self) -> GrpcClients {
+        self.grpc_clients.read().clone()
+    }
+
+    #[inline]
+    pub(crate) fn set_grpc_clients(&self, grpc_clients: GrpcClients, node_names: HashMap<NodeId, NodeName>) {
+        *self.grpc_clients.write() = grpc_clients;
+        *self.node_names.write() = node_names;
     }
 }
 
@@ -340,6 +360,19 @@ impl Shared for &'static ClusterShared {
             }
         };
 
+        //The quorum is lost, publishes are rejected
+        if self.router.partition.is_rejected() {
+            let reason = Reason::from_static("cluster degraded");
+            let droppeds = relations_map
+                .into_iter()
+                .flat_map(|(node_id, relations)| {
+                    relations.into_iter().map(move |(_, client_id, ..)| Id::from(node_id, client_id))
+                })
+                .map(|to| (to, from.clone(), publish.clone(), reason.clone()))
+                .collect::<Vec<_>>();
+            return if droppeds.is_empty() { Ok(None) } else { Err(droppeds) };
+        }
+
         //let subs_size: SubscriptionSize = relations_map.values().map(|subs| subs.len()).sum();
         let sub_client_ids = self.inner()._collect_subscription_client_ids(&relations_map);
 
@@ -369,7 +402,12 @@ impl Shared for &'static ClusterShared {
                             max_retries: 1,
                             retry_interval: Duration::from_millis(500),
                         };
-                        (node_id, msg_sender.send().await)
+                        let reply = msg_sender.send().await;
+                        if reply.is_err() {
+                            //the node is in maintenance, the message is resent when it leaves
+                            Maintenance::instance().hold(node_id, message_type, msg_sender.msg);
+                        }
+                        (node_id, reply)
                     };
                     fut_senders.push(fut_sender.boxed());
                 } else {
@@ -460,7 +498,59 @@ impl Shared for &'static ClusterShared {
 
     #[inline]
     async fn query_subscriptions(&self, q: SubsSearchParams) -> Vec<SubsSearchResult> {
-        self.inner.query_subscriptions(q).await
+        //With route_refcount, the non-shared subscriptions are only on the node of the subscriber
+        if !self.router.route_refcount {
+            return self.inner.query_subscriptions(q).await;
+        }
+        let node_id = Runtime::instance().node.id();
+        let (offset, limit) = (q._offset, q._limit);
+        //Each node returns its first offset + limit items, the page is taken after merging
+        let mut node_q = q;
+        node_q._offset = 0;
+        node_q._limit = offset.saturating_add(limit);
+
+        let mut replys = Vec::new();
+        if node_q.node_id.map(|id| id == node_id).unwrap_or(true) {
+            replys.push(self.inner.query_subscriptions(node_q.clone()).await);
+        }
+
+        let mut grpc_clients = self
+            .grpc_clients()
+            .iter()
+            .filter(|(id, _)| node_q.node_id.map(|node_id| node_id == **id).unwrap_or(true))
+            .map(|(id, (_, c))| (*id, c.clone()))
+            .collect::<Vec<_>>();
+        //Merged in the order of node ids, so that the pages are stable
+        grpc_clients.sort_by_key(|(id, _)| *id);
+        let futs = grpc_clients.into_iter().map(|(id, client)| {
+            //The peer only returns the subscriptions of its own clients
+            let mut q = node_q.clone();
+            q.node_id = Some(id);
+            let mut sender = MessageSender {
+                client,
+                msg_type: self.message_type,
+                msg: Message::SubscriptionsSearch(q),
+                max_retries: 0,
+                retry_interval: Duration::from_millis(500),
+            };
+            async move { (id, sender.send().await) }
+        });
+        for (id, reply) in futures::future::join_all(futs).await {
+            match reply {
+                Ok(MessageReply::SubscriptionsSearch(subs)) => replys.push(subs),
+                Ok(reply) => log::warn!("query_subscriptions, node: {}, unexpected reply: {:?}", id, reply),
+                Err(e) => log::warn!("query_subscriptions, node: {}, error: {:?}", id, e),
+            }
+        }
+
+        let mut exists = HashSet::new();
+        replys
+            .into_iter()
+            .flatten()
+            .filter(|s| exists.insert((s.node_id, s.clientid.clone(), s.topic.clone())))
+            .skip(offset)
+            .take(limit)
+            .collect()
     }
 
     #[inline]
@@ -469,12 +559,12 @@ impl Shared for &'static ClusterShared {
     }
     #[inline]
     fn get_grpc_clients(&self) -> GrpcClients {
-        self.grpc_clients.clone()
+        self.grpc_clients()
     }
 
     #[inline]
     fn node_name(&self, id: NodeId) -> String {
-        self.node_names.get(&id).cloned().unwrap_or_default()
+        self.node_names.read().get(&id).cloned().unwrap_or_default()
     }
 
     #[inline]
@@ -490,17 +580,19 @@ impl Shared for &'static ClusterShared {
         leader_ids.insert(status.leader_id);
 
         let data = RaftGrpcMessage::GetRaftStatus.encode()?;
-        let replys =
-            MessageBroadcaster::new(self.grpc_clients.clone(), self.message_type, Message::Data(data))
-                .join_all()
-                .await;
+        let replys = MessageBroadcaster::new(self.grpc_clients(), self.message_type, Message::Data(data))
+            .join_all()
+            .await;
 
         for (node_id, reply) in replys {
             match reply {
                 Ok(reply) => {
                     if let MessageReply::Data(data) = reply {
                         let RaftGrpcMessageReply::GetRaftStatus(o_status) =
-                            RaftGrpcMessageReply::decode(&data)?;
+                            RaftGrpcMessageReply::decode(&data)?
+                        else {
+                            unreachable!()
+                        };
                         node_statuses.push(json!({
                             "node_id": o_status.id,
                             "leader_id": o_status.leader_id,
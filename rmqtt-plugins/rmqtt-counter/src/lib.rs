@@ -118,7 +118,7 @@ impl CounterHandler {
 
 #[async_trait]
 impl Handler for CounterHandler {
-    async fn hook(&self, param: &Parameter, acc: Option<HookResult>) -> ReturnType {
+    async fn hook(&self, param: &Parameter, _acc: &mut Option<HookResult>) -> ReturnType {
         match param {
             Parameter::ClientConnect(connect_info) => {
                 self.metrics.client_connect_inc();
@@ -239,6 +239,6 @@ impl Handler for CounterHandler {
                 log::error!("parameter is: {:?}", param);
             }
         }
-        (true, acc)
+        true
     }
 }
@@ -0,0 +1,121 @@
+use std::net::SocketAddr;
+
+use salvo::conn::tcp::TcpAcceptor;
+use salvo::prelude::*;
+
+use rmqtt::{broker::Entry, Result, Runtime, Session};
+use rmqtt::{
+    futures, log,
+    serde_json::{self, json},
+    tokio::{self, sync::oneshot},
+};
+
+use super::PluginConfigType;
+
+const INDEX_HTML: &str = include_str!("index.html");
+
+fn route(cfg: PluginConfigType) -> Router {
+    Router::new()
+        .hoop(affix::inject(cfg))
+        .get(index)
+        .push(Router::with_path("data/overview").get(overview))
+        .push(Router::with_path("data/clients").get(search_clients))
+}
+
+pub(crate) async fn listen_and_serve(
+    laddr: SocketAddr,
+    cfg: PluginConfigType,
+    rx: oneshot::Receiver<()>,
+) -> Result<()> {
+    log::info!("Dashboard Listening on {}", laddr);
+    let listen =
+        tokio::net::TcpListener::from_std(rmqtt::grpc::server::Server::bind(laddr, 128, true, false)?)?;
+    let acceptor = TcpAcceptor::try_from(listen)?;
+    let server = Server::new(acceptor);
+    let handler = server.handle();
+    tokio::task::spawn(async move {
+        rx.await.ok();
+        handler.stop_graceful(None);
+    });
+    server.try_serve(route(cfg)).await?;
+    Ok(())
+}
+
+#[handler]
+async fn index(depot: &mut Depot, res: &mut Response) -> std::result::Result<(), salvo::Error> {
+    let cfg = depot.obtain::<PluginConfigType>()?.clone();
+    let refresh_interval = cfg.read().await.refresh_interval.as_millis().max(500);
+    res.render(Text::Html(INDEX_HTML.replace("{{refresh_interval}}", &refresh_interval.to_string())));
+    Ok(())
+}
+
+#[handler]
+async fn overview(res: &mut Response) {
+    let node = &Runtime::instance().node;
+    let stats = Runtime::instance().stats.clone().await;
+    let mut plugins = Vec::new();
+    for entry in Runtime::instance().plugins.iter() {
+        match entry.to_info(entry.key()).await {
+            Ok(p) => plugins.push(json!({
+                "name": p.name,
+                "version": p.version,
+                "descr": p.descr,
+                "inited": p.inited,
+                "active": p.active,
+                "immutable": p.immutable,
+                "health": p.health,
+            })),
+            Err(e) => log::warn!("dashboard, {} plugin info error, {:?}", entry.key(), e),
+        }
+    }
+    res.render(Json(json!({
+        "broker": node.broker_info().await.to_json(),
+        "node": node.node_info().await.to_json(),
+        "stats": stats.to_json().await,
+        "metrics": Runtime::instance().metrics.to_json(),
+        "plugins": plugins,
+    })));
+}
+
+///The sessions of this node whose client id or username contains `q`, all if it is empty
+#[handler]
+async fn search_clients(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> std::result::Result<(), salvo::Error> {
+    let cfg = depot.obtain::<PluginConfigType>()?.clone();
+    let max_search_results = cfg.read().await.max_search_results;
+    let q = req.query::<String>("q").unwrap_or_default();
+    let limit = req.query::<usize>("limit").unwrap_or(max_search_results).min(max_search_results);
+    let sessions = Runtime::instance()
+        .extends
+        .shared()
+        .await
+        .iter()
+        .filter(|entry| {
+            let id = entry.id();
+            q.is_empty() || id.client_id.contains(q.as_str()) || id.username_ref().contains(q.as_str())
+        })
+        .filter_map(|entry| entry.session())
+        .take(limit)
+        .collect::<Vec<_>>();
+    let clients = futures::future::join_all(sessions.iter().map(client_json)).await;
+    res.render(Json(serde_json::Value::Array(clients)));
+    Ok(())
+}
+
+async fn client_json(s: &Session) -> serde_json::Value {
+    let subscriptions = if let Ok(subs) = s.subscriptions().await { subs.len().await } else { 0 };
+    json!({
+        "clientid": s.id.client_id,
+        "username": s.id.username_ref(),
+        "ip_address": s.id.remote_addr.map(|addr| addr.to_string()),
+        "proto_ver": s.connect_info().await.map(|c| c.proto_ver()).unwrap_or_default(),
+        "connected": s.connected().await.unwrap_or_default(),
+        "connected_at": s.connected_at().await.unwrap_or_default(),
+        "subscriptions": subscriptions,
+        "inflight": s.inflight_win().read().await.len(),
+        "mqueue_len": s.deliver_queue().len(),
+    })
+}
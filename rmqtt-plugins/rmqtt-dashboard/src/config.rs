@@ -0,0 +1,49 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use rmqtt::settings::{deserialize_addr, deserialize_duration};
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct PluginConfig {
+    #[serde(default = "PluginConfig::workers_default")]
+    pub workers: usize,
+
+    #[serde(default = "PluginConfig::http_laddr_default", deserialize_with = "deserialize_addr")]
+    pub http_laddr: SocketAddr,
+
+    ///Interval between two refreshes of the page
+    #[serde(default = "PluginConfig::refresh_interval_default", deserialize_with = "deserialize_duration")]
+    pub refresh_interval: Duration,
+
+    ///Maximum number of the clients returned by a search
+    #[serde(default = "PluginConfig::max_search_results_default")]
+    pub max_search_results: usize,
+}
+
+impl PluginConfig {
+    #[inline]
+    fn workers_default() -> usize {
+        1
+    }
+
+    #[inline]
+    fn http_laddr_default() -> SocketAddr {
+        "0.0.0.0:6070".parse::<std::net::SocketAddr>().unwrap()
+    }
+
+    #[inline]
+    fn refresh_interval_default() -> Duration {
+        Duration::from_secs(2)
+    }
+
+    #[inline]
+    fn max_search_results_default() -> usize {
+        100
+    }
+
+    ///The HTTP server is restarted if its address or its workers change
+    #[inline]
+    pub fn restart_enable(&self, other: &Self) -> bool {
+        self.workers != other.workers || self.http_laddr != other.http_laddr
+    }
+}
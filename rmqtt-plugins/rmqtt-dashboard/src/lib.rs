@@ -0,0 +1,130 @@
+#![deny(unsafe_code)]
+#[macro_use]
+extern crate serde;
+
+#[macro_use]
+extern crate rmqtt_macros;
+
+use std::sync::Arc;
+
+use config::PluginConfig;
+use rmqtt::{
+    async_trait::async_trait,
+    log, serde_json,
+    tokio::{self, sync::oneshot, sync::RwLock},
+};
+use rmqtt::{
+    plugin::{PackageInfo, Plugin},
+    register, Result, Runtime,
+};
+
+mod api;
+mod config;
+
+type ShutdownTX = oneshot::Sender<()>;
+type PluginConfigType = Arc<RwLock<PluginConfig>>;
+
+register!(DashboardPlugin::new);
+
+#[derive(Plugin)]
+struct DashboardPlugin {
+    runtime: &'static Runtime,
+    cfg: PluginConfigType,
+    shutdown_tx: Option<ShutdownTX>,
+}
+
+impl DashboardPlugin {
+    #[inline]
+    async fn new<S: Into<String>>(runtime: &'static Runtime, name: S) -> Result<Self> {
+        let name = name.into();
+        let cfg = runtime.settings.plugins.load_config_default::<PluginConfig>(&name)?;
+        log::debug!("{} DashboardPlugin cfg: {:?}", name, cfg);
+        Ok(Self { runtime, cfg: Arc::new(RwLock::new(cfg)), shutdown_tx: None })
+    }
+
+    //The HTTP server runs on its own thread, it is stopped by the returned sender
+    async fn serve(cfg: PluginConfigType) -> ShutdownTX {
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let (workers, http_laddr) = {
+            let cfg = cfg.read().await;
+            (cfg.workers, cfg.http_laddr)
+        };
+        let _child = std::thread::Builder::new().name("dashboard".to_string()).spawn(move || {
+            let runner = async move {
+                if let Err(e) = api::listen_and_serve(http_laddr, cfg, shutdown_rx).await {
+                    log::error!("{:?}", e);
+                }
+            };
+            let rt = tokio::runtime::Builder::new_multi_thread()
+                .enable_all()
+                .worker_threads(workers.max(1))
+                .thread_name("dashboard-worker")
+                .build()
+                .unwrap();
+            rt.block_on(runner);
+            log::info!("Exit Dashboard Server, ..., http://{:?}", http_laddr);
+        });
+        shutdown_tx
+    }
+
+    #[inline]
+    fn shutdown(&mut self) {
+        if let Some(tx) = self.shutdown_tx.take() {
+            if tx.send(()).is_err() {
+                log::warn!("dashboard shutdown_tx send fail");
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Plugin for DashboardPlugin {
+    #[inline]
+    async fn init(&mut self) -> Result<()> {
+        log::info!("{} init", self.name());
+        Ok(())
+    }
+
+    #[inline]
+    async fn get_config(&self) -> Result<serde_json::Value> {
+        Ok(serde_json::to_value(&*self.cfg.read().await)?)
+    }
+
+    #[inline]
+    async fn load_config(&mut self) -> Result<()> {
+        let new_cfg = self.runtime.settings.plugins.load_config::<PluginConfig>(self.name())?;
+        let restart_enable = self.cfg.read().await.restart_enable(&new_cfg);
+        *self.cfg.write().await = new_cfg;
+        if restart_enable && self.shutdown_tx.is_some() {
+            self.shutdown();
+            self.shutdown_tx = Some(Self::serve(self.cfg.clone()).await);
+        }
+        log::debug!("load_config ok,  {:?}", self.cfg);
+        Ok(())
+    }
+
+    #[inline]
+    async fn start(&mut self) -> Result<()> {
+        log::info!("{} start", self.name());
+        if self.shutdown_tx.is_none() {
+            self.shutdown_tx = Some(Self::serve(self.cfg.clone()).await);
+        }
+        Ok(())
+    }
+
+    #[inline]
+    async fn stop(&mut self) -> Result<bool> {
+        log::info!("{} stop", self.name());
+        self.shutdown();
+        Ok(true)
+    }
+
+    #[inline]
+    async fn attrs(&self) -> serde_json::Value {
+        let cfg = self.cfg.read().await;
+        serde_json::json!({
+            "url": format!("http://{}/", cfg.http_laddr),
+            "serving": self.shutdown_tx.is_some(),
+        })
+    }
+}
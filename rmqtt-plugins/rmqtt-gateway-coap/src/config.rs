@@ -0,0 +1,91 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use rmqtt::broker::datagram::DtlsConfig;
+use rmqtt::settings::listener::{Listener, ListenerInner};
+use rmqtt::settings::{deserialize_addr, deserialize_duration};
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PluginConfig {
+    ///UDP listen address
+    #[serde(default = "PluginConfig::listen_default", deserialize_with = "deserialize_addr")]
+    pub listen: SocketAddr,
+    ///First URI path segment of the pub/sub resources, e.g. "ps" for coap://host/ps/{topic}
+    #[serde(default = "PluginConfig::path_prefix_default")]
+    pub path_prefix: String,
+    ///DTLS, coaps://
+    #[serde(default)]
+    pub dtls: DtlsConfig,
+
+    ///Allow requests without a client id (query "c"), they use the remote address as client id
+    #[serde(default = "PluginConfig::allow_anonymous_default")]
+    pub allow_anonymous: bool,
+    ///The keep alive of the client session, it is closed without requests
+    #[serde(
+        default = "PluginConfig::client_idle_timeout_default",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub client_idle_timeout: Duration,
+
+    ///Lifetime of an observation if it is not refreshed by the client
+    #[serde(default = "PluginConfig::observe_lifetime_default", deserialize_with = "deserialize_duration")]
+    pub observe_lifetime: Duration,
+    ///Maximum number of observations of the gateway
+    #[serde(default = "PluginConfig::max_observations_default")]
+    pub max_observations: usize,
+
+    #[serde(default)]
+    pub retain_available: bool,
+    ///Message expiry interval if the request has no Max-Age option
+    #[serde(default = "PluginConfig::expiry_interval_default", deserialize_with = "deserialize_duration")]
+    pub expiry_interval: Duration,
+}
+
+impl PluginConfig {
+    fn listen_default() -> SocketAddr {
+        ([0, 0, 0, 0], 5683).into()
+    }
+
+    fn path_prefix_default() -> String {
+        "ps".into()
+    }
+
+    fn allow_anonymous_default() -> bool {
+        true
+    }
+
+    fn client_idle_timeout_default() -> Duration {
+        Duration::from_secs(300)
+    }
+
+    fn observe_lifetime_default() -> Duration {
+        Duration::from_secs(3600)
+    }
+
+    fn max_observations_default() -> usize {
+        100_000
+    }
+
+    fn expiry_interval_default() -> Duration {
+        Duration::from_secs(300)
+    }
+
+    ///The listener of the client sessions
+    pub fn listener(&self) -> Listener {
+        Listener::new(ListenerInner {
+            name: "coap".into(),
+            addr: self.listen,
+            allow_anonymous: self.allow_anonymous,
+            max_keepalive: u16::MAX,
+            retain_available: self.retain_available,
+            message_expiry_interval: self.expiry_interval,
+            ..Default::default()
+        })
+    }
+
+    ///The keep alive of the client session, see Fitter::keep_alive
+    #[inline]
+    pub fn keep_alive(&self) -> u16 {
+        self.client_idle_timeout.as_secs().clamp(1, u16::MAX as u64) as u16
+    }
+}
@@ -0,0 +1,677 @@
+//! CoAP gateway:
+//!
+//! PUT|POST /ps/{topic}?c={clientid}&u={username}&p={password}&qos={0|1|2}&retain={true|false}
+//!     publish the payload to {topic}
+//! GET /ps/{topic filter}, with Observe=0
+//!     observe {topic filter}, every matching message is sent as a notification
+//! GET /ps/{topic filter}, with Observe=1
+//!     cancel the observation
+//! GET /ps/{topic}
+//!     fetch the retained message of {topic}
+//!
+//! CoAP options are mapped to MQTT 5 properties:
+//!     Content-Format <=> content_type (and is_utf8_payload for text formats)
+//!     Max-Age        <=> message_expiry_interval
+//!
+//! Each client, by remote address and client id, is a broker session without expiry, listed by
+//! the clients API and closed after client_idle_timeout without requests. An observation is a
+//! QoS 0 subscription of the session, its notifications are written by the session sink.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::num::{NonZeroU16, NonZeroU32};
+use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU32, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use coap_lite::{CoapOption, ContentFormat, MessageClass, MessageType, Packet, RequestType, ResponseType};
+
+use rmqtt::broker::datagram::Datagram;
+use rmqtt::broker::gateway::{self, GatewayConnect, GatewaySession, GatewaySink};
+use rmqtt::settings::listener::Listener;
+use rmqtt::{
+    bytes, log, ntex,
+    serde_json::{self, json},
+    timestamp_millis,
+    tokio::{self, sync::oneshot},
+    DashMap,
+};
+use rmqtt::{
+    ClientId, Id, MqttError, NodeId, Publish, PublishProperties, QoS, Result, Runtime, Topic, TopicFilter,
+    TopicName, UserName,
+};
+
+use crate::config::PluginConfig;
+
+type Token = Vec<u8>;
+
+const MAX_DATAGRAM_SIZE: usize = 65535;
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(10);
+//The sessions are closed by the stop of the gateway, at most
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+//CoAP Content-Format registry <=> MIME type
+const CONTENT_FORMATS: &[(ContentFormat, &str, bool)] = &[
+    (ContentFormat::TextPlain, "text/plain;charset=utf-8", true),
+    (ContentFormat::ApplicationLinkFormat, "application/link-format", true),
+    (ContentFormat::ApplicationXML, "application/xml", true),
+    (ContentFormat::ApplicationOctetStream, "application/octet-stream", false),
+    (ContentFormat::ApplicationEXI, "application/exi", false),
+    (ContentFormat::ApplicationJSON, "application/json", true),
+    (ContentFormat::ApplicationCBOR, "application/cbor", false),
+];
+
+#[derive(Default)]
+struct Metrics {
+    requests: AtomicUsize,
+    publishes: AtomicUsize,
+    notifications: AtomicUsize,
+    auth_fails: AtomicUsize,
+    bad_requests: AtomicUsize,
+}
+
+struct Observation {
+    topic_filter: TopicFilter,
+    topic: Topic,
+    seq: AtomicU32,
+    expire_at: AtomicI64,
+    last_message_id: AtomicU32,
+}
+
+struct Request {
+    path: Vec<String>,
+    query: HashMap<String, String>,
+}
+
+impl Request {
+    fn parse(packet: &Packet) -> Self {
+        let to_string = |v: &Vec<u8>| String::from_utf8_lossy(v).into_owned();
+        let path = packet
+            .get_option(CoapOption::UriPath)
+            .map(|segs| segs.iter().map(to_string).collect::<Vec<_>>())
+            .unwrap_or_default();
+        let query = packet
+            .get_option(CoapOption::UriQuery)
+            .map(|qs| {
+                qs.iter()
+                    .map(to_string)
+                    .map(|q| match q.split_once('=') {
+                        Some((k, v)) => (k.to_string(), v.to_string()),
+                        None => (q, String::new()),
+                    })
+                    .collect::<HashMap<_, _>>()
+            })
+            .unwrap_or_default();
+        Request { path, query }
+    }
+
+    #[inline]
+    fn qos(&self) -> Result<QoS> {
+        match self.query.get("qos").map(|q| q.as_str()) {
+            None | Some("0") => Ok(QoS::AtMostOnce),
+            Some("1") => Ok(QoS::AtLeastOnce),
+            Some("2") => Ok(QoS::ExactlyOnce),
+            Some(q) => Err(MqttError::from(format!("invalid qos, {}", q))),
+        }
+    }
+
+    #[inline]
+    fn retain(&self) -> bool {
+        matches!(self.query.get("retain").map(|r| r.as_str()), Some("true") | Some("1"))
+    }
+}
+
+///Writes the notifications of the observations of a client
+struct CoapSink {
+    addr: SocketAddr,
+    client_id: ClientId,
+    observations: DashMap<Token, Observation>,
+    closed: AtomicBool,
+    gateway: Arc<Gateway>,
+}
+
+impl fmt::Debug for CoapSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "CoapSink {{ {}, {:?}, observations: {} }}",
+            self.addr,
+            self.client_id,
+            self.observations.len()
+        )
+    }
+}
+
+impl GatewaySink for CoapSink {
+    fn publish(&self, p: &Publish) -> Result<()> {
+        for o in self.observations.iter() {
+            if !o.topic.matches_str(&p.topic) {
+                continue;
+            }
+            let message_id = self.gateway.message_id.fetch_add(1, Ordering::SeqCst) as u16;
+            let mut notification = Packet::new();
+            notification.header.set_type(MessageType::NonConfirmable);
+            notification.header.message_id = message_id;
+            notification.header.code = MessageClass::Response(ResponseType::Content);
+            notification.set_token(o.key().clone());
+            notification.set_observe_value(o.seq.fetch_add(1, Ordering::SeqCst) & 0xff_ffff);
+            fill(&mut notification, p);
+            o.last_message_id.store(message_id as u32, Ordering::SeqCst);
+            self.gateway
+                .notify_ids
+                .insert((self.addr, message_id), (self.client_id.clone(), o.key().clone()));
+            log::debug!("notify {:?} {}, topic: {}", self.client_id, self.addr, p.topic);
+            self.gateway.send_to(self.addr, &notification);
+            self.gateway.metrics.notifications.fetch_add(1, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+
+    //The observations are QoS 0 subscriptions, there is no release
+    fn release(&self, _packet_id: NonZeroU16) -> Result<()> {
+        Ok(())
+    }
+
+    fn close(&self) {
+        if !self.closed.swap(true, Ordering::SeqCst) {
+            self.gateway.observations.fetch_sub(self.observations.len(), Ordering::SeqCst);
+            self.observations.clear();
+        }
+    }
+}
+
+struct CoapClient {
+    session: GatewaySession,
+    sink: Arc<CoapSink>,
+}
+
+impl CoapClient {
+    #[inline]
+    fn is_closed(&self) -> bool {
+        self.sink.closed.load(Ordering::SeqCst)
+    }
+}
+
+pub(crate) struct Gateway {
+    cfg: PluginConfig,
+    listen_cfg: Listener,
+    node_id: NodeId,
+    socket: Datagram,
+    //message id of the last notification => observation, a Reset cancels the observation
+    notify_ids: DashMap<(SocketAddr, u16), (ClientId, Token)>,
+    message_id: AtomicU32,
+    clients: AtomicUsize,
+    observations: AtomicUsize,
+    metrics: Metrics,
+}
+
+impl Gateway {
+    pub async fn bind(cfg: PluginConfig, node_id: NodeId) -> Result<Arc<Gateway>> {
+        let socket = Datagram::bind(cfg.listen, &cfg.dtls).await?;
+        log::info!("CoAP gateway listening on {}, dtls: {}", cfg.listen, socket.is_secure());
+        Ok(Arc::new(Gateway {
+            listen_cfg: cfg.listener(),
+            cfg,
+            node_id,
+            socket,
+            notify_ids: DashMap::default(),
+            message_id: AtomicU32::new(rmqtt::rand::random::<u16>() as u32),
+            clients: AtomicUsize::new(0),
+            observations: AtomicUsize::new(0),
+            metrics: Metrics::default(),
+        }))
+    }
+
+    ///Serves the requests on the ntex runtime until stopped, the client sessions are closed then
+    pub async fn serve(self: Arc<Self>, mut stop: oneshot::Receiver<()>) {
+        let server = Rc::new(Server { gateway: self.clone(), clients: RefCell::new(HashMap::default()) });
+        let mut cleanup = tokio::time::interval(CLEANUP_INTERVAL);
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        loop {
+            tokio::select! {
+                _ = &mut stop => break,
+                _ = cleanup.tick() => server.cleanup().await,
+                res = self.socket.recv_from(&mut buf) => {
+                    let (len, addr) = match res {
+                        Ok(r) => r,
+                        Err(e) => {
+                            log::warn!("CoAP gateway recv error, {:?}", e);
+                            continue;
+                        }
+                    };
+                    let packet = match Packet::from_bytes(&buf[..len]) {
+                        Ok(p) => p,
+                        Err(e) => {
+                            self.metrics.bad_requests.fetch_add(1, Ordering::SeqCst);
+                            log::debug!("{} invalid CoAP packet, {:?}", addr, e);
+                            continue;
+                        }
+                    };
+                    let server = server.clone();
+                    ntex::rt::spawn(async move {
+                        if let Some(reply) = server.handle(addr, packet).await {
+                            server.gateway.send_to(addr, &reply);
+                        }
+                    });
+                }
+            }
+        }
+        server.close().await;
+    }
+
+    #[inline]
+    fn send_to(self: &Arc<Self>, addr: SocketAddr, packet: &Packet) {
+        match packet.to_bytes() {
+            Ok(data) => {
+                let gw = self.clone();
+                ntex::rt::spawn(async move {
+                    if let Err(e) = gw.socket.send_to(&data, addr).await {
+                        log::warn!("CoAP gateway send to {} error, {:?}", addr, e);
+                    }
+                });
+            }
+            Err(e) => log::warn!("CoAP gateway encode error, {:?}", e),
+        }
+    }
+
+    #[inline]
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "listen": self.cfg.listen,
+            "dtls": self.socket.is_secure(),
+            "clients": self.clients.load(Ordering::SeqCst),
+            "observations": self.observations.load(Ordering::SeqCst),
+            "metrics": {
+                "requests": self.metrics.requests.load(Ordering::SeqCst),
+                "publishes": self.metrics.publishes.load(Ordering::SeqCst),
+                "notifications": self.metrics.notifications.load(Ordering::SeqCst),
+                "auth_fails": self.metrics.auth_fails.load(Ordering::SeqCst),
+                "bad_requests": self.metrics.bad_requests.load(Ordering::SeqCst),
+            }
+        })
+    }
+}
+
+//The client sessions are not Send, they are kept on the ntex runtime of the gateway
+struct Server {
+    gateway: Arc<Gateway>,
+    //(remote address, client id) => client
+    clients: RefCell<HashMap<(SocketAddr, ClientId), Rc<CoapClient>>>,
+}
+
+impl Server {
+    async fn handle(&self, addr: SocketAddr, packet: Packet) -> Option<Packet> {
+        let metrics = &self.gateway.metrics;
+        metrics.requests.fetch_add(1, Ordering::SeqCst);
+        let typ = packet.header.get_type();
+        if matches!(typ, MessageType::Reset) {
+            if let Some((_, (client_id, token))) =
+                self.gateway.notify_ids.remove(&(addr, packet.header.message_id))
+            {
+                log::debug!("{} reset, cancel observation", addr);
+                let client = self.clients.borrow().get(&(addr, client_id)).cloned();
+                if let Some(client) = client {
+                    self.cancel_observe(&client, &token).await;
+                }
+            }
+            return None;
+        }
+        let method = match packet.header.code {
+            MessageClass::Request(method) => method,
+            _ => return None,
+        };
+
+        let req = Request::parse(&packet);
+        let res = match req.path.split_first() {
+            Some((prefix, topic)) if *prefix == self.gateway.cfg.path_prefix && !topic.is_empty() => {
+                let topic = topic.join("/");
+                match self.client(addr, &req).await {
+                    Ok(client) => match method {
+                        RequestType::Put | RequestType::Post => {
+                            self.publish(&client, &req, &packet, topic).await.map(|published| {
+                                let code = if !published {
+                                    ResponseType::Forbidden
+                                } else if method == RequestType::Post {
+                                    ResponseType::Created
+                                } else {
+                                    ResponseType::Changed
+                                };
+                                response(&packet, code)
+                            })
+                        }
+                        RequestType::Get => self.get(&client, &packet, topic).await,
+                        _ => Ok(response(&packet, ResponseType::MethodNotAllowed)),
+                    },
+                    Err(e) => {
+                        metrics.auth_fails.fetch_add(1, Ordering::SeqCst);
+                        log::debug!("{} CoAP authentication failed, {:?}", addr, e);
+                        Ok(response(&packet, ResponseType::Unauthorized))
+                    }
+                }
+            }
+            _ => Ok(response(&packet, ResponseType::NotFound)),
+        };
+
+        match res {
+            Ok(reply) => Some(reply),
+            Err(e) => {
+                metrics.bad_requests.fetch_add(1, Ordering::SeqCst);
+                log::debug!("{} CoAP request error, {:?}", addr, e);
+                Some(response(&packet, ResponseType::BadRequest))
+            }
+        }
+    }
+
+    //Connects the session of the client, a request keeps the session alive
+    async fn client(&self, addr: SocketAddr, req: &Request) -> Result<Rc<CoapClient>> {
+        let cfg = &self.gateway.cfg;
+        let client_id = match req.query.get("c") {
+            Some(c) if !c.is_empty() => ClientId::from(c.as_str()),
+            _ if cfg.allow_anonymous => ClientId::from(format!("coap-{}", addr)),
+            _ => return Err(MqttError::from("client id is required")),
+        };
+        let key = (addr, client_id.clone());
+        let client = self.clients.borrow().get(&key).cloned();
+        if let Some(client) = client {
+            if !client.is_closed() && client.session.keepalive(false).is_ok() {
+                return Ok(client);
+            }
+        }
+
+        let username = req.query.get("u").map(|u| UserName::from(u.as_str()));
+        let id = Id::new(self.gateway.node_id, Some(cfg.listen), Some(addr), client_id.clone(), username);
+        let sink = Arc::new(CoapSink {
+            addr,
+            client_id,
+            observations: DashMap::default(),
+            closed: AtomicBool::new(false),
+            gateway: self.gateway.clone(),
+        });
+        let connect = GatewayConnect {
+            id,
+            clean_start: true,
+            session_expiry_interval: 0,
+            keep_alive: cfg.keep_alive(),
+            password: req.query.get("p").map(|p| bytes::Bytes::from(p.clone())),
+        };
+        let (session, _) = gateway::connect(self.gateway.listen_cfg.clone(), connect, sink.clone()).await?;
+        let client = Rc::new(CoapClient { session, sink });
+        if self.clients.borrow_mut().insert(key, client.clone()).is_none() {
+            self.gateway.clients.fetch_add(1, Ordering::SeqCst);
+        }
+        Ok(client)
+    }
+
+    //Returns false if the message is refused
+    async fn publish(
+        &self,
+        client: &CoapClient,
+        req: &Request,
+        packet: &Packet,
+        topic: String,
+    ) -> Result<bool> {
+        let mut properties = PublishProperties::default();
+        if let Some(cf) = packet.get_content_format() {
+            if let Some((_, mime, utf8)) = CONTENT_FORMATS.iter().find(|(f, _, _)| *f == cf) {
+                properties.content_type = Some((*mime).into());
+                properties.is_utf8_payload = Some(*utf8);
+            }
+        }
+        properties.message_expiry_interval = max_age(packet).and_then(NonZeroU32::new);
+
+        let msg = Publish {
+            dup: false,
+            retain: req.retain(),
+            qos: req.qos()?,
+            topic: TopicName::from(topic),
+            packet_id: None,
+            payload: ntex::util::Bytes::from(packet.payload.clone()),
+            properties,
+            create_time: timestamp_millis(),
+        };
+        let published = client.session.publish(msg).await?;
+        if published {
+            self.gateway.metrics.publishes.fetch_add(1, Ordering::SeqCst);
+        }
+        Ok(published)
+    }
+
+    async fn get(&self, client: &CoapClient, packet: &Packet, topic: String) -> Result<Packet> {
+        let token = packet.get_token().to_vec();
+        let observe = match packet.get_observe_value() {
+            Some(Ok(v)) => Some(v),
+            Some(Err(_)) => return Err(MqttError::from("invalid observe option")),
+            None => None,
+        };
+        let mut reply = response(packet, ResponseType::Content);
+        match observe {
+            Some(0) => match self.observe(client, token, &topic).await? {
+                Some(seq) => reply.set_observe_value(seq),
+                None => return Ok(response(packet, ResponseType::Forbidden)),
+            },
+            Some(1) => {
+                self.cancel_observe(client, &token).await;
+            }
+            _ => {}
+        }
+
+        //Responds with the retained message, if any
+        let retains = Runtime::instance().extends.retain().await.get(&TopicFilter::from(topic)).await?;
+        match retains.into_iter().next() {
+            Some((_, retain)) => fill(&mut reply, &retain.publish),
+            None if observe == Some(0) => {}
+            None => reply.header.code = MessageClass::Response(ResponseType::NotFound),
+        }
+        Ok(reply)
+    }
+
+    //Subscribes the topic filter, the retained messages are in the response. None if refused.
+    async fn observe(&self, client: &CoapClient, token: Token, topic_filter: &str) -> Result<Option<u32>> {
+        let cfg = &self.gateway.cfg;
+        let expire_at = timestamp_millis() + cfg.observe_lifetime.as_millis() as i64;
+        //Re-registration refreshes the observation
+        if let Some(o) = client.sink.observations.get(&token) {
+            o.expire_at.store(expire_at, Ordering::SeqCst);
+            return Ok(Some(o.seq.fetch_add(1, Ordering::SeqCst)));
+        }
+        if self.gateway.observations.load(Ordering::SeqCst) >= cfg.max_observations {
+            return Err(MqttError::from("too many observations"));
+        }
+        let topic = Topic::from_str(topic_filter)?;
+        let topic_filter = TopicFilter::from(topic_filter);
+        let sub_ret = client.session.subscribe(&topic_filter, QoS::AtMostOnce, false).await?;
+        if sub_ret.success().is_none() {
+            log::debug!(
+                "{:?} observe {} refused, {:?}",
+                client.session.id(),
+                topic_filter,
+                sub_ret.ack_reason
+            );
+            return Ok(None);
+        }
+        client.sink.observations.insert(
+            token,
+            Observation {
+                topic_filter,
+                topic,
+                seq: AtomicU32::new(1),
+                expire_at: AtomicI64::new(expire_at),
+                last_message_id: AtomicU32::new(0),
+            },
+        );
+        self.gateway.observations.fetch_add(1, Ordering::SeqCst);
+        Ok(Some(0))
+    }
+
+    //The topic filter is unsubscribed with its last observation
+    async fn cancel_observe(&self, client: &CoapClient, token: &Token) {
+        if let Some((_, o)) = client.sink.observations.remove(token) {
+            self.gateway.observations.fetch_sub(1, Ordering::SeqCst);
+            if !client.sink.observations.iter().any(|other| other.topic_filter == o.topic_filter) {
+                if let Err(e) = client.session.unsubscribe(&o.topic_filter).await {
+                    log::debug!("{:?} unsubscribe {} error, {:?}", client.session.id(), o.topic_filter, e);
+                }
+            }
+        }
+    }
+
+    ///Removes the closed clients and the expired observations
+    async fn cleanup(&self) {
+        let now = timestamp_millis();
+        let removed = {
+            let mut clients = self.clients.borrow_mut();
+            let len = clients.len();
+            clients.retain(|_, c| !c.is_closed());
+            len - clients.len()
+        };
+        self.gateway.clients.fetch_sub(removed, Ordering::SeqCst);
+
+        let clients = self.clients.borrow().values().cloned().collect::<Vec<_>>();
+        for client in clients {
+            let expired = client
+                .sink
+                .observations
+                .iter()
+                .filter(|o| o.expire_at.load(Ordering::SeqCst) < now)
+                .map(|o| o.key().clone())
+                .collect::<Vec<_>>();
+            for token in expired {
+                self.cancel_observe(&client, &token).await;
+            }
+        }
+
+        //Only the last notification of each observation can be reset
+        let clients = self.clients.borrow();
+        self.gateway.notify_ids.retain(|(addr, message_id), (client_id, token)| {
+            clients
+                .get(&(*addr, client_id.clone()))
+                .and_then(|c| {
+                    c.sink
+                        .observations
+                        .get(token)
+                        .map(|o| o.last_message_id.load(Ordering::SeqCst) == *message_id as u32)
+                })
+                .unwrap_or(false)
+        });
+    }
+
+    //Disconnects the client sessions, before the runtime of the gateway is stopped
+    async fn close(&self) {
+        let clients = self.clients.borrow_mut().drain().map(|(_, c)| c).collect::<Vec<_>>();
+        self.gateway.clients.fetch_sub(clients.len(), Ordering::SeqCst);
+        for client in clients.iter() {
+            if let Err(e) = client.session.disconnect(None) {
+                log::debug!("{:?} disconnect error, {:?}", client.session.id(), e);
+            }
+        }
+        let _ = tokio::time::timeout(CLOSE_TIMEOUT, async {
+            while clients.iter().any(|c| !c.is_closed()) {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await;
+    }
+}
+
+#[inline]
+fn fill(packet: &mut Packet, p: &Publish) {
+    if let Some(content_type) = p.properties.content_type.as_ref() {
+        if let Some((cf, _, _)) = CONTENT_FORMATS.iter().find(|(_, mime, _)| *mime == &**content_type) {
+            packet.set_content_format(*cf);
+        }
+    }
+    if let Some(expiry) = p.properties.message_expiry_interval {
+        //uint option, without leading zero bytes
+        let max_age = expiry.get().to_be_bytes().into_iter().skip_while(|b| *b == 0).collect::<Vec<_>>();
+        packet.add_option(CoapOption::MaxAge, max_age);
+    }
+    packet.payload = p.payload.to_vec();
+}
+
+#[inline]
+fn max_age(packet: &Packet) -> Option<u32> {
+    packet
+        .get_option(CoapOption::MaxAge)
+        .and_then(|vals| vals.front())
+        .map(|v| v.iter().fold(0u32, |acc, b| (acc << 8) | *b as u32))
+}
+
+#[inline]
+fn response(req: &Packet, code: ResponseType) -> Packet {
+    let mut reply = Packet::new();
+    //Piggybacked response for confirmable requests
+    let typ = if matches!(req.header.get_type(), MessageType::Confirmable) {
+        MessageType::Acknowledgement
+    } else {
+        MessageType::NonConfirmable
+    };
+    reply.header.set_type(typ);
+    reply.header.message_id = req.header.message_id;
+    reply.header.code = MessageClass::Response(code);
+    reply.set_token(req.get_token().to_vec());
+    reply
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(path: &[&str], query: &[&str]) -> Packet {
+        let mut packet = Packet::new();
+        packet.header.set_type(MessageType::Confirmable);
+        packet.header.code = MessageClass::Request(RequestType::Put);
+        packet.header.message_id = 7;
+        packet.set_token(vec![1, 2]);
+        for seg in path {
+            packet.add_option(CoapOption::UriPath, seg.as_bytes().to_vec());
+        }
+        for q in query {
+            packet.add_option(CoapOption::UriQuery, q.as_bytes().to_vec());
+        }
+        packet
+    }
+
+    #[test]
+    fn test_request() {
+        let packet = request(&["ps", "a", "b"], &["c=client1", "qos=1", "retain=true", "flag"]);
+        let req = Request::parse(&packet);
+        assert_eq!(req.path, vec!["ps", "a", "b"]);
+        assert_eq!(req.query.get("c").map(|c| c.as_str()), Some("client1"));
+        assert_eq!(req.query.get("flag").map(|c| c.as_str()), Some(""));
+        assert_eq!(req.qos().unwrap(), QoS::AtLeastOnce);
+        assert!(req.retain());
+
+        let req = Request::parse(&request(&["ps", "a"], &["qos=3"]));
+        assert!(req.qos().is_err());
+        assert!(!req.retain());
+    }
+
+    #[test]
+    fn test_fill() {
+        let mut properties = PublishProperties::default();
+        properties.content_type = Some("application/json".into());
+        properties.message_expiry_interval = NonZeroU32::new(300);
+        let p = Publish {
+            dup: false,
+            retain: false,
+            qos: QoS::AtMostOnce,
+            topic: TopicName::from("a/b"),
+            packet_id: None,
+            payload: ntex::util::Bytes::from_static(b"{}"),
+            properties,
+            create_time: 0,
+        };
+        let mut packet = response(&request(&["ps", "a", "b"], &[]), ResponseType::Content);
+        fill(&mut packet, &p);
+        assert_eq!(packet.header.get_type(), MessageType::Acknowledgement);
+        assert_eq!(packet.header.message_id, 7);
+        assert_eq!(packet.get_token(), &[1, 2]);
+        assert_eq!(packet.get_content_format(), Some(ContentFormat::ApplicationJSON));
+        assert_eq!(max_age(&packet), Some(300));
+        assert_eq!(packet.payload, b"{}".to_vec());
+    }
+}
@@ -0,0 +1,127 @@
+#![deny(unsafe_code)]
+#[macro_use]
+extern crate serde;
+
+#[macro_use]
+extern crate rmqtt_macros;
+
+use std::ops::Deref;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+use rmqtt::{
+    async_trait::async_trait,
+    log, ntex,
+    serde_json::{self, json},
+    tokio::{self, sync::oneshot, sync::RwLock},
+};
+use rmqtt::{
+    plugin::{PackageInfo, Plugin},
+    register, MqttError, Result, Runtime,
+};
+
+use config::PluginConfig;
+use gateway::Gateway;
+
+mod config;
+mod gateway;
+
+register!(CoapGatewayPlugin::new);
+
+#[derive(Plugin)]
+struct CoapGatewayPlugin {
+    runtime: &'static Runtime,
+    cfg: Arc<RwLock<PluginConfig>>,
+    gateway: Arc<RwLock<Option<Arc<Gateway>>>>,
+    //The gateway runs on its own ntex runtime, the client sessions are not Send
+    serving: Option<(oneshot::Sender<()>, JoinHandle<()>)>,
+}
+
+impl CoapGatewayPlugin {
+    #[inline]
+    async fn new(runtime: &'static Runtime, name: &'static str) -> Result<Self> {
+        let cfg = runtime.settings.plugins.load_config::<PluginConfig>(name)?;
+        log::info!("{} CoapGatewayPlugin cfg: {:?}", name, cfg);
+        Ok(Self {
+            runtime,
+            cfg: Arc::new(RwLock::new(cfg)),
+            gateway: Arc::new(RwLock::new(None)),
+            serving: None,
+        })
+    }
+
+    async fn start_gateway(&mut self) -> Result<()> {
+        let cfg = self.cfg.read().await.clone();
+        let gateway = Gateway::bind(cfg, self.runtime.node.id()).await?;
+        let (stop_tx, stop_rx) = oneshot::channel();
+        let gw = gateway.clone();
+        let handle = std::thread::Builder::new().name("coap-gateway".into()).spawn(move || {
+            ntex::rt::System::new("coap-gateway").block_on(gw.serve(stop_rx));
+        })?;
+        self.serving = Some((stop_tx, handle));
+        *self.gateway.write().await = Some(gateway);
+        Ok(())
+    }
+
+    //The client sessions are disconnected, the listener is released
+    async fn stop_gateway(&mut self) -> Result<()> {
+        if let Some((stop_tx, handle)) = self.serving.take() {
+            let _ = stop_tx.send(());
+            tokio::task::spawn_blocking(move || handle.join())
+                .await
+                .map_err(|e| MqttError::from(e.to_string()))?
+                .map_err(|_| MqttError::from("CoAP gateway thread panicked"))?;
+        }
+        self.gateway.write().await.take();
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Plugin for CoapGatewayPlugin {
+    #[inline]
+    async fn init(&mut self) -> Result<()> {
+        log::info!("{} init", self.name());
+        Ok(())
+    }
+
+    #[inline]
+    async fn get_config(&self) -> Result<serde_json::Value> {
+        Ok(serde_json::to_value(self.cfg.read().await.deref())?)
+    }
+
+    #[inline]
+    async fn load_config(&mut self) -> Result<()> {
+        let new_cfg = self.runtime.settings.plugins.load_config::<PluginConfig>(self.name())?;
+        *self.cfg.write().await = new_cfg;
+        //Rebinds the listener, the client sessions are closed
+        if self.serving.is_some() {
+            self.stop_gateway().await?;
+            self.start_gateway().await?;
+        }
+        log::debug!("load_config ok,  {:?}", self.cfg);
+        Ok(())
+    }
+
+    #[inline]
+    async fn start(&mut self) -> Result<()> {
+        log::info!("{} start", self.name());
+        self.start_gateway().await?;
+        Ok(())
+    }
+
+    #[inline]
+    async fn stop(&mut self) -> Result<bool> {
+        log::info!("{} stop", self.name());
+        self.stop_gateway().await?;
+        Ok(true)
+    }
+
+    #[inline]
+    async fn attrs(&self) -> serde_json::Value {
+        match self.gateway.read().await.as_ref() {
+            Some(gateway) => gateway.to_json(),
+            None => json!({}),
+        }
+    }
+}
@@ -0,0 +1,395 @@
+//! MQTT-SN v1.2 packet codec
+
+use rmqtt::bytes::{Buf, BufMut, Bytes, BytesMut};
+use rmqtt::{MqttError, Result};
+
+const FLAG_DUP: u8 = 0x80;
+const FLAG_RETAIN: u8 = 0x10;
+const FLAG_WILL: u8 = 0x08;
+const FLAG_CLEAN_SESSION: u8 = 0x04;
+
+pub(crate) const RC_ACCEPTED: u8 = 0x00;
+pub(crate) const RC_CONGESTION: u8 = 0x01;
+pub(crate) const RC_INVALID_TOPIC_ID: u8 = 0x02;
+pub(crate) const RC_NOT_SUPPORTED: u8 = 0x03;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TopicIdType {
+    Normal,
+    Predefined,
+    Short,
+}
+
+///QoS of MQTT-SN, Minus1 is a connectionless publish with a predefined or short topic id
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SnQoS {
+    AtMostOnce,
+    AtLeastOnce,
+    ExactlyOnce,
+    Minus1,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Flags {
+    pub dup: bool,
+    pub qos: SnQoS,
+    pub retain: bool,
+    pub will: bool,
+    pub clean_session: bool,
+    pub topic_id_type: TopicIdType,
+}
+
+impl Flags {
+    #[inline]
+    pub fn new(qos: SnQoS, topic_id_type: TopicIdType) -> Self {
+        Flags { dup: false, qos, retain: false, will: false, clean_session: false, topic_id_type }
+    }
+
+    fn decode(b: u8) -> Result<Self> {
+        let qos = match (b >> 5) & 0x03 {
+            0 => SnQoS::AtMostOnce,
+            1 => SnQoS::AtLeastOnce,
+            2 => SnQoS::ExactlyOnce,
+            _ => SnQoS::Minus1,
+        };
+        let topic_id_type = match b & 0x03 {
+            0 => TopicIdType::Normal,
+            1 => TopicIdType::Predefined,
+            2 => TopicIdType::Short,
+            _ => return Err(MqttError::from("reserved topic id type")),
+        };
+        Ok(Flags {
+            dup: b & FLAG_DUP != 0,
+            qos,
+            retain: b & FLAG_RETAIN != 0,
+            will: b & FLAG_WILL != 0,
+            clean_session: b & FLAG_CLEAN_SESSION != 0,
+            topic_id_type,
+        })
+    }
+
+    fn encode(&self) -> u8 {
+        let qos = match self.qos {
+            SnQoS::AtMostOnce => 0,
+            SnQoS::AtLeastOnce => 1,
+            SnQoS::ExactlyOnce => 2,
+            SnQoS::Minus1 => 3,
+        };
+        let topic_id_type = match self.topic_id_type {
+            TopicIdType::Normal => 0,
+            TopicIdType::Predefined => 1,
+            TopicIdType::Short => 2,
+        };
+        let mut b = (qos << 5) | topic_id_type;
+        if self.dup {
+            b |= FLAG_DUP;
+        }
+        if self.retain {
+            b |= FLAG_RETAIN;
+        }
+        if self.will {
+            b |= FLAG_WILL;
+        }
+        if self.clean_session {
+            b |= FLAG_CLEAN_SESSION;
+        }
+        b
+    }
+}
+
+///Topic of SUBSCRIBE and UNSUBSCRIBE, a name for the normal type, otherwise an id
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum SnTopic {
+    Name(String),
+    Id(u16),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Packet {
+    SearchGw { radius: u8 },
+    GwInfo { gw_id: u8 },
+    Connect { flags: Flags, duration: u16, client_id: String },
+    ConnAck { return_code: u8 },
+    Register { topic_id: u16, msg_id: u16, topic_name: String },
+    RegAck { topic_id: u16, msg_id: u16, return_code: u8 },
+    Publish { flags: Flags, topic_id: u16, msg_id: u16, data: Bytes },
+    PubAck { topic_id: u16, msg_id: u16, return_code: u8 },
+    PubRec { msg_id: u16 },
+    PubRel { msg_id: u16 },
+    PubComp { msg_id: u16 },
+    Subscribe { flags: Flags, msg_id: u16, topic: SnTopic },
+    SubAck { flags: Flags, topic_id: u16, msg_id: u16, return_code: u8 },
+    Unsubscribe { flags: Flags, msg_id: u16, topic: SnTopic },
+    UnsubAck { msg_id: u16 },
+    PingReq { client_id: Option<String> },
+    PingResp,
+    Disconnect { duration: Option<u16> },
+}
+
+#[inline]
+fn utf8(b: &[u8]) -> Result<String> {
+    Ok(std::str::from_utf8(b)?.to_string())
+}
+
+#[inline]
+fn ensure(buf: &[u8], len: usize) -> Result<()> {
+    if buf.len() < len {
+        Err(MqttError::from("malformed packet"))
+    } else {
+        Ok(())
+    }
+}
+
+impl Packet {
+    pub fn decode(data: &[u8]) -> Result<Packet> {
+        ensure(data, 2)?;
+        //A length of 0x01 indicates the three octets format
+        let (len, header_len) = if data[0] == 0x01 {
+            ensure(data, 4)?;
+            (u16::from_be_bytes([data[1], data[2]]) as usize, 3)
+        } else {
+            (data[0] as usize, 1)
+        };
+        if len > data.len() || len <= header_len {
+            return Err(MqttError::from("invalid packet length"));
+        }
+        let msg_type = data[header_len];
+        let mut buf = &data[header_len + 1..len];
+        let packet = match msg_type {
+            0x01 => {
+                ensure(buf, 1)?;
+                Packet::SearchGw { radius: buf.get_u8() }
+            }
+            0x04 => {
+                ensure(buf, 4)?;
+                let flags = Flags::decode(buf.get_u8())?;
+                let _protocol_id = buf.get_u8();
+                let duration = buf.get_u16();
+                Packet::Connect { flags, duration, client_id: utf8(buf)? }
+            }
+            0x0A => {
+                ensure(buf, 4)?;
+                let topic_id = buf.get_u16();
+                let msg_id = buf.get_u16();
+                Packet::Register { topic_id, msg_id, topic_name: utf8(buf)? }
+            }
+            0x0B => {
+                ensure(buf, 5)?;
+                Packet::RegAck { topic_id: buf.get_u16(), msg_id: buf.get_u16(), return_code: buf.get_u8() }
+            }
+            0x0C => {
+                ensure(buf, 5)?;
+                let flags = Flags::decode(buf.get_u8())?;
+                let topic_id = buf.get_u16();
+                let msg_id = buf.get_u16();
+                Packet::Publish { flags, topic_id, msg_id, data: Bytes::copy_from_slice(buf) }
+            }
+            0x0D => {
+                ensure(buf, 5)?;
+                Packet::PubAck { topic_id: buf.get_u16(), msg_id: buf.get_u16(), return_code: buf.get_u8() }
+            }
+            0x0E..=0x10 => {
+                ensure(buf, 2)?;
+                let msg_id = buf.get_u16();
+                match msg_type {
+                    0x0E => Packet::PubComp { msg_id },
+                    0x0F => Packet::PubRec { msg_id },
+                    _ => Packet::PubRel { msg_id },
+                }
+            }
+            0x12 | 0x14 => {
+                ensure(buf, 3)?;
+                let flags = Flags::decode(buf.get_u8())?;
+                let msg_id = buf.get_u16();
+                let topic = match flags.topic_id_type {
+                    TopicIdType::Normal => SnTopic::Name(utf8(buf)?),
+                    _ => {
+                        ensure(buf, 2)?;
+                        SnTopic::Id(buf.get_u16())
+                    }
+                };
+                if msg_type == 0x12 {
+                    Packet::Subscribe { flags, msg_id, topic }
+                } else {
+                    Packet::Unsubscribe { flags, msg_id, topic }
+                }
+            }
+            0x16 => Packet::PingReq { client_id: if buf.is_empty() { None } else { Some(utf8(buf)?) } },
+            0x17 => Packet::PingResp,
+            0x18 => Packet::Disconnect { duration: if buf.len() >= 2 { Some(buf.get_u16()) } else { None } },
+            _ => return Err(MqttError::from(format!("unsupported message type, 0x{:02X}", msg_type))),
+        };
+        Ok(packet)
+    }
+
+    pub fn encode(&self) -> Bytes {
+        let mut body = BytesMut::new();
+        let msg_type = match self {
+            Packet::SearchGw { radius } => {
+                body.put_u8(*radius);
+                0x01
+            }
+            Packet::GwInfo { gw_id } => {
+                body.put_u8(*gw_id);
+                0x02
+            }
+            Packet::Connect { flags, duration, client_id } => {
+                body.put_u8(flags.encode());
+                body.put_u8(0x01);
+                body.put_u16(*duration);
+                body.put_slice(client_id.as_bytes());
+                0x04
+            }
+            Packet::ConnAck { return_code } => {
+                body.put_u8(*return_code);
+                0x05
+            }
+            Packet::Register { topic_id, msg_id, topic_name } => {
+                body.put_u16(*topic_id);
+                body.put_u16(*msg_id);
+                body.put_slice(topic_name.as_bytes());
+                0x0A
+            }
+            Packet::RegAck { topic_id, msg_id, return_code } => {
+                body.put_u16(*topic_id);
+                body.put_u16(*msg_id);
+                body.put_u8(*return_code);
+                0x0B
+            }
+            Packet::Publish { flags, topic_id, msg_id, data } => {
+                body.put_u8(flags.encode());
+                body.put_u16(*topic_id);
+                body.put_u16(*msg_id);
+                body.put_slice(data);
+                0x0C
+            }
+            Packet::PubAck { topic_id, msg_id, return_code } => {
+                body.put_u16(*topic_id);
+                body.put_u16(*msg_id);
+                body.put_u8(*return_code);
+                0x0D
+            }
+            Packet::PubComp { msg_id } => {
+                body.put_u16(*msg_id);
+                0x0E
+            }
+            Packet::PubRec { msg_id } => {
+                body.put_u16(*msg_id);
+                0x0F
+            }
+            Packet::PubRel { msg_id } => {
+                body.put_u16(*msg_id);
+                0x10
+            }
+            Packet::Subscribe { flags, msg_id, topic } | Packet::Unsubscribe { flags, msg_id, topic } => {
+                body.put_u8(flags.encode());
+                body.put_u16(*msg_id);
+                match topic {
+                    SnTopic::Name(name) => body.put_slice(name.as_bytes()),
+                    SnTopic::Id(id) => body.put_u16(*id),
+                }
+                if matches!(self, Packet::Subscribe { .. }) {
+                    0x12
+                } else {
+                    0x14
+                }
+            }
+            Packet::SubAck { flags, topic_id, msg_id, return_code } => {
+                body.put_u8(flags.encode());
+                body.put_u16(*topic_id);
+                body.put_u16(*msg_id);
+                body.put_u8(*return_code);
+                0x13
+            }
+            Packet::UnsubAck { msg_id } => {
+                body.put_u16(*msg_id);
+                0x15
+            }
+            Packet::PingReq { client_id } => {
+                if let Some(client_id) = client_id {
+                    body.put_slice(client_id.as_bytes());
+                }
+                0x16
+            }
+            Packet::PingResp => 0x17,
+            Packet::Disconnect { duration } => {
+                if let Some(duration) = duration {
+                    body.put_u16(*duration);
+                }
+                0x18
+            }
+        };
+
+        let mut buf = BytesMut::with_capacity(body.len() + 4);
+        if body.len() + 2 < 256 {
+            buf.put_u8((body.len() + 2) as u8);
+        } else {
+            buf.put_u8(0x01);
+            buf.put_u16((body.len() + 4) as u16);
+        }
+        buf.put_u8(msg_type);
+        buf.put_slice(&body);
+        buf.freeze()
+    }
+}
+
+#[inline]
+pub(crate) fn short_topic_id(topic: &str) -> Option<u16> {
+    let b = topic.as_bytes();
+    if b.len() == 2 {
+        Some(u16::from_be_bytes([b[0], b[1]]))
+    } else {
+        None
+    }
+}
+
+#[inline]
+pub(crate) fn short_topic_name(topic_id: u16) -> Result<String> {
+    utf8(&topic_id.to_be_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let mut flags = Flags::new(SnQoS::ExactlyOnce, TopicIdType::Normal);
+        flags.clean_session = true;
+        let packets = vec![
+            Packet::SearchGw { radius: 1 },
+            Packet::Connect { flags, duration: 60, client_id: "sensor1".into() },
+            Packet::Register { topic_id: 0, msg_id: 1, topic_name: "a/b".into() },
+            Packet::Publish {
+                flags: Flags::new(SnQoS::Minus1, TopicIdType::Predefined),
+                topic_id: 9,
+                msg_id: 0,
+                data: Bytes::from_static(b"21.5"),
+            },
+            Packet::Publish { flags, topic_id: 1, msg_id: 2, data: Bytes::from(vec![0u8; 300]) },
+            Packet::PubRel { msg_id: 2 },
+            Packet::Subscribe { flags, msg_id: 3, topic: SnTopic::Name("a/#".into()) },
+            Packet::Unsubscribe {
+                flags: Flags::new(SnQoS::AtMostOnce, TopicIdType::Short),
+                msg_id: 4,
+                topic: SnTopic::Id(short_topic_id("ab").unwrap()),
+            },
+            Packet::PingReq { client_id: None },
+            Packet::PingReq { client_id: Some("sensor1".into()) },
+            Packet::Disconnect { duration: Some(600) },
+            Packet::Disconnect { duration: None },
+        ];
+        for packet in packets {
+            assert_eq!(Packet::decode(&packet.encode()).unwrap(), packet);
+        }
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        assert!(Packet::decode(&[]).is_err());
+        assert!(Packet::decode(&[0x05, 0x0C, 0x00]).is_err());
+        assert!(Packet::decode(&[0x02, 0x7F]).is_err());
+        assert_eq!(short_topic_name(short_topic_id("ab").unwrap()).unwrap(), "ab");
+        assert_eq!(short_topic_id("abc"), None);
+    }
+}
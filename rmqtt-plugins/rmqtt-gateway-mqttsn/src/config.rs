@@ -0,0 +1,127 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use rmqtt::broker::datagram::DtlsConfig;
+use rmqtt::settings::listener::{Listener, ListenerInner};
+use rmqtt::settings::{deserialize_addr, deserialize_duration};
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PluginConfig {
+    ///UDP listen address
+    #[serde(default = "PluginConfig::listen_default", deserialize_with = "deserialize_addr")]
+    pub listen: SocketAddr,
+    ///Gateway id, returned in GWINFO
+    #[serde(default = "PluginConfig::gateway_id_default")]
+    pub gateway_id: u8,
+    ///DTLS, MQTT-SN over DTLS
+    #[serde(default)]
+    pub dtls: DtlsConfig,
+
+    #[serde(default = "PluginConfig::allow_anonymous_default")]
+    pub allow_anonymous: bool,
+    ///Accept connectionless QoS -1 publishes with predefined or short topic ids
+    #[serde(default = "PluginConfig::enable_qos_minus1_default")]
+    pub enable_qos_minus1: bool,
+
+    ///Topic ids known by the gateway and the clients in advance
+    #[serde(default)]
+    pub predefined_topics: Vec<PredefinedTopic>,
+    ///Maximum number of topics a client can register
+    #[serde(default = "PluginConfig::max_registered_topics_default")]
+    pub max_registered_topics: usize,
+
+    ///Session expiry interval of a client connected without the clean session flag
+    #[serde(
+        default = "PluginConfig::session_expiry_interval_default",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub session_expiry_interval: Duration,
+    ///Maximum number of messages queued for a sleeping client, the max_mqueue_len of its session
+    #[serde(default = "PluginConfig::max_sleep_buffer_default")]
+    pub max_sleep_buffer: usize,
+    ///Keep alive of a client is the CONNECT duration multiplied by this factor, and the session
+    ///of a sleeping client expires after the sleep duration multiplied by this factor
+    #[serde(default = "PluginConfig::keepalive_backoff_default")]
+    pub keepalive_backoff: f32,
+
+    #[serde(default)]
+    pub retain_available: bool,
+    #[serde(default = "PluginConfig::expiry_interval_default", deserialize_with = "deserialize_duration")]
+    pub expiry_interval: Duration,
+}
+
+impl PluginConfig {
+    fn listen_default() -> SocketAddr {
+        ([0, 0, 0, 0], 1884).into()
+    }
+
+    fn gateway_id_default() -> u8 {
+        1
+    }
+
+    fn allow_anonymous_default() -> bool {
+        true
+    }
+
+    fn enable_qos_minus1_default() -> bool {
+        true
+    }
+
+    fn max_registered_topics_default() -> usize {
+        1000
+    }
+
+    fn session_expiry_interval_default() -> Duration {
+        Duration::from_secs(2 * 60 * 60)
+    }
+
+    fn max_sleep_buffer_default() -> usize {
+        1000
+    }
+
+    fn keepalive_backoff_default() -> f32 {
+        1.5
+    }
+
+    fn expiry_interval_default() -> Duration {
+        Duration::from_secs(300)
+    }
+
+    #[inline]
+    pub fn predefined_topic(&self, topic_id: u16) -> Option<&str> {
+        self.predefined_topics.iter().find(|t| t.id == topic_id).map(|t| t.topic.as_str())
+    }
+
+    #[inline]
+    pub fn predefined_topic_id(&self, topic: &str) -> Option<u16> {
+        self.predefined_topics.iter().find(|t| t.topic == topic).map(|t| t.id)
+    }
+
+    ///The listener of the client sessions
+    pub fn listener(&self) -> Listener {
+        Listener::new(ListenerInner {
+            name: "mqttsn".into(),
+            addr: self.listen,
+            allow_anonymous: self.allow_anonymous,
+            max_keepalive: u16::MAX,
+            //the keep alive timeout of the fitter is twice the backoff
+            keepalive_backoff: self.keepalive_backoff / 2.0,
+            max_mqueue_len: self.max_sleep_buffer,
+            retain_available: self.retain_available,
+            message_expiry_interval: self.expiry_interval,
+            ..Default::default()
+        })
+    }
+
+    ///The session of a sleeping client is kept for the sleep duration, with the backoff
+    #[inline]
+    pub fn sleep_expiry_interval(&self, duration: u16) -> u32 {
+        (duration as f32 * self.keepalive_backoff).ceil() as u32
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PredefinedTopic {
+    pub id: u16,
+    pub topic: String,
+}
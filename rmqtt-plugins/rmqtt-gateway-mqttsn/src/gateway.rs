@@ -0,0 +1,884 @@
+//! MQTT-SN gateway, each connected client is a broker session.
+//!
+//! The session of a client is connected by CONNECT and listed by the clients API. A sleeping
+//! client, DISCONNECT with a duration, is disconnected with the session expiry of the sleep
+//! duration, its messages are kept by the offline session, and stored by the session storage.
+//! A PINGREQ with the client id reconnects the session, the messages are delivered and the
+//! client is asleep again after the PINGRESP.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::net::SocketAddr;
+use std::num::NonZeroU16;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rmqtt::broker::datagram::Datagram;
+use rmqtt::broker::gateway::{self, GatewayConnect, GatewaySession, GatewaySink};
+use rmqtt::settings::listener::Listener;
+use rmqtt::{
+    bytes::Bytes,
+    log, ntex,
+    serde_json::{self, json},
+    timestamp_millis,
+    tokio::{self, sync::oneshot},
+};
+use rmqtt::{
+    ClientId, From, Id, MqttError, NodeId, Publish, PublishProperties, QoS, Result, Runtime, SessionState,
+    TimestampMillis, TopicFilter, TopicName,
+};
+
+use crate::codec::{
+    short_topic_id, short_topic_name, Flags, Packet, SnQoS, SnTopic, TopicIdType, RC_ACCEPTED, RC_CONGESTION,
+    RC_INVALID_TOPIC_ID, RC_NOT_SUPPORTED,
+};
+use crate::config::PluginConfig;
+
+const MAX_DATAGRAM_SIZE: usize = 65535;
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(10);
+//The messages of an awake client are acknowledged before the PINGRESP, at most
+const WAKEUP_TIMEOUT: Duration = Duration::from_secs(5);
+//The sessions are closed by the stop of the gateway, at most
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+struct Metrics {
+    publishes_in: AtomicUsize,
+    publishes_out: AtomicUsize,
+    qos_minus1: AtomicUsize,
+    wakeups: AtomicUsize,
+    drops: AtomicUsize,
+    auth_fails: AtomicUsize,
+    bad_packets: AtomicUsize,
+}
+
+struct ClientState {
+    addr: SocketAddr,
+    //Normal topic ids, registered by the client or by the gateway
+    topics: HashMap<u16, String>,
+    topic_ids: HashMap<String, u16>,
+    next_topic_id: u16,
+    next_msg_id: u16,
+    //QoS 2 messages published, awaiting PUBREL
+    qos2_incoming: HashSet<u16>,
+    //Sleep duration, the client is asleep until the deadline, then its session is expired
+    sleep: Option<(u16, TimestampMillis)>,
+    //The packets of the session are held while the reply is built, they follow the reply
+    held: Option<Vec<Packet>>,
+}
+
+impl ClientState {
+    fn new(addr: SocketAddr) -> Self {
+        ClientState {
+            addr,
+            topics: HashMap::default(),
+            topic_ids: HashMap::default(),
+            next_topic_id: 1,
+            next_msg_id: 1,
+            qos2_incoming: HashSet::default(),
+            sleep: None,
+            held: None,
+        }
+    }
+
+    #[inline]
+    fn msg_id(&mut self) -> u16 {
+        let id = self.next_msg_id;
+        self.next_msg_id = self.next_msg_id.checked_add(1).unwrap_or(1);
+        id
+    }
+
+    #[inline]
+    fn register(&mut self, topic: &str, max: usize) -> Option<u16> {
+        if let Some(id) = self.topic_ids.get(topic) {
+            return Some(*id);
+        }
+        if self.topics.len() >= max || self.next_topic_id == u16::MAX {
+            return None;
+        }
+        let id = self.next_topic_id;
+        self.next_topic_id += 1;
+        self.topics.insert(id, topic.to_string());
+        self.topic_ids.insert(topic.to_string(), id);
+        Some(id)
+    }
+
+    #[inline]
+    fn is_asleep(&self) -> bool {
+        self.sleep.is_some()
+    }
+}
+
+#[inline]
+fn to_qos(qos: SnQoS) -> QoS {
+    match qos {
+        SnQoS::AtLeastOnce => QoS::AtLeastOnce,
+        SnQoS::ExactlyOnce => QoS::ExactlyOnce,
+        SnQoS::AtMostOnce | SnQoS::Minus1 => QoS::AtMostOnce,
+    }
+}
+
+#[inline]
+fn to_sn_qos(qos: QoS) -> SnQoS {
+    match qos {
+        QoS::AtMostOnce => SnQoS::AtMostOnce,
+        QoS::AtLeastOnce => SnQoS::AtLeastOnce,
+        QoS::ExactlyOnce => SnQoS::ExactlyOnce,
+    }
+}
+
+///Builds the packets delivering the message to the client, a topic id is registered if needed.
+///The message id is the packet id of the session.
+fn outgoing(cfg: &PluginConfig, state: &mut ClientState, p: &Publish) -> Option<Vec<Packet>> {
+    let mut packets = Vec::new();
+    let (topic_id_type, topic_id) = if let Some(topic_id) = cfg.predefined_topic_id(&p.topic) {
+        (TopicIdType::Predefined, topic_id)
+    } else if let Some(topic_id) = short_topic_id(&p.topic) {
+        (TopicIdType::Short, topic_id)
+    } else if let Some(topic_id) = state.topic_ids.get(&*p.topic) {
+        (TopicIdType::Normal, *topic_id)
+    } else if let Some(topic_id) = state.register(&p.topic, cfg.max_registered_topics) {
+        let msg_id = state.msg_id();
+        packets.push(Packet::Register { topic_id, msg_id, topic_name: p.topic.to_string() });
+        (TopicIdType::Normal, topic_id)
+    } else {
+        return None;
+    };
+    let msg_id = p.packet_id.map(|id| id.get()).unwrap_or_default();
+    let mut flags = Flags::new(to_sn_qos(p.qos), topic_id_type);
+    flags.retain = p.retain;
+    flags.dup = p.dup;
+    packets.push(Packet::Publish { flags, topic_id, msg_id, data: Bytes::copy_from_slice(&p.payload) });
+    Some(packets)
+}
+
+///Writes the messages of the session to the client
+struct SnSink {
+    client_id: ClientId,
+    state: Arc<Mutex<ClientState>>,
+    closed: AtomicBool,
+    gateway: Arc<Gateway>,
+}
+
+impl fmt::Debug for SnSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SnSink {{ {:?}, closed: {} }}", self.client_id, self.closed.load(Ordering::SeqCst))
+    }
+}
+
+impl SnSink {
+    #[inline]
+    fn send(&self, state: &mut ClientState, packets: Vec<Packet>) {
+        match state.held.as_mut() {
+            Some(held) => held.extend(packets),
+            None => self.gateway.send_all(state.addr, packets),
+        }
+    }
+}
+
+impl GatewaySink for SnSink {
+    fn publish(&self, p: &Publish) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        match outgoing(&self.gateway.cfg, &mut state, p) {
+            Some(packets) => {
+                self.send(&mut state, packets);
+                self.gateway.metrics.publishes_out.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            }
+            None => {
+                self.gateway.metrics.drops.fetch_add(1, Ordering::SeqCst);
+                Err(MqttError::from(format!("too many registered topics, topic: {}", p.topic)))
+            }
+        }
+    }
+
+    fn release(&self, packet_id: NonZeroU16) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        self.send(&mut state, vec![Packet::PubRel { msg_id: packet_id.get() }]);
+        Ok(())
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+    }
+}
+
+struct SnClient {
+    session: GatewaySession,
+    sink: Arc<SnSink>,
+}
+
+impl SnClient {
+    #[inline]
+    fn is_closed(&self) -> bool {
+        self.sink.closed.load(Ordering::SeqCst)
+    }
+
+    #[inline]
+    fn state(&self) -> std::sync::MutexGuard<'_, ClientState> {
+        self.sink.state.lock().unwrap()
+    }
+}
+
+pub(crate) struct Gateway {
+    cfg: PluginConfig,
+    listen_cfg: Listener,
+    node_id: NodeId,
+    socket: Datagram,
+    clients: AtomicUsize,
+    metrics: Metrics,
+}
+
+impl Gateway {
+    pub async fn bind(cfg: PluginConfig, node_id: NodeId) -> Result<Arc<Gateway>> {
+        let socket = Datagram::bind(cfg.listen, &cfg.dtls).await?;
+        log::info!("MQTT-SN gateway listening on {}, dtls: {}", cfg.listen, socket.is_secure());
+        Ok(Arc::new(Gateway {
+            listen_cfg: cfg.listener(),
+            cfg,
+            node_id,
+            socket,
+            clients: AtomicUsize::new(0),
+            metrics: Metrics::default(),
+        }))
+    }
+
+    ///Serves the clients on the ntex runtime until stopped, the client sessions are closed then
+    pub async fn serve(self: Arc<Self>, mut stop: oneshot::Receiver<()>) {
+        let server = Rc::new(Server {
+            gateway: self.clone(),
+            clients: RefCell::new(HashMap::default()),
+            addrs: RefCell::new(HashMap::default()),
+        });
+        let mut cleanup = tokio::time::interval(CLEANUP_INTERVAL);
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        loop {
+            tokio::select! {
+                _ = &mut stop => break,
+                _ = cleanup.tick() => server.cleanup(),
+                res = self.socket.recv_from(&mut buf) => {
+                    let (len, addr) = match res {
+                        Ok(r) => r,
+                        Err(e) => {
+                            log::warn!("MQTT-SN gateway recv error, {:?}", e);
+                            continue;
+                        }
+                    };
+                    let packet = match Packet::decode(&buf[..len]) {
+                        Ok(p) => p,
+                        Err(e) => {
+                            self.metrics.bad_packets.fetch_add(1, Ordering::SeqCst);
+                            log::debug!("{} invalid MQTT-SN packet, {:?}", addr, e);
+                            continue;
+                        }
+                    };
+                    let server = server.clone();
+                    ntex::rt::spawn(async move {
+                        match server.handle(addr, packet).await {
+                            Ok(replies) => server.gateway.send_all(addr, replies),
+                            Err(e) => log::debug!("{} MQTT-SN request error, {:?}", addr, e),
+                        }
+                    });
+                }
+            }
+        }
+        server.close().await;
+    }
+
+    //The packets are sent in the order of the calls
+    #[inline]
+    fn send_all(self: &Arc<Self>, addr: SocketAddr, packets: Vec<Packet>) {
+        if packets.is_empty() {
+            return;
+        }
+        let gw = self.clone();
+        ntex::rt::spawn(async move {
+            for packet in packets {
+                if let Err(e) = gw.socket.send_to(&packet.encode(), addr).await {
+                    log::warn!("MQTT-SN gateway send to {} error, {:?}", addr, e);
+                }
+            }
+        });
+    }
+
+    #[inline]
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "listen": self.cfg.listen,
+            "dtls": self.socket.is_secure(),
+            "clients": self.clients.load(Ordering::SeqCst),
+            "metrics": {
+                "publishes_in": self.metrics.publishes_in.load(Ordering::SeqCst),
+                "publishes_out": self.metrics.publishes_out.load(Ordering::SeqCst),
+                "qos_minus1": self.metrics.qos_minus1.load(Ordering::SeqCst),
+                "wakeups": self.metrics.wakeups.load(Ordering::SeqCst),
+                "drops": self.metrics.drops.load(Ordering::SeqCst),
+                "auth_fails": self.metrics.auth_fails.load(Ordering::SeqCst),
+                "bad_packets": self.metrics.bad_packets.load(Ordering::SeqCst),
+            }
+        })
+    }
+}
+
+//The client sessions are not Send, they are kept on the ntex runtime of the gateway
+struct Server {
+    gateway: Arc<Gateway>,
+    clients: RefCell<HashMap<ClientId, Rc<SnClient>>>,
+    addrs: RefCell<HashMap<SocketAddr, ClientId>>,
+}
+
+impl Server {
+    #[inline]
+    fn client(&self, addr: &SocketAddr) -> Option<Rc<SnClient>> {
+        let client_id = self.addrs.borrow().get(addr)?.clone();
+        self.clients.borrow().get(&client_id).cloned()
+    }
+
+    fn insert(&self, client_id: ClientId, client: Rc<SnClient>) {
+        let addr = client.state().addr;
+        if let Some(prev) = self.clients.borrow_mut().insert(client_id.clone(), client) {
+            let prev_addr = prev.state().addr;
+            if prev_addr != addr {
+                self.addrs.borrow_mut().remove(&prev_addr);
+            }
+        } else {
+            self.gateway.clients.fetch_add(1, Ordering::SeqCst);
+        }
+        self.addrs.borrow_mut().insert(addr, client_id);
+    }
+
+    fn remove(&self, client_id: &ClientId) {
+        if let Some(client) = self.clients.borrow_mut().remove(client_id) {
+            self.gateway.clients.fetch_sub(1, Ordering::SeqCst);
+            let addr = client.state().addr;
+            let mut addrs = self.addrs.borrow_mut();
+            if addrs.get(&addr) == Some(client_id) {
+                addrs.remove(&addr);
+            }
+        }
+    }
+
+    ///Removes the closed clients, and the sleeping clients whose sessions are expired
+    fn cleanup(&self) {
+        let now = timestamp_millis();
+        let removeds = self
+            .clients
+            .borrow()
+            .iter()
+            .filter(|(_, c)| {
+                c.is_closed()
+                    && match c.state().sleep {
+                        Some((_, deadline)) => deadline < now,
+                        None => true,
+                    }
+            })
+            .map(|(client_id, _)| client_id.clone())
+            .collect::<Vec<_>>();
+        for client_id in removeds {
+            log::debug!("{:?} MQTT-SN client removed", client_id);
+            self.remove(&client_id);
+        }
+    }
+
+    async fn handle(&self, addr: SocketAddr, packet: Packet) -> Result<Vec<Packet>> {
+        match packet {
+            Packet::SearchGw { .. } => Ok(vec![Packet::GwInfo { gw_id: self.gateway.cfg.gateway_id }]),
+            Packet::Connect { flags, duration, client_id } => {
+                self.connect(addr, flags, duration, client_id).await
+            }
+            Packet::Publish { flags, topic_id, data, .. } if flags.qos == SnQoS::Minus1 => {
+                self.publish_minus1(addr, flags, topic_id, data).await?;
+                Ok(Vec::new())
+            }
+            Packet::PingReq { client_id: Some(client_id) } => self.wakeup(addr, client_id).await,
+            packet => {
+                let client = match self.client(&addr) {
+                    Some(client) if !client.is_closed() => client,
+                    _ => {
+                        log::debug!("{} not connected, {:?}", addr, packet);
+                        return Ok(vec![Packet::Disconnect { duration: None }]);
+                    }
+                };
+                if let Err(e) = client.session.keepalive(matches!(packet, Packet::PingReq { .. })) {
+                    log::debug!("{:?} keepalive error, {:?}", client.session.id(), e);
+                }
+                self.handle_connected(&client, packet).await
+            }
+        }
+    }
+
+    async fn handle_connected(&self, client: &SnClient, packet: Packet) -> Result<Vec<Packet>> {
+        let cfg = &self.gateway.cfg;
+        let replies = match packet {
+            Packet::Register { msg_id, topic_name, .. } => {
+                let (topic_id, return_code) = if topic_name.contains(['+', '#']) {
+                    (0, RC_NOT_SUPPORTED)
+                } else {
+                    match client.state().register(&topic_name, cfg.max_registered_topics) {
+                        Some(topic_id) => (topic_id, RC_ACCEPTED),
+                        None => (0, RC_CONGESTION),
+                    }
+                };
+                vec![Packet::RegAck { topic_id, msg_id, return_code }]
+            }
+            Packet::Publish { flags, topic_id, msg_id, data } => {
+                self.publish(client, flags, topic_id, msg_id, data).await?
+            }
+            Packet::PubRel { msg_id } => {
+                client.state().qos2_incoming.remove(&msg_id);
+                vec![Packet::PubComp { msg_id }]
+            }
+            //Acknowledgements of the messages delivered by the session
+            Packet::PubAck { msg_id, .. } => {
+                if let Some(packet_id) = NonZeroU16::new(msg_id) {
+                    client.session.acked(packet_id).await;
+                }
+                Vec::new()
+            }
+            Packet::PubRec { msg_id } => {
+                if let Some(packet_id) = NonZeroU16::new(msg_id) {
+                    client.session.received(packet_id).await?;
+                }
+                Vec::new()
+            }
+            Packet::PubComp { msg_id } => {
+                if let Some(packet_id) = NonZeroU16::new(msg_id) {
+                    client.session.completed(packet_id).await;
+                }
+                Vec::new()
+            }
+            Packet::RegAck { .. } => Vec::new(),
+            Packet::Subscribe { flags, msg_id, topic } => {
+                self.subscribe(client, flags, msg_id, topic).await?
+            }
+            Packet::Unsubscribe { flags, msg_id, topic } => {
+                if let Some(tf) = self.topic_filter(client, flags.topic_id_type, topic)? {
+                    client.session.unsubscribe(&TopicFilter::from(tf)).await?;
+                }
+                vec![Packet::UnsubAck { msg_id }]
+            }
+            Packet::PingReq { .. } => vec![Packet::PingResp],
+            Packet::Disconnect { duration: Some(duration) } => {
+                let expiry_interval = cfg.sleep_expiry_interval(duration);
+                let deadline = timestamp_millis() + expiry_interval as i64 * 1000;
+                client.state().sleep = Some((duration, deadline));
+                log::debug!("{:?} asleep for {}s", client.session.id(), duration);
+                client.session.disconnect(Some(expiry_interval))?;
+                vec![Packet::Disconnect { duration: None }]
+            }
+            Packet::Disconnect { duration: None } => {
+                client.session.disconnect(None)?;
+                self.remove(&client.session.id().client_id);
+                vec![Packet::Disconnect { duration: None }]
+            }
+            packet => {
+                log::debug!("{:?} unexpected packet, {:?}", client.session.id(), packet);
+                Vec::new()
+            }
+        };
+        Ok(replies)
+    }
+
+    //Connects the session of the client, the packets of the session are held until the CONNACK is sent
+    async fn connect_session(
+        &self,
+        id: Id,
+        clean_start: bool,
+        session_expiry_interval: u32,
+        keep_alive: u16,
+        state: Arc<Mutex<ClientState>>,
+    ) -> Result<Rc<SnClient>> {
+        let sink = Arc::new(SnSink {
+            client_id: id.client_id.clone(),
+            state,
+            closed: AtomicBool::new(false),
+            gateway: self.gateway.clone(),
+        });
+        sink.state.lock().unwrap().held = Some(Vec::new());
+        let connect = GatewayConnect { id, clean_start, session_expiry_interval, keep_alive, password: None };
+        match gateway::connect(self.gateway.listen_cfg.clone(), connect, sink.clone()).await {
+            Ok((session, _)) => Ok(Rc::new(SnClient { session, sink })),
+            Err(e) => {
+                sink.state.lock().unwrap().held = None;
+                Err(e)
+            }
+        }
+    }
+
+    //Sends the reply, then the packets of the session held while it was built
+    fn release(&self, client: &SnClient, mut replies: Vec<Packet>) {
+        let mut state = client.state();
+        replies.extend(state.held.take().unwrap_or_default());
+        self.gateway.send_all(state.addr, replies);
+    }
+
+    async fn connect(
+        &self,
+        addr: SocketAddr,
+        flags: Flags,
+        duration: u16,
+        client_id: String,
+    ) -> Result<Vec<Packet>> {
+        let cfg = &self.gateway.cfg;
+        //Will topic and message are not supported
+        if flags.will {
+            return Ok(vec![Packet::ConnAck { return_code: RC_NOT_SUPPORTED }]);
+        }
+        let client_id = if client_id.is_empty() {
+            if !cfg.allow_anonymous {
+                return Ok(vec![Packet::ConnAck { return_code: RC_NOT_SUPPORTED }]);
+            }
+            ClientId::from(format!("mqttsn-{}", addr))
+        } else {
+            ClientId::from(client_id)
+        };
+
+        //The registered topics are kept unless the clean session flag is set
+        let prev = self.clients.borrow().get(&client_id).cloned();
+        let state = match prev {
+            Some(prev) if !flags.clean_session => {
+                let mut state = prev.state();
+                state.addr = addr;
+                state.sleep = None;
+                drop(state);
+                prev.sink.state.clone()
+            }
+            _ => Arc::new(Mutex::new(ClientState::new(addr))),
+        };
+
+        let id = Id::new(self.gateway.node_id, Some(cfg.listen), Some(addr), client_id.clone(), None);
+        let session_expiry_interval =
+            if flags.clean_session { 0 } else { cfg.session_expiry_interval.as_secs() as u32 };
+        let client = match self
+            .connect_session(id, flags.clean_session, session_expiry_interval, duration, state)
+            .await
+        {
+            Ok(client) => client,
+            Err(e) => {
+                self.gateway.metrics.auth_fails.fetch_add(1, Ordering::SeqCst);
+                log::debug!("{:?} MQTT-SN connect failed, {:?}", client_id, e);
+                return Ok(vec![Packet::ConnAck { return_code: RC_NOT_SUPPORTED }]);
+            }
+        };
+        self.insert(client_id, client.clone());
+        self.release(&client, vec![Packet::ConnAck { return_code: RC_ACCEPTED }]);
+        Ok(Vec::new())
+    }
+
+    //PINGREQ with the client id, a sleeping client is awake until the messages of its session are
+    //delivered, the session is reconnected then disconnected again
+    async fn wakeup(&self, addr: SocketAddr, client_id: String) -> Result<Vec<Packet>> {
+        let cfg = &self.gateway.cfg;
+        let client_id = ClientId::from(client_id);
+        let client = match self.clients.borrow().get(&client_id).cloned() {
+            Some(client) => client,
+            None => return Ok(vec![Packet::PingResp]),
+        };
+        let sleep = client.state().sleep;
+        let duration = match sleep {
+            Some((duration, _)) if client.is_closed() => duration,
+            //An awake client, PINGREQ with its client id
+            _ => {
+                if let Err(e) = client.session.keepalive(true) {
+                    log::debug!("{:?} keepalive error, {:?}", client.session.id(), e);
+                }
+                return Ok(vec![Packet::PingResp]);
+            }
+        };
+        client.state().addr = addr;
+        self.gateway.metrics.wakeups.fetch_add(1, Ordering::SeqCst);
+
+        let id = Id::new(self.gateway.node_id, Some(cfg.listen), Some(addr), client_id.clone(), None);
+        let state = client.sink.state.clone();
+        let expiry_interval = cfg.sleep_expiry_interval(duration);
+        let client = match self.connect_session(id, false, expiry_interval, duration, state).await {
+            Ok(client) => client,
+            Err(e) => {
+                log::debug!("{:?} MQTT-SN wakeup failed, {:?}", client_id, e);
+                return Ok(vec![Packet::Disconnect { duration: None }]);
+            }
+        };
+        self.insert(client_id, client.clone());
+        self.release(&client, Vec::new());
+
+        let _ = tokio::time::timeout(WAKEUP_TIMEOUT, async {
+            while client.session.pending().await > 0 && !client.is_closed() {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await;
+
+        let deadline = timestamp_millis() + expiry_interval as i64 * 1000;
+        client.state().sleep = Some((duration, deadline));
+        client.session.disconnect(Some(expiry_interval))?;
+        Ok(vec![Packet::PingResp])
+    }
+
+    #[inline]
+    fn topic_name(&self, state: &ClientState, topic_id_type: TopicIdType, topic_id: u16) -> Option<String> {
+        match topic_id_type {
+            TopicIdType::Normal => state.topics.get(&topic_id).cloned(),
+            TopicIdType::Predefined => self.gateway.cfg.predefined_topic(topic_id).map(|t| t.to_string()),
+            TopicIdType::Short => short_topic_name(topic_id).ok(),
+        }
+    }
+
+    fn topic_filter(
+        &self,
+        client: &SnClient,
+        topic_id_type: TopicIdType,
+        topic: SnTopic,
+    ) -> Result<Option<String>> {
+        Ok(match topic {
+            SnTopic::Name(name) => Some(name),
+            SnTopic::Id(topic_id) => match topic_id_type {
+                TopicIdType::Predefined => self.gateway.cfg.predefined_topic(topic_id).map(|t| t.to_string()),
+                TopicIdType::Short => Some(short_topic_name(topic_id)?),
+                TopicIdType::Normal => client.state().topics.get(&topic_id).cloned(),
+            },
+        })
+    }
+
+    async fn publish(
+        &self,
+        client: &SnClient,
+        flags: Flags,
+        topic_id: u16,
+        msg_id: u16,
+        data: Bytes,
+    ) -> Result<Vec<Packet>> {
+        let (topic, duplicate) = {
+            let mut state = client.state();
+            let topic = self.topic_name(&state, flags.topic_id_type, topic_id);
+            let duplicate =
+                topic.is_some() && flags.qos == SnQoS::ExactlyOnce && !state.qos2_incoming.insert(msg_id);
+            (topic, duplicate)
+        };
+        let topic = match topic {
+            Some(topic) if !topic.contains(['+', '#']) => topic,
+            _ => return Ok(vec![Packet::PubAck { topic_id, msg_id, return_code: RC_INVALID_TOPIC_ID }]),
+        };
+        let return_code = if duplicate {
+            RC_ACCEPTED
+        } else {
+            let msg = Publish {
+                dup: flags.dup,
+                retain: flags.retain,
+                qos: to_qos(flags.qos),
+                topic: TopicName::from(topic),
+                packet_id: None,
+                payload: ntex::util::Bytes::copy_from_slice(&data),
+                properties: PublishProperties::default(),
+                create_time: timestamp_millis(),
+            };
+            if client.session.publish(msg).await? {
+                self.gateway.metrics.publishes_in.fetch_add(1, Ordering::SeqCst);
+                RC_ACCEPTED
+            } else {
+                RC_NOT_SUPPORTED
+            }
+        };
+        Ok(match flags.qos {
+            SnQoS::AtLeastOnce => vec![Packet::PubAck { topic_id, msg_id, return_code }],
+            SnQoS::ExactlyOnce if return_code == RC_ACCEPTED => vec![Packet::PubRec { msg_id }],
+            SnQoS::ExactlyOnce => vec![Packet::PubAck { topic_id, msg_id, return_code }],
+            _ => Vec::new(),
+        })
+    }
+
+    //Connectionless publish, only predefined and short topic ids are accepted
+    async fn publish_minus1(&self, addr: SocketAddr, flags: Flags, topic_id: u16, data: Bytes) -> Result<()> {
+        let cfg = &self.gateway.cfg;
+        if !cfg.enable_qos_minus1 {
+            return Err(MqttError::from("QoS -1 is disabled"));
+        }
+        let topic = match flags.topic_id_type {
+            TopicIdType::Predefined => cfg.predefined_topic(topic_id).map(|t| t.to_string()),
+            TopicIdType::Short => Some(short_topic_name(topic_id)?),
+            TopicIdType::Normal => None,
+        }
+        .ok_or_else(|| MqttError::from(format!("invalid topic id for QoS -1, {}", topic_id)))?;
+        let id = Id::new(
+            self.gateway.node_id,
+            Some(cfg.listen),
+            Some(addr),
+            ClientId::from(format!("mqttsn-{}", addr)),
+            None,
+        );
+        let msg = Publish {
+            dup: false,
+            retain: flags.retain,
+            qos: QoS::AtMostOnce,
+            topic: TopicName::from(topic),
+            packet_id: None,
+            payload: ntex::util::Bytes::copy_from_slice(&data),
+            properties: PublishProperties::default(),
+            create_time: timestamp_millis(),
+        };
+        let from = From::from_custom(id);
+        //hook, message_publish
+        let msg = match Runtime::instance()
+            .extends
+            .hook_mgr()
+            .await
+            .message_publish(None, from.clone(), &msg)
+            .await
+        {
+            Ok(modified) => modified.unwrap_or(msg),
+            //dropped by a handler
+            Err(_) => return Ok(()),
+        };
+        let storage_available = Runtime::instance().extends.message_mgr().await.enable();
+        SessionState::forwards(
+            from,
+            msg,
+            self.gateway.listen_cfg.retain_available,
+            storage_available,
+            Some(cfg.expiry_interval),
+        )
+        .await?;
+        self.gateway.metrics.qos_minus1.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    //The retained messages are delivered by the session after the SUBACK
+    async fn subscribe(
+        &self,
+        client: &SnClient,
+        flags: Flags,
+        msg_id: u16,
+        topic: SnTopic,
+    ) -> Result<Vec<Packet>> {
+        let failure = |return_code| {
+            vec![Packet::SubAck {
+                flags: Flags::new(flags.qos, flags.topic_id_type),
+                topic_id: 0,
+                msg_id,
+                return_code,
+            }]
+        };
+        let tf = match self.topic_filter(client, flags.topic_id_type, topic.clone())? {
+            Some(tf) => tf,
+            None => return Ok(failure(RC_INVALID_TOPIC_ID)),
+        };
+
+        client.state().held = Some(Vec::new());
+        let sub_ret =
+            client.session.subscribe(&TopicFilter::from(tf.as_str()), to_qos(flags.qos), true).await;
+        let qos = match sub_ret.map(|ret| ret.success()) {
+            Ok(Some(qos)) => qos,
+            Ok(None) => {
+                self.release(client, failure(RC_NOT_SUPPORTED));
+                return Ok(Vec::new());
+            }
+            Err(e) => {
+                log::debug!("{:?} subscribe {} error, {:?}", client.session.id(), tf, e);
+                self.release(client, failure(RC_INVALID_TOPIC_ID));
+                return Ok(Vec::new());
+            }
+        };
+
+        let topic_id = match topic {
+            SnTopic::Id(topic_id) => topic_id,
+            //A topic id is assigned to a topic name without wildcards
+            SnTopic::Name(_) if !tf.contains(['+', '#']) => {
+                client.state().register(&tf, self.gateway.cfg.max_registered_topics).unwrap_or(0)
+            }
+            SnTopic::Name(_) => 0,
+        };
+        self.release(
+            client,
+            vec![Packet::SubAck {
+                flags: Flags::new(to_sn_qos(qos), flags.topic_id_type),
+                topic_id,
+                msg_id,
+                return_code: RC_ACCEPTED,
+            }],
+        );
+        Ok(Vec::new())
+    }
+
+    //Disconnects the client sessions, before the runtime of the gateway is stopped
+    async fn close(&self) {
+        let clients = self.clients.borrow_mut().drain().map(|(_, c)| c).collect::<Vec<_>>();
+        self.addrs.borrow_mut().clear();
+        self.gateway.clients.fetch_sub(clients.len(), Ordering::SeqCst);
+        for client in clients.iter().filter(|c| !c.is_closed()) {
+            if let Err(e) = client.session.disconnect(None) {
+                log::debug!("{:?} disconnect error, {:?}", client.session.id(), e);
+            }
+        }
+        let _ = tokio::time::timeout(CLOSE_TIMEOUT, async {
+            while clients.iter().any(|c| !c.is_closed()) {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PredefinedTopic;
+
+    fn publish(topic: &str, qos: QoS, packet_id: u16) -> Publish {
+        Publish {
+            dup: false,
+            retain: false,
+            qos,
+            topic: TopicName::from(topic),
+            packet_id: NonZeroU16::new(packet_id),
+            payload: ntex::util::Bytes::from_static(b"data"),
+            properties: PublishProperties::default(),
+            create_time: 0,
+        }
+    }
+
+    #[test]
+    fn test_outgoing() {
+        let mut cfg: PluginConfig = serde_json::from_str("{}").unwrap();
+        cfg.predefined_topics.push(PredefinedTopic { id: 9, topic: "sensors/t".into() });
+        cfg.max_registered_topics = 1;
+        let mut state = ClientState::new(([127, 0, 0, 1], 1884).into());
+
+        //predefined and short topic ids are not registered
+        let packets = outgoing(&cfg, &mut state, &publish("sensors/t", QoS::AtLeastOnce, 3)).unwrap();
+        assert!(matches!(
+            packets.as_slice(),
+            [Packet::Publish { topic_id: 9, msg_id: 3, flags, .. }]
+                if flags.topic_id_type == TopicIdType::Predefined && flags.qos == SnQoS::AtLeastOnce
+        ));
+        let packets = outgoing(&cfg, &mut state, &publish("ab", QoS::AtMostOnce, 0)).unwrap();
+        assert!(matches!(
+            packets.as_slice(),
+            [Packet::Publish { msg_id: 0, flags, .. }] if flags.topic_id_type == TopicIdType::Short
+        ));
+
+        //a topic name is registered before the publish, once
+        let packets = outgoing(&cfg, &mut state, &publish("a/b", QoS::ExactlyOnce, 4)).unwrap();
+        assert!(matches!(
+            packets.as_slice(),
+            [Packet::Register { topic_id: 1, .. }, Packet::Publish { topic_id: 1, msg_id: 4, .. }]
+        ));
+        let packets = outgoing(&cfg, &mut state, &publish("a/b", QoS::ExactlyOnce, 5)).unwrap();
+        assert!(matches!(packets.as_slice(), [Packet::Publish { topic_id: 1, msg_id: 5, .. }]));
+
+        //the registry is full
+        assert!(outgoing(&cfg, &mut state, &publish("a/c", QoS::AtMostOnce, 0)).is_none());
+    }
+
+    #[test]
+    fn test_sleep_expiry_interval() {
+        let mut cfg: PluginConfig = serde_json::from_str("{}").unwrap();
+        assert_eq!(cfg.sleep_expiry_interval(60), 90);
+        cfg.keepalive_backoff = 1.0;
+        assert_eq!(cfg.sleep_expiry_interval(60), 60);
+        let listener = cfg.listener();
+        assert_eq!(listener.max_mqueue_len, cfg.max_sleep_buffer);
+        assert_eq!(listener.keepalive_backoff, 0.5);
+    }
+}
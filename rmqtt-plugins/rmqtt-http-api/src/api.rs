@@ -7,32 +7,50 @@ use salvo::conn::tcp::TcpAcceptor;
 use salvo::http::header::{HeaderValue, CONTENT_TYPE};
 use salvo::http::mime;
 use salvo::prelude::*;
+use salvo::sse::SseKeepAlive;
 
 use rmqtt::{
     anyhow::{self, anyhow},
     base64::{engine::general_purpose, Engine as _},
-    bytes, chrono, futures, log,
+    bytes,
+    bytestring::ByteString,
+    chrono, futures, log,
     serde_json::{self, json},
     tokio,
     tokio::sync::oneshot,
     HashMap, SessionState,
 };
 use rmqtt::{
+    broker::acl_cache::AclCache,
+    broker::alarm::{Alarm, AlarmEventKind, AlarmManager},
+    broker::audit::{AuditEvent, AuditLog},
+    broker::conformance::Conformance,
+    broker::consistency::Consistency,
+    broker::journal::{Journal, JournalEntry},
+    broker::labels::Labels,
+    broker::listeners::ListenerManager,
+    broker::maintenance::Maintenance,
+    broker::provision::{self, ProvisionedSession},
     broker::types::NodeId,
     grpc::{
-        client::NodeGrpcClient, Message as GrpcMessage, MessageBroadcaster, MessageReply as GrpcMessageReply,
-        MessageSender, MessageType,
+        client::NodeGrpcClient, message_types::MessageTypes, Message as GrpcMessage, MessageBroadcaster,
+        MessageReply as GrpcMessageReply, MessageSender, MessageType,
     },
+    logger::LogLevels,
     node::NodeStatus,
+    settings::listener::{Listener, ListenerInner, ListenerType},
+    settings::to_duration,
     ClientId, From, Id, MqttError, Publish, PublishProperties, QoS, Result, Runtime, SubsSearchParams,
-    TopicFilter, TopicName, UserName,
+    TimestampMillis, TopicFilter, TopicName, UserName,
 };
 
+use super::topic_samples::TopicSamples;
 use super::types::{
-    ClientSearchParams, Message, MessageReply, PublishParams, SubscribeParams, UnsubscribeParams,
+    ClientSearchParams, Message, MessageReply, PublishParams, RedirectParams, SubscribeParams,
+    UnsubscribeParams,
 };
 use super::PluginConfigType;
-use super::{clients, plugin, subs};
+use super::{client_events, clients, ingest, plugin, subs};
 
 fn route(cfg: PluginConfigType) -> Router {
     Router::with_path("api/v1")
@@ -42,19 +60,50 @@ fn route(cfg: PluginConfigType) -> Router {
         .push(Router::with_path("brokers").get(get_brokers).push(Router::with_path("<id>").get(get_brokers)))
         .push(Router::with_path("nodes").get(get_nodes).push(Router::with_path("<id>").get(get_nodes)))
         .push(Router::with_path("health/check").get(check_health))
+        .push(Router::with_path("alarms").get(get_alarms))
+        .push(Router::with_path("conformance").get(get_conformance))
+        .push(Router::with_path("consistency").get(check_consistency).put(repair_consistency))
+        .push(Router::with_path("acl/cache").delete(invalidate_acl_cache))
+        .push(Router::with_path("drain").put(drain_node))
+        .push(
+            Router::with_path("maintenance")
+                .get(get_maintenance)
+                .put(enter_maintenance)
+                .delete(leave_maintenance),
+        )
+        .push(Router::with_path("redirect").put(redirect_clients))
+        .push(Router::with_path("log/levels").get(get_log_levels).put(set_log_level).delete(remove_log_level))
+        .push(Router::with_path("grpc/message_types").get(get_message_types))
+        .push(Router::with_path("sessions/migrate").put(migrate_sessions))
+        .push(Router::with_path("sessions/export").get(export_sessions))
+        .push(Router::with_path("sessions/import").post(import_sessions))
+        .push(
+            Router::with_path("listeners").get(get_listeners).push(
+                Router::with_path("<type>")
+                    .post(add_listener)
+                    .push(Router::with_path("<port>").put(modify_listener).delete(remove_listener)),
+            ),
+        )
         .push(
             Router::with_path("clients").get(search_clients).push(
                 Router::with_path("<clientid>")
                     .get(get_client)
                     .delete(kick_client)
-                    .push(Router::with_path("online").get(check_online)),
+                    .push(Router::with_path("online").get(check_online))
+                    .push(Router::with_path("session").get(get_client_session).delete(purge_client_session))
+                    .push(Router::with_path("journal").get(get_client_journal).delete(clear_client_journal)),
             ),
         )
+        .push(Router::with_path("stream/clients").get(stream_clients))
+        .push(
+            Router::with_path("labels").get(get_labels).push(Router::with_path("<label>").delete(kick_label)),
+        )
         .push(
             Router::with_path("subscriptions")
                 .get(query_subscriptions)
                 .push(Router::with_path("<clientid>").get(get_client_subscriptions)),
         )
+        .push(Router::with_path("topics/samples").get(get_topic_samples))
         .push(Router::with_path("routes").get(get_routes).push(Router::with_path("<topic>").get(get_route)))
         .push(
             Router::with_path("mqtt")
@@ -62,12 +111,18 @@ fn route(cfg: PluginConfigType) -> Router {
                 .push(Router::with_path("subscribe").post(subscribe))
                 .push(Router::with_path("unsubscribe").post(unsubscribe)),
         )
+        .push(
+            Router::with_path("publish")
+                .post(ingest::publish)
+                .push(Router::with_path("bulk").post(ingest::publish_bulk)),
+        )
         .push(
             Router::with_path("plugins")
                 .get(all_plugins)
                 .push(Router::with_path("<node>").get(node_plugins))
                 .push(Router::with_path("<node>/<plugin>").get(node_plugin_info))
                 .push(Router::with_path("<node>/<plugin>/config").get(node_plugin_config))
+                .push(Router::with_path("<node>/<plugin>/config/schema").get(node_plugin_config_schema))
                 .push(Router::with_path("<node>/<plugin>/config/reload").put(node_plugin_config_reload))
                 .push(Router::with_path("<node>/<plugin>/load").put(node_plugin_load))
                 .push(Router::with_path("<node>/<plugin>/unload").put(node_plugin_unload)),
@@ -127,12 +182,138 @@ async fn list_apis(res: &mut Response) {
             "path": "/nodes/{node}",
             "descr": "Returns the status of the node"
         },
+        {
+            "name": "get_alarms",
+            "method": "GET",
+            "path": "/alarms",
+            "descr": "Returns the alarms of the cluster, the active ones, or the deactivated ones with activated=false"
+        },
         {
             "name": "check_health",
             "method": "GET",
             "path": "/health/check",
             "descr": "Node health check"
         },
+        {
+            "name": "get_conformance",
+            "method": "GET",
+            "path": "/conformance",
+            "descr": "Returns the MQTT spec violation counts of the strict conformance listeners of this node"
+        },
+        {
+            "name": "check_consistency",
+            "method": "GET",
+            "path": "/consistency",
+            "descr": "Audit the routes, the sessions and the retained messages of the nodes of the cluster, returns the orphans found on each node"
+        },
+        {
+            "name": "repair_consistency",
+            "method": "PUT",
+            "path": "/consistency",
+            "descr": "Audit the nodes of the cluster and repair the inconsistencies found, returns the report of each node"
+        },
+        {
+            "name": "invalidate_acl_cache",
+            "method": "DELETE",
+            "path": "/acl/cache",
+            "descr": "Drop the cached ACL decisions of the nodes of the cluster, of a client or of a topic if given"
+        },
+        {
+            "name": "drain_node",
+            "method": "PUT",
+            "path": "/drain",
+            "descr": "Drain this node, new connections are refused and the connected clients are disconnected"
+        },
+        {
+            "name": "get_maintenance",
+            "method": "GET",
+            "path": "/maintenance",
+            "descr": "Returns the maintenance state of this node, the peers in maintenance and the messages held for them"
+        },
+        {
+            "name": "enter_maintenance",
+            "method": "PUT",
+            "path": "/maintenance",
+            "descr": "Put this node in maintenance, it keeps serving its sessions but new connections are refused and it is not ready"
+        },
+        {
+            "name": "leave_maintenance",
+            "method": "DELETE",
+            "path": "/maintenance",
+            "descr": "Take this node out of maintenance, the peers resend the messages held for it"
+        },
+        {
+            "name": "redirect_clients",
+            "method": "PUT",
+            "path": "/redirect",
+            "descr": "Redirect the connected clients of this node to another server, MQTT 5.0 clients are disconnected with the reason code and the server reference"
+        },
+        {
+            "name": "get_log_levels",
+            "method": "GET",
+            "path": "/log/levels",
+            "descr": "Returns the log level overrides of the modules of this node"
+        },
+        {
+            "name": "set_log_level",
+            "method": "PUT",
+            "path": "/log/levels",
+            "descr": "Set the log level of a module or plugin of this node without restart, e.g. target=rmqtt::grpc&level=debug&expiry=10m, the level reverts after the expiry"
+        },
+        {
+            "name": "remove_log_level",
+            "method": "DELETE",
+            "path": "/log/levels",
+            "descr": "Remove the log level override of a module of this node, all if the target is not given"
+        },
+        {
+            "name": "get_message_types",
+            "method": "GET",
+            "path": "/grpc/message_types",
+            "descr": "Returns the gRPC message types claimed by the broker and the plugins on this node"
+        },
+        {
+            "name": "migrate_sessions",
+            "method": "PUT",
+            "path": "/sessions/migrate",
+            "descr": "Migrate the sessions of this node to another node"
+        },
+        {
+            "name": "export_sessions",
+            "method": "GET",
+            "path": "/sessions/export",
+            "descr": "Export the persistent sessions of the nodes of the cluster and their subscriptions, as JSON or CSV"
+        },
+        {
+            "name": "import_sessions",
+            "method": "POST",
+            "path": "/sessions/import",
+            "descr": "Create offline persistent sessions with their subscriptions on this node, from JSON or CSV"
+        },
+        {
+            "name": "get_listeners",
+            "method": "GET",
+            "path": "/listeners",
+            "descr": "Returns the active listeners of this node"
+        },
+        {
+            "name": "add_listener",
+            "method": "POST",
+            "path": "/listeners/{type}",
+            "descr": "Add a listener to this node, it binds immediately"
+        },
+        {
+            "name": "modify_listener",
+            "method": "PUT",
+            "path": "/listeners/{type}/{port}",
+            "descr": "Modify a listener of this node, it is restarted with the new config"
+        },
+        {
+            "name": "remove_listener",
+            "method": "DELETE",
+            "path": "/listeners/{type}/{port}",
+            "descr": "Remove a listener of this node, with drain=true the clients connected through it are disconnected"
+        },
         {
             "name": "search_clients",
             "method": "GET",
@@ -151,12 +332,42 @@ async fn list_apis(res: &mut Response) {
             "path": "/clients/{clientid}",
             "descr": "Kick client from the cluster"
         },
+        {
+            "name": "get_labels",
+            "method": "GET",
+            "path": "/labels",
+            "descr": "Returns the number of the connected sessions of each label in the cluster"
+        },
+        {
+            "name": "kick_label",
+            "method": "DELETE",
+            "path": "/labels/{label}",
+            "descr": "Kick the clients of the label from the cluster"
+        },
         {
             "name": "check_online",
             "method": "GET",
             "path": "/clients/{clientid}/online",
             "descr": "Check a client whether online from the cluster"
         },
+        {
+            "name": "get_client_session",
+            "method": "GET",
+            "path": "/clients/{clientid}/session",
+            "descr": "Dump the session state of a client from the cluster, including inflight messages and message queue"
+        },
+        {
+            "name": "purge_client_session",
+            "method": "DELETE",
+            "path": "/clients/{clientid}/session",
+            "descr": "Purge a stuck session from the cluster, the client is kicked, its routes, stored session and stored message marks are removed"
+        },
+        {
+            "name": "stream_clients",
+            "method": "GET",
+            "path": "/stream/clients",
+            "descr": "Stream the clients of the cluster as Server-Sent Events, a snapshot of each node, then the connected, disconnected, subscribed and unsubscribed events with sequence numbers"
+        },
 
         {
             "name": "query_subscriptions",
@@ -171,6 +382,12 @@ async fn list_apis(res: &mut Response) {
             "descr": "Get subscriptions information for the client from the cluster"
         },
 
+        {
+            "name": "get_topic_samples",
+            "method": "GET",
+            "path": "/topics/samples",
+            "descr": "Returns the samples of the top topics by publish rate and by subscribers of the cluster, within start and end (unix timestamps in seconds)"
+        },
         {
             "name": "get_routes",
             "method": "GET",
@@ -202,6 +419,18 @@ async fn list_apis(res: &mut Response) {
             "path": "/mqtt/unsubscribe",
             "descr": "Unsubscribe"
         },
+        {
+            "name": "ingest_publish",
+            "method": "POST",
+            "path": "/publish",
+            "descr": "Publish a JSON or raw message through the hook pipeline, with token auth and rate limiting"
+        },
+        {
+            "name": "ingest_publish_bulk",
+            "method": "POST",
+            "path": "/publish/bulk",
+            "descr": "Publish a JSON array of messages, returns the per-message results"
+        },
 
         {
             "name": "all_plugins",
@@ -227,6 +456,12 @@ async fn list_apis(res: &mut Response) {
             "path": "/plugins/{node}/{plugin}/config",
             "descr": "Get a plugin config"
         },
+        {
+            "name": "node_plugin_config_schema",
+            "method": "GET",
+            "path": "/plugins/{node}/{plugin}/config/schema",
+            "descr": "Get the JSON Schema of a plugin config"
+        },
         {
             "name": "node_plugin_config_reload",
             "method": "PUT",
@@ -276,7 +511,7 @@ async fn list_apis(res: &mut Response) {
     res.render(Json(data));
 }
 
-fn get_cfg(depot: &mut Depot) -> Result<&PluginConfigType, salvo::Error> {
+pub(crate) fn get_cfg(depot: &mut Depot) -> Result<&PluginConfigType, salvo::Error> {
     let cfg = depot.obtain::<PluginConfigType>().map_err(|e| match e {
         None => salvo::Error::Io(std::io::Error::new(ErrorKind::NotFound, anyhow!("None"))),
         Some(e) => salvo::Error::Io(std::io::Error::new(ErrorKind::NotFound, format!("{:?}", e))),
@@ -441,43 +676,512 @@ async fn _get_node(message_type: MessageType, id: NodeId) -> Result<Option<serde
             Ok(None)
         }
     }
-}
-
-#[inline]
-async fn _get_nodes(message_type: MessageType) -> Result<Vec<serde_json::Value>> {
-    let mut nodes = vec![Runtime::instance().node.node_info().await.to_json()];
-    let grpc_clients = Runtime::instance().extends.shared().await.get_grpc_clients();
-    if !grpc_clients.is_empty() {
-        let msg = Message::NodeInfo.encode()?;
-        let replys = MessageBroadcaster::new(grpc_clients, message_type, GrpcMessage::Data(msg))
-            .join_all()
-            .await
-            .drain(..)
-            .map(|reply| match reply {
-                (_, Ok(GrpcMessageReply::Data(msg))) => match MessageReply::decode(&msg) {
-                    Ok(MessageReply::NodeInfo(node_info)) => Ok(node_info.to_json()),
-                    Err(e) => Err(e),
-                    _ => unreachable!(),
-                },
-                (_, Ok(_)) => unreachable!(),
-                (id, Err(e)) => {
-                    log::warn!("Get GrpcMessage::NodeInfo from other node({}), error: {:?}", id, e);
-                    Ok(serde_json::Value::String(e.to_string()))
-                }
-            })
-            .collect::<Result<Vec<_>, _>>()?;
-        nodes.extend(replys);
+}
+
+#[inline]
+async fn _get_nodes(message_type: MessageType) -> Result<Vec<serde_json::Value>> {
+    let mut nodes = vec![Runtime::instance().node.node_info().await.to_json()];
+    let grpc_clients = Runtime::instance().extends.shared().await.get_grpc_clients();
+    if !grpc_clients.is_empty() {
+        let msg = Message::NodeInfo.encode()?;
+        let replys = MessageBroadcaster::new(grpc_clients, message_type, GrpcMessage::Data(msg))
+            .join_all()
+            .await
+            .drain(..)
+            .map(|reply| match reply {
+                (_, Ok(GrpcMessageReply::Data(msg))) => match MessageReply::decode(&msg) {
+                    Ok(MessageReply::NodeInfo(node_info)) => Ok(node_info.to_json()),
+                    Err(e) => Err(e),
+                    _ => unreachable!(),
+                },
+                (_, Ok(_)) => unreachable!(),
+                (id, Err(e)) => {
+                    log::warn!("Get GrpcMessage::NodeInfo from other node({}), error: {:?}", id, e);
+                    Ok(serde_json::Value::String(e.to_string()))
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        nodes.extend(replys);
+    }
+    Ok(nodes)
+}
+
+#[handler]
+async fn get_topic_samples(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> Result<(), salvo::Error> {
+    let cfg = get_cfg(depot)?;
+    let message_type = cfg.read().await.message_type;
+    let start = req.query::<i64>("start").map(|t| t * 1000);
+    let end = req.query::<i64>("end").map(|t| t * 1000);
+    match _get_topic_samples(message_type, start, end).await {
+        Ok(samples) => res.render(Json(samples)),
+        Err(e) => res.render(StatusError::service_unavailable().detail(e.to_string())),
+    }
+    Ok(())
+}
+
+#[inline]
+async fn _get_topic_samples(
+    message_type: MessageType,
+    start: Option<TimestampMillis>,
+    end: Option<TimestampMillis>,
+) -> Result<Vec<serde_json::Value>> {
+    let mut samples =
+        TopicSamples::instance().samples(start, end).iter().map(|s| s.to_json()).collect::<Vec<_>>();
+    let grpc_clients = Runtime::instance().extends.shared().await.get_grpc_clients();
+    if !grpc_clients.is_empty() {
+        let msg = Message::TopicSamples { start, end }.encode()?;
+        let replys = MessageBroadcaster::new(grpc_clients, message_type, GrpcMessage::Data(msg))
+            .join_all()
+            .await
+            .drain(..)
+            .map(|reply| match reply {
+                (_, Ok(GrpcMessageReply::Data(msg))) => match MessageReply::decode(&msg) {
+                    Ok(MessageReply::TopicSamples(samples)) => Ok(samples),
+                    Ok(_) => Err(MqttError::from("unexpected reply")),
+                    Err(e) => Err(e),
+                },
+                (_, Ok(GrpcMessageReply::Error(e))) => Err(MqttError::from(e)),
+                (_, Ok(_)) => Err(MqttError::from("unexpected reply")),
+                (id, Err(e)) => {
+                    log::warn!("Get GrpcMessage::TopicSamples from other node({}), error: {:?}", id, e);
+                    Ok(Vec::new())
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+        samples.extend(replys.into_iter().flatten().map(|s| s.to_json()));
+    }
+    Ok(samples)
+}
+
+#[handler]
+async fn check_health(_req: &mut Request, _depot: &mut Depot, res: &mut Response) {
+    match Runtime::instance().extends.shared().await.check_health().await {
+        Ok(Some(health_info)) => res.render(Json(health_info)),
+        Ok(None) => {
+            res.status_code(StatusCode::NOT_FOUND);
+        }
+        Err(e) => res.render(StatusError::service_unavailable().detail(e.to_string())),
+    }
+}
+
+#[handler]
+async fn get_alarms(req: &mut Request, depot: &mut Depot, res: &mut Response) -> Result<(), salvo::Error> {
+    let cfg = get_cfg(depot)?;
+    let message_type = cfg.read().await.message_type;
+    let activated = req.query::<bool>("activated").unwrap_or(true);
+    match _get_alarms(message_type, activated).await {
+        Ok(alarms) => res.render(Json(alarms)),
+        Err(e) => res.render(StatusError::service_unavailable().detail(e.to_string())),
+    }
+    Ok(())
+}
+
+///The active alarms of this node, or the deactivated ones
+#[inline]
+pub(crate) fn alarms(activated: bool) -> Vec<Alarm> {
+    if activated {
+        AlarmManager::instance().actives()
+    } else {
+        AlarmManager::instance()
+            .history()
+            .into_iter()
+            .filter(|e| e.event == AlarmEventKind::Deactivate)
+            .map(|e| e.alarm)
+            .collect()
+    }
+}
+
+#[inline]
+async fn _get_alarms(message_type: MessageType, activated: bool) -> Result<Vec<serde_json::Value>> {
+    let mut alarms = alarms(activated).iter().map(|a| a.to_json()).collect::<Vec<_>>();
+    let grpc_clients = Runtime::instance().extends.shared().await.get_grpc_clients();
+    if !grpc_clients.is_empty() {
+        let msg = Message::GetAlarms { activated }.encode()?;
+        let replys = MessageBroadcaster::new(grpc_clients, message_type, GrpcMessage::Data(msg))
+            .join_all()
+            .await
+            .drain(..)
+            .map(|reply| match reply {
+                (_, Ok(GrpcMessageReply::Data(msg))) => match MessageReply::decode(&msg) {
+                    Ok(MessageReply::GetAlarms(alarms)) => Ok(alarms),
+                    Ok(_) => Err(MqttError::from("unexpected reply")),
+                    Err(e) => Err(e),
+                },
+                (_, Ok(GrpcMessageReply::Error(e))) => Err(MqttError::from(e)),
+                (_, Ok(_)) => Err(MqttError::from("unexpected reply")),
+                (id, Err(e)) => {
+                    log::warn!("Get GrpcMessage::GetAlarms from other node({}), error: {:?}", id, e);
+                    Ok(Vec::new())
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+        alarms.extend(replys.into_iter().flatten().map(|a| a.to_json()));
+    }
+    Ok(alarms)
+}
+
+#[handler]
+async fn get_conformance(_req: &mut Request, _depot: &mut Depot, res: &mut Response) {
+    res.render(Json(Conformance::instance().to_json()));
+}
+
+#[handler]
+async fn check_consistency(_req: &mut Request, _depot: &mut Depot, res: &mut Response) {
+    res.render(Json(consistency(false, "http-api").await));
+}
+
+#[handler]
+async fn repair_consistency(_req: &mut Request, _depot: &mut Depot, res: &mut Response) {
+    res.render(Json(consistency(true, "http-api").await));
+}
+
+///Audits the nodes of the cluster, repairing the inconsistencies if set, the report of each node
+pub(crate) async fn consistency(repair: bool, by: &str) -> serde_json::Value {
+    let reports = Consistency::instance().check(repair).await;
+    let repaired = reports.iter().filter_map(|(_, r)| r.as_ref().ok()).map(|r| r.repaired).sum::<usize>();
+    if repaired > 0 {
+        AuditLog::instance().record(AuditEvent::ConsistencyRepaired { repaired, by: by.into() }).await;
+    }
+    let consistent = reports.iter().all(|(_, r)| r.as_ref().map(|r| r.is_consistent()).unwrap_or(false));
+    let nodes = reports
+        .into_iter()
+        .map(|(node_id, report)| match report {
+            Ok(report) => json!({"node_id": node_id, "report": report.to_json()}),
+            Err(e) => json!({"node_id": node_id, "error": e.to_string()}),
+        })
+        .collect::<Vec<_>>();
+    json!({ "consistent": consistent, "repair": repair, "nodes": nodes })
+}
+
+#[handler]
+async fn invalidate_acl_cache(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> Result<(), salvo::Error> {
+    let cfg = get_cfg(depot)?;
+    let message_type = cfg.read().await.message_type;
+    let clientid = req.query::<String>("clientid");
+    let topic = req.query::<String>("topic");
+    match _invalidate_acl_cache(message_type, clientid.as_deref(), topic.as_deref()).await {
+        Ok(removeds) => res.render(Json(json!({ "removeds": removeds }))),
+        Err(e) => res.render(StatusError::service_unavailable().detail(e.to_string())),
+    }
+    Ok(())
+}
+
+#[inline]
+async fn _invalidate_acl_cache(
+    message_type: MessageType,
+    clientid: Option<&str>,
+    topic: Option<&str>,
+) -> Result<usize> {
+    let mut removeds = AclCache::instance().invalidate(clientid, topic);
+    let grpc_clients = Runtime::instance().extends.shared().await.get_grpc_clients();
+    if !grpc_clients.is_empty() {
+        let msg = Message::InvalidateAclCache { clientid, topic }.encode()?;
+        for reply in
+            MessageBroadcaster::new(grpc_clients, message_type, GrpcMessage::Data(msg)).join_all().await
+        {
+            match reply {
+                (_, Ok(GrpcMessageReply::Data(msg))) => match MessageReply::decode(&msg)? {
+                    MessageReply::InvalidateAclCache(n) => removeds += n,
+                    _ => return Err(MqttError::from("unexpected reply")),
+                },
+                (_, Ok(GrpcMessageReply::Error(e))) => return Err(MqttError::from(e)),
+                (_, Ok(_)) => return Err(MqttError::from("unexpected reply")),
+                (id, Err(e)) => {
+                    log::warn!("Get GrpcMessage::InvalidateAclCache from other node({}), error: {:?}", id, e);
+                }
+            }
+        }
+    }
+    Ok(removeds)
+}
+
+#[handler]
+async fn drain_node(_req: &mut Request, _depot: &mut Depot, res: &mut Response) {
+    //Draining takes up to the grace period, the node status becomes Draining immediately
+    if !Runtime::instance().node.is_draining() {
+        tokio::spawn(Runtime::instance().node.drain());
+    }
+    res.render(Json(json!({
+        "node_id": Runtime::instance().node.id(),
+        "node_status": NodeStatus::Draining,
+    })));
+}
+
+#[handler]
+async fn get_maintenance(_req: &mut Request, res: &mut Response) {
+    res.render(Json(Maintenance::instance().to_json()));
+}
+
+#[handler]
+async fn enter_maintenance(_req: &mut Request, res: &mut Response) {
+    Maintenance::instance().set(true).await;
+    res.render(Json(json!({
+        "node_id": Runtime::instance().node.id(),
+        "node_status": Runtime::instance().node.status().await,
+    })));
+}
+
+#[handler]
+async fn leave_maintenance(_req: &mut Request, res: &mut Response) {
+    Maintenance::instance().set(false).await;
+    res.render(Json(json!({
+        "node_id": Runtime::instance().node.id(),
+        "node_status": Runtime::instance().node.status().await,
+    })));
+}
+
+#[handler]
+async fn redirect_clients(req: &mut Request, res: &mut Response) {
+    let params = match req.parse_json::<RedirectParams>().await {
+        Ok(p) => p,
+        Err(e) => {
+            res.render(StatusError::bad_request().detail(e.to_string()));
+            return;
+        }
+    };
+    let cfg = &Runtime::instance().settings.node.redirect;
+    let reason = params.reason.unwrap_or(cfg.reason);
+    let server_reference = match params.server_reference {
+        Some(server_reference) if !server_reference.is_empty() => Some(ByteString::from(server_reference)),
+        Some(_) => None,
+        None => cfg.server_reference(),
+    };
+    let redirecteds = Runtime::instance()
+        .node
+        .redirect(params.clientids.as_deref(), params.limit, reason, server_reference.clone())
+        .await;
+    res.render(Json(json!({
+        "node_id": Runtime::instance().node.id(),
+        "redirecteds": redirecteds,
+        "reason": reason,
+        "server_reference": server_reference,
+    })));
+}
+
+#[handler]
+async fn get_log_levels(_req: &mut Request, res: &mut Response) {
+    res.render(Json(json!({
+        "node_id": Runtime::instance().node.id(),
+        "level": Runtime::instance().settings.log.level.as_str().to_lowercase(),
+        "overrides": LogLevels::instance().overrides(),
+    })));
+}
+
+#[handler]
+async fn get_message_types(_req: &mut Request, res: &mut Response) {
+    res.render(Json(json!({
+        "node_id": Runtime::instance().node.id(),
+        "claims": MessageTypes::instance().claims(),
+    })));
+}
+
+#[handler]
+async fn set_log_level(req: &mut Request, res: &mut Response) {
+    let (target, level) = match (req.query::<String>("target"), req.query::<String>("level")) {
+        (Some(target), Some(level)) => (target, level),
+        _ => {
+            res.render(StatusError::bad_request().detail("target and level are required"));
+            return;
+        }
+    };
+    let expiry = req.query::<String>("expiry").map(|e| to_duration(&e)).filter(|e| !e.is_zero());
+    match LogLevels::instance().set(&target, &level, expiry) {
+        Ok(()) => res.render(Json(LogLevels::instance().overrides())),
+        Err(e) => res.render(StatusError::bad_request().detail(e.to_string())),
+    }
+}
+
+#[handler]
+async fn remove_log_level(req: &mut Request, res: &mut Response) {
+    let target = req.query::<String>("target");
+    LogLevels::instance().remove(target.as_deref());
+    res.render(Json(LogLevels::instance().overrides()));
+}
+
+#[handler]
+async fn migrate_sessions(req: &mut Request, res: &mut Response) {
+    let target_node = match req.query::<NodeId>("target_node") {
+        Some(target_node) => target_node,
+        None => {
+            res.render(StatusError::bad_request().detail("target_node is required"));
+            return;
+        }
+    };
+    let batch_size = req.query::<usize>("batch_size").unwrap_or(100);
+    match Runtime::instance().node.migrate_sessions(target_node, batch_size).await {
+        Ok(migrated) => res.render(Json(json!({
+            "node_id": Runtime::instance().node.id(),
+            "target_node": target_node,
+            "migrated": migrated,
+        }))),
+        Err(e) => res.render(StatusError::service_unavailable().detail(e.to_string())),
+    }
+}
+
+#[handler]
+async fn export_sessions(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> Result<(), salvo::Error> {
+    let cfg = get_cfg(depot)?;
+    let message_type = cfg.read().await.message_type;
+    let csv = req.query::<String>("format").map(|f| f.eq_ignore_ascii_case("csv")).unwrap_or_default();
+    match _export_sessions(message_type).await {
+        Ok(sessions) if csv => {
+            res.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("text/csv; charset=utf-8"));
+            res.write_body(provision::to_csv(&sessions)).ok();
+        }
+        Ok(sessions) => res.render(Json(sessions)),
+        Err(e) => res.render(StatusError::service_unavailable().detail(e.to_string())),
+    }
+    Ok(())
+}
+
+#[inline]
+async fn _export_sessions(message_type: MessageType) -> Result<Vec<ProvisionedSession>> {
+    let mut sessions = provision::export_local().await;
+    let grpc_clients = Runtime::instance().extends.shared().await.get_grpc_clients();
+    if !grpc_clients.is_empty() {
+        let msg = Message::ExportSessions.encode()?;
+        for reply in
+            MessageBroadcaster::new(grpc_clients, message_type, GrpcMessage::Data(msg)).join_all().await
+        {
+            match reply {
+                (_, Ok(GrpcMessageReply::Data(msg))) => match MessageReply::decode(&msg)? {
+                    MessageReply::ExportSessions(others) => sessions.extend(others),
+                    _ => return Err(MqttError::from("unexpected reply")),
+                },
+                (_, Ok(GrpcMessageReply::Error(e))) => return Err(MqttError::from(e)),
+                (_, Ok(_)) => return Err(MqttError::from("unexpected reply")),
+                (id, Err(e)) => return Err(MqttError::from(format!("node {} is unavailable, {:?}", id, e))),
+            }
+        }
+        sessions.sort_by(|a, b| a.clientid.cmp(&b.clientid));
+    }
+    Ok(sessions)
+}
+
+#[handler]
+async fn import_sessions(req: &mut Request, res: &mut Response) {
+    let port = req.query::<u16>("port");
+    let csv = req.query::<String>("format").map(|f| f.eq_ignore_ascii_case("csv")).unwrap_or_else(|| {
+        req.content_type().map(|m| m.subtype().as_str().eq_ignore_ascii_case("csv")).unwrap_or_default()
+    });
+    let sessions = if csv {
+        match req.payload().await {
+            Ok(body) => provision::from_csv(&String::from_utf8_lossy(body)).map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        }
+    } else {
+        req.parse_json::<Vec<ProvisionedSession>>().await.map_err(|e| e.to_string())
+    };
+    let sessions = match sessions {
+        Ok(sessions) => sessions,
+        Err(e) => {
+            res.render(StatusError::bad_request().detail(e));
+            return;
+        }
+    };
+    let results = provision::import(sessions, port).await;
+    let imported = results.iter().filter(|(_, r)| r.is_ok()).count();
+    let errors = results
+        .into_iter()
+        .filter_map(|(clientid, r)| r.err().map(|e| json!({"clientid": clientid, "error": e.to_string()})))
+        .collect::<Vec<_>>();
+    res.render(Json(json!({
+        "node_id": Runtime::instance().node.id(),
+        "imported": imported,
+        "errors": errors,
+    })));
+}
+
+#[inline]
+fn listener_type(req: &mut Request) -> std::result::Result<ListenerType, String> {
+    req.param::<String>("type").ok_or_else(|| "listener type is required".to_string())?.parse()
+}
+
+#[handler]
+async fn get_listeners(_req: &mut Request, res: &mut Response) {
+    res.render(Json(ListenerManager::instance().to_json()));
+}
+
+#[handler]
+async fn add_listener(req: &mut Request, res: &mut Response) {
+    let typ = match listener_type(req) {
+        Ok(typ) => typ,
+        Err(e) => {
+            res.render(StatusError::bad_request().detail(e));
+            return;
+        }
+    };
+    let inner = match req.parse_json::<ListenerInner>().await {
+        Ok(inner) => inner,
+        Err(e) => {
+            res.render(StatusError::bad_request().detail(e.to_string()));
+            return;
+        }
+    };
+    match ListenerManager::instance().add(typ, Listener::new(inner)).await {
+        Ok(()) => res.render(Json(ListenerManager::instance().to_json())),
+        Err(e) => res.render(StatusError::service_unavailable().detail(e.to_string())),
+    }
+}
+
+#[handler]
+async fn modify_listener(req: &mut Request, res: &mut Response) {
+    let typ = match listener_type(req) {
+        Ok(typ) => typ,
+        Err(e) => {
+            res.render(StatusError::bad_request().detail(e));
+            return;
+        }
+    };
+    let port = req.param::<u16>("port");
+    let inner = match req.parse_json::<ListenerInner>().await {
+        Ok(inner) => inner,
+        Err(e) => {
+            res.render(StatusError::bad_request().detail(e.to_string()));
+            return;
+        }
+    };
+    if port != Some(inner.addr.port()) {
+        res.render(StatusError::bad_request().detail("the port of addr differs from that of the path"));
+        return;
+    }
+    match ListenerManager::instance().modify(typ, Listener::new(inner)).await {
+        Ok(()) => res.render(Json(ListenerManager::instance().to_json())),
+        Err(e) => res.render(StatusError::service_unavailable().detail(e.to_string())),
     }
-    Ok(nodes)
 }
 
 #[handler]
-async fn check_health(_req: &mut Request, _depot: &mut Depot, res: &mut Response) {
-    match Runtime::instance().extends.shared().await.check_health().await {
-        Ok(Some(health_info)) => res.render(Json(health_info)),
-        Ok(None) => {
-            res.status_code(StatusCode::NOT_FOUND);
+async fn remove_listener(req: &mut Request, res: &mut Response) {
+    let typ = match listener_type(req) {
+        Ok(typ) => typ,
+        Err(e) => {
+            res.render(StatusError::bad_request().detail(e));
+            return;
         }
+    };
+    let port = match req.param::<u16>("port") {
+        Some(port) => port,
+        None => {
+            res.render(StatusError::bad_request().detail("port is required"));
+            return;
+        }
+    };
+    let drain = req.query::<bool>("drain").unwrap_or(false);
+    match ListenerManager::instance().remove(typ, port, drain).await {
+        Ok(()) => res.render(Json(ListenerManager::instance().to_json())),
         Err(e) => res.render(StatusError::service_unavailable().detail(e.to_string())),
     }
 }
@@ -591,6 +1295,17 @@ async fn _search_clients(
     Ok(replys)
 }
 
+#[handler]
+async fn stream_clients(depot: &mut Depot, res: &mut Response) -> Result<(), salvo::Error> {
+    let cfg = get_cfg(depot)?;
+    let (message_type, interval, limit) = {
+        let cfg = cfg.read().await;
+        (cfg.message_type, cfg.client_stream_interval, cfg.max_row_limit)
+    };
+    SseKeepAlive::new(client_events::stream(message_type, interval, limit)).stream(res);
+    Ok(())
+}
+
 #[handler]
 async fn kick_client(req: &mut Request, res: &mut Response) {
     let clientid = req.param::<String>("clientid");
@@ -606,13 +1321,129 @@ async fn kick_client(req: &mut Request, res: &mut Response) {
             Ok(None) => {
                 res.status_code(StatusCode::NOT_FOUND);
             }
-            Ok(Some(offline_info)) => res.render(Text::Plain(offline_info.id.to_string())),
+            Ok(Some(offline_info)) => {
+                AuditLog::instance()
+                    .record(AuditEvent::Kicked { id: offline_info.id.clone(), by: "http-api".into() })
+                    .await;
+                res.render(Text::Plain(offline_info.id.to_string()))
+            }
         }
     } else {
         res.render(StatusError::bad_request())
     }
 }
 
+#[handler]
+async fn purge_client_session(req: &mut Request, res: &mut Response) {
+    match req.param::<String>("clientid") {
+        Some(clientid) => res.render(Json(purge_session(&clientid, "http-api").await)),
+        None => res.render(StatusError::bad_request()),
+    }
+}
+
+///Purges the session of the client from all the nodes, the result of each node
+pub(crate) async fn purge_session(clientid: &str, by: &str) -> serde_json::Value {
+    let reports = Runtime::instance().node.purge_session(clientid).await;
+    AuditLog::instance()
+        .record(AuditEvent::SessionPurged { client_id: clientid.into(), by: by.into() })
+        .await;
+    let nodes = reports
+        .into_iter()
+        .map(|(node_id, report)| match report {
+            Ok(report) => json!({"node_id": node_id, "report": report}),
+            Err(e) => json!({"node_id": node_id, "error": e.to_string()}),
+        })
+        .collect::<Vec<_>>();
+    json!({ "clientid": clientid, "nodes": nodes })
+}
+
+#[handler]
+async fn get_labels(depot: &mut Depot, res: &mut Response) -> Result<(), salvo::Error> {
+    let cfg = get_cfg(depot)?;
+    let message_type = cfg.read().await.message_type;
+    match _get_labels(message_type).await {
+        Ok(labels) => res.render(Json(labels)),
+        Err(e) => res.render(StatusError::service_unavailable().detail(e.to_string())),
+    }
+    Ok(())
+}
+
+#[inline]
+async fn _get_labels(message_type: MessageType) -> Result<HashMap<String, usize>> {
+    let mut labels = Labels::instance().local_counts();
+    let grpc_clients = Runtime::instance().extends.shared().await.get_grpc_clients();
+    if !grpc_clients.is_empty() {
+        let msg = Message::LabelCounts.encode()?;
+        for reply in
+            MessageBroadcaster::new(grpc_clients, message_type, GrpcMessage::Data(msg)).join_all().await
+        {
+            match reply {
+                (_, Ok(GrpcMessageReply::Data(msg))) => match MessageReply::decode(&msg)? {
+                    MessageReply::LabelCounts(others) => {
+                        for (label, n) in others {
+                            *labels.entry(label).or_default() += n;
+                        }
+                    }
+                    _ => return Err(MqttError::from("unexpected reply")),
+                },
+                (_, Ok(GrpcMessageReply::Error(e))) => return Err(MqttError::from(e)),
+                (_, Ok(_)) => return Err(MqttError::from("unexpected reply")),
+                (id, Err(e)) => return Err(MqttError::from(format!("node {} is unavailable, {:?}", id, e))),
+            }
+        }
+    }
+    Ok(labels)
+}
+
+#[handler]
+async fn kick_label(req: &mut Request, depot: &mut Depot, res: &mut Response) -> Result<(), salvo::Error> {
+    let label = if let Some(label) = req.param::<String>("label") {
+        label
+    } else {
+        res.render(StatusError::bad_request());
+        return Ok(());
+    };
+    let cfg = get_cfg(depot)?;
+    let message_type = cfg.read().await.message_type;
+    match _kick_label(message_type, &label).await {
+        Ok(kickeds) => res.render(Json(json!({ "label": label, "kickeds": kickeds }))),
+        Err(e) => res.render(StatusError::service_unavailable().detail(e.to_string())),
+    }
+    Ok(())
+}
+
+#[inline]
+async fn _kick_label(message_type: MessageType, label: &str) -> Result<usize> {
+    let mut kickeds = kick_label_local(label, "http-api").await;
+    let grpc_clients = Runtime::instance().extends.shared().await.get_grpc_clients();
+    if !grpc_clients.is_empty() {
+        let msg = Message::KickLabel { label }.encode()?;
+        for reply in
+            MessageBroadcaster::new(grpc_clients, message_type, GrpcMessage::Data(msg)).join_all().await
+        {
+            match reply {
+                (_, Ok(GrpcMessageReply::Data(msg))) => match MessageReply::decode(&msg)? {
+                    MessageReply::KickLabel(n) => kickeds += n,
+                    _ => return Err(MqttError::from("unexpected reply")),
+                },
+                (_, Ok(GrpcMessageReply::Error(e))) => return Err(MqttError::from(e)),
+                (_, Ok(_)) => return Err(MqttError::from("unexpected reply")),
+                (id, Err(e)) => return Err(MqttError::from(format!("node {} is unavailable, {:?}", id, e))),
+            }
+        }
+    }
+    Ok(kickeds)
+}
+
+///Kicks the sessions of the label on this node, returns the number of the sessions kicked
+pub(crate) async fn kick_label_local(label: &str, by: &str) -> usize {
+    let kickeds = Labels::instance().kick_local(label).await;
+    for id in kickeds.iter() {
+        AuditLog::instance().record(AuditEvent::Kicked { id: id.clone(), by: by.into() }).await;
+    }
+    kickeds.len()
+}
+
 #[handler]
 async fn check_online(req: &mut Request, res: &mut Response) {
     let clientid = req.param::<String>("clientid");
@@ -630,6 +1461,149 @@ async fn check_online(req: &mut Request, res: &mut Response) {
     }
 }
 
+#[handler]
+async fn get_client_session(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> Result<(), salvo::Error> {
+    let cfg = get_cfg(depot)?;
+    let message_type = cfg.read().await.message_type;
+    let clientid = req.param::<String>("clientid");
+    if let Some(clientid) = clientid {
+        match _get_client_session(message_type, &clientid).await {
+            Ok(Some(reply)) => res.render(Json(reply)),
+            Ok(None) | Err(MqttError::None) => {
+                res.status_code(StatusCode::NOT_FOUND);
+            }
+            Err(e) => res.render(StatusError::service_unavailable().detail(e.to_string())),
+        }
+    } else {
+        res.render(StatusError::bad_request())
+    }
+    Ok(())
+}
+
+async fn _get_client_session(message_type: MessageType, clientid: &str) -> Result<Option<serde_json::Value>> {
+    if let Some(reply) = clients::dump(clientid).await {
+        return Ok(Some(reply));
+    }
+
+    let check_result = |reply: GrpcMessageReply| match reply {
+        GrpcMessageReply::Data(res) => match MessageReply::decode(&res) {
+            Ok(MessageReply::SessionDump(ress)) => match ress {
+                Some(res) => Ok(serde_json::from_slice::<serde_json::Value>(&res)?),
+                None => Err(MqttError::None),
+            },
+            Err(e) => Err(e),
+            _ => unreachable!(),
+        },
+        _ => unreachable!(),
+    };
+
+    let grpc_clients = Runtime::instance().extends.shared().await.get_grpc_clients();
+    if !grpc_clients.is_empty() {
+        let q = Message::SessionDump { clientid }.encode()?;
+        let reply = MessageBroadcaster::new(grpc_clients, message_type, GrpcMessage::Data(q))
+            .select_ok(check_result)
+            .await?;
+        return Ok(Some(reply));
+    }
+
+    Ok(None)
+}
+
+#[handler]
+async fn get_client_journal(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> Result<(), salvo::Error> {
+    let clientid = if let Some(clientid) = req.param::<String>("clientid") {
+        clientid
+    } else {
+        res.render(StatusError::bad_request());
+        return Ok(());
+    };
+    let cfg = get_cfg(depot)?;
+    let message_type = cfg.read().await.message_type;
+    match _get_client_journal(message_type, &clientid).await {
+        Ok(entries) => res.render(Json(entries)),
+        Err(e) => res.render(StatusError::service_unavailable().detail(e.to_string())),
+    }
+    Ok(())
+}
+
+//The journal events of the client on all the nodes, by time
+async fn _get_client_journal(message_type: MessageType, clientid: &str) -> Result<Vec<JournalEntry>> {
+    let mut entries = Journal::instance().get(clientid);
+    let grpc_clients = Runtime::instance().extends.shared().await.get_grpc_clients();
+    if !grpc_clients.is_empty() {
+        let msg = Message::ClientJournal { clientid }.encode()?;
+        for reply in
+            MessageBroadcaster::new(grpc_clients, message_type, GrpcMessage::Data(msg)).join_all().await
+        {
+            match reply {
+                (_, Ok(GrpcMessageReply::Data(msg))) => match MessageReply::decode(&msg)? {
+                    MessageReply::ClientJournal(others) => {
+                        entries.extend(serde_json::from_slice::<Vec<JournalEntry>>(&others)?)
+                    }
+                    _ => return Err(MqttError::from("unexpected reply")),
+                },
+                (_, Ok(GrpcMessageReply::Error(e))) => return Err(MqttError::from(e)),
+                (_, Ok(_)) => return Err(MqttError::from("unexpected reply")),
+                (id, Err(e)) => return Err(MqttError::from(format!("node {} is unavailable, {:?}", id, e))),
+            }
+        }
+    }
+    entries.sort_by_key(|entry| entry.time);
+    Ok(entries)
+}
+
+#[handler]
+async fn clear_client_journal(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> Result<(), salvo::Error> {
+    let clientid = if let Some(clientid) = req.param::<String>("clientid") {
+        clientid
+    } else {
+        res.render(StatusError::bad_request());
+        return Ok(());
+    };
+    let cfg = get_cfg(depot)?;
+    let message_type = cfg.read().await.message_type;
+    match _clear_client_journal(message_type, &clientid).await {
+        Ok(cleared) => res.render(Json(json!({ "clientid": clientid, "cleared": cleared }))),
+        Err(e) => res.render(StatusError::service_unavailable().detail(e.to_string())),
+    }
+    Ok(())
+}
+
+#[inline]
+async fn _clear_client_journal(message_type: MessageType, clientid: &str) -> Result<bool> {
+    let mut cleared = Journal::instance().clear(clientid);
+    let grpc_clients = Runtime::instance().extends.shared().await.get_grpc_clients();
+    if !grpc_clients.is_empty() {
+        let msg = Message::ClearClientJournal { clientid }.encode()?;
+        for reply in
+            MessageBroadcaster::new(grpc_clients, message_type, GrpcMessage::Data(msg)).join_all().await
+        {
+            match reply {
+                (_, Ok(GrpcMessageReply::Data(msg))) => match MessageReply::decode(&msg)? {
+                    MessageReply::ClearClientJournal(c) => cleared |= c,
+                    _ => return Err(MqttError::from("unexpected reply")),
+                },
+                (_, Ok(GrpcMessageReply::Error(e))) => return Err(MqttError::from(e)),
+                (_, Ok(_)) => return Err(MqttError::from("unexpected reply")),
+                (id, Err(e)) => return Err(MqttError::from(format!("node {} is unavailable, {:?}", id, e))),
+            }
+        }
+    }
+    Ok(cleared)
+}
+
 #[handler]
 async fn query_subscriptions(
     req: &mut Request,
@@ -813,13 +1787,17 @@ async fn _publish(
 
         let fut = async move {
             //hook, message_publish
-            let p1 = Runtime::instance()
+            let p1 = match Runtime::instance()
                 .extends
                 .hook_mgr()
                 .await
                 .message_publish(None, from.clone(), &p1)
                 .await
-                .unwrap_or(p1);
+            {
+                Ok(modified) => modified.unwrap_or(p1),
+                //dropped by a handler
+                Err(_) => return,
+            };
 
             if let Err(e) = SessionState::forwards(
                 from,
@@ -1180,6 +2158,61 @@ async fn _node_plugin_config(node_id: NodeId, name: &str, message_type: MessageT
     Ok(plugin_cfg)
 }
 
+#[handler]
+async fn node_plugin_config_schema(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> Result<(), salvo::Error> {
+    let cfg = get_cfg(depot)?;
+    let message_type = cfg.read().await.message_type;
+    let node_id = if let Some(node_id) = req.param::<NodeId>("node") {
+        node_id
+    } else {
+        res.status_code(StatusCode::NOT_FOUND);
+        return Ok(());
+    };
+    let name = if let Some(name) = req.param::<String>("plugin") {
+        name
+    } else {
+        res.status_code(StatusCode::NOT_FOUND);
+        return Ok(());
+    };
+
+    match _node_plugin_config_schema(node_id, &name, message_type).await {
+        Ok(Some(schema)) => {
+            res.headers_mut()
+                .insert(CONTENT_TYPE, HeaderValue::from_static("application/json; charset=utf-8"));
+            res.write_body(schema).ok();
+        }
+        Ok(None) => res.status_code(StatusCode::NOT_FOUND),
+        Err(e) => res.render(StatusError::service_unavailable().detail(e.to_string())),
+    }
+    Ok(())
+}
+
+async fn _node_plugin_config_schema(
+    node_id: NodeId,
+    name: &str,
+    message_type: MessageType,
+) -> Result<Option<Vec<u8>>> {
+    let schema = if node_id == Runtime::instance().node.id() {
+        plugin::get_plugin_config_schema(name).await?
+    } else {
+        let c = get_grpc_client(node_id).await?;
+        let msg = Message::GetPluginConfigSchema { name }.encode()?;
+        let reply = MessageSender::new(c, message_type, GrpcMessage::Data(msg)).send().await?;
+        match reply {
+            GrpcMessageReply::Data(msg) => match MessageReply::decode(&msg)? {
+                MessageReply::GetPluginConfigSchema(schema) => schema,
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        }
+    };
+    Ok(schema)
+}
+
 #[handler]
 async fn node_plugin_config_reload(
     req: &mut Request,
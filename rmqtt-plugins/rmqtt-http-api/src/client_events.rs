@@ -0,0 +1,279 @@
+//! Client change notifications. The lifecycle events of the clients of this node are kept in a
+//! bounded buffer with sequence numbers. GET /api/v1/stream/clients streams a snapshot of the clients
+//! of each node of the cluster, then their events, the nodes are polled for the events after the last
+//! sequence number. A node whose events were evicted from its buffer, or restarted, is snapshotted again.
+
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use salvo::sse::SseEvent;
+
+use rmqtt::{
+    async_trait::async_trait,
+    futures::{self, Stream},
+    log,
+    once_cell::sync::Lazy,
+    rust_box::std_ext::RwLock,
+    serde_json::{self, json},
+    timestamp_millis, tokio,
+};
+use rmqtt::{
+    broker::hook::{Handler, HookResult, Parameter, ReturnType},
+    grpc::{
+        client::NodeGrpcClient, Message as GrpcMessage, MessageReply as GrpcMessageReply, MessageSender,
+        MessageType,
+    },
+    Id, MqttError, NodeId, Result, Runtime, TimestampMillis,
+};
+
+use super::clients;
+use super::types::{ClientSearchParams, ClientSearchResult, Message, MessageReply};
+
+static EVENTS: Lazy<ClientEvents> = Lazy::new(ClientEvents::default);
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientEventKind {
+    Connected,
+    Disconnected,
+    Subscribed,
+    Unsubscribed,
+}
+
+impl ClientEventKind {
+    #[inline]
+    fn as_str(&self) -> &'static str {
+        match self {
+            ClientEventKind::Connected => "connected",
+            ClientEventKind::Disconnected => "disconnected",
+            ClientEventKind::Subscribed => "subscribed",
+            ClientEventKind::Unsubscribed => "unsubscribed",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ClientEvent {
+    pub seq: u64,
+    pub node_id: NodeId,
+    pub event: ClientEventKind,
+    pub clientid: String,
+    pub username: Option<String>,
+    pub ip_address: Option<String>,
+    pub topic_filter: Option<String>,
+    pub reason: Option<String>,
+    pub time: TimestampMillis,
+}
+
+#[derive(Default)]
+pub(crate) struct ClientEvents {
+    max: AtomicUsize,
+    //last sequence number, and the recent events
+    events: RwLock<(u64, VecDeque<ClientEvent>)>,
+}
+
+impl ClientEvents {
+    #[inline]
+    pub(crate) fn instance() -> &'static ClientEvents {
+        &EVENTS
+    }
+
+    #[inline]
+    pub(crate) fn set_max(&self, max: usize) {
+        self.max.store(max.max(1), Ordering::SeqCst);
+    }
+
+    fn push(&self, event: ClientEventKind, id: &Id, topic_filter: Option<String>, reason: Option<String>) {
+        let max = self.max.load(Ordering::SeqCst);
+        let mut events = self.events.write();
+        events.0 += 1;
+        let ev = ClientEvent {
+            seq: events.0,
+            node_id: id.node_id,
+            event,
+            clientid: id.client_id.to_string(),
+            username: id.username.as_ref().map(|u| u.to_string()),
+            ip_address: id.remote_addr.map(|addr| addr.ip().to_string()),
+            topic_filter,
+            reason,
+            time: timestamp_millis(),
+        };
+        events.1.push_back(ev);
+        while events.1.len() > max {
+            events.1.pop_front();
+        }
+    }
+
+    #[inline]
+    pub(crate) fn seq(&self) -> u64 {
+        self.events.read().0
+    }
+
+    ///The events after the sequence number, none if some of them were evicted or the sequence
+    ///number is unknown (the node restarted)
+    pub(crate) fn after(&self, seq: u64, limit: usize) -> Option<Vec<ClientEvent>> {
+        let events = self.events.read();
+        let (last, events) = (events.0, &events.1);
+        if seq > last {
+            return None;
+        }
+        let first = events.front().map(|ev| ev.seq).unwrap_or(last + 1);
+        if seq + 1 < first {
+            return None;
+        }
+        Some(events.iter().skip((seq + 1 - first) as usize).take(limit).cloned().collect())
+    }
+}
+
+///The clients of this node, and the sequence number of the last event before
+pub(crate) async fn snapshot(limit: usize) -> (u64, Vec<ClientSearchResult>) {
+    let seq = ClientEvents::instance().seq();
+    let q = ClientSearchParams { _limit: limit, ..Default::default() };
+    (seq, clients::search(&q).await)
+}
+
+pub(crate) struct ClientEventsHandler;
+
+#[async_trait]
+impl Handler for ClientEventsHandler {
+    async fn hook(&self, param: &Parameter, _acc: &mut Option<HookResult>) -> ReturnType {
+        let events = ClientEvents::instance();
+        match param {
+            Parameter::ClientConnected(s) => events.push(ClientEventKind::Connected, &s.id, None, None),
+            Parameter::ClientDisconnected(s, reason) => {
+                events.push(ClientEventKind::Disconnected, &s.id, None, Some(reason.to_string()))
+            }
+            Parameter::SessionSubscribed(s, sub) => {
+                events.push(ClientEventKind::Subscribed, &s.id, Some(sub.topic_filter.to_string()), None)
+            }
+            Parameter::SessionUnsubscribed(s, unsub) => {
+                events.push(ClientEventKind::Unsubscribed, &s.id, Some(unsub.topic_filter.to_string()), None)
+            }
+            _ => {
+                log::error!("unimplemented, {:?}", param)
+            }
+        }
+        true
+    }
+}
+
+struct ClientStream {
+    message_type: MessageType,
+    interval: Duration,
+    limit: usize,
+    //node id => last sequence number sent
+    cursors: HashMap<NodeId, u64>,
+    pending: VecDeque<SseEvent>,
+    polled: bool,
+}
+
+impl ClientStream {
+    async fn poll(&mut self) {
+        let node_id = Runtime::instance().node.id();
+        self.poll_node(node_id, None).await;
+        let grpc_clients = Runtime::instance().extends.shared().await.get_grpc_clients();
+        for (id, (_addr, c)) in grpc_clients.iter() {
+            self.poll_node(*id, Some(c)).await;
+        }
+    }
+
+    async fn poll_node(&mut self, node_id: NodeId, c: Option<&NodeGrpcClient>) {
+        let events = match self.cursors.get(&node_id) {
+            Some(seq) => match self.events(*seq, c).await {
+                Ok(events) => events,
+                Err(e) => {
+                    log::debug!("client events of node {}, error: {:?}", node_id, e);
+                    return;
+                }
+            },
+            None => None,
+        };
+        match events {
+            Some(events) => {
+                for ev in events {
+                    self.cursors.insert(node_id, ev.seq);
+                    let data = serde_json::to_string(&ev).unwrap_or_default();
+                    let ev = SseEvent::default()
+                        .name(ev.event.as_str())
+                        .id(format!("{}:{}", node_id, ev.seq))
+                        .text(data);
+                    self.pending.push_back(ev);
+                }
+            }
+            None => match self.snapshot(c).await {
+                Ok((seq, clients)) => {
+                    let resync = self.cursors.insert(node_id, seq).is_some();
+                    let data = json!({
+                        "node_id": node_id,
+                        "seq": seq,
+                        "resync": resync,
+                        "clients": clients.iter().map(|c| c.to_json()).collect::<Vec<_>>(),
+                    });
+                    let ev = SseEvent::default()
+                        .name("snapshot")
+                        .id(format!("{}:{}", node_id, seq))
+                        .text(data.to_string());
+                    self.pending.push_back(ev);
+                }
+                Err(e) => {
+                    log::debug!("client snapshot of node {}, error: {:?}", node_id, e);
+                }
+            },
+        }
+    }
+
+    async fn snapshot(&self, c: Option<&NodeGrpcClient>) -> Result<(u64, Vec<ClientSearchResult>)> {
+        let c = if let Some(c) = c { c } else { return Ok(snapshot(self.limit).await) };
+        match self.send(c, Message::ClientSnapshot { limit: self.limit }).await? {
+            MessageReply::ClientSnapshot(seq, clients) => Ok((seq, clients)),
+            _ => Err(MqttError::from("unexpected reply")),
+        }
+    }
+
+    async fn events(&self, seq: u64, c: Option<&NodeGrpcClient>) -> Result<Option<Vec<ClientEvent>>> {
+        let c = if let Some(c) = c { c } else { return Ok(ClientEvents::instance().after(seq, self.limit)) };
+        match self.send(c, Message::ClientEvents { after: seq, limit: self.limit }).await? {
+            MessageReply::ClientEvents(events) => Ok(events),
+            _ => Err(MqttError::from("unexpected reply")),
+        }
+    }
+
+    async fn send(&self, c: &NodeGrpcClient, msg: Message<'_>) -> Result<MessageReply> {
+        let msg = GrpcMessage::Data(msg.encode()?);
+        match MessageSender::new(c.clone(), self.message_type, msg).send().await? {
+            GrpcMessageReply::Data(res) => MessageReply::decode(&res),
+            GrpcMessageReply::Error(e) => Err(MqttError::from(e)),
+            _ => Err(MqttError::from("unexpected reply")),
+        }
+    }
+}
+
+///The snapshots of the nodes, then the events of their clients
+pub(crate) fn stream(
+    message_type: MessageType,
+    interval: Duration,
+    limit: usize,
+) -> impl Stream<Item = std::result::Result<SseEvent, Infallible>> + Send + 'static {
+    let s = ClientStream {
+        message_type,
+        interval,
+        limit,
+        cursors: HashMap::default(),
+        pending: VecDeque::new(),
+        polled: false,
+    };
+    futures::stream::unfold(s, |mut s| async move {
+        loop {
+            if let Some(ev) = s.pending.pop_front() {
+                return Some((Ok(ev), s));
+            }
+            if s.polled {
+                tokio::time::sleep(s.interval).await;
+            }
+            s.poll().await;
+            s.polled = true;
+        }
+    })
+}
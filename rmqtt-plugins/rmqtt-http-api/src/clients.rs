@@ -1,7 +1,11 @@
 use rmqtt::{
-    broker::Entry, log, tokio, ClientId, ConnectInfo, Id, Result, Runtime, Session, TimestampMillis,
+    broker::Entry, log, timestamp_millis, tokio, ClientId, ConnectInfo, Id, QoSEx, Result, Runtime, Session,
+    TimestampMillis,
+};
+use rmqtt::{
+    chrono, futures,
+    serde_json::{self, json},
 };
-use rmqtt::{chrono, futures, serde_json};
 use std::sync::Arc;
 
 use super::types::{ClientSearchParams as SearchParams, ClientSearchResult as SearchResult};
@@ -17,6 +21,89 @@ pub(crate) async fn get(clientid: &str) -> Option<SearchResult> {
     Some(build_result(Some(s)).await)
 }
 
+///Session state of the client, for debugging stuck QoS 1/2 flows
+pub(crate) async fn dump(clientid: &str) -> Option<serde_json::Value> {
+    let shared = Runtime::instance().extends.shared().await;
+    if !shared.exist(clientid) {
+        return None;
+    }
+    let id = Id::from(Runtime::instance().node.id(), ClientId::from(clientid));
+    let s = shared.entry(id).session()?;
+    let now = timestamp_millis();
+
+    let subscriptions = if let Ok(subs) = s.subscriptions().await {
+        subs.read()
+            .await
+            .iter()
+            .map(|(tf, opts)| json!({"topic_filter": tf, "opts": opts.to_json()}))
+            .collect::<Vec<_>>()
+    } else {
+        Vec::new()
+    };
+
+    let inflight_win = s.inflight_win().read().await;
+    let (retransmissions, exhausteds) = (inflight_win.retransmissions(), inflight_win.exhausteds());
+    let (quota, ack_latency) = (inflight_win.quota(), inflight_win.ack_latency());
+    let inflights = inflight_win
+        .iter()
+        .map(|(packet_id, m)| {
+            m.from.id.to_from_json(json!({
+                "packet_id": packet_id,
+                "status": m.status,
+                "update_time": m.update_time,
+                "dup": m.publish.dup(),
+                "qos": m.publish.qos().value(),
+                "retain": m.publish.retain(),
+                "topic": m.publish.topic(),
+                "payload_len": m.publish.payload().len(),
+                "create_time": m.publish.create_time(),
+            }))
+        })
+        .collect::<Vec<_>>();
+    drop(inflight_win);
+
+    let oldest_create_time = s.deliver_queue().peek(|(_, p)| p.create_time());
+    let connect_info = s.connect_info().await.ok();
+    Some(json!({
+        "node_id": s.id.node_id,
+        "clientid": s.id.client_id,
+        "connected": s.connected().await.unwrap_or_default(),
+        "created_at": s.created_at().await.unwrap_or_default(),
+        "connected_at": s.connected_at().await.unwrap_or_default(),
+        "disconnected_at": s.disconnected_at().await.unwrap_or_default(),
+        "session_present": s.session_present().await.unwrap_or_default(),
+        "labels": s.labels(),
+        "connect_info": connect_info.as_ref().map(|c| c.to_json()),
+        "auth": {
+            "username": s.id.username_ref(),
+            "superuser": s.superuser().await.unwrap_or_default(),
+            "anonymous": s.anonymous(),
+            //No expiry is kept for the credentials, they are valid until the session expires
+            "session_expiry_interval": s
+                .fitter
+                .session_expiry_interval(s.disconnect().await.unwrap_or_default().as_ref())
+                .as_secs(),
+        },
+        "subscriptions": subscriptions,
+        "inflight": {
+            "len": inflights.len(),
+            "max": s.listen_cfg().max_inflight.get(),
+            "quota": quota,
+            "ack_latency": ack_latency,
+            "retransmissions": retransmissions,
+            "retries_exhausted": exhausteds,
+            "messages": inflights,
+        },
+        "mqueue": {
+            "len": s.deliver_queue().len(),
+            "max": s.listen_cfg().max_mqueue_len,
+            "oldest_create_time": oldest_create_time,
+            "oldest_age": oldest_create_time.map(|t| now - t),
+        },
+        "extra_attrs": s.extra_attrs.read().await.len(),
+    }))
+}
+
 pub(crate) async fn search(q: &SearchParams) -> Vec<SearchResult> {
     let limit = q._limit;
     let mut curr: usize = 0;
@@ -76,6 +163,8 @@ async fn build_result(s: Option<Session>) -> SearchResult {
         clientid: id.client_id.clone(),
         username: id.username(),
         superuser: s.superuser().await.unwrap_or_default(),
+        anonymous: s.anonymous(),
+        labels: s.labels(),
         proto_ver: protocol,
         ip_address: id.remote_addr.map(|addr| addr.ip().to_string()),
         port: id.remote_addr.map(|addr| addr.port()),
@@ -150,6 +239,18 @@ async fn _filtering(q: &SearchParams, entry: &dyn Entry) -> Result<bool> {
         }
     }
 
+    if let Some(anonymous) = &q.anonymous {
+        if *anonymous != s.anonymous() {
+            return Ok(false);
+        }
+    }
+
+    if let Some(label) = &q.label {
+        if !s.has_label(label) {
+            return Ok(false);
+        }
+    }
+
     if let Some(session_present) = &q.session_present {
         if *session_present != s.session_present().await.unwrap_or_default() {
             return Ok(false);
@@ -1,11 +1,17 @@
 use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
 use std::time::Duration;
 
+use serde::de::{self, Deserialize, Deserializer};
+use serde::ser::{self, Serialize};
+
 use rmqtt::serde_json;
 use rmqtt::{
+    broker::topic::TopicTree,
     grpc::MessageType,
     settings::{deserialize_addr, deserialize_duration},
-    Result,
+    ClientId, Result, Topic, UserName,
 };
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -48,6 +54,25 @@ pub struct PluginConfig {
         deserialize_with = "deserialize_duration"
     )]
     pub message_expiry_interval: Duration,
+
+    ///Publish endpoints for server-side applications, /api/v1/publish and /api/v1/publish/bulk
+    #[serde(default)]
+    pub ingest: IngestConfig,
+
+    ///Maximum number of the client events kept for /api/v1/stream/clients, per node
+    #[serde(default = "PluginConfig::client_events_max_default")]
+    pub client_events_max: usize,
+
+    ///Interval of polling the nodes for the client events
+    #[serde(
+        default = "PluginConfig::client_stream_interval_default",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub client_stream_interval: Duration,
+
+    ///Samples of the top topics by publish rate and by subscribers, /api/v1/topics/samples
+    #[serde(default)]
+    pub topic_sampling: TopicSamplingConfig,
 }
 
 impl PluginConfig {
@@ -106,6 +131,16 @@ impl PluginConfig {
         Duration::from_secs(300)
     }
 
+    #[inline]
+    fn client_events_max_default() -> usize {
+        10_000
+    }
+
+    #[inline]
+    fn client_stream_interval_default() -> Duration {
+        Duration::from_secs(1)
+    }
+
     #[inline]
     pub fn to_json(&self) -> Result<serde_json::Value> {
         Ok(serde_json::to_value(self)?)
@@ -118,6 +153,9 @@ impl PluginConfig {
             || self.http_laddr != other.http_laddr
             || self.metrics_sample_interval != other.metrics_sample_interval
             || self.http_request_log != other.http_request_log
+            || self.client_events_max != other.client_events_max
+            || self.client_stream_interval != other.client_stream_interval
+            || self.topic_sampling != other.topic_sampling
     }
 
     #[inline]
@@ -125,3 +163,155 @@ impl PluginConfig {
         self.workers != other.workers || self.http_laddr != other.http_laddr
     }
 }
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct TopicSamplingConfig {
+    #[serde(default)]
+    pub enable: bool,
+    #[serde(default = "TopicSamplingConfig::interval_default", deserialize_with = "deserialize_duration")]
+    pub interval: Duration,
+    ///Number of the topics of each top list of a sample
+    #[serde(default = "TopicSamplingConfig::top_default")]
+    pub top: usize,
+    ///Maximum number of the samples kept, the oldest are dropped
+    #[serde(default = "TopicSamplingConfig::max_samples_default")]
+    pub max_samples: usize,
+    ///Maximum number of the topics counted between two samples, the others are not counted
+    #[serde(default = "TopicSamplingConfig::max_topics_default")]
+    pub max_topics: usize,
+    ///File persisting the samples, in memory only if not set
+    #[serde(default)]
+    pub file: Option<String>,
+}
+
+impl Default for TopicSamplingConfig {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            interval: Self::interval_default(),
+            top: Self::top_default(),
+            max_samples: Self::max_samples_default(),
+            max_topics: Self::max_topics_default(),
+            file: None,
+        }
+    }
+}
+
+impl TopicSamplingConfig {
+    #[inline]
+    fn interval_default() -> Duration {
+        Duration::from_secs(60)
+    }
+
+    #[inline]
+    fn top_default() -> usize {
+        10
+    }
+
+    #[inline]
+    fn max_samples_default() -> usize {
+        1440
+    }
+
+    #[inline]
+    fn max_topics_default() -> usize {
+        100_000
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct IngestConfig {
+    ///Bearer tokens, requests without a valid token are rejected. No tokens, no authentication
+    #[serde(default)]
+    pub tokens: Vec<IngestToken>,
+    ///Messages per second per token (or per remote IP if there are no tokens), 0 is unlimited
+    #[serde(default)]
+    pub rate_limit: u32,
+    #[serde(default = "IngestConfig::rate_burst_default")]
+    pub rate_burst: u32,
+    ///Maximum number of messages in a bulk request
+    #[serde(default = "IngestConfig::max_bulk_default")]
+    pub max_bulk: usize,
+}
+
+impl Default for IngestConfig {
+    fn default() -> Self {
+        Self {
+            tokens: Vec::new(),
+            rate_limit: 0,
+            rate_burst: Self::rate_burst_default(),
+            max_bulk: Self::max_bulk_default(),
+        }
+    }
+}
+
+impl IngestConfig {
+    #[inline]
+    fn rate_burst_default() -> u32 {
+        100
+    }
+
+    #[inline]
+    fn max_bulk_default() -> usize {
+        1000
+    }
+
+    #[inline]
+    pub fn token(&self, token: &str) -> Option<&IngestToken> {
+        self.tokens.iter().find(|t| t.token == token)
+    }
+}
+
+type TopicsType = (Arc<TopicTree<()>>, Vec<String>);
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct IngestToken {
+    pub token: String,
+    ///Client identifier of the published messages
+    #[serde(default = "IngestToken::clientid_default")]
+    pub clientid: ClientId,
+    pub username: Option<UserName>,
+    ///Topic filters the token can publish to, empty means all topics
+    #[serde(
+        default = "IngestToken::topics_default",
+        deserialize_with = "IngestToken::deserialize_topics",
+        serialize_with = "IngestToken::serialize_topics"
+    )]
+    pub topics: TopicsType,
+    ///Overrides the rate limit of the token
+    pub rate_limit: Option<u32>,
+}
+
+impl IngestToken {
+    fn clientid_default() -> ClientId {
+        "system".into()
+    }
+
+    fn topics_default() -> TopicsType {
+        (Arc::new(TopicTree::default()), Vec::new())
+    }
+
+    #[inline]
+    pub fn is_allowed(&self, topic: &Topic) -> bool {
+        self.topics.1.is_empty() || self.topics.0.is_match(topic)
+    }
+
+    fn serialize_topics<S>(topics: &TopicsType, s: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: ser::Serializer,
+    {
+        topics.1.serialize(s)
+    }
+
+    fn deserialize_topics<'de, D>(deserializer: D) -> std::result::Result<TopicsType, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let topics_cfg: Vec<String> = Vec::deserialize(deserializer)?;
+        let mut topics = TopicTree::default();
+        for topic in topics_cfg.iter() {
+            topics.insert(&Topic::from_str(topic).map_err(|e| de::Error::custom(format!("{:?}", e)))?, ());
+        }
+        Ok((Arc::new(topics), topics_cfg))
+    }
+}
@@ -1,13 +1,20 @@
-use rmqtt::{async_trait::async_trait, log};
+use rmqtt::{async_trait::async_trait, log, serde_json};
 use rmqtt::{
+    broker::acl_cache::AclCache,
     broker::hook::{Handler, HookResult, Parameter, ReturnType},
+    broker::journal::Journal,
+    broker::labels::Labels,
+    broker::provision,
     grpc::{Message as GrpcMessage, MessageReply as GrpcMessageReply, MessageType},
-    Runtime,
+    MqttError, Runtime,
 };
 
+use super::api::{alarms, kick_label_local};
+use super::client_events::{self, ClientEvents};
 use super::clients;
 use super::plugin;
 use super::subs;
+use super::topic_samples::TopicSamples;
 use super::types::{Message, MessageReply};
 
 pub(crate) struct HookHandler {
@@ -22,12 +29,12 @@ impl HookHandler {
 
 #[async_trait]
 impl Handler for HookHandler {
-    async fn hook(&self, param: &Parameter, acc: Option<HookResult>) -> ReturnType {
+    async fn hook(&self, param: &Parameter, acc: &mut Option<HookResult>) -> ReturnType {
         match param {
             Parameter::GrpcMessageReceived(typ, msg) => {
                 log::debug!("GrpcMessageReceived, type: {}, msg: {:?}", typ, msg);
                 if self.message_type != *typ {
-                    return (true, acc);
+                    return true;
                 }
                 match msg {
                     GrpcMessage::Data(data) => {
@@ -101,6 +108,127 @@ impl Handler for HookHandler {
                                     ))),
                                 }
                             }
+                            Ok(Message::ClientSnapshot { limit }) => {
+                                let (seq, clients) = client_events::snapshot(limit).await;
+                                match MessageReply::ClientSnapshot(seq, clients).encode() {
+                                    Ok(ress) => {
+                                        HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Data(ress)))
+                                    }
+                                    Err(e) => HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Error(
+                                        e.to_string(),
+                                    ))),
+                                }
+                            }
+                            Ok(Message::GetAlarms { activated }) => {
+                                match MessageReply::GetAlarms(alarms(activated)).encode() {
+                                    Ok(ress) => {
+                                        HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Data(ress)))
+                                    }
+                                    Err(e) => HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Error(
+                                        e.to_string(),
+                                    ))),
+                                }
+                            }
+                            Ok(Message::InvalidateAclCache { clientid, topic }) => {
+                                let removeds = AclCache::instance().invalidate(clientid, topic);
+                                match MessageReply::InvalidateAclCache(removeds).encode() {
+                                    Ok(ress) => {
+                                        HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Data(ress)))
+                                    }
+                                    Err(e) => HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Error(
+                                        e.to_string(),
+                                    ))),
+                                }
+                            }
+                            Ok(Message::ExportSessions) => {
+                                match MessageReply::ExportSessions(provision::export_local().await).encode() {
+                                    Ok(ress) => {
+                                        HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Data(ress)))
+                                    }
+                                    Err(e) => HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Error(
+                                        e.to_string(),
+                                    ))),
+                                }
+                            }
+                            Ok(Message::LabelCounts) => {
+                                match MessageReply::LabelCounts(Labels::instance().local_counts()).encode() {
+                                    Ok(ress) => {
+                                        HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Data(ress)))
+                                    }
+                                    Err(e) => HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Error(
+                                        e.to_string(),
+                                    ))),
+                                }
+                            }
+                            Ok(Message::ClientJournal { clientid }) => {
+                                let entries = Journal::instance().get(clientid);
+                                match serde_json::to_vec(&entries)
+                                    .map_err(|e| MqttError::from(e.to_string()))
+                                    .and_then(|entries| MessageReply::ClientJournal(entries).encode())
+                                {
+                                    Ok(ress) => {
+                                        HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Data(ress)))
+                                    }
+                                    Err(e) => HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Error(
+                                        e.to_string(),
+                                    ))),
+                                }
+                            }
+                            Ok(Message::ClearClientJournal { clientid }) => {
+                                let cleared = Journal::instance().clear(clientid);
+                                match MessageReply::ClearClientJournal(cleared).encode() {
+                                    Ok(ress) => {
+                                        HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Data(ress)))
+                                    }
+                                    Err(e) => HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Error(
+                                        e.to_string(),
+                                    ))),
+                                }
+                            }
+                            Ok(Message::KickLabel { label }) => {
+                                let kickeds = kick_label_local(label, "http-api").await;
+                                match MessageReply::KickLabel(kickeds).encode() {
+                                    Ok(ress) => {
+                                        HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Data(ress)))
+                                    }
+                                    Err(e) => HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Error(
+                                        e.to_string(),
+                                    ))),
+                                }
+                            }
+                            Ok(Message::TopicSamples { start, end }) => {
+                                let samples = TopicSamples::instance().samples(start, end);
+                                match MessageReply::TopicSamples(samples).encode() {
+                                    Ok(ress) => {
+                                        HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Data(ress)))
+                                    }
+                                    Err(e) => HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Error(
+                                        e.to_string(),
+                                    ))),
+                                }
+                            }
+                            Ok(Message::ClientEvents { after, limit }) => {
+                                let events = ClientEvents::instance().after(after, limit);
+                                match MessageReply::ClientEvents(events).encode() {
+                                    Ok(ress) => {
+                                        HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Data(ress)))
+                                    }
+                                    Err(e) => HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Error(
+                                        e.to_string(),
+                                    ))),
+                                }
+                            }
+                            Ok(Message::SessionDump { clientid }) => {
+                                let dump = clients::dump(clientid).await.map(|d| d.to_string().into_bytes());
+                                match MessageReply::SessionDump(dump).encode() {
+                                    Ok(ress) => {
+                                        HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Data(ress)))
+                                    }
+                                    Err(e) => HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Error(
+                                        e.to_string(),
+                                    ))),
+                                }
+                            }
                             Ok(Message::Subscribe(params)) =>
                             {
                                 #[allow(clippy::mutable_key_type)]
@@ -181,6 +309,23 @@ impl Handler for HookHandler {
                                     ))),
                                 }
                             }
+                            Ok(Message::GetPluginConfigSchema { name }) => {
+                                match plugin::get_plugin_config_schema(name).await {
+                                    Ok(schema) => {
+                                        match MessageReply::GetPluginConfigSchema(schema).encode() {
+                                            Ok(ress) => {
+                                                HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Data(ress)))
+                                            }
+                                            Err(e) => HookResult::GrpcMessageReply(Ok(
+                                                GrpcMessageReply::Error(e.to_string()),
+                                            )),
+                                        }
+                                    }
+                                    Err(e) => HookResult::GrpcMessageReply(Ok(GrpcMessageReply::Error(
+                                        e.to_string(),
+                                    ))),
+                                }
+                            }
                             Ok(Message::ReloadPluginConfig { name }) => {
                                 match Runtime::instance().plugins.load_config(name).await {
                                     Ok(()) => match MessageReply::ReloadPluginConfig.encode() {
@@ -227,7 +372,8 @@ impl Handler for HookHandler {
                                 }
                             }
                         };
-                        return (false, Some(new_acc));
+                        *acc = Some(new_acc);
+                        return false;
                     }
                     _ => {
                         log::error!("unimplemented, {:?}", param)
@@ -238,6 +384,6 @@ impl Handler for HookHandler {
                 log::error!("unimplemented, {:?}", param)
             }
         }
-        (true, acc)
+        true
     }
 }
@@ -0,0 +1,354 @@
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use salvo::http::header::AUTHORIZATION;
+use salvo::http::StatusCode;
+use salvo::prelude::*;
+
+use rmqtt::{
+    base64::{engine::general_purpose, Engine as _},
+    bytes::Bytes,
+    once_cell::sync::Lazy,
+    serde_json::{self, json},
+    timestamp_millis, DashMap, SessionState,
+};
+use rmqtt::{
+    ClientId, From, Id, MqttError, Publish, PublishProperties, QoS, Result, Runtime, Topic, TopicName,
+};
+
+use super::api::get_cfg;
+use super::config::{IngestConfig, IngestToken};
+
+//Token buckets, by token or by remote IP
+static LIMITERS: Lazy<DashMap<String, RateLimiter>> = Lazy::new(DashMap::default);
+
+struct RateLimiter {
+    rate: u32,
+    burst: u32,
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    fn new(rate: u32, burst: u32) -> Self {
+        Self { rate, burst, tokens: burst.max(1) as f64, last: Instant::now() }
+    }
+
+    #[inline]
+    fn acquire(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.burst.max(1) as f64);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct IngestMessage {
+    topic: TopicName,
+    //A string, or any JSON value which is published as JSON text
+    payload: serde_json::Value,
+    //plain or base64, for string payloads
+    #[serde(default = "IngestMessage::encoding_default")]
+    encoding: String,
+    #[serde(default)]
+    qos: u8,
+    #[serde(default)]
+    retain: bool,
+    properties: Option<PublishProperties>,
+}
+
+impl IngestMessage {
+    fn encoding_default() -> String {
+        "plain".into()
+    }
+}
+
+struct Ingest {
+    from: From,
+    rate_limit: u32,
+    rate_burst: u32,
+    limiter_key: String,
+    token: Option<IngestToken>,
+    retain_available: bool,
+    storage_available: bool,
+    expiry_interval: Duration,
+}
+
+impl Ingest {
+    //Authenticates the request, None if the token is missing or unknown
+    async fn new(req: &Request, depot: &mut Depot) -> std::result::Result<Option<Self>, salvo::Error> {
+        let cfg = get_cfg(depot)?.read().await;
+        let addr = req.remote_addr();
+        let remote_addr = if let Some(ipv4) = addr.as_ipv4() {
+            Some(SocketAddr::V4(*ipv4))
+        } else {
+            addr.as_ipv6().map(|ipv6| SocketAddr::V6(*ipv6))
+        };
+
+        let ingest_cfg: &IngestConfig = &cfg.ingest;
+        let (token, limiter_key) = if ingest_cfg.tokens.is_empty() {
+            (None, remote_addr.map(|a| a.ip().to_string()).unwrap_or_default())
+        } else {
+            let bearer = req
+                .headers()
+                .get(AUTHORIZATION)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.strip_prefix("Bearer "))
+                .map(|v| v.trim());
+            match bearer.and_then(|t| ingest_cfg.token(t)) {
+                Some(token) => (Some(token.clone()), token.token.clone()),
+                None => return Ok(None),
+            }
+        };
+
+        let (clientid, username) = match &token {
+            Some(t) => (t.clientid.clone(), t.username.clone()),
+            None => (ClientId::from("system"), None),
+        };
+        let from = From::from_custom(Id::new(
+            Runtime::instance().node.id(),
+            Some(cfg.http_laddr),
+            remote_addr,
+            clientid,
+            username,
+        ));
+        Ok(Some(Self {
+            from,
+            rate_limit: token.as_ref().and_then(|t| t.rate_limit).unwrap_or(ingest_cfg.rate_limit),
+            rate_burst: ingest_cfg.rate_burst,
+            limiter_key,
+            token,
+            retain_available: cfg.message_retain_available,
+            storage_available: cfg.message_storage_available,
+            expiry_interval: cfg.message_expiry_interval,
+        }))
+    }
+
+    #[inline]
+    fn acquire(&self) -> bool {
+        if self.rate_limit == 0 {
+            return true;
+        }
+        let mut limiter = LIMITERS
+            .entry(self.limiter_key.clone())
+            .or_insert_with(|| RateLimiter::new(self.rate_limit, self.rate_burst));
+        if limiter.rate != self.rate_limit || limiter.burst != self.rate_burst {
+            *limiter = RateLimiter::new(self.rate_limit, self.rate_burst);
+        }
+        limiter.acquire()
+    }
+
+    //Returns the per-message result, {"topic", "code", "message"}
+    async fn publish(
+        &self,
+        topic: TopicName,
+        qos: u8,
+        retain: bool,
+        payload: Bytes,
+        props: PublishProperties,
+    ) -> serde_json::Value {
+        let (code, message) = match self._publish(topic.clone(), qos, retain, payload, props).await {
+            Ok(()) => (StatusCode::OK, "ok".to_string()),
+            Err((code, e)) => (code, e.to_string()),
+        };
+        json!({ "topic": topic, "code": code.as_u16(), "message": message })
+    }
+
+    async fn _publish(
+        &self,
+        topic: TopicName,
+        qos: u8,
+        retain: bool,
+        payload: Bytes,
+        properties: PublishProperties,
+    ) -> std::result::Result<(), (StatusCode, MqttError)> {
+        let bad_request = |e: MqttError| (StatusCode::BAD_REQUEST, e);
+        let qos = QoS::try_from(qos).map_err(|e| bad_request(MqttError::from(e.to_string())))?;
+        let t = Topic::from_str(&topic).map_err(|e| bad_request(MqttError::from(e)))?;
+        if topic.is_empty() || topic.contains(['+', '#']) {
+            return Err(bad_request(MqttError::from("invalid topic")));
+        }
+        if let Some(token) = &self.token {
+            if !token.is_allowed(&t) {
+                return Err((StatusCode::FORBIDDEN, MqttError::from("not authorized")));
+            }
+        }
+        if !self.acquire() {
+            return Err((StatusCode::TOO_MANY_REQUESTS, MqttError::from("rate limited")));
+        }
+
+        let expiry_interval = properties
+            .message_expiry_interval
+            .map(|interval| Duration::from_secs(interval.get() as u64))
+            .unwrap_or(self.expiry_interval);
+        let p = Publish {
+            dup: false,
+            retain,
+            qos,
+            topic,
+            packet_id: None,
+            payload,
+            properties,
+            create_time: timestamp_millis(),
+        };
+        //hook, message_publish, the rule engine and other plugins see the message as an ordinary publish
+        let p = match Runtime::instance()
+            .extends
+            .hook_mgr()
+            .await
+            .message_publish(None, self.from.clone(), &p)
+            .await
+        {
+            Ok(modified) => modified.unwrap_or(p),
+            //dropped by a handler
+            Err(_) => return Ok(()),
+        };
+        SessionState::forwards(
+            self.from.clone(),
+            p,
+            self.retain_available,
+            self.storage_available,
+            Some(expiry_interval),
+        )
+        .await
+        .map_err(|e| (StatusCode::SERVICE_UNAVAILABLE, e))
+    }
+
+    async fn publish_message(&self, msg: IngestMessage) -> serde_json::Value {
+        let mut properties = msg.properties.unwrap_or_default();
+        let payload = match Self::payload(msg.payload, &msg.encoding, &mut properties) {
+            Ok(payload) => payload,
+            Err(e) => {
+                return json!({ "topic": msg.topic, "code": StatusCode::BAD_REQUEST.as_u16(), "message": e.to_string() })
+            }
+        };
+        self.publish(msg.topic, msg.qos, msg.retain, payload, properties).await
+    }
+
+    #[inline]
+    fn payload(
+        payload: serde_json::Value,
+        encoding: &str,
+        properties: &mut PublishProperties,
+    ) -> Result<Bytes> {
+        match payload {
+            serde_json::Value::String(s) => match encoding.to_ascii_lowercase().as_str() {
+                "plain" => Ok(Bytes::from(s)),
+                "base64" => Ok(Bytes::from(
+                    general_purpose::STANDARD.decode(s).map_err(|e| MqttError::from(e.to_string()))?,
+                )),
+                _ => Err(MqttError::from("encoding error, currently only plain and base64 are supported")),
+            },
+            v => {
+                if properties.content_type.is_none() {
+                    properties.content_type = Some("application/json".into());
+                }
+                Ok(Bytes::from(serde_json::to_vec(&v)?))
+            }
+        }
+    }
+}
+
+#[inline]
+fn render(res: &mut Response, result: serde_json::Value) {
+    let code = result
+        .get("code")
+        .and_then(|c| c.as_u64())
+        .and_then(|c| StatusCode::from_u16(c as u16).ok())
+        .unwrap_or(StatusCode::OK);
+    res.status_code(code);
+    res.render(Json(result));
+}
+
+///POST /api/v1/publish
+///
+///A JSON message {"topic", "payload", "encoding", "qos", "retain", "properties"}, or a raw payload
+///of any other content type with the topic, qos and retain query parameters
+#[handler]
+pub(crate) async fn publish(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> Result<(), salvo::Error> {
+    let ingest = match Ingest::new(req, depot).await? {
+        Some(ingest) => ingest,
+        None => {
+            res.render(StatusError::unauthorized());
+            return Ok(());
+        }
+    };
+
+    let content_type = req.content_type();
+    if content_type.as_ref().map(|m| m.subtype() == salvo::http::mime::JSON).unwrap_or(false) {
+        let msg = match req.parse_json::<IngestMessage>().await {
+            Ok(msg) => msg,
+            Err(e) => {
+                res.render(StatusError::bad_request().detail(e.to_string()));
+                return Ok(());
+            }
+        };
+        render(res, ingest.publish_message(msg).await);
+    } else {
+        let topic = match req.query::<String>("topic") {
+            Some(topic) => TopicName::from(topic),
+            None => {
+                res.render(StatusError::bad_request().detail("topic is required"));
+                return Ok(());
+            }
+        };
+        let qos = req.query::<u8>("qos").unwrap_or(0);
+        let retain = req.query::<bool>("retain").unwrap_or(false);
+        let payload = req.payload().await?.clone();
+        let properties = PublishProperties {
+            content_type: content_type.map(|m| m.to_string().into()),
+            ..Default::default()
+        };
+        render(res, ingest.publish(topic, qos, retain, payload, properties).await);
+    }
+    Ok(())
+}
+
+///POST /api/v1/publish/bulk
+///
+///A JSON array of messages, the response is the array of the per-message results
+#[handler]
+pub(crate) async fn publish_bulk(
+    req: &mut Request,
+    depot: &mut Depot,
+    res: &mut Response,
+) -> Result<(), salvo::Error> {
+    let max_bulk = get_cfg(depot)?.read().await.ingest.max_bulk;
+    let ingest = match Ingest::new(req, depot).await? {
+        Some(ingest) => ingest,
+        None => {
+            res.render(StatusError::unauthorized());
+            return Ok(());
+        }
+    };
+    let msgs = match req.parse_json::<Vec<IngestMessage>>().await {
+        Ok(msgs) => msgs,
+        Err(e) => {
+            res.render(StatusError::bad_request().detail(e.to_string()));
+            return Ok(());
+        }
+    };
+    if msgs.len() > max_bulk {
+        res.render(StatusError::payload_too_large().detail(format!("at most {} messages", max_bulk)));
+        return Ok(());
+    }
+    //Published in order
+    let mut results = Vec::with_capacity(msgs.len());
+    for msg in msgs {
+        results.push(ingest.publish_message(msg).await);
+    }
+    res.render(Json(results));
+    Ok(())
+}
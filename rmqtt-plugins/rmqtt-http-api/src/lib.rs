@@ -7,6 +7,7 @@ extern crate rmqtt_macros;
 
 use std::sync::Arc;
 
+use client_events::{ClientEvents, ClientEventsHandler};
 use config::PluginConfig;
 use rmqtt::{
     async_trait::async_trait,
@@ -15,16 +16,21 @@ use rmqtt::{
 };
 use rmqtt::{
     broker::hook::{Register, Type},
+    grpc::message_types::MessageTypes,
     plugin::{PackageInfo, Plugin},
-    register, Result, Runtime,
+    register, MqttError, Result, Runtime,
 };
+use topic_samples::{TopicSamples, TopicSamplesHandler};
 
 mod api;
+mod client_events;
 mod clients;
 mod config;
 mod handler;
+mod ingest;
 mod plugin;
 mod subs;
+mod topic_samples;
 mod types;
 
 type ShutdownTX = oneshot::Sender<()>;
@@ -84,7 +90,20 @@ impl Plugin for HttpApiPlugin {
     async fn init(&mut self) -> Result<()> {
         log::info!("{} init", self.name());
         let mgs_type = self.cfg.read().await.message_type;
+        MessageTypes::instance().claim(self.name(), "api", mgs_type)?;
         self.register.add(Type::GrpcMessageReceived, Box::new(handler::HookHandler::new(mgs_type))).await;
+        ClientEvents::instance().set_max(self.cfg.read().await.client_events_max);
+        for typ in [
+            Type::ClientConnected,
+            Type::ClientDisconnected,
+            Type::SessionSubscribed,
+            Type::SessionUnsubscribed,
+        ] {
+            self.register.add(typ, Box::new(ClientEventsHandler)).await;
+        }
+        TopicSamples::instance().set_config(self.cfg.read().await.topic_sampling.clone());
+        self.register.add(Type::MessagePublish, Box::new(TopicSamplesHandler)).await;
+        TopicSamples::instance().start();
         Ok(())
     }
 
@@ -99,6 +118,8 @@ impl Plugin for HttpApiPlugin {
         if !self.cfg.read().await.changed(&new_cfg) {
             return Ok(());
         }
+        ClientEvents::instance().set_max(new_cfg.client_events_max);
+        TopicSamples::instance().set_config(new_cfg.topic_sampling.clone());
         let restart_enable = self.cfg.read().await.restart_enable(&new_cfg);
         if restart_enable {
             let new_cfg = Arc::new(RwLock::new(new_cfg));
@@ -130,4 +151,25 @@ impl Plugin for HttpApiPlugin {
         //self.register.stop().await;
         Ok(false)
     }
+
+    ///{"cmd": "purge_session", "clientid": "..."}, purges a stuck session from the cluster
+    ///{"cmd": "check_consistency", "repair": false}, audits the routes, the sessions and the retained
+    ///messages of the cluster
+    #[inline]
+    async fn send(&self, msg: serde_json::Value) -> Result<serde_json::Value> {
+        match msg.get("cmd").and_then(|cmd| cmd.as_str()) {
+            Some("purge_session") => {
+                let clientid = msg
+                    .get("clientid")
+                    .and_then(|c| c.as_str())
+                    .ok_or_else(|| MqttError::from("clientid is required"))?;
+                Ok(api::purge_session(clientid, "plugin-send").await)
+            }
+            Some("check_consistency") => {
+                let repair = msg.get("repair").and_then(|r| r.as_bool()).unwrap_or(false);
+                Ok(api::consistency(repair, "plugin-send").await)
+            }
+            _ => Err(MqttError::from(format!("unknown command, {}", msg))),
+        }
+    }
 }
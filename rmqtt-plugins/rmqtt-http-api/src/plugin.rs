@@ -29,3 +29,11 @@ pub(crate) async fn get_plugin_config(name: &str) -> Result<Vec<u8>> {
     let data = Runtime::instance().plugins.get_config(name).await.map(|cfg| serde_json::to_vec(&cfg))??;
     Ok(data)
 }
+
+#[inline]
+pub(crate) async fn get_plugin_config_schema(name: &str) -> Result<Option<Vec<u8>>> {
+    match Runtime::instance().plugins.config_schema(name).await? {
+        Some(schema) => Ok(Some(serde_json::to_vec(&schema)?)),
+        None => Ok(None),
+    }
+}
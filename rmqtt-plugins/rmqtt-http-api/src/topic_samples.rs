@@ -0,0 +1,197 @@
+//! Topic samples for capacity planning. The messages published to each topic are counted, and every
+//! `topic_sampling.interval` the top topics by publish rate and the top topic filters by subscriber
+//! count of this node are recorded in a bounded ring of samples, optionally persisted to a file and
+//! loaded again at startup. GET /api/v1/topics/samples returns the samples of the nodes within a time
+//! range.
+
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use rmqtt::{
+    async_trait::async_trait,
+    log,
+    once_cell::sync::Lazy,
+    rust_box::std_ext::RwLock,
+    serde_json::{self, json},
+    timestamp_millis, tokio, DashMap,
+};
+use rmqtt::{
+    broker::hook::{Handler, HookResult, Parameter, ReturnType},
+    NodeId, Result, Runtime, TimestampMillis,
+};
+
+use super::config::TopicSamplingConfig;
+
+static SAMPLES: Lazy<TopicSamples> = Lazy::new(TopicSamples::default);
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TopicSample {
+    pub node_id: NodeId,
+    pub time: TimestampMillis,
+    ///Topics and their messages per second since the previous sample
+    pub publish_rates: Vec<(String, f64)>,
+    ///Topic filters and their subscribers
+    pub subscribers: Vec<(String, usize)>,
+}
+
+impl TopicSample {
+    #[inline]
+    pub fn to_json(&self) -> serde_json::Value {
+        let publish_rates =
+            self.publish_rates.iter().map(|(topic, rate)| json!({"topic": topic, "rate": rate}));
+        let subscribers =
+            self.subscribers.iter().map(|(topic, count)| json!({"topic": topic, "count": count}));
+        json!({
+            "node_id": self.node_id,
+            "time": self.time,
+            "publish_rates": publish_rates.collect::<Vec<_>>(),
+            "subscribers": subscribers.collect::<Vec<_>>(),
+        })
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct TopicSamples {
+    cfg: RwLock<TopicSamplingConfig>,
+    started: AtomicBool,
+    //topic => messages published since the previous sample
+    publishes: DashMap<String, usize>,
+    samples: RwLock<VecDeque<TopicSample>>,
+}
+
+impl TopicSamples {
+    #[inline]
+    pub(crate) fn instance() -> &'static TopicSamples {
+        &SAMPLES
+    }
+
+    #[inline]
+    pub(crate) fn set_config(&self, cfg: TopicSamplingConfig) {
+        *self.cfg.write() = cfg;
+    }
+
+    ///Starts sampling once, the persisted samples are loaded
+    pub(crate) fn start(&'static self) {
+        if self.started.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        if let Err(e) = self.load() {
+            log::warn!("load the topic samples error, {:?}", e);
+        }
+        tokio::spawn(async move {
+            let mut last = timestamp_millis();
+            loop {
+                let interval = self.cfg.read().interval.max(Duration::from_secs(1));
+                tokio::time::sleep(interval).await;
+                if !self.cfg.read().enable {
+                    self.publishes.clear();
+                    continue;
+                }
+                let now = timestamp_millis();
+                self.sample((now - last).max(1) as f64 / 1000.0).await;
+                last = now;
+                if let Err(e) = self.persist() {
+                    log::warn!("persist the topic samples error, {:?}", e);
+                }
+            }
+        });
+    }
+
+    #[inline]
+    fn publish(&self, topic: &str) {
+        if let Some(mut count) = self.publishes.get_mut(topic) {
+            *count += 1;
+        } else if self.publishes.len() < self.cfg.read().max_topics {
+            *self.publishes.entry(topic.into()).or_default() += 1;
+        }
+    }
+
+    async fn sample(&self, secs: f64) {
+        let (top, max_samples) = {
+            let cfg = self.cfg.read();
+            (cfg.top, cfg.max_samples)
+        };
+        let mut publishes = self.publishes.iter().map(|e| (e.key().clone(), *e.value())).collect::<Vec<_>>();
+        self.publishes.clear();
+        publishes.sort_by(|a, b| b.1.cmp(&a.1));
+        let publish_rates =
+            publishes.into_iter().take(top).map(|(topic, count)| (topic, count as f64 / secs)).collect();
+
+        let mut subscribers = rmqtt::HashMap::default();
+        for entry in Runtime::instance().extends.shared().await.iter() {
+            for sub in entry.subscriptions().await.unwrap_or_default() {
+                *subscribers.entry(sub.topic_filter.to_string()).or_insert(0usize) += 1;
+            }
+        }
+        let mut subscribers = subscribers.into_iter().collect::<Vec<_>>();
+        subscribers.sort_by(|a, b| b.1.cmp(&a.1));
+        subscribers.truncate(top);
+
+        let sample = TopicSample {
+            node_id: Runtime::instance().node.id(),
+            time: timestamp_millis(),
+            publish_rates,
+            subscribers,
+        };
+        let mut samples = self.samples.write();
+        samples.push_back(sample);
+        while samples.len() > max_samples.max(1) {
+            samples.pop_front();
+        }
+    }
+
+    ///The samples of this node taken within the time range, in milliseconds
+    pub(crate) fn samples(
+        &self,
+        start: Option<TimestampMillis>,
+        end: Option<TimestampMillis>,
+    ) -> Vec<TopicSample> {
+        self.samples
+            .read()
+            .iter()
+            .filter(|s| start.map(|start| s.time >= start).unwrap_or(true))
+            .filter(|s| end.map(|end| s.time <= end).unwrap_or(true))
+            .cloned()
+            .collect()
+    }
+
+    fn load(&self) -> Result<()> {
+        let file = if let Some(file) = self.cfg.read().file.clone() { file } else { return Ok(()) };
+        if !Path::new(&file).exists() {
+            return Ok(());
+        }
+        let samples: VecDeque<TopicSample> = serde_json::from_slice(&std::fs::read(&file)?)?;
+        log::info!("{} topic samples loaded from {}", samples.len(), file);
+        *self.samples.write() = samples;
+        Ok(())
+    }
+
+    fn persist(&self) -> Result<()> {
+        let file = if let Some(file) = self.cfg.read().file.clone() { file } else { return Ok(()) };
+        if let Some(dir) = Path::new(&file).parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+        let data = serde_json::to_vec(&*self.samples.read())?;
+        let tmp = format!("{}.tmp", file);
+        std::fs::write(&tmp, data)?;
+        std::fs::rename(&tmp, &file)?;
+        Ok(())
+    }
+}
+
+pub(crate) struct TopicSamplesHandler;
+
+#[async_trait]
+impl Handler for TopicSamplesHandler {
+    async fn hook(&self, param: &Parameter, _acc: &mut Option<HookResult>) -> ReturnType {
+        if let Parameter::MessagePublish(_, _, p) = param {
+            let samples = TopicSamples::instance();
+            if samples.cfg.read().enable {
+                samples.publish(&p.topic);
+            }
+        }
+        true
+    }
+}
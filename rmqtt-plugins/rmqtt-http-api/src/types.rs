@@ -2,15 +2,20 @@ use serde::de::{self, Deserialize};
 use serde::ser::{self, Serialize};
 use std::time::Duration;
 
+use rmqtt::broker::alarm::Alarm;
+use rmqtt::broker::provision::ProvisionedSession;
 use rmqtt::chrono::LocalResult;
 use rmqtt::node::{BrokerInfo, NodeInfo, NodeStatus};
 use rmqtt::plugin::PluginInfo;
-use rmqtt::settings::{deserialize_datetime_option, serialize_datetime_option};
+use rmqtt::settings::{deserialize_datetime_option, serialize_datetime_option, DrainReason};
 use rmqtt::{anyhow, bincode, chrono, serde_json, HashMap, MqttError, QoS};
 use rmqtt::{metrics::Metrics, stats::Stats};
-use rmqtt::{ClientId, NodeId, Timestamp, TopicFilter, TopicName, UserName};
+use rmqtt::{ClientId, NodeId, Timestamp, TimestampMillis, TopicFilter, TopicName, UserName};
 use rmqtt::{PublishProperties, Result};
 
+use super::client_events::ClientEvent;
+use super::topic_samples::TopicSample;
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum Message<'a> {
     BrokerInfo,
@@ -27,6 +32,18 @@ pub enum Message<'a> {
     ReloadPluginConfig { name: &'a str },
     LoadPlugin { name: &'a str },
     UnloadPlugin { name: &'a str },
+    SessionDump { clientid: &'a str },
+    GetPluginConfigSchema { name: &'a str },
+    ClientSnapshot { limit: usize },
+    ClientEvents { after: u64, limit: usize },
+    GetAlarms { activated: bool },
+    TopicSamples { start: Option<TimestampMillis>, end: Option<TimestampMillis> },
+    InvalidateAclCache { clientid: Option<&'a str>, topic: Option<&'a str> },
+    ExportSessions,
+    LabelCounts,
+    KickLabel { label: &'a str },
+    ClientJournal { clientid: &'a str },
+    ClearClientJournal { clientid: &'a str },
 }
 
 impl<'a> Message<'a> {
@@ -56,6 +73,29 @@ pub enum MessageReply {
     ReloadPluginConfig,
     LoadPlugin,
     UnloadPlugin(bool),
+    //Session state in JSON
+    SessionDump(Option<Vec<u8>>),
+    //JSON Schema of the plugin config
+    GetPluginConfigSchema(Option<Vec<u8>>),
+    //Last sequence number of the client events, and the clients
+    ClientSnapshot(u64, Vec<ClientSearchResult>),
+    //None if the events after the sequence number are not available
+    ClientEvents(Option<Vec<ClientEvent>>),
+    //The active alarms, or the deactivated ones kept in the history
+    GetAlarms(Vec<Alarm>),
+    TopicSamples(Vec<TopicSample>),
+    //The number of the ACL decisions dropped
+    InvalidateAclCache(usize),
+    //The persistent sessions and their subscriptions
+    ExportSessions(Vec<ProvisionedSession>),
+    //The number of the connected sessions of each label
+    LabelCounts(HashMap<String, usize>),
+    //The number of the sessions kicked
+    KickLabel(usize),
+    //The journal events of the client in JSON
+    ClientJournal(Vec<u8>),
+    //Whether the node had a journal of the client
+    ClearClientJournal(bool),
 }
 
 impl MessageReply {
@@ -80,6 +120,8 @@ pub struct ClientSearchParams {
     pub clean_start: Option<bool>,
     pub session_present: Option<bool>,
     pub proto_ver: Option<u8>,
+    pub anonymous: Option<bool>,
+    pub label: Option<String>,
     pub _like_clientid: Option<String>,
     //Substring fuzzy search
     pub _like_username: Option<String>,
@@ -123,6 +165,8 @@ pub struct ClientSearchResult {
     pub clientid: ClientId,
     pub username: UserName,
     pub superuser: bool,
+    pub anonymous: bool,
+    pub labels: Vec<String>,
     pub proto_ver: u8,
     pub ip_address: Option<String>,
     pub port: Option<u16>,
@@ -186,6 +230,8 @@ impl ClientSearchResult {
             "clientid": self.clientid,
             "username": self.username,
             "superuser": self.superuser,
+            "anonymous": self.anonymous,
+            "labels": self.labels,
             "proto_ver": self.proto_ver,
             "ip_address": self.ip_address,
             "port": self.port,
@@ -225,6 +271,18 @@ impl ClientSearchResult {
     }
 }
 
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct RedirectParams {
+    //Client identifiers, all the clients of the node if not specified
+    pub clientids: Option<Vec<ClientId>>,
+    //Maximum number of the clients redirected
+    pub limit: Option<usize>,
+    //use_another_server or server_moved, Default: node.redirect.reason
+    pub reason: Option<DrainReason>,
+    //Where the clients should reconnect to, Default: node.redirect.server_reference
+    pub server_reference: Option<String>,
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone, Default)]
 pub struct PublishParams {
     //For topic and topics, with at least one of them specified
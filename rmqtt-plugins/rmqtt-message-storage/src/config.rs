@@ -1,5 +1,7 @@
+use std::time::Duration;
+
 use rmqtt::serde_json;
-use rmqtt::settings::Bytesize;
+use rmqtt::settings::{deserialize_duration, Bytesize};
 use serde::de::{self, Deserialize, Deserializer};
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -9,6 +11,14 @@ pub struct PluginConfig {
     pub storage: Config,
     #[serde(default = "PluginConfig::cleanup_count_default")]
     pub cleanup_count: usize,
+    #[serde(default)]
+    pub retention: Retention,
+    ///The stored messages delivered to a shared subscription are acknowledged and deleted
+    #[serde(default)]
+    pub delete_on_ack: bool,
+    ///The payloads stored by sled or redis are encrypted, see the encryption keys of rmqtt.toml
+    #[serde(default)]
+    pub encrypt: bool,
 }
 
 impl PluginConfig {
@@ -79,3 +89,46 @@ impl Default for RamConfig {
         }
     }
 }
+
+///Retention limits of the persistent storage, 0 means unlimited
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Retention {
+    ///Upper limit of the message expiry interval
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub max_age: Duration,
+    ///Maximum number of stored messages, the messages closest to expiry are removed first
+    #[serde(default)]
+    pub max_count: usize,
+    ///Maximum total size of the stored messages, topic and payload
+    #[serde(default = "Retention::max_size_default")]
+    pub max_size: Bytesize,
+}
+
+impl Default for Retention {
+    #[inline]
+    fn default() -> Self {
+        Retention { max_age: Duration::ZERO, max_count: 0, max_size: Self::max_size_default() }
+    }
+}
+
+impl Retention {
+    fn max_size_default() -> Bytesize {
+        Bytesize::from(0)
+    }
+
+    ///The expiry interval limited by max_age
+    #[inline]
+    pub fn expiry_interval(&self, expiry_interval: Duration) -> Duration {
+        if self.max_age.is_zero() {
+            expiry_interval
+        } else {
+            expiry_interval.min(self.max_age)
+        }
+    }
+
+    #[inline]
+    pub fn exceeded(&self, count: usize, size: usize) -> bool {
+        (self.max_count > 0 && count > self.max_count)
+            || (self.max_size.as_usize() > 0 && size > self.max_size.as_usize())
+    }
+}
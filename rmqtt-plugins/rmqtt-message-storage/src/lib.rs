@@ -14,11 +14,12 @@ use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
 use rmqtt::{
+    broker::health::{HealthProbe, ReadinessCheck},
     broker::hook::Register,
     plugin::{PackageInfo, Plugin},
-    register, Result, Runtime,
+    register, MqttError, Result, Runtime,
 };
-use rmqtt_storage::init_db;
+use rmqtt_storage::{init_db, DefaultStorageDB, StorageType};
 
 use config::Config;
 use config::PluginConfig;
@@ -49,11 +50,21 @@ impl StoragePlugin {
 
         let (message_mgr, cfg) = match &mut cfg.storage {
             Config::Ram(ram_cfg) => {
-                let message_mgr = ram::get_or_init(ram_cfg.clone(), cfg.cleanup_count).await?;
+                let message_mgr =
+                    ram::get_or_init(ram_cfg.clone(), cfg.cleanup_count, cfg.delete_on_ack).await?;
                 (MessageMgr::Ram(message_mgr), Arc::new(cfg))
             }
             Config::Storage(s_cfg) => {
-                s_cfg.redis.prefix = s_cfg.redis.prefix.replace("{node}", &format!("{}", node_id));
+                match s_cfg.typ {
+                    StorageType::Sled => {
+                        s_cfg.sled.path = s_cfg.sled.path.replace("{node}", &format!("{}", node_id));
+                    }
+                    StorageType::Redis => {
+                        s_cfg.redis.prefix = s_cfg.redis.prefix.replace("{node}", &format!("{}", node_id));
+                    }
+                    #[allow(unreachable_patterns)]
+                    _ => return Err(MqttError::from("unsupported storage type")),
+                }
                 let storage_db = init_db(s_cfg).await?;
                 let cfg = Arc::new(cfg);
                 let message_mgr =
@@ -85,7 +96,11 @@ impl Plugin for StoragePlugin {
     async fn start(&mut self) -> Result<()> {
         log::info!("{} start", self.name());
         let mgr: Box<dyn MessageManager> = match self.message_mgr {
-            MessageMgr::Storage(mgr) => Box::new(mgr),
+            MessageMgr::Storage(mgr) => {
+                let storage_db = mgr.storage_db.clone();
+                HealthProbe::instance().register(self.name(), Arc::new(StorageCheck { storage_db }));
+                Box::new(mgr)
+            }
             MessageMgr::Ram(mgr) => Box::new(mgr),
         };
         *self.runtime.extends.message_mgr_mut().await = mgr;
@@ -105,6 +120,18 @@ impl Plugin for StoragePlugin {
     }
 }
 
+//Pings the storage backend, for the readiness probe
+struct StorageCheck {
+    storage_db: DefaultStorageDB,
+}
+
+#[async_trait]
+impl ReadinessCheck for StorageCheck {
+    async fn check(&self) -> Result<serde_json::Value> {
+        Ok(self.storage_db.info().await?)
+    }
+}
+
 enum MessageMgr {
     Ram(&'static RamMessageManager),
     Storage(&'static StorageMessageManager),
@@ -156,6 +183,7 @@ impl MessageMgr {
                 let exec_active_count = mgr.exec.active_count();
                 let exec_waiting_count = mgr.exec.waiting_count();
                 let storage_info = mgr.storage_db.info().await.unwrap_or_default();
+                let bytes_size = mgr.bytes_size.load(Ordering::Relaxed);
                 let cost_time = format!("{:?}", now.elapsed());
                 json!({
                     "storage_info": storage_info,
@@ -163,6 +191,7 @@ impl MessageMgr {
                     "message": {
                         "topic_nodes": topic_nodes,
                         "receiveds": receiveds,
+                        "bytes_size": bytes_size,
                         "cost_time":cost_time,
                     },
                     "exec_active_count": exec_active_count,
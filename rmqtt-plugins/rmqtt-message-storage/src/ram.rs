@@ -30,11 +30,15 @@ use rmqtt::{
 static INSTANCE: OnceCell<RamMessageManager> = OnceCell::new();
 
 #[inline]
-pub(crate) async fn get_or_init(cfg: RamConfig, cleanup_count: usize) -> Result<&'static RamMessageManager> {
+pub(crate) async fn get_or_init(
+    cfg: RamConfig,
+    cleanup_count: usize,
+    delete_on_ack: bool,
+) -> Result<&'static RamMessageManager> {
     if let Some(msg_mgr) = INSTANCE.get() {
         return Ok(msg_mgr);
     }
-    let msg_mgr = RamMessageManager::new(cfg, cleanup_count).await?;
+    let msg_mgr = RamMessageManager::new(cfg, cleanup_count, delete_on_ack).await?;
     INSTANCE.set(msg_mgr).map_err(|_| anyhow!("init error!"))?;
     if let Some(msg_mgr) = INSTANCE.get() {
         Ok(msg_mgr)
@@ -67,9 +71,12 @@ pub struct RamMessageManager {
 
 impl RamMessageManager {
     #[inline]
-    async fn new(cfg: RamConfig, cleanup_count: usize) -> Result<RamMessageManager> {
+    async fn new(cfg: RamConfig, cleanup_count: usize, delete_on_ack: bool) -> Result<RamMessageManager> {
         let exec = Self::serve(cfg.clone(), cleanup_count)?;
-        Ok(Self { inner: Arc::new(RamMessageManagerInner { cfg, ..Default::default() }), exec })
+        Ok(Self {
+            inner: Arc::new(RamMessageManagerInner { cfg, delete_on_ack, ..Default::default() }),
+            exec,
+        })
     }
 
     fn serve(_cfg: RamConfig, max_limit: usize) -> Result<TaskExecQueue> {
@@ -137,6 +144,7 @@ pub struct RamMessageManagerInner {
     pub(crate) expiries: RwLock<BinaryHeap<(Reverse<TimestampMillis>, MsgID)>>,
     pub(crate) id_gen: AtomicUsize,
     messages_bytes_size: AtomicIsize,
+    delete_on_ack: bool,
 }
 
 impl RamMessageManager {
@@ -193,7 +201,14 @@ impl RamMessageManager {
             removeds
         };
 
-        for msg_id in removed_msg_ids.iter() {
+        self.remove_messages(&removed_msg_ids).await?;
+        Ok(removed_msg_ids.len())
+    }
+
+    #[inline]
+    async fn remove_messages(&self, msg_ids: &[MsgID]) -> Result<()> {
+        let inner = self.inner.as_ref();
+        for msg_id in msg_ids.iter() {
             if let Ok(Some(msg)) = self.messages_remove(msg_id).await {
                 let mut topic =
                     Topic::from_str(&msg.publish.topic).map_err(|e| anyhow!(format!("{:?}", e)))?;
@@ -202,7 +217,7 @@ impl RamMessageManager {
                 inner.forwardeds.remove(msg_id);
             }
         }
-        Ok(removed_msg_ids.len())
+        Ok(())
     }
 
     #[inline]
@@ -321,14 +336,16 @@ impl RamMessageManager {
         client_id: &str,
         topic_filter: &str,
         group: Option<&SharedGroup>,
-    ) -> Result<Vec<(MsgID, From, Publish)>> {
+        after: Option<MsgID>,
+        limit: usize,
+    ) -> Result<(Vec<(MsgID, From, Publish)>, Option<MsgID>)> {
         let inner = &self.inner;
         let mut topic = Topic::from_str(topic_filter).map_err(|e| anyhow!(format!("{:?}", e)))?;
         if !topic.levels().last().map(|l| matches!(l, TopicLevel::MultiWildcard)).unwrap_or_default() {
             topic.push(TopicLevel::SingleWildcard);
         }
 
-        let matcheds = {
+        let mut matcheds = {
             inner
                 .topic_tree
                 .read()
@@ -338,48 +355,66 @@ impl RamMessageManager {
                 .map(|(_, msg_id)| *msg_id)
                 .collect::<Vec<_>>()
         };
+        matcheds.sort_unstable();
+        let skip = after.map(|after| matcheds.partition_point(|msg_id| *msg_id <= after)).unwrap_or(0);
+
+        let mut msgs = Vec::new();
+        for msg_id in matcheds.into_iter().skip(skip) {
+            if limit > 0 && msgs.len() >= limit {
+                let next = msgs.last().map(|(msg_id, _, _): &(MsgID, From, Publish)| *msg_id);
+                return Ok((msgs, next));
+            }
+            if let Some(msg) = self._take(msg_id, client_id, topic_filter, group) {
+                msgs.push(msg);
+            }
+        }
+        Ok((msgs, None))
+    }
 
-        let matcheds = matcheds
-            .into_iter()
-            .filter_map(|msg_id| {
-                if let Ok(Some(msg)) = self.messages_get(&msg_id) {
-                    let mut clientids = self.inner.forwardeds.entry(msg_id).or_default();
-                    let is_forwarded = if clientids.get().contains_key(client_id) {
-                        true
-                    } else if let Some(group) = group {
-                        //Check if subscription is shared
-                        clientids.get().iter().any(|(_, tf_g)| {
-                            if let Some((tf, g)) = tf_g.as_ref() {
-                                g == group && tf == topic_filter
-                            } else {
-                                false
-                            }
-                        })
+    ///The message if it is not yet forwarded to the client or its shared group, it is marked as forwarded
+    #[inline]
+    fn _take(
+        &self,
+        msg_id: MsgID,
+        client_id: &str,
+        topic_filter: &str,
+        group: Option<&SharedGroup>,
+    ) -> Option<(MsgID, From, Publish)> {
+        if let Ok(Some(msg)) = self.messages_get(&msg_id) {
+            let mut clientids = self.inner.forwardeds.entry(msg_id).or_default();
+            let is_forwarded = if clientids.get().contains_key(client_id) {
+                true
+            } else if let Some(group) = group {
+                //Check if subscription is shared
+                clientids.get().iter().any(|(_, tf_g)| {
+                    if let Some((tf, g)) = tf_g.as_ref() {
+                        g == group && tf == topic_filter
                     } else {
                         false
-                    };
-
-                    log::debug!("is_forwarded: {}", is_forwarded);
-                    if is_forwarded {
-                        None
-                    } else {
-                        let msg = msg.get();
-                        if msg.is_expiry() {
-                            None
-                        } else {
-                            clientids.get_mut().insert(
-                                ClientId::from(client_id),
-                                group.map(|g| (TopicFilter::from(topic_filter), g.clone())),
-                            );
-                            Some((msg_id, msg.from.clone(), msg.publish.clone()))
-                        }
                     }
-                } else {
+                })
+            } else {
+                false
+            };
+
+            log::debug!("is_forwarded: {}", is_forwarded);
+            if is_forwarded {
+                None
+            } else {
+                let msg = msg.get();
+                if msg.is_expiry() {
                     None
+                } else {
+                    clientids.get_mut().insert(
+                        ClientId::from(client_id),
+                        group.map(|g| (TopicFilter::from(topic_filter), g.clone())),
+                    );
+                    Some((msg_id, msg.from.clone(), msg.publish.clone()))
                 }
-            })
-            .collect::<Vec<_>>();
-        Ok(matcheds)
+            }
+        } else {
+            None
+        }
     }
 
     #[allow(dead_code)]
@@ -442,7 +477,48 @@ impl MessageManager for &'static RamMessageManager {
         topic_filter: &str,
         group: Option<&SharedGroup>,
     ) -> Result<Vec<(MsgID, From, Publish)>> {
-        self._get(client_id, topic_filter, group).await
+        Ok(self._get(client_id, topic_filter, group, None, 0).await?.0)
+    }
+
+    #[inline]
+    async fn get_page(
+        &self,
+        client_id: &str,
+        topic_filter: &str,
+        group: Option<&SharedGroup>,
+        after: Option<MsgID>,
+        limit: usize,
+    ) -> Result<(Vec<(MsgID, From, Publish)>, Option<MsgID>)> {
+        self._get(client_id, topic_filter, group, after, limit).await
+    }
+
+    #[inline]
+    async fn ack(&self, msg_ids: &[MsgID]) -> Result<()> {
+        if self.delete_on_ack {
+            self.remove_messages(msg_ids).await?;
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn should_ack(&self) -> bool {
+        self.delete_on_ack
+    }
+
+    #[inline]
+    async fn purge(&self, client_id: &str) -> Result<usize> {
+        let client_id = ClientId::from(client_id);
+        let mut c = 0;
+        self.inner
+            .forwardeds
+            .retain_async(|_, clientids| {
+                if clientids.remove(&client_id).is_some() {
+                    c += 1;
+                }
+                true
+            })
+            .await;
+        Ok(c)
     }
 
     #[inline]
@@ -476,7 +552,7 @@ fn test_message_manager() {
 
     let runner = async move {
         let cfg = RamConfig::default();
-        let msg_mgr = Box::leak(Box::new(RamMessageManager::new(cfg, usize::MAX).await.unwrap()))
+        let msg_mgr = Box::leak(Box::new(RamMessageManager::new(cfg, usize::MAX, false).await.unwrap()))
             as &'static RamMessageManager;
         sleep(Duration::from_millis(10)).await;
         let f = From::from_custom(Id::from(1, ClientId::from("test-001")));
@@ -25,18 +25,19 @@ use rmqtt::{
 };
 
 use rmqtt::{
-    broker::retain::RetainTree, broker::MessageManager, ClientId, From, MqttError, MsgID, Publish, Result,
-    SharedGroup, StoredMessage, Topic, TopicFilter,
+    broker::encryption, broker::retain::RetainTree, broker::MessageManager, ClientId, From, MqttError, MsgID,
+    Publish, Result, SharedGroup, StoredMessage, Topic, TopicFilter,
 };
 
 use rmqtt::tokio::runtime::Handle;
 use rmqtt::tokio::task::spawn_blocking;
 use rmqtt_storage::{DefaultStorageDB, Map, StorageMap};
 
-use crate::config::PluginConfig;
+use crate::config::{PluginConfig, Retention};
 
 type TopicTreeType = Arc<RwLock<RetainTree<MsgID>>>;
-type TopicListType = Arc<RwLock<BTreeSet<(TimestampMillis, Topic)>>>;
+//(expiry time, topic, msg id, message size)
+type TopicListType = Arc<RwLock<BTreeSet<(TimestampMillis, Topic, MsgID, usize)>>>;
 
 const DATA: &[u8] = b"data";
 const FORWARDED_PREFIX: &[u8] = b"fwd_";
@@ -82,6 +83,9 @@ impl StorageMessageManager {
         let messages_received_max =
             StorageMessageManagerInner::storage_new_messages_counter(&storage_db).await?;
         log::info!("messages_received_max: {}", messages_received_max.load(Ordering::SeqCst));
+        let retention = cfg.retention.clone();
+        let delete_on_ack = cfg.delete_on_ack;
+        let encrypt = cfg.encrypt;
         let (exec, msg_tx, msg_queue_count) = Self::serve(cfg)?;
 
         let inner = Arc::new(StorageMessageManagerInner {
@@ -93,6 +97,10 @@ impl StorageMessageManager {
             msg_queue_count,
             id_generater,
             should_merge_on_get,
+            retention,
+            bytes_size: AtomicIsize::new(0),
+            delete_on_ack,
+            encrypt,
         });
         Ok(Self { inner, exec })
     }
@@ -166,11 +174,12 @@ impl StorageMessageManager {
                             let removed_topics = {
                                 let mut topic_list = msg_mgr.topic_list.write().await;
                                 let mut removeds = Vec::new();
-                                while let Some((expiry_time_at, _)) = topic_list.first() {
+                                while let Some((expiry_time_at, _, _, _)) = topic_list.first() {
                                     if *expiry_time_at > curr_time || removeds.len() > max_limit {
                                         break;
                                     }
-                                    if let Some((_, t)) = topic_list.pop_first() {
+                                    if let Some((_, t, _, size)) = topic_list.pop_first() {
+                                        msg_mgr.bytes_size.fetch_sub(size as isize, Ordering::SeqCst);
                                         removeds.push(t)
                                     } else {
                                         break;
@@ -230,11 +239,24 @@ pub struct StorageMessageManagerInner {
 
     id_generater: AtomicUsize,
     should_merge_on_get: bool,
+
+    retention: Retention,
+    pub(crate) bytes_size: AtomicIsize,
+    delete_on_ack: bool,
+    encrypt: bool,
 }
 
 impl StorageMessageManagerInner {
     #[inline]
     pub(crate) async fn restore_topic_tree(&self) -> Result<()> {
+        self._restore_topic_tree().await?;
+        //The retention limits may have been lowered since the last run
+        self.evict().await;
+        Ok(())
+    }
+
+    #[inline]
+    async fn _restore_topic_tree(&self) -> Result<()> {
         let mut topic_tree = self.topic_tree.write().await;
         let mut topic_list = self.topic_list.write().await;
         let mut storage_db = self.storage_db.clone();
@@ -264,8 +286,10 @@ impl StorageMessageManagerInner {
                                     topic
                                 }
                             };
+                            let size = Self::message_size(&smsg.publish);
                             topic_tree.insert(&topic, smsg.msg_id);
-                            topic_list.insert((smsg.expiry_time_at, topic));
+                            topic_list.insert((smsg.expiry_time_at, topic, smsg.msg_id, size));
+                            self.bytes_size.fetch_add(size as isize, Ordering::SeqCst);
                         }
                     }
                     Ok(None) => {}
@@ -320,6 +344,68 @@ impl StorageMessageManagerInner {
         self.messages_received_max.fetch_add(len, Ordering::SeqCst);
     }
 
+    #[inline]
+    fn message_size(publish: &Publish) -> usize {
+        publish.topic.len() + publish.payload.len()
+    }
+
+    ///Removes the messages exceeding the retention limits, those closest to expiry first
+    async fn evict(&self) {
+        let evicteds = {
+            let mut topic_list = self.topic_list.write().await;
+            let mut evicteds = Vec::new();
+            while self.retention.exceeded(topic_list.len(), self.bytes_size.load(Ordering::SeqCst) as usize) {
+                if let Some((_, t, msg_id, size)) = topic_list.pop_first() {
+                    self.bytes_size.fetch_sub(size as isize, Ordering::SeqCst);
+                    evicteds.push((t, msg_id));
+                } else {
+                    break;
+                }
+            }
+            evicteds
+        };
+        if evicteds.is_empty() {
+            return;
+        }
+        log::debug!("evict messages exceeding the retention limits, count: {}", evicteds.len());
+        self.remove(evicteds).await;
+    }
+
+    ///Removes the acknowledged messages
+    async fn remove_acks(&self, msg_ids: &[MsgID]) {
+        let msg_ids = msg_ids.iter().collect::<BTreeSet<_>>();
+        let acks = {
+            let mut topic_list = self.topic_list.write().await;
+            let mut acks = Vec::new();
+            topic_list.retain(|(_, t, msg_id, size)| {
+                if msg_ids.contains(msg_id) {
+                    self.bytes_size.fetch_sub(*size as isize, Ordering::SeqCst);
+                    acks.push((t.clone(), *msg_id));
+                    false
+                } else {
+                    true
+                }
+            });
+            acks
+        };
+        log::debug!("remove acknowledged messages, count: {}", acks.len());
+        self.remove(acks).await;
+    }
+
+    async fn remove(&self, removeds: Vec<(Topic, MsgID)>) {
+        {
+            let mut topic_tree = self.topic_tree.write().await;
+            for (t, _) in removeds.iter() {
+                topic_tree.remove(t);
+            }
+        }
+        for (_, msg_id) in removeds {
+            if let Err(e) = self.storage_db.map_remove(msg_id.to_be_bytes()).await {
+                log::warn!("remove message error, msg_id: {}, {:?}", msg_id, e);
+            }
+        }
+    }
+
     #[inline]
     fn make_forwarded_key(client_id: &str) -> Vec<u8> {
         [FORWARDED_PREFIX, client_id.as_bytes()].concat()
@@ -338,7 +424,7 @@ impl StorageMessageManagerInner {
         }
 
         let mut count = 0;
-        for ((from, publish, expiry_interval, msg_id), forwardeds) in msgs {
+        for ((from, mut publish, expiry_interval, msg_id), forwardeds) in msgs {
             let mut topic = match Topic::from_str(&publish.topic) {
                 Err(e) => {
                     log::warn!("Topic::from_str error, {:?}", e);
@@ -346,7 +432,15 @@ impl StorageMessageManagerInner {
                 }
                 Ok(topic) => topic,
             };
+            let expiry_interval = self.retention.expiry_interval(expiry_interval);
             let expiry_time_at = timestamp_millis() + expiry_interval.as_millis() as i64;
+            let size = Self::message_size(&publish);
+            if self.encrypt {
+                if let Err(e) = encryption::seal_publish(&mut publish).await {
+                    log::warn!("store to db error, encrypt(..), {:?}, msg_id: {}", e, msg_id);
+                    continue;
+                }
+            }
 
             let smsg = StoredMessage { msg_id, from, publish, expiry_time_at };
 
@@ -382,11 +476,13 @@ impl StorageMessageManagerInner {
             //topic
             topic.push(TopicLevel::Normal(msg_id.to_string()));
             self.topic_tree.write().await.insert(&topic, msg_id);
-            self.topic_list.write().await.insert((expiry_time_at, topic));
+            self.topic_list.write().await.insert((expiry_time_at, topic, msg_id, size));
+            self.bytes_size.fetch_add(size as isize, Ordering::SeqCst);
 
             count += 1;
         }
         self.messages_received_count_add(count);
+        self.evict().await;
         if let Err(e) = self
             .storage_messages_counter_add(count)
             .timeout(futures_time::time::Duration::from_millis(5000))
@@ -429,59 +525,85 @@ impl StorageMessageManagerInner {
         client_id: &str,
         topic_filter: &str,
         group: Option<&SharedGroup>,
-    ) -> Result<Vec<(MsgID, From, Publish)>> {
+        after: Option<MsgID>,
+        limit: usize,
+    ) -> Result<(Vec<(MsgID, From, Publish)>, Option<MsgID>)> {
         let inner = self;
         let mut topic = Topic::from_str(topic_filter).map_err(|e| anyhow!(format!("{:?}", e)))?;
         if !topic.levels().last().map(|l| matches!(l, TopicLevel::MultiWildcard)).unwrap_or_default() {
             topic.push(TopicLevel::SingleWildcard);
         }
 
-        let matcheds: Vec<_> =
+        let mut matcheds: Vec<_> =
             inner.topic_tree.read().await.matches(&topic).into_iter().map(|(_t, msg_id)| msg_id).collect();
+        matcheds.sort_unstable();
+        let skip = after.map(|after| matcheds.partition_point(|msg_id| *msg_id <= after)).unwrap_or(0);
+        let matcheds = &matcheds[skip..];
 
         log::debug!("_get matcheds msg_ids: {:?}", matcheds);
-        let matcheds = futures::future::join_all(matcheds.into_iter().map(|msg_id| async move {
-            let msg_key = msg_id.to_be_bytes();
-            let msg_map = self.storage_db.map(msg_key, None).await;
-            match msg_map {
-                Ok(mut msg_map) => {
-                    let is_forwarded = self
-                        ._is_forwarded(&mut msg_map, client_id, topic_filter, group)
-                        .await
-                        .unwrap_or_default();
-
-                    if is_forwarded {
+        let mut msgs = Vec::new();
+        let mut pos = 0;
+        while pos < matcheds.len() {
+            if limit > 0 && msgs.len() >= limit {
+                return Ok((msgs, Some(matcheds[pos - 1])));
+            }
+            //The forwarded and expired messages are skipped, the rest of the page is fetched
+            let n = if limit > 0 { limit - msgs.len() } else { matcheds.len() };
+            let batch = &matcheds[pos..(pos + n).min(matcheds.len())];
+            pos += batch.len();
+            msgs.extend(
+                futures::future::join_all(
+                    batch.iter().map(|msg_id| self._take(*msg_id, client_id, topic_filter, group)),
+                )
+                .await
+                .into_iter()
+                .flatten(),
+            );
+        }
+        Ok((msgs, None))
+    }
+
+    ///The message if it is not yet forwarded to the client or its shared group, it is marked as forwarded
+    async fn _take(
+        &self,
+        msg_id: MsgID,
+        client_id: &str,
+        topic_filter: &str,
+        group: Option<&SharedGroup>,
+    ) -> Option<(MsgID, From, Publish)> {
+        let msg_key = msg_id.to_be_bytes();
+        let msg_map = self.storage_db.map(msg_key, None).await;
+        match msg_map {
+            Ok(mut msg_map) => {
+                let is_forwarded = self
+                    ._is_forwarded(&mut msg_map, client_id, topic_filter, group)
+                    .await
+                    .unwrap_or_default();
+
+                if is_forwarded {
+                    None
+                } else if let Ok(Some(msg)) = self._get_message(&msg_map).await {
+                    log::debug!("_get msg: {:?}, msg.is_expiry(): {}", msg, msg.is_expiry());
+                    if msg.is_expiry() {
                         None
-                    } else if let Ok(Some(msg)) = inner._get_message(&msg_map).await {
-                        log::debug!("_get msg: {:?}, msg.is_expiry(): {}", msg, msg.is_expiry());
-                        if msg.is_expiry() {
-                            None
-                        } else {
-                            let opts = group.map(|g| (TopicFilter::from(topic_filter), g.clone()));
-                            if let Err(e) = msg_map.insert(Self::make_forwarded_key(client_id), &opts).await {
-                                log::warn!("_get::insert error, {:?}", e);
-                            }
-                            Some((msg_id, msg.from, msg.publish))
-                        }
                     } else {
-                        None
+                        let opts = group.map(|g| (TopicFilter::from(topic_filter), g.clone()));
+                        if let Err(e) = msg_map.insert(Self::make_forwarded_key(client_id), &opts).await {
+                            log::warn!("_get::insert error, {:?}", e);
+                        }
+                        Some((msg_id, msg.from, msg.publish))
                     }
-                }
-                Err(e) => {
-                    log::warn!("_get new map error, {:?}", e);
+                } else {
                     None
                 }
             }
-        }))
-        .await
-        .into_iter()
-        .flatten()
-        .collect();
-
-        Ok(matcheds)
+            Err(e) => {
+                log::warn!("_get new map error, {:?}", e);
+                None
+            }
+        }
     }
 
-    #[inline]
     async fn _is_forwarded(
         &self,
         msg_map: &mut StorageMap,
@@ -518,7 +640,13 @@ impl StorageMessageManagerInner {
 
     #[inline]
     async fn _get_message(&self, msg_map: &StorageMap) -> Result<Option<StoredMessage>> {
-        Ok(msg_map.get::<_, StoredMessage>(DATA).await?)
+        match msg_map.get::<_, StoredMessage>(DATA).await? {
+            Some(mut msg) => {
+                encryption::open_publish(&mut msg.publish).await?;
+                Ok(Some(msg))
+            }
+            None => Ok(None),
+        }
     }
 }
 
@@ -568,16 +696,29 @@ impl MessageManager for &'static StorageMessageManager {
         topic_filter: &str,
         group: Option<&SharedGroup>,
     ) -> Result<Vec<(MsgID, From, Publish)>> {
+        Ok(self.get_page(client_id, topic_filter, group, None, 0).await?.0)
+    }
+
+    #[inline]
+    async fn get_page(
+        &self,
+        client_id: &str,
+        topic_filter: &str,
+        group: Option<&SharedGroup>,
+        after: Option<MsgID>,
+        limit: usize,
+    ) -> Result<(Vec<(MsgID, From, Publish)>, Option<MsgID>)> {
         let now = std::time::Instant::now();
         let inner = self.inner.clone();
         let client_id = ClientId::from(client_id);
         let topic_filter = TopicFilter::from(topic_filter);
         let group = group.cloned();
-        let matcheds = async move { inner._get(&client_id, &topic_filter, group.as_ref()).await }
-            .spawn(&self.exec)
-            .result()
-            .timeout(futures_time::time::Duration::from_millis(3000))
-            .await;
+        let matcheds =
+            async move { inner._get(&client_id, &topic_filter, group.as_ref(), after, limit).await }
+                .spawn(&self.exec)
+                .result()
+                .timeout(futures_time::time::Duration::from_millis(3000))
+                .await;
         let matcheds = match matcheds {
             Ok(Ok(Ok(res))) => res,
             Ok(Ok(Err(e))) => {
@@ -590,7 +731,7 @@ impl MessageManager for &'static StorageMessageManager {
             }
             Err(e) => {
                 log::warn!("StorageMessageManager get timeout, {:?}", e);
-                vec![]
+                (vec![], None)
             }
         };
         if now.elapsed().as_millis() > 900 {
@@ -603,6 +744,40 @@ impl MessageManager for &'static StorageMessageManager {
         Ok(matcheds)
     }
 
+    #[inline]
+    async fn ack(&self, msg_ids: &[MsgID]) -> Result<()> {
+        if self.delete_on_ack {
+            self.remove_acks(msg_ids).await;
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn should_ack(&self) -> bool {
+        self.delete_on_ack
+    }
+
+    async fn purge(&self, client_id: &str) -> Result<usize> {
+        let key = StorageMessageManagerInner::make_forwarded_key(client_id);
+        let mut storage_db = self.storage_db.clone();
+        let mut map_iter = storage_db.map_iter().await?;
+        let mut c = 0;
+        while let Some(map) = map_iter.next().await {
+            match map {
+                Ok(m) => match m.contains_key(&key).await {
+                    Ok(true) => {
+                        m.remove(&key).await?;
+                        c += 1;
+                    }
+                    Ok(false) => {}
+                    Err(e) => log::warn!("{} purge, contains_key error, {:?}", client_id, e),
+                },
+                Err(e) => log::warn!("{} purge, iterate messages error, {:?}", client_id, e),
+            }
+        }
+        Ok(c)
+    }
+
     #[inline]
     fn should_merge_on_get(&self) -> bool {
         self.should_merge_on_get
@@ -0,0 +1,122 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use serde::de::{self, Deserialize, Deserializer};
+
+use rmqtt::settings::deserialize_duration;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PluginConfig {
+    ///statsd or DogStatsD server address, host:port
+    #[serde(default = "PluginConfig::server_default")]
+    pub server: String,
+    #[serde(default)]
+    pub format: Format,
+
+    ///Interval between two pushes
+    #[serde(default = "PluginConfig::flush_interval_default", deserialize_with = "deserialize_duration")]
+    pub flush_interval: Duration,
+    ///Prepended to the metric names, "{node}" is replaced by the node id
+    #[serde(default = "PluginConfig::prefix_default")]
+    pub prefix: String,
+    ///Maximum size of a UDP packet, the metrics of a push are split into several packets
+    #[serde(default = "PluginConfig::max_packet_size_default")]
+    pub max_packet_size: usize,
+
+    ///Tagged as "cluster", DogStatsD only
+    #[serde(default)]
+    pub cluster_name: String,
+    ///Tags of all the metrics, the node id is always tagged as "node", DogStatsD only
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+
+    ///Pushes the numeric attributes of the active plug-ins
+    #[serde(default = "PluginConfig::plugins_default")]
+    pub plugins: bool,
+
+    #[serde(default)]
+    pub topics: Topics,
+}
+
+impl PluginConfig {
+    fn server_default() -> String {
+        "127.0.0.1:8125".into()
+    }
+
+    fn flush_interval_default() -> Duration {
+        Duration::from_secs(10)
+    }
+
+    fn prefix_default() -> String {
+        "rmqtt.".into()
+    }
+
+    fn max_packet_size_default() -> usize {
+        1432
+    }
+
+    fn plugins_default() -> bool {
+        true
+    }
+}
+
+///Per-topic message counters, the topics are high-cardinality, the publishes are sampled
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Topics {
+    #[serde(default)]
+    pub enable: bool,
+    ///Fraction of the publishes counted, from 0.0 to 1.0
+    #[serde(default = "Topics::sample_rate_default")]
+    pub sample_rate: f64,
+    ///Only the topics matching these filters are counted, all if empty
+    #[serde(default)]
+    pub topic_filters: Vec<String>,
+    ///Maximum number of topics counted between two pushes, the other topics are skipped
+    #[serde(default = "Topics::max_topics_default")]
+    pub max_topics: usize,
+}
+
+impl Default for Topics {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            enable: false,
+            sample_rate: Self::sample_rate_default(),
+            topic_filters: Vec::new(),
+            max_topics: Self::max_topics_default(),
+        }
+    }
+}
+
+impl Topics {
+    fn sample_rate_default() -> f64 {
+        0.1
+    }
+
+    fn max_topics_default() -> usize {
+        1000
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub enum Format {
+    ///Plain statsd, the tags are not sent
+    #[default]
+    Statsd,
+    ///Datadog extension of statsd, with tags
+    DogStatsd,
+}
+
+impl<'de> Deserialize<'de> for Format {
+    #[inline]
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        match String::deserialize(deserializer)?.to_ascii_lowercase().as_str() {
+            "statsd" => Ok(Format::Statsd),
+            "dogstatsd" => Ok(Format::DogStatsd),
+            s => Err(de::Error::custom(format!("invalid format '{}', statsd or dogstatsd", s))),
+        }
+    }
+}
@@ -0,0 +1,149 @@
+#![deny(unsafe_code)]
+#[macro_use]
+extern crate serde;
+
+#[macro_use]
+extern crate rmqtt_macros;
+
+use std::ops::Deref;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rmqtt::{
+    async_trait::async_trait,
+    log,
+    serde_json::{self, json},
+    tokio::{self, sync::RwLock, task::JoinHandle},
+};
+use rmqtt::{
+    broker::hook::{Handler, HookResult, Parameter, Register, ReturnType, Type},
+    plugin::{PackageInfo, Plugin},
+    register, Result, Runtime,
+};
+
+use config::PluginConfig;
+use sink::Sink;
+
+mod config;
+mod sink;
+
+register!(StatsdPlugin::new);
+
+#[derive(Plugin)]
+struct StatsdPlugin {
+    runtime: &'static Runtime,
+    cfg: Arc<RwLock<PluginConfig>>,
+    register: Box<dyn Register>,
+    sink: Arc<RwLock<Option<Arc<Sink>>>>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl StatsdPlugin {
+    #[inline]
+    async fn new(runtime: &'static Runtime, name: &'static str) -> Result<Self> {
+        let cfg = runtime.settings.plugins.load_config_default::<PluginConfig>(name)?;
+        log::info!("{} StatsdPlugin cfg: {:?}", name, cfg);
+        let register = runtime.extends.hook_mgr().await.register();
+        Ok(Self {
+            runtime,
+            cfg: Arc::new(RwLock::new(cfg)),
+            register,
+            sink: Arc::new(RwLock::new(None)),
+            task: None,
+        })
+    }
+
+    async fn start_sink(&mut self) -> Result<()> {
+        let cfg = self.cfg.read().await.clone();
+        let flush_interval = cfg.flush_interval.max(Duration::from_secs(1));
+        let sink = Arc::new(Sink::bind(cfg, self.runtime.node.id()).await?);
+        let s = sink.clone();
+        self.task = Some(tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(flush_interval).await;
+                s.flush().await;
+            }
+        }));
+        *self.sink.write().await = Some(sink);
+        Ok(())
+    }
+
+    async fn stop_sink(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+        self.sink.write().await.take();
+    }
+}
+
+#[async_trait]
+impl Plugin for StatsdPlugin {
+    #[inline]
+    async fn init(&mut self) -> Result<()> {
+        log::info!("{} init", self.name());
+        self.register.add(Type::MessagePublish, Box::new(StatsdHandler { sink: self.sink.clone() })).await;
+        Ok(())
+    }
+
+    #[inline]
+    async fn get_config(&self) -> Result<serde_json::Value> {
+        Ok(serde_json::to_value(self.cfg.read().await.deref())?)
+    }
+
+    #[inline]
+    async fn load_config(&mut self) -> Result<()> {
+        let new_cfg = self.runtime.settings.plugins.load_config::<PluginConfig>(self.name())?;
+        *self.cfg.write().await = new_cfg;
+        if self.task.is_some() {
+            self.stop_sink().await;
+            self.start_sink().await?;
+        }
+        log::debug!("load_config ok,  {:?}", self.cfg);
+        Ok(())
+    }
+
+    #[inline]
+    async fn start(&mut self) -> Result<()> {
+        log::info!("{} start", self.name());
+        self.start_sink().await?;
+        self.register.start().await;
+        Ok(())
+    }
+
+    #[inline]
+    async fn stop(&mut self) -> Result<bool> {
+        log::info!("{} stop", self.name());
+        self.register.stop().await;
+        self.stop_sink().await;
+        Ok(true)
+    }
+
+    #[inline]
+    async fn attrs(&self) -> serde_json::Value {
+        match self.sink.read().await.as_ref() {
+            Some(sink) => sink.to_json(),
+            None => json!({}),
+        }
+    }
+}
+
+struct StatsdHandler {
+    sink: Arc<RwLock<Option<Arc<Sink>>>>,
+}
+
+#[async_trait]
+impl Handler for StatsdHandler {
+    async fn hook(&self, param: &Parameter, _acc: &mut Option<HookResult>) -> ReturnType {
+        match param {
+            Parameter::MessagePublish(_, _, publish) => {
+                if let Some(sink) = self.sink.read().await.as_ref() {
+                    sink.sample(&publish.topic);
+                }
+            }
+            _ => {
+                log::error!("unimplemented, {:?}", param)
+            }
+        }
+        true
+    }
+}
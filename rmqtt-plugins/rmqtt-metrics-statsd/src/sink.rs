@@ -0,0 +1,236 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use rmqtt::{
+    broker::topic::{Topic, TopicTree},
+    dashmap::DashMap,
+    log, rand,
+    serde_json::{self, json},
+    tokio::net::UdpSocket,
+};
+use rmqtt::{NodeId, Result, Runtime, TopicName};
+
+use crate::config::{Format, PluginConfig};
+
+type Metrics = Vec<(String, f64)>;
+
+pub(crate) struct Sink {
+    cfg: PluginConfig,
+    socket: UdpSocket,
+    prefix: String,
+    //"|#node:1,cluster:rmqtt", empty for plain statsd
+    tags: String,
+    topic_filters: Option<TopicTree<()>>,
+    //last values of the cumulative broker metrics, they are pushed as deltas
+    counters: Mutex<HashMap<String, f64>>,
+    topics: DashMap<TopicName, AtomicUsize>,
+    pushes: AtomicUsize,
+    errors: AtomicUsize,
+}
+
+impl Sink {
+    pub(crate) async fn bind(cfg: PluginConfig, node_id: NodeId) -> Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        socket.connect(cfg.server.as_str()).await?;
+
+        let tags = if cfg.format == Format::DogStatsd {
+            let mut tags = vec![format!("node:{}", node_id)];
+            if !cfg.cluster_name.is_empty() {
+                tags.push(format!("cluster:{}", tag_value(&cfg.cluster_name)));
+            }
+            tags.extend(cfg.tags.iter().map(|(k, v)| format!("{}:{}", tag_value(k), tag_value(v))));
+            format!("|#{}", tags.join(","))
+        } else {
+            String::new()
+        };
+
+        let topic_filters = if cfg.topics.topic_filters.is_empty() {
+            None
+        } else {
+            let mut tree = TopicTree::default();
+            for tf in cfg.topics.topic_filters.iter() {
+                tree.insert(&Topic::from_str(tf)?, ());
+            }
+            Some(tree)
+        };
+
+        //The metrics counted before the plug-in started are not pushed
+        let mut counters = Metrics::new();
+        flatten("", &Runtime::instance().metrics.to_json(), &mut counters);
+
+        Ok(Self {
+            prefix: cfg.prefix.replace("{node}", &node_id.to_string()),
+            cfg,
+            socket,
+            tags,
+            topic_filters,
+            counters: Mutex::new(counters.into_iter().collect()),
+            topics: DashMap::default(),
+            pushes: AtomicUsize::new(0),
+            errors: AtomicUsize::new(0),
+        })
+    }
+
+    ///Counts a publish of the topic, if it is sampled
+    #[inline]
+    pub(crate) fn sample(&self, topic: &TopicName) {
+        let cfg = &self.cfg.topics;
+        if !cfg.enable || rand::random::<f64>() >= cfg.sample_rate {
+            return;
+        }
+        if let Some(tree) = self.topic_filters.as_ref() {
+            match Topic::from_str(topic) {
+                Ok(t) if tree.is_match(&t) => {}
+                _ => return,
+            }
+        }
+        if let Some(count) = self.topics.get(topic) {
+            count.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        if self.topics.len() < cfg.max_topics {
+            self.topics
+                .entry(topic.clone())
+                .or_insert_with(|| AtomicUsize::new(0))
+                .fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    ///Pushes the stats, the metrics counted since the last push and the sampled topics
+    pub(crate) async fn flush(&self) {
+        let mut lines = Vec::new();
+
+        let mut gauges = Metrics::new();
+        flatten("", &Runtime::instance().stats.clone().await.to_json().await, &mut gauges);
+        if self.cfg.plugins {
+            for entry in Runtime::instance().plugins.iter() {
+                if !entry.active() {
+                    continue;
+                }
+                match entry.to_info(entry.key()).await.and_then(|info| info.to_json()) {
+                    Ok(info) => flatten(&format!("plugins.{}", entry.key()), &info["attrs"], &mut gauges),
+                    Err(e) => log::debug!("{} attrs error, {:?}", entry.key(), e),
+                }
+            }
+        }
+        for (name, value) in gauges {
+            lines.push(self.line(&name, value, "g", None));
+        }
+
+        let mut metrics = Metrics::new();
+        flatten("", &Runtime::instance().metrics.to_json(), &mut metrics);
+        {
+            let mut counters = self.counters.lock().unwrap();
+            for (name, value) in metrics {
+                let prev = counters.insert(name.clone(), value).unwrap_or_default();
+                //reset
+                let delta = if value >= prev { value - prev } else { value };
+                lines.push(self.line(&name, delta, "c", None));
+            }
+        }
+
+        let topics = self.topics.iter().map(|e| e.key().clone()).collect::<Vec<_>>();
+        for topic in topics {
+            if let Some((topic, count)) = self.topics.remove(&topic) {
+                let count = count.load(Ordering::Relaxed) as f64;
+                lines.push(self.topic_line(&topic, count));
+            }
+        }
+
+        self.send(lines).await;
+    }
+
+    #[inline]
+    fn line(&self, name: &str, value: f64, typ: &str, sample_rate: Option<f64>) -> String {
+        let rate = sample_rate.map(|r| format!("|@{}", r)).unwrap_or_default();
+        format!("{}{}:{}|{}{}{}", self.prefix, metric_name(name), value, typ, rate, self.tags)
+    }
+
+    #[inline]
+    fn topic_line(&self, topic: &str, count: f64) -> String {
+        let rate = Some(self.cfg.topics.sample_rate);
+        match self.cfg.format {
+            Format::DogStatsd => {
+                let mut line = self.line("messages.publish.topic", count, "c", rate);
+                line.push_str(&format!(",topic:{}", tag_value(topic)));
+                line
+            }
+            Format::Statsd => {
+                self.line(&format!("messages.publish.topic.{}", topic.replace('/', ".")), count, "c", rate)
+            }
+        }
+    }
+
+    ///Sends the lines, as few packets of at most `max_packet_size` bytes as possible
+    async fn send(&self, lines: Vec<String>) {
+        let mut packets = Vec::new();
+        let mut packet = String::new();
+        for line in lines {
+            if !packet.is_empty() && packet.len() + 1 + line.len() > self.cfg.max_packet_size {
+                packets.push(std::mem::take(&mut packet));
+            }
+            if !packet.is_empty() {
+                packet.push('\n');
+            }
+            packet.push_str(&line);
+        }
+        if !packet.is_empty() {
+            packets.push(packet);
+        }
+
+        for packet in packets {
+            if let Err(e) = self.socket.send(packet.as_bytes()).await {
+                self.errors.fetch_add(1, Ordering::Relaxed);
+                log::warn!("send to {} error, {:?}", self.cfg.server, e);
+            }
+        }
+        self.pushes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        json!({
+            "pushes": self.pushes.load(Ordering::Relaxed),
+            "send_errors": self.errors.load(Ordering::Relaxed),
+            "sampled_topics": self.topics.len(),
+        })
+    }
+}
+
+///The numbers of a json value by their dotted paths, booleans are 1 or 0
+fn flatten(path: &str, val: &serde_json::Value, out: &mut Metrics) {
+    let join = |key: &str| if path.is_empty() { key.to_owned() } else { format!("{}.{}", path, key) };
+    match val {
+        serde_json::Value::Number(n) => {
+            if let Some(n) = n.as_f64() {
+                out.push((path.to_owned(), n));
+            }
+        }
+        serde_json::Value::Bool(b) => out.push((path.to_owned(), if *b { 1.0 } else { 0.0 })),
+        serde_json::Value::Object(obj) => {
+            for (key, val) in obj {
+                flatten(&join(key), val, out);
+            }
+        }
+        serde_json::Value::Array(arr) => {
+            for (idx, val) in arr.iter().enumerate() {
+                flatten(&join(&idx.to_string()), val, out);
+            }
+        }
+        _ => {}
+    }
+}
+
+//':', '|' and '@' delimit the fields of a line
+#[inline]
+fn metric_name(name: &str) -> String {
+    name.replace([':', '|', '@', '\n'], "_")
+}
+
+//',' and '#' delimit the tags
+#[inline]
+fn tag_value(v: &str) -> String {
+    v.replace([',', '|', '#', '\n'], "_")
+}
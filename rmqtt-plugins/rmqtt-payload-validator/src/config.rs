@@ -0,0 +1,80 @@
+use serde::de::{self, Deserialize, Deserializer};
+
+use rmqtt::broker::hook::Priority;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PluginConfig {
+    ///Hook priority of the validation, the rejected messages are not passed to the MessagePublish handlers
+    ///of a lower priority
+    #[serde(default = "PluginConfig::priority_default")]
+    pub priority: Priority,
+    ///Disconnect if the publishing is rejected
+    #[serde(default)]
+    pub disconnect_if_rejected: bool,
+    ///User property added to the annotated messages, its value is the rule name and the error
+    #[serde(default = "PluginConfig::annotation_property_default")]
+    pub annotation_property: String,
+    ///The rule of the longest matching topic prefix applies
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+}
+
+impl PluginConfig {
+    fn priority_default() -> Priority {
+        100
+    }
+
+    fn annotation_property_default() -> String {
+        "validation-error".into()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Rule {
+    pub name: String,
+    ///Topics starting with this prefix, "" matches all the topics
+    pub topic_prefix: String,
+    #[serde(default)]
+    pub mode: Mode,
+    ///Expected content type (MQTT 5.0)
+    #[serde(default)]
+    pub content_type: Option<String>,
+    ///The messages without a content type are invalid, e.g. the MQTT 3.1.1 messages, if content_type is set
+    #[serde(default)]
+    pub require_content_type: bool,
+    #[serde(default)]
+    pub schema: Option<Schema>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Schema {
+    ///JSON Schema file, the payload must be a JSON document valid against it
+    Json { path: String },
+    ///Protobuf descriptor set file, "protoc --include_imports --descriptor_set_out", the payload must
+    ///decode as the message, its full name, e.g. "telemetry.Reading"
+    Protobuf { descriptor: String, message: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub enum Mode {
+    ///The publishing of an invalid message is rejected, like an ACL rejection
+    #[default]
+    Reject,
+    ///An invalid message is forwarded with the annotation property
+    Annotate,
+}
+
+impl<'de> Deserialize<'de> for Mode {
+    #[inline]
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        match String::deserialize(deserializer)?.to_ascii_lowercase().as_str() {
+            "reject" => Ok(Mode::Reject),
+            "annotate" => Ok(Mode::Annotate),
+            s => Err(de::Error::custom(format!("invalid mode '{}', reject or annotate", s))),
+        }
+    }
+}
@@ -0,0 +1,172 @@
+#![deny(unsafe_code)]
+#[macro_use]
+extern crate serde;
+
+#[macro_use]
+extern crate rmqtt_macros;
+
+use std::ops::Deref;
+use std::sync::Arc;
+
+use rmqtt::{
+    async_trait::async_trait,
+    bytestring::ByteString,
+    log,
+    serde_json::{self, json},
+    tokio::sync::RwLock,
+};
+use rmqtt::{
+    broker::hook::{Handler, HookResult, Parameter, Register, ReturnType, Type},
+    broker::types::{PublishDrop, Reason},
+    plugin::{PackageInfo, Plugin},
+    register, Result, Runtime,
+};
+
+use config::{Mode, PluginConfig};
+use validator::Validator;
+
+mod config;
+mod validator;
+
+register!(PayloadValidatorPlugin::new);
+
+#[derive(Plugin)]
+struct PayloadValidatorPlugin {
+    runtime: &'static Runtime,
+    cfg: Arc<RwLock<PluginConfig>>,
+    register: Box<dyn Register>,
+    validator: Arc<RwLock<Arc<Validator>>>,
+}
+
+impl PayloadValidatorPlugin {
+    #[inline]
+    async fn new(runtime: &'static Runtime, name: &'static str) -> Result<Self> {
+        let cfg = runtime.settings.plugins.load_config_default::<PluginConfig>(name)?;
+        log::info!("{} PayloadValidatorPlugin cfg: {:?}", name, cfg);
+        let validator = Validator::new(&cfg)?;
+        let register = runtime.extends.hook_mgr().await.register();
+        Ok(Self {
+            runtime,
+            cfg: Arc::new(RwLock::new(cfg)),
+            register,
+            validator: Arc::new(RwLock::new(Arc::new(validator))),
+        })
+    }
+}
+
+#[async_trait]
+impl Plugin for PayloadValidatorPlugin {
+    #[inline]
+    async fn init(&mut self) -> Result<()> {
+        log::info!("{} init", self.name());
+        let priority = self.cfg.read().await.priority;
+        //Each message is validated when published, the result is not cached as that of the ACL check
+        self.register
+            .add_priority(Type::MessagePublish, priority, Box::new(ValidatorHandler::new(self)))
+            .await;
+        Ok(())
+    }
+
+    #[inline]
+    async fn get_config(&self) -> Result<serde_json::Value> {
+        Ok(serde_json::to_value(self.cfg.read().await.deref())?)
+    }
+
+    #[inline]
+    async fn load_config(&mut self) -> Result<()> {
+        let new_cfg = self.runtime.settings.plugins.load_config::<PluginConfig>(self.name())?;
+        *self.validator.write().await = Arc::new(Validator::new(&new_cfg)?);
+        *self.cfg.write().await = new_cfg;
+        log::debug!("load_config ok,  {:?}", self.cfg);
+        Ok(())
+    }
+
+    #[inline]
+    async fn start(&mut self) -> Result<()> {
+        log::info!("{} start", self.name());
+        self.register.start().await;
+        Ok(())
+    }
+
+    #[inline]
+    async fn stop(&mut self) -> Result<bool> {
+        log::info!("{} stop", self.name());
+        self.register.stop().await;
+        Ok(true)
+    }
+
+    #[inline]
+    async fn attrs(&self) -> serde_json::Value {
+        self.validator.read().await.to_json()
+    }
+}
+
+struct ValidatorHandler {
+    cfg: Arc<RwLock<PluginConfig>>,
+    validator: Arc<RwLock<Arc<Validator>>>,
+}
+
+impl ValidatorHandler {
+    fn new(plugin: &PayloadValidatorPlugin) -> Self {
+        Self { cfg: plugin.cfg.clone(), validator: plugin.validator.clone() }
+    }
+}
+
+#[async_trait]
+impl Handler for ValidatorHandler {
+    async fn hook(&self, param: &Parameter, acc: &mut Option<HookResult>) -> ReturnType {
+        let validator = self.validator.read().await.clone();
+        match param {
+            Parameter::MessagePublish(s, _, p) => {
+                //Uses the message modified by the preceding hooks, if any
+                let p = if let Some(HookResult::Publish(p)) = acc.as_ref() { p } else { *p };
+                if let Some(rule) = validator.rule(&p.topic, Mode::Reject) {
+                    if let Err(e) = rule.check(p) {
+                        rule.rejected_inc();
+                        log::info!(
+                            "{:?} {} invalid message rejected, topic: {}, {}",
+                            s.map(|s| &s.id),
+                            rule.rule.name,
+                            p.topic,
+                            e
+                        );
+                        //The publish of a MQTT client is refused, the messages of the bridges, gateways and
+                        //HTTP API are dropped
+                        let reason = Reason::Error(ByteString::from(format!("{}: {}", rule.rule.name, e)));
+                        let dropped = if s.is_some() {
+                            PublishDrop::refused(reason, self.cfg.read().await.disconnect_if_rejected)
+                        } else {
+                            PublishDrop::new(reason)
+                        };
+                        *acc = Some(HookResult::PublishDrop(dropped));
+                        return false;
+                    }
+                    rule.passed_inc();
+                }
+                if let Some(rule) = validator.rule(&p.topic, Mode::Annotate) {
+                    if let Err(e) = rule.check(p) {
+                        rule.annotated_inc();
+                        log::debug!(
+                            "{} invalid message annotated, topic: {}, {}",
+                            rule.rule.name,
+                            p.topic,
+                            e
+                        );
+                        let mut p = p.clone();
+                        p.properties.user_properties.push((
+                            ByteString::from(self.cfg.read().await.annotation_property.as_str()),
+                            ByteString::from(format!("{}: {}", rule.rule.name, e)),
+                        ));
+                        *acc = Some(HookResult::Publish(p));
+                        return true;
+                    }
+                    rule.passed_inc();
+                }
+            }
+            _ => {
+                log::error!("unimplemented, {:?}", param)
+            }
+        }
+        true
+    }
+}
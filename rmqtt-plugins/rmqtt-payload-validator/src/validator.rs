@@ -0,0 +1,150 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use jsonschema::JSONSchema;
+use prost_reflect::{DescriptorPool, DynamicMessage, MessageDescriptor};
+
+use rmqtt::{
+    serde_json::{self, json},
+    MqttError, Publish, Result,
+};
+
+use crate::config::{Mode, PluginConfig, Rule, Schema};
+
+enum Checker {
+    Json(JSONSchema),
+    Protobuf(MessageDescriptor),
+}
+
+impl Checker {
+    fn load(schema: &Schema) -> Result<Self> {
+        match schema {
+            Schema::Json { path } => {
+                let schema: serde_json::Value = serde_json::from_slice(&std::fs::read(path)?)?;
+                let schema = JSONSchema::compile(&schema)
+                    .map_err(|e| MqttError::from(format!("invalid JSON Schema {}, {}", path, e)))?;
+                Ok(Checker::Json(schema))
+            }
+            Schema::Protobuf { descriptor, message } => {
+                let pool = DescriptorPool::decode(std::fs::read(descriptor)?.as_slice())
+                    .map_err(|e| MqttError::from(format!("invalid descriptor set {}, {}", descriptor, e)))?;
+                let msg = pool.get_message_by_name(message).ok_or_else(|| {
+                    MqttError::from(format!("message {} not found in {}", message, descriptor))
+                })?;
+                Ok(Checker::Protobuf(msg))
+            }
+        }
+    }
+
+    fn check(&self, payload: &[u8]) -> std::result::Result<(), String> {
+        match self {
+            Checker::Json(schema) => {
+                let doc: serde_json::Value =
+                    serde_json::from_slice(payload).map_err(|e| format!("payload is not JSON, {}", e))?;
+                schema.validate(&doc).map_err(|errs| {
+                    errs.map(|e| format!("{} at {}", e, e.instance_path)).collect::<Vec<_>>().join("; ")
+                })
+            }
+            Checker::Protobuf(msg) => DynamicMessage::decode(msg.clone(), payload)
+                .map(|_| ())
+                .map_err(|e| format!("payload is not a {}, {}", msg.full_name(), e)),
+        }
+    }
+}
+
+pub(crate) struct CompiledRule {
+    pub(crate) rule: Rule,
+    checker: Option<Checker>,
+    passed: AtomicUsize,
+    rejected: AtomicUsize,
+    annotated: AtomicUsize,
+}
+
+impl CompiledRule {
+    ///Checks the message, the error if it is invalid
+    pub(crate) fn check(&self, p: &Publish) -> std::result::Result<(), String> {
+        //the payload format indicator, the payload is UTF-8 encoded character data
+        if p.properties.is_utf8_payload.unwrap_or_default() && std::str::from_utf8(&p.payload).is_err() {
+            return Err("payload is not UTF-8, but the payload format indicator is set".into());
+        }
+        if let Some(expected) = self.rule.content_type.as_deref() {
+            match p.properties.content_type.as_deref() {
+                Some(content_type) if content_type != expected => {
+                    return Err(format!("content type {} is not {}", content_type, expected))
+                }
+                None if self.rule.require_content_type => {
+                    return Err(format!("content type {} is missing", expected))
+                }
+                _ => {}
+            }
+        }
+        if let Some(checker) = self.checker.as_ref() {
+            checker.check(&p.payload)?;
+        }
+        Ok(())
+    }
+
+    #[inline]
+    pub(crate) fn passed_inc(&self) {
+        self.passed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub(crate) fn rejected_inc(&self) {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub(crate) fn annotated_inc(&self) {
+        self.annotated.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub(crate) struct Validator {
+    //by topic prefix length, longest first
+    rules: Vec<CompiledRule>,
+}
+
+impl Validator {
+    pub(crate) fn new(cfg: &PluginConfig) -> Result<Self> {
+        let mut rules = cfg
+            .rules
+            .iter()
+            .map(|rule| {
+                let checker = rule.schema.as_ref().map(Checker::load).transpose()?;
+                Ok(CompiledRule {
+                    rule: rule.clone(),
+                    checker,
+                    passed: AtomicUsize::new(0),
+                    rejected: AtomicUsize::new(0),
+                    annotated: AtomicUsize::new(0),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        rules.sort_by(|a, b| b.rule.topic_prefix.len().cmp(&a.rule.topic_prefix.len()));
+        Ok(Self { rules })
+    }
+
+    ///The rule of the topic, if it is in the mode
+    #[inline]
+    pub(crate) fn rule(&self, topic: &str, mode: Mode) -> Option<&CompiledRule> {
+        self.rules.iter().find(|r| topic.starts_with(&r.rule.topic_prefix)).filter(|r| r.rule.mode == mode)
+    }
+
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        let rules = self
+            .rules
+            .iter()
+            .map(|r| {
+                json!({
+                    "name": r.rule.name,
+                    "topic_prefix": r.rule.topic_prefix,
+                    "mode": r.rule.mode,
+                    "passed": r.passed.load(Ordering::Relaxed),
+                    "rejected": r.rejected.load(Ordering::Relaxed),
+                    "annotated": r.annotated.load(Ordering::Relaxed),
+                })
+            })
+            .collect::<Vec<_>>();
+        json!({ "rules": rules })
+    }
+}
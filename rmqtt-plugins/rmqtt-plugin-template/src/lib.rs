@@ -65,7 +65,7 @@ impl HookHandler {
 
 #[async_trait]
 impl Handler for HookHandler {
-    async fn hook(&self, param: &Parameter, acc: Option<HookResult>) -> ReturnType {
+    async fn hook(&self, param: &Parameter, _acc: &mut Option<HookResult>) -> ReturnType {
         match param {
             Parameter::ClientConnack(connect_info, r) => {
                 log::debug!("client connack, {:?}, {:?}", connect_info, r);
@@ -95,6 +95,6 @@ impl Handler for HookHandler {
                 log::error!("unimplemented, {:?}", param)
             }
         }
-        (true, acc)
+        true
     }
 }
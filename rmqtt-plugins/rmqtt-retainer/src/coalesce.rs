@@ -0,0 +1,167 @@
+//! Coalescing of the retained writes stored by a storage backend. The devices publishing their retained
+//! status many times per second would otherwise write each message to the storage. A write of a topic
+//! is held for `coalesce_window`, a newer write of the topic within it replaces the held one, only
+//! the latest is stored when the window ends. With `write_rate_limit`, "n,period", the stored writes
+//! of a topic are spaced by period/n at least, the writes over the rate are held the same way, the
+//! oldest dropped. The held writes are served to the new subscriptions as if they were stored.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use rmqtt::{
+    broker::types::DashMap,
+    dashmap::mapref::entry::Entry,
+    serde_json::{self, json},
+    Retain, Topic, TopicName,
+};
+
+use crate::config::PluginConfig;
+
+pub(crate) type Msg = (TopicName, Retain, Option<Duration>);
+
+struct Held {
+    msg: Msg,
+    //When the write is stored
+    due: Instant,
+}
+
+pub(crate) struct Coalescer {
+    window: Duration,
+    //The minimum interval between the stored writes of a topic
+    min_interval: Duration,
+    max_topics: usize,
+    helds: DashMap<TopicName, Held>,
+    //topic => when its last write was stored, kept for min_interval
+    storeds: DashMap<TopicName, Instant>,
+    coalesced: AtomicUsize,
+    rate_limited: AtomicUsize,
+    bypassed: AtomicUsize,
+    stored: AtomicUsize,
+}
+
+impl Coalescer {
+    pub(crate) fn new(cfg: &PluginConfig) -> Self {
+        let min_interval = cfg.write_rate_limit.map(|(n, period)| period / n.get()).unwrap_or_default();
+        Self {
+            window: cfg.coalesce_window,
+            min_interval,
+            max_topics: cfg.coalesce_max_topics,
+            helds: DashMap::default(),
+            storeds: DashMap::default(),
+            coalesced: AtomicUsize::new(0),
+            rate_limited: AtomicUsize::new(0),
+            bypassed: AtomicUsize::new(0),
+            stored: AtomicUsize::new(0),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn enabled(&self) -> bool {
+        !self.window.is_zero() || !self.min_interval.is_zero()
+    }
+
+    ///The interval of checking the held writes
+    #[inline]
+    pub(crate) fn tick(&self) -> Duration {
+        let shortest = match (self.window.is_zero(), self.min_interval.is_zero()) {
+            (false, false) => self.window.min(self.min_interval),
+            (false, true) => self.window,
+            _ => self.min_interval,
+        };
+        (shortest / 4).clamp(Duration::from_millis(10), Duration::from_secs(1))
+    }
+
+    ///Holds the write, it is returned if it is to be stored at once
+    pub(crate) fn hold(&self, msg: Msg) -> Option<Msg> {
+        if !self.enabled() {
+            return Some(msg);
+        }
+        let now = Instant::now();
+        let not_before = self.storeds.get(&msg.0).map(|at| *at.value() + self.min_interval);
+        let len = self.helds.len();
+        match self.helds.entry(msg.0.clone()) {
+            Entry::Occupied(mut held) => {
+                held.get_mut().msg = msg;
+                self.coalesced.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+            Entry::Vacant(vacant) => {
+                let rate_limited = not_before.map(|not_before| not_before > now).unwrap_or(false);
+                if !rate_limited && self.window.is_zero() {
+                    self.stored(&msg.0, now);
+                    return Some(msg);
+                }
+                if len >= self.max_topics {
+                    self.bypassed.fetch_add(1, Ordering::Relaxed);
+                    self.stored(&msg.0, now);
+                    return Some(msg);
+                }
+                if rate_limited {
+                    self.rate_limited.fetch_add(1, Ordering::Relaxed);
+                }
+                let due = not_before
+                    .map(|not_before| not_before.max(now + self.window))
+                    .unwrap_or(now + self.window);
+                vacant.insert(Held { msg, due });
+                None
+            }
+        }
+    }
+
+    ///Takes the held writes that are due, to be stored
+    pub(crate) fn take_dues(&self) -> Vec<Msg> {
+        let now = Instant::now();
+        let topics = self
+            .helds
+            .iter()
+            .filter(|held| held.due <= now)
+            .map(|held| held.key().clone())
+            .collect::<Vec<_>>();
+        let dues = topics
+            .into_iter()
+            .filter_map(|topic| self.helds.remove_if(&topic, |_, held| held.due <= now))
+            .map(|(topic, held)| {
+                self.stored(&topic, now);
+                held.msg
+            })
+            .collect::<Vec<_>>();
+        if !self.min_interval.is_zero() {
+            self.storeds.retain(|_, at| now.duration_since(*at) < self.min_interval);
+        }
+        dues
+    }
+
+    ///The held writes of the topics matching the filter
+    pub(crate) fn helds(&self, topic: &Topic) -> Vec<Msg> {
+        if self.helds.is_empty() {
+            return Vec::new();
+        }
+        self.helds.iter().filter(|held| topic.matches_str(held.key())).map(|held| held.msg.clone()).collect()
+    }
+
+    ///Drops the held writes of the topics matching the filter, returns how many
+    pub(crate) fn remove(&self, topic: &Topic) -> usize {
+        let len = self.helds.len();
+        self.helds.retain(|name, _| !topic.matches_str(name));
+        len.saturating_sub(self.helds.len())
+    }
+
+    #[inline]
+    fn stored(&self, topic: &TopicName, now: Instant) {
+        if !self.min_interval.is_zero() {
+            self.storeds.insert(topic.clone(), now);
+        }
+        self.stored.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        json!({
+            "enable": self.enabled(),
+            "helds": self.helds.len(),
+            "coalesced": self.coalesced.load(Ordering::Relaxed),
+            "rate_limited": self.rate_limited.load(Ordering::Relaxed),
+            "bypassed": self.bypassed.load(Ordering::Relaxed),
+            "stored": self.stored.load(Ordering::Relaxed),
+        })
+    }
+}
@@ -1,7 +1,11 @@
+use std::num::NonZeroU32;
+use std::str::FromStr;
+use std::time::Duration;
+
 use serde::de::{self, Deserialize, Deserializer};
 
 use rmqtt::serde_json;
-use rmqtt::settings::Bytesize;
+use rmqtt::settings::{deserialize_duration, to_duration, Bytesize};
 use rmqtt::Result;
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -19,6 +23,24 @@ pub struct PluginConfig {
     // message server will process the received reserved message as a regular message.
     #[serde(default = "PluginConfig::max_payload_size_default")]
     pub max_payload_size: Bytesize, // = "1MB"
+
+    // Encrypts the payloads of the retained messages stored by a storage backend, see the encryption keys of rmqtt.toml
+    #[serde(default)]
+    pub encrypt: bool,
+
+    // Coalesces the retained writes of a topic stored by a storage backend, only the latest within the window
+    // is stored, 0s disables it
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub coalesce_window: Duration,
+
+    // The maximum rate of the stored writes of a topic, "n,period", the writes over it are held, a newer
+    // write replacing the held one, not set is no limit
+    #[serde(default, deserialize_with = "PluginConfig::deserialize_write_rate_limit")]
+    pub write_rate_limit: Option<(NonZeroU32, Duration)>,
+
+    // The maximum number of the topics with a held write, the writes of the other topics are stored at once
+    #[serde(default = "PluginConfig::coalesce_max_topics_default")]
+    pub coalesce_max_topics: usize,
 }
 
 impl PluginConfig {
@@ -30,6 +52,31 @@ impl PluginConfig {
         Bytesize::from(1024 * 1024)
     }
 
+    fn coalesce_max_topics_default() -> usize {
+        100_000
+    }
+
+    #[inline]
+    fn deserialize_write_rate_limit<'de, D>(
+        deserializer: D,
+    ) -> std::result::Result<Option<(NonZeroU32, Duration)>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let v = String::deserialize(deserializer)?;
+        let pair: Vec<&str> = v.split(',').map(|s| s.trim()).collect();
+        if pair.len() != 2 {
+            return Err(de::Error::custom(format!("write_rate_limit, value format error, {}", v)));
+        }
+        let n = NonZeroU32::from_str(pair[0])
+            .map_err(|e| de::Error::custom(format!("write_rate_limit, n format error, {:?}", e)))?;
+        let period = to_duration(pair[1]);
+        if period.is_zero() {
+            return Err(de::Error::custom(format!("write_rate_limit, period format error, {}", v)));
+        }
+        Ok(Some((n, period)))
+    }
+
     #[inline]
     fn deserialize_storage<'de, D>(deserializer: D) -> std::result::Result<Config, D::Error>
     where
@@ -50,7 +97,7 @@ impl PluginConfig {
                     Ok(_) => Ok(Config::Ram),
                 }
             }
-            _ => match serde_json::from_value::<rmqtt_storage::Config>(storage) {
+            _ => match serde_json::from_value::<rmqtt_storage_ext::Config>(storage) {
                 Err(e) => Err(de::Error::custom(e.to_string())),
                 Ok(s_cfg) => Ok(Config::Storage(s_cfg)),
             },
@@ -66,7 +113,7 @@ impl PluginConfig {
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub enum Config {
     Ram,
-    Storage(rmqtt_storage::Config),
+    Storage(rmqtt_storage_ext::Config),
 }
 
 impl Default for Config {
@@ -20,13 +20,15 @@ use rmqtt::{
     MqttError,
 };
 use rmqtt::{
+    broker::health::{HealthProbe, ReadinessCheck},
     broker::hook::{Handler, HookResult, Parameter, Register, ReturnType, Type},
-    broker::RetainStorage,
+    broker::{types::TopicFilter, RetainStorage},
     plugin::{PackageInfo, Plugin},
     register, Result, Runtime,
 };
-use rmqtt_storage::{init_db, StorageType};
+use rmqtt_storage_ext::{init_db, DefaultStorageDB};
 
+mod coalesce;
 mod config;
 mod ram;
 mod storage;
@@ -59,20 +61,8 @@ impl RetainerPlugin {
                 (Retainer::Ram(RamRetainer::get_or_init(cfg.clone(), retain_enable.clone())), false)
             }
             Config::Storage(s_cfg) => {
-                let support_cluster = match s_cfg.typ {
-                    StorageType::Sled => {
-                        s_cfg.sled.path =
-                            s_cfg.sled.path.replace("{node}", &format!("{}", runtime.node.id()));
-                        false
-                    }
-                    StorageType::Redis => {
-                        s_cfg.redis.prefix =
-                            s_cfg.redis.prefix.replace("{node}", &format!("{}", runtime.node.id()));
-                        true
-                    }
-                    #[allow(unreachable_patterns)]
-                    _ => return Err(MqttError::from("unsupported storage type")),
-                };
+                s_cfg.replace_node(runtime.node.id());
+                let support_cluster = s_cfg.typ.is_shared();
                 let storage_db = init_db(s_cfg).await?;
                 (
                     Retainer::Storage(
@@ -137,7 +127,11 @@ impl Plugin for RetainerPlugin {
         log::info!("{} start", self.name());
         let r: Box<dyn RetainStorage> = match self.retainer {
             Retainer::Ram(r) => Box::new(r),
-            Retainer::Storage(r) => Box::new(r),
+            Retainer::Storage(r) => {
+                let storage_db = r.storage_db.clone();
+                HealthProbe::instance().register(self.name(), Arc::new(StorageCheck { storage_db }));
+                Box::new(r)
+            }
         };
         *self.runtime.extends.retain_mut().await = r;
         self.register.start().await;
@@ -155,6 +149,51 @@ impl Plugin for RetainerPlugin {
     async fn attrs(&self) -> serde_json::Value {
         self.retainer.info().await
     }
+
+    ///{"cmd": "list", "topic_filter": "#", "offset": 0, "limit": 100}, a page of the retained messages
+    ///{"cmd": "get", "topic": "..."}, the retained message of the topic, with its metadata
+    ///{"cmd": "delete", "topic_filter": "..."}, removes the retained messages of a topic or a filter
+    #[inline]
+    async fn send(&self, msg: serde_json::Value) -> Result<serde_json::Value> {
+        let str_arg = |name: &str| {
+            msg.get(name)
+                .and_then(|v| v.as_str())
+                .map(TopicFilter::from)
+                .ok_or_else(|| MqttError::from(format!("{} is required", name)))
+        };
+        let retain = self.runtime.extends.retain().await;
+        match msg.get("cmd").and_then(|cmd| cmd.as_str()) {
+            Some("list") => {
+                let offset = msg.get("offset").and_then(|v| v.as_u64()).unwrap_or(0) as usize;
+                let limit = msg.get("limit").and_then(|v| v.as_u64()).unwrap_or(100) as usize;
+                let (total, infos) = retain.list(&str_arg("topic_filter")?, offset, limit).await?;
+                let retains = infos.iter().map(|info| info.to_json()).collect::<Vec<_>>();
+                Ok(json!({"total": total, "retains": retains}))
+            }
+            Some("get") => {
+                let topic = str_arg("topic")?;
+                let info = retain.get_info(&topic).await?.into_iter().find(|info| info.topic == topic);
+                Ok(info.map(|info| info.to_json()).unwrap_or(serde_json::Value::Null))
+            }
+            Some("delete") => {
+                let removeds = retain.remove(&str_arg("topic_filter")?).await?;
+                Ok(json!({ "removed": removeds }))
+            }
+            _ => Err(MqttError::from(format!("unknown command, {}", msg))),
+        }
+    }
+}
+
+//Pings the storage backend, for the readiness probe
+struct StorageCheck {
+    storage_db: DefaultStorageDB,
+}
+
+#[async_trait]
+impl ReadinessCheck for StorageCheck {
+    async fn check(&self) -> Result<serde_json::Value> {
+        Ok(self.storage_db.info().await?)
+    }
 }
 
 struct RetainHandler {
@@ -170,7 +209,7 @@ impl RetainHandler {
 
 #[async_trait]
 impl Handler for RetainHandler {
-    async fn hook(&self, param: &Parameter, acc: Option<HookResult>) -> ReturnType {
+    async fn hook(&self, param: &Parameter, _acc: &mut Option<HookResult>) -> ReturnType {
         match param {
             Parameter::BeforeStartup => {
                 let grpc_clients = Runtime::instance().extends.shared().await.get_grpc_clients();
@@ -186,7 +225,7 @@ impl Handler for RetainHandler {
                 log::error!("unimplemented, {:?}", param)
             }
         }
-        (true, acc)
+        true
     }
 }
 
@@ -233,6 +272,7 @@ impl Retainer {
                         "max": msg_max,
                         "count": msg_count,
                     },
+                    "coalesce": r.coalescer.to_json(),
                 })
             }
         }
@@ -4,7 +4,7 @@ use rmqtt::{async_trait::async_trait, log, once_cell, tokio::sync::RwLock};
 use rmqtt::{
     broker::{
         default::DefaultRetainStorage,
-        types::{Retain, TopicFilter, TopicName},
+        types::{Retain, RetainInfo, TopicFilter, TopicName},
         RetainStorage,
     },
     Result,
@@ -77,6 +77,21 @@ impl RetainStorage for &'static RamRetainer {
         }
     }
 
+    #[inline]
+    async fn get_info(&self, topic_filter: &TopicFilter) -> Result<Vec<RetainInfo>> {
+        if !self.retain_enable.load(Ordering::SeqCst) {
+            log::error!("{}", ERR_NOT_SUPPORTED);
+            Ok(Vec::new())
+        } else {
+            self.inner.get_message_info(topic_filter).await
+        }
+    }
+
+    #[inline]
+    async fn remove(&self, topic_filter: &TopicFilter) -> Result<usize> {
+        self.inner.remove_message(topic_filter).await
+    }
+
     #[inline]
     async fn count(&self) -> isize {
         self.inner.count().await
@@ -86,4 +101,9 @@ impl RetainStorage for &'static RamRetainer {
     async fn max(&self) -> isize {
         self.inner.max().await
     }
+
+    #[inline]
+    async fn check_count(&self, repair: bool) -> Result<Option<(isize, usize)>> {
+        Ok(Some(self.inner.check_count(repair).await))
+    }
 }
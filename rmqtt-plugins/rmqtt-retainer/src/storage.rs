@@ -19,19 +19,18 @@ use rmqtt::{
     timestamp_millis, tokio,
     tokio::sync::RwLock,
     tokio::time::sleep,
-    NodeId, Retain, StatsMergeMode, TimestampMillis, TopicName,
+    NodeId, Retain, RetainInfo, StatsMergeMode, TimestampMillis, TopicName,
 };
 
 use rmqtt::{MqttError, Result, Topic, TopicFilter};
 
-use rmqtt::broker::RetainStorage;
-use rmqtt_storage::DefaultStorageDB;
+use rmqtt::broker::{encryption, RetainStorage};
+use rmqtt_storage_ext::DefaultStorageDB;
 
+use crate::coalesce::{Coalescer, Msg};
 use crate::config::PluginConfig;
 use crate::ERR_NOT_SUPPORTED;
 
-type Msg = (TopicName, Retain, Option<Duration>);
-
 type StoredMsg = (Retain, Option<TimestampMillis>);
 
 const RETAIN_MESSAGES_MAX: &[u8] = b"m|";
@@ -72,6 +71,10 @@ impl Retainer {
         retain_enable: Arc<AtomicBool>,
     ) -> Result<Retainer> {
         let (msg_tx, msg_queue_count) = Self::serve(cfg.clone())?;
+        let coalescer = Coalescer::new(&*cfg.read().await);
+        if coalescer.enabled() {
+            Self::flush(coalescer.tick());
+        }
         let storage_messages_count = ValueCached::new(Duration::from_millis(3000));
         let storage_messages_max = ValueCached::new(Duration::from_millis(3000));
         let inner = Arc::new(RetainerInner {
@@ -82,10 +85,31 @@ impl Retainer {
             retain_enable,
             storage_messages_count,
             storage_messages_max,
+            coalescer,
         });
         Ok(Self { inner })
     }
 
+    //Stores the held writes when they are due
+    fn flush(tick: Duration) {
+        tokio::spawn(async move {
+            let msg_mgr = loop {
+                if let Some(msg_mgr) = INSTANCE.get() {
+                    break msg_mgr;
+                }
+                sleep(Duration::from_millis(10)).await;
+            };
+            loop {
+                sleep(tick).await;
+                for msg in msg_mgr.coalescer.take_dues() {
+                    if let Err(e) = msg_mgr.enqueue(msg).await {
+                        log::warn!("Retainer flush held write error, {:?}", e);
+                    }
+                }
+            }
+        });
+    }
+
     fn serve(_cfg: Arc<RwLock<PluginConfig>>) -> Result<(mpsc::Sender<Msg>, Arc<AtomicIsize>)> {
         let msg_queue_count = Arc::new(AtomicIsize::new(0));
         let msg_queue_count1 = msg_queue_count.clone();
@@ -156,18 +180,44 @@ pub struct RetainerInner {
     // retain_count_utime: Arc<AtomicI64>,
     storage_messages_count: ValueCached<usize>,
     storage_messages_max: ValueCached<isize>,
+    pub(crate) coalescer: Coalescer,
 }
 
 impl RetainerInner {
+    #[inline]
+    async fn enqueue(&self, msg: Msg) -> Result<()> {
+        let res = self
+            .msg_tx
+            .clone()
+            .send(msg)
+            .timeout(futures_time::time::Duration::from_millis(3500))
+            .await
+            .map_err(|e| anyhow!(e));
+        match res {
+            Ok(Ok(())) => {
+                self.msg_queue_count.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            }
+            Ok(Err(e)) => {
+                log::error!("Retainer set error, {:?}", e);
+                Err(MqttError::from(e.to_string()))
+            }
+            Err(e) => {
+                log::warn!("Retainer store timeout, {:?}", e);
+                Err(MqttError::from(e.to_string()))
+            }
+        }
+    }
+
     #[inline]
     async fn _batch_store(&self, msgs: Vec<Msg>) -> Result<()> {
-        let (max_retained_messages, max_payload_size) = {
+        let (max_retained_messages, max_payload_size, encrypt) = {
             let cfg = self.cfg.read().await;
-            (cfg.max_retained_messages as usize, *cfg.max_payload_size)
+            (cfg.max_retained_messages as usize, *cfg.max_payload_size, cfg.encrypt)
         };
 
         let mut count = 0;
-        for (topic_name, retain, expiry_interval) in msgs {
+        for (topic_name, mut retain, expiry_interval) in msgs {
             let store_topic_name = [RETAIN_MESSAGES_PREFIX, topic_name.as_bytes().as_ref()].concat();
             if retain.publish.payload.is_empty() {
                 //remove retain messagge
@@ -199,6 +249,13 @@ impl RetainerInner {
                     }
                 }
 
+                if encrypt {
+                    if let Err(e) = encryption::seal_publish(&mut retain.publish).await {
+                        log::warn!("store to db error, encrypt(..), {:?}, topic_name: {:?}", e, topic_name);
+                        continue;
+                    }
+                }
+
                 //add retain messagge
                 let expiry_interval_millis =
                     expiry_interval.map(|expiry_interval| expiry_interval.as_millis() as i64);
@@ -312,7 +369,7 @@ impl RetainerInner {
     }
 
     #[inline]
-    async fn get_message(&self, topic_filter: &TopicFilter) -> Result<Vec<(TopicName, Retain)>> {
+    async fn matched_keys(&self, topic_filter: &TopicFilter) -> Result<Vec<Vec<u8>>> {
         let topic = Topic::from_str(topic_filter)?;
         let topic_filter_pattern = Self::topic_filter_to_pattern(topic_filter);
         let mut matched_topics = Vec::new();
@@ -340,21 +397,30 @@ impl RetainerInner {
                 }
             }
         }
-        drop(iter);
+        Ok(matched_topics)
+    }
 
-        let mut retains = Vec::new();
+    #[inline]
+    async fn get_message_info(&self, topic_filter: &TopicFilter) -> Result<Vec<RetainInfo>> {
+        let helds = self.coalescer.helds(&Topic::from_str(topic_filter)?);
+        let matched_topics = self.matched_keys(topic_filter).await?;
+        let db = self.storage_db.clone();
+        let mut infos = Vec::new();
         for key in matched_topics {
             match db.get::<_, StoredMsg>(key.as_slice()).await {
-                Ok(Some((retain, expiry_time_at))) => {
-                    let topic_name = TopicName::from(
+                Ok(Some((mut retain, expiry_time_at))) => {
+                    if let Err(e) = encryption::open_publish(&mut retain.publish).await {
+                        log::error!("decrypt retained message error, {:?}", e);
+                        continue;
+                    }
+                    let topic = TopicName::from(
                         String::from_utf8_lossy(&key[RETAIN_MESSAGES_PREFIX.len()..]).as_ref(),
                     );
-                    if let Some(expiry_time_at) = expiry_time_at {
-                        if expiry_time_at > timestamp_millis() {
-                            retains.push((topic_name, retain));
-                        }
-                    } else {
-                        retains.push((topic_name, retain))
+                    if expiry_time_at
+                        .map(|expiry_time_at| expiry_time_at > timestamp_millis())
+                        .unwrap_or(true)
+                    {
+                        infos.push(RetainInfo { topic, retain, expiry_time_at });
                     }
                 }
                 Ok(None) => {}
@@ -363,7 +429,44 @@ impl RetainerInner {
                 }
             }
         }
-        Ok(retains)
+        //The held writes are newer than the stored ones
+        if !helds.is_empty() {
+            infos.retain(|info| !helds.iter().any(|(topic, _, _)| *topic == info.topic));
+            let now = timestamp_millis();
+            infos.extend(helds.into_iter().filter(|(_, retain, _)| !retain.publish.payload.is_empty()).map(
+                |(topic, retain, expiry_interval)| RetainInfo {
+                    topic,
+                    retain,
+                    expiry_time_at: expiry_interval.map(|d| now + d.as_millis() as TimestampMillis),
+                },
+            ));
+        }
+        Ok(infos)
+    }
+
+    #[inline]
+    async fn get_message(&self, topic_filter: &TopicFilter) -> Result<Vec<(TopicName, Retain)>> {
+        Ok(self
+            .get_message_info(topic_filter)
+            .await?
+            .into_iter()
+            .map(|info| (info.topic, info.retain))
+            .collect())
+    }
+
+    #[inline]
+    async fn remove_message(&self, topic_filter: &TopicFilter) -> Result<usize> {
+        self.coalescer.remove(&Topic::from_str(topic_filter)?);
+        let mut removeds = 0;
+        for key in self.matched_keys(topic_filter).await? {
+            self.storage_db
+                .remove(key.as_slice())
+                .timeout(futures_time::time::Duration::from_millis(5000))
+                .await
+                .map_err(|_e| MqttError::from("storage_db.remove timeout"))??;
+            removeds += 1;
+        }
+        Ok(removeds)
     }
 }
 
@@ -376,26 +479,9 @@ impl RetainStorage for &'static Retainer {
             return Ok(());
         }
 
-        let res = self
-            .msg_tx
-            .clone()
-            .send((topic.clone(), retain, expiry_interval))
-            .timeout(futures_time::time::Duration::from_millis(3500))
-            .await
-            .map_err(|e| anyhow!(e));
-        match res {
-            Ok(Ok(())) => {
-                self.msg_queue_count.fetch_add(1, Ordering::Relaxed);
-                Ok(())
-            }
-            Ok(Err(e)) => {
-                log::error!("Retainer set error, {:?}", e);
-                Err(MqttError::from(e.to_string()))
-            }
-            Err(e) => {
-                log::warn!("Retainer store timeout, {:?}", e);
-                Err(MqttError::from(e.to_string()))
-            }
+        match self.coalescer.hold((topic.clone(), retain, expiry_interval)) {
+            Some(msg) => self.enqueue(msg).await,
+            None => Ok(()),
         }
     }
 
@@ -409,6 +495,21 @@ impl RetainStorage for &'static Retainer {
         }
     }
 
+    #[inline]
+    async fn get_info(&self, topic_filter: &TopicFilter) -> Result<Vec<RetainInfo>> {
+        if !self.retain_enable.load(Ordering::SeqCst) {
+            log::error!("{}", ERR_NOT_SUPPORTED);
+            Ok(Vec::new())
+        } else {
+            self.get_message_info(topic_filter).await
+        }
+    }
+
+    #[inline]
+    async fn remove(&self, topic_filter: &TopicFilter) -> Result<usize> {
+        self.remove_message(topic_filter).await
+    }
+
     #[inline]
     async fn count(&self) -> isize {
         self.get_retain_count().await as isize
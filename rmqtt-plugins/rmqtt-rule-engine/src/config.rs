@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use serde::de::{self, Deserialize, Deserializer};
+use serde::ser::{self, Serialize};
+
+use rmqtt::plugin::bus;
+use rmqtt::schemars::JsonSchema;
+use rmqtt::settings::deserialize_duration;
+use rmqtt::{QoS, QoSEx};
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
+#[schemars(crate = "rmqtt::schemars")]
+pub struct PluginConfig {
+    #[serde(default)]
+    pub rules: Vec<RuleConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[schemars(crate = "rmqtt::schemars")]
+pub struct RuleConfig {
+    pub id: String,
+    #[serde(default = "RuleConfig::enable_default")]
+    pub enable: bool,
+    #[serde(default)]
+    pub description: String,
+    ///e.g. SELECT payload.temp AS temp, clientid FROM "devices/+/data" WHERE payload.temp > 30
+    pub sql: String,
+    #[serde(default)]
+    pub actions: Vec<Action>,
+}
+
+impl RuleConfig {
+    fn enable_default() -> bool {
+        true
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[schemars(crate = "rmqtt::schemars")]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Action {
+    ///Publish the output of the rule to a local topic
+    Republish {
+        ///Topic template, supported placeholders: ${<output field>}
+        topic: String,
+        #[serde(
+            default = "Action::qos_default",
+            deserialize_with = "Action::deserialize_qos",
+            serialize_with = "Action::serialize_qos"
+        )]
+        #[schemars(with = "u8")]
+        qos: QoS,
+        #[serde(default)]
+        retain: bool,
+        ///Payload template, the output is encoded as JSON if not set
+        #[serde(default)]
+        payload: Option<String>,
+        #[serde(default)]
+        retain_available: bool,
+        #[serde(default)]
+        storage_available: bool,
+        #[serde(default = "Action::expiry_interval_default", deserialize_with = "deserialize_duration")]
+        #[schemars(with = "String")]
+        expiry_interval: Duration,
+    },
+    ///Send the output to another plug-in through its send() interface, e.g. a bridge plug-in
+    Bridge { plugin: String },
+    ///Publish the output to a topic of the plugin message bus, for the plug-ins subscribed to it
+    Bus {
+        #[serde(default = "Action::bus_topic_default")]
+        topic: String,
+    },
+    ///POST the output as JSON to an HTTP endpoint
+    Webhook {
+        url: String,
+        #[serde(default)]
+        headers: HashMap<String, String>,
+        #[serde(default = "Action::timeout_default", deserialize_with = "deserialize_duration")]
+        #[schemars(with = "String")]
+        timeout: Duration,
+    },
+    ///Drop the message, it is not forwarded and the following hooks of MessagePublish are not executed
+    Drop,
+}
+
+impl Action {
+    fn qos_default() -> QoS {
+        QoS::AtMostOnce
+    }
+
+    fn bus_topic_default() -> String {
+        bus::RULE_OUTPUT.name().into()
+    }
+
+    fn expiry_interval_default() -> Duration {
+        Duration::from_secs(300)
+    }
+
+    fn timeout_default() -> Duration {
+        Duration::from_secs(5)
+    }
+
+    #[inline]
+    fn deserialize_qos<'de, D>(deserializer: D) -> Result<QoS, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        match u8::deserialize(deserializer)? {
+            0 => Ok(QoS::AtMostOnce),
+            1 => Ok(QoS::AtLeastOnce),
+            2 => Ok(QoS::ExactlyOnce),
+            _ => Err(de::Error::custom("invalid value")),
+        }
+    }
+
+    #[inline]
+    fn serialize_qos<S>(qos: &QoS, s: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: ser::Serializer,
+    {
+        qos.value().serialize(s)
+    }
+}
@@ -0,0 +1,140 @@
+#![deny(unsafe_code)]
+#[macro_use]
+extern crate serde;
+
+#[macro_use]
+extern crate rmqtt_macros;
+
+use std::ops::Deref;
+use std::sync::Arc;
+
+use rmqtt::{
+    async_trait::async_trait,
+    bytestring::ByteString,
+    log, schemars,
+    serde_json::{self, json},
+    tokio::sync::RwLock,
+};
+use rmqtt::{
+    broker::hook::{Handler, HookResult, Parameter, Register, ReturnType, Type},
+    broker::types::{PublishDrop, Reason},
+    plugin::{PackageInfo, Plugin},
+    register, Result, Runtime,
+};
+
+use config::PluginConfig;
+use rule::RuleManager;
+
+mod config;
+mod rule;
+mod sql;
+
+register!(RuleEnginePlugin::new);
+
+#[derive(Plugin)]
+struct RuleEnginePlugin {
+    runtime: &'static Runtime,
+    cfg: Arc<RwLock<PluginConfig>>,
+    register: Box<dyn Register>,
+    rule_mgr: RuleManager,
+}
+
+impl RuleEnginePlugin {
+    #[inline]
+    async fn new(runtime: &'static Runtime, name: &'static str) -> Result<Self> {
+        let cfg = Arc::new(RwLock::new(runtime.settings.plugins.load_config::<PluginConfig>(name)?));
+        log::info!("{} RuleEnginePlugin cfg: {:?}", name, cfg.read().await);
+        let register = runtime.extends.hook_mgr().await.register();
+        let rule_mgr = RuleManager::new(runtime.node.id(), cfg.clone());
+        rule_mgr.load().await?;
+        Ok(Self { runtime, cfg, register, rule_mgr })
+    }
+}
+
+#[async_trait]
+impl Plugin for RuleEnginePlugin {
+    #[inline]
+    async fn init(&mut self) -> Result<()> {
+        log::info!("{} init", self.name());
+        self.register.add(Type::MessagePublish, Box::new(HookHandler::new(self.rule_mgr.clone()))).await;
+        Ok(())
+    }
+
+    #[inline]
+    async fn get_config(&self) -> Result<serde_json::Value> {
+        Ok(serde_json::to_value(self.cfg.read().await.deref())?)
+    }
+
+    #[inline]
+    async fn config_schema(&self) -> Option<serde_json::Value> {
+        serde_json::to_value(schemars::schema_for!(PluginConfig)).ok()
+    }
+
+    #[inline]
+    async fn load_config(&mut self) -> Result<()> {
+        let new_cfg = self.runtime.settings.plugins.load_config::<PluginConfig>(self.name())?;
+        *self.cfg.write().await = new_cfg;
+        self.rule_mgr.load().await?;
+        log::debug!("load_config ok,  {:?}", self.cfg);
+        Ok(())
+    }
+
+    #[inline]
+    async fn start(&mut self) -> Result<()> {
+        log::info!("{} start", self.name());
+        self.register.start().await;
+        Ok(())
+    }
+
+    #[inline]
+    async fn stop(&mut self) -> Result<bool> {
+        log::info!("{} stop", self.name());
+        self.register.stop().await;
+        Ok(true)
+    }
+
+    #[inline]
+    async fn attrs(&self) -> serde_json::Value {
+        json!({
+            "rules": self.rule_mgr.to_json()
+        })
+    }
+
+    #[inline]
+    async fn send(&self, msg: serde_json::Value) -> Result<serde_json::Value> {
+        self.rule_mgr.command(msg).await
+    }
+}
+
+struct HookHandler {
+    rule_mgr: RuleManager,
+}
+
+impl HookHandler {
+    fn new(rule_mgr: RuleManager) -> Self {
+        Self { rule_mgr }
+    }
+}
+
+#[async_trait]
+impl Handler for HookHandler {
+    async fn hook(&self, param: &Parameter, acc: &mut Option<HookResult>) -> ReturnType {
+        match param {
+            Parameter::MessagePublish(_s, f, p) => {
+                //Uses the message modified by the preceding hooks, if any
+                let p = if let Some(HookResult::Publish(p)) = acc.as_ref() { p } else { *p };
+                //Dropped, the following hooks are not executed and the message is not forwarded
+                if let Some(rule_id) = self.rule_mgr.on_publish(f, p) {
+                    log::debug!("message dropped by the rule {}, topic: {}", rule_id, p.topic);
+                    let reason = Reason::Error(ByteString::from(format!("dropped by the rule {}", rule_id)));
+                    *acc = Some(HookResult::PublishDrop(PublishDrop::new(reason)));
+                    return false;
+                }
+            }
+            _ => {
+                log::error!("unimplemented, {:?}", param)
+            }
+        }
+        true
+    }
+}
@@ -0,0 +1,355 @@
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use rmqtt::plugin::bus::{self, RuleOutput};
+use rmqtt::{
+    anyhow::anyhow,
+    bytes::Bytes,
+    log,
+    once_cell::sync::Lazy,
+    reqwest,
+    serde_json::{self, json, Value},
+    timestamp_millis, tokio,
+    tokio::sync::RwLock,
+    DashMap,
+};
+use rmqtt::{
+    broker::topic::TopicTree, From, FromType, Id, MqttError, NodeId, Publish, PublishProperties, QoSEx,
+    Result, Runtime, SessionState, Topic, TopicName,
+};
+
+use crate::config::{Action, PluginConfig, RuleConfig};
+use crate::sql::{self, Sql};
+
+//Messages republished by a rule carry this client id prefix and are not evaluated again
+const REPUBLISH_CLIENTID_PREFIX: &str = "$rule_engine:";
+
+static HTTP_CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
+    reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(8))
+        .timeout(Duration::from_secs(15))
+        .build()
+        .unwrap()
+});
+
+#[derive(Default)]
+pub(crate) struct Metrics {
+    //FROM matched
+    matched: AtomicUsize,
+    //WHERE passed
+    passed: AtomicUsize,
+    //SQL evaluation failed
+    failed: AtomicUsize,
+    actions_success: AtomicUsize,
+    actions_failed: AtomicUsize,
+}
+
+impl Metrics {
+    #[inline]
+    fn to_json(&self) -> Value {
+        json!({
+            "matched": self.matched.load(Ordering::SeqCst),
+            "passed": self.passed.load(Ordering::SeqCst),
+            "failed": self.failed.load(Ordering::SeqCst),
+            "actions_success": self.actions_success.load(Ordering::SeqCst),
+            "actions_failed": self.actions_failed.load(Ordering::SeqCst),
+        })
+    }
+}
+
+pub(crate) struct Rule {
+    cfg: RuleConfig,
+    sql: Sql,
+    topics: TopicTree<()>,
+    metrics: Metrics,
+}
+
+impl Rule {
+    fn new(cfg: RuleConfig) -> Result<Rule> {
+        let sql = Sql::parse(&cfg.sql).map_err(|e| MqttError::from(format!("rule {}, {}", cfg.id, e)))?;
+        let mut topics = TopicTree::default();
+        for topic_filter in sql.from.iter() {
+            topics.insert(&Topic::from_str(topic_filter)?, ());
+        }
+        Ok(Rule { cfg, sql, topics, metrics: Metrics::default() })
+    }
+
+    #[inline]
+    fn to_json(&self) -> Value {
+        json!({
+            "id": self.cfg.id,
+            "enable": self.cfg.enable,
+            "description": self.cfg.description,
+            "sql": self.cfg.sql,
+            "actions": self.cfg.actions,
+            "metrics": self.metrics.to_json(),
+        })
+    }
+
+    //Returns the output of the rule, or None if FROM or WHERE does not match
+    #[inline]
+    fn apply(&self, topic: &Topic, ctx: &mut Option<Value>, mk_ctx: impl FnOnce() -> Value) -> Option<Value> {
+        if !self.cfg.enable || !self.topics.is_match(topic) {
+            return None;
+        }
+        self.metrics.matched.fetch_add(1, Ordering::SeqCst);
+        let ctx: &Value = ctx.get_or_insert_with(mk_ctx);
+        let res =
+            self.sql
+                .is_pass(ctx)
+                .and_then(|pass| if pass { self.sql.select(ctx).map(Some) } else { Ok(None) });
+        match res {
+            Ok(Some(output)) => {
+                self.metrics.passed.fetch_add(1, Ordering::SeqCst);
+                Some(output)
+            }
+            Ok(None) => None,
+            Err(e) => {
+                self.metrics.failed.fetch_add(1, Ordering::SeqCst);
+                log::warn!("rule {} evaluate error, {:?}", self.cfg.id, e);
+                None
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct RuleManager {
+    node_id: NodeId,
+    cfg: Arc<RwLock<PluginConfig>>,
+    rules: Arc<DashMap<String, Arc<Rule>>>,
+}
+
+impl RuleManager {
+    pub fn new(node_id: NodeId, cfg: Arc<RwLock<PluginConfig>>) -> Self {
+        Self { node_id, cfg, rules: Arc::new(DashMap::default()) }
+    }
+
+    pub async fn load(&self) -> Result<()> {
+        self.rules.clear();
+        for rule_cfg in self.cfg.read().await.rules.iter() {
+            self.rules.insert(rule_cfg.id.clone(), Arc::new(Rule::new(rule_cfg.clone())?));
+        }
+        Ok(())
+    }
+
+    ///Evaluates all rules against the message, returns the id of the first rule dropping it, if any
+    pub fn on_publish(&self, f: &From, p: &Publish) -> Option<String> {
+        if matches!(f.typ(), FromType::System) && f.id.client_id.starts_with(REPUBLISH_CLIENTID_PREFIX) {
+            return None;
+        }
+        let topic = match Topic::from_str(&p.topic) {
+            Ok(topic) => topic,
+            Err(e) => {
+                log::warn!("invalid topic {}, {:?}", p.topic, e);
+                return None;
+            }
+        };
+
+        //The context is built lazily, only when the topic of some rule matches
+        let mut ctx = None;
+        let mut dropped_by = None;
+        for rule in self.rules.iter() {
+            let rule = rule.value().clone();
+            let output =
+                if let Some(output) = rule.apply(&topic, &mut ctx, || Self::context(self.node_id, f, p)) {
+                    output
+                } else {
+                    continue;
+                };
+            for action in rule.cfg.actions.iter() {
+                if matches!(action, Action::Drop) {
+                    rule.metrics.actions_success.fetch_add(1, Ordering::SeqCst);
+                    dropped_by.get_or_insert_with(|| rule.cfg.id.clone());
+                    continue;
+                }
+                let (rule, action, output, node_id) =
+                    (rule.clone(), action.clone(), output.clone(), self.node_id);
+                tokio::spawn(async move {
+                    match Self::exec_action(node_id, &rule.cfg.id, &action, output).await {
+                        Ok(()) => {
+                            rule.metrics.actions_success.fetch_add(1, Ordering::SeqCst);
+                        }
+                        Err(e) => {
+                            rule.metrics.actions_failed.fetch_add(1, Ordering::SeqCst);
+                            log::warn!("rule {} action error, {:?}", rule.cfg.id, e);
+                        }
+                    }
+                });
+            }
+        }
+        dropped_by
+    }
+
+    #[inline]
+    fn context(node_id: NodeId, f: &From, p: &Publish) -> Value {
+        //JSON payload decoder, falls back to a string if the payload is not JSON
+        let payload = serde_json::from_slice::<Value>(&p.payload)
+            .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&p.payload).into_owned()));
+        json!({
+            "topic": p.topic,
+            "payload": payload,
+            "qos": p.qos.value(),
+            "retain": p.retain,
+            "clientid": f.id.client_id,
+            "username": f.id.username,
+            "from_type": f.typ().to_string(),
+            "node": node_id,
+            "timestamp": p.create_time,
+        })
+    }
+
+    async fn exec_action(node_id: NodeId, rule_id: &str, action: &Action, output: Value) -> Result<()> {
+        match action {
+            Action::Republish {
+                topic,
+                qos,
+                retain,
+                payload,
+                retain_available,
+                storage_available,
+                expiry_interval,
+            } => {
+                let payload = match payload {
+                    Some(payload) => render(payload, &output),
+                    None => output.to_string(),
+                };
+                let msg = Publish {
+                    dup: false,
+                    retain: *retain,
+                    qos: *qos,
+                    topic: TopicName::from(render(topic, &output)),
+                    packet_id: None,
+                    payload: Bytes::from(payload),
+                    properties: PublishProperties::default(),
+                    create_time: timestamp_millis(),
+                };
+                let from = From::from_system(Id::new(
+                    node_id,
+                    None,
+                    None,
+                    format!("{}{}", REPUBLISH_CLIENTID_PREFIX, rule_id).into(),
+                    None,
+                ));
+                //hook, message_publish
+                let msg = match Runtime::instance()
+                    .extends
+                    .hook_mgr()
+                    .await
+                    .message_publish(None, from.clone(), &msg)
+                    .await
+                {
+                    Ok(modified) => modified.unwrap_or(msg),
+                    //dropped by a handler
+                    Err(_) => return Ok(()),
+                };
+                SessionState::forwards(
+                    from,
+                    msg,
+                    *retain_available,
+                    *storage_available,
+                    Some(*expiry_interval),
+                )
+                .await
+            }
+            Action::Bridge { plugin } => {
+                let msg = json!({
+                    "type": "rule_output",
+                    "rule": rule_id,
+                    "output": output,
+                });
+                Runtime::instance().plugins.send(plugin, msg).await.map(|_| ())
+            }
+            Action::Bus { topic } => {
+                let msg = RuleOutput { rule: rule_id.into(), output };
+                Runtime::instance()
+                    .plugins
+                    .bus
+                    .publish(&bus::Topic::new(topic.clone()), msg)
+                    .await
+                    .map(|_| ())
+            }
+            Action::Webhook { url, headers, timeout } => {
+                let mut req = HTTP_CLIENT.clone().request(reqwest::Method::POST, url).timeout(*timeout);
+                for (k, v) in headers.iter() {
+                    req = req.header(k.as_str(), v.as_str());
+                }
+                let resp = req.json(&output).send().await.map_err(|e| MqttError::Anyhow(anyhow!(e)))?;
+                if resp.status().is_success() {
+                    Ok(())
+                } else {
+                    Err(MqttError::from(format!(
+                        "response status is not OK, url:{:?}, response:{:?}",
+                        url, resp
+                    )))
+                }
+            }
+            Action::Drop => Ok(()),
+        }
+    }
+
+    ///Runtime management of rules, the message is one of:
+    ///{"cmd": "list"}, {"cmd": "get", "id": ".."}, {"cmd": "create", "rule": {..}},
+    ///{"cmd": "update", "rule": {..}}, {"cmd": "delete", "id": ".."}
+    pub async fn command(&self, msg: Value) -> Result<Value> {
+        let cmd = msg.get("cmd").and_then(|cmd| cmd.as_str()).unwrap_or_default();
+        let id = msg.get("id").and_then(|id| id.as_str());
+        match (cmd, id) {
+            ("list", _) => Ok(Value::Array(self.rules.iter().map(|r| r.value().to_json()).collect())),
+            ("get", Some(id)) => Ok(self.rules.get(id).map(|r| r.value().to_json()).unwrap_or(Value::Null)),
+            ("create", _) | ("update", _) => {
+                let rule_cfg: RuleConfig =
+                    serde_json::from_value(msg.get("rule").cloned().unwrap_or_default())?;
+                let exists = self.rules.contains_key(&rule_cfg.id);
+                if cmd == "create" && exists {
+                    return Err(MqttError::from(format!("rule {} already exists", rule_cfg.id)));
+                }
+                if cmd == "update" && !exists {
+                    return Err(MqttError::from(format!("rule {} does not exist", rule_cfg.id)));
+                }
+                let rule = Arc::new(Rule::new(rule_cfg.clone())?);
+                let reply = rule.to_json();
+                let mut cfg = self.cfg.write().await;
+                cfg.rules.retain(|r| r.id != rule_cfg.id);
+                cfg.rules.push(rule_cfg.clone());
+                self.rules.insert(rule_cfg.id, rule);
+                Ok(reply)
+            }
+            ("delete", Some(id)) => {
+                self.cfg.write().await.rules.retain(|r| r.id != id);
+                Ok(json!({ "deleted": self.rules.remove(id).is_some() }))
+            }
+            _ => Err(MqttError::from(format!("unsupported command, {}", msg))),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn to_json(&self) -> Vec<Value> {
+        self.rules.iter().map(|r| r.value().to_json()).collect()
+    }
+}
+
+///Replaces ${field} placeholders with the values of the rule output
+#[inline]
+fn render(tmpl: &str, output: &Value) -> String {
+    let mut res = String::with_capacity(tmpl.len());
+    let mut rest = tmpl;
+    while let Some(start) = rest.find("${") {
+        res.push_str(&rest[..start]);
+        match rest[start + 2..].find('}') {
+            Some(end) => {
+                let path = rest[start + 2..start + 2 + end].split('.').map(String::from).collect::<Vec<_>>();
+                res.push_str(&sql::get_path(output, &path).map(sql::to_string).unwrap_or_default());
+                rest = &rest[start + 3 + end..];
+            }
+            None => {
+                res.push_str(&rest[start..]);
+                rest = "";
+            }
+        }
+    }
+    res.push_str(rest);
+    res
+}
@@ -0,0 +1,421 @@
+//! A small SQL-like language for rules:
+//!
+//! SELECT <field> [AS <alias>], ... FROM "<topic filter>", ... [WHERE <condition>]
+//!
+//! Fields and conditions are expressions over the message context, e.g. `payload.temp`, `topic`,
+//! `clientid`, literals ('abc', 1.5, true, null), comparisons (= != <> > >= < <=), arithmetic
+//! (+ - * /), AND, OR, NOT and parentheses.
+
+use std::cmp::Ordering;
+
+use rmqtt::{
+    serde_json::{self, Map, Value},
+    MqttError, Result,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Quoted(String),
+    Number(f64),
+    Op(&'static str),
+    Comma,
+    LParen,
+    RParen,
+}
+
+#[derive(Debug, Clone)]
+pub enum Expr {
+    Lit(Value),
+    Path(Vec<String>),
+    Not(Box<Expr>),
+    Binary(Box<Expr>, &'static str, Box<Expr>),
+}
+
+#[derive(Debug, Clone)]
+pub enum Field {
+    All,
+    Expr(Expr, String),
+}
+
+#[derive(Debug, Clone)]
+pub struct Sql {
+    pub fields: Vec<Field>,
+    pub from: Vec<String>,
+    pub cond: Option<Expr>,
+}
+
+impl Sql {
+    pub fn parse(sql: &str) -> Result<Sql> {
+        let tokens = tokenize(sql)?;
+        let mut p = Parser { src: sql, tokens, pos: 0 };
+        p.expect_keyword("SELECT")?;
+        let fields = p.parse_fields()?;
+        p.expect_keyword("FROM")?;
+        let mut from = Vec::new();
+        loop {
+            match p.next() {
+                Some(Token::Quoted(t)) | Some(Token::Str(t)) => from.push(t),
+                t => return Err(MqttError::from(format!("expected topic filter after FROM, got {:?}", t))),
+            }
+            if p.peek() == Some(&Token::Comma) {
+                p.pos += 1;
+            } else {
+                break;
+            }
+        }
+        let cond = if p.eat_keyword("WHERE") { Some(p.parse_or()?) } else { None };
+        if let Some(t) = p.peek() {
+            return Err(MqttError::from(format!("unexpected token {:?}", t)));
+        }
+        Ok(Sql { fields, from, cond })
+    }
+
+    ///Returns true if the WHERE condition is absent or holds
+    #[inline]
+    pub fn is_pass(&self, ctx: &Value) -> Result<bool> {
+        match &self.cond {
+            None => Ok(true),
+            Some(cond) => Ok(is_true(&cond.eval(ctx)?)),
+        }
+    }
+
+    #[inline]
+    pub fn select(&self, ctx: &Value) -> Result<Value> {
+        let mut output = Map::new();
+        for field in self.fields.iter() {
+            match field {
+                Field::All => {
+                    if let Value::Object(obj) = ctx {
+                        output.extend(obj.iter().map(|(k, v)| (k.clone(), v.clone())));
+                    }
+                }
+                Field::Expr(expr, alias) => {
+                    output.insert(alias.clone(), expr.eval(ctx)?);
+                }
+            }
+        }
+        Ok(Value::Object(output))
+    }
+}
+
+impl Expr {
+    pub fn eval(&self, ctx: &Value) -> Result<Value> {
+        Ok(match self {
+            Expr::Lit(v) => v.clone(),
+            Expr::Path(path) => get_path(ctx, path).cloned().unwrap_or(Value::Null),
+            Expr::Not(e) => Value::Bool(!is_true(&e.eval(ctx)?)),
+            Expr::Binary(l, "AND", r) => Value::Bool(is_true(&l.eval(ctx)?) && is_true(&r.eval(ctx)?)),
+            Expr::Binary(l, "OR", r) => Value::Bool(is_true(&l.eval(ctx)?) || is_true(&r.eval(ctx)?)),
+            Expr::Binary(l, op, r) => {
+                let (l, r) = (l.eval(ctx)?, r.eval(ctx)?);
+                match *op {
+                    "=" => Value::Bool(compare(&l, &r) == Some(Ordering::Equal)),
+                    "!=" | "<>" => Value::Bool(compare(&l, &r) != Some(Ordering::Equal)),
+                    ">" => Value::Bool(compare(&l, &r) == Some(Ordering::Greater)),
+                    "<" => Value::Bool(compare(&l, &r) == Some(Ordering::Less)),
+                    ">=" => Value::Bool(matches!(compare(&l, &r), Some(Ordering::Greater | Ordering::Equal))),
+                    "<=" => Value::Bool(matches!(compare(&l, &r), Some(Ordering::Less | Ordering::Equal))),
+                    "+" => match (&l, &r) {
+                        (Value::String(l), r) => Value::String(format!("{}{}", l, to_string(r))),
+                        (l, Value::String(r)) => Value::String(format!("{}{}", to_string(l), r)),
+                        _ => arith(&l, &r, |a, b| a + b),
+                    },
+                    "-" => arith(&l, &r, |a, b| a - b),
+                    "*" => arith(&l, &r, |a, b| a * b),
+                    "/" => arith(&l, &r, |a, b| a / b),
+                    _ => return Err(MqttError::from(format!("unsupported operator {}", op))),
+                }
+            }
+        })
+    }
+}
+
+///Looks up "a.b.0.c" in nested objects and arrays
+#[inline]
+pub fn get_path<'a>(ctx: &'a Value, path: &[String]) -> Option<&'a Value> {
+    path.iter().try_fold(ctx, |v, key| match v {
+        Value::Object(obj) => obj.get(key),
+        Value::Array(arr) => key.parse::<usize>().ok().and_then(|idx| arr.get(idx)),
+        _ => None,
+    })
+}
+
+///Strings are rendered without quotes, other values as JSON
+#[inline]
+pub fn to_string(v: &Value) -> String {
+    match v {
+        Value::String(s) => s.clone(),
+        _ => v.to_string(),
+    }
+}
+
+#[inline]
+fn is_true(v: &Value) -> bool {
+    matches!(v, Value::Bool(true))
+}
+
+#[inline]
+fn as_f64(v: &Value) -> Option<f64> {
+    match v {
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) => s.parse().ok(),
+        _ => None,
+    }
+}
+
+#[inline]
+fn compare(l: &Value, r: &Value) -> Option<Ordering> {
+    match (l, r) {
+        (Value::Null, Value::Null) => Some(Ordering::Equal),
+        (Value::Null, _) | (_, Value::Null) => None,
+        (Value::String(l), Value::String(r)) => Some(l.cmp(r)),
+        (Value::Bool(l), Value::Bool(r)) => Some(l.cmp(r)),
+        (Value::Number(_), _) | (_, Value::Number(_)) => as_f64(l)?.partial_cmp(&as_f64(r)?),
+        _ => (l == r).then_some(Ordering::Equal),
+    }
+}
+
+#[inline]
+fn arith(l: &Value, r: &Value, f: impl Fn(f64, f64) -> f64) -> Value {
+    match (as_f64(l), as_f64(r)) {
+        (Some(l), Some(r)) => serde_json::Number::from_f64(f(l, r)).map(Value::Number).unwrap_or(Value::Null),
+        _ => Value::Null,
+    }
+}
+
+fn tokenize(sql: &str) -> Result<Vec<(Token, usize, usize)>> {
+    let chars = sql.char_indices().collect::<Vec<_>>();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let (start, c) = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+        let token = match c {
+            ',' => Token::Comma,
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            '\'' | '"' => {
+                let mut s = String::new();
+                i += 1;
+                loop {
+                    match chars.get(i) {
+                        None => return Err(MqttError::from("unterminated string")),
+                        //Doubled quote is an escaped quote
+                        Some((_, ch)) if *ch == c && chars.get(i + 1).map(|(_, n)| *n) == Some(c) => {
+                            s.push(c);
+                            i += 1;
+                        }
+                        Some((_, ch)) if *ch == c => break,
+                        Some((_, ch)) => s.push(*ch),
+                    }
+                    i += 1;
+                }
+                if c == '\'' {
+                    Token::Str(s)
+                } else {
+                    Token::Quoted(s)
+                }
+            }
+            '0'..='9' => {
+                let mut end = i;
+                while chars.get(end + 1).map(|(_, ch)| ch.is_ascii_digit() || *ch == '.').unwrap_or(false) {
+                    end += 1;
+                }
+                let s = &sql[start..chars.get(end + 1).map(|(idx, _)| *idx).unwrap_or(sql.len())];
+                i = end;
+                Token::Number(s.parse().map_err(|_| MqttError::from(format!("invalid number {}", s)))?)
+            }
+            c if c.is_alphabetic() || c == '_' || c == '$' => {
+                let mut end = i;
+                while chars
+                    .get(end + 1)
+                    .map(|(_, ch)| ch.is_alphanumeric() || *ch == '_' || *ch == '.' || *ch == '$')
+                    .unwrap_or(false)
+                {
+                    end += 1;
+                }
+                let s = &sql[start..chars.get(end + 1).map(|(idx, _)| *idx).unwrap_or(sql.len())];
+                i = end;
+                Token::Ident(s.to_string())
+            }
+            _ => {
+                let two = chars.get(i + 1).map(|(_, n)| format!("{}{}", c, n)).unwrap_or_default();
+                if let Some(op) = ["!=", "<>", ">=", "<="].iter().copied().find(|op| *op == two) {
+                    i += 1;
+                    Token::Op(op)
+                } else if let Some(op) =
+                    ["=", ">", "<", "+", "-", "*", "/"].iter().copied().find(|op| op.starts_with(c))
+                {
+                    Token::Op(op)
+                } else {
+                    return Err(MqttError::from(format!("unexpected character '{}'", c)));
+                }
+            }
+        };
+        i += 1;
+        let end = chars.get(i).map(|(idx, _)| *idx).unwrap_or(sql.len());
+        tokens.push((token, start, end));
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    src: &'a str,
+    tokens: Vec<(Token, usize, usize)>,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    #[inline]
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(t, _, _)| t)
+    }
+
+    #[inline]
+    fn next(&mut self) -> Option<Token> {
+        let t = self.tokens.get(self.pos).map(|(t, _, _)| t.clone());
+        self.pos += 1;
+        t
+    }
+
+    #[inline]
+    fn is_keyword(&self, kw: &str) -> bool {
+        matches!(self.peek(), Some(Token::Ident(id)) if id.eq_ignore_ascii_case(kw))
+    }
+
+    #[inline]
+    fn eat_keyword(&mut self, kw: &str) -> bool {
+        if self.is_keyword(kw) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    #[inline]
+    fn expect_keyword(&mut self, kw: &str) -> Result<()> {
+        if self.eat_keyword(kw) {
+            Ok(())
+        } else {
+            Err(MqttError::from(format!("expected {}, got {:?}", kw, self.peek())))
+        }
+    }
+
+    fn parse_fields(&mut self) -> Result<Vec<Field>> {
+        let mut fields = Vec::new();
+        loop {
+            if self.peek() == Some(&Token::Op("*")) {
+                self.pos += 1;
+                fields.push(Field::All);
+            } else {
+                let start = self.tokens.get(self.pos).map(|(_, s, _)| *s).unwrap_or_default();
+                let expr = self.parse_or()?;
+                let end = self.tokens.get(self.pos - 1).map(|(_, _, e)| *e).unwrap_or(start);
+                let alias = if self.eat_keyword("AS") {
+                    match self.next() {
+                        Some(Token::Ident(alias)) | Some(Token::Quoted(alias)) => alias,
+                        t => return Err(MqttError::from(format!("expected alias after AS, got {:?}", t))),
+                    }
+                } else if let Expr::Path(path) = &expr {
+                    path.last().cloned().unwrap_or_default()
+                } else {
+                    self.src[start..end].trim().to_string()
+                };
+                fields.push(Field::Expr(expr, alias));
+            }
+            if self.peek() == Some(&Token::Comma) {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+        Ok(fields)
+    }
+
+    fn parse_or(&mut self) -> Result<Expr> {
+        let mut l = self.parse_and()?;
+        while self.eat_keyword("OR") {
+            l = Expr::Binary(Box::new(l), "OR", Box::new(self.parse_and()?));
+        }
+        Ok(l)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr> {
+        let mut l = self.parse_not()?;
+        while self.eat_keyword("AND") {
+            l = Expr::Binary(Box::new(l), "AND", Box::new(self.parse_not()?));
+        }
+        Ok(l)
+    }
+
+    fn parse_not(&mut self) -> Result<Expr> {
+        if self.eat_keyword("NOT") {
+            Ok(Expr::Not(Box::new(self.parse_not()?)))
+        } else {
+            self.parse_cmp()
+        }
+    }
+
+    fn parse_cmp(&mut self) -> Result<Expr> {
+        let l = self.parse_binary(&["+", "-"], Self::parse_term)?;
+        match self.peek() {
+            Some(Token::Op(op)) if ["=", "!=", "<>", ">", ">=", "<", "<="].contains(op) => {
+                let op = *op;
+                self.pos += 1;
+                let r = self.parse_binary(&["+", "-"], Self::parse_term)?;
+                Ok(Expr::Binary(Box::new(l), op, Box::new(r)))
+            }
+            _ => Ok(l),
+        }
+    }
+
+    fn parse_term(&mut self) -> Result<Expr> {
+        self.parse_binary(&["*", "/"], Self::parse_primary)
+    }
+
+    #[inline]
+    fn parse_binary(&mut self, ops: &[&str], next: fn(&mut Self) -> Result<Expr>) -> Result<Expr> {
+        let mut l = next(self)?;
+        while let Some(Token::Op(op)) = self.peek() {
+            if !ops.contains(op) {
+                break;
+            }
+            let op = *op;
+            self.pos += 1;
+            l = Expr::Binary(Box::new(l), op, Box::new(next(self)?));
+        }
+        Ok(l)
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr> {
+        match self.next() {
+            Some(Token::Number(n)) => {
+                Ok(Expr::Lit(serde_json::Number::from_f64(n).map(Value::Number).unwrap_or(Value::Null)))
+            }
+            Some(Token::Str(s)) | Some(Token::Quoted(s)) => Ok(Expr::Lit(Value::String(s))),
+            Some(Token::Op("-")) => {
+                Ok(Expr::Binary(Box::new(Expr::Lit(Value::from(0))), "-", Box::new(self.parse_primary()?)))
+            }
+            Some(Token::LParen) => {
+                let e = self.parse_or()?;
+                match self.next() {
+                    Some(Token::RParen) => Ok(e),
+                    t => Err(MqttError::from(format!("expected ')', got {:?}", t))),
+                }
+            }
+            Some(Token::Ident(id)) => Ok(match id.to_ascii_lowercase().as_str() {
+                "true" => Expr::Lit(Value::Bool(true)),
+                "false" => Expr::Lit(Value::Bool(false)),
+                "null" => Expr::Lit(Value::Null),
+                _ => Expr::Path(id.split('.').map(String::from).collect()),
+            }),
+            t => Err(MqttError::from(format!("unexpected token {:?}", t))),
+        }
+    }
+}
@@ -0,0 +1,35 @@
+use rmqtt::broker::hook::Priority;
+use rmqtt::schemars::JsonSchema;
+
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+#[schemars(crate = "rmqtt::schemars")]
+pub struct PluginConfig {
+    ///Path of the Rhai script
+    pub script: String,
+    ///Hook priority
+    #[serde(default = "PluginConfig::priority_default")]
+    pub priority: Priority,
+    ///Maximum number of operations of a single hook call, 0 means no limit
+    #[serde(default = "PluginConfig::max_operations_default")]
+    pub max_operations: u64,
+    ///Maximum length of strings, arrays and maps created by the script
+    #[serde(default = "PluginConfig::max_collection_size_default")]
+    pub max_collection_size: usize,
+    ///Reject the connection, subscription or publish if the script fails
+    #[serde(default)]
+    pub deny_if_error: bool,
+}
+
+impl PluginConfig {
+    fn priority_default() -> Priority {
+        50
+    }
+
+    fn max_operations_default() -> u64 {
+        100_000
+    }
+
+    fn max_collection_size_default() -> usize {
+        65536
+    }
+}
@@ -0,0 +1,351 @@
+#![deny(unsafe_code)]
+#[macro_use]
+extern crate serde;
+
+#[macro_use]
+extern crate rmqtt_macros;
+
+use std::ops::Deref;
+use std::sync::Arc;
+
+use rhai::{Dynamic, Map};
+
+use rmqtt::{
+    async_trait::async_trait,
+    bytes::Bytes,
+    log, schemars,
+    serde_json::{self, json},
+    tokio::sync::RwLock,
+    DashMap,
+};
+use rmqtt::{
+    broker::hook::{Handler, HookResult, Parameter, Register, ReturnType, Type},
+    broker::types::{AuthResult, PublishAclResult, SubscribeAckReason, SubscribeAclResult},
+    plugin::{PackageInfo, Plugin},
+    register, ClientId, Id, QoSEx, Result, Runtime, Session, TopicName,
+};
+
+use config::PluginConfig;
+use script::{Attrs, Reply, Script, Verdict};
+
+mod config;
+mod script;
+
+//Key of the script attributes in Session::extra_attrs
+const ATTRS_KEY: &str = "rmqtt-script-attrs";
+
+register!(ScriptPlugin::new);
+
+#[derive(Plugin)]
+struct ScriptPlugin {
+    runtime: &'static Runtime,
+    cfg: Arc<RwLock<PluginConfig>>,
+    register: Box<dyn Register>,
+    script: Arc<RwLock<Arc<Script>>>,
+    //Attributes set during authentication, moved to the session once it is connected
+    pending_attrs: Arc<DashMap<ClientId, Attrs>>,
+}
+
+impl ScriptPlugin {
+    #[inline]
+    async fn new(runtime: &'static Runtime, name: &'static str) -> Result<Self> {
+        let cfg = runtime.settings.plugins.load_config::<PluginConfig>(name)?;
+        log::info!("{} ScriptPlugin cfg: {:?}", name, cfg);
+        let script = Arc::new(RwLock::new(Arc::new(Script::load(&cfg)?)));
+        let register = runtime.extends.hook_mgr().await.register();
+        Ok(Self {
+            runtime,
+            cfg: Arc::new(RwLock::new(cfg)),
+            register,
+            script,
+            pending_attrs: Arc::new(DashMap::default()),
+        })
+    }
+}
+
+#[async_trait]
+impl Plugin for ScriptPlugin {
+    #[inline]
+    async fn init(&mut self) -> Result<()> {
+        log::info!("{} init", self.name());
+        let priority = self.cfg.read().await.priority;
+        for typ in [
+            Type::ClientAuthenticate,
+            Type::ClientSubscribeCheckAcl,
+            Type::MessagePublishCheckAcl,
+            Type::MessagePublish,
+            Type::ClientConnected,
+            Type::ClientDisconnected,
+        ] {
+            let handler = ScriptHandler {
+                cfg: self.cfg.clone(),
+                script: self.script.clone(),
+                pending_attrs: self.pending_attrs.clone(),
+            };
+            self.register.add_priority(typ, priority, Box::new(handler)).await;
+        }
+        Ok(())
+    }
+
+    #[inline]
+    async fn get_config(&self) -> Result<serde_json::Value> {
+        Ok(serde_json::to_value(self.cfg.read().await.deref())?)
+    }
+
+    #[inline]
+    async fn config_schema(&self) -> Option<serde_json::Value> {
+        serde_json::to_value(schemars::schema_for!(PluginConfig)).ok()
+    }
+
+    #[inline]
+    async fn load_config(&mut self) -> Result<()> {
+        let new_cfg = self.runtime.settings.plugins.load_config::<PluginConfig>(self.name())?;
+        //The running script is kept if the new one does not compile
+        let script = Script::load(&new_cfg)?;
+        *self.script.write().await = Arc::new(script);
+        *self.cfg.write().await = new_cfg;
+        log::debug!("load_config ok,  {:?}", self.cfg);
+        Ok(())
+    }
+
+    #[inline]
+    async fn start(&mut self) -> Result<()> {
+        log::info!("{} start", self.name());
+        self.register.start().await;
+        Ok(())
+    }
+
+    #[inline]
+    async fn stop(&mut self) -> Result<bool> {
+        log::info!("{} stop", self.name());
+        self.register.stop().await;
+        Ok(true)
+    }
+
+    #[inline]
+    async fn attrs(&self) -> serde_json::Value {
+        json!({
+            "pending_attrs": self.pending_attrs.len(),
+        })
+    }
+}
+
+struct ScriptHandler {
+    cfg: Arc<RwLock<PluginConfig>>,
+    script: Arc<RwLock<Arc<Script>>>,
+    pending_attrs: Arc<DashMap<ClientId, Attrs>>,
+}
+
+impl ScriptHandler {
+    #[inline]
+    fn client(id: &Id, attrs: Option<&Attrs>) -> Map {
+        let mut client = Map::new();
+        client.insert("clientid".into(), Dynamic::from(id.client_id.to_string()));
+        client.insert(
+            "username".into(),
+            id.username.as_ref().map(|u| Dynamic::from(u.to_string())).unwrap_or(Dynamic::UNIT),
+        );
+        client.insert(
+            "remote_addr".into(),
+            id.remote_addr.map(|addr| Dynamic::from(addr.to_string())).unwrap_or(Dynamic::UNIT),
+        );
+        client.insert("node".into(), Dynamic::from(id.node_id as i64));
+        let attrs = attrs
+            .map(|attrs| attrs.iter().map(|(k, v)| (k.into(), Dynamic::from(v.clone()))).collect::<Map>())
+            .unwrap_or_default();
+        client.insert("attrs".into(), Dynamic::from(attrs));
+        client
+    }
+
+    #[inline]
+    async fn session_client(s: &Session) -> Map {
+        Self::client(&s.id, s.extra_attrs.read().await.get::<Attrs>(ATTRS_KEY))
+    }
+
+    #[inline]
+    async fn merge_attrs(s: &Session, attrs: Option<Attrs>) {
+        if let Some(attrs) = attrs {
+            if let Some(s_attrs) =
+                s.extra_attrs.write().await.get_default_mut(ATTRS_KEY.into(), Attrs::default)
+            {
+                s_attrs.extend(attrs);
+            }
+        }
+    }
+
+    #[inline]
+    async fn call(&self, name: &str, args: impl rhai::FuncArgs) -> Option<Reply> {
+        let script = self.script.read().await.clone();
+        if !script.has(name) {
+            return None;
+        }
+        match script.call(name, args) {
+            Ok(reply) => Some(reply),
+            Err(e) => {
+                log::warn!("script error, {:?}", e);
+                let verdict =
+                    if self.cfg.read().await.deny_if_error { Verdict::Deny } else { Verdict::Ignore };
+                Some(Reply { verdict, attrs: None, topic: None, payload: None })
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Handler for ScriptHandler {
+    async fn hook(&self, param: &Parameter, acc: &mut Option<HookResult>) -> ReturnType {
+        match param {
+            Parameter::ClientAuthenticate(connect_info) => {
+                if matches!(
+                    acc,
+                    Some(HookResult::AuthResult(AuthResult::BadUsernameOrPassword))
+                        | Some(HookResult::AuthResult(AuthResult::NotAuthorized))
+                ) {
+                    return false;
+                }
+                let mut client = Self::client(connect_info.id(), None);
+                client.insert(
+                    "password".into(),
+                    connect_info
+                        .password()
+                        .map(|p| Dynamic::from(String::from_utf8_lossy(p).into_owned()))
+                        .unwrap_or(Dynamic::UNIT),
+                );
+                let reply = if let Some(reply) = self.call("on_client_authenticate", (client,)).await {
+                    reply
+                } else {
+                    return true;
+                };
+                if let Some(attrs) = reply.attrs {
+                    self.pending_attrs.insert(connect_info.id().client_id.clone(), attrs);
+                }
+                return match reply.verdict {
+                    Verdict::Allow => {
+                        *acc = Some(HookResult::AuthResult(AuthResult::Allow(false, None)));
+                        false
+                    }
+                    Verdict::Superuser => {
+                        *acc = Some(HookResult::AuthResult(AuthResult::Allow(true, None)));
+                        false
+                    }
+                    Verdict::Deny => {
+                        *acc = Some(HookResult::AuthResult(AuthResult::BadUsernameOrPassword));
+                        false
+                    }
+                    Verdict::Ignore => true,
+                };
+            }
+
+            Parameter::ClientSubscribeCheckAcl(session, subscribe) => {
+                if let Some(HookResult::SubscribeAclResult(acl_result)) = &*acc {
+                    if acl_result.failure() {
+                        return false;
+                    }
+                }
+                let client = Self::session_client(session).await;
+                let args = (client, subscribe.topic_filter.to_string(), subscribe.opts.qos().value() as i64);
+                let reply = if let Some(reply) = self.call("on_client_subscribe_check_acl", args).await {
+                    reply
+                } else {
+                    return true;
+                };
+                Self::merge_attrs(session, reply.attrs).await;
+                return match reply.verdict {
+                    Verdict::Allow | Verdict::Superuser => (
+                        false,
+                        Some(HookResult::SubscribeAclResult(SubscribeAclResult::new_success(
+                            subscribe.opts.qos(),
+                            None,
+                        ))),
+                    ),
+                    Verdict::Deny => (
+                        false,
+                        Some(HookResult::SubscribeAclResult(SubscribeAclResult::new_failure(
+                            SubscribeAckReason::NotAuthorized,
+                        ))),
+                    ),
+                    Verdict::Ignore => true,
+                };
+            }
+
+            Parameter::MessagePublishCheckAcl(session, publish) => {
+                if let Some(HookResult::PublishAclResult(PublishAclResult::Rejected(_))) = &*acc {
+                    return false;
+                }
+                let client = Self::session_client(session).await;
+                let args = (client, publish.topic().to_string());
+                let reply = if let Some(reply) = self.call("on_message_publish_check_acl", args).await {
+                    reply
+                } else {
+                    return true;
+                };
+                Self::merge_attrs(session, reply.attrs).await;
+                return match reply.verdict {
+                    Verdict::Allow | Verdict::Superuser => {
+                        *acc = Some(HookResult::PublishAclResult(PublishAclResult::Allow));
+                        false
+                    }
+                    Verdict::Deny => {
+                        *acc = Some(HookResult::PublishAclResult(PublishAclResult::Rejected(false)));
+                        false
+                    }
+                    Verdict::Ignore => true,
+                };
+            }
+
+            Parameter::MessagePublish(session, from, publish) => {
+                //Uses the message modified by the preceding hooks, if any
+                let publish = if let Some(HookResult::Publish(p)) = acc.as_ref() { p } else { *publish };
+                let client = match session {
+                    Some(s) => Self::session_client(s).await,
+                    None => Self::client(&from.id, None),
+                };
+                let mut msg = Map::new();
+                msg.insert("topic".into(), Dynamic::from(publish.topic.to_string()));
+                msg.insert(
+                    "payload".into(),
+                    Dynamic::from(String::from_utf8_lossy(&publish.payload).into_owned()),
+                );
+                msg.insert("qos".into(), Dynamic::from(publish.qos.value() as i64));
+                msg.insert("retain".into(), Dynamic::from(publish.retain));
+                if let Some(reply) = self.call("on_message_publish", (client, msg)).await {
+                    if let Some(s) = session {
+                        Self::merge_attrs(s, reply.attrs).await;
+                    }
+                    if reply.topic.is_some() || reply.payload.is_some() {
+                        let mut new_publish = publish.clone();
+                        if let Some(topic) = reply.topic {
+                            new_publish.topic = TopicName::from(topic);
+                        }
+                        if let Some(payload) = reply.payload {
+                            new_publish.payload = Bytes::from(payload);
+                        }
+                        *acc = Some(HookResult::Publish(new_publish));
+                        return true;
+                    }
+                }
+            }
+
+            Parameter::ClientConnected(session) => {
+                if let Some((_, attrs)) = self.pending_attrs.remove(&session.id.client_id) {
+                    Self::merge_attrs(session, Some(attrs)).await;
+                }
+                let client = Self::session_client(session).await;
+                if let Some(reply) = self.call("on_client_connected", (client,)).await {
+                    Self::merge_attrs(session, reply.attrs).await;
+                }
+            }
+
+            Parameter::ClientDisconnected(session, reason) => {
+                self.pending_attrs.remove(&session.id.client_id);
+                let client = Self::session_client(session).await;
+                let _ = self.call("on_client_disconnected", (client, reason.to_string())).await;
+            }
+
+            _ => {
+                log::error!("unimplemented, {:?}", param)
+            }
+        }
+        true
+    }
+}
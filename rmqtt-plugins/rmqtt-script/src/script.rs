@@ -0,0 +1,114 @@
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+
+use rhai::module_resolvers::DummyModuleResolver;
+use rhai::{Dynamic, Engine, FuncArgs, Map, Scope, AST};
+
+use rmqtt::{log, MqttError, Result};
+
+use crate::config::PluginConfig;
+
+pub(crate) type Attrs = HashMap<String, String>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Verdict {
+    Allow,
+    Superuser,
+    Deny,
+    ///Leave the decision to the following hooks
+    Ignore,
+}
+
+///What a script function returned, either a result string or a map with the optional keys
+///"result", "attrs", "topic" and "payload"
+#[derive(Debug)]
+pub(crate) struct Reply {
+    pub verdict: Verdict,
+    pub attrs: Option<Attrs>,
+    pub topic: Option<String>,
+    pub payload: Option<String>,
+}
+
+impl Reply {
+    fn ignore() -> Self {
+        Reply { verdict: Verdict::Ignore, attrs: None, topic: None, payload: None }
+    }
+
+    fn from_dynamic(d: Dynamic) -> Result<Self> {
+        if d.is_unit() {
+            return Ok(Self::ignore());
+        }
+        if d.is_string() {
+            let verdict = Self::verdict(&d.into_string().unwrap_or_default())?;
+            return Ok(Reply { verdict, ..Self::ignore() });
+        }
+        let mut map = d.try_cast::<Map>().ok_or_else(|| MqttError::from("unsupported return value"))?;
+        let verdict = match map.remove("result") {
+            Some(r) => Self::verdict(&r.into_string().map_err(MqttError::from)?)?,
+            None => Verdict::Ignore,
+        };
+        let attrs = map
+            .remove("attrs")
+            .and_then(|attrs| attrs.try_cast::<Map>())
+            .map(|attrs| attrs.into_iter().map(|(k, v)| (k.to_string(), v.to_string())).collect::<Attrs>());
+        let topic = map.remove("topic").map(|t| t.to_string());
+        let payload = map.remove("payload").map(|p| p.to_string());
+        Ok(Reply { verdict, attrs, topic, payload })
+    }
+
+    #[inline]
+    fn verdict(r: &str) -> Result<Verdict> {
+        match r {
+            "allow" => Ok(Verdict::Allow),
+            "superuser" => Ok(Verdict::Superuser),
+            "deny" => Ok(Verdict::Deny),
+            "ignore" => Ok(Verdict::Ignore),
+            _ => Err(MqttError::from(format!("unsupported result, {}", r))),
+        }
+    }
+}
+
+pub(crate) struct Script {
+    engine: Engine,
+    ast: AST,
+    fns: HashSet<String>,
+}
+
+impl Script {
+    pub fn load(cfg: &PluginConfig) -> Result<Script> {
+        let mut engine = Engine::new();
+        //Sandbox, no module imports and bounded execution
+        engine.set_module_resolver(DummyModuleResolver::new());
+        engine.disable_symbol("eval");
+        engine.set_max_operations(cfg.max_operations);
+        engine.set_max_string_size(cfg.max_collection_size);
+        engine.set_max_array_size(cfg.max_collection_size);
+        engine.set_max_map_size(cfg.max_collection_size);
+        engine.on_print(|s| log::info!("script: {}", s));
+        engine.on_debug(|s, _, pos| log::debug!("script: {:?} {}", pos, s));
+
+        let ast = engine
+            .compile_file(PathBuf::from(&cfg.script))
+            .map_err(|e| MqttError::from(format!("compile script {} error, {}", cfg.script, e)))?;
+        let fns = ast.iter_functions().map(|f| f.name.to_string()).collect::<HashSet<_>>();
+        log::info!("script {} loaded, functions: {:?}", cfg.script, fns);
+        Ok(Script { engine, ast, fns })
+    }
+
+    #[inline]
+    pub fn has(&self, name: &str) -> bool {
+        self.fns.contains(name)
+    }
+
+    ///Calls the function if the script defines it
+    pub fn call(&self, name: &str, args: impl FuncArgs) -> Result<Reply> {
+        if !self.has(name) {
+            return Ok(Reply::ignore());
+        }
+        let d = self
+            .engine
+            .call_fn::<Dynamic>(&mut Scope::new(), &self.ast, name, args)
+            .map_err(|e| MqttError::from(format!("call {} error, {}", name, e)))?;
+        Reply::from_dynamic(d)
+    }
+}
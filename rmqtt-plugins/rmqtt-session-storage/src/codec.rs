@@ -0,0 +1,173 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use rmqtt::{anyhow, bincode, futures, log, MqttError, Result};
+
+use rmqtt_storage_ext::{StorageList, StorageMap};
+
+//"RMQS", the records stored before the format tags do not start with it
+const MAGIC: u32 = 0x524d_5153;
+
+///Serialization format of the session records, the format tag is stored with each record, the records
+///of any format are read back. The fields added to the stored types are read from the older records
+///of msgpack and cbor, bincode is not self-describing.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Bincode,
+    Msgpack,
+    Cbor,
+}
+
+impl Format {
+    #[inline]
+    fn tag(self) -> u8 {
+        match self {
+            Format::Bincode => 1,
+            Format::Msgpack => 2,
+            Format::Cbor => 3,
+        }
+    }
+
+    #[inline]
+    fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            1 => Some(Format::Bincode),
+            2 => Some(Format::Msgpack),
+            3 => Some(Format::Cbor),
+            _ => None,
+        }
+    }
+
+    #[inline]
+    pub(crate) fn encode<T: Serialize + ?Sized>(self, v: &T) -> Result<Record> {
+        let data = match self {
+            Format::Bincode => bincode::serialize(v).map_err(anyhow::Error::new)?,
+            Format::Msgpack => rmp_serde::to_vec_named(v).map_err(anyhow::Error::new)?,
+            Format::Cbor => {
+                let mut data = Vec::new();
+                ciborium::into_writer(v, &mut data).map_err(anyhow::Error::new)?;
+                data
+            }
+        };
+        Ok(Record { magic: MAGIC, format: self.tag(), data })
+    }
+
+    #[inline]
+    pub(crate) async fn map_insert<T: Serialize + ?Sized>(
+        self,
+        m: &StorageMap,
+        key: &[u8],
+        v: &T,
+    ) -> Result<()> {
+        m.insert(key, &self.encode(v)?).await?;
+        Ok(())
+    }
+
+    #[inline]
+    pub(crate) async fn list_push<T: Serialize + ?Sized>(self, l: &StorageList, v: &T) -> Result<()> {
+        l.push::<Record>(&self.encode(v)?).await?;
+        Ok(())
+    }
+
+    #[inline]
+    pub(crate) async fn list_push_limit<T: Serialize + ?Sized>(
+        self,
+        l: &StorageList,
+        v: &T,
+        limit: usize,
+    ) -> Result<()> {
+        l.push_limit::<Record>(&self.encode(v)?, limit, true).await?;
+        Ok(())
+    }
+
+    ///Pushes the values with one request, the oldest values beyond the limit are removed. The list is
+    ///cleared if all its values are, otherwise the overflow is popped with concurrent requests,
+    ///pipelined by the connection of the storage
+    #[inline]
+    pub(crate) async fn list_pushs_limit<T: Serialize>(
+        self,
+        l: &StorageList,
+        vs: &[T],
+        limit: usize,
+    ) -> Result<()> {
+        let limit = limit.max(1);
+        let vs = &vs[vs.len().saturating_sub(limit)..];
+        if vs.is_empty() {
+            return Ok(());
+        }
+        let records = vs.iter().map(|v| self.encode(v)).collect::<Result<Vec<Record>>>()?;
+        let len = l.len().await?;
+        let overflow = (len + records.len()).saturating_sub(limit);
+        if overflow > 0 && overflow >= len {
+            l.clear().await?;
+            l.pushs::<Record>(records).await?;
+        } else {
+            l.pushs::<Record>(records).await?;
+            futures::future::try_join_all((0..overflow).map(|_| l.pop::<Record>())).await?;
+        }
+        Ok(())
+    }
+}
+
+///A stored record, tagged with its format
+#[derive(Deserialize, Serialize)]
+pub(crate) struct Record {
+    magic: u32,
+    format: u8,
+    data: Vec<u8>,
+}
+
+impl Record {
+    #[inline]
+    pub(crate) fn decode<T: DeserializeOwned>(&self) -> Result<T> {
+        let format = match Format::from_tag(self.format) {
+            Some(format) if self.magic == MAGIC => format,
+            _ => return Err(MqttError::from(format!("unknown record format, {}", self.format))),
+        };
+        let v = match format {
+            Format::Bincode => bincode::deserialize(&self.data).map_err(anyhow::Error::new)?,
+            Format::Msgpack => rmp_serde::from_slice(&self.data).map_err(anyhow::Error::new)?,
+            Format::Cbor => ciborium::from_reader(self.data.as_slice()).map_err(anyhow::Error::new)?,
+        };
+        Ok(v)
+    }
+}
+
+//The record of the key, or the bincode value stored before the format tags
+#[inline]
+pub(crate) async fn map_get<T: DeserializeOwned + Sync + Send>(
+    m: &StorageMap,
+    key: &[u8],
+) -> Result<Option<T>> {
+    match m.get::<_, Record>(key).await {
+        Ok(None) => return Ok(None),
+        Ok(Some(r)) => match r.decode() {
+            Ok(v) => return Ok(Some(v)),
+            Err(e) => log::debug!("{:?} not a tagged record, {:?}", key, e),
+        },
+        Err(e) => log::debug!("{:?} not a tagged record, {:?}", key, e),
+    }
+    Ok(m.get::<_, T>(key).await?)
+}
+
+//The records of the list, a list stored before the format tags is rewritten in the format, the new
+//records are appended to it in the format
+pub(crate) async fn list_all<T: Serialize + DeserializeOwned + Sync + Send>(
+    l: &StorageList,
+    format: Format,
+) -> Result<Vec<T>> {
+    if let Ok(records) = l.all::<Record>().await {
+        if let Ok(vals) = records.iter().map(|r| r.decode()).collect::<Result<Vec<T>>>() {
+            return Ok(vals);
+        }
+    }
+    let vals = l.all::<T>().await?;
+    log::info!("{:?} rewrite {} records stored before the format tags", l.name(), vals.len());
+    l.clear().await?;
+    for v in vals.iter() {
+        format.list_push(l, v).await?;
+    }
+    Ok(vals)
+}
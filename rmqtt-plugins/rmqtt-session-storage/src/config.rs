@@ -1,11 +1,39 @@
+use std::time::Duration;
+
 use rmqtt::serde_json;
+use rmqtt::settings::deserialize_duration;
+
+use rmqtt_storage_ext::Config;
 
-use rmqtt_storage::Config;
+use crate::codec::Format;
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct PluginConfig {
     #[serde(default)]
     pub storage: Config,
+
+    #[serde(default)]
+    pub write_behind: WriteBehind,
+
+    ///The payloads of the offline and inflight messages are encrypted, see the encryption keys of rmqtt.toml
+    #[serde(default)]
+    pub encrypt: bool,
+
+    #[serde(default)]
+    pub tiering: Tiering,
+
+    ///Serialization format of the session records, bincode, msgpack or cbor, the records stored in
+    ///another format, or before the format tags, are still read
+    #[serde(default)]
+    pub format: Format,
+
+    #[serde(default)]
+    pub gc: Gc,
+
+    ///The copies of an offline message by the overlapping subscriptions of a session are stored and
+    ///delivered once
+    #[serde(default = "PluginConfig::offline_dedup_default")]
+    pub offline_dedup: bool,
 }
 
 impl PluginConfig {
@@ -13,4 +41,153 @@ impl PluginConfig {
     pub fn to_json(&self) -> serde_json::Value {
         serde_json::json!(self)
     }
+
+    fn offline_dedup_default() -> bool {
+        true
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct WriteBehind {
+    ///Buffer session changes in memory and write them to the storage in batches
+    #[serde(default = "WriteBehind::enable_default")]
+    pub enable: bool,
+
+    ///Interval between two flushes of the write-behind buffer
+    #[serde(default = "WriteBehind::flush_interval_default", deserialize_with = "deserialize_duration")]
+    pub flush_interval: Duration,
+
+    ///Maximum number of dirty sessions written in one batch
+    #[serde(default = "WriteBehind::max_batch_size_default")]
+    pub max_batch_size: usize,
+}
+
+impl Default for WriteBehind {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            enable: Self::enable_default(),
+            flush_interval: Self::flush_interval_default(),
+            max_batch_size: Self::max_batch_size_default(),
+        }
+    }
+}
+
+impl WriteBehind {
+    fn enable_default() -> bool {
+        true
+    }
+
+    fn flush_interval_default() -> Duration {
+        Duration::from_millis(500)
+    }
+
+    fn max_batch_size_default() -> usize {
+        1000
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Tiering {
+    ///The messages evicted from the full in-memory message queue of a session are spilled to the storage,
+    ///the queue keeps the newest messages
+    #[serde(default)]
+    pub enable: bool,
+
+    ///Maximum number of spilled messages of a session, the oldest ones are dropped beyond it
+    #[serde(default = "Tiering::max_spilled_default")]
+    pub max_spilled: usize,
+
+    ///Interval between two restorations of the spilled messages into the drained queues
+    #[serde(default = "Tiering::restore_interval_default", deserialize_with = "deserialize_duration")]
+    pub restore_interval: Duration,
+
+    ///Maximum number of spilled messages of a session restored at once
+    #[serde(default = "Tiering::restore_batch_size_default")]
+    pub restore_batch_size: usize,
+}
+
+impl Default for Tiering {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            enable: false,
+            max_spilled: Self::max_spilled_default(),
+            restore_interval: Self::restore_interval_default(),
+            restore_batch_size: Self::restore_batch_size_default(),
+        }
+    }
+}
+
+impl Tiering {
+    fn max_spilled_default() -> usize {
+        1_000_000
+    }
+
+    fn restore_interval_default() -> Duration {
+        Duration::from_secs(1)
+    }
+
+    fn restore_batch_size_default() -> usize {
+        1000
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Gc {
+    ///The stored sessions past their expiry and the orphan message lists are removed in the background,
+    ///the lists beyond their limit are compacted
+    #[serde(default = "Gc::enable_default")]
+    pub enable: bool,
+
+    ///Interval between two passes of the collector
+    #[serde(default = "Gc::interval_default", deserialize_with = "deserialize_duration")]
+    pub interval: Duration,
+
+    ///A session is removed this time after its expiry
+    #[serde(default = "Gc::safety_margin_default", deserialize_with = "deserialize_duration")]
+    pub safety_margin: Duration,
+
+    ///Number of the keys scanned before a pause
+    #[serde(default = "Gc::batch_size_default")]
+    pub batch_size: usize,
+
+    ///Pause between two batches of keys scanned, the rate limit of the collector
+    #[serde(default = "Gc::batch_interval_default", deserialize_with = "deserialize_duration")]
+    pub batch_interval: Duration,
+}
+
+impl Default for Gc {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            enable: Self::enable_default(),
+            interval: Self::interval_default(),
+            safety_margin: Self::safety_margin_default(),
+            batch_size: Self::batch_size_default(),
+            batch_interval: Self::batch_interval_default(),
+        }
+    }
+}
+
+impl Gc {
+    fn enable_default() -> bool {
+        true
+    }
+
+    fn interval_default() -> Duration {
+        Duration::from_secs(3600)
+    }
+
+    fn safety_margin_default() -> Duration {
+        Duration::from_secs(3600)
+    }
+
+    fn batch_size_default() -> usize {
+        100
+    }
+
+    fn batch_interval_default() -> Duration {
+        Duration::from_millis(100)
+    }
 }
@@ -0,0 +1,179 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use rmqtt::{
+    serde_json::{self, json},
+    DashMap, From, Publish,
+};
+
+use crate::session::StoredKey;
+
+//Offline message deduplication. A persistent session with overlapping subscriptions, a/+ and a/b,
+//receives a message once per matching subscription, and would store it as many times. The offline
+//messages carry no message id, a message is identified by its publisher, its creation time, its
+//packet id, its topic and its payload, the same for each copy of the fan-in. The ids of the last
+//offline messages of a session, as many as its list holds, are kept while it is offline, a copy is
+//not stored again. The messages stored before are deduplicated when the session is rebuilt.
+pub(crate) struct OfflineDedup {
+    enable: bool,
+    //list stored key => the ids of the recent offline messages, and their order, oldest first
+    recents: DashMap<StoredKey, (HashSet<u64>, VecDeque<u64>)>,
+    //copies not stored
+    suppressed: AtomicUsize,
+    //stored copies not delivered by the rebuilt sessions
+    suppressed_rebuild: AtomicUsize,
+}
+
+impl OfflineDedup {
+    #[inline]
+    pub(crate) fn new(enable: bool) -> Self {
+        Self {
+            enable,
+            recents: DashMap::default(),
+            suppressed: AtomicUsize::new(0),
+            suppressed_rebuild: AtomicUsize::new(0),
+        }
+    }
+
+    //Whether the message is a copy of a recent offline message of the session, it is recorded if not
+    #[inline]
+    pub(crate) fn is_duplicate(&self, list_stored_key: &StoredKey, id: u64, limit: usize) -> bool {
+        if !self.enable {
+            return false;
+        }
+        let mut entry = self.recents.entry(list_stored_key.clone()).or_default();
+        let (ids, order) = entry.value_mut();
+        if ids.contains(&id) {
+            self.suppressed.fetch_add(1, Ordering::Relaxed);
+            return true;
+        }
+        while order.len() >= limit.max(1) {
+            if let Some(oldest) = order.pop_front() {
+                ids.remove(&oldest);
+            }
+        }
+        ids.insert(id);
+        order.push_back(id);
+        false
+    }
+
+    //Removes the copies from the offline messages of a rebuilt session, the first is kept
+    #[inline]
+    pub(crate) fn rebuild(&self, msgs: &mut Vec<(From, Publish)>) -> usize {
+        if !self.enable {
+            return 0;
+        }
+        let len = msgs.len();
+        let mut ids = HashSet::with_capacity(len);
+        msgs.retain(|(f, p)| ids.insert(message_id(f, p)));
+        let suppressed = len - msgs.len();
+        self.suppressed_rebuild.fetch_add(suppressed, Ordering::Relaxed);
+        suppressed
+    }
+
+    //The session is online again, or removed
+    #[inline]
+    pub(crate) fn discard(&self, list_stored_key: &StoredKey) {
+        self.recents.remove(list_stored_key);
+    }
+
+    #[inline]
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        json!({
+            "enable": self.enable,
+            "sessions": self.recents.len(),
+            "suppressed": self.suppressed.load(Ordering::Relaxed),
+            "suppressed_rebuild": self.suppressed_rebuild.load(Ordering::Relaxed),
+        })
+    }
+}
+
+//The id of an offline message, of its plaintext payload
+#[inline]
+pub(crate) fn message_id(f: &From, p: &Publish) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    f.node_id.hash(&mut hasher);
+    f.client_id.hash(&mut hasher);
+    p.create_time.hash(&mut hasher);
+    p.packet_id.hash(&mut hasher);
+    p.topic.hash(&mut hasher);
+    p.payload.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU16;
+
+    use rmqtt::{ntex::util::Bytes, From, Id, Publish, QoS};
+
+    use super::{message_id, OfflineDedup};
+    use crate::session::StoredKey;
+
+    fn message(client_id: &str, packet_id: u16, payload: &'static [u8]) -> (From, Publish) {
+        let publish = Publish {
+            dup: false,
+            retain: false,
+            qos: QoS::AtLeastOnce,
+            topic: "a/b".into(),
+            packet_id: NonZeroU16::new(packet_id),
+            payload: Bytes::from_static(payload),
+            properties: Default::default(),
+            create_time: 1000,
+        };
+        (From::from_custom(Id::new(1, None, None, client_id.into(), None)), publish)
+    }
+
+    #[test]
+    fn test_store() {
+        let dedup = OfflineDedup::new(true);
+        let key = StoredKey::from_static(b"c1");
+        let (f, p) = message("c0", 1, b"1");
+        let id = message_id(&f, &p);
+        //the copies of the fan-in, a/+ and a/b
+        assert!(!dedup.is_duplicate(&key, id, 2));
+        assert!(dedup.is_duplicate(&key, id, 2));
+        //another publisher, another message
+        let (f2, p2) = message("c2", 1, b"1");
+        assert_ne!(message_id(&f2, &p2), id);
+        //the oldest id is dropped beyond the limit
+        assert!(!dedup.is_duplicate(&key, 2, 2));
+        assert!(!dedup.is_duplicate(&key, 3, 2));
+        assert!(!dedup.is_duplicate(&key, id, 2));
+        assert!(dedup.is_duplicate(&key, 3, 2));
+        //the session is online again
+        dedup.discard(&key);
+        assert!(!dedup.is_duplicate(&key, 3, 2));
+        let json = dedup.to_json();
+        assert_eq!(json["sessions"], 1);
+        assert_eq!(json["suppressed"], 2);
+        assert_eq!(json["suppressed_rebuild"], 0);
+
+        let disabled = OfflineDedup::new(false);
+        assert!(!disabled.is_duplicate(&key, id, 2));
+        assert!(!disabled.is_duplicate(&key, id, 2));
+    }
+
+    #[test]
+    fn test_rebuild() {
+        let dedup = OfflineDedup::new(true);
+        let mut msgs = vec![
+            message("c0", 1, b"1"),
+            message("c0", 1, b"1"),
+            message("c0", 2, b"2"),
+            message("c0", 1, b"1"),
+            message("c0", 1, b"3"),
+        ];
+        assert_eq!(dedup.rebuild(&mut msgs), 2);
+        let payloads = msgs.iter().map(|(_, p)| p.payload.as_ref()).collect::<Vec<_>>();
+        assert_eq!(payloads, vec![&b"1"[..], &b"2"[..], &b"3"[..]]);
+        assert_eq!(dedup.to_json()["suppressed_rebuild"], 2);
+
+        let disabled = OfflineDedup::new(false);
+        let mut msgs = vec![message("c0", 1, b"1"), message("c0", 1, b"1")];
+        assert_eq!(disabled.rebuild(&mut msgs), 0);
+        assert_eq!(msgs.len(), 2);
+    }
+}
@@ -0,0 +1,297 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicI64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use rmqtt::{broker::types::DisconnectInfo, chrono, ClientId, Id, Result, Runtime, TimestampMillis};
+use rmqtt::{
+    futures::StreamExt,
+    log,
+    serde_json::{self, json},
+    tokio,
+};
+
+use rmqtt_storage_ext::{DefaultStorageDB, StorageList, StorageMap};
+
+use crate::codec::{self, Record};
+use crate::config::Gc as GcConfig;
+use crate::session::{Basic, StoredKey, BASIC, DISCONNECT_INFO, LAST_TIME};
+use crate::tiering::{SpilledMessage, Tiering};
+use crate::writer::SessionWriter;
+use crate::{
+    list_stored_key_to_id_bytes, make_list_stored_key, make_map_stored_key, map_stored_key_to_id_bytes,
+    session_expiry_interval, SPILL_PREFIX,
+};
+
+#[derive(Default)]
+struct GcCounters {
+    runs: AtomicUsize,
+    //keys scanned, maps and lists
+    scanned: AtomicUsize,
+    //sessions past their expiry removed, with their offline messages
+    sessions_removed: AtomicUsize,
+    //offline and spilled message lists without session removed
+    lists_removed: AtomicUsize,
+    //lists beyond their limit, and the oldest messages removed of them
+    lists_compacted: AtomicUsize,
+    messages_compacted: AtomicUsize,
+    last_run_at: AtomicI64,
+    last_run_cost: AtomicI64,
+}
+
+//Garbage collection of the storage. The records of the sessions that will never return, those of
+//random client ids for instance, are left when the node is down at their expiry. The collector scans
+//the keys by batches of `gc.batch_size` with a pause of `gc.batch_interval` between them, removes the
+//sessions past their expiry plus `gc.safety_margin`, those not in memory, and the message lists of no
+//session, and trims the lists beyond their limit, the oldest messages first.
+pub(crate) struct Gc {
+    cfg: GcConfig,
+    storage_db: DefaultStorageDB,
+    writer: Arc<SessionWriter>,
+    tiering: Arc<Tiering>,
+    max_spilled: usize,
+    counters: GcCounters,
+}
+
+impl Gc {
+    #[inline]
+    pub(crate) fn new(
+        cfg: GcConfig,
+        storage_db: DefaultStorageDB,
+        writer: Arc<SessionWriter>,
+        tiering: Arc<Tiering>,
+        max_spilled: usize,
+    ) -> Arc<Self> {
+        Arc::new(Self { cfg, storage_db, writer, tiering, max_spilled, counters: GcCounters::default() })
+    }
+
+    #[inline]
+    pub(crate) fn start(self: &Arc<Self>) {
+        if !self.cfg.enable {
+            return;
+        }
+        let gc = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(gc.cfg.interval).await;
+                if let Err(e) = gc.run().await {
+                    log::warn!("session storage gc error, {:?}", e);
+                }
+            }
+        });
+    }
+
+    //One pass over the sessions, then over the message lists
+    async fn run(&self) -> Result<()> {
+        let started_at = chrono::Local::now().timestamp_millis();
+        let mut scanned = 0;
+        let alives = self.collect_sessions(&mut scanned).await?;
+        self.collect_lists(&alives, &mut scanned).await?;
+
+        let c = &self.counters;
+        c.runs.fetch_add(1, Ordering::SeqCst);
+        c.last_run_at.store(started_at, Ordering::SeqCst);
+        c.last_run_cost.store(chrono::Local::now().timestamp_millis() - started_at, Ordering::SeqCst);
+        log::info!(
+            "session storage gc, scanned: {}, sessions removed: {}, lists removed: {}, messages compacted: {}",
+            scanned,
+            c.sessions_removed.load(Ordering::SeqCst),
+            c.lists_removed.load(Ordering::SeqCst),
+            c.messages_compacted.load(Ordering::SeqCst)
+        );
+        Ok(())
+    }
+
+    //Removes the sessions expired, returns those kept, id key => (client id, max_mqueue_len)
+    async fn collect_sessions(&self, scanned: &mut usize) -> Result<HashMap<StoredKey, (ClientId, usize)>> {
+        let mut alives = HashMap::new();
+        let mut expireds = Vec::new();
+        {
+            let mut storage_db = self.storage_db.clone();
+            let mut map_iter = storage_db.map_iter().await?;
+            while let Some(m) = map_iter.next().await {
+                self.pace(scanned).await;
+                let m = match m {
+                    Ok(m) => m,
+                    Err(e) => {
+                        log::warn!("session storage gc, iterate session info error, {:?}", e);
+                        continue;
+                    }
+                };
+                let id_key = StoredKey::from(map_stored_key_to_id_bytes(m.name()).to_vec());
+                if self.writer.is_dirty(&id_key) {
+                    continue;
+                }
+                match self.check_session(&id_key, &m).await {
+                    Some(alive) => {
+                        alives.insert(id_key, alive);
+                    }
+                    None => expireds.push(id_key),
+                }
+            }
+        }
+
+        for id_key in expireds {
+            log::debug!("session storage gc, remove session {:?}", id_key);
+            self.writer.discard(&id_key).await;
+            self.storage_db.map_remove(make_map_stored_key(&id_key)).await?;
+            self.storage_db.list_remove(make_list_stored_key(&id_key)).await?;
+            self.counters.sessions_removed.fetch_add(1, Ordering::SeqCst);
+        }
+        Ok(alives)
+    }
+
+    //The client id and the max_mqueue_len of a session kept, None if it is past its expiry plus the
+    //safety margin, or has no basic info
+    async fn check_session(&self, id_key: &[u8], m: &StorageMap) -> Option<(ClientId, usize)> {
+        let basic = match codec::map_get::<Basic>(m, BASIC).await {
+            Ok(Some(basic)) => basic,
+            _ => return None,
+        };
+        let id = basic.id;
+        let entry = Runtime::instance().extends.shared().await.entry(id.clone());
+        let in_memory = entry.session().map(|s| s.id.to_string().as_bytes() == id_key).unwrap_or_default();
+        let listen_cfg =
+            id.local_addr.and_then(|addr| Runtime::instance().settings.listeners.get(addr.port()));
+        let max_mqueue_len = listen_cfg.as_ref().map(|l| l.max_mqueue_len).unwrap_or(usize::MAX);
+        if in_memory {
+            return Some((id.client_id, max_mqueue_len));
+        }
+
+        let last_time =
+            codec::map_get::<TimestampMillis>(m, LAST_TIME).await.ok().flatten().unwrap_or_default();
+        let disconnect_info = codec::map_get::<DisconnectInfo>(m, DISCONNECT_INFO).await.ok().flatten();
+        let remaining = match listen_cfg {
+            Some(listen_cfg) => {
+                let fitter = Runtime::instance().extends.fitter_mgr().await.create(
+                    basic.conn_info,
+                    id.clone(),
+                    listen_cfg,
+                );
+                session_expiry_interval(fitter.as_ref(), disconnect_info.as_ref(), last_time).await
+            }
+            //The listener is gone, the session is not rebuilt
+            None => last_time - chrono::Local::now().timestamp_millis(),
+        };
+        if remaining + self.cfg.safety_margin.as_millis() as TimestampMillis > 0 {
+            Some((id.client_id, max_mqueue_len))
+        } else {
+            None
+        }
+    }
+
+    //Removes the lists of no session kept and compacts the others
+    async fn collect_lists(
+        &self,
+        alives: &HashMap<StoredKey, (ClientId, usize)>,
+        scanned: &mut usize,
+    ) -> Result<()> {
+        let alive_clients = alives.values().map(|(client_id, _)| client_id.clone()).collect::<HashSet<_>>();
+        let mut orphans = Vec::new();
+        let mut oversizes = Vec::new();
+        {
+            let mut storage_db = self.storage_db.clone();
+            let mut list_iter = storage_db.list_iter().await?;
+            while let Some(l) = list_iter.next().await {
+                self.pace(scanned).await;
+                let l = match l {
+                    Ok(l) => l,
+                    Err(e) => {
+                        log::warn!("session storage gc, iterate message list error, {:?}", e);
+                        continue;
+                    }
+                };
+                let limit = if l.name().starts_with(SPILL_PREFIX) {
+                    let client_id =
+                        ClientId::from(String::from_utf8_lossy(&l.name()[SPILL_PREFIX.len()..]).into_owned());
+                    if !alive_clients.contains(&client_id) && !self.in_memory(&client_id).await {
+                        orphans.push((l.name().to_vec(), Some(client_id)));
+                        continue;
+                    }
+                    self.max_spilled
+                } else {
+                    let id_key = list_stored_key_to_id_bytes(l.name());
+                    match alives.get(id_key) {
+                        Some((_, max_mqueue_len)) => *max_mqueue_len,
+                        None if self.writer.is_dirty(id_key) => continue,
+                        None => {
+                            orphans.push((l.name().to_vec(), None));
+                            continue;
+                        }
+                    }
+                };
+                match l.len().await {
+                    Ok(len) if len > limit => oversizes.push((l.name().to_vec(), len - limit)),
+                    Ok(_) => {}
+                    Err(e) => log::warn!("session storage gc, {:?} len error, {:?}", l.name(), e),
+                }
+            }
+        }
+
+        for (name, client_id) in orphans {
+            log::debug!("session storage gc, remove list {:?}", StoredKey::from(name.clone()));
+            match client_id {
+                Some(client_id) => self.tiering.discard(&client_id).await,
+                None => self.storage_db.list_remove(name).await?,
+            }
+            self.counters.lists_removed.fetch_add(1, Ordering::SeqCst);
+        }
+
+        for (name, n) in oversizes {
+            let l = self.storage_db.list(name, None).await?;
+            let removed = Self::compact(&l, n).await?;
+            self.counters.lists_compacted.fetch_add(1, Ordering::SeqCst);
+            self.counters.messages_compacted.fetch_add(removed, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+
+    //Removes the n oldest messages of the list, the spilled messages are bincode, the offline
+    //messages are tagged records
+    async fn compact(l: &StorageList, n: usize) -> Result<usize> {
+        let spilled = l.name().starts_with(SPILL_PREFIX);
+        let mut removed = 0;
+        for _ in 0..n {
+            let popped = if spilled {
+                l.pop::<SpilledMessage>().await?.is_some()
+            } else {
+                l.pop::<Record>().await?.is_some()
+            };
+            if !popped {
+                break;
+            }
+            removed += 1;
+        }
+        Ok(removed)
+    }
+
+    #[inline]
+    async fn in_memory(&self, client_id: &ClientId) -> bool {
+        let id = Id::new(Runtime::instance().node.id(), None, None, client_id.clone(), None);
+        Runtime::instance().extends.shared().await.entry(id).session().is_some()
+    }
+
+    //Pauses after each batch of keys
+    #[inline]
+    async fn pace(&self, scanned: &mut usize) {
+        *scanned += 1;
+        self.counters.scanned.fetch_add(1, Ordering::Relaxed);
+        if *scanned % self.cfg.batch_size.max(1) == 0 {
+            tokio::time::sleep(self.cfg.batch_interval).await;
+        }
+    }
+
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        let c = &self.counters;
+        json!({
+            "enable": self.cfg.enable,
+            "runs": c.runs.load(Ordering::Relaxed),
+            "scanned": c.scanned.load(Ordering::Relaxed),
+            "sessions_removed": c.sessions_removed.load(Ordering::Relaxed),
+            "lists_removed": c.lists_removed.load(Ordering::Relaxed),
+            "lists_compacted": c.lists_compacted.load(Ordering::Relaxed),
+            "messages_compacted": c.messages_compacted.load(Ordering::Relaxed),
+            "last_run_at": c.last_run_at.load(Ordering::Relaxed),
+            "last_run_cost": c.last_run_cost.load(Ordering::Relaxed),
+        })
+    }
+}
@@ -22,26 +22,38 @@ use rmqtt::{
 };
 
 use rmqtt::{
+    broker::encryption,
     broker::fitter::Fitter,
+    broker::health::{HealthProbe, ReadinessCheck},
     broker::hook::{Handler, HookResult, Parameter, Register, ReturnType, Type},
     broker::inflight::InflightMessage,
     broker::types::DisconnectInfo,
     plugin::{PackageInfo, Plugin},
-    register, ClientId, From, MqttError, Publish, Result, Runtime, Session, SessionState, SessionSubMap,
+    register, ClientId, From, Publish, Reason, Result, Runtime, Session, SessionState, SessionSubMap,
     SessionSubs, TimestampMillis,
 };
 
-use rmqtt_storage::{init_db, DefaultStorageDB, List, Map, StorageType};
+use rmqtt_storage_ext::{init_db, DefaultStorageDB};
 
 use config::PluginConfig;
+use dedup::OfflineDedup;
+use gc::Gc;
 use session::{Basic, StorageSessionManager, StoredSessionInfo, StoredSessionInfos};
-use session::{StoredKey, BASIC, DISCONNECT_INFO, INFLIGHT_MESSAGES, LAST_TIME, SESSION_SUB_MAP};
+use session::{StoredKey, BASIC, DISCONNECT_INFO, INFLIGHT_MESSAGES, LAST_TIME, SESSION_SUB_MAP, WILL};
+use tiering::Tiering;
+use writer::SessionWriter;
 
+mod codec;
 mod config;
+mod dedup;
+mod gc;
 mod session;
+mod tiering;
+mod writer;
 
 enum RebuildChanType {
-    Session(Session, Duration),
+    //The session, its session expiry interval and the remaining delay of its pending will
+    Session(Session, Duration, Option<Duration>),
     Done(oneshot::Sender<()>),
 }
 
@@ -58,6 +70,9 @@ struct StoragePlugin {
     register: Box<dyn Register>,
     session_mgr: &'static StorageSessionManager,
     rebuild_tx: mpsc::Sender<RebuildChanType>,
+    writer: Arc<SessionWriter>,
+    tiering: Arc<Tiering>,
+    gc: Arc<Gc>,
 }
 
 impl StoragePlugin {
@@ -65,18 +80,7 @@ impl StoragePlugin {
     async fn new<S: Into<String>>(runtime: &'static Runtime, name: S) -> Result<Self> {
         let name = name.into();
         let mut cfg = runtime.settings.plugins.load_config_default::<PluginConfig>(&name)?;
-        match cfg.storage.typ {
-            StorageType::Sled => {
-                cfg.storage.sled.path =
-                    cfg.storage.sled.path.replace("{node}", &format!("{}", runtime.node.id()));
-            }
-            StorageType::Redis => {
-                cfg.storage.redis.prefix =
-                    cfg.storage.redis.prefix.replace("{node}", &format!("{}", runtime.node.id()));
-            }
-            #[allow(unreachable_patterns)]
-            _ => return Err(MqttError::from("unsupported storage type")),
-        }
+        cfg.storage.replace_node(runtime.node.id());
 
         log::info!("{} StoragePlugin cfg: {:?}", name, cfg);
 
@@ -85,12 +89,41 @@ impl StoragePlugin {
         let stored_session_infos = StoredSessionInfos::new();
 
         let register = runtime.extends.hook_mgr().await.register();
-        let session_mgr =
-            StorageSessionManager::get_or_init(storage_db.clone(), stored_session_infos.clone());
+        let writer = SessionWriter::new(
+            cfg.write_behind.clone(),
+            cfg.format,
+            storage_db.clone(),
+            OfflineDedup::new(cfg.offline_dedup),
+        );
+        let tiering = Tiering::new(cfg.tiering.clone(), cfg.encrypt, storage_db.clone());
+        let gc = Gc::new(
+            cfg.gc.clone(),
+            storage_db.clone(),
+            writer.clone(),
+            tiering.clone(),
+            cfg.tiering.max_spilled.max(1),
+        );
+        let session_mgr = StorageSessionManager::get_or_init(
+            storage_db.clone(),
+            stored_session_infos.clone(),
+            writer.clone(),
+            tiering.clone(),
+        );
 
         let cfg = Arc::new(cfg);
         let rebuild_tx = Self::start_local_runtime();
-        Ok(Self { runtime, cfg, storage_db, stored_session_infos, register, session_mgr, rebuild_tx })
+        Ok(Self {
+            runtime,
+            cfg,
+            storage_db,
+            stored_session_infos,
+            register,
+            session_mgr,
+            rebuild_tx,
+            writer,
+            tiering,
+            gc,
+        })
     }
 
     async fn load_offline_session_infos(&mut self) -> Result<()> {
@@ -104,7 +137,7 @@ impl StoragePlugin {
                 Ok(m) => {
                     let id_key = StoredKey::from(map_stored_key_to_id_bytes(m.name()).to_vec());
                     log::debug!("map_stored_key: {:?}", id_key);
-                    let basic = match m.get::<_, Basic>(BASIC).await {
+                    let basic = match codec::map_get::<Basic>(&m, BASIC).await {
                         Err(e) => {
                             log::warn!("{:?} load offline session basic info error, {:?}", id_key, e);
                             if let Err(e) = storage_db.map_remove(m.name()).await {
@@ -126,7 +159,7 @@ impl StoragePlugin {
                     log::debug!("map key: {:?}", id_key);
                     let mut s_info = StoredSessionInfo::from(id_key.clone(), basic);
 
-                    match m.get::<_, TimestampMillis>(LAST_TIME).await {
+                    match codec::map_get::<TimestampMillis>(&m, LAST_TIME).await {
                         Ok(Some(last_time)) => {
                             log::debug!("last_time: {:?}", last_time);
                             s_info.set_last_time(last_time);
@@ -137,7 +170,7 @@ impl StoragePlugin {
                         }
                     }
 
-                    match m.get::<_, SessionSubMap>(SESSION_SUB_MAP).await {
+                    match codec::map_get::<SessionSubMap>(&m, SESSION_SUB_MAP).await {
                         Ok(Some(subs)) => {
                             log::debug!("subs: {:?}", subs);
                             s_info.set_subs(subs);
@@ -148,7 +181,7 @@ impl StoragePlugin {
                         }
                     }
 
-                    match m.get::<_, DisconnectInfo>(DISCONNECT_INFO).await {
+                    match codec::map_get::<DisconnectInfo>(&m, DISCONNECT_INFO).await {
                         Ok(Some(disc_info)) => {
                             log::debug!("disc_info: {:?}", disc_info);
                             s_info.set_disconnect_info(disc_info);
@@ -159,9 +192,25 @@ impl StoragePlugin {
                         }
                     }
 
-                    match m.get::<_, Vec<InflightMessage>>(INFLIGHT_MESSAGES).await {
-                        Ok(Some(inflights)) => {
+                    match codec::map_get::<Option<TimestampMillis>>(&m, WILL).await {
+                        Ok(Some(will_due_at)) => {
+                            log::debug!("will_due_at: {:?}", will_due_at);
+                            s_info.will_due_at = will_due_at;
+                        }
+                        Ok(None) => {}
+                        Err(e) => {
+                            log::warn!("{:?} load offline session will error, {:?}", id_key, e);
+                        }
+                    }
+
+                    match codec::map_get::<Vec<InflightMessage>>(&m, INFLIGHT_MESSAGES).await {
+                        Ok(Some(mut inflights)) => {
                             log::debug!("inflights len: {:?}", inflights.len());
+                            for inflight in inflights.iter_mut() {
+                                if let Err(e) = encryption::open_publish(&mut inflight.publish).await {
+                                    log::warn!("{:?} decrypt inflight message error, {:?}", id_key, e);
+                                }
+                            }
                             s_info.inflight_messages = inflights;
                         }
                         Ok(None) => {}
@@ -179,15 +228,30 @@ impl StoragePlugin {
         }
         drop(map_iter);
 
+        let mut spills = Vec::new();
         let mut list_iter = iter_storage_db.list_iter().await?;
         while let Some(l) = list_iter.next().await {
             match l {
+                Ok(l) if l.name().starts_with(SPILL_PREFIX) => {
+                    //The spilled messages are restored when the session is connected
+                    let client_id =
+                        ClientId::from(String::from_utf8_lossy(&l.name()[SPILL_PREFIX.len()..]).into_owned());
+                    match l.len().await {
+                        Ok(len) => spills.push((client_id, len)),
+                        Err(e) => log::warn!("{:?} load spilled messages error, {:?}", client_id, e),
+                    }
+                }
                 Ok(l) => {
                     let id_key = StoredKey::from(list_stored_key_to_id_bytes(l.name()).to_vec());
                     log::debug!("list_stored_key, id_key: {:?}", id_key);
-                    match l.all::<OfflineMessageOptionType>().await {
-                        Ok(offline_msgs) => {
+                    match codec::list_all::<OfflineMessageOptionType>(&l, self.cfg.format).await {
+                        Ok(mut offline_msgs) => {
                             log::debug!("{:?} offline_msgs len: {}", id_key, offline_msgs.len(),);
+                            for (_, _, p) in offline_msgs.iter_mut().flatten() {
+                                if let Err(e) = encryption::open_publish(p).await {
+                                    log::warn!("{:?} decrypt offline message error, {:?}", id_key, e);
+                                }
+                            }
                             let ok =
                                 self.stored_session_infos.set_offline_messages(id_key.clone(), offline_msgs);
                             log::debug!(
@@ -220,6 +284,14 @@ impl StoragePlugin {
             storage_db.map_remove(make_map_stored_key(removed_key.as_ref())).await?;
             storage_db.list_remove(make_list_stored_key(removed_key.as_ref())).await?;
         }
+
+        for (client_id, len) in spills {
+            if self.stored_session_infos.contains_key(&client_id) {
+                self.tiering.track(client_id, len);
+            } else if let Err(e) = storage_db.list_remove(make_spill_stored_key(client_id.as_bytes())).await {
+                log::warn!("{:?} remove spilled messages error, {:?}", client_id, e);
+            }
+        }
         log::info!("stored_session_infos len: {:?}", self.stored_session_infos.len());
 
         Ok(())
@@ -234,15 +306,16 @@ impl StoragePlugin {
             local_set.block_on(&local_rt, async {
                 while let Some(msg) = rx.next().await {
                     match msg {
-                        RebuildChanType::Session(session, session_expiry_interval)  => {
+                        RebuildChanType::Session(session, session_expiry_interval, pending_will)  => {
 
                                 let (state, msg_tx) =
-                                    SessionState::offline_restart(session.clone(), session_expiry_interval).await;
+                                    SessionState::offline_rebuild(session.clone(), session_expiry_interval, pending_will).await;
                                 let mut session_entry =
                                     Runtime::instance().extends.shared().await.entry(state.id.clone());
 
                                 let id = session_entry.id().clone();
                                 let task_fut = async move {
+                                    restore_routes(&session).await;
                                     if let Err(e) = session_entry.set(session, msg_tx).await {
                                         log::warn!("{:?} Rebuild offline session error, {:?}", session_entry.id(), e);
                                     }
@@ -293,21 +366,41 @@ impl Plugin for StoragePlugin {
                     self.cfg.clone(),
                     self.stored_session_infos.clone(),
                     self.rebuild_tx.clone(),
+                    self.writer.clone(),
+                    self.tiering.clone(),
+                )),
+            )
+            .await;
+        self.register
+            .add(
+                Type::BeforeShutdown,
+                Box::new(StorageHandler::new(
+                    self.storage_db.clone(),
+                    self.cfg.clone(),
+                    self.stored_session_infos.clone(),
+                    self.rebuild_tx.clone(),
+                    self.writer.clone(),
+                    self.tiering.clone(),
                 )),
             )
             .await;
         self.register
             .add(
                 Type::OfflineMessage,
-                Box::new(OfflineMessageHandler::new(self.cfg.clone(), self.storage_db.clone())),
+                Box::new(OfflineMessageHandler::new(self.cfg.clone(), self.writer.clone())),
             )
             .await;
         self.register
             .add(
                 Type::OfflineInflightMessages,
-                Box::new(OfflineMessageHandler::new(self.cfg.clone(), self.storage_db.clone())),
+                Box::new(OfflineMessageHandler::new(self.cfg.clone(), self.writer.clone())),
             )
             .await;
+        if self.tiering.enable() {
+            self.register
+                .add(Type::MessageDropped, Box::new(TieringHandler { tiering: self.tiering.clone() }))
+                .await;
+        }
 
         self.load_offline_session_infos().await?;
 
@@ -323,7 +416,12 @@ impl Plugin for StoragePlugin {
     async fn start(&mut self) -> Result<()> {
         log::info!("{} start", self.name());
         *self.runtime.extends.session_mgr_mut().await = Box::new(self.session_mgr);
+        let storage_db = self.storage_db.clone();
+        HealthProbe::instance().register(self.name(), Arc::new(StorageCheck { storage_db }));
 
+        self.writer.start();
+        self.tiering.start();
+        self.gc.start();
         self.register.start().await;
         Ok(())
     }
@@ -331,6 +429,7 @@ impl Plugin for StoragePlugin {
     #[inline]
     async fn stop(&mut self) -> Result<bool> {
         log::warn!("{} stop, if the storage plugin is started, it cannot be stopped", self.name());
+        self.writer.checkpoint().await;
         Ok(false)
     }
 
@@ -384,25 +483,44 @@ impl Plugin for StoragePlugin {
         json!({
             "session_count": map_count,
             "offline_messages_count": list_count,
-            "storage_info": storage_info
+            "storage_info": storage_info,
+            "write_behind": {
+                "dirty_sessions": self.writer.dirty_sessions_count(),
+                "offline_messages": self.writer.offline_messages_count(),
+            },
+            "tiering": self.tiering.to_json(max_limit),
+            "offline_dedup": self.writer.dedup.to_json(),
+            "gc": self.gc.to_json(),
         })
     }
 }
 
+//Pings the storage backend, for the readiness probe
+struct StorageCheck {
+    storage_db: DefaultStorageDB,
+}
+
+#[async_trait]
+impl ReadinessCheck for StorageCheck {
+    async fn check(&self) -> Result<serde_json::Value> {
+        Ok(self.storage_db.info().await?)
+    }
+}
+
 struct OfflineMessageHandler {
     cfg: Arc<PluginConfig>,
-    storage_db: DefaultStorageDB,
+    writer: Arc<SessionWriter>,
 }
 
 impl OfflineMessageHandler {
-    fn new(cfg: Arc<PluginConfig>, storage_db: DefaultStorageDB) -> Self {
-        Self { cfg, storage_db }
+    fn new(cfg: Arc<PluginConfig>, writer: Arc<SessionWriter>) -> Self {
+        Self { cfg, writer }
     }
 }
 
 #[async_trait]
 impl Handler for OfflineMessageHandler {
-    async fn hook(&self, param: &Parameter, acc: Option<HookResult>) -> ReturnType {
+    async fn hook(&self, param: &Parameter, _acc: &mut Option<HookResult>) -> ReturnType {
         match param {
             Parameter::OfflineMessage(s, f, p) => {
                 log::debug!(
@@ -412,23 +530,30 @@ impl Handler for OfflineMessageHandler {
                     p
                 );
                 let list_stored_key = make_list_stored_key(s.id.to_string());
-                match self.storage_db.list(list_stored_key.as_ref(), None).await {
-                    Ok(offlines_list) => {
-                        let res = offlines_list
-                            .push_limit::<OfflineMessageOptionType>(
-                                &Some((s.id.client_id.clone(), f.clone(), (*p).clone())),
-                                s.listen_cfg().max_mqueue_len,
-                                true,
-                            )
-                            .await;
-                        if let Err(e) = res {
-                            log::warn!("{:?} save offline messages error, {:?}", s.id, e)
-                        }
-                    }
-                    Err(e) => {
-                        log::warn!("{:?} save offline messages error, {:?}", s.id, e)
+                let limit = s.listen_cfg().max_mqueue_len;
+                //a copy of the message by an overlapping subscription is not stored again
+                if self.writer.dedup.is_duplicate(&list_stored_key, dedup::message_id(f, p), limit) {
+                    log::debug!("{:?} duplicate offline message suppressed, topic: {}", s.id, p.topic);
+                    return true;
+                }
+                let mut p = (*p).clone();
+                if self.cfg.encrypt {
+                    if let Err(e) = encryption::seal_publish(&mut p).await {
+                        log::warn!("{:?} encrypt offline message error, {:?}", s.id, e);
+                        return true;
                     }
                 }
+                let res = self
+                    .writer
+                    .offline_message_push(
+                        list_stored_key,
+                        Some((s.id.client_id.clone(), f.clone(), p)),
+                        limit,
+                    )
+                    .await;
+                if let Err(e) = res {
+                    log::warn!("{:?} save offline messages error, {:?}", s.id, e)
+                }
             }
 
             Parameter::OfflineInflightMessages(s, inflight_messages) => {
@@ -439,15 +564,17 @@ impl Handler for OfflineMessageHandler {
                 );
                 let map_stored_key = make_map_stored_key(s.id.to_string());
                 log::debug!("{:?} map_stored_key: {:?}", s.id, map_stored_key);
-                match self.storage_db.map(map_stored_key.as_ref(), None).await {
-                    Ok(m) => {
-                        if let Err(e) = m.insert(INFLIGHT_MESSAGES, inflight_messages).await {
-                            log::warn!("{:?} save offline inflight messages error, {:?}", s.id, e)
+                let mut inflight_messages = inflight_messages.clone();
+                if self.cfg.encrypt {
+                    for inflight in inflight_messages.iter_mut() {
+                        if let Err(e) = encryption::seal_publish(&mut inflight.publish).await {
+                            log::warn!("{:?} encrypt inflight message error, {:?}", s.id, e);
+                            return true;
                         }
                     }
-                    Err(e) => {
-                        log::warn!("{:?} save offline inflight messages error, {:?}", s.id, e)
-                    }
+                }
+                if let Err(e) = self.writer.inflight_messages_set(map_stored_key, inflight_messages).await {
+                    log::warn!("{:?} save offline inflight messages error, {:?}", s.id, e)
                 }
             }
 
@@ -455,7 +582,37 @@ impl Handler for OfflineMessageHandler {
                 log::error!("unimplemented, {:?}", param)
             }
         }
-        (true, acc)
+        true
+    }
+}
+
+//Spills the messages evicted from the full in-memory queues of the persistent sessions
+struct TieringHandler {
+    tiering: Arc<Tiering>,
+}
+
+#[async_trait]
+impl Handler for TieringHandler {
+    async fn hook(&self, param: &Parameter, _acc: &mut Option<HookResult>) -> ReturnType {
+        match param {
+            Parameter::MessageDropped(Some(to), f, p, Reason::MessageQueueFull) => {
+                let entry = Runtime::instance().extends.shared().await.entry(to.clone());
+                let persistent = match entry.session() {
+                    Some(s) => s.connect_info().await.map(|c| !c.clean_start()).unwrap_or_default(),
+                    None => false,
+                };
+                if persistent {
+                    if let Err(e) = self.tiering.spill(&to.client_id, f.clone(), p.clone()).await {
+                        log::warn!("{:?} spill message error, {:?}", to, e);
+                    }
+                }
+            }
+            Parameter::MessageDropped(..) => {}
+            _ => {
+                log::error!("unimplemented, {:?}", param)
+            }
+        }
+        true
     }
 }
 
@@ -464,6 +621,8 @@ struct StorageHandler {
     cfg: Arc<PluginConfig>,
     stored_session_infos: StoredSessionInfos,
     rebuild_tx: mpsc::Sender<RebuildChanType>,
+    writer: Arc<SessionWriter>,
+    tiering: Arc<Tiering>,
 }
 
 impl StorageHandler {
@@ -472,8 +631,10 @@ impl StorageHandler {
         cfg: Arc<PluginConfig>,
         stored_session_infos: StoredSessionInfos,
         rebuild_tx: mpsc::Sender<RebuildChanType>,
+        writer: Arc<SessionWriter>,
+        tiering: Arc<Tiering>,
     ) -> Self {
-        Self { storage_db, cfg, stored_session_infos, rebuild_tx }
+        Self { storage_db, cfg, stored_session_infos, rebuild_tx, writer, tiering }
     }
 
     //Rebuild offline session.
@@ -509,7 +670,12 @@ impl StorageHandler {
                 )
                 .await;
                 log::debug!("{:?} session_expiry_interval: {:?}", id, session_expiry_interval);
-                if session_expiry_interval <= 0 {
+                //The remaining delay of the pending will, it is published at once if the delay or the
+                //session is over
+                let pending_will = stored.will_due_at.map(|due_at| {
+                    Duration::from_millis((due_at - chrono::Local::now().timestamp_millis()).max(0) as u64)
+                });
+                if session_expiry_interval <= 0 && pending_will.is_none() {
                     log::debug!(
                         "{:?} session is expiry, {:?}, id_key: {:?}, {:?}, {:?}",
                         id,
@@ -526,6 +692,7 @@ impl StorageHandler {
                     {
                         log::warn!("{:?} remove list error, {:?}", id, e);
                     }
+                    self.tiering.discard(&id.client_id).await;
                     //session is expiry
                     continue;
                 }
@@ -564,6 +731,15 @@ impl StorageHandler {
                     }
                 };
 
+                //the copies stored before the deduplication are delivered once
+                let suppressed = self.writer.dedup.rebuild(&mut stored.offline_messages);
+                if suppressed > 0 {
+                    log::debug!(
+                        "{:?} rebuild session, duplicate offline messages suppressed: {}",
+                        id,
+                        suppressed
+                    );
+                }
                 let deliver_queue = session.deliver_queue();
                 for item in stored.offline_messages.drain(..) {
                     if let Err((f, p)) = deliver_queue.push(item) {
@@ -581,7 +757,8 @@ impl StorageHandler {
                     .clone()
                     .send(RebuildChanType::Session(
                         session,
-                        Duration::from_millis(session_expiry_interval as u64),
+                        Duration::from_millis(session_expiry_interval.max(0) as u64),
+                        pending_will.map(|d| if session_expiry_interval > 0 { d } else { Duration::ZERO }),
                     ))
                     .await
                 {
@@ -596,7 +773,7 @@ impl StorageHandler {
 
 #[async_trait]
 impl Handler for StorageHandler {
-    async fn hook(&self, param: &Parameter, acc: Option<HookResult>) -> ReturnType {
+    async fn hook(&self, param: &Parameter, _acc: &mut Option<HookResult>) -> ReturnType {
         match param {
             Parameter::BeforeStartup => {
                 log::info!(
@@ -608,11 +785,34 @@ impl Handler for StorageHandler {
                 self.rebuild_offline_sessions(rebuild_done_tx).await;
                 let _ = rebuild_done_rx.await;
             }
+            Parameter::BeforeShutdown => {
+                log::info!("BeforeShutdown storage_type: {:?}, checkpoint ...", self.cfg.storage.typ);
+                self.writer.checkpoint().await;
+            }
             _ => {
                 log::error!("unimplemented, {:?}", param)
             }
         }
-        (true, acc)
+        true
+    }
+}
+
+///Adds the stored subscriptions of the rebuilt session to the router, with the exact options,
+///MQTT 5 subscription identifier, no_local, retain_as_published and retain_handling included
+async fn restore_routes(session: &Session) {
+    let subs = match session.subscriptions().await {
+        Ok(subs) => subs.read().await.iter().map(|(tf, opts)| (tf.clone(), opts.clone())).collect::<Vec<_>>(),
+        Err(e) => {
+            log::warn!("{:?} restore routes error, {:?}", session.id, e);
+            return;
+        }
+    };
+    let router = Runtime::instance().extends.router().await;
+    for (tf, opts) in subs {
+        log::debug!("{:?} restore route, topic_filter: {:?}, opts: {:?}", session.id, tf, opts);
+        if let Err(e) = router.add(&tf, session.id.clone(), opts).await {
+            log::warn!("{:?} restore route error, topic_filter: {:?}, {:?}", session.id, tf, e);
+        }
     }
 }
 
@@ -652,6 +852,16 @@ pub(crate) fn make_list_stored_key<T: AsRef<[u8]>>(id: T) -> StoredKey {
     Bytes::from(key)
 }
 
+const SPILL_PREFIX: &[u8] = b"spill-";
+
+//The spilled messages are stored by client id, a new connection to the session takes them over
+#[inline]
+pub(crate) fn make_spill_stored_key<T: AsRef<[u8]>>(client_id: T) -> StoredKey {
+    let mut key = Vec::from(SPILL_PREFIX);
+    key.extend_from_slice(client_id.as_ref());
+    Bytes::from(key)
+}
+
 #[inline]
 pub(crate) fn list_stored_key_to_id_bytes(stored_key: &[u8]) -> &[u8] {
     if stored_key.starts_with(b"list-") {
@@ -2,34 +2,47 @@ use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 use std::ops::Deref;
 use std::sync::atomic::{AtomicI64, AtomicU8, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Weak};
 use std::time::Duration;
 
-use rmqtt::{async_trait::async_trait, chrono, log, once_cell::sync::OnceCell, tokio, DashMap};
+use rmqtt::{
+    async_trait::async_trait, chrono, futures::StreamExt, log, once_cell::sync::OnceCell, tokio, DashMap,
+};
 use rmqtt::{
     broker::inflight::InflightMessage,
     broker::session::{SessionLike, SessionManager},
     broker::types::DisconnectInfo,
     settings::Listener,
     ClientId, ConnectInfo, ConnectInfoType, Disconnect, FitterType, From, Id, InflightType, IsPing,
-    MessageQueueType, Password, Publish, Reason, Result, SessionSubMap, SessionSubs, SubscriptionOptions,
-    Subscriptions, TimestampMillis, TopicFilter, UserName,
+    MessageQueueType, Password, Publish, Reason, Result, Runtime, SessionSubMap, SessionSubs,
+    SubscriptionOptions, Subscriptions, TimestampMillis, TopicFilter, UserName,
 };
 
-use crate::{make_list_stored_key, make_map_stored_key, OfflineMessageOptionType};
+use crate::codec;
+use crate::tiering::Tiering;
+use crate::writer::{
+    SessionWriter, DIRTY_BASIC, DIRTY_DISCONNECT_INFO, DIRTY_LAST_TIME, DIRTY_SUBSCRIPTIONS, DIRTY_WILL,
+};
+use crate::{
+    make_list_stored_key, make_map_stored_key, map_stored_key_to_id_bytes, OfflineMessageOptionType,
+};
 use rmqtt::broker::default::DefaultSession;
 use rmqtt::bytes::Bytes;
-use rmqtt_storage::{DefaultStorageDB, List, Map, StorageList, StorageMap};
+use rmqtt_storage_ext::{DefaultStorageDB, StorageList, StorageMap};
 
 pub(crate) const LAST_TIME: &[u8] = b"1";
 pub(crate) const DISCONNECT_INFO: &[u8] = b"2";
 pub(crate) const SESSION_SUB_MAP: &[u8] = b"3";
 pub(crate) const BASIC: &[u8] = b"4";
 pub(crate) const INFLIGHT_MESSAGES: &[u8] = b"5";
+//Due time of the pending will message, the will itself is in the connect info of BASIC
+pub(crate) const WILL: &[u8] = b"6";
 
 pub(crate) struct StorageSessionManager {
     storage_db: DefaultStorageDB,
     _stored_session_infos: StoredSessionInfos,
+    writer: Arc<SessionWriter>,
+    tiering: Arc<Tiering>,
 }
 
 impl StorageSessionManager {
@@ -37,9 +50,11 @@ impl StorageSessionManager {
     pub(crate) fn get_or_init(
         storage_db: DefaultStorageDB,
         _stored_session_infos: StoredSessionInfos,
+        writer: Arc<SessionWriter>,
+        tiering: Arc<Tiering>,
     ) -> &'static StorageSessionManager {
         static INSTANCE: OnceCell<StorageSessionManager> = OnceCell::new();
-        INSTANCE.get_or_init(|| Self { storage_db, _stored_session_infos })
+        INSTANCE.get_or_init(|| Self { storage_db, _stored_session_infos, writer, tiering })
     }
 }
 
@@ -82,6 +97,10 @@ impl SessionManager for &'static StorageSessionManager {
         );
 
         if clean_start {
+            //The spilled messages of the previous session are discarded with it
+            let tiering = self.tiering.clone();
+            let client_id = inner.id().client_id.clone();
+            tokio::spawn(async move { tiering.discard(&client_id).await });
             Ok(Arc::new(inner))
         } else {
             let id_str = inner.id().to_string();
@@ -91,13 +110,18 @@ impl SessionManager for &'static StorageSessionManager {
 
             //Only when 'clean_session' is equal to false or 'clean_start' is equal to false, the
             // session information persistence feature will be initiated.
-            let s = Arc::new(StorageSession::new(
-                inner,
-                fitter,
-                self.storage_db.clone(),
-                session_info_map,
-                offline_messages_list,
-            ));
+            let s = Arc::new_cyclic(|me| {
+                StorageSession::new(
+                    inner,
+                    fitter,
+                    self.storage_db.clone(),
+                    session_info_map,
+                    offline_messages_list,
+                    self.writer.clone(),
+                    self.tiering.clone(),
+                    me.clone(),
+                )
+            });
             if connected {
                 let s1 = s.clone();
                 tokio::spawn(async move {
@@ -107,6 +131,8 @@ impl SessionManager for &'static StorageSessionManager {
                     if let Some(last_id) = last_id {
                         log::debug!("Remove last offline session info from db, last_id: {:?}", last_id,);
 
+                        s1.writer.discard(last_id.to_string()).await;
+
                         let map = s1.storage_db.map(make_map_stored_key(last_id.to_string()), None).await;
                         let list = s1.storage_db.list(make_list_stored_key(last_id.to_string()), None).await;
 
@@ -135,6 +161,36 @@ impl SessionManager for &'static StorageSessionManager {
             Ok(s)
         }
     }
+
+    async fn purge(&self, client_id: &str) -> Result<usize> {
+        let mut storage_db = self.storage_db.clone();
+        let mut id_keys = Vec::new();
+        {
+            let mut map_iter = storage_db.map_iter().await?;
+            while let Some(m) = map_iter.next().await {
+                let m = match m {
+                    Ok(m) => m,
+                    Err(e) => {
+                        log::warn!("{} purge, iterate session info error, {:?}", client_id, e);
+                        continue;
+                    }
+                };
+                if let Ok(Some(basic)) = codec::map_get::<Basic>(&m, BASIC).await {
+                    if basic.id.client_id == client_id {
+                        id_keys.push(map_stored_key_to_id_bytes(m.name()).to_vec());
+                    }
+                }
+            }
+        }
+
+        for id_key in id_keys.iter() {
+            self.writer.discard(id_key).await;
+            storage_db.map_remove(make_map_stored_key(id_key)).await?;
+            storage_db.list_remove(make_list_stored_key(id_key)).await?;
+        }
+        self.tiering.discard(&ClientId::from(client_id)).await;
+        Ok(id_keys.len())
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -175,6 +231,13 @@ pub struct StorageSession {
     session_info_map: StorageMap,
     offline_messages_list: StorageList,
     last_time: AtomicI64,
+    //Due time of the pending will message, 0 if none
+    will_due_at: AtomicI64,
+    //----------------------------------
+    writer: Arc<SessionWriter>,
+    tiering: Arc<Tiering>,
+    pub(crate) dirty: AtomicU8,
+    me: Weak<StorageSession>,
 }
 
 impl StorageSession {
@@ -185,6 +248,9 @@ impl StorageSession {
         storage_db: DefaultStorageDB,
         session_info_map: StorageMap,
         offline_messages_list: StorageList,
+        writer: Arc<SessionWriter>,
+        tiering: Arc<Tiering>,
+        me: Weak<StorageSession>,
     ) -> Self {
         Self {
             inner,
@@ -193,6 +259,52 @@ impl StorageSession {
             session_info_map,
             offline_messages_list,
             last_time: AtomicI64::new(chrono::Local::now().timestamp_millis()),
+            will_due_at: AtomicI64::new(0),
+            writer,
+            tiering,
+            dirty: AtomicU8::empty(),
+            me,
+        }
+    }
+
+    //Mark the changes as dirty, they will be written to the db by the write-behind buffer.
+    // If write-behind is disabled, the changes are written immediately.
+    #[inline]
+    async fn mark_dirty(&self, flags: u8) {
+        if self.writer.enable() {
+            if self.dirty.fetch_or(flags, Ordering::SeqCst) == 0 {
+                self.writer
+                    .session_dirty(StoredKey::from(self.session_info_map.name().to_vec()), self.me.clone());
+            }
+        } else {
+            self.save_dirty(flags).await;
+        }
+    }
+
+    #[inline]
+    pub(crate) async fn flush_dirty(&self) {
+        let flags = self.dirty.swap(0, Ordering::SeqCst);
+        if flags != 0 {
+            self.save_dirty(flags).await;
+        }
+    }
+
+    #[inline]
+    async fn save_dirty(&self, flags: u8) {
+        if flags & DIRTY_LAST_TIME > 0 {
+            self.save_last_time().await;
+        }
+        if flags & DIRTY_BASIC > 0 {
+            self.save_basic_info().await;
+        }
+        if flags & DIRTY_SUBSCRIPTIONS > 0 {
+            self.save_subscriptions().await;
+        }
+        if flags & DIRTY_DISCONNECT_INFO > 0 {
+            self.save_disconnect_info().await;
+        }
+        if flags & DIRTY_WILL > 0 {
+            self.save_will().await;
         }
     }
 
@@ -201,29 +313,47 @@ impl StorageSession {
         let now = chrono::Local::now().timestamp_millis();
         let old = self.last_time.swap(now, Ordering::SeqCst);
         if save_enable || (now - old) > (1000 * 60) {
-            if let Err(e) = self.session_info_map.insert(LAST_TIME, &now).await {
-                log::warn!("{:?} save last time to db error, {:?}", self.id(), e);
-            }
+            self.mark_dirty(DIRTY_LAST_TIME).await;
             log::debug!("{:?} update last time", self.id());
         }
     }
 
+    #[inline]
+    async fn save_last_time(&self) {
+        let last_time = self.last_time.load(Ordering::SeqCst);
+        if let Err(e) = self.writer.format().map_insert(&self.session_info_map, LAST_TIME, &last_time).await {
+            log::warn!("{:?} save last time to db error, {:?}", self.id(), e);
+        }
+    }
+
     #[inline]
     pub(crate) async fn delete_from_db(&self) -> Result<()> {
+        self.writer.discard(self.id().to_string()).await;
         if let Err(e) = self.session_info_map.clear().await {
             log::error!("{:?} remove session info error from db, {:?}", self.id(), e);
         }
         if let Err(e) = self.offline_messages_list.clear().await {
             log::error!("{:?} remove session offline messages error from db, {:?}", self.id(), e);
         }
+        //The spilled messages are kept if the session is taken over by a new connection
+        let taken_over = Runtime::instance()
+            .extends
+            .shared()
+            .await
+            .entry(self.id().clone())
+            .session()
+            .map(|s| s.id != *self.id())
+            .unwrap_or_default();
+        if !taken_over {
+            self.tiering.discard(&self.id().client_id).await;
+        }
         Ok(())
     }
 
     #[inline]
     pub(crate) async fn save_to_db(&self) -> Result<()> {
-        self.update_last_time(true).await;
-        self.save_basic_info().await;
-        self.save_subscriptions().await;
+        self.last_time.store(chrono::Local::now().timestamp_millis(), Ordering::SeqCst);
+        self.mark_dirty(DIRTY_LAST_TIME | DIRTY_BASIC | DIRTY_SUBSCRIPTIONS).await;
         log::debug!("{:?} save to db ...", self.id());
         Ok(())
     }
@@ -243,7 +373,7 @@ impl StorageSession {
             created_at: self.created_at().await?,
             connected_at: self.connected_at().await?,
         };
-        self.session_info_map.insert(BASIC, &basic).await?;
+        self.writer.format().map_insert(&self.session_info_map, BASIC, &basic).await?;
         Ok(())
     }
 
@@ -257,7 +387,7 @@ impl StorageSession {
     #[inline]
     async fn _save_subscriptions(&self) -> Result<()> {
         let subs = self.inner.subscriptions.read().await;
-        self.session_info_map.insert(SESSION_SUB_MAP, subs.deref()).await?;
+        self.writer.format().map_insert(&self.session_info_map, SESSION_SUB_MAP, subs.deref()).await?;
         Ok(())
     }
 
@@ -276,12 +406,22 @@ impl StorageSession {
 
     #[inline]
     async fn _save_disconnect_info(&self) -> Result<()> {
-        self.session_info_map
-            .insert(DISCONNECT_INFO, self.inner.disconnect_info.read().await.deref())
+        let disconnect_info = self.inner.disconnect_info.read().await;
+        self.writer
+            .format()
+            .map_insert(&self.session_info_map, DISCONNECT_INFO, disconnect_info.deref())
             .await?;
         Ok(())
     }
 
+    #[inline]
+    async fn save_will(&self) {
+        let due_at = Some(self.will_due_at.load(Ordering::SeqCst)).filter(|due_at| *due_at > 0);
+        if let Err(e) = self.writer.format().map_insert(&self.session_info_map, WILL, &due_at).await {
+            log::warn!("{:?} save will to db error, {:?}", self.id(), e);
+        }
+    }
+
     #[inline]
     async fn set_map_stored_key_ttl(&self, session_expiry_interval_millis: i64) {
         match self.session_info_map.expire(session_expiry_interval_millis).await {
@@ -351,7 +491,7 @@ impl SessionLike for StorageSession {
         opts: SubscriptionOptions,
     ) -> Result<Option<SubscriptionOptions>> {
         let opts = self.inner.subscriptions_add(topic_filter, opts).await?;
-        self.save_subscriptions().await;
+        self.mark_dirty(DIRTY_SUBSCRIPTIONS).await;
         Ok(opts)
     }
 
@@ -361,21 +501,21 @@ impl SessionLike for StorageSession {
         topic_filter: &str,
     ) -> Result<Option<(TopicFilter, SubscriptionOptions)>> {
         let sub = self.inner.subscriptions_remove(topic_filter).await?;
-        self.save_subscriptions().await;
+        self.mark_dirty(DIRTY_SUBSCRIPTIONS).await;
         Ok(sub)
     }
 
     #[inline]
     async fn subscriptions_drain(&self) -> Result<Subscriptions> {
         let subs = self.inner.subscriptions_drain().await?;
-        self.save_subscriptions().await;
+        self.mark_dirty(DIRTY_SUBSCRIPTIONS).await;
         Ok(subs)
     }
 
     #[inline]
     async fn subscriptions_extend(&self, other: Subscriptions) -> Result<()> {
         self.inner.subscriptions_extend(other).await?;
-        self.save_subscriptions().await;
+        self.mark_dirty(DIRTY_SUBSCRIPTIONS).await;
         Ok(())
     }
 
@@ -443,7 +583,7 @@ impl SessionLike for StorageSession {
     #[inline]
     async fn disconnected_reason_add(&self, r: Reason) -> Result<()> {
         self.inner.disconnected_reason_add(r).await?;
-        self.save_disconnect_info().await;
+        self.mark_dirty(DIRTY_DISCONNECT_INFO).await;
         log::debug!("{:?} disconnected_reason_add ... ", self.id());
         Ok(())
     }
@@ -466,7 +606,12 @@ impl SessionLike for StorageSession {
             session_expiry_interval
         );
         self.set_map_stored_key_ttl(session_expiry_interval).await;
-        match self.offline_messages_list.push::<OfflineMessageOptionType>(&None).await {
+        match self
+            .writer
+            .format()
+            .list_push::<OfflineMessageOptionType>(&self.offline_messages_list, &None)
+            .await
+        {
             Ok(()) => {
                 self.set_list_stored_key_ttl(session_expiry_interval).await;
             }
@@ -477,7 +622,7 @@ impl SessionLike for StorageSession {
 
         self.inner.disconnected_set(d, reason).await?;
 
-        self.save_disconnect_info().await;
+        self.mark_dirty(DIRTY_DISCONNECT_INFO).await;
 
         log::debug!("{:?} disconnected_set ... ", self.id());
         Ok(())
@@ -504,6 +649,30 @@ impl SessionLike for StorageSession {
             self.update_last_time(false).await;
         }
     }
+
+    //The session is written immediately, the expiry of the stored session starts
+    #[inline]
+    async fn persist(&self) -> Result<()> {
+        self.last_time.store(chrono::Local::now().timestamp_millis(), Ordering::SeqCst);
+        self.dirty.store(0, Ordering::SeqCst);
+        self.save_dirty(
+            DIRTY_LAST_TIME | DIRTY_BASIC | DIRTY_SUBSCRIPTIONS | DIRTY_DISCONNECT_INFO | DIRTY_WILL,
+        )
+        .await;
+        let d = self.inner.disconnect().await?;
+        let session_expiry_interval = self.fitter.session_expiry_interval(d.as_ref()).as_millis() as i64;
+        self.set_map_stored_key_ttl(session_expiry_interval).await;
+        Ok(())
+    }
+
+    #[inline]
+    async fn will_pending_set(&self, due_at: Option<TimestampMillis>) -> Result<()> {
+        let due_at = due_at.unwrap_or_default();
+        if self.will_due_at.swap(due_at, Ordering::SeqCst) != due_at {
+            self.mark_dirty(DIRTY_WILL).await;
+        }
+        Ok(())
+    }
 }
 
 // const SESSION_PRESENT: u8 = 0b00000001;
@@ -594,6 +763,8 @@ pub(crate) struct StoredSessionInfo {
     pub offline_messages: Vec<(From, Publish)>,
     pub inflight_messages: Vec<InflightMessage>,
     pub last_time: TimestampMillis,
+    //Due time of the pending will message
+    pub will_due_at: Option<TimestampMillis>,
 }
 
 impl StoredSessionInfo {
@@ -608,6 +779,7 @@ impl StoredSessionInfo {
             offline_messages: Vec::new(),
             inflight_messages: Vec::new(),
             last_time,
+            will_due_at: None,
         }
     }
 
@@ -0,0 +1,201 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use rmqtt::{broker::encryption, ClientId, From, Id, Publish, Result, Runtime};
+use rmqtt::{
+    log,
+    serde_json::{self, json},
+    tokio, DashMap,
+};
+
+use rmqtt_storage_ext::{DefaultStorageDB, StorageList};
+
+use crate::config::Tiering as TieringConfig;
+use crate::make_spill_stored_key;
+
+pub(crate) type SpilledMessage = (From, Publish);
+
+#[derive(Default)]
+pub(crate) struct SpillCounters {
+    //messages evicted from the full in-memory queue and written to the storage
+    spilled: AtomicUsize,
+    //messages read back into the in-memory queue
+    restored: AtomicUsize,
+    //the oldest spilled messages dropped beyond max_spilled
+    dropped: AtomicUsize,
+    //spilled messages not restored yet
+    pending: AtomicUsize,
+}
+
+impl SpillCounters {
+    #[inline]
+    fn to_json(&self) -> serde_json::Value {
+        json!({
+            "spilled": self.spilled.load(Ordering::Relaxed),
+            "restored": self.restored.load(Ordering::Relaxed),
+            "dropped": self.dropped.load(Ordering::Relaxed),
+            "pending": self.pending.load(Ordering::Relaxed),
+        })
+    }
+}
+
+//Offline message tiering, the in-memory message queue of a session keeps the newest messages, the
+//older ones evicted from the full queue are spilled to the storage and restored as the queue drains.
+pub(crate) struct Tiering {
+    cfg: TieringConfig,
+    encrypt: bool,
+    storage_db: DefaultStorageDB,
+    //client id => spill counters, of the sessions that spilled messages
+    sessions: DashMap<ClientId, Arc<SpillCounters>>,
+    totals: SpillCounters,
+}
+
+impl Tiering {
+    #[inline]
+    pub(crate) fn new(cfg: TieringConfig, encrypt: bool, storage_db: DefaultStorageDB) -> Arc<Self> {
+        Arc::new(Self {
+            cfg,
+            encrypt,
+            storage_db,
+            sessions: DashMap::default(),
+            totals: SpillCounters::default(),
+        })
+    }
+
+    #[inline]
+    pub(crate) fn enable(&self) -> bool {
+        self.cfg.enable
+    }
+
+    //The spilled messages are also restored when tiering is disabled, those stored before
+    #[inline]
+    pub(crate) fn start(self: &Arc<Self>) {
+        let tiering = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(tiering.cfg.restore_interval).await;
+                let sessions = tiering
+                    .sessions
+                    .iter()
+                    .filter(|entry| entry.value().pending.load(Ordering::SeqCst) > 0)
+                    .map(|entry| (entry.key().clone(), entry.value().clone()))
+                    .collect::<Vec<_>>();
+                for (client_id, counters) in sessions {
+                    if let Err(e) = tiering.restore(&client_id, &counters).await {
+                        log::warn!("{:?} restore spilled messages error, {:?}", client_id, e);
+                    }
+                }
+            }
+        });
+    }
+
+    ///Spilled messages of the session, loaded from the storage
+    #[inline]
+    pub(crate) fn track(&self, client_id: ClientId, pending: usize) {
+        self.sessions.entry(client_id).or_default().pending.store(pending, Ordering::SeqCst);
+        self.totals.pending.fetch_add(pending, Ordering::SeqCst);
+    }
+
+    ///Writes the message evicted from the full in-memory queue to the storage
+    pub(crate) async fn spill(&self, client_id: &ClientId, from: From, mut p: Publish) -> Result<()> {
+        if self.encrypt {
+            encryption::seal_publish(&mut p).await?;
+        }
+        let l = self.list(client_id).await?;
+        let removed = l.push_limit::<SpilledMessage>(&(from, p), self.cfg.max_spilled.max(1), true).await?;
+        let counters = self.sessions.entry(client_id.clone()).or_default().value().clone();
+        for c in [counters.as_ref(), &self.totals] {
+            c.spilled.fetch_add(1, Ordering::SeqCst);
+            if removed.is_some() {
+                c.dropped.fetch_add(1, Ordering::SeqCst);
+            } else {
+                c.pending.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        Ok(())
+    }
+
+    //Restores the oldest spilled messages into the room left in the in-memory queue, if the session
+    //is connected to this node
+    async fn restore(&self, client_id: &ClientId, counters: &SpillCounters) -> Result<()> {
+        let id = Id::new(Runtime::instance().node.id(), None, None, client_id.clone(), None);
+        let entry = Runtime::instance().extends.shared().await.entry(id);
+        let session = match entry.session() {
+            Some(s) if s.connected().await.unwrap_or_default() => s,
+            _ => return Ok(()),
+        };
+        let queue = session.deliver_queue();
+        let room = queue.capacity().saturating_sub(queue.len()).min(self.cfg.restore_batch_size);
+        if room == 0 {
+            return Ok(());
+        }
+
+        let l = self.list(client_id).await?;
+        let mut restored = 0;
+        let mut drained = false;
+        for _ in 0..room {
+            let (from, mut p) = match l.pop::<SpilledMessage>().await? {
+                Some(msg) => msg,
+                None => {
+                    drained = true;
+                    break;
+                }
+            };
+            if let Err(e) = encryption::open_publish(&mut p).await {
+                log::warn!("{:?} decrypt spilled message error, {:?}", client_id, e);
+            }
+            if let Err((_, _, reason)) = entry.publish(from, p).await {
+                log::warn!("{:?} restore spilled message error, {}", client_id, reason);
+                break;
+            }
+            restored += 1;
+        }
+        for c in [counters, &self.totals] {
+            c.restored.fetch_add(restored, Ordering::SeqCst);
+            saturating_sub(&c.pending, restored);
+        }
+        if drained {
+            //The counters were ahead of the storage, e.g. the list expired with the session
+            saturating_sub(&self.totals.pending, counters.pending.swap(0, Ordering::SeqCst));
+        }
+        log::debug!("{:?} restored {} spilled messages", client_id, restored);
+        Ok(())
+    }
+
+    ///Removes the spilled messages of the session
+    pub(crate) async fn discard(&self, client_id: &ClientId) {
+        if let Some((_, counters)) = self.sessions.remove(client_id) {
+            saturating_sub(&self.totals.pending, counters.pending.load(Ordering::SeqCst));
+        }
+        if let Err(e) = self.storage_db.list_remove(make_spill_stored_key(client_id.as_bytes())).await {
+            log::warn!("{:?} remove spilled messages error, {:?}", client_id, e);
+        }
+    }
+
+    #[inline]
+    async fn list(&self, client_id: &ClientId) -> Result<StorageList> {
+        Ok(self.storage_db.list(make_spill_stored_key(client_id.as_bytes()), None).await?)
+    }
+
+    pub(crate) fn to_json(&self, max_limit: usize) -> serde_json::Value {
+        let sessions = self
+            .sessions
+            .iter()
+            .take(max_limit)
+            .map(|entry| {
+                let mut c = entry.value().to_json();
+                c["client_id"] = json!(entry.key());
+                c
+            })
+            .collect::<Vec<_>>();
+        let mut totals = self.totals.to_json();
+        totals["enable"] = json!(self.enable());
+        totals["sessions"] = json!(sessions);
+        totals
+    }
+}
+
+#[inline]
+fn saturating_sub(counter: &AtomicUsize, n: usize) {
+    let _ = counter.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |v| Some(v.saturating_sub(n)));
+}
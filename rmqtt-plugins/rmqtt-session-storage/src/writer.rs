@@ -0,0 +1,238 @@
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Weak};
+
+use rmqtt::{broker::inflight::InflightMessage, Result};
+use rmqtt::{futures, log, tokio, DashMap};
+
+use rmqtt_storage_ext::DefaultStorageDB;
+
+use crate::codec::Format;
+use crate::config::WriteBehind;
+use crate::dedup::OfflineDedup;
+use crate::session::{StorageSession, StoredKey, INFLIGHT_MESSAGES};
+use crate::{make_list_stored_key, make_map_stored_key, OfflineMessageOptionType};
+
+pub(crate) const DIRTY_LAST_TIME: u8 = 0b00000001;
+pub(crate) const DIRTY_BASIC: u8 = 0b00000010;
+pub(crate) const DIRTY_SUBSCRIPTIONS: u8 = 0b00000100;
+pub(crate) const DIRTY_DISCONNECT_INFO: u8 = 0b00001000;
+pub(crate) const DIRTY_WILL: u8 = 0b00010000;
+
+type OfflineMessages = (usize, Vec<OfflineMessageOptionType>);
+
+//Write-behind buffer, session changes are marked as dirty and written to the storage in batches.
+pub(crate) struct SessionWriter {
+    cfg: WriteBehind,
+    format: Format,
+    storage_db: DefaultStorageDB,
+    //map stored key => session
+    dirty_sessions: DashMap<StoredKey, Weak<StorageSession>>,
+    //list stored key => (limit, offline messages)
+    offline_messages: DashMap<StoredKey, OfflineMessages>,
+    //map stored key => inflight messages
+    inflight_messages: DashMap<StoredKey, Vec<InflightMessage>>,
+    //the ids of the recent offline messages of the sessions, discarded with their buffered changes
+    pub(crate) dedup: OfflineDedup,
+    flush_lock: tokio::sync::Mutex<()>,
+}
+
+impl SessionWriter {
+    #[inline]
+    pub(crate) fn new(
+        cfg: WriteBehind,
+        format: Format,
+        storage_db: DefaultStorageDB,
+        dedup: OfflineDedup,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            cfg,
+            format,
+            storage_db,
+            dirty_sessions: DashMap::default(),
+            offline_messages: DashMap::default(),
+            inflight_messages: DashMap::default(),
+            dedup,
+            flush_lock: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    #[inline]
+    pub(crate) fn enable(&self) -> bool {
+        self.cfg.enable
+    }
+
+    //The format of the records written
+    #[inline]
+    pub(crate) fn format(&self) -> Format {
+        self.format
+    }
+
+    #[inline]
+    pub(crate) fn start(self: &Arc<Self>) {
+        if !self.enable() {
+            return;
+        }
+        let writer = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(writer.cfg.flush_interval).await;
+                //When the buffer is not drained by one batch, continue without waiting
+                while writer.flush().await >= writer.cfg.max_batch_size.max(1) {}
+            }
+        });
+    }
+
+    #[inline]
+    pub(crate) fn dirty_sessions_count(&self) -> usize {
+        self.dirty_sessions.len()
+    }
+
+    #[inline]
+    pub(crate) fn offline_messages_count(&self) -> usize {
+        self.offline_messages.iter().map(|entry| entry.value().1.len()).sum()
+    }
+
+    #[inline]
+    pub(crate) fn is_empty(&self) -> bool {
+        self.dirty_sessions.is_empty()
+            && self.offline_messages.is_empty()
+            && self.inflight_messages.is_empty()
+    }
+
+    //Whether changes of the session are buffered and not written yet
+    #[inline]
+    pub(crate) fn is_dirty<T: AsRef<[u8]>>(&self, id: T) -> bool {
+        let map_stored_key = make_map_stored_key(id.as_ref());
+        self.dirty_sessions.contains_key(&map_stored_key)
+            || self.inflight_messages.contains_key(&map_stored_key)
+            || self.offline_messages.contains_key(&make_list_stored_key(id.as_ref()))
+    }
+
+    #[inline]
+    pub(crate) fn session_dirty(&self, map_stored_key: StoredKey, s: Weak<StorageSession>) {
+        self.dirty_sessions.insert(map_stored_key, s);
+    }
+
+    #[inline]
+    pub(crate) async fn offline_message_push(
+        &self,
+        list_stored_key: StoredKey,
+        msg: OfflineMessageOptionType,
+        limit: usize,
+    ) -> Result<()> {
+        if self.enable() {
+            let mut entry =
+                self.offline_messages.entry(list_stored_key).or_insert_with(|| (limit, Vec::new()));
+            let (l, msgs) = entry.value_mut();
+            *l = limit;
+            msgs.push(msg);
+        } else {
+            let offlines_list = self.storage_db.list(list_stored_key.as_ref(), None).await?;
+            self.format.list_push_limit(&offlines_list, &msg, limit).await?;
+        }
+        Ok(())
+    }
+
+    #[inline]
+    pub(crate) async fn inflight_messages_set(
+        &self,
+        map_stored_key: StoredKey,
+        inflight_messages: Vec<InflightMessage>,
+    ) -> Result<()> {
+        if self.enable() {
+            self.inflight_messages.insert(map_stored_key, inflight_messages);
+        } else {
+            let m = self.storage_db.map(map_stored_key.as_ref(), None).await?;
+            self.format.map_insert(&m, INFLIGHT_MESSAGES, &inflight_messages).await?;
+        }
+        Ok(())
+    }
+
+    //Discard the buffered changes of the session, called before the session data is removed from the storage.
+    #[inline]
+    pub(crate) async fn discard<T: AsRef<[u8]>>(&self, id: T) {
+        let map_stored_key = make_map_stored_key(id.as_ref());
+        let list_stored_key = make_list_stored_key(id.as_ref());
+        let _guard = self.flush_lock.lock().await;
+        if let Some((_, s)) = self.dirty_sessions.remove(&map_stored_key) {
+            if let Some(s) = s.upgrade() {
+                s.dirty.store(0, Ordering::SeqCst);
+            }
+        }
+        self.inflight_messages.remove(&map_stored_key);
+        self.offline_messages.remove(&list_stored_key);
+        self.dedup.discard(&list_stored_key);
+    }
+
+    //Write one batch to the storage and return the number of sessions written.
+    pub(crate) async fn flush(&self) -> usize {
+        let _guard = self.flush_lock.lock().await;
+        let max_batch_size = self.cfg.max_batch_size.max(1);
+
+        let sessions = Self::take_batch(&self.dirty_sessions, max_batch_size);
+        let inflights = Self::take_batch(&self.inflight_messages, max_batch_size);
+        let offlines = Self::take_batch(&self.offline_messages, max_batch_size);
+        let count = sessions.len().max(inflights.len()).max(offlines.len());
+        if count == 0 {
+            return 0;
+        }
+
+        let now = std::time::Instant::now();
+        let sessions_fut = futures::future::join_all(
+            sessions
+                .into_iter()
+                .filter_map(|(_, s)| s.upgrade())
+                .map(|s| async move { s.flush_dirty().await }),
+        );
+        let inflights_fut =
+            futures::future::join_all(inflights.into_iter().map(|(key, inflights)| async move {
+                if let Err(e) = self.write_inflight_messages(&key, inflights).await {
+                    log::warn!("{:?} save offline inflight messages error, {:?}", key, e);
+                }
+            }));
+        let offlines_fut =
+            futures::future::join_all(offlines.into_iter().map(|(key, (limit, msgs))| async move {
+                if let Err(e) = self.write_offline_messages(&key, msgs, limit).await {
+                    log::warn!("{:?} save offline messages error, {:?}", key, e);
+                }
+            }));
+        futures::future::join3(sessions_fut, inflights_fut, offlines_fut).await;
+        log::debug!("write-behind flush, count: {}, cost time: {:?}", count, now.elapsed());
+        count
+    }
+
+    //Flush all buffered changes, called on shutdown.
+    pub(crate) async fn checkpoint(&self) {
+        let now = std::time::Instant::now();
+        let mut count = 0;
+        while !self.is_empty() {
+            count += self.flush().await;
+        }
+        log::info!("write-behind checkpoint, count: {}, cost time: {:?}", count, now.elapsed());
+    }
+
+    #[inline]
+    fn take_batch<V>(buffer: &DashMap<StoredKey, V>, max_batch_size: usize) -> Vec<(StoredKey, V)> {
+        let keys = buffer.iter().take(max_batch_size).map(|entry| entry.key().clone()).collect::<Vec<_>>();
+        keys.iter().filter_map(|key| buffer.remove(key)).collect()
+    }
+
+    #[inline]
+    async fn write_inflight_messages(&self, key: &StoredKey, inflights: Vec<InflightMessage>) -> Result<()> {
+        let m = self.storage_db.map(key.as_ref(), None).await?;
+        self.format.map_insert(&m, INFLIGHT_MESSAGES, &inflights).await?;
+        Ok(())
+    }
+
+    #[inline]
+    async fn write_offline_messages(
+        &self,
+        key: &StoredKey,
+        msgs: Vec<OfflineMessageOptionType>,
+        limit: usize,
+    ) -> Result<()> {
+        let l = self.storage_db.list(key.as_ref(), None).await?;
+        self.format.list_pushs_limit(&l, &msgs, limit).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,119 @@
+use std::time::Duration;
+
+use serde::de::{self, Deserialize, Deserializer};
+
+use rmqtt::settings::deserialize_duration;
+use rmqtt::QoS;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PluginConfig {
+    ///Normalized state of the edge nodes and devices, republished as JSON when it changes
+    #[serde(default)]
+    pub republish: Republish,
+    ///Requests a rebirth of the edge node, with the "Node Control/Rebirth" NCMD, after an out-of-order
+    ///message or a message of an edge node that is not born
+    #[serde(default)]
+    pub request_rebirth: bool,
+    ///Capacity of the republishing queue, the states are dropped when the queue is full
+    #[serde(default = "PluginConfig::queue_capacity_default")]
+    pub queue_capacity: usize,
+}
+
+impl PluginConfig {
+    fn queue_capacity_default() -> usize {
+        100_000
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Republish {
+    #[serde(default = "Republish::enable_default")]
+    pub enable: bool,
+    ///Topic of the edge node state, supported placeholders: ${group}, ${node}
+    #[serde(default = "Republish::node_topic_default")]
+    pub node_topic: String,
+    ///Topic of the device state, supported placeholders: ${group}, ${node}, ${device}
+    #[serde(default = "Republish::device_topic_default")]
+    pub device_topic: String,
+    ///Last values of the metrics, from the birth and data messages
+    #[serde(default = "Republish::include_metrics_default")]
+    pub include_metrics: bool,
+    #[serde(default = "Republish::qos_default", deserialize_with = "Republish::deserialize_qos")]
+    pub qos: QoS,
+    #[serde(default = "Republish::retain_default")]
+    pub retain: bool,
+    #[serde(default)]
+    pub storage_available: bool,
+    #[serde(default = "Republish::expiry_interval_default", deserialize_with = "deserialize_duration")]
+    pub expiry_interval: Duration,
+}
+
+impl Default for Republish {
+    fn default() -> Self {
+        Self {
+            enable: Self::enable_default(),
+            node_topic: Self::node_topic_default(),
+            device_topic: Self::device_topic_default(),
+            include_metrics: Self::include_metrics_default(),
+            qos: Self::qos_default(),
+            retain: Self::retain_default(),
+            storage_available: false,
+            expiry_interval: Self::expiry_interval_default(),
+        }
+    }
+}
+
+impl Republish {
+    fn enable_default() -> bool {
+        true
+    }
+
+    fn node_topic_default() -> String {
+        "sparkplug/state/${group}/${node}".into()
+    }
+
+    fn device_topic_default() -> String {
+        "sparkplug/state/${group}/${node}/${device}".into()
+    }
+
+    fn include_metrics_default() -> bool {
+        true
+    }
+
+    fn qos_default() -> QoS {
+        QoS::AtLeastOnce
+    }
+
+    fn retain_default() -> bool {
+        true
+    }
+
+    fn expiry_interval_default() -> Duration {
+        Duration::ZERO
+    }
+
+    #[inline]
+    pub fn make_topic(&self, group: &str, node: &str, device: Option<&str>) -> String {
+        match device {
+            Some(device) => self
+                .device_topic
+                .replace("${group}", group)
+                .replace("${node}", node)
+                .replace("${device}", device),
+            None => self.node_topic.replace("${group}", group).replace("${node}", node),
+        }
+    }
+
+    #[inline]
+    fn deserialize_qos<'de, D>(deserializer: D) -> Result<QoS, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        match u8::deserialize(deserializer)? {
+            0 => Ok(QoS::AtMostOnce),
+            1 => Ok(QoS::AtLeastOnce),
+            2 => Ok(QoS::ExactlyOnce),
+            _ => Err(de::Error::custom("QoS configuration error, only values (0,1,2) are supported")),
+        }
+    }
+}
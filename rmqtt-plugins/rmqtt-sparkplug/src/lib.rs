@@ -0,0 +1,206 @@
+#![deny(unsafe_code)]
+#[macro_use]
+extern crate serde;
+
+#[macro_use]
+extern crate rmqtt_macros;
+
+use std::ops::Deref;
+use std::sync::Arc;
+
+use rmqtt::{
+    async_trait::async_trait,
+    bytes::Bytes,
+    log, serde_json, timestamp_millis,
+    tokio::{
+        self,
+        sync::mpsc::{self, Receiver, Sender},
+        sync::RwLock,
+    },
+};
+use rmqtt::{
+    broker::hook::{Handler, HookResult, Parameter, Register, ReturnType, Type},
+    plugin::{PackageInfo, Plugin},
+    register, ClientId, From, Id, NodeId, Publish, PublishProperties, QoS, Result, Runtime, SessionState,
+    TopicName, UserName,
+};
+
+use config::PluginConfig;
+use sparkplug::{Event, Sparkplug, SparkplugTopic};
+
+mod config;
+mod proto;
+mod sparkplug;
+
+register!(SparkplugPlugin::new);
+
+#[derive(Plugin)]
+struct SparkplugPlugin {
+    runtime: &'static Runtime,
+    cfg: Arc<RwLock<PluginConfig>>,
+    register: Box<dyn Register>,
+    sparkplug: Arc<Sparkplug>,
+    tx: Sender<Event>,
+}
+
+impl SparkplugPlugin {
+    #[inline]
+    async fn new(runtime: &'static Runtime, name: &'static str) -> Result<Self> {
+        let cfg = runtime.settings.plugins.load_config_default::<PluginConfig>(name)?;
+        log::info!("{} SparkplugPlugin cfg: {:?}", name, cfg);
+        let (tx, rx) = mpsc::channel(cfg.queue_capacity);
+        let cfg = Arc::new(RwLock::new(cfg));
+        tokio::spawn(republish(cfg.clone(), runtime.node.id(), rx));
+        let register = runtime.extends.hook_mgr().await.register();
+        Ok(Self { runtime, cfg, register, sparkplug: Arc::new(Sparkplug::default()), tx })
+    }
+}
+
+#[async_trait]
+impl Plugin for SparkplugPlugin {
+    #[inline]
+    async fn init(&mut self) -> Result<()> {
+        log::info!("{} init", self.name());
+        //Including the Will Messages, the NDEATH certificates
+        self.register.add(Type::MessagePublish, Box::new(SparkplugHandler::new(self))).await;
+        Ok(())
+    }
+
+    #[inline]
+    async fn get_config(&self) -> Result<serde_json::Value> {
+        Ok(serde_json::to_value(self.cfg.read().await.deref())?)
+    }
+
+    #[inline]
+    async fn load_config(&mut self) -> Result<()> {
+        let new_cfg = self.runtime.settings.plugins.load_config::<PluginConfig>(self.name())?;
+        *self.cfg.write().await = new_cfg;
+        log::debug!("load_config ok,  {:?}", self.cfg);
+        Ok(())
+    }
+
+    #[inline]
+    async fn start(&mut self) -> Result<()> {
+        log::info!("{} start", self.name());
+        self.register.start().await;
+        Ok(())
+    }
+
+    #[inline]
+    async fn stop(&mut self) -> Result<bool> {
+        log::info!("{} stop", self.name());
+        self.register.stop().await;
+        Ok(true)
+    }
+
+    #[inline]
+    async fn attrs(&self) -> serde_json::Value {
+        self.sparkplug.to_json()
+    }
+}
+
+struct SparkplugHandler {
+    cfg: Arc<RwLock<PluginConfig>>,
+    sparkplug: Arc<Sparkplug>,
+    tx: Sender<Event>,
+}
+
+impl SparkplugHandler {
+    fn new(plugin: &SparkplugPlugin) -> Self {
+        Self { cfg: plugin.cfg.clone(), sparkplug: plugin.sparkplug.clone(), tx: plugin.tx.clone() }
+    }
+}
+
+#[async_trait]
+impl Handler for SparkplugHandler {
+    async fn hook(&self, param: &Parameter, _acc: &mut Option<HookResult>) -> ReturnType {
+        match param {
+            Parameter::MessagePublish(_, _, p) => {
+                if let Some(t) = SparkplugTopic::parse(&p.topic) {
+                    let (request_rebirth, republish, include_metrics) = {
+                        let cfg = self.cfg.read().await;
+                        (cfg.request_rebirth, cfg.republish.enable, cfg.republish.include_metrics)
+                    };
+                    for ev in self.sparkplug.handle(&t, &p.payload, request_rebirth, include_metrics) {
+                        if !republish && matches!(ev, Event::State { .. }) {
+                            continue;
+                        }
+                        if let Err(e) = self.tx.try_send(ev) {
+                            log::warn!("sparkplug republish queue error, {}", e);
+                        }
+                    }
+                }
+            }
+            _ => {
+                log::error!("unimplemented, {:?}", param)
+            }
+        }
+        true
+    }
+}
+
+///Publishes the states and the rebirth requests, in order
+async fn republish(cfg: Arc<RwLock<PluginConfig>>, node_id: NodeId, mut rx: Receiver<Event>) {
+    let from = From::from_system(Id::new(
+        node_id,
+        None,
+        None,
+        ClientId::from_static("sparkplug"),
+        Some(UserName::from("sparkplug")),
+    ));
+    while let Some(ev) = rx.recv().await {
+        let republish = cfg.read().await.republish.clone();
+        let (topic, payload, qos, retain) = match ev {
+            Event::State { group, node, device, state } => (
+                republish.make_topic(&group, &node, device.as_deref()),
+                Bytes::from(state.to_string()),
+                republish.qos,
+                republish.retain,
+            ),
+            Event::Rebirth { group, node } => {
+                log::debug!("request rebirth, {}/{}", group, node);
+                (
+                    format!("spBv1.0/{}/NCMD/{}", group, node),
+                    Bytes::from(proto::rebirth_request()),
+                    QoS::AtMostOnce,
+                    false,
+                )
+            }
+        };
+        let p = Publish {
+            dup: false,
+            retain,
+            qos,
+            topic: TopicName::from(topic),
+            packet_id: None,
+            payload,
+            properties: PublishProperties::default(),
+            create_time: timestamp_millis(),
+        };
+
+        //hook, message_publish
+        let p = match Runtime::instance()
+            .extends
+            .hook_mgr()
+            .await
+            .message_publish(None, from.clone(), &p)
+            .await
+        {
+            Ok(modified) => modified.unwrap_or(p),
+            //dropped by a handler
+            Err(_) => continue,
+        };
+
+        if let Err(e) = SessionState::forwards(
+            from.clone(),
+            p,
+            retain,
+            republish.storage_available,
+            Some(republish.expiry_interval),
+        )
+        .await
+        {
+            log::warn!("sparkplug republish error, {:?}", e);
+        }
+    }
+}
@@ -0,0 +1,106 @@
+//! Sparkplug B payload, the fields of sparkplug_b.proto used here, the other fields are skipped when
+//! decoding. The value fields are the members of the "value" oneof.
+
+use prost::Message;
+
+use rmqtt::{
+    base64::{engine::general_purpose, Engine as _},
+    serde_json::{self, json},
+    timestamp_millis,
+};
+
+const DATATYPE_BOOLEAN: u32 = 11;
+const REBIRTH: &str = "Node Control/Rebirth";
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub(crate) struct Payload {
+    #[prost(uint64, optional, tag = "1")]
+    pub timestamp: Option<u64>,
+    #[prost(message, repeated, tag = "2")]
+    pub metrics: Vec<Metric>,
+    #[prost(uint64, optional, tag = "3")]
+    pub seq: Option<u64>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub(crate) struct Metric {
+    #[prost(string, optional, tag = "1")]
+    pub name: Option<String>,
+    #[prost(uint64, optional, tag = "2")]
+    pub alias: Option<u64>,
+    #[prost(uint64, optional, tag = "3")]
+    pub timestamp: Option<u64>,
+    #[prost(uint32, optional, tag = "4")]
+    pub datatype: Option<u32>,
+    #[prost(bool, optional, tag = "7")]
+    pub is_null: Option<bool>,
+    #[prost(uint32, optional, tag = "10")]
+    pub int_value: Option<u32>,
+    #[prost(uint64, optional, tag = "11")]
+    pub long_value: Option<u64>,
+    #[prost(float, optional, tag = "12")]
+    pub float_value: Option<f32>,
+    #[prost(double, optional, tag = "13")]
+    pub double_value: Option<f64>,
+    #[prost(bool, optional, tag = "14")]
+    pub boolean_value: Option<bool>,
+    #[prost(string, optional, tag = "15")]
+    pub string_value: Option<String>,
+    #[prost(bytes = "vec", optional, tag = "16")]
+    pub bytes_value: Option<Vec<u8>>,
+}
+
+impl Metric {
+    ///The value as JSON, the signed integers are sign-extended by their datatype, the datasets,
+    ///templates and extensions are null
+    pub(crate) fn value(&self, datatype: Option<u32>) -> serde_json::Value {
+        if self.is_null.unwrap_or_default() {
+            return serde_json::Value::Null;
+        }
+        if let Some(v) = self.int_value {
+            return match datatype {
+                Some(1) => json!(v as i8),
+                Some(2) => json!(v as i16),
+                Some(3) => json!(v as i32),
+                _ => json!(v),
+            };
+        }
+        if let Some(v) = self.long_value {
+            return match datatype {
+                Some(4) => json!(v as i64),
+                _ => json!(v),
+            };
+        }
+        if let Some(v) = self.float_value {
+            return json!(v);
+        }
+        if let Some(v) = self.double_value {
+            return json!(v);
+        }
+        if let Some(v) = self.boolean_value {
+            return json!(v);
+        }
+        if let Some(v) = self.string_value.as_ref() {
+            return json!(v);
+        }
+        if let Some(v) = self.bytes_value.as_ref() {
+            return json!(general_purpose::STANDARD.encode(v));
+        }
+        serde_json::Value::Null
+    }
+}
+
+///NCMD payload requesting the edge node to publish its birth certificates again
+pub(crate) fn rebirth_request() -> Vec<u8> {
+    Payload {
+        timestamp: Some(timestamp_millis() as u64),
+        metrics: vec![Metric {
+            name: Some(REBIRTH.into()),
+            datatype: Some(DATATYPE_BOOLEAN),
+            boolean_value: Some(true),
+            ..Default::default()
+        }],
+        seq: None,
+    }
+    .encode_to_vec()
+}
@@ -0,0 +1,391 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use prost::Message;
+
+use rmqtt::{
+    log,
+    serde_json::{self, json},
+    timestamp_millis, DashMap,
+};
+
+use crate::proto::{Metric, Payload};
+
+const NAMESPACE: &str = "spBv1.0/";
+const BD_SEQ: &str = "bdSeq";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MessageType {
+    NBirth,
+    NDeath,
+    NData,
+    NCmd,
+    DBirth,
+    DDeath,
+    DData,
+    DCmd,
+}
+
+///spBv1.0/{group_id}/{message_type}/{edge_node_id}[/{device_id}]
+pub(crate) struct SparkplugTopic<'a> {
+    pub(crate) group: &'a str,
+    pub(crate) typ: MessageType,
+    pub(crate) node: &'a str,
+    pub(crate) device: Option<&'a str>,
+}
+
+impl<'a> SparkplugTopic<'a> {
+    ///None if the topic is not a Sparkplug B topic of an edge node or device, e.g. the STATE topics of
+    ///the host applications
+    pub(crate) fn parse(topic: &'a str) -> Option<Self> {
+        let mut parts = topic.strip_prefix(NAMESPACE)?.splitn(4, '/');
+        let group = parts.next()?;
+        let typ = match parts.next()? {
+            "NBIRTH" => MessageType::NBirth,
+            "NDEATH" => MessageType::NDeath,
+            "NDATA" => MessageType::NData,
+            "NCMD" => MessageType::NCmd,
+            "DBIRTH" => MessageType::DBirth,
+            "DDEATH" => MessageType::DDeath,
+            "DDATA" => MessageType::DData,
+            "DCMD" => MessageType::DCmd,
+            _ => return None,
+        };
+        let node = parts.next()?;
+        let device = parts.next();
+        let is_device =
+            matches!(typ, MessageType::DBirth | MessageType::DDeath | MessageType::DData | MessageType::DCmd);
+        if is_device != device.is_some() {
+            return None;
+        }
+        Some(Self { group, typ, node, device })
+    }
+}
+
+///Republished by the plugin, in order
+pub(crate) enum Event {
+    ///Normalized state of the edge node, or of the device
+    State { group: String, node: String, device: Option<String>, state: serde_json::Value },
+    ///"Node Control/Rebirth" NCMD to the edge node
+    Rebirth { group: String, node: String },
+}
+
+#[derive(Default)]
+struct Device {
+    online: bool,
+    timestamp: u64,
+    metrics: BTreeMap<String, serde_json::Value>,
+}
+
+#[derive(Default)]
+struct Node {
+    online: bool,
+    bd_seq: Option<u64>,
+    seq: Option<u64>,
+    birth_timestamp: u64,
+    timestamp: u64,
+    out_of_order: usize,
+    //one rebirth request until the next NBIRTH
+    rebirth_requested: bool,
+    //alias -> (metric name, datatype), of the edge node and its devices
+    aliases: HashMap<u64, (String, Option<u32>)>,
+    metrics: BTreeMap<String, serde_json::Value>,
+    devices: BTreeMap<String, Device>,
+}
+
+impl Node {
+    fn to_json(&self, group: &str, node: &str, include_metrics: bool) -> serde_json::Value {
+        let devices = self.devices.iter().filter(|(_, d)| d.online).map(|(name, _)| name).collect::<Vec<_>>();
+        let mut state = json!({
+            "group": group,
+            "node": node,
+            "online": self.online,
+            "bd_seq": self.bd_seq,
+            "seq": self.seq,
+            "birth_timestamp": self.birth_timestamp,
+            "timestamp": self.timestamp,
+            "out_of_order": self.out_of_order,
+            "devices": devices,
+        });
+        if include_metrics {
+            state["metrics"] = json!(self.metrics);
+        }
+        state
+    }
+
+    fn device_json(&self, group: &str, node: &str, device: &str, include_metrics: bool) -> serde_json::Value {
+        let d = self.devices.get(device);
+        let mut state = json!({
+            "group": group,
+            "node": node,
+            "device": device,
+            "online": d.map(|d| d.online).unwrap_or_default(),
+            "timestamp": d.map(|d| d.timestamp).unwrap_or_default(),
+        });
+        if include_metrics {
+            state["metrics"] = json!(d.map(|d| &d.metrics));
+        }
+        state
+    }
+
+    ///Updates the last values of the metrics, a birth certificate also defines the aliases
+    fn update_metrics(
+        aliases: &mut HashMap<u64, (String, Option<u32>)>,
+        values: &mut BTreeMap<String, serde_json::Value>,
+        metrics: &[Metric],
+        birth: bool,
+    ) {
+        for m in metrics {
+            let (name, datatype) = match (m.name.as_ref(), m.alias) {
+                (Some(name), alias) => {
+                    if let (true, Some(alias)) = (birth, alias) {
+                        aliases.insert(alias, (name.clone(), m.datatype));
+                    }
+                    let datatype =
+                        m.datatype.or_else(|| alias.and_then(|a| aliases.get(&a)).and_then(|(_, t)| *t));
+                    (name.clone(), datatype)
+                }
+                (None, Some(alias)) => match aliases.get(&alias) {
+                    Some((name, datatype)) => (name.clone(), m.datatype.or(*datatype)),
+                    None => continue,
+                },
+                (None, None) => continue,
+            };
+            values.insert(name, m.value(datatype));
+        }
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Metrics {
+    births: AtomicUsize,
+    deaths: AtomicUsize,
+    datas: AtomicUsize,
+    out_of_order: AtomicUsize,
+    unborn: AtomicUsize,
+    stale_deaths: AtomicUsize,
+    decode_errors: AtomicUsize,
+    rebirth_requests: AtomicUsize,
+}
+
+impl Metrics {
+    #[inline]
+    fn inc(counter: &AtomicUsize) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    fn to_json(&self) -> serde_json::Value {
+        json!({
+            "births": self.births.load(Ordering::Relaxed),
+            "deaths": self.deaths.load(Ordering::Relaxed),
+            "datas": self.datas.load(Ordering::Relaxed),
+            "out_of_order": self.out_of_order.load(Ordering::Relaxed),
+            "unborn": self.unborn.load(Ordering::Relaxed),
+            "stale_deaths": self.stale_deaths.load(Ordering::Relaxed),
+            "decode_errors": self.decode_errors.load(Ordering::Relaxed),
+            "rebirth_requests": self.rebirth_requests.load(Ordering::Relaxed),
+        })
+    }
+}
+
+///Sparkplug state machine of the edge nodes and their devices, of the messages published on this node
+#[derive(Default)]
+pub(crate) struct Sparkplug {
+    //(group id, edge node id)
+    nodes: DashMap<(String, String), Node>,
+    metrics: Metrics,
+}
+
+impl Sparkplug {
+    ///Updates the state with the message, the events to republish
+    pub(crate) fn handle(
+        &self,
+        t: &SparkplugTopic,
+        payload: &[u8],
+        request_rebirth: bool,
+        include_metrics: bool,
+    ) -> Vec<Event> {
+        let mut events = Vec::new();
+        if matches!(t.typ, MessageType::NCmd | MessageType::DCmd) {
+            return events;
+        }
+        let payload = match Payload::decode(payload) {
+            Ok(payload) => payload,
+            Err(e) => {
+                Metrics::inc(&self.metrics.decode_errors);
+                log::debug!("invalid Sparkplug B payload, {}/{}, {}", t.group, t.node, e);
+                return events;
+            }
+        };
+        let timestamp = payload.timestamp.unwrap_or_else(|| timestamp_millis() as u64);
+        let (group, node_id) = (t.group.to_owned(), t.node.to_owned());
+        let mut node = self.nodes.entry((group.clone(), node_id.clone())).or_default();
+        let state = |node: &Node, device: Option<&str>| Event::State {
+            group: group.clone(),
+            node: node_id.clone(),
+            device: device.map(ToOwned::to_owned),
+            state: match device {
+                Some(device) => node.device_json(&group, &node_id, device, include_metrics),
+                None => node.to_json(&group, &node_id, include_metrics),
+            },
+        };
+
+        match t.typ {
+            MessageType::NBirth => {
+                Metrics::inc(&self.metrics.births);
+                let node = &mut *node;
+                node.online = true;
+                node.bd_seq = bd_seq(&payload);
+                node.seq = payload.seq;
+                node.birth_timestamp = timestamp;
+                node.timestamp = timestamp;
+                node.rebirth_requested = false;
+                node.aliases.clear();
+                node.metrics.clear();
+                Node::update_metrics(&mut node.aliases, &mut node.metrics, &payload.metrics, true);
+                //The devices are born again after the edge node
+                let devices = Self::devices_offline(node, timestamp);
+                let node = &*node;
+                events.push(state(node, None));
+                events.extend(devices.iter().map(|d| state(node, Some(d))));
+            }
+            MessageType::NDeath => {
+                let bd_seq = bd_seq(&payload);
+                //The death certificate of a previous session, e.g. the Will Message of a stale connection
+                if !node.online || (bd_seq.is_some() && node.bd_seq.is_some() && bd_seq != node.bd_seq) {
+                    Metrics::inc(&self.metrics.stale_deaths);
+                    log::debug!("stale NDEATH, {}/{}, bdSeq: {:?}/{:?}", group, node_id, bd_seq, node.bd_seq);
+                    return events;
+                }
+                Metrics::inc(&self.metrics.deaths);
+                let node = &mut *node;
+                node.online = false;
+                node.timestamp = timestamp;
+                let devices = Self::devices_offline(node, timestamp);
+                let node = &*node;
+                events.push(state(node, None));
+                events.extend(devices.iter().map(|d| state(node, Some(d))));
+            }
+            _ => {
+                if !node.online {
+                    Metrics::inc(&self.metrics.unborn);
+                    log::debug!("{:?} of an edge node not born, {}/{}", t.typ, group, node_id);
+                    if request_rebirth && !node.rebirth_requested {
+                        node.rebirth_requested = true;
+                        Metrics::inc(&self.metrics.rebirth_requests);
+                        events.push(Event::Rebirth { group: group.clone(), node: node_id.clone() });
+                    }
+                    return events;
+                }
+
+                let expected = node.seq.map(|seq| (seq + 1) % 256);
+                if payload.seq.is_none() || payload.seq != expected {
+                    Metrics::inc(&self.metrics.out_of_order);
+                    node.out_of_order += 1;
+                    log::debug!(
+                        "out-of-order {:?}, {}/{}, seq: {:?}, expected: {:?}",
+                        t.typ,
+                        group,
+                        node_id,
+                        payload.seq,
+                        expected
+                    );
+                    if request_rebirth && !node.rebirth_requested {
+                        node.rebirth_requested = true;
+                        Metrics::inc(&self.metrics.rebirth_requests);
+                        events.push(Event::Rebirth { group: group.clone(), node: node_id.clone() });
+                    }
+                }
+                //The sequence continues from the received one
+                node.seq = payload.seq.or(expected);
+                node.timestamp = timestamp;
+
+                let node = &mut *node;
+                match (t.typ, t.device) {
+                    (MessageType::NData, _) => {
+                        Metrics::inc(&self.metrics.datas);
+                        Node::update_metrics(&mut node.aliases, &mut node.metrics, &payload.metrics, false);
+                        if include_metrics {
+                            events.push(state(node, None));
+                        }
+                    }
+                    (MessageType::DBirth, Some(device)) => {
+                        Metrics::inc(&self.metrics.births);
+                        let d = node.devices.entry(device.to_owned()).or_default();
+                        d.online = true;
+                        d.timestamp = timestamp;
+                        d.metrics.clear();
+                        Node::update_metrics(&mut node.aliases, &mut d.metrics, &payload.metrics, true);
+                        events.push(state(node, Some(device)));
+                        events.push(state(node, None));
+                    }
+                    (MessageType::DData, Some(device)) => {
+                        Metrics::inc(&self.metrics.datas);
+                        match node.devices.get_mut(device).filter(|d| d.online) {
+                            Some(d) => {
+                                d.timestamp = timestamp;
+                                Node::update_metrics(
+                                    &mut node.aliases,
+                                    &mut d.metrics,
+                                    &payload.metrics,
+                                    false,
+                                );
+                                if include_metrics {
+                                    events.push(state(node, Some(device)));
+                                }
+                            }
+                            None => {
+                                Metrics::inc(&self.metrics.unborn);
+                                log::debug!("DDATA of a device not born, {}/{}/{}", group, node_id, device);
+                            }
+                        }
+                    }
+                    (MessageType::DDeath, Some(device)) => {
+                        Metrics::inc(&self.metrics.deaths);
+                        let d = node.devices.entry(device.to_owned()).or_default();
+                        d.online = false;
+                        d.timestamp = timestamp;
+                        events.push(state(node, Some(device)));
+                        events.push(state(node, None));
+                    }
+                    _ => {}
+                }
+            }
+        }
+        events
+    }
+
+    //The devices online, set offline
+    fn devices_offline(node: &mut Node, timestamp: u64) -> Vec<String> {
+        node.devices
+            .iter_mut()
+            .filter(|(_, d)| d.online)
+            .map(|(name, d)| {
+                d.online = false;
+                d.timestamp = timestamp;
+                name.clone()
+            })
+            .collect()
+    }
+
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        let nodes = self
+            .nodes
+            .iter()
+            .map(|entry| {
+                let (group, node) = entry.key();
+                entry.value().to_json(group, node, false)
+            })
+            .collect::<Vec<_>>();
+        json!({
+            "metrics": self.metrics.to_json(),
+            "nodes": nodes,
+        })
+    }
+}
+
+#[inline]
+fn bd_seq(payload: &Payload) -> Option<u64> {
+    payload.metrics.iter().find(|m| m.name.as_deref() == Some(BD_SEQ)).and_then(|m| m.long_value)
+}
@@ -13,10 +13,11 @@ use rmqtt::{
     chrono, log,
     serde_json::{self, json},
     tokio::spawn,
-    tokio::sync::RwLock,
+    tokio::sync::{broadcast::error::RecvError, RwLock},
     tokio::time::sleep,
 };
 use rmqtt::{
+    broker::alarm::AlarmManager,
     broker::hook::{Handler, HookResult, Parameter, Register, ReturnType, Type},
     broker::types::{From, Id, QoSEx},
     plugin::{PackageInfo, Plugin},
@@ -91,6 +92,53 @@ impl SystemTopicPlugin {
         });
     }
 
+    //Alarms, activated and deactivated
+    //$SYS/brokers/${node}/alarms
+    fn start_alarms(runtime: &'static Runtime, cfg: Arc<RwLock<PluginConfig>>, running: Arc<AtomicBool>) {
+        spawn(async move {
+            let mut rx = AlarmManager::instance().subscribe();
+            loop {
+                let event = match rx.recv().await {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(n)) => {
+                        log::warn!("alarm events lagged, {} events skipped", n);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                if !running.load(Ordering::SeqCst) {
+                    continue;
+                }
+                let (publish_qos, retain_available, storage_available, expiry_interval) = {
+                    let cfg_rl = cfg.read().await;
+                    (
+                        cfg_rl.publish_qos,
+                        cfg_rl.message_retain_available,
+                        cfg_rl.message_storage_available,
+                        cfg_rl.message_expiry_interval,
+                    )
+                };
+                let mut payload = event.to_json();
+                if let Some(obj) = payload.as_object_mut() {
+                    let now_time = chrono::Local::now().format("%Y-%m-%d %H:%M:%S%.3f").to_string();
+                    obj.insert("time".into(), serde_json::Value::String(now_time));
+                }
+                let nodeid = runtime.node.id();
+                let topic = format!("$SYS/brokers/{}/alarms", nodeid);
+                sys_publish(
+                    nodeid,
+                    topic,
+                    publish_qos,
+                    payload,
+                    retain_available,
+                    storage_available,
+                    expiry_interval,
+                )
+                .await;
+            }
+        });
+    }
+
     //Statistics
     //$SYS/brokers/${node}/stats
     async fn send_stats(
@@ -156,6 +204,7 @@ impl Plugin for SystemTopicPlugin {
         self.register.add(Type::MessageDropped, Box::new(SystemTopicHandler::new(cfg))).await;
 
         Self::start(self.runtime, self.cfg.clone(), self.running.clone());
+        Self::start_alarms(self.runtime, self.cfg.clone(), self.running.clone());
         Ok(())
     }
 
@@ -204,7 +253,7 @@ impl SystemTopicHandler {
 
 #[async_trait]
 impl Handler for SystemTopicHandler {
-    async fn hook(&self, param: &Parameter, acc: Option<HookResult>) -> ReturnType {
+    async fn hook(&self, param: &Parameter, acc: &mut Option<HookResult>) -> ReturnType {
         log::debug!("param: {:?}, acc: {:?}", param, acc);
         let now = chrono::Local::now();
         let now_time = now.format("%Y-%m-%d %H:%M:%S%.3f").to_string();
@@ -350,7 +399,7 @@ impl Handler for SystemTopicHandler {
                 expiry_interval,
             ));
         }
-        (true, acc)
+        true
     }
 }
 
@@ -386,13 +435,17 @@ async fn sys_publish(
             };
 
             //hook, message_publish
-            let p = Runtime::instance()
+            let p = match Runtime::instance()
                 .extends
                 .hook_mgr()
                 .await
                 .message_publish(None, from.clone(), &p)
                 .await
-                .unwrap_or(p);
+            {
+                Ok(modified) => modified.unwrap_or(p),
+                //dropped by a handler
+                Err(_) => return,
+            };
 
             if let Err(e) = SessionState::forwards(
                 from,
@@ -0,0 +1,106 @@
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::de::{self, Deserialize, Deserializer};
+use serde::ser::{self, Serialize};
+
+use rmqtt::broker::topic::TopicTree;
+use rmqtt::settings::{deserialize_duration, Bytesize};
+use rmqtt::Topic;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PluginConfig {
+    ///Interval of checking the module files for changes, modified modules are reloaded
+    #[serde(default = "PluginConfig::reload_interval_default", deserialize_with = "deserialize_duration")]
+    pub reload_interval: Duration,
+    #[serde(default)]
+    pub modules: Vec<ModuleConfig>,
+}
+
+impl PluginConfig {
+    fn reload_interval_default() -> Duration {
+        Duration::from_secs(10)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Hook {
+    MessagePublish,
+    MessageDelivered,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ModuleConfig {
+    pub name: String,
+    #[serde(default = "ModuleConfig::enable_default")]
+    pub enable: bool,
+    ///Path of the .wasm (or .wat) file
+    pub path: String,
+    #[serde(default = "ModuleConfig::hooks_default")]
+    pub hooks: Vec<Hook>,
+    ///Only messages matching these topic filters are transformed
+    #[serde(
+        default = "ModuleConfig::topics_default",
+        deserialize_with = "ModuleConfig::deserialize_topics",
+        serialize_with = "ModuleConfig::serialize_topics"
+    )]
+    pub topics: TopicsType,
+    ///CPU limit, maximum fuel (roughly the number of WASM instructions) per call
+    #[serde(default = "ModuleConfig::fuel_default")]
+    pub fuel: u64,
+    ///Memory limit of the module instance
+    #[serde(default = "ModuleConfig::max_memory_default")]
+    pub max_memory: Bytesize,
+}
+
+type TopicsType = (Arc<TopicTree<()>>, Vec<String>);
+
+impl ModuleConfig {
+    fn enable_default() -> bool {
+        true
+    }
+
+    fn hooks_default() -> Vec<Hook> {
+        vec![Hook::MessagePublish]
+    }
+
+    fn topics_default() -> TopicsType {
+        let mut topics = TopicTree::default();
+        topics.insert(&Topic::from_str("#").unwrap(), ());
+        (Arc::new(topics), vec!["#".into()])
+    }
+
+    fn fuel_default() -> u64 {
+        10_000_000
+    }
+
+    fn max_memory_default() -> Bytesize {
+        Bytesize::from("16M")
+    }
+
+    #[inline]
+    pub fn is_match(&self, hook: Hook, topic: &Topic) -> bool {
+        self.enable && self.hooks.contains(&hook) && self.topics.0.is_match(topic)
+    }
+
+    fn serialize_topics<S>(topics: &TopicsType, s: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: ser::Serializer,
+    {
+        topics.1.serialize(s)
+    }
+
+    fn deserialize_topics<'de, D>(deserializer: D) -> std::result::Result<TopicsType, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let topics_cfg: Vec<String> = Vec::deserialize(deserializer)?;
+        let mut topics = TopicTree::default();
+        for topic in topics_cfg.iter() {
+            topics.insert(&Topic::from_str(topic).map_err(|e| de::Error::custom(format!("{:?}", e)))?, ());
+        }
+        Ok((Arc::new(topics), topics_cfg))
+    }
+}
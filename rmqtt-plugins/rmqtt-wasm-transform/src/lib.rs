@@ -0,0 +1,150 @@
+#![deny(unsafe_code)]
+#[macro_use]
+extern crate serde;
+
+#[macro_use]
+extern crate rmqtt_macros;
+
+use std::ops::Deref;
+use std::sync::Arc;
+
+use rmqtt::{
+    async_trait::async_trait,
+    log,
+    serde_json::{self, json},
+    tokio::{self, sync::RwLock, task::JoinHandle},
+};
+use rmqtt::{
+    broker::hook::{Handler, HookResult, Parameter, Register, ReturnType, Type},
+    plugin::{PackageInfo, Plugin},
+    register, Result, Runtime,
+};
+
+use config::{Hook, PluginConfig};
+use wasm::WasmRuntime;
+
+mod config;
+mod wasm;
+
+register!(WasmTransformPlugin::new);
+
+#[derive(Plugin)]
+struct WasmTransformPlugin {
+    runtime: &'static Runtime,
+    cfg: Arc<RwLock<PluginConfig>>,
+    register: Box<dyn Register>,
+    wasm: WasmRuntime,
+    reloader: Option<JoinHandle<()>>,
+}
+
+impl WasmTransformPlugin {
+    #[inline]
+    async fn new(runtime: &'static Runtime, name: &'static str) -> Result<Self> {
+        let cfg = runtime.settings.plugins.load_config::<PluginConfig>(name)?;
+        log::info!("{} WasmTransformPlugin cfg: {:?}", name, cfg);
+        let wasm = WasmRuntime::new()?;
+        wasm.load(&cfg).await?;
+        let register = runtime.extends.hook_mgr().await.register();
+        Ok(Self { runtime, cfg: Arc::new(RwLock::new(cfg)), register, wasm, reloader: None })
+    }
+
+    fn start_reloader(&mut self, cfg: &PluginConfig) {
+        if let Some(reloader) = self.reloader.take() {
+            reloader.abort();
+        }
+        let (wasm, interval) = (self.wasm.clone(), cfg.reload_interval);
+        self.reloader = Some(tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                wasm.reload_modified().await;
+            }
+        }));
+    }
+}
+
+#[async_trait]
+impl Plugin for WasmTransformPlugin {
+    #[inline]
+    async fn init(&mut self) -> Result<()> {
+        log::info!("{} init", self.name());
+        self.register.add(Type::MessagePublish, Box::new(HookHandler::new(self.wasm.clone()))).await;
+        self.register.add(Type::MessageDelivered, Box::new(HookHandler::new(self.wasm.clone()))).await;
+        Ok(())
+    }
+
+    #[inline]
+    async fn get_config(&self) -> Result<serde_json::Value> {
+        Ok(serde_json::to_value(self.cfg.read().await.deref())?)
+    }
+
+    #[inline]
+    async fn load_config(&mut self) -> Result<()> {
+        let new_cfg = self.runtime.settings.plugins.load_config::<PluginConfig>(self.name())?;
+        self.wasm.load(&new_cfg).await?;
+        if self.reloader.is_some() {
+            self.start_reloader(&new_cfg);
+        }
+        *self.cfg.write().await = new_cfg;
+        log::debug!("load_config ok,  {:?}", self.cfg);
+        Ok(())
+    }
+
+    #[inline]
+    async fn start(&mut self) -> Result<()> {
+        log::info!("{} start", self.name());
+        let cfg = self.cfg.read().await.clone();
+        self.start_reloader(&cfg);
+        self.register.start().await;
+        Ok(())
+    }
+
+    #[inline]
+    async fn stop(&mut self) -> Result<bool> {
+        log::info!("{} stop", self.name());
+        self.register.stop().await;
+        if let Some(reloader) = self.reloader.take() {
+            reloader.abort();
+        }
+        Ok(true)
+    }
+
+    #[inline]
+    async fn attrs(&self) -> serde_json::Value {
+        json!({
+            "modules": self.wasm.to_json().await
+        })
+    }
+}
+
+struct HookHandler {
+    wasm: WasmRuntime,
+}
+
+impl HookHandler {
+    fn new(wasm: WasmRuntime) -> Self {
+        Self { wasm }
+    }
+}
+
+#[async_trait]
+impl Handler for HookHandler {
+    async fn hook(&self, param: &Parameter, acc: &mut Option<HookResult>) -> ReturnType {
+        let (hook, p) = match param {
+            Parameter::MessagePublish(_, _, p) => (Hook::MessagePublish, *p),
+            Parameter::MessageDelivered(_, _, p) => (Hook::MessageDelivered, *p),
+            _ => {
+                log::error!("unimplemented, {:?}", param);
+                return true;
+            }
+        };
+        //Uses the message modified by the preceding hooks, if any
+        let p = if let Some(HookResult::Publish(p)) = acc.as_ref() { p } else { p };
+        match self.wasm.transform(hook, p).await {
+            Some(new_p) => {
+                *acc = Some(HookResult::Publish(new_p));
+                true
+            }
+            None => true,
+        }
+    }
+}
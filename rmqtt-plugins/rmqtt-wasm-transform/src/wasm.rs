@@ -0,0 +1,227 @@
+//! Module ABI, the module must export:
+//!
+//! memory
+//! alloc(len: i32) -> i32, allocates `len` bytes and returns the offset
+//! transform(topic_ptr: i32, topic_len: i32, payload_ptr: i32, payload_len: i32) -> i64
+//!
+//! `transform` returns the new payload as `(ptr << 32) | len`, or a negative value to keep the
+//! payload unchanged. Each call runs in a new instance, so nothing leaks between messages.
+
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Instant, SystemTime};
+
+use wasmtime::{Config, Engine, InstancePre, Linker, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+use rmqtt::{
+    bytes::Bytes,
+    log,
+    serde_json::{self, json},
+    tokio::{self, sync::RwLock},
+};
+use rmqtt::{MqttError, Publish, Result, Topic};
+
+use crate::config::{Hook, ModuleConfig, PluginConfig};
+
+#[derive(Default)]
+struct Metrics {
+    calls: AtomicUsize,
+    changed: AtomicUsize,
+    fails: AtomicUsize,
+    reloads: AtomicUsize,
+    fuel_consumed: AtomicU64,
+    elapsed_us: AtomicU64,
+}
+
+impl Metrics {
+    #[inline]
+    fn to_json(&self) -> serde_json::Value {
+        let calls = self.calls.load(Ordering::SeqCst);
+        let elapsed_us = self.elapsed_us.load(Ordering::SeqCst);
+        json!({
+            "calls": calls,
+            "changed": self.changed.load(Ordering::SeqCst),
+            "fails": self.fails.load(Ordering::SeqCst),
+            "reloads": self.reloads.load(Ordering::SeqCst),
+            "fuel_consumed": self.fuel_consumed.load(Ordering::SeqCst),
+            "avg_elapsed_us": if calls > 0 { elapsed_us / calls as u64 } else { 0 },
+        })
+    }
+}
+
+struct StoreState {
+    limits: StoreLimits,
+}
+
+struct Compiled {
+    pre: InstancePre<StoreState>,
+    modified: Option<SystemTime>,
+}
+
+impl Compiled {
+    fn load(engine: &Engine, path: &str) -> Result<Compiled> {
+        let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        let module = Module::from_file(engine, path)
+            .map_err(|e| MqttError::from(format!("load wasm module {} error, {:?}", path, e)))?;
+        let pre = Linker::new(engine).instantiate_pre(&module)?;
+        Ok(Compiled { pre, modified })
+    }
+
+    fn transform(
+        &self,
+        engine: &Engine,
+        cfg: &ModuleConfig,
+        topic: &str,
+        payload: &[u8],
+    ) -> Result<(Option<Vec<u8>>, u64)> {
+        let limits = StoreLimitsBuilder::new().memory_size(cfg.max_memory.as_usize()).instances(1).build();
+        let mut store = Store::new(engine, StoreState { limits });
+        store.limiter(|s| &mut s.limits);
+        store.add_fuel(cfg.fuel)?;
+
+        let instance = self.pre.instantiate(&mut store)?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| MqttError::from("the module does not export memory"))?;
+        let alloc = instance.get_typed_func::<i32, i32>(&mut store, "alloc")?;
+        let transform = instance.get_typed_func::<(i32, i32, i32, i32), i64>(&mut store, "transform")?;
+
+        let topic_ptr = alloc.call(&mut store, topic.len() as i32)?;
+        memory
+            .write(&mut store, topic_ptr as usize, topic.as_bytes())
+            .map_err(|e| MqttError::from(e.to_string()))?;
+        let payload_ptr = alloc.call(&mut store, payload.len() as i32)?;
+        memory
+            .write(&mut store, payload_ptr as usize, payload)
+            .map_err(|e| MqttError::from(e.to_string()))?;
+
+        let res =
+            transform.call(&mut store, (topic_ptr, topic.len() as i32, payload_ptr, payload.len() as i32))?;
+        let output = if res < 0 {
+            None
+        } else {
+            let (ptr, len) = ((res >> 32) as u32 as usize, (res & 0xffff_ffff) as u32 as usize);
+            let mut output = vec![0; len];
+            memory.read(&store, ptr, &mut output).map_err(|e| MqttError::from(e.to_string()))?;
+            Some(output)
+        };
+        Ok((output, store.fuel_consumed().unwrap_or_default()))
+    }
+}
+
+pub(crate) struct WasmModule {
+    cfg: ModuleConfig,
+    compiled: RwLock<Arc<Compiled>>,
+    metrics: Metrics,
+}
+
+impl WasmModule {
+    #[inline]
+    fn to_json(&self) -> serde_json::Value {
+        json!({
+            "name": self.cfg.name,
+            "path": self.cfg.path,
+            "enable": self.cfg.enable,
+            "hooks": self.cfg.hooks,
+            "metrics": self.metrics.to_json(),
+        })
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct WasmRuntime {
+    engine: Engine,
+    modules: Arc<RwLock<Vec<Arc<WasmModule>>>>,
+}
+
+impl WasmRuntime {
+    pub fn new() -> Result<Self> {
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config)?;
+        Ok(Self { engine, modules: Arc::new(RwLock::new(Vec::new())) })
+    }
+
+    pub async fn load(&self, cfg: &PluginConfig) -> Result<()> {
+        let mut modules = Vec::new();
+        for m_cfg in cfg.modules.iter().filter(|m_cfg| m_cfg.enable) {
+            let compiled = Compiled::load(&self.engine, &m_cfg.path)?;
+            log::info!("wasm module {} loaded from {}", m_cfg.name, m_cfg.path);
+            modules.push(Arc::new(WasmModule {
+                cfg: m_cfg.clone(),
+                compiled: RwLock::new(Arc::new(compiled)),
+                metrics: Metrics::default(),
+            }));
+        }
+        *self.modules.write().await = modules;
+        Ok(())
+    }
+
+    ///Recompiles the modules whose file has been modified
+    pub async fn reload_modified(&self) {
+        let modules = self.modules.read().await.clone();
+        for m in modules {
+            let modified = tokio::fs::metadata(&m.cfg.path).await.and_then(|meta| meta.modified()).ok();
+            if modified.is_none() || modified == m.compiled.read().await.modified {
+                continue;
+            }
+            let (engine, path) = (self.engine.clone(), m.cfg.path.clone());
+            match tokio::task::spawn_blocking(move || Compiled::load(&engine, &path)).await {
+                Ok(Ok(compiled)) => {
+                    *m.compiled.write().await = Arc::new(compiled);
+                    m.metrics.reloads.fetch_add(1, Ordering::SeqCst);
+                    log::info!("wasm module {} reloaded from {}", m.cfg.name, m.cfg.path);
+                }
+                //Keep running the previous version
+                Ok(Err(e)) => log::warn!("wasm module {} reload error, {:?}", m.cfg.name, e),
+                Err(e) => log::warn!("wasm module {} reload error, {:?}", m.cfg.name, e),
+            }
+        }
+    }
+
+    ///Runs the matching modules in order, returns the transformed message if any module changed it
+    pub async fn transform(&self, hook: Hook, p: &Publish) -> Option<Publish> {
+        let topic = Topic::from_str(&p.topic).ok()?;
+        let modules = self.modules.read().await.clone();
+        let mut payload: Option<Bytes> = None;
+        for m in modules.into_iter().filter(|m| m.cfg.is_match(hook, &topic)) {
+            let compiled = m.compiled.read().await.clone();
+            let engine = self.engine.clone();
+            let (topic, input) = (p.topic.clone(), payload.clone().unwrap_or_else(|| p.payload.clone()));
+            let m1 = m.clone();
+            let now = Instant::now();
+            let res =
+                tokio::task::spawn_blocking(move || compiled.transform(&engine, &m1.cfg, &topic, &input))
+                    .await
+                    .map_err(|e| MqttError::from(e.to_string()))
+                    .and_then(|res| res);
+            m.metrics.calls.fetch_add(1, Ordering::SeqCst);
+            m.metrics.elapsed_us.fetch_add(now.elapsed().as_micros() as u64, Ordering::SeqCst);
+            match res {
+                Ok((output, fuel)) => {
+                    m.metrics.fuel_consumed.fetch_add(fuel, Ordering::SeqCst);
+                    if let Some(output) = output {
+                        m.metrics.changed.fetch_add(1, Ordering::SeqCst);
+                        payload = Some(Bytes::from(output));
+                    }
+                }
+                Err(e) => {
+                    //Traps, fuel exhaustion and memory limits leave the payload unchanged
+                    m.metrics.fails.fetch_add(1, Ordering::SeqCst);
+                    log::warn!("wasm module {} transform error, topic: {}, {:?}", m.cfg.name, p.topic, e);
+                }
+            }
+        }
+        payload.map(|payload| {
+            let mut p = p.clone();
+            p.payload = payload;
+            p
+        })
+    }
+
+    #[inline]
+    pub async fn to_json(&self) -> Vec<serde_json::Value> {
+        self.modules.read().await.iter().map(|m| m.to_json()).collect()
+    }
+}
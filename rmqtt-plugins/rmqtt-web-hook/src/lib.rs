@@ -256,6 +256,24 @@ impl Plugin for WebHookPlugin {
                 Box::new(WebHookHandler { tx: tx.clone(), chan_queue_count: chan_queue_count.clone() }),
             )
             .await;
+        self.register
+            .add(
+                Type::WillMessagePublish,
+                Box::new(WebHookHandler { tx: tx.clone(), chan_queue_count: chan_queue_count.clone() }),
+            )
+            .await;
+        self.register
+            .add(
+                Type::WillMessageDropped,
+                Box::new(WebHookHandler { tx: tx.clone(), chan_queue_count: chan_queue_count.clone() }),
+            )
+            .await;
+        self.register
+            .add(
+                Type::AuditRecord,
+                Box::new(WebHookHandler { tx: tx.clone(), chan_queue_count: chan_queue_count.clone() }),
+            )
+            .await;
 
         Ok(())
     }
@@ -499,7 +517,7 @@ impl WebHookHandler {
 
 #[async_trait]
 impl Handler for WebHookHandler {
-    async fn hook(&self, param: &Parameter, acc: Option<HookResult>) -> ReturnType {
+    async fn hook(&self, param: &Parameter, _acc: &mut Option<HookResult>) -> ReturnType {
         let typ = param.get_type();
         let now = chrono::Local::now();
         let now_time = now.format("%Y-%m-%d %H:%M:%S%.3f").to_string();
@@ -709,6 +727,40 @@ impl Handler for WebHookHandler {
                     Some((None, body))
                 }
             }
+            Parameter::WillMessagePublish(_session, from, publish) => {
+                let topic = publish.topic();
+                let body = json!({
+                    "retain": publish.retain(),
+                    "qos": publish.qos().value(),
+                    "topic": topic,
+                    "payload": general_purpose::STANDARD.encode(publish.payload()),
+                    "ts": publish.create_time(),
+                    "time": now_time
+                });
+                let body = from.to_from_json(body);
+                Some((Some(topic.clone()), body))
+            }
+            Parameter::WillMessageDropped(_session, from, publish, reason) => {
+                let body = json!({
+                    "retain": publish.retain(),
+                    "qos": publish.qos().value(),
+                    "topic": publish.topic(),
+                    "payload": general_purpose::STANDARD.encode(publish.payload()),
+                    "reason": reason.to_string(),
+                    "pts": publish.create_time(),
+                    "ts": now.timestamp_millis(),
+                    "time": now_time
+                });
+                let body = from.to_from_json(body);
+                Some((None, body))
+            }
+            Parameter::AuditRecord(record) => match serde_json::to_value(record) {
+                Ok(body) => Some((None, body)),
+                Err(e) => {
+                    log::warn!("audit record encode error, {:?}", e);
+                    None
+                }
+            },
             _ => {
                 log::error!("parameter is: {:?}", param);
                 None
@@ -726,7 +778,7 @@ impl Handler for WebHookHandler {
             }
         }
 
-        (true, acc)
+        true
     }
 }
 
@@ -0,0 +1,669 @@
+//! Storage backends of the session storage and the retainer.
+//!
+//! The sled and redis backends are those of rmqtt-storage, PostgreSQL and RocksDB are added with the
+//! same map, list and key-value API. The `storage` section of a plug-in selects the backend by its type, the
+//! plug-ins use `DefaultStorageDB`, `StorageMap` and `StorageList` whatever the backend.
+
+#![deny(unsafe_code)]
+#[macro_use]
+extern crate serde;
+
+use std::fmt;
+
+use serde::de::{self, DeserializeOwned, Deserializer};
+use serde::ser::{self, Serializer};
+
+use rmqtt::{anyhow::Result, async_trait::async_trait, serde_json, NodeId, TimestampMillis};
+use rmqtt_storage::{List as _, Map as _};
+
+#[cfg(feature = "postgres")]
+pub mod postgres;
+#[cfg(feature = "rocksdb")]
+pub mod rocksdb;
+
+#[cfg(feature = "rocksdb")]
+use self::rocksdb::{RocksdbConfig, RocksdbList, RocksdbMap, RocksdbStorageDB};
+#[cfg(feature = "postgres")]
+use postgres::{PostgresConfig, PostgresList, PostgresMap, PostgresStorageDB};
+
+pub type Key = Vec<u8>;
+
+#[async_trait]
+pub trait AsyncIterator {
+    type Item;
+
+    async fn next(&mut self) -> Option<Self::Item>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageType {
+    #[default]
+    Sled,
+    Redis,
+    #[cfg(feature = "postgres")]
+    Postgres,
+    #[cfg(feature = "rocksdb")]
+    Rocksdb,
+}
+
+impl StorageType {
+    ///The data of redis and postgres is shared by the nodes of a cluster, sled and rocksdb are local
+    #[inline]
+    pub fn is_shared(&self) -> bool {
+        match self {
+            StorageType::Sled => false,
+            StorageType::Redis => true,
+            #[cfg(feature = "postgres")]
+            StorageType::Postgres => true,
+            #[cfg(feature = "rocksdb")]
+            StorageType::Rocksdb => false,
+        }
+    }
+}
+
+///The storage section of a plug-in, `type` selects the backend, the sled and redis settings are
+///those of rmqtt-storage
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    pub typ: StorageType,
+    pub storage: rmqtt_storage::Config,
+    #[cfg(feature = "postgres")]
+    pub postgres: PostgresConfig,
+    #[cfg(feature = "rocksdb")]
+    pub rocksdb: RocksdbConfig,
+}
+
+impl Config {
+    ///Replaces the `{node}` placeholder of the sled path, the redis prefix, the table prefix of
+    ///PostgreSQL or the RocksDB path by the node id
+    pub fn replace_node(&mut self, node_id: NodeId) {
+        let node = format!("{}", node_id);
+        match self.typ {
+            StorageType::Sled => self.storage.sled.path = self.storage.sled.path.replace("{node}", &node),
+            StorageType::Redis => {
+                self.storage.redis.prefix = self.storage.redis.prefix.replace("{node}", &node)
+            }
+            #[cfg(feature = "postgres")]
+            StorageType::Postgres => {
+                self.postgres.table_prefix = self.postgres.table_prefix.replace("{node}", &node)
+            }
+            #[cfg(feature = "rocksdb")]
+            StorageType::Rocksdb => self.rocksdb.path = self.rocksdb.path.replace("{node}", &node),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Config {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let mut storage = serde_json::Value::deserialize(deserializer)?;
+        let obj = storage.as_object_mut().ok_or_else(|| de::Error::custom("storage must be a table"))?;
+        let typ = match obj.get("type") {
+            Some(typ) => StorageType::deserialize(typ).map_err(de::Error::custom)?,
+            None => StorageType::default(),
+        };
+        #[cfg(feature = "postgres")]
+        let postgres = match obj.remove("postgres") {
+            Some(postgres) => serde_json::from_value(postgres).map_err(de::Error::custom)?,
+            None => PostgresConfig::default(),
+        };
+        #[cfg(feature = "rocksdb")]
+        let rocksdb = match obj.remove("rocksdb") {
+            Some(rocksdb) => serde_json::from_value(rocksdb).map_err(de::Error::custom)?,
+            None => RocksdbConfig::default(),
+        };
+        //rmqtt-storage only knows its own types
+        if !matches!(typ, StorageType::Sled | StorageType::Redis) {
+            obj.insert("type".into(), serde_json::Value::from("sled"));
+        }
+        let storage = serde_json::from_value::<rmqtt_storage::Config>(storage).map_err(de::Error::custom)?;
+        Ok(Config {
+            typ,
+            storage,
+            #[cfg(feature = "postgres")]
+            postgres,
+            #[cfg(feature = "rocksdb")]
+            rocksdb,
+        })
+    }
+}
+
+impl Serialize for Config {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut storage = serde_json::to_value(&self.storage).map_err(ser::Error::custom)?;
+        if let Some(obj) = storage.as_object_mut() {
+            obj.insert("type".into(), serde_json::to_value(self.typ).map_err(ser::Error::custom)?);
+            #[cfg(feature = "postgres")]
+            obj.insert("postgres".into(), serde_json::to_value(&self.postgres).map_err(ser::Error::custom)?);
+            #[cfg(feature = "rocksdb")]
+            obj.insert("rocksdb".into(), serde_json::to_value(&self.rocksdb).map_err(ser::Error::custom)?);
+        }
+        storage.serialize(serializer)
+    }
+}
+
+pub async fn init_db(cfg: &Config) -> Result<DefaultStorageDB> {
+    match cfg.typ {
+        StorageType::Sled | StorageType::Redis => {
+            Ok(DefaultStorageDB::Storage(rmqtt_storage::init_db(&cfg.storage).await?))
+        }
+        #[cfg(feature = "postgres")]
+        StorageType::Postgres => {
+            Ok(DefaultStorageDB::Postgres(PostgresStorageDB::new(cfg.postgres.clone()).await?))
+        }
+        #[cfg(feature = "rocksdb")]
+        StorageType::Rocksdb => {
+            Ok(DefaultStorageDB::Rocksdb(RocksdbStorageDB::new(cfg.rocksdb.clone()).await?))
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum DefaultStorageDB {
+    Storage(rmqtt_storage::DefaultStorageDB),
+    #[cfg(feature = "postgres")]
+    Postgres(PostgresStorageDB),
+    #[cfg(feature = "rocksdb")]
+    Rocksdb(RocksdbStorageDB),
+}
+
+impl fmt::Debug for DefaultStorageDB {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DefaultStorageDB::Storage(_) => write!(f, "DefaultStorageDB::Storage"),
+            #[cfg(feature = "postgres")]
+            DefaultStorageDB::Postgres(db) => write!(f, "DefaultStorageDB::Postgres({:?})", db),
+            #[cfg(feature = "rocksdb")]
+            DefaultStorageDB::Rocksdb(db) => write!(f, "DefaultStorageDB::Rocksdb({:?})", db),
+        }
+    }
+}
+
+impl DefaultStorageDB {
+    #[inline]
+    pub async fn map<N: AsRef<[u8]> + Sync + Send>(
+        &self,
+        name: N,
+        expire: Option<TimestampMillis>,
+    ) -> Result<StorageMap> {
+        Ok(match self {
+            DefaultStorageDB::Storage(db) => StorageMap::Storage(db.map(name, expire).await?),
+            #[cfg(feature = "postgres")]
+            DefaultStorageDB::Postgres(db) => StorageMap::Postgres(db.map(name, expire).await?),
+            #[cfg(feature = "rocksdb")]
+            DefaultStorageDB::Rocksdb(db) => StorageMap::Rocksdb(db.map(name, expire).await?),
+        })
+    }
+
+    #[inline]
+    pub async fn map_remove<K: AsRef<[u8]> + Sync + Send>(&self, name: K) -> Result<()> {
+        match self {
+            DefaultStorageDB::Storage(db) => db.map_remove(name).await,
+            #[cfg(feature = "postgres")]
+            DefaultStorageDB::Postgres(db) => db.map_remove(name).await,
+            #[cfg(feature = "rocksdb")]
+            DefaultStorageDB::Rocksdb(db) => db.map_remove(name).await,
+        }
+    }
+
+    #[inline]
+    pub async fn list<N: AsRef<[u8]> + Sync + Send>(
+        &self,
+        name: N,
+        expire: Option<TimestampMillis>,
+    ) -> Result<StorageList> {
+        Ok(match self {
+            DefaultStorageDB::Storage(db) => StorageList::Storage(db.list(name, expire).await?),
+            #[cfg(feature = "postgres")]
+            DefaultStorageDB::Postgres(db) => StorageList::Postgres(db.list(name, expire).await?),
+            #[cfg(feature = "rocksdb")]
+            DefaultStorageDB::Rocksdb(db) => StorageList::Rocksdb(db.list(name, expire).await?),
+        })
+    }
+
+    #[inline]
+    pub async fn list_remove<K: AsRef<[u8]> + Sync + Send>(&self, name: K) -> Result<()> {
+        match self {
+            DefaultStorageDB::Storage(db) => db.list_remove(name).await,
+            #[cfg(feature = "postgres")]
+            DefaultStorageDB::Postgres(db) => db.list_remove(name).await,
+            #[cfg(feature = "rocksdb")]
+            DefaultStorageDB::Rocksdb(db) => db.list_remove(name).await,
+        }
+    }
+
+    #[inline]
+    pub async fn map_iter<'a>(
+        &'a mut self,
+    ) -> Result<Box<dyn AsyncIterator<Item = Result<StorageMap>> + Send + 'a>> {
+        Ok(match self {
+            DefaultStorageDB::Storage(db) => {
+                Box::new(Mapped { iter: StorageIter(db.map_iter().await?), f: StorageMap::Storage })
+            }
+            #[cfg(feature = "postgres")]
+            DefaultStorageDB::Postgres(db) => {
+                Box::new(Mapped { iter: db.map_iter(), f: StorageMap::Postgres })
+            }
+            #[cfg(feature = "rocksdb")]
+            DefaultStorageDB::Rocksdb(db) => Box::new(Mapped { iter: db.map_iter(), f: StorageMap::Rocksdb }),
+        })
+    }
+
+    #[inline]
+    pub async fn list_iter<'a>(
+        &'a mut self,
+    ) -> Result<Box<dyn AsyncIterator<Item = Result<StorageList>> + Send + 'a>> {
+        Ok(match self {
+            DefaultStorageDB::Storage(db) => {
+                Box::new(Mapped { iter: StorageIter(db.list_iter().await?), f: StorageList::Storage })
+            }
+            #[cfg(feature = "postgres")]
+            DefaultStorageDB::Postgres(db) => {
+                Box::new(Mapped { iter: db.list_iter(), f: StorageList::Postgres })
+            }
+            #[cfg(feature = "rocksdb")]
+            DefaultStorageDB::Rocksdb(db) => {
+                Box::new(Mapped { iter: db.list_iter(), f: StorageList::Rocksdb })
+            }
+        })
+    }
+
+    ///The keys matching the pattern, `*` and `?` are wildcards, `\` escapes them
+    #[inline]
+    pub async fn scan<'a, P: AsRef<[u8]> + Send + Sync>(
+        &'a mut self,
+        pattern: P,
+    ) -> Result<Box<dyn AsyncIterator<Item = Result<Key>> + Send + 'a>> {
+        Ok(match self {
+            DefaultStorageDB::Storage(db) => Box::new(StorageIter(db.scan(pattern).await?)),
+            #[cfg(feature = "postgres")]
+            DefaultStorageDB::Postgres(db) => Box::new(db.scan(pattern.as_ref())),
+            #[cfg(feature = "rocksdb")]
+            DefaultStorageDB::Rocksdb(db) => Box::new(db.scan(pattern.as_ref())),
+        })
+    }
+
+    #[inline]
+    pub async fn insert<K, V>(&self, key: K, val: &V) -> Result<()>
+    where
+        K: AsRef<[u8]> + Sync + Send,
+        V: serde::Serialize + Sync + Send,
+    {
+        match self {
+            DefaultStorageDB::Storage(db) => db.insert(key, val).await,
+            #[cfg(feature = "postgres")]
+            DefaultStorageDB::Postgres(db) => db.insert(key, val).await,
+            #[cfg(feature = "rocksdb")]
+            DefaultStorageDB::Rocksdb(db) => db.insert(key, val).await,
+        }
+    }
+
+    #[inline]
+    pub async fn get<K, V>(&self, key: K) -> Result<Option<V>>
+    where
+        K: AsRef<[u8]> + Sync + Send,
+        V: DeserializeOwned + Sync + Send,
+    {
+        match self {
+            DefaultStorageDB::Storage(db) => db.get(key).await,
+            #[cfg(feature = "postgres")]
+            DefaultStorageDB::Postgres(db) => db.get(key).await,
+            #[cfg(feature = "rocksdb")]
+            DefaultStorageDB::Rocksdb(db) => db.get(key).await,
+        }
+    }
+
+    #[inline]
+    pub async fn remove<K: AsRef<[u8]> + Sync + Send>(&self, key: K) -> Result<()> {
+        match self {
+            DefaultStorageDB::Storage(db) => db.remove(key).await,
+            #[cfg(feature = "postgres")]
+            DefaultStorageDB::Postgres(db) => db.remove(key).await,
+            #[cfg(feature = "rocksdb")]
+            DefaultStorageDB::Rocksdb(db) => db.remove(key).await,
+        }
+    }
+
+    ///The key expires in `dur` milliseconds, false if it does not exist
+    #[inline]
+    pub async fn expire<K: AsRef<[u8]> + Sync + Send>(&self, key: K, dur: TimestampMillis) -> Result<bool> {
+        match self {
+            DefaultStorageDB::Storage(db) => db.expire(key, dur).await,
+            #[cfg(feature = "postgres")]
+            DefaultStorageDB::Postgres(db) => db.expire(key, dur).await,
+            #[cfg(feature = "rocksdb")]
+            DefaultStorageDB::Rocksdb(db) => db.expire(key, dur).await,
+        }
+    }
+
+    #[inline]
+    pub async fn len(&self) -> Result<usize> {
+        match self {
+            DefaultStorageDB::Storage(db) => db.len().await,
+            #[cfg(feature = "postgres")]
+            DefaultStorageDB::Postgres(db) => db.len().await,
+            #[cfg(feature = "rocksdb")]
+            DefaultStorageDB::Rocksdb(db) => db.len().await,
+        }
+    }
+
+    #[inline]
+    pub async fn counter_incr<K: AsRef<[u8]> + Sync + Send>(&self, key: K, increment: isize) -> Result<()> {
+        match self {
+            DefaultStorageDB::Storage(db) => db.counter_incr(key, increment).await,
+            #[cfg(feature = "postgres")]
+            DefaultStorageDB::Postgres(db) => db.counter_incr(key, increment).await,
+            #[cfg(feature = "rocksdb")]
+            DefaultStorageDB::Rocksdb(db) => db.counter_incr(key, increment).await,
+        }
+    }
+
+    #[inline]
+    pub async fn counter_get<K: AsRef<[u8]> + Sync + Send>(&self, key: K) -> Result<Option<isize>> {
+        match self {
+            DefaultStorageDB::Storage(db) => db.counter_get(key).await,
+            #[cfg(feature = "postgres")]
+            DefaultStorageDB::Postgres(db) => db.counter_get(key).await,
+            #[cfg(feature = "rocksdb")]
+            DefaultStorageDB::Rocksdb(db) => db.counter_get(key).await,
+        }
+    }
+
+    #[inline]
+    pub async fn info(&self) -> Result<serde_json::Value> {
+        match self {
+            DefaultStorageDB::Storage(db) => db.info().await,
+            #[cfg(feature = "postgres")]
+            DefaultStorageDB::Postgres(db) => db.info().await,
+            #[cfg(feature = "rocksdb")]
+            DefaultStorageDB::Rocksdb(db) => db.info().await,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum StorageMap {
+    Storage(rmqtt_storage::StorageMap),
+    #[cfg(feature = "postgres")]
+    Postgres(PostgresMap),
+    #[cfg(feature = "rocksdb")]
+    Rocksdb(RocksdbMap),
+}
+
+impl StorageMap {
+    #[inline]
+    pub fn name(&self) -> &[u8] {
+        match self {
+            StorageMap::Storage(m) => m.name(),
+            #[cfg(feature = "postgres")]
+            StorageMap::Postgres(m) => m.name(),
+            #[cfg(feature = "rocksdb")]
+            StorageMap::Rocksdb(m) => m.name(),
+        }
+    }
+
+    #[inline]
+    pub async fn insert<K, V>(&self, key: K, val: &V) -> Result<()>
+    where
+        K: AsRef<[u8]> + Sync + Send,
+        V: serde::Serialize + Sync + Send,
+    {
+        match self {
+            StorageMap::Storage(m) => m.insert(key, val).await,
+            #[cfg(feature = "postgres")]
+            StorageMap::Postgres(m) => m.insert(key, val).await,
+            #[cfg(feature = "rocksdb")]
+            StorageMap::Rocksdb(m) => m.insert(key, val).await,
+        }
+    }
+
+    #[inline]
+    pub async fn get<K, V>(&self, key: K) -> Result<Option<V>>
+    where
+        K: AsRef<[u8]> + Sync + Send,
+        V: DeserializeOwned + Sync + Send,
+    {
+        match self {
+            StorageMap::Storage(m) => m.get(key).await,
+            #[cfg(feature = "postgres")]
+            StorageMap::Postgres(m) => m.get(key).await,
+            #[cfg(feature = "rocksdb")]
+            StorageMap::Rocksdb(m) => m.get(key).await,
+        }
+    }
+
+    #[inline]
+    pub async fn clear(&self) -> Result<()> {
+        match self {
+            StorageMap::Storage(m) => m.clear().await,
+            #[cfg(feature = "postgres")]
+            StorageMap::Postgres(m) => m.clear().await,
+            #[cfg(feature = "rocksdb")]
+            StorageMap::Rocksdb(m) => m.clear().await,
+        }
+    }
+
+    ///The map expires in `dur` milliseconds, false if it does not exist
+    #[inline]
+    pub async fn expire(&self, dur: TimestampMillis) -> Result<bool> {
+        match self {
+            StorageMap::Storage(m) => m.expire(dur).await,
+            #[cfg(feature = "postgres")]
+            StorageMap::Postgres(m) => m.expire(dur).await,
+            #[cfg(feature = "rocksdb")]
+            StorageMap::Rocksdb(m) => m.expire(dur).await,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum StorageList {
+    Storage(rmqtt_storage::StorageList),
+    #[cfg(feature = "postgres")]
+    Postgres(PostgresList),
+    #[cfg(feature = "rocksdb")]
+    Rocksdb(RocksdbList),
+}
+
+impl StorageList {
+    #[inline]
+    pub fn name(&self) -> &[u8] {
+        match self {
+            StorageList::Storage(l) => l.name(),
+            #[cfg(feature = "postgres")]
+            StorageList::Postgres(l) => l.name(),
+            #[cfg(feature = "rocksdb")]
+            StorageList::Rocksdb(l) => l.name(),
+        }
+    }
+
+    #[inline]
+    pub async fn push<V>(&self, val: &V) -> Result<()>
+    where
+        V: serde::Serialize + Sync + Send,
+    {
+        match self {
+            StorageList::Storage(l) => l.push(val).await,
+            #[cfg(feature = "postgres")]
+            StorageList::Postgres(l) => l.push(val).await,
+            #[cfg(feature = "rocksdb")]
+            StorageList::Rocksdb(l) => l.push(val).await,
+        }
+    }
+
+    #[inline]
+    pub async fn pushs<V>(&self, vals: Vec<V>) -> Result<()>
+    where
+        V: serde::Serialize + Sync + Send,
+    {
+        match self {
+            StorageList::Storage(l) => l.pushs(vals).await,
+            #[cfg(feature = "postgres")]
+            StorageList::Postgres(l) => l.pushs(vals).await,
+            #[cfg(feature = "rocksdb")]
+            StorageList::Rocksdb(l) => l.pushs(vals).await,
+        }
+    }
+
+    ///Pushes the value to the back of the list, if the list has `limit` values the front one is
+    ///popped and returned, or the push fails if not `pop_front_if_limited`
+    #[inline]
+    pub async fn push_limit<V>(&self, val: &V, limit: usize, pop_front_if_limited: bool) -> Result<Option<V>>
+    where
+        V: serde::Serialize + DeserializeOwned + Sync + Send,
+    {
+        match self {
+            StorageList::Storage(l) => l.push_limit(val, limit, pop_front_if_limited).await,
+            #[cfg(feature = "postgres")]
+            StorageList::Postgres(l) => l.push_limit(val, limit, pop_front_if_limited).await,
+            #[cfg(feature = "rocksdb")]
+            StorageList::Rocksdb(l) => l.push_limit(val, limit, pop_front_if_limited).await,
+        }
+    }
+
+    ///Pops the front value
+    #[inline]
+    pub async fn pop<V>(&self) -> Result<Option<V>>
+    where
+        V: DeserializeOwned + Sync + Send,
+    {
+        match self {
+            StorageList::Storage(l) => l.pop().await,
+            #[cfg(feature = "postgres")]
+            StorageList::Postgres(l) => l.pop().await,
+            #[cfg(feature = "rocksdb")]
+            StorageList::Rocksdb(l) => l.pop().await,
+        }
+    }
+
+    #[inline]
+    pub async fn all<V>(&self) -> Result<Vec<V>>
+    where
+        V: DeserializeOwned + Sync + Send,
+    {
+        match self {
+            StorageList::Storage(l) => l.all().await,
+            #[cfg(feature = "postgres")]
+            StorageList::Postgres(l) => l.all().await,
+            #[cfg(feature = "rocksdb")]
+            StorageList::Rocksdb(l) => l.all().await,
+        }
+    }
+
+    #[inline]
+    pub async fn len(&self) -> Result<usize> {
+        match self {
+            StorageList::Storage(l) => l.len().await,
+            #[cfg(feature = "postgres")]
+            StorageList::Postgres(l) => l.len().await,
+            #[cfg(feature = "rocksdb")]
+            StorageList::Rocksdb(l) => l.len().await,
+        }
+    }
+
+    #[inline]
+    pub async fn clear(&self) -> Result<()> {
+        match self {
+            StorageList::Storage(l) => l.clear().await,
+            #[cfg(feature = "postgres")]
+            StorageList::Postgres(l) => l.clear().await,
+            #[cfg(feature = "rocksdb")]
+            StorageList::Rocksdb(l) => l.clear().await,
+        }
+    }
+
+    ///The list expires in `dur` milliseconds, false if it does not exist
+    #[inline]
+    pub async fn expire(&self, dur: TimestampMillis) -> Result<bool> {
+        match self {
+            StorageList::Storage(l) => l.expire(dur).await,
+            #[cfg(feature = "postgres")]
+            StorageList::Postgres(l) => l.expire(dur).await,
+            #[cfg(feature = "rocksdb")]
+            StorageList::Rocksdb(l) => l.expire(dur).await,
+        }
+    }
+}
+
+//The iterators of rmqtt-storage
+struct StorageIter<'a, T>(Box<dyn rmqtt_storage::AsyncIterator<Item = Result<T>> + Send + 'a>);
+
+#[async_trait]
+impl<'a, T: Send> AsyncIterator for StorageIter<'a, T> {
+    type Item = Result<T>;
+
+    async fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().await
+    }
+}
+
+struct Mapped<I, F> {
+    iter: I,
+    f: F,
+}
+
+#[async_trait]
+impl<I, F, T, U> AsyncIterator for Mapped<I, F>
+where
+    I: AsyncIterator<Item = Result<T>> + Send,
+    F: Fn(T) -> U + Send,
+    T: Send,
+    U: Send,
+{
+    type Item = Result<U>;
+
+    async fn next(&mut self) -> Option<Self::Item> {
+        let item = self.iter.next().await;
+        item.map(|item| item.map(&self.f))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config() {
+        let cfg: Config = serde_json::from_value(serde_json::json!({
+            "type": "sled",
+            "sled": { "path": "/var/rmqtt/{node}" },
+        }))
+        .unwrap();
+        assert_eq!(cfg.typ, StorageType::Sled);
+        let mut cfg: Config = serde_json::from_value(serde_json::to_value(&cfg).unwrap()).unwrap();
+        cfg.replace_node(3);
+        assert_eq!(cfg.storage.sled.path, "/var/rmqtt/3");
+
+        #[cfg(feature = "postgres")]
+        {
+            let mut cfg: Config = serde_json::from_value(serde_json::json!({
+                "type": "postgres",
+                "postgres": { "url": "postgres://rmqtt@127.0.0.1/rmqtt", "table_prefix": "session_{node}" },
+            }))
+            .unwrap();
+            assert_eq!(cfg.typ, StorageType::Postgres);
+            cfg.replace_node(2);
+            assert_eq!(cfg.postgres.table_prefix, "session_2");
+            assert_eq!(cfg.postgres.url, "postgres://rmqtt@127.0.0.1/rmqtt");
+        }
+
+        #[cfg(feature = "rocksdb")]
+        {
+            let mut cfg: Config = serde_json::from_value(serde_json::json!({
+                "type": "rocksdb",
+                "rocksdb": { "path": "/var/rmqtt/rocksdb/{node}", "write_buffer_size": "16M" },
+            }))
+            .unwrap();
+            assert_eq!(cfg.typ, StorageType::Rocksdb);
+            cfg.replace_node(2);
+            assert_eq!(cfg.rocksdb.path, "/var/rmqtt/rocksdb/2");
+            assert_eq!(cfg.rocksdb.write_buffer_size.as_usize(), 16 * 1024 * 1024);
+        }
+    }
+}
@@ -0,0 +1,975 @@
+//! RocksDB backend, the key-values, the map entries and the list values are kept in their own column
+//! families, `kv`, `map` and `list`, the names of the maps and the lists, with their expiry and the
+//! bounds of the lists, in `meta`.
+//!
+//! The keys of a map or a list are prefixed by the length and the name, the values of a list are
+//! ordered by a sequence number. The values are encoded by bincode, those of `kv` after their expiry.
+//! The expired entries are deleted by a janitor task, the reads ignore them until then.
+
+use std::fmt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use ::rocksdb::{
+    ColumnFamily, ColumnFamilyDescriptor, DBCompactionStyle, DBCompressionType, Direction, IteratorMode,
+    Options, WriteBatch, DB,
+};
+use serde::de::DeserializeOwned;
+
+use rmqtt::settings::{deserialize_duration, Bytesize};
+use rmqtt::{
+    anyhow::{anyhow, Result},
+    async_trait::async_trait,
+    bincode, log,
+    serde_json::{self, json},
+    timestamp_millis,
+    tokio::{self, task::JoinHandle},
+    TimestampMillis,
+};
+
+use crate::{AsyncIterator, Key};
+
+const CF_KV: &str = "kv";
+const CF_MAP: &str = "map";
+const CF_LIST: &str = "list";
+const CF_META: &str = "meta";
+
+const META_MAP: u8 = b'm';
+const META_LIST: u8 = b'l';
+
+//Number of names or keys read by one seek of the iterators
+const PAGE_SIZE: usize = 200;
+
+//Properties of each column family returned by info()
+const INT_PROPERTIES: [&str; 7] = [
+    "rocksdb.estimate-num-keys",
+    "rocksdb.estimate-live-data-size",
+    "rocksdb.total-sst-files-size",
+    "rocksdb.cur-size-all-mem-tables",
+    "rocksdb.num-immutable-mem-table",
+    "rocksdb.compaction-pending",
+    "rocksdb.estimate-pending-compaction-bytes",
+];
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CompactionStyle {
+    Level,
+    Universal,
+    Fifo,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    None,
+    Snappy,
+    Lz4,
+    Zstd,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RocksdbConfig {
+    ///Directory of the database, `{node}` is replaced by the node id
+    #[serde(default = "RocksdbConfig::path_default")]
+    pub path: String,
+    ///Size of a memtable of a column family, written to a file when it is full
+    #[serde(default = "RocksdbConfig::write_buffer_size_default")]
+    pub write_buffer_size: Bytesize,
+    ///Maximum number of memtables of a column family, the full ones waiting to be written included
+    #[serde(default = "RocksdbConfig::max_write_buffer_number_default")]
+    pub max_write_buffer_number: i32,
+    ///Size of the files of the level 1, the files of the next levels are larger
+    #[serde(default = "RocksdbConfig::target_file_size_base_default")]
+    pub target_file_size_base: Bytesize,
+    ///Maximum number of the flushes and the compactions running at once
+    #[serde(default = "RocksdbConfig::max_background_jobs_default")]
+    pub max_background_jobs: i32,
+    ///level, universal or fifo
+    #[serde(default = "RocksdbConfig::compaction_style_default")]
+    pub compaction_style: CompactionStyle,
+    ///none, snappy, lz4 or zstd
+    #[serde(default = "RocksdbConfig::compression_default")]
+    pub compression: Compression,
+    ///Interval between two deletions of the expired entries
+    #[serde(default = "RocksdbConfig::janitor_interval_default", deserialize_with = "deserialize_duration")]
+    pub janitor_interval: Duration,
+}
+
+impl Default for RocksdbConfig {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            path: Self::path_default(),
+            write_buffer_size: Self::write_buffer_size_default(),
+            max_write_buffer_number: Self::max_write_buffer_number_default(),
+            target_file_size_base: Self::target_file_size_base_default(),
+            max_background_jobs: Self::max_background_jobs_default(),
+            compaction_style: Self::compaction_style_default(),
+            compression: Self::compression_default(),
+            janitor_interval: Self::janitor_interval_default(),
+        }
+    }
+}
+
+impl RocksdbConfig {
+    fn path_default() -> String {
+        "/var/log/rmqtt/.cache/rocksdb/{node}".into()
+    }
+
+    fn write_buffer_size_default() -> Bytesize {
+        Bytesize::from(64 * 1024 * 1024)
+    }
+
+    fn max_write_buffer_number_default() -> i32 {
+        3
+    }
+
+    fn target_file_size_base_default() -> Bytesize {
+        Bytesize::from(64 * 1024 * 1024)
+    }
+
+    fn max_background_jobs_default() -> i32 {
+        4
+    }
+
+    fn compaction_style_default() -> CompactionStyle {
+        CompactionStyle::Level
+    }
+
+    fn compression_default() -> Compression {
+        Compression::Lz4
+    }
+
+    fn janitor_interval_default() -> Duration {
+        Duration::from_secs(60)
+    }
+
+    fn options(&self) -> Options {
+        let mut opts = Options::default();
+        opts.set_write_buffer_size(self.write_buffer_size.as_usize());
+        opts.set_max_write_buffer_number(self.max_write_buffer_number.max(2));
+        opts.set_target_file_size_base(self.target_file_size_base.as_u64());
+        opts.set_compaction_style(match self.compaction_style {
+            CompactionStyle::Level => DBCompactionStyle::Level,
+            CompactionStyle::Universal => DBCompactionStyle::Universal,
+            CompactionStyle::Fifo => DBCompactionStyle::Fifo,
+        });
+        opts.set_compression_type(match self.compression {
+            Compression::None => DBCompressionType::None,
+            Compression::Snappy => DBCompressionType::Snappy,
+            Compression::Lz4 => DBCompressionType::Lz4,
+            Compression::Zstd => DBCompressionType::Zstd,
+        });
+        opts
+    }
+}
+
+//The list values are the sequence numbers head..tail
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+struct Meta {
+    expire_at: Option<TimestampMillis>,
+    head: u64,
+    tail: u64,
+}
+
+impl Meta {
+    #[inline]
+    fn is_expired(&self, now: TimestampMillis) -> bool {
+        self.expire_at.map(|at| at <= now).unwrap_or(false)
+    }
+}
+
+struct Db {
+    db: DB,
+    //Serializes the read-modify-writes of the metas, the list pushes and pops
+    lock: Mutex<()>,
+}
+
+impl Db {
+    #[inline]
+    fn cf(&self, name: &str) -> Result<&ColumnFamily> {
+        self.db.cf_handle(name).ok_or_else(|| anyhow!("rocksdb column family {} not found", name))
+    }
+
+    #[inline]
+    fn meta(&self, meta_key: &[u8]) -> Result<Option<Meta>> {
+        Ok(match self.db.get_cf(self.cf(CF_META)?, meta_key)? {
+            Some(v) => Some(bincode::deserialize(&v)?),
+            None => None,
+        })
+    }
+
+    //The meta of the name, a new one if the name does not exist or is expired, the rows of an
+    //expired name are deleted
+    fn meta_or_new(&self, meta_key: &[u8], rows_cf: &str, now: TimestampMillis) -> Result<Meta> {
+        match self.meta(meta_key)? {
+            Some(meta) if !meta.is_expired(now) => Ok(meta),
+            Some(_) => {
+                self.remove_rows(meta_key, rows_cf)?;
+                Ok(Meta::default())
+            }
+            None => Ok(Meta::default()),
+        }
+    }
+
+    //Meta of an existing name, not expired
+    #[inline]
+    fn live_meta(&self, meta_key: &[u8]) -> Result<Option<Meta>> {
+        Ok(self.meta(meta_key)?.filter(|meta| !meta.is_expired(timestamp_millis())))
+    }
+
+    fn remove_rows(&self, meta_key: &[u8], rows_cf: &str) -> Result<()> {
+        let prefix = name_prefix(&meta_key[1..]);
+        let end = prefix_end(&prefix);
+        let mut batch = WriteBatch::default();
+        batch.delete_range_cf(self.cf(rows_cf)?, &prefix, &end);
+        batch.delete_cf(self.cf(CF_META)?, meta_key);
+        self.db.write(batch)?;
+        Ok(())
+    }
+
+    //Up to PAGE_SIZE keys of the column family after `last`, starting with `prefix`
+    fn page(&self, cf: &str, prefix: &[u8], last: Option<&[u8]>) -> Result<Vec<(Key, Vec<u8>)>> {
+        let from = last.unwrap_or(prefix);
+        let mut page = Vec::with_capacity(PAGE_SIZE);
+        for item in self.db.iterator_cf(self.cf(cf)?, IteratorMode::From(from, Direction::Forward)) {
+            let (k, v) = item?;
+            if !k.starts_with(prefix) {
+                break;
+            }
+            if Some(k.as_ref()) == last {
+                continue;
+            }
+            page.push((k.to_vec(), v.to_vec()));
+            if page.len() >= PAGE_SIZE {
+                break;
+            }
+        }
+        Ok(page)
+    }
+
+    //The janitor, deletes the expired key-values, maps and lists
+    fn remove_expireds(&self) -> Result<usize> {
+        let now = timestamp_millis();
+        let mut n = 0;
+        let mut last: Option<Key> = None;
+        loop {
+            let page = self.page(CF_META, &[], last.as_deref())?;
+            let done = page.len() < PAGE_SIZE;
+            for (meta_key, v) in page.iter() {
+                let meta: Meta = bincode::deserialize(v)?;
+                if meta.is_expired(now) {
+                    let rows_cf = if meta_key.first() == Some(&META_MAP) { CF_MAP } else { CF_LIST };
+                    let _guard = self.lock.lock().map_err(|e| anyhow!(e.to_string()))?;
+                    //pushed to again or expiry changed meanwhile
+                    if self.meta(meta_key)?.map(|m| m.is_expired(now)).unwrap_or(false) {
+                        self.remove_rows(meta_key, rows_cf)?;
+                        n += 1;
+                    }
+                }
+            }
+            last = page.last().map(|(k, _)| k.clone());
+            if done {
+                break;
+            }
+        }
+
+        let kv = self.cf(CF_KV)?;
+        let mut last: Option<Key> = None;
+        loop {
+            let page = self.page(CF_KV, &[], last.as_deref())?;
+            let done = page.len() < PAGE_SIZE;
+            let mut batch = WriteBatch::default();
+            for (key, v) in page.iter() {
+                if kv_is_expired(v, now) {
+                    batch.delete_cf(kv, key);
+                    n += 1;
+                }
+            }
+            if !batch.is_empty() {
+                self.db.write(batch)?;
+            }
+            last = page.last().map(|(k, _)| k.clone());
+            if done {
+                break;
+            }
+        }
+        Ok(n)
+    }
+}
+
+struct Inner {
+    cfg: RocksdbConfig,
+    db: Arc<Db>,
+    janitor: JoinHandle<()>,
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        self.janitor.abort();
+    }
+}
+
+#[derive(Clone)]
+pub struct RocksdbStorageDB {
+    inner: Arc<Inner>,
+}
+
+impl fmt::Debug for RocksdbStorageDB {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RocksdbStorageDB {{ path: {} }}", self.inner.cfg.path)
+    }
+}
+
+impl RocksdbStorageDB {
+    pub async fn new(cfg: RocksdbConfig) -> Result<Self> {
+        let mut opts = cfg.options();
+        opts.create_if_missing(true);
+        opts.create_missing_column_families(true);
+        opts.set_max_background_jobs(cfg.max_background_jobs.max(1));
+        let cfs = [CF_KV, CF_MAP, CF_LIST, CF_META]
+            .into_iter()
+            .map(|name| ColumnFamilyDescriptor::new(name, cfg.options()))
+            .collect::<Vec<_>>();
+        let db = DB::open_cf_descriptors(&opts, Path::new(&cfg.path), cfs)?;
+        let db = Arc::new(Db { db, lock: Mutex::new(()) });
+        let janitor = tokio::spawn(remove_expireds(db.clone(), cfg.janitor_interval));
+        Ok(Self { inner: Arc::new(Inner { cfg, db, janitor }) })
+    }
+
+    #[inline]
+    fn db(&self) -> &Db {
+        &self.inner.db
+    }
+
+    #[inline]
+    pub async fn map<N: AsRef<[u8]>>(&self, name: N, expire: Option<TimestampMillis>) -> Result<RocksdbMap> {
+        let m = RocksdbMap { db: self.inner.db.clone(), name: name.as_ref().to_vec() };
+        if let Some(expire) = expire {
+            set_expire(self.db(), &meta_key(META_MAP, &m.name), CF_MAP, expire)?;
+        }
+        Ok(m)
+    }
+
+    #[inline]
+    pub async fn map_remove<N: AsRef<[u8]>>(&self, name: N) -> Result<()> {
+        self.db().remove_rows(&meta_key(META_MAP, name.as_ref()), CF_MAP)
+    }
+
+    #[inline]
+    pub async fn list<N: AsRef<[u8]>>(
+        &self,
+        name: N,
+        expire: Option<TimestampMillis>,
+    ) -> Result<RocksdbList> {
+        let l = RocksdbList { db: self.inner.db.clone(), name: name.as_ref().to_vec() };
+        if let Some(expire) = expire {
+            set_expire(self.db(), &meta_key(META_LIST, &l.name), CF_LIST, expire)?;
+        }
+        Ok(l)
+    }
+
+    #[inline]
+    pub async fn list_remove<N: AsRef<[u8]>>(&self, name: N) -> Result<()> {
+        self.db().remove_rows(&meta_key(META_LIST, name.as_ref()), CF_LIST)
+    }
+
+    #[inline]
+    pub fn map_iter(&self) -> RocksdbIter<RocksdbMap> {
+        RocksdbIter::new(self.inner.db.clone(), CF_META, vec![META_MAP], None, |db, meta_key, meta| {
+            let meta: Meta = bincode::deserialize(meta)?;
+            Ok((!meta.is_expired(timestamp_millis()))
+                .then(|| RocksdbMap { db: db.clone(), name: meta_key[1..].to_vec() }))
+        })
+    }
+
+    #[inline]
+    pub fn list_iter(&self) -> RocksdbIter<RocksdbList> {
+        RocksdbIter::new(self.inner.db.clone(), CF_META, vec![META_LIST], None, |db, meta_key, meta| {
+            let meta: Meta = bincode::deserialize(meta)?;
+            Ok((!meta.is_expired(timestamp_millis()))
+                .then(|| RocksdbList { db: db.clone(), name: meta_key[1..].to_vec() }))
+        })
+    }
+
+    ///The keys matching the pattern, the keys are read from the literal prefix of the pattern
+    #[inline]
+    pub fn scan(&self, pattern: &[u8]) -> RocksdbIter<Key> {
+        RocksdbIter::new(
+            self.inner.db.clone(),
+            CF_KV,
+            glob_prefix(pattern),
+            Some(pattern.to_vec()),
+            |_, key, v| Ok((!kv_is_expired(v, timestamp_millis())).then(|| key.to_vec())),
+        )
+    }
+
+    #[inline]
+    pub async fn insert<K, V>(&self, key: K, val: &V) -> Result<()>
+    where
+        K: AsRef<[u8]>,
+        V: serde::Serialize,
+    {
+        //A new value has no expiry, like a redis SET
+        let db = self.db();
+        db.db.put_cf(db.cf(CF_KV)?, key, kv_encode(None, &bincode::serialize(val)?))?;
+        Ok(())
+    }
+
+    #[inline]
+    pub async fn get<K, V>(&self, key: K) -> Result<Option<V>>
+    where
+        K: AsRef<[u8]>,
+        V: DeserializeOwned,
+    {
+        let db = self.db();
+        match db.db.get_cf(db.cf(CF_KV)?, key)? {
+            Some(v) if !kv_is_expired(&v, timestamp_millis()) => Ok(Some(bincode::deserialize(&v[8..])?)),
+            _ => Ok(None),
+        }
+    }
+
+    #[inline]
+    pub async fn remove<K: AsRef<[u8]>>(&self, key: K) -> Result<()> {
+        let db = self.db();
+        db.db.delete_cf(db.cf(CF_KV)?, key)?;
+        Ok(())
+    }
+
+    #[inline]
+    pub async fn expire<K: AsRef<[u8]>>(&self, key: K, dur: TimestampMillis) -> Result<bool> {
+        let db = self.db();
+        let kv = db.cf(CF_KV)?;
+        let now = timestamp_millis();
+        let _guard = db.lock.lock().map_err(|e| anyhow!(e.to_string()))?;
+        match db.db.get_cf(kv, key.as_ref())? {
+            Some(v) if !kv_is_expired(&v, now) => {
+                db.db.put_cf(kv, key, kv_encode(Some(now + dur), &v[8..]))?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    pub async fn len(&self) -> Result<usize> {
+        let db = self.db();
+        let now = timestamp_millis();
+        let mut n = 0;
+        for item in db.db.iterator_cf(db.cf(CF_KV)?, IteratorMode::Start) {
+            let (_, v) = item?;
+            if !kv_is_expired(&v, now) {
+                n += 1;
+            }
+        }
+        Ok(n)
+    }
+
+    #[inline]
+    pub async fn counter_incr<K: AsRef<[u8]>>(&self, key: K, increment: isize) -> Result<()> {
+        let db = self.db();
+        let kv = db.cf(CF_KV)?;
+        let now = timestamp_millis();
+        let _guard = db.lock.lock().map_err(|e| anyhow!(e.to_string()))?;
+        //An expired counter starts again from zero
+        let (expire_at, counter) = match db.db.get_cf(kv, key.as_ref())? {
+            Some(v) if !kv_is_expired(&v, now) => (kv_expire_at(&v), bincode::deserialize::<isize>(&v[8..])?),
+            _ => (None, 0),
+        };
+        db.db.put_cf(kv, key, kv_encode(expire_at, &bincode::serialize(&(counter + increment))?))?;
+        Ok(())
+    }
+
+    #[inline]
+    pub async fn counter_get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<isize>> {
+        self.get::<_, isize>(key).await
+    }
+
+    ///The sizes and the compaction stats of the column families
+    pub async fn info(&self) -> Result<serde_json::Value> {
+        let db = self.db();
+        let mut cfs = serde_json::Map::new();
+        for name in [CF_KV, CF_MAP, CF_LIST, CF_META] {
+            let cf = db.cf(name)?;
+            let mut props = serde_json::Map::new();
+            for prop in INT_PROPERTIES {
+                props.insert(
+                    prop.trim_start_matches("rocksdb.").into(),
+                    json!(db.db.property_int_value_cf(cf, prop)?),
+                );
+            }
+            props.insert("levelstats".into(), json!(db.db.property_value_cf(cf, "rocksdb.levelstats")?));
+            cfs.insert(name.into(), serde_json::Value::Object(props));
+        }
+        Ok(json!({
+            "storage_engine": "RocksDB",
+            "path": self.inner.cfg.path,
+            "num-running-compactions": db.db.property_int_value("rocksdb.num-running-compactions")?,
+            "num-running-flushes": db.db.property_int_value("rocksdb.num-running-flushes")?,
+            "column_families": cfs,
+        }))
+    }
+}
+
+#[derive(Clone)]
+pub struct RocksdbMap {
+    db: Arc<Db>,
+    name: Key,
+}
+
+impl RocksdbMap {
+    #[inline]
+    pub fn name(&self) -> &[u8] {
+        &self.name
+    }
+
+    #[inline]
+    fn meta_key(&self) -> Key {
+        meta_key(META_MAP, &self.name)
+    }
+
+    #[inline]
+    pub async fn insert<K, V>(&self, key: K, val: &V) -> Result<()>
+    where
+        K: AsRef<[u8]>,
+        V: serde::Serialize,
+    {
+        let db = &self.db;
+        let meta_key = self.meta_key();
+        let mut batch = WriteBatch::default();
+        batch.put_cf(db.cf(CF_MAP)?, entry_key(&self.name, key.as_ref()), bincode::serialize(val)?);
+        let _guard = db.lock.lock().map_err(|e| anyhow!(e.to_string()))?;
+        //The name is registered by its first entry
+        if db.live_meta(&meta_key)?.is_none() {
+            let meta = db.meta_or_new(&meta_key, CF_MAP, timestamp_millis())?;
+            batch.put_cf(db.cf(CF_META)?, &meta_key, bincode::serialize(&meta)?);
+        }
+        db.db.write(batch)?;
+        Ok(())
+    }
+
+    #[inline]
+    pub async fn get<K, V>(&self, key: K) -> Result<Option<V>>
+    where
+        K: AsRef<[u8]>,
+        V: DeserializeOwned,
+    {
+        let db = &self.db;
+        if db.live_meta(&self.meta_key())?.is_none() {
+            return Ok(None);
+        }
+        match db.db.get_cf(db.cf(CF_MAP)?, entry_key(&self.name, key.as_ref()))? {
+            Some(v) => Ok(Some(bincode::deserialize(&v)?)),
+            None => Ok(None),
+        }
+    }
+
+    #[inline]
+    pub async fn clear(&self) -> Result<()> {
+        self.db.remove_rows(&self.meta_key(), CF_MAP)
+    }
+
+    #[inline]
+    pub async fn expire(&self, dur: TimestampMillis) -> Result<bool> {
+        update_expire(&self.db, &self.meta_key(), dur)
+    }
+}
+
+#[derive(Clone)]
+pub struct RocksdbList {
+    db: Arc<Db>,
+    name: Key,
+}
+
+impl RocksdbList {
+    #[inline]
+    pub fn name(&self) -> &[u8] {
+        &self.name
+    }
+
+    #[inline]
+    fn meta_key(&self) -> Key {
+        meta_key(META_LIST, &self.name)
+    }
+
+    #[inline]
+    pub async fn push<V: serde::Serialize>(&self, val: &V) -> Result<()> {
+        self.pushs_values(vec![bincode::serialize(val)?])
+    }
+
+    #[inline]
+    pub async fn pushs<V: serde::Serialize>(&self, vals: Vec<V>) -> Result<()> {
+        let values = vals.iter().map(bincode::serialize).collect::<bincode::Result<Vec<_>>>()?;
+        self.pushs_values(values)
+    }
+
+    fn pushs_values(&self, values: Vec<Vec<u8>>) -> Result<()> {
+        if values.is_empty() {
+            return Ok(());
+        }
+        let db = &self.db;
+        let meta_key = self.meta_key();
+        let list = db.cf(CF_LIST)?;
+        let _guard = db.lock.lock().map_err(|e| anyhow!(e.to_string()))?;
+        let mut meta = db.meta_or_new(&meta_key, CF_LIST, timestamp_millis())?;
+        let mut batch = WriteBatch::default();
+        for value in values {
+            batch.put_cf(list, value_key(&self.name, meta.tail), value);
+            meta.tail += 1;
+        }
+        batch.put_cf(db.cf(CF_META)?, &meta_key, bincode::serialize(&meta)?);
+        db.db.write(batch)?;
+        Ok(())
+    }
+
+    pub async fn push_limit<V>(&self, val: &V, limit: usize, pop_front_if_limited: bool) -> Result<Option<V>>
+    where
+        V: serde::Serialize + DeserializeOwned,
+    {
+        let value = bincode::serialize(val)?;
+        let db = &self.db;
+        let meta_key = self.meta_key();
+        let list = db.cf(CF_LIST)?;
+        let _guard = db.lock.lock().map_err(|e| anyhow!(e.to_string()))?;
+        let mut meta = db.meta_or_new(&meta_key, CF_LIST, timestamp_millis())?;
+        let mut batch = WriteBatch::default();
+        let popped = if (meta.tail - meta.head) as usize >= limit {
+            if !pop_front_if_limited {
+                return Err(anyhow!("Is full"));
+            }
+            let front_key = value_key(&self.name, meta.head);
+            let front = db.db.get_cf(list, &front_key)?;
+            batch.delete_cf(list, front_key);
+            meta.head += 1;
+            front
+        } else {
+            None
+        };
+        batch.put_cf(list, value_key(&self.name, meta.tail), value);
+        meta.tail += 1;
+        batch.put_cf(db.cf(CF_META)?, &meta_key, bincode::serialize(&meta)?);
+        db.db.write(batch)?;
+        popped.map(|v| Ok(bincode::deserialize(&v)?)).transpose()
+    }
+
+    #[inline]
+    pub async fn pop<V: DeserializeOwned>(&self) -> Result<Option<V>> {
+        let db = &self.db;
+        let meta_key = self.meta_key();
+        let list = db.cf(CF_LIST)?;
+        let _guard = db.lock.lock().map_err(|e| anyhow!(e.to_string()))?;
+        let mut meta = match db.live_meta(&meta_key)? {
+            Some(meta) if meta.head < meta.tail => meta,
+            _ => return Ok(None),
+        };
+        let front_key = value_key(&self.name, meta.head);
+        let front = db.db.get_cf(list, &front_key)?;
+        meta.head += 1;
+        let mut batch = WriteBatch::default();
+        batch.delete_cf(list, front_key);
+        batch.put_cf(db.cf(CF_META)?, &meta_key, bincode::serialize(&meta)?);
+        db.db.write(batch)?;
+        front.map(|v| Ok(bincode::deserialize(&v)?)).transpose()
+    }
+
+    #[inline]
+    pub async fn all<V: DeserializeOwned>(&self) -> Result<Vec<V>> {
+        let db = &self.db;
+        if db.live_meta(&self.meta_key())?.is_none() {
+            return Ok(Vec::new());
+        }
+        let prefix = name_prefix(&self.name);
+        let mut vals = Vec::new();
+        for item in db.db.iterator_cf(db.cf(CF_LIST)?, IteratorMode::From(&prefix, Direction::Forward)) {
+            let (k, v) = item?;
+            if !k.starts_with(&prefix) {
+                break;
+            }
+            vals.push(bincode::deserialize(&v)?);
+        }
+        Ok(vals)
+    }
+
+    #[inline]
+    pub async fn len(&self) -> Result<usize> {
+        Ok(self.db.live_meta(&self.meta_key())?.map(|meta| (meta.tail - meta.head) as usize).unwrap_or(0))
+    }
+
+    #[inline]
+    pub async fn clear(&self) -> Result<()> {
+        self.db.remove_rows(&self.meta_key(), CF_LIST)
+    }
+
+    #[inline]
+    pub async fn expire(&self, dur: TimestampMillis) -> Result<bool> {
+        update_expire(&self.db, &self.meta_key(), dur)
+    }
+}
+
+type MakeItem<T> = fn(&Arc<Db>, &[u8], &[u8]) -> Result<Option<T>>;
+
+///The names of the maps or the lists, or the keys, read by pages
+pub struct RocksdbIter<T> {
+    db: Arc<Db>,
+    cf: &'static str,
+    prefix: Vec<u8>,
+    pattern: Option<Vec<u8>>,
+    last: Option<Key>,
+    page: std::vec::IntoIter<(Key, Vec<u8>)>,
+    done: bool,
+    make: MakeItem<T>,
+}
+
+impl<T> RocksdbIter<T> {
+    fn new(
+        db: Arc<Db>,
+        cf: &'static str,
+        prefix: Vec<u8>,
+        pattern: Option<Vec<u8>>,
+        make: MakeItem<T>,
+    ) -> Self {
+        Self { db, cf, prefix, pattern, last: None, page: Vec::new().into_iter(), done: false, make }
+    }
+}
+
+#[async_trait]
+impl<T: Send> AsyncIterator for RocksdbIter<T> {
+    type Item = Result<T>;
+
+    async fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((key, v)) = self.page.next() {
+                if let Some(pattern) = self.pattern.as_ref() {
+                    if !glob_match(pattern, &key) {
+                        continue;
+                    }
+                }
+                match (self.make)(&self.db, &key, &v) {
+                    Ok(Some(item)) => return Some(Ok(item)),
+                    Ok(None) => continue,
+                    Err(e) => return Some(Err(e)),
+                }
+            }
+            if self.done {
+                return None;
+            }
+            match self.db.page(self.cf, &self.prefix, self.last.as_deref()) {
+                Ok(page) => {
+                    self.done = page.len() < PAGE_SIZE;
+                    self.last = page.last().map(|(k, _)| k.clone());
+                    self.page = page.into_iter();
+                }
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+fn set_expire(db: &Db, meta_key: &[u8], rows_cf: &str, dur: TimestampMillis) -> Result<()> {
+    let now = timestamp_millis();
+    let _guard = db.lock.lock().map_err(|e| anyhow!(e.to_string()))?;
+    let mut meta = db.meta_or_new(meta_key, rows_cf, now)?;
+    meta.expire_at = Some(now + dur);
+    db.db.put_cf(db.cf(CF_META)?, meta_key, bincode::serialize(&meta)?)?;
+    Ok(())
+}
+
+fn update_expire(db: &Db, meta_key: &[u8], dur: TimestampMillis) -> Result<bool> {
+    let now = timestamp_millis();
+    let _guard = db.lock.lock().map_err(|e| anyhow!(e.to_string()))?;
+    match db.meta(meta_key)? {
+        Some(mut meta) if !meta.is_expired(now) => {
+            meta.expire_at = Some(now + dur);
+            db.db.put_cf(db.cf(CF_META)?, meta_key, bincode::serialize(&meta)?)?;
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
+
+async fn remove_expireds(db: Arc<Db>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval.max(Duration::from_secs(1)));
+    loop {
+        ticker.tick().await;
+        let db = db.clone();
+        match tokio::task::spawn_blocking(move || db.remove_expireds()).await {
+            Ok(Ok(n)) => {
+                if n > 0 {
+                    log::debug!("rocksdb janitor removed {} expired entries", n);
+                }
+            }
+            Ok(Err(e)) => log::warn!("rocksdb janitor error, {:?}", e),
+            Err(e) => log::warn!("rocksdb janitor error, {:?}", e),
+        }
+    }
+}
+
+#[inline]
+fn meta_key(typ: u8, name: &[u8]) -> Key {
+    let mut key = Vec::with_capacity(name.len() + 1);
+    key.push(typ);
+    key.extend_from_slice(name);
+    key
+}
+
+//The length prefix keeps the names apart, "a" and "ab" do not share their keys
+#[inline]
+fn name_prefix(name: &[u8]) -> Key {
+    let mut prefix = Vec::with_capacity(name.len() + 4);
+    prefix.extend_from_slice(&(name.len() as u32).to_be_bytes());
+    prefix.extend_from_slice(name);
+    prefix
+}
+
+#[inline]
+fn entry_key(name: &[u8], key: &[u8]) -> Key {
+    let mut k = name_prefix(name);
+    k.extend_from_slice(key);
+    k
+}
+
+#[inline]
+fn value_key(name: &[u8], seq: u64) -> Key {
+    let mut k = name_prefix(name);
+    k.extend_from_slice(&seq.to_be_bytes());
+    k
+}
+
+//The first key after all the keys starting with the prefix
+fn prefix_end(prefix: &[u8]) -> Key {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return end;
+        }
+    }
+    vec![u8::MAX; prefix.len() + 1]
+}
+
+#[inline]
+fn kv_encode(expire_at: Option<TimestampMillis>, val: &[u8]) -> Vec<u8> {
+    let mut v = Vec::with_capacity(val.len() + 8);
+    v.extend_from_slice(&expire_at.unwrap_or(0).to_be_bytes());
+    v.extend_from_slice(val);
+    v
+}
+
+#[inline]
+fn kv_expire_at(v: &[u8]) -> Option<TimestampMillis> {
+    let mut at = [0u8; 8];
+    at.copy_from_slice(&v[..8]);
+    Some(TimestampMillis::from_be_bytes(at)).filter(|at| *at > 0)
+}
+
+#[inline]
+fn kv_is_expired(v: &[u8], now: TimestampMillis) -> bool {
+    v.len() < 8 || kv_expire_at(v).map(|at| at <= now).unwrap_or(false)
+}
+
+//The literal prefix of a glob pattern, before its first wildcard
+fn glob_prefix(pattern: &[u8]) -> Vec<u8> {
+    let mut prefix = Vec::new();
+    let mut escaped = false;
+    for &b in pattern {
+        match b {
+            _ if escaped => {
+                prefix.push(b);
+                escaped = false;
+            }
+            b'\\' => escaped = true,
+            b'*' | b'?' => break,
+            _ => prefix.push(b),
+        }
+    }
+    prefix
+}
+
+///Matches a glob pattern, `*` and `?` are wildcards, `\` escapes them
+fn glob_match(pattern: &[u8], key: &[u8]) -> bool {
+    let (mut p, mut k) = (0, 0);
+    //position of the last `*` in the pattern and of the key when it was met
+    let mut star: Option<(usize, usize)> = None;
+    while k < key.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, k));
+                p += 1;
+                continue;
+            }
+            Some(b'?') => {
+                p += 1;
+                k += 1;
+                continue;
+            }
+            Some(b'\\') if pattern.get(p + 1) == Some(&key[k]) => {
+                p += 2;
+                k += 1;
+                continue;
+            }
+            Some(&c) if c != b'\\' && c == key[k] => {
+                p += 1;
+                k += 1;
+                continue;
+            }
+            _ => {}
+        }
+        match star {
+            Some((sp, sk)) => {
+                p = sp + 1;
+                k = sk + 1;
+                star = Some((sp, sk + 1));
+            }
+            None => return false,
+        }
+    }
+    pattern[p.min(pattern.len())..].iter().all(|c| *c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob() {
+        assert_eq!(glob_prefix(b"$retain|*"), b"$retain|".to_vec());
+        assert_eq!(glob_prefix(b"a\\*b?c"), b"a*b".to_vec());
+        assert!(glob_match(b"$retain|*", b"$retain|a/b"));
+        assert!(glob_match(b"a/?/c*", b"a/b/cde"));
+        assert!(!glob_match(b"a/?/c*", b"a/bb/c"));
+        assert!(glob_match(b"a\\*b", b"a*b"));
+        assert!(!glob_match(b"a\\*b", b"axb"));
+        assert!(glob_match(b"*x*", b"abxcd"));
+        assert!(!glob_match(b"*x", b"abxcd"));
+    }
+
+    #[test]
+    fn test_keys() {
+        assert!(entry_key(b"a", b"bc") < entry_key(b"ab", b"c"));
+        assert!(value_key(b"l", 255) < value_key(b"l", 256));
+        assert_eq!(prefix_end(&[0, 0, 0, 1, b'a']), vec![0, 0, 0, 1, b'b']);
+        assert_eq!(prefix_end(&[0, 1, 0xff]), vec![0, 2]);
+        let v = kv_encode(Some(100), b"v");
+        assert_eq!(kv_expire_at(&v), Some(100));
+        assert!(kv_is_expired(&v, 100));
+        assert!(!kv_is_expired(&kv_encode(None, b"v"), 100));
+    }
+
+    #[test]
+    fn test_config() {
+        let cfg: RocksdbConfig = serde_json::from_value(json!({
+            "write_buffer_size": "32M",
+            "compaction_style": "universal",
+            "janitor_interval": "30s",
+        }))
+        .unwrap();
+        assert_eq!(cfg.write_buffer_size.as_usize(), 32 * 1024 * 1024);
+        assert!(matches!(cfg.compaction_style, CompactionStyle::Universal));
+        assert!(matches!(cfg.compression, Compression::Lz4));
+        assert_eq!(cfg.janitor_interval, Duration::from_secs(30));
+    }
+}
@@ -0,0 +1,55 @@
+//! ACL rules of a client, a publish checked against 10, 100 and 1000 rules, compiled into the trie of
+//! broker::acl and scanned linearly like the rules of the ACL plugins. The compiled check takes about
+//! the same time whatever the number of rules, it walks the levels of the topic.
+//!
+//! cargo bench -p rmqtt --bench acl
+
+use std::str::FromStr;
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+
+use rmqtt::broker::acl::{AclPermission, AclRule, AclRuleAction, AclRules, ACL_PUBLISH};
+use rmqtt::broker::topic::Topic;
+
+//Each device may publish to its topics, the last rule denies the others
+fn rules(n: usize) -> Vec<AclRule> {
+    let mut rules = (0..n - 1)
+        .map(|i| AclRule {
+            permission: AclPermission::Allow,
+            action: AclRuleAction::Publish,
+            topic: format!("site/{}/device/{}/+", i % 10, i),
+        })
+        .collect::<Vec<_>>();
+    rules.push(AclRule { permission: AclPermission::Deny, action: AclRuleAction::All, topic: "#".into() });
+    rules
+}
+
+fn linear(rules: &[(Topic, AclRule)], topic: &str) -> Option<bool> {
+    rules
+        .iter()
+        .find(|(filter, _)| filter.matches_str(topic))
+        .map(|(_, rule)| rule.permission == AclPermission::Allow)
+}
+
+fn bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("acl_check");
+    for n in [10, 100, 1000] {
+        let rules = rules(n);
+        let compiled = AclRules::compile(&rules, "dev", None);
+        let filters =
+            rules.iter().map(|r| (Topic::from_str(&r.topic).unwrap(), r.clone())).collect::<Vec<_>>();
+        //matched by the last allow rule, the worst case of the linear scan
+        let topic = format!("site/{}/device/{}/temp", (n - 2) % 10, n - 2);
+        assert_eq!(compiled.check(&topic, ACL_PUBLISH), linear(&filters, &topic));
+        group.bench_with_input(BenchmarkId::new("compiled", n), &topic, |b, topic| {
+            b.iter(|| black_box(compiled.check(topic, ACL_PUBLISH)))
+        });
+        group.bench_with_input(BenchmarkId::new("linear", n), &topic, |b, topic| {
+            b.iter(|| black_box(linear(&filters, topic)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench);
+criterion_main!(benches);
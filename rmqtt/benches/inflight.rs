@@ -0,0 +1,94 @@
+//! Inflight window, 100k messages through a window of max_inflight = 64, QoS 1 acks and
+//! QoS 2 PUBREC/PUBCOMP, compared with the previous DequeBTreeMap based window.
+//!
+//! cargo bench -p rmqtt --bench inflight
+
+use std::num::NonZeroU16;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use rust_box::dequemap::DequeBTreeMap;
+
+use rmqtt::broker::inflight::{Inflight, InflightMessage, MomentStatus};
+use rmqtt::{From, Id, PacketId, Publish, QoS};
+
+const MESSAGES: usize = 100_000;
+const MAX_INFLIGHT: usize = 64;
+
+fn message(from: &From, packet_id: PacketId) -> InflightMessage {
+    let publish = Publish {
+        dup: false,
+        retain: false,
+        qos: QoS::ExactlyOnce,
+        topic: "bench/inflight".into(),
+        packet_id: NonZeroU16::new(packet_id),
+        payload: Default::default(),
+        properties: Default::default(),
+        create_time: 0,
+    };
+    InflightMessage::new(MomentStatus::UnReceived, from.clone(), publish)
+}
+
+//Acks come back slightly out of order, every 4th message is acked one round later
+#[inline]
+fn ack_order(i: usize) -> usize {
+    if i % 4 == 0 && i >= MAX_INFLIGHT / 2 {
+        i - MAX_INFLIGHT / 2
+    } else {
+        i
+    }
+}
+
+fn inflight(from: &From) {
+    let mut win = Inflight::new(MAX_INFLIGHT, 30_000, 0);
+    let mut ids = Vec::with_capacity(MESSAGES);
+    for i in 0..MESSAGES {
+        if !win.has_credit() {
+            let packet_id = ids[ack_order(i - MAX_INFLIGHT)];
+            win.update_status(&packet_id, MomentStatus::UnComplete);
+            black_box(win.remove(&packet_id));
+        }
+        let packet_id = win.next_id().unwrap();
+        ids.push(packet_id);
+        win.push_back(message(from, packet_id));
+        black_box(win.get_timeout());
+        black_box(win.pop_front_timeout());
+    }
+}
+
+//The previous window, a DequeBTreeMap in insertion order
+fn dequemap(from: &From) {
+    let mut win: DequeBTreeMap<PacketId, InflightMessage> = DequeBTreeMap::default();
+    let mut ids = Vec::with_capacity(MESSAGES);
+    let mut next: PacketId = 0;
+    for i in 0..MESSAGES {
+        if win.len() >= MAX_INFLIGHT {
+            let packet_id = ids[ack_order(i - MAX_INFLIGHT)];
+            if let Some(m) = win.get_mut(&packet_id) {
+                m.status = MomentStatus::UnComplete;
+            }
+            black_box(win.remove(&packet_id));
+        }
+        let packet_id = loop {
+            next = next.wrapping_add(1);
+            if next != 0 && !win.contains_key(&next) {
+                break next;
+            }
+        };
+        ids.push(packet_id);
+        win.insert(packet_id, message(from, packet_id));
+        black_box(win.front().map(|(_, m)| m.update_time));
+    }
+}
+
+fn bench(c: &mut Criterion) {
+    let from = From::from_custom(Id::new(1, None, None, "bench".into(), None));
+    let mut group = c.benchmark_group("inflight");
+    group.throughput(Throughput::Elements(MESSAGES as u64));
+    group.sample_size(20);
+    group.bench_function("inflight", |b| b.iter(|| inflight(&from)));
+    group.bench_function("dequemap", |b| b.iter(|| dequemap(&from)));
+    group.finish();
+}
+
+criterion_group!(benches, bench);
+criterion_main!(benches);
@@ -0,0 +1,142 @@
+//! Topic tree, 5M routes over 5M unique topic filters, compared with the previous tree of
+//! HashMap<Level, Node> branches holding their own level strings. The heap bytes of each tree
+//! are printed once, then the inserts and the matches are timed.
+//!
+//! ROUTES=1000000 cargo bench -p rmqtt --bench topic_tree
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::{BTreeSet, HashMap};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+
+use rmqtt::broker::topic::{interned_segments, Level, Topic, TopicTree};
+
+struct Counting;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+fn routes() -> usize {
+    std::env::var("ROUTES").ok().and_then(|n| n.parse().ok()).unwrap_or(5_000_000)
+}
+
+//Few distinct levels near the root, many at the leaves, like device telemetry topics
+fn topic_filters(n: usize) -> Vec<Topic> {
+    (0..n)
+        .map(|i| Topic::from_str(&format!("site/{}/device/{}/telemetry", i % 100, i / 100)).unwrap())
+        .collect()
+}
+
+//The previous tree
+#[derive(Default)]
+struct HashNode {
+    values: BTreeSet<()>,
+    branches: HashMap<Level, HashNode, rmqtt::ahash::RandomState>,
+}
+
+impl HashNode {
+    fn insert(&mut self, path: &[Level]) {
+        match path.first() {
+            Some(first) => self.branches.entry(first.clone()).or_default().insert(&path[1..]),
+            None => {
+                self.values.insert(());
+            }
+        }
+    }
+
+    fn matches(&self, path: &[Level]) -> usize {
+        match path.first() {
+            Some(first) => self.branches.get(first).map(|n| n.matches(&path[1..])).unwrap_or_default(),
+            None => self.values.len(),
+        }
+    }
+}
+
+fn memory(filters: &[Topic]) {
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    let mut tree: TopicTree<()> = TopicTree::default();
+    for f in filters {
+        tree.insert(f, ());
+    }
+    let (segments, segments_bytes) = interned_segments();
+    println!(
+        "topic tree, routes: {}, heap: {} bytes, estimated: {} bytes, interned segments: {} ({} bytes)",
+        filters.len(),
+        ALLOCATED.load(Ordering::Relaxed) - before,
+        tree.memory_size(),
+        segments,
+        segments_bytes
+    );
+    drop(tree);
+
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    let mut tree = HashNode::default();
+    for f in filters {
+        tree.insert(f.levels());
+    }
+    println!(
+        "previous tree, routes: {}, heap: {} bytes",
+        filters.len(),
+        ALLOCATED.load(Ordering::Relaxed) - before
+    );
+}
+
+fn bench(c: &mut Criterion) {
+    let filters = topic_filters(routes());
+    memory(&filters);
+
+    let mut tree: TopicTree<()> = TopicTree::default();
+    let mut prev = HashNode::default();
+    for f in filters.iter() {
+        tree.insert(f, ());
+        prev.insert(f.levels());
+    }
+    let topic = Topic::from_str("site/42/device/4242/telemetry").unwrap();
+
+    let mut group = c.benchmark_group("topic_tree");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(filters.len() as u64));
+    group.bench_function("insert", |b| {
+        b.iter(|| {
+            let mut tree: TopicTree<()> = TopicTree::default();
+            for f in filters.iter() {
+                tree.insert(f, ());
+            }
+            black_box(tree.values_size())
+        })
+    });
+    group.bench_function("insert_previous", |b| {
+        b.iter(|| {
+            let mut tree = HashNode::default();
+            for f in filters.iter() {
+                tree.insert(f.levels());
+            }
+            black_box(tree.values.len())
+        })
+    });
+    group.finish();
+
+    let mut group = c.benchmark_group("topic_tree_match");
+    group.bench_function("matches", |b| b.iter(|| black_box(tree.matches(&topic).iter().count())));
+    group.bench_function("matches_previous", |b| b.iter(|| black_box(prev.matches(topic.levels()))));
+    group.finish();
+}
+
+criterion_group!(benches, bench);
+criterion_main!(benches);
@@ -0,0 +1,261 @@
+//! ACL rules of a client returned by the auth plugins with the AuthInfo, `AuthInfo::rules`. The rules
+//! are compiled once when the AuthInfo is attached to the session, the `%c` and `%u` placeholders
+//! replaced by the client id and the username, into a trie of the topic levels. Each rule ending at
+//! a node keeps its index, its permission and its actions as a bitmask, a publish or a subscribe is
+//! checked by walking the levels of its topic, O(topic levels) whatever the number of rules. The
+//! first rule matching, in their order, decides, like the rules of the ACL plugins.
+//!
+//! The rules are checked by the hook manager before the ACL hooks, after the superuser and the
+//! anonymous checks. A topic no rule matches is left to the ACL plugins.
+//!
+//! A rule, `action` defaults to "all":
+//! `{"permission": "allow", "action": "publish", "topic": "devices/%c/#"}`
+//!
+//! The ACL plugins compile their rules of a client the same way, see `AclRules::push`, with the rules
+//! matching all the topics and the rules matching a topic, or a topic filter, equal to theirs.
+
+use crate::broker::types::HashMap;
+
+///Action bitmask of the rules
+pub const ACL_PUBLISH: u8 = 0b01;
+pub const ACL_SUBSCRIBE: u8 = 0b10;
+
+const PH_C: &str = "%c";
+const PH_U: &str = "%u";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AclPermission {
+    Allow,
+    Deny,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AclRuleAction {
+    Publish,
+    Subscribe,
+    #[default]
+    All,
+}
+
+impl AclRuleAction {
+    #[inline]
+    pub fn mask(&self) -> u8 {
+        match self {
+            AclRuleAction::Publish => ACL_PUBLISH,
+            AclRuleAction::Subscribe => ACL_SUBSCRIBE,
+            AclRuleAction::All => ACL_PUBLISH | ACL_SUBSCRIBE,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AclRule {
+    pub permission: AclPermission,
+    #[serde(default)]
+    pub action: AclRuleAction,
+    ///Topic filter, with the `%c` and `%u` placeholders
+    pub topic: String,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    idx: u32,
+    allow: bool,
+    actions: u8,
+}
+
+#[derive(Debug, Default)]
+struct Node {
+    children: HashMap<String, Node>,
+    //The rules ending at the node
+    ends: Vec<Entry>,
+}
+
+///The topic of a compiled rule
+#[derive(Debug, Clone, Copy)]
+pub enum AclTopic<'a> {
+    ///All the topics, those starting with '$' included
+    All,
+    ///A topic filter, its wildcards do not match a first level starting with '$'
+    Filter(&'a str),
+    ///The topic name, or the topic filter, equal to it
+    Eq(&'a str),
+}
+
+///The compiled rules of a client
+#[derive(Debug, Default)]
+pub struct AclRules {
+    root: Node,
+    //The rules of all the topics
+    all: Vec<Entry>,
+    //topic => the rules of the topic equal to it
+    eqs: HashMap<String, Vec<Entry>>,
+    len: usize,
+}
+
+impl AclRules {
+    ///Compiles the rules, a rule with an invalid topic filter is skipped
+    pub fn compile(rules: &[AclRule], client_id: &str, username: Option<&str>) -> Self {
+        let mut acl = AclRules::default();
+        for rule in rules.iter() {
+            let topic = rule.topic.replace(PH_C, client_id).replace(PH_U, username.unwrap_or_default());
+            if !acl.push(rule.permission, rule.action, AclTopic::Filter(&topic)) {
+                log::warn!("{:?} ACL rule skipped, invalid topic filter: {:?}", client_id, rule.topic);
+            }
+        }
+        acl
+    }
+
+    ///Adds a rule after the others, the placeholders are already replaced, false if the topic filter
+    ///is invalid
+    pub fn push(&mut self, permission: AclPermission, action: AclRuleAction, topic: AclTopic) -> bool {
+        let entry =
+            Entry { idx: self.len as u32, allow: permission == AclPermission::Allow, actions: action.mask() };
+        match topic {
+            AclTopic::All => self.all.push(entry),
+            AclTopic::Eq(topic) => self.eqs.entry(topic.to_owned()).or_default().push(entry),
+            AclTopic::Filter(topic) => {
+                if !valid(topic) {
+                    return false;
+                }
+                let node = topic
+                    .split('/')
+                    .fold(&mut self.root, |node, level| node.children.entry(level.to_owned()).or_default());
+                node.ends.push(entry);
+            }
+        }
+        self.len += 1;
+        true
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    ///Whether the action on the topic, a topic name or a topic filter, is allowed by the first rule
+    ///matching, None if no rule matches
+    #[inline]
+    pub fn check(&self, topic: &str, action: u8) -> Option<bool> {
+        let levels = topic.split('/').collect::<Vec<_>>();
+        let mut first: Option<Entry> = None;
+        select(&self.all, action, &mut first);
+        if let Some(eqs) = self.eqs.get(topic) {
+            select(eqs, action, &mut first);
+        }
+        walk(&self.root, &levels, 0, action, &mut first);
+        first.map(|e| e.allow)
+    }
+}
+
+fn walk(node: &Node, levels: &[&str], i: usize, action: u8, first: &mut Option<Entry>) {
+    //the wildcards of the rules do not match a first level starting with '$'
+    let wildcards = i > 0 || !levels.first().map(|l| l.starts_with('$')).unwrap_or(false);
+    if wildcards {
+        //'#' matches the parent level too
+        if let Some(multi) = node.children.get("#") {
+            select(&multi.ends, action, first);
+        }
+    }
+    let level = match levels.get(i) {
+        Some(level) => *level,
+        None => {
+            select(&node.ends, action, first);
+            return;
+        }
+    };
+    //a '#' of a topic filter is only matched by a '#' of a rule
+    if level == "#" {
+        return;
+    }
+    if level != "+" {
+        if let Some(child) = node.children.get(level) {
+            walk(child, levels, i + 1, action, first);
+        }
+    }
+    if wildcards {
+        if let Some(single) = node.children.get("+") {
+            walk(single, levels, i + 1, action, first);
+        }
+    }
+}
+
+#[inline]
+fn select(entries: &[Entry], action: u8, first: &mut Option<Entry>) {
+    for e in entries.iter().filter(|e| e.actions & action != 0) {
+        if first.map(|f| e.idx < f.idx).unwrap_or(true) {
+            *first = Some(*e);
+        }
+    }
+}
+
+//'#' only as the last level, the wildcards alone in their level
+fn valid(topic: &str) -> bool {
+    let levels = topic.split('/').collect::<Vec<_>>();
+    levels.iter().enumerate().all(|(i, level)| {
+        if level.contains('#') {
+            *level == "#" && i == levels.len() - 1
+        } else {
+            !level.contains('+') || *level == "+"
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AclPermission, AclRule, AclRuleAction, AclRules, AclTopic, ACL_PUBLISH, ACL_SUBSCRIBE};
+
+    fn rule(permission: AclPermission, action: AclRuleAction, topic: &str) -> AclRule {
+        AclRule { permission, action, topic: topic.into() }
+    }
+
+    #[test]
+    fn test_check() {
+        use AclPermission::*;
+        use AclRuleAction::*;
+        let rules = vec![
+            rule(Deny, Publish, "devices/%c/ctrl"),
+            rule(Allow, All, "devices/%c/#"),
+            rule(Allow, Subscribe, "users/%u/+/status"),
+            rule(Deny, All, "#"),
+            rule(Allow, All, "a/#/b"),
+        ];
+        let acl = AclRules::compile(&rules, "dev1", Some("alice"));
+        assert_eq!(acl.len(), 4);
+        assert_eq!(acl.check("devices/dev1/ctrl", ACL_PUBLISH), Some(false));
+        assert_eq!(acl.check("devices/dev1/ctrl", ACL_SUBSCRIBE), Some(true));
+        assert_eq!(acl.check("devices/dev1", ACL_PUBLISH), Some(true));
+        assert_eq!(acl.check("devices/dev1/temp/1", ACL_PUBLISH), Some(true));
+        assert_eq!(acl.check("devices/dev2/temp", ACL_PUBLISH), Some(false));
+        assert_eq!(acl.check("users/alice/x/status", ACL_SUBSCRIBE), Some(true));
+        assert_eq!(acl.check("users/alice/+/status", ACL_SUBSCRIBE), Some(true));
+        assert_eq!(acl.check("users/alice/#", ACL_SUBSCRIBE), Some(false));
+        assert_eq!(acl.check("users/alice/x/status", ACL_PUBLISH), Some(false));
+        assert_eq!(acl.check("$SYS/brokers", ACL_SUBSCRIBE), None);
+    }
+
+    #[test]
+    fn test_push() {
+        use AclPermission::*;
+        use AclRuleAction::*;
+        let mut acl = AclRules::default();
+        assert!(acl.push(Deny, Subscribe, AclTopic::Eq("#")));
+        assert!(acl.push(Allow, Publish, AclTopic::Filter("$SYS/#")));
+        assert!(!acl.push(Allow, All, AclTopic::Filter("a/#/b")));
+        assert!(acl.push(Deny, Publish, AclTopic::All));
+        assert!(acl.push(Allow, All, AclTopic::Filter("#")));
+        assert_eq!(acl.len(), 4);
+        assert_eq!(acl.check("#", ACL_SUBSCRIBE), Some(false));
+        assert_eq!(acl.check("a/#", ACL_SUBSCRIBE), Some(true));
+        assert_eq!(acl.check("$SYS/brokers", ACL_PUBLISH), Some(true));
+        assert_eq!(acl.check("$SYS/brokers", ACL_SUBSCRIBE), None);
+        assert_eq!(acl.check("a/b", ACL_PUBLISH), Some(false));
+    }
+}
@@ -0,0 +1,122 @@
+//! ACL decision cache of the hook manager, an optional layer in front of the ACL plugins. The publish
+//! decisions are cached by client and topic for `acl_cache.ttl`, the cached decision is returned
+//! without running the ACL hooks. The SUBSCRIBE checks are not cached, they are rare and a SUBSCRIBE
+//! re-evaluates the rules of its topic filters. The decisions of a client are dropped when it
+//! disconnects or its AuthInfo, with its ACL rules, is attached again. The ACL plugins call
+//! `invalidate` or `clear` when the rules of their backend change.
+//!
+//! The decisions of the superusers are not cached, the superusers are not checked.
+
+use once_cell::sync::OnceCell;
+
+use crate::broker::types::{
+    timestamp_millis, ClientId, DashMap, HashMap, Id, PublishAclResult, TimestampMillis, TopicName,
+};
+use crate::Runtime;
+
+struct Decisions {
+    //The session of the decisions, those of a previous session of the client are not returned
+    id: Id,
+    entries: HashMap<TopicName, (PublishAclResult, TimestampMillis)>,
+}
+
+pub struct AclCache {
+    clients: DashMap<ClientId, Decisions>,
+}
+
+impl AclCache {
+    #[inline]
+    pub fn instance() -> &'static Self {
+        static INSTANCE: OnceCell<AclCache> = OnceCell::new();
+        INSTANCE.get_or_init(|| Self { clients: DashMap::default() })
+    }
+
+    #[inline]
+    pub fn enabled() -> bool {
+        Runtime::instance().settings.acl_cache.enable
+    }
+
+    #[inline]
+    pub fn get_publish(&self, id: &Id, topic: &TopicName) -> Option<PublishAclResult> {
+        let decisions = self.clients.get(&id.client_id)?;
+        if decisions.id != *id {
+            return None;
+        }
+        let (decision, expire) = decisions.entries.get(topic)?;
+        if *expire > timestamp_millis() {
+            Some(decision.clone())
+        } else {
+            None
+        }
+    }
+
+    pub fn set_publish(&self, id: &Id, topic: TopicName, decision: PublishAclResult) {
+        let cfg = &Runtime::instance().settings.acl_cache;
+        let now = timestamp_millis();
+        let mut decisions = self
+            .clients
+            .entry(id.client_id.clone())
+            .or_insert_with(|| Decisions { id: id.clone(), entries: HashMap::default() });
+        if decisions.id != *id {
+            decisions.id = id.clone();
+            decisions.entries.clear();
+        }
+        if cfg.max_per_client > 0 && decisions.entries.len() >= cfg.max_per_client {
+            decisions.entries.retain(|_, (_, expire)| *expire > now);
+            if decisions.entries.len() >= cfg.max_per_client {
+                return;
+            }
+        }
+        decisions.entries.insert(topic, (decision, now + cfg.ttl.as_millis() as TimestampMillis));
+    }
+
+    ///Drops the decisions of the client
+    #[inline]
+    pub fn remove(&self, id: &Id) {
+        self.clients.remove_if(&id.client_id, |_, decisions| decisions.id == *id);
+    }
+
+    ///Drops the decisions of the client, or of all the clients, of the topic, or of all the topics.
+    ///Returns the number of decisions dropped.
+    pub fn invalidate(&self, client_id: Option<&str>, topic: Option<&str>) -> usize {
+        let mut removeds = 0;
+        let mut invalidate = |decisions: &mut Decisions| {
+            let len = decisions.entries.len();
+            if let Some(topic) = topic {
+                decisions.entries.retain(|t, _| **t != *topic);
+            } else {
+                decisions.entries.clear();
+            }
+            removeds += len - decisions.entries.len();
+        };
+        if let Some(client_id) = client_id {
+            if let Some(mut decisions) = self.clients.get_mut(client_id) {
+                invalidate(&mut decisions);
+            }
+        } else {
+            for mut decisions in self.clients.iter_mut() {
+                invalidate(&mut decisions);
+            }
+        }
+        removeds
+    }
+
+    ///Drops all the decisions
+    #[inline]
+    pub fn clear(&self) -> usize {
+        let removeds = self.len();
+        self.clients.clear();
+        removeds
+    }
+
+    ///The number of decisions cached
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.clients.iter().map(|decisions| decisions.entries.len()).sum()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
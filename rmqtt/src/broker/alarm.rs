@@ -0,0 +1,318 @@
+//! Alarms of the node. The checks run every `alarm.check_interval`, an alarm is activated once until
+//! its check clears it, each activation and deactivation is an event, kept in a bounded history and
+//! sent to the subscribers, rmqtt-sys-topic publishes them to $SYS/brokers/{node}/alarms. The built-in
+//! checks watch the memory, the file descriptors, the message queues, the storage backends (the
+//! readiness checks registered by the plugins) and the cluster, the plugins register their own sources.
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use once_cell::sync::OnceCell;
+use rust_box::std_ext::RwLock;
+use systemstat::Platform;
+use tokio::sync::broadcast;
+
+use crate::broker::health::HealthProbe;
+use crate::broker::overload::Overload;
+use crate::broker::types::{timestamp_millis, HashMap, NodeId, TimestampMillis};
+use crate::Runtime;
+
+pub const HIGH_MEMORY: &str = "high_memory";
+pub const FD_EXHAUSTION: &str = "fd_exhaustion";
+pub const QUEUE_OVERLOAD: &str = "queue_overload";
+pub const STORAGE_BACKEND_DOWN: &str = "storage_backend_down";
+pub const CLUSTER_PARTITION: &str = "cluster_partition";
+
+#[async_trait]
+pub trait AlarmSource: Sync + Send {
+    ///The message of the alarm if it is raised, none if it is cleared
+    async fn check(&self) -> Option<String>;
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Alarm {
+    pub name: String,
+    pub message: String,
+    pub node_id: NodeId,
+    pub activated_at: TimestampMillis,
+    pub deactivated_at: Option<TimestampMillis>,
+}
+
+impl Alarm {
+    #[inline]
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "name": self.name,
+            "message": self.message,
+            "node_id": self.node_id,
+            "activated": self.deactivated_at.is_none(),
+            "activated_at": self.activated_at,
+            "deactivated_at": self.deactivated_at,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AlarmEventKind {
+    Activate,
+    Deactivate,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AlarmEvent {
+    pub seq: u64,
+    pub event: AlarmEventKind,
+    pub alarm: Alarm,
+}
+
+impl AlarmEvent {
+    #[inline]
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut body = self.alarm.to_json();
+        if let Some(obj) = body.as_object_mut() {
+            obj.insert("seq".into(), json!(self.seq));
+            obj.insert("event".into(), json!(self.event));
+        }
+        body
+    }
+}
+
+#[derive(Default)]
+struct Alarms {
+    seq: u64,
+    actives: HashMap<String, Alarm>,
+    history: VecDeque<AlarmEvent>,
+}
+
+pub struct AlarmManager {
+    alarms: RwLock<Alarms>,
+    sources: RwLock<HashMap<String, Arc<dyn AlarmSource>>>,
+    tx: broadcast::Sender<AlarmEvent>,
+}
+
+impl AlarmManager {
+    #[inline]
+    pub fn instance() -> &'static Self {
+        static INSTANCE: OnceCell<AlarmManager> = OnceCell::new();
+        INSTANCE.get_or_init(|| Self {
+            alarms: RwLock::new(Alarms::default()),
+            sources: RwLock::new(HashMap::default()),
+            tx: broadcast::channel(256).0,
+        })
+    }
+
+    ///Activates the alarm, false if it is already active, its message is updated
+    pub fn activate(&self, name: &str, message: String) -> bool {
+        let event = {
+            let mut alarms = self.alarms.write();
+            if let Some(alarm) = alarms.actives.get_mut(name) {
+                alarm.message = message;
+                return false;
+            }
+            let alarm = Alarm {
+                name: name.into(),
+                message,
+                node_id: Runtime::instance().node.id(),
+                activated_at: timestamp_millis(),
+                deactivated_at: None,
+            };
+            log::warn!("alarm {} activated, {}", alarm.name, alarm.message);
+            alarms.actives.insert(name.into(), alarm.clone());
+            Self::push(&mut alarms, AlarmEventKind::Activate, alarm)
+        };
+        let _ = self.tx.send(event);
+        true
+    }
+
+    ///Deactivates the alarm, false if it is not active
+    pub fn deactivate(&self, name: &str) -> bool {
+        let event = {
+            let mut alarms = self.alarms.write();
+            let mut alarm = if let Some(alarm) = alarms.actives.remove(name) { alarm } else { return false };
+            alarm.deactivated_at = Some(timestamp_millis());
+            log::info!("alarm {} deactivated", alarm.name);
+            Self::push(&mut alarms, AlarmEventKind::Deactivate, alarm)
+        };
+        let _ = self.tx.send(event);
+        true
+    }
+
+    #[inline]
+    fn push(alarms: &mut Alarms, event: AlarmEventKind, alarm: Alarm) -> AlarmEvent {
+        alarms.seq += 1;
+        let event = AlarmEvent { seq: alarms.seq, event, alarm };
+        alarms.history.push_back(event.clone());
+        while alarms.history.len() > Runtime::instance().settings.alarm.history_max {
+            alarms.history.pop_front();
+        }
+        event
+    }
+
+    #[inline]
+    pub fn actives(&self) -> Vec<Alarm> {
+        let mut actives = self.alarms.read().actives.values().cloned().collect::<Vec<_>>();
+        actives.sort_by_key(|a| a.activated_at);
+        actives
+    }
+
+    ///The recent events, the oldest first
+    #[inline]
+    pub fn history(&self) -> Vec<AlarmEvent> {
+        self.alarms.read().history.iter().cloned().collect()
+    }
+
+    ///The events activated or deactivated from now on
+    #[inline]
+    pub fn subscribe(&self) -> broadcast::Receiver<AlarmEvent> {
+        self.tx.subscribe()
+    }
+
+    ///Registers an alarm source, the name of the alarm, it replaces the source of the same name
+    #[inline]
+    pub fn register<N: Into<String>>(&self, name: N, source: Arc<dyn AlarmSource>) {
+        self.sources.write().insert(name.into(), source);
+    }
+
+    ///Unregisters the alarm source, its active alarm is deactivated
+    #[inline]
+    pub fn unregister(&self, name: &str) {
+        self.sources.write().remove(name);
+        self.deactivate(name);
+    }
+
+    pub fn start(&'static self) {
+        let cfg = &Runtime::instance().settings.alarm;
+        if !cfg.enable {
+            return;
+        }
+        ntex::rt::spawn(async move {
+            loop {
+                tokio::time::sleep(cfg.check_interval).await;
+                self.check().await;
+            }
+        });
+    }
+
+    async fn check(&self) {
+        self.update(HIGH_MEMORY, Self::check_memory());
+        self.update(FD_EXHAUSTION, Self::check_fds());
+        self.update(QUEUE_OVERLOAD, Self::check_queues());
+        self.update(STORAGE_BACKEND_DOWN, Self::check_storages().await);
+        self.update(CLUSTER_PARTITION, Self::check_cluster().await);
+        let sources =
+            self.sources.read().iter().map(|(name, s)| (name.clone(), s.clone())).collect::<Vec<_>>();
+        for (name, source) in sources {
+            self.update(&name, source.check().await);
+        }
+    }
+
+    #[inline]
+    fn update(&self, name: &str, message: Option<String>) {
+        match message {
+            Some(message) => {
+                self.activate(name, message);
+            }
+            None => {
+                self.deactivate(name);
+            }
+        }
+    }
+
+    fn check_memory() -> Option<String> {
+        let watermark = Runtime::instance().settings.alarm.memory_high_watermark;
+        if watermark <= 0.0 {
+            return None;
+        }
+        let mem = systemstat::System::new().memory().ok()?;
+        let (total, free) = (mem.total.as_u64(), mem.free.as_u64());
+        if total == 0 {
+            return None;
+        }
+        let ratio = total.saturating_sub(free) as f32 / total as f32;
+        if ratio >= watermark {
+            Some(format!("memory used {:.2}, high watermark {:.2}", ratio, watermark))
+        } else {
+            None
+        }
+    }
+
+    fn check_fds() -> Option<String> {
+        let watermark = Runtime::instance().settings.alarm.fd_high_watermark;
+        if watermark <= 0.0 {
+            return None;
+        }
+        let used = std::fs::read_dir("/proc/self/fd").ok()?.count();
+        let limit = max_open_files(&std::fs::read_to_string("/proc/self/limits").ok()?)?;
+        if limit > 0 && used as f32 / limit as f32 >= watermark {
+            Some(format!("{} file descriptors open, limit {}", used, limit))
+        } else {
+            None
+        }
+    }
+
+    fn check_queues() -> Option<String> {
+        let max = Runtime::instance().settings.alarm.message_queues;
+        let queues = Runtime::instance().stats.message_queues.count();
+        let level = Overload::instance().level();
+        if level > 0 {
+            Some(format!("overload level {}, {} messages queued", level, queues))
+        } else if max > 0 && queues >= max {
+            Some(format!("{} messages queued, maximum {}", queues, max))
+        } else {
+            None
+        }
+    }
+
+    async fn check_storages() -> Option<String> {
+        let checks = HealthProbe::instance().registered();
+        let downs = futures::future::join_all(checks.iter().map(|(name, check)| async move {
+            HealthProbe::timeout(check.check()).await.err().map(|e| format!("{}: {}", name, e))
+        }))
+        .await
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+        if downs.is_empty() {
+            None
+        } else {
+            Some(downs.join(", "))
+        }
+    }
+
+    #[inline]
+    async fn check_cluster() -> Option<String> {
+        HealthProbe::timeout(HealthProbe::check_cluster()).await.err().map(|e| e.to_string())
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "actives": self.actives().iter().map(|a| a.to_json()).collect::<Vec<_>>(),
+            "history": self.history().iter().map(|e| e.to_json()).collect::<Vec<_>>(),
+        })
+    }
+}
+
+///The soft limit of the open files of /proc/self/limits, none if unlimited
+#[inline]
+fn max_open_files(limits: &str) -> Option<usize> {
+    limits.lines().find(|l| l.starts_with("Max open files"))?.split_whitespace().nth(3)?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::max_open_files;
+
+    #[test]
+    fn test_max_open_files() {
+        let limits = "Limit                     Soft Limit           Hard Limit           Units\n\
+                      Max processes             63432                63432                processes\n\
+                      Max open files            1024                 524288               files\n";
+        assert_eq!(max_open_files(limits), Some(1024));
+        assert_eq!(
+            max_open_files("Max open files            unlimited            unlimited            files"),
+            None
+        );
+        assert_eq!(max_open_files(""), None);
+    }
+}
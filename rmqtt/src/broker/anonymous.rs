@@ -0,0 +1,58 @@
+//! Anonymous clients of the listeners. A client is anonymous when it connects without a username to a
+//! listener with `allow_anonymous`, or is not found by the auth plugins, and every client of a listener
+//! in `anonymous_mode` is, the auth plugins are bypassed and its username is replaced by
+//! `anonymous_username` if set. An anonymous session gets the `anonymous_*` limits of the listener,
+//! the subscription limits, the topic prefixes it may publish to and subscribe to, and a token bucket
+//! of its publishes, see publish_quota. The anonymous sessions are tagged in the client queries and
+//! counted in the stats.
+
+use crate::broker::types::AuthInfo;
+use crate::settings::listener::Listener;
+
+///The AuthInfo of an anonymous client with the anonymous limits of the listener, others unchanged
+#[inline]
+pub fn limits(listen_cfg: &Listener, auth_info: AuthInfo) -> AuthInfo {
+    if !auth_info.anonymous {
+        return auth_info;
+    }
+    AuthInfo {
+        max_subscriptions: listen_cfg.anonymous_max_subscriptions.or(auth_info.max_subscriptions),
+        max_wildcard_subscriptions: listen_cfg
+            .anonymous_max_wildcard_subscriptions
+            .or(auth_info.max_wildcard_subscriptions),
+        max_topic_levels: listen_cfg.anonymous_max_topic_levels.or(auth_info.max_topic_levels),
+        ..auth_info
+    }
+}
+
+#[inline]
+pub fn publish_allowed(listen_cfg: &Listener, topic: &str) -> bool {
+    allowed(&listen_cfg.anonymous_publish_topics, topic)
+}
+
+#[inline]
+pub fn subscribe_allowed(listen_cfg: &Listener, topic_filter: &str) -> bool {
+    allowed(&listen_cfg.anonymous_subscribe_topics, topic_filter)
+}
+
+//No prefix allows all the topics
+#[inline]
+fn allowed(prefixes: &[String], topic: &str) -> bool {
+    prefixes.is_empty() || prefixes.iter().any(|prefix| topic.starts_with(prefix.as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::allowed;
+
+    #[test]
+    fn test_allowed() {
+        let prefixes = vec!["sandbox/".to_owned(), "public/".to_owned()];
+        assert!(allowed(&prefixes, "sandbox/a/b"));
+        assert!(allowed(&prefixes, "public/#"));
+        assert!(!allowed(&prefixes, "#"));
+        assert!(!allowed(&prefixes, "+/a"));
+        assert!(!allowed(&prefixes, "sandbox"));
+        assert!(allowed(&[], "private/a"));
+    }
+}
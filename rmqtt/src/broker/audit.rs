@@ -0,0 +1,238 @@
+//! Audit log of the security-relevant events, authentication, ACL denials, admin kicks and session
+//! purges, bans, plugin start/stop and config reloads. The records are JSON, written by a dedicated
+//! thread to a rotating file or to syslog, and passed to the `audit_record` hook for the plugins
+//! forwarding them to a SIEM.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::sync::mpsc;
+
+use once_cell::sync::OnceCell;
+
+use crate::broker::types::{Id, NodeId};
+use crate::settings::log::{Audit, AuditTo};
+use crate::Runtime;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent {
+    AuthSuccess {
+        id: Id,
+        superuser: bool,
+    },
+    AuthFailure {
+        id: Id,
+        reason: String,
+    },
+    AclDenied {
+        id: Id,
+        action: String,
+        topic: String,
+    },
+    ///Kicked by an administrator
+    Kicked {
+        id: Id,
+        by: String,
+    },
+    ///A stuck session purged from the cluster by an administrator
+    SessionPurged {
+        client_id: String,
+        by: String,
+    },
+    ///Inconsistencies of the routes, the sessions or the retained messages repaired by an
+    ///administrator
+    ConsistencyRepaired {
+        repaired: usize,
+        by: String,
+    },
+    ///Recorded by the plugins maintaining a ban list
+    BanAdded {
+        target: String,
+        by: String,
+        reason: Option<String>,
+    },
+    PluginStarted {
+        name: String,
+    },
+    PluginStopped {
+        name: String,
+    },
+    ConfigReloaded {
+        target: String,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditRecord {
+    pub time: String,
+    pub node_id: NodeId,
+    #[serde(flatten)]
+    pub event: AuditEvent,
+}
+
+pub struct AuditLog {
+    tx: Option<mpsc::Sender<String>>,
+}
+
+impl AuditLog {
+    #[inline]
+    pub fn instance() -> &'static Self {
+        static INSTANCE: OnceCell<AuditLog> = OnceCell::new();
+        INSTANCE.get_or_init(|| {
+            let cfg = &Runtime::instance().settings.log.audit;
+            let tx = if cfg.enable { Self::start_writer(cfg) } else { None };
+            Self { tx }
+        })
+    }
+
+    #[inline]
+    pub fn enable(&self) -> bool {
+        Runtime::instance().settings.log.audit.enable
+    }
+
+    ///Writes the record to the sink and passes it to the audit_record hook
+    pub async fn record(&self, event: AuditEvent) {
+        if !self.enable() {
+            return;
+        }
+        let record = AuditRecord {
+            time: chrono::Local::now().format("%Y-%m-%d %H:%M:%S%.3f").to_string(),
+            node_id: Runtime::instance().node.id(),
+            event,
+        };
+        if let Some(tx) = self.tx.as_ref() {
+            match serde_json::to_string(&record) {
+                Ok(line) => {
+                    if let Err(e) = tx.send(line) {
+                        log::warn!("audit log is closed, {:?}", e);
+                    }
+                }
+                Err(e) => log::warn!("audit record encode error, {:?}", e),
+            }
+        }
+        //hook, audit_record
+        Runtime::instance().extends.hook_mgr().await.audit_record(&record).await;
+    }
+
+    fn start_writer(cfg: &Audit) -> Option<mpsc::Sender<String>> {
+        let mut sink: Box<dyn Sink> = match cfg.to {
+            AuditTo::Off => return None,
+            AuditTo::File => Box::new(FileSink::new(cfg.filename(), cfg.max_size.as_u64(), cfg.max_files)),
+            #[cfg(unix)]
+            AuditTo::Syslog => Box::new(SyslogSink::new(cfg.syslog_addr.clone())),
+            #[cfg(not(unix))]
+            AuditTo::Syslog => {
+                log::warn!("audit log, syslog is not supported on this platform");
+                return None;
+            }
+        };
+        let (tx, rx) = mpsc::channel::<String>();
+        let res = std::thread::Builder::new().name("audit-log".into()).spawn(move || {
+            while let Ok(line) = rx.recv() {
+                if let Err(e) = sink.write(&line) {
+                    log::warn!("audit log write error, {:?}", e);
+                }
+            }
+        });
+        if let Err(e) = res {
+            log::error!("audit log, start writer error, {:?}", e);
+            return None;
+        }
+        Some(tx)
+    }
+}
+
+trait Sink: Send {
+    fn write(&mut self, line: &str) -> io::Result<()>;
+}
+
+struct FileSink {
+    filename: String,
+    max_size: u64,
+    max_files: usize,
+    file: Option<(File, u64)>,
+}
+
+impl FileSink {
+    fn new(filename: String, max_size: u64, max_files: usize) -> Self {
+        Self { filename, max_size, max_files, file: None }
+    }
+
+    fn open(&self) -> io::Result<(File, u64)> {
+        if let Some(dir) = std::path::Path::new(&self.filename).parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&self.filename)?;
+        let size = file.metadata()?.len();
+        Ok((file, size))
+    }
+
+    ///audit.log is renamed to audit.log.1, audit.log.1 to audit.log.2 ..., the oldest is overwritten
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file = None;
+        if self.max_files == 0 {
+            return std::fs::remove_file(&self.filename);
+        }
+        for i in (1..self.max_files).rev() {
+            let from = format!("{}.{}", self.filename, i);
+            if std::path::Path::new(&from).exists() {
+                std::fs::rename(&from, format!("{}.{}", self.filename, i + 1))?;
+            }
+        }
+        std::fs::rename(&self.filename, format!("{}.1", self.filename))
+    }
+}
+
+impl Sink for FileSink {
+    fn write(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if matches!(self.file, Some((_, size)) if size > 0 && size + len > self.max_size) {
+            self.rotate()?;
+        }
+        if self.file.is_none() {
+            self.file = Some(self.open()?);
+        }
+        if let Some((file, size)) = self.file.as_mut() {
+            file.write_all(line.as_bytes())?;
+            file.write_all(b"\n")?;
+            *size += len;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+struct SyslogSink {
+    addr: String,
+    socket: Option<std::os::unix::net::UnixDatagram>,
+}
+
+#[cfg(unix)]
+impl SyslogSink {
+    //facility auth(4), severity info(6)
+    const PRIORITY: u8 = 4 * 8 + 6;
+
+    fn new(addr: String) -> Self {
+        Self { addr, socket: None }
+    }
+}
+
+#[cfg(unix)]
+impl Sink for SyslogSink {
+    fn write(&mut self, line: &str) -> io::Result<()> {
+        if self.socket.is_none() {
+            let socket = std::os::unix::net::UnixDatagram::unbound()?;
+            socket.connect(&self.addr)?;
+            self.socket = Some(socket);
+        }
+        let msg = format!("<{}>rmqtt[{}]: {}", Self::PRIORITY, std::process::id(), line);
+        if let Some(socket) = self.socket.as_ref() {
+            if let Err(e) = socket.send(msg.as_bytes()) {
+                //reconnect on the next record, the syslog daemon may have been restarted
+                self.socket = None;
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,128 @@
+//! Client id policies, invoked at connect. A policy validates the client id of a connecting client
+//! and assigns the client id of a client connecting without one, returned to MQTT 5 clients as the
+//! Assigned Client Identifier. The default policy applies the rules of the listener, `clientid_*`,
+//! the auth plugins may register the policy of a tenant, see `Quota::tenant`.
+
+use std::sync::Arc;
+
+use dashmap::DashMap;
+use once_cell::sync::OnceCell;
+use rust_box::std_ext::RwLock;
+use uuid::Uuid;
+
+use crate::broker::quota::Quota;
+use crate::settings::listener::{ClientIdCharset, Listener};
+use crate::{ClientId, MqttError, Result};
+
+pub trait ClientIdPolicy: Sync + Send {
+    ///Validates the client id of a connecting client, the error is the reason of the refusal
+    fn validate(&self, listen_cfg: &Listener, client_id: &str) -> Result<()>;
+
+    ///The client id of a client connecting without one, none if it is refused
+    fn assign(&self, listen_cfg: &Listener, username: Option<&str>) -> Option<ClientId>;
+}
+
+///The rules of the listener
+pub struct DefaultClientIdPolicy;
+
+impl ClientIdPolicy for DefaultClientIdPolicy {
+    fn validate(&self, listen_cfg: &Listener, client_id: &str) -> Result<()> {
+        if client_id.is_empty() {
+            return Err(MqttError::from("client_id is empty"));
+        }
+        if client_id.len() < listen_cfg.clientid_min_len {
+            return Err(MqttError::from("client_id is too short"));
+        }
+        if listen_cfg.max_clientid_len > 0 && client_id.len() > listen_cfg.max_clientid_len {
+            return Err(MqttError::from("client_id is too long"));
+        }
+        if !listen_cfg.clientid_charset.is_valid(client_id) {
+            return Err(MqttError::from(format!("client_id has invalid characters, {}", client_id)));
+        }
+        if let Some(pattern) = listen_cfg.clientid_pattern.as_ref() {
+            if !pattern.is_match(client_id) {
+                return Err(MqttError::from(format!("client_id does not match the pattern, {}", client_id)));
+            }
+        }
+        Ok(())
+    }
+
+    fn assign(&self, listen_cfg: &Listener, _username: Option<&str>) -> Option<ClientId> {
+        if !listen_cfg.clientid_assign {
+            return None;
+        }
+        let uuid = Uuid::new_v4();
+        let uuid = uuid.as_simple().encode_lower(&mut Uuid::encode_buffer()).to_owned();
+        Some(ClientId::from(format!("{}{}", listen_cfg.clientid_prefix, uuid)))
+    }
+}
+
+pub struct ClientIdPolicies {
+    default: RwLock<Arc<dyn ClientIdPolicy>>,
+    //tenant => policy
+    tenants: DashMap<String, Arc<dyn ClientIdPolicy>>,
+}
+
+impl ClientIdPolicies {
+    #[inline]
+    pub fn instance() -> &'static Self {
+        static INSTANCE: OnceCell<ClientIdPolicies> = OnceCell::new();
+        INSTANCE.get_or_init(|| Self {
+            default: RwLock::new(Arc::new(DefaultClientIdPolicy)),
+            tenants: DashMap::default(),
+        })
+    }
+
+    ///Replaces the policy of the clients without a tenant policy
+    #[inline]
+    pub fn set_default(&self, policy: Arc<dyn ClientIdPolicy>) {
+        *self.default.write() = policy;
+    }
+
+    ///Registers the policy of the tenant, it replaces the policy registered before
+    #[inline]
+    pub fn register<T: Into<String>>(&self, tenant: T, policy: Arc<dyn ClientIdPolicy>) {
+        self.tenants.insert(tenant.into(), policy);
+    }
+
+    #[inline]
+    pub fn unregister(&self, tenant: &str) {
+        self.tenants.remove(tenant);
+    }
+
+    ///The policy of the tenant of the username, or the default policy
+    #[inline]
+    pub fn get(&self, username: Option<&str>) -> Arc<dyn ClientIdPolicy> {
+        if !self.tenants.is_empty() {
+            if let Some(policy) = username.and_then(Quota::tenant).and_then(|t| self.tenants.get(t)) {
+                return policy.value().clone();
+            }
+        }
+        self.default.read().clone()
+    }
+}
+
+impl ClientIdCharset {
+    #[inline]
+    pub fn is_valid(&self, client_id: &str) -> bool {
+        match self {
+            ClientIdCharset::Any => true,
+            ClientIdCharset::Alphanumeric => client_id.bytes().all(|b| b.is_ascii_alphanumeric()),
+            ClientIdCharset::Printable => client_id.bytes().all(|b| b.is_ascii_graphic()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::settings::listener::ClientIdCharset;
+
+    #[test]
+    fn test_charset() {
+        assert!(ClientIdCharset::Any.is_valid("dev 1/é"));
+        assert!(ClientIdCharset::Alphanumeric.is_valid("dev01"));
+        assert!(!ClientIdCharset::Alphanumeric.is_valid("dev-01"));
+        assert!(ClientIdCharset::Printable.is_valid("dev-01/a"));
+        assert!(!ClientIdCharset::Printable.is_valid("dev 01"));
+    }
+}
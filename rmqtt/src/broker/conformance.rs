@@ -0,0 +1,143 @@
+//! Strict MQTT protocol conformance. On the listeners with `strict_conformance`, the packets are
+//! checked for the spec violations the codec lets through, a violation is logged, counted only, or
+//! the client is disconnected with the reason code of the violation, see `conformance.actions`. The
+//! violations are counted per listener, for fleet debugging.
+
+use std::collections::BTreeMap;
+
+use bytestring::ByteString;
+use once_cell::sync::OnceCell;
+
+use crate::broker::types::{DisconnectReasonCode, Id, Publish, QoS, Reason};
+use crate::settings::listener::Listener;
+use crate::settings::{Violation, ViolationAction};
+use crate::{MqttError, Result, Runtime, SessionState};
+
+const MAX_CLIENT_ID_LEN: usize = 23;
+
+pub struct Conformance {
+    //violation counts, by listener name and port
+    counts: dashmap::DashMap<(String, u16), BTreeMap<&'static str, usize>>,
+}
+
+impl Conformance {
+    #[inline]
+    pub fn instance() -> &'static Self {
+        static INSTANCE: OnceCell<Conformance> = OnceCell::new();
+        INSTANCE.get_or_init(|| Self { counts: dashmap::DashMap::default() })
+    }
+
+    ///Counts the violation and logs it, returns the action of the violation
+    pub fn record(&self, listen_cfg: &Listener, id: &Id, v: Violation, detail: &str) -> ViolationAction {
+        *self
+            .counts
+            .entry((listen_cfg.name.clone(), listen_cfg.addr.port()))
+            .or_default()
+            .entry(v.as_str())
+            .or_default() += 1;
+        let action = Runtime::instance().settings.conformance.action(v);
+        if action != ViolationAction::Count {
+            log::warn!("{:?} MQTT spec violation, {}, {}, action: {:?}", id, v.as_str(), detail, action);
+        }
+        action
+    }
+
+    ///Records the violation of a connected client, which is disconnected if configured so
+    pub async fn violated(&self, state: &SessionState, v: Violation, detail: &str) -> Result<()> {
+        if self.record(state.listen_cfg(), &state.id, v, detail) != ViolationAction::Disconnect {
+            return Ok(());
+        }
+        if let Some(sink) = state.sink.as_ref() {
+            sink.close_with_reason(disconnect_code(v), None);
+        }
+        let reason = Reason::ProtocolError(ByteString::from(format!("{}, {}", v.as_str(), detail)));
+        state.disconnected_reason_add(reason.clone()).await?;
+        Err(MqttError::Reason(reason))
+    }
+
+    ///Checks a publish of the client
+    pub async fn check_publish(&self, state: &SessionState, publish: &Publish) -> Result<()> {
+        if !is_valid_topic(&publish.topic) {
+            self.violated(state, Violation::InvalidUtf8Topic, &format!("topic: {:?}", publish.topic)).await?;
+        }
+        if publish.qos == QoS::AtMostOnce && publish.dup {
+            self.violated(state, Violation::Qos0Dup, &format!("topic: {:?}", publish.topic)).await?;
+        }
+        Ok(())
+    }
+
+    ///Checks a subscription of the client, no_local is always false for MQTT 3.1.1
+    pub async fn check_subscribe(
+        &self,
+        state: &SessionState,
+        topic_filter: &str,
+        no_local: bool,
+    ) -> Result<()> {
+        if !is_valid_topic(topic_filter) {
+            self.violated(state, Violation::InvalidUtf8Topic, &format!("topic filter: {:?}", topic_filter))
+                .await?;
+        }
+        if no_local && topic_filter.starts_with("$share/") {
+            self.violated(state, Violation::SharedNoLocal, &format!("topic filter: {:?}", topic_filter))
+                .await?;
+        }
+        Ok(())
+    }
+
+    ///Violation counts of this node, by listener
+    pub fn to_json(&self) -> serde_json::Value {
+        let listeners = self
+            .counts
+            .iter()
+            .map(|entry| {
+                let (name, port) = entry.key();
+                json!({"name": name, "port": port, "violations": entry.value()})
+            })
+            .collect::<Vec<_>>();
+        json!({ "node_id": Runtime::instance().node.id(), "listeners": listeners })
+    }
+}
+
+#[inline]
+fn disconnect_code(v: Violation) -> DisconnectReasonCode {
+    match v {
+        Violation::SharedNoLocal => DisconnectReasonCode::ProtocolError,
+        Violation::OversizedClientId => DisconnectReasonCode::ProtocolError,
+        Violation::InvalidUtf8Topic | Violation::Qos0Dup | Violation::MalformedPacket => {
+            DisconnectReasonCode::MalformedPacket
+        }
+    }
+}
+
+///The client ids of 1 to 23 bytes must be accepted, longer ones may be, an empty one is assigned
+#[inline]
+pub fn is_valid_client_id(client_id: &str) -> bool {
+    client_id.len() <= MAX_CLIENT_ID_LEN
+}
+
+///U+0000 must not be included, the control characters and the non-characters should not
+#[inline]
+pub fn is_valid_topic(topic: &str) -> bool {
+    !topic
+        .chars()
+        .any(|c| c.is_control() || ('\u{FDD0}'..='\u{FDEF}').contains(&c) || (c as u32 & 0xFFFE) == 0xFFFE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_valid_client_id, is_valid_topic};
+
+    #[test]
+    fn test_checks() {
+        assert!(is_valid_topic("sensors/+/temp"));
+        assert!(is_valid_topic("传感器/温度"));
+        assert!(!is_valid_topic("a/\u{0}/b"));
+        assert!(!is_valid_topic("a/\u{1b}"));
+        assert!(!is_valid_topic("a/\u{85}"));
+        assert!(!is_valid_topic("a/\u{FFFF}"));
+        assert!(!is_valid_topic("a/\u{1FFFE}"));
+        assert!(is_valid_client_id(""));
+        assert!(is_valid_client_id("client-00000000000000001"));
+        assert!(!is_valid_client_id("client-000000000000000001"));
+    }
+}
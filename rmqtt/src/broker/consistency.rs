@@ -0,0 +1,288 @@
+//! Consistency audit of the routes, the sessions and the retained messages, triggered by the admin.
+//! On each node the routes of its clients are compared with its sessions: a route of a session that
+//! no longer exists is an orphan, a subscription of a session without a route is missing. The offline
+//! sessions of a node whose client is connected on another node are stale, and the retained message
+//! counter is compared with the stored messages. With repair, the orphan routes are removed, the
+//! missing routes are added, the stale sessions are kicked and the retained counter is reset.
+//!
+//! The audit runs on a live broker, a subscription or a disconnection in progress may be reported.
+
+use std::collections::HashSet;
+
+use once_cell::sync::OnceCell;
+
+use crate::broker::default::DefaultShared;
+use crate::broker::types::{timestamp_millis, ClientId, HashMap, Id, TimestampMillis, TopicFilter};
+use crate::broker::Shared;
+use crate::grpc::{Message, MessageBroadcaster, MessageReply, MessageSender, MESSAGE_TYPE_CONSISTENCY};
+use crate::{MqttError, NodeId, Result, Runtime};
+
+///The inconsistencies found on a node
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ConsistencyReport {
+    pub checked_at: TimestampMillis,
+    ///The routes of the clients of the node
+    pub routes: usize,
+    pub sessions: usize,
+    ///Routes of the sessions no longer on the node
+    pub orphan_routes: Vec<(TopicFilter, ClientId)>,
+    ///Subscriptions of the sessions of the node without a route
+    pub missing_routes: Vec<(TopicFilter, ClientId)>,
+    ///Offline sessions of the node whose client is connected on another node
+    pub stale_sessions: Vec<ClientId>,
+    ///The counted and the stored retained messages, if they differ
+    pub retain_count: Option<(isize, usize)>,
+    pub repaired: usize,
+    //The offline sessions of the node, for the stale sessions check
+    offline_sessions: Vec<ClientId>,
+}
+
+impl ConsistencyReport {
+    #[inline]
+    pub fn is_consistent(&self) -> bool {
+        self.orphan_routes.is_empty()
+            && self.missing_routes.is_empty()
+            && self.stale_sessions.is_empty()
+            && self.retain_count.is_none()
+    }
+
+    #[inline]
+    pub fn to_json(&self) -> serde_json::Value {
+        let routes = |routes: &[(TopicFilter, ClientId)]| {
+            routes
+                .iter()
+                .map(|(topic_filter, client_id)| json!({"topic_filter": topic_filter, "clientid": client_id}))
+                .collect::<Vec<_>>()
+        };
+        json!({
+            "consistent": self.is_consistent(),
+            "checked_at": self.checked_at,
+            "routes": self.routes,
+            "sessions": self.sessions,
+            "orphan_routes": routes(&self.orphan_routes),
+            "missing_routes": routes(&self.missing_routes),
+            "stale_sessions": self.stale_sessions,
+            "retain_count": self.retain_count.map(|(counted, stored)| json!({
+                "counted": counted,
+                "stored": stored,
+            })),
+            "repaired": self.repaired,
+        })
+    }
+}
+
+pub struct Consistency {}
+
+impl Consistency {
+    #[inline]
+    pub fn instance() -> &'static Self {
+        static INSTANCE: OnceCell<Consistency> = OnceCell::new();
+        INSTANCE.get_or_init(|| Self {})
+    }
+
+    ///Audits the nodes of the cluster, see `check_local`, then the offline sessions of each node are
+    ///looked up on the other nodes. Returns the report of each node, or its error.
+    pub async fn check(&self, repair: bool) -> Vec<(NodeId, Result<ConsistencyReport>)> {
+        let node_id = Runtime::instance().node.id();
+        let mut reports = vec![(node_id, self.check_local(repair).await)];
+        let grpc_clients = Runtime::instance().extends.shared().await.get_grpc_clients();
+        if grpc_clients.is_empty() {
+            return reports;
+        }
+
+        let msg = Message::ConsistencyCheck(repair);
+        let replys =
+            MessageBroadcaster::new(grpc_clients.clone(), MESSAGE_TYPE_CONSISTENCY, msg).join_all().await;
+        for (id, reply) in replys {
+            let report = match reply {
+                Ok(MessageReply::ConsistencyCheck(report)) => Ok(report),
+                Ok(MessageReply::Error(e)) => Err(MqttError::from(e)),
+                Ok(r) => Err(MqttError::from(format!("unexpected reply, {:?}", r))),
+                Err(e) => Err(e),
+            };
+            reports.push((id, report));
+        }
+
+        //The nodes where the offline clients of the other nodes are connected
+        let offlines = reports
+            .iter()
+            .filter_map(|(_, r)| r.as_ref().ok())
+            .flat_map(|r| r.offline_sessions.iter().cloned())
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        if offlines.is_empty() {
+            return reports;
+        }
+        let mut connecteds: HashMap<ClientId, Vec<NodeId>> = HashMap::default();
+        for client_id in self.connected_local(&offlines).await {
+            connecteds.entry(client_id).or_default().push(node_id);
+        }
+        let msg = Message::ConsistencyConnected(offlines);
+        for (id, reply) in
+            MessageBroadcaster::new(grpc_clients.clone(), MESSAGE_TYPE_CONSISTENCY, msg).join_all().await
+        {
+            match reply {
+                Ok(MessageReply::ConsistencyConnected(client_ids)) => {
+                    for client_id in client_ids {
+                        connecteds.entry(client_id).or_default().push(id);
+                    }
+                }
+                Ok(r) => log::warn!("consistency check, unexpected reply of node {}, {:?}", id, r),
+                Err(e) => log::warn!("consistency check, node {} error, {:?}", id, e),
+            }
+        }
+
+        for (id, report) in reports.iter_mut() {
+            let report = if let Ok(report) = report { report } else { continue };
+            report.stale_sessions = report
+                .offline_sessions
+                .iter()
+                .filter(|client_id| {
+                    connecteds.get(*client_id).map(|ids| ids.iter().any(|n| n != id)).unwrap_or(false)
+                })
+                .cloned()
+                .collect();
+            if !repair || report.stale_sessions.is_empty() {
+                continue;
+            }
+            let client_ids = report.stale_sessions.clone();
+            let removeds = if *id == node_id {
+                self.remove_stale_local(&client_ids).await
+            } else if let Some((_, grpc_client)) = grpc_clients.get(id) {
+                let msg = Message::ConsistencyRemoveStale(client_ids);
+                match MessageSender::new(grpc_client.clone(), MESSAGE_TYPE_CONSISTENCY, msg).send().await {
+                    Ok(MessageReply::ConsistencyRemoveStale(removeds)) => removeds,
+                    Ok(r) => {
+                        log::warn!("consistency check, unexpected reply of node {}, {:?}", id, r);
+                        0
+                    }
+                    Err(e) => {
+                        log::warn!("consistency check, node {} error, {:?}", id, e);
+                        0
+                    }
+                }
+            } else {
+                0
+            };
+            report.repaired += removeds;
+        }
+        reports
+    }
+
+    ///Audits this node, the routes of its clients against its sessions and the retained message
+    ///counter. The stale sessions are not checked here, the offline sessions are reported instead.
+    #[allow(clippy::mutable_key_type)]
+    pub async fn check_local(&self, repair: bool) -> Result<ConsistencyReport> {
+        let node_id = Runtime::instance().node.id();
+        let router = Runtime::instance().extends.router().await;
+        let mut report = ConsistencyReport { checked_at: timestamp_millis(), ..Default::default() };
+
+        //Routes without a session, or of a previous session of the client
+        let mut routed = HashSet::default();
+        let relations = router.relations(node_id).await;
+        report.routes = relations.len();
+        for (topic_filter, id) in relations {
+            let same =
+                DefaultShared::instance().entry(id.clone()).session().map(|s| s.id == id).unwrap_or(false);
+            if same {
+                routed.insert((topic_filter, id.client_id));
+                continue;
+            }
+            report.orphan_routes.push((topic_filter.clone(), id.client_id.clone()));
+            if repair && router.remove(&topic_filter, id).await? {
+                report.repaired += 1;
+            }
+        }
+
+        //Subscriptions without a route
+        for entry in DefaultShared::instance().iter() {
+            let s = if let Some(s) = entry.session() { s } else { continue };
+            report.sessions += 1;
+            if !s.connected().await.unwrap_or_default() {
+                report.offline_sessions.push(s.id.client_id.clone());
+            }
+            let subs = s.subscriptions().await?.read().await.clone();
+            for (topic_filter, opts) in subs {
+                if routed.contains(&(topic_filter.clone(), s.id.client_id.clone())) {
+                    continue;
+                }
+                report.missing_routes.push((topic_filter.clone(), s.id.client_id.clone()));
+                if repair {
+                    router.add(&topic_filter, s.id.clone(), opts).await?;
+                    report.repaired += 1;
+                }
+            }
+        }
+
+        //Retained message counter
+        if let Some((counted, stored)) =
+            Runtime::instance().extends.retain().await.check_count(repair).await?
+        {
+            if counted != stored as isize {
+                report.retain_count = Some((counted, stored));
+                if repair {
+                    report.repaired += 1;
+                }
+            }
+        }
+
+        if !report.is_consistent() {
+            log::warn!("consistency check, {:?}", report);
+        }
+        Ok(report)
+    }
+
+    ///The clients connected on this node
+    pub async fn connected_local(&self, client_ids: &[ClientId]) -> Vec<ClientId> {
+        let node_id = Runtime::instance().node.id();
+        let mut connecteds = Vec::new();
+        for client_id in client_ids {
+            if DefaultShared::instance().entry(Id::from(node_id, client_id.clone())).is_connected().await {
+                connecteds.push(client_id.clone());
+            }
+        }
+        connecteds
+    }
+
+    ///Kicks the offline sessions of the clients from this node, their routes are removed, the routes
+    ///of the sessions connected on the other nodes are kept. Returns the number of sessions kicked.
+    pub async fn remove_stale_local(&self, client_ids: &[ClientId]) -> usize {
+        let node_id = Runtime::instance().node.id();
+        let mut removeds = 0;
+        for client_id in client_ids {
+            let mut entry = DefaultShared::instance().entry(Id::from(node_id, client_id.clone()));
+            let s = if let Some(s) = entry.session() { s } else { continue };
+            if s.connected().await.unwrap_or_default() {
+                continue;
+            }
+            match entry.kick(true, true, true).await {
+                Ok(_) => {
+                    log::info!("{:?} stale session kicked", s.id);
+                    removeds += 1;
+                }
+                Err(e) => log::warn!("{:?} failed to kick the stale session, {:?}", s.id, e),
+            }
+        }
+        removeds
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ConsistencyReport;
+    use crate::broker::types::{ClientId, TopicFilter};
+
+    #[test]
+    fn test_is_consistent() {
+        let mut report = ConsistencyReport::default();
+        assert!(report.is_consistent());
+        report.orphan_routes.push((TopicFilter::from("foo/#"), ClientId::from("c1")));
+        assert!(!report.is_consistent());
+        assert_eq!(report.to_json()["orphan_routes"][0]["clientid"], "c1");
+
+        let report = ConsistencyReport { retain_count: Some((3, 2)), ..Default::default() };
+        assert!(!report.is_consistent());
+        assert_eq!(report.to_json()["retain_count"]["stored"], 2);
+    }
+}
@@ -0,0 +1,305 @@
+//! The datagram transport of the gateways, plain UDP or DTLS.
+//!
+//! DTLS is built with the `dtls` feature, the server is authenticated by a certificate and the
+//! clients by a pre-shared key, or both. A DTLS client is addressed by its remote address like a
+//! UDP client, each connection is read by its own task.
+
+use std::net::SocketAddr;
+
+use serde::{Deserialize, Serialize};
+use tokio::net::UdpSocket;
+
+use crate::{MqttError, Result};
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct DtlsConfig {
+    #[serde(default)]
+    pub enable: bool,
+    //The certificate chain and the private key of the server, PEM
+    #[serde(default)]
+    pub cert: Option<String>,
+    #[serde(default)]
+    pub key: Option<String>,
+    //The pre-shared keys of the clients, the key is hex encoded
+    #[serde(default)]
+    pub psk: Vec<PskEntry>,
+    #[serde(default)]
+    pub psk_identity_hint: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct PskEntry {
+    pub identity: String,
+    pub key: String,
+}
+
+impl DtlsConfig {
+    ///The pre-shared keys by identity, the configuration is invalid if a key is not hex
+    pub fn psk_keys(&self) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.psk
+            .iter()
+            .map(|entry| {
+                let key = decode_hex(&entry.key).ok_or_else(|| {
+                    MqttError::from(format!("DTLS pre-shared key of {} is not hex", entry.identity))
+                })?;
+                Ok((entry.identity.as_bytes().to_vec(), key))
+            })
+            .collect()
+    }
+
+    #[inline]
+    pub fn validate(&self) -> Result<()> {
+        if !self.enable {
+            return Ok(());
+        }
+        if self.cert.is_some() != self.key.is_some() {
+            return Err(MqttError::from("DTLS cert and key must be configured together"));
+        }
+        if self.cert.is_none() && self.psk.is_empty() {
+            return Err(MqttError::from("DTLS requires a cert and key, or pre-shared keys"));
+        }
+        self.psk_keys().map(|_| ())
+    }
+}
+
+#[inline]
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    let s = s.trim();
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    (0..s.len()).step_by(2).map(|i| s.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok())).collect()
+}
+
+pub enum Datagram {
+    Udp(UdpSocket),
+    #[cfg(feature = "dtls")]
+    Dtls(dtls::DtlsSocket),
+}
+
+impl Datagram {
+    pub async fn bind(addr: SocketAddr, cfg: &DtlsConfig) -> Result<Self> {
+        if !cfg.enable {
+            return Ok(Datagram::Udp(UdpSocket::bind(addr).await?));
+        }
+        cfg.validate()?;
+        #[cfg(feature = "dtls")]
+        {
+            Ok(Datagram::Dtls(dtls::DtlsSocket::bind(addr, cfg).await?))
+        }
+        #[cfg(not(feature = "dtls"))]
+        {
+            Err(MqttError::from("DTLS is not supported, rmqtt is built without the dtls feature"))
+        }
+    }
+
+    #[inline]
+    pub fn is_secure(&self) -> bool {
+        !matches!(self, Datagram::Udp(_))
+    }
+
+    #[inline]
+    pub async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        match self {
+            Datagram::Udp(socket) => Ok(socket.recv_from(buf).await?),
+            #[cfg(feature = "dtls")]
+            Datagram::Dtls(socket) => socket.recv_from(buf).await,
+        }
+    }
+
+    #[inline]
+    pub async fn send_to(&self, buf: &[u8], addr: SocketAddr) -> Result<usize> {
+        match self {
+            Datagram::Udp(socket) => Ok(socket.send_to(buf, addr).await?),
+            #[cfg(feature = "dtls")]
+            Datagram::Dtls(socket) => socket.send_to(buf, addr).await,
+        }
+    }
+
+    ///The DTLS connection of the client is closed, its session is ended
+    #[inline]
+    pub async fn close(&self, _addr: SocketAddr) {
+        #[cfg(feature = "dtls")]
+        if let Datagram::Dtls(socket) = self {
+            socket.close(_addr).await;
+        }
+    }
+}
+
+#[cfg(feature = "dtls")]
+mod dtls {
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+
+    use tokio::sync::{mpsc, Mutex};
+    use tokio::task::JoinHandle;
+    use webrtc_dtls::config::{Config, ExtendedMasterSecretType};
+    use webrtc_dtls::crypto::{Certificate, CryptoPrivateKey};
+    use webrtc_util::conn::{Conn, Listener};
+
+    use super::DtlsConfig;
+    use crate::broker::types::DashMap;
+    use crate::{MqttError, Result};
+
+    type DtlsConn = Arc<dyn Conn + Send + Sync>;
+
+    pub struct DtlsSocket {
+        listener: Arc<dyn Listener + Send + Sync>,
+        accept_task: JoinHandle<()>,
+        conns: Arc<DashMap<SocketAddr, DtlsConn>>,
+        rx: Mutex<mpsc::UnboundedReceiver<(SocketAddr, Vec<u8>)>>,
+    }
+
+    //The listening port is released with the socket
+    impl Drop for DtlsSocket {
+        fn drop(&mut self) {
+            self.accept_task.abort();
+            let listener = self.listener.clone();
+            let conns = self.conns.iter().map(|c| c.value().clone()).collect::<Vec<_>>();
+            self.conns.clear();
+            tokio::spawn(async move {
+                for conn in conns {
+                    let _ = conn.close().await;
+                }
+                if let Err(e) = listener.close().await {
+                    log::debug!("DTLS listener close error, {}", e);
+                }
+            });
+        }
+    }
+
+    impl DtlsSocket {
+        pub(super) async fn bind(addr: SocketAddr, cfg: &DtlsConfig) -> Result<Self> {
+            let listener: Arc<dyn Listener + Send + Sync> = Arc::new(
+                webrtc_dtls::listener::listen(addr, config(cfg)?)
+                    .await
+                    .map_err(|e| MqttError::from(format!("DTLS listen error, {}", e)))?,
+            );
+            let conns: Arc<DashMap<SocketAddr, DtlsConn>> = Arc::new(DashMap::default());
+            let (tx, rx) = mpsc::unbounded_channel();
+            let accept_conns = conns.clone();
+            let accept_listener = listener.clone();
+            let accept_task = tokio::spawn(async move {
+                loop {
+                    let (conn, remote_addr) = match accept_listener.accept().await {
+                        Ok(c) => c,
+                        Err(e) => {
+                            //a failed handshake does not stop the listener
+                            log::debug!("DTLS accept error, {}", e);
+                            continue;
+                        }
+                    };
+                    accept_conns.insert(remote_addr, conn.clone());
+                    let conns = accept_conns.clone();
+                    let tx = tx.clone();
+                    tokio::spawn(async move {
+                        let mut buf = vec![0u8; 2048];
+                        loop {
+                            match conn.recv(&mut buf).await {
+                                Ok(n) => {
+                                    if tx.send((remote_addr, buf[..n].to_vec())).is_err() {
+                                        break;
+                                    }
+                                }
+                                Err(e) => {
+                                    log::debug!("{} DTLS connection closed, {}", remote_addr, e);
+                                    break;
+                                }
+                            }
+                        }
+                        conns.remove_if(&remote_addr, |_, c| Arc::ptr_eq(c, &conn));
+                    });
+                }
+            });
+            Ok(Self { listener, accept_task, conns, rx: Mutex::new(rx) })
+        }
+
+        pub(super) async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+            let (addr, data) = self
+                .rx
+                .lock()
+                .await
+                .recv()
+                .await
+                .ok_or_else(|| MqttError::from("DTLS listener is closed"))?;
+            let n = data.len().min(buf.len());
+            buf[..n].copy_from_slice(&data[..n]);
+            Ok((n, addr))
+        }
+
+        pub(super) async fn send_to(&self, buf: &[u8], addr: SocketAddr) -> Result<usize> {
+            let conn = self
+                .conns
+                .get(&addr)
+                .map(|c| c.value().clone())
+                .ok_or_else(|| MqttError::from(format!("{} DTLS connection is closed", addr)))?;
+            conn.send(buf).await.map_err(|e| MqttError::from(format!("DTLS send error, {}", e)))
+        }
+
+        pub(super) async fn close(&self, addr: SocketAddr) {
+            if let Some((_, conn)) = self.conns.remove(&addr) {
+                if let Err(e) = conn.close().await {
+                    log::debug!("{} DTLS close error, {}", addr, e);
+                }
+            }
+        }
+    }
+
+    fn config(cfg: &DtlsConfig) -> Result<Config> {
+        let mut config =
+            Config { extended_master_secret: ExtendedMasterSecretType::Require, ..Default::default() };
+        if let (Some(cert), Some(key)) = (cfg.cert.as_ref(), cfg.key.as_ref()) {
+            config.certificates = vec![certificate(cert, key)?];
+        }
+        let keys = cfg.psk_keys()?;
+        if !keys.is_empty() {
+            config.psk = Some(Arc::new(move |identity: &[u8]| {
+                keys.iter()
+                    .find(|(id, _)| id.as_slice() == identity)
+                    .map(|(_, key)| key.clone())
+                    .ok_or_else(|| webrtc_dtls::Error::Other(format!("unknown PSK identity {:?}", identity)))
+            }));
+            config.psk_identity_hint = cfg.psk_identity_hint.as_ref().map(|hint| hint.as_bytes().to_vec());
+        }
+        Ok(config)
+    }
+
+    fn certificate(cert: &str, key: &str) -> Result<Certificate> {
+        let key_pem = std::fs::read_to_string(key)?;
+        let key_pair = rcgen::KeyPair::from_pem(&key_pem)
+            .map_err(|e| MqttError::from(format!("DTLS key {} is invalid, {}", key, e)))?;
+        let private_key = CryptoPrivateKey::from_key_pair(&key_pair)
+            .map_err(|e| MqttError::from(format!("DTLS key {} is invalid, {}", key, e)))?;
+        let mut reader = std::io::BufReader::new(std::fs::File::open(cert)?);
+        let certificate = rustls_pemfile::certs(&mut reader)?.into_iter().map(rustls::Certificate).collect();
+        Ok(Certificate { certificate, private_key })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_hex() {
+        assert_eq!(decode_hex("00ff1A"), Some(vec![0x00, 0xff, 0x1a]));
+        assert_eq!(decode_hex(""), Some(vec![]));
+        assert_eq!(decode_hex("abc"), None);
+        assert_eq!(decode_hex("zz"), None);
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(DtlsConfig::default().validate().is_ok());
+        let mut cfg = DtlsConfig { enable: true, ..Default::default() };
+        assert!(cfg.validate().is_err());
+        cfg.cert = Some("cert.pem".into());
+        assert!(cfg.validate().is_err());
+        cfg.key = Some("key.pem".into());
+        assert!(cfg.validate().is_ok());
+        cfg.psk.push(PskEntry { identity: "client1".into(), key: "0102".into() });
+        assert_eq!(cfg.psk_keys().unwrap(), vec![(b"client1".to_vec(), vec![1, 2])]);
+        cfg.psk.push(PskEntry { identity: "client2".into(), key: "xyz".into() });
+        assert!(cfg.validate().is_err());
+    }
+}
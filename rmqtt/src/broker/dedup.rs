@@ -0,0 +1,81 @@
+//! Per-session message deduplication, the recent publishes of a client are remembered by the
+//! client message id, a user property, or by the hash of the topic, QoS, retain flag and payload.
+//! A publish seen again within `dedup_ttl` is dropped.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
+use std::hash::{Hash, Hasher};
+
+use rust_box::std_ext::RwLock;
+
+use crate::broker::types::{timestamp_millis, AuthInfo, HashMap, Publish, TimestampMillis};
+use crate::settings::listener::Listener;
+
+pub struct Dedup {
+    max: usize,
+    ttl: TimestampMillis,
+    key_property: Option<String>,
+    //key => last seen at, and the keys in the order seen, least recently seen first. An entry
+    //of the order whose time differs from that of the map is stale, the key was seen again.
+    entries: RwLock<(HashMap<u64, TimestampMillis>, VecDeque<(u64, TimestampMillis)>)>,
+}
+
+impl Dedup {
+    ///None if deduplication is disabled by the listener, or by the auth plugin for this client
+    #[inline]
+    pub fn new(listen_cfg: &Listener, auth_info: Option<&AuthInfo>) -> Option<Self> {
+        let enable = auth_info.and_then(|a| a.dedup).unwrap_or(listen_cfg.dedup);
+        if !enable || listen_cfg.dedup_max == 0 {
+            return None;
+        }
+        Some(Self {
+            max: listen_cfg.dedup_max,
+            ttl: listen_cfg.dedup_ttl.as_millis() as TimestampMillis,
+            key_property: Some(listen_cfg.dedup_key_property.clone()).filter(|p| !p.is_empty()),
+            entries: RwLock::new((HashMap::default(), VecDeque::new())),
+        })
+    }
+
+    #[inline]
+    fn key(&self, p: &Publish) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        let msg_id = self.key_property.as_ref().and_then(|name| {
+            p.properties.user_properties.iter().find(|(k, _)| k == name.as_str()).map(|(_, v)| v)
+        });
+        if let Some(msg_id) = msg_id {
+            msg_id.as_bytes().hash(&mut hasher);
+        } else {
+            p.topic.as_bytes().hash(&mut hasher);
+            p.qos.value().hash(&mut hasher);
+            p.retain.hash(&mut hasher);
+            p.payload.as_ref().hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+
+    ///Remembers the publish, returns true if it was seen within the ttl
+    pub fn is_duplicate(&self, p: &Publish) -> bool {
+        let key = self.key(p);
+        let now = timestamp_millis();
+        let mut entries = self.entries.write();
+        let (seens, order) = &mut *entries;
+
+        //Expired, over the limit, or stale
+        while let Some((k, seen_at)) = order.front().copied() {
+            let current = seens.get(&k).copied();
+            if current != Some(seen_at) {
+                order.pop_front();
+            } else if now - seen_at > self.ttl || seens.len() > self.max {
+                seens.remove(&k);
+                order.pop_front();
+            } else {
+                break;
+            }
+        }
+
+        let duplicate = seens.get(&key).map(|seen_at| now - *seen_at <= self.ttl).unwrap_or(false);
+        seens.insert(key, now);
+        order.push_back((key, now));
+        duplicate
+    }
+}
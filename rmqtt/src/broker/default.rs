@@ -2,11 +2,13 @@ use std::collections::BTreeMap;
 use std::convert::From as _f;
 use std::num::NonZeroU16;
 use std::num::NonZeroU32;
+use std::panic::AssertUnwindSafe;
 use std::str::FromStr;
 use std::sync::Arc;
 
 #[allow(unused_imports)]
 use bitflags::Flags;
+use futures::FutureExt;
 use itertools::Itertools;
 use ntex_mqtt::types::{MQTT_LEVEL_31, MQTT_LEVEL_311, MQTT_LEVEL_5};
 use once_cell::sync::OnceCell;
@@ -16,12 +18,23 @@ use tokio::sync::{self, Mutex, OwnedMutexGuard};
 use tokio::time::Duration;
 use uuid::Uuid;
 
+use crate::broker::acl::{ACL_PUBLISH, ACL_SUBSCRIBE};
+use crate::broker::acl_cache::AclCache;
+use crate::broker::anonymous;
+use crate::broker::audit::AuditRecord;
+use crate::broker::fanout::FanOut;
 use crate::broker::fitter::{Fitter, FitterManager};
 use crate::broker::hook::{Handler, Hook, HookManager, HookResult, Parameter, Priority, Register, Type};
+use crate::broker::hook_stats::{HandlerStats, HookStats};
+use crate::broker::idempotency;
 use crate::broker::inflight::InflightMessage;
-use crate::broker::session::{Session, SessionLike, SessionManager, SessionOfflineInfo};
-use crate::broker::topic::{Topic, VecToTopic};
+use crate::broker::journal::{Journal, JournalEvent};
+use crate::broker::routing::RoutingPolicy;
+use crate::broker::session::{Session, SessionLike, SessionManager, SessionOfflineInfo, SessionSnapshot};
+use crate::broker::shared_queue::SharedQueues;
+use crate::broker::topic::{self, Topic, VecToTopic};
 use crate::broker::types::*;
+use crate::grpc::message_types::MessageTypes;
 use crate::settings::listener::Listener;
 use crate::stats::Counter;
 use crate::{grpc, MqttError, Result, Runtime};
@@ -352,6 +365,81 @@ impl DefaultShared {
         self.peers.get(client_id).map(|peer| (peer.tx.clone(), peer.s.id.clone()))
     }
 
+    ///Delivers the publish to the local subscribers, yields every yield_batch deliveries if it is
+    ///not 0
+    pub(crate) async fn _forwards_to(
+        &self,
+        from: &From,
+        publish: &Publish,
+        mut relations: SubRelations,
+        yield_batch: usize,
+    ) -> Vec<(To, From, Publish, Reason)> {
+        let mut errs = Vec::new();
+        let origin = RoutingPolicy::instance().origin(from, &publish.topic);
+
+        for (i, (topic_filter, client_id, opts, sub_ids, group_shared)) in relations.drain(..).enumerate() {
+            if yield_batch > 0 && i > 0 && i % yield_batch == 0 {
+                tokio::task::yield_now().await;
+            }
+            let target = self.tx(&client_id);
+            //the routing policy applies before the message is held for a group
+            let denied = match (origin.as_ref(), target.as_ref()) {
+                (Some(origin), Some((_, to))) => !origin.allowed(to),
+                _ => false,
+            };
+            //no member of the group is online, the message is held by the queue of the group
+            if let Some((group, false, _)) = group_shared.as_ref().filter(|_| !denied) {
+                if SharedQueues::enabled(group) {
+                    match SharedQueues::instance().push(&topic_filter, group, from, publish).await {
+                        Ok(()) => continue,
+                        Err(e) => log::warn!("forwards_to, group: {:?}, shared queue error, {:?}", group, e),
+                    }
+                }
+            }
+            let mut p = publish.clone();
+            p.dup = false;
+            p.retain = opts.retain_flag(publish.retain);
+            p.qos = p.qos.less_value(opts.qos());
+            p.packet_id = None;
+            p.properties.subscription_ids = sub_ids;
+            let (tx, to) = if let Some((tx, to)) = target {
+                (tx, to)
+            } else {
+                log::warn!(
+                    "forwards_to, from:{:?}, to:{:?}, topic_filter:{:?}, topic:{:?}, error: Tx is None",
+                    from,
+                    client_id,
+                    topic_filter,
+                    publish.topic
+                );
+                errs.push((To::from(0, client_id), from.clone(), p, Reason::from_static("Tx is None")));
+                continue;
+            };
+
+            if denied {
+                log::debug!("forwards_to, from:{:?}, to:{:?}, denied by the routing policy", from, to);
+                errs.push((to, from.clone(), p, Reason::from_static("Denied by the routing policy")));
+                continue;
+            }
+
+            if let Err(e) = tx.unbounded_send(Message::Forward(from.clone(), p)) {
+                log::warn!(
+                    "forwards_to,  from:{:?}, to:{:?}, topic_filter:{:?}, topic:{:?}, error:{:?}",
+                    from,
+                    client_id,
+                    topic_filter,
+                    publish.topic,
+                    e
+                );
+                if let Message::Forward(from, p) = e.into_inner() {
+                    errs.push((to, from, p, Reason::from_static("Connection Tx is closed")));
+                }
+            }
+        }
+
+        errs
+    }
+
     #[inline]
     pub async fn _query_subscriptions(&self, q: &SubsSearchParams) -> Vec<SubsSearchResult> {
         DefaultRouter::instance()._query_subscriptions(q).await
@@ -513,56 +601,14 @@ impl Shared for &'static DefaultShared {
         &self,
         from: From,
         publish: &Publish,
-        mut relations: SubRelations,
+        relations: SubRelations,
     ) -> Result<(), Vec<(To, From, Publish, Reason)>> {
-        let mut errs = Vec::new();
-
-        for (topic_filter, client_id, opts, sub_ids, _) in relations.drain(..) {
-            let retain = if let Some(retain_as_published) = opts.retain_as_published() {
-                //MQTT V5: Retain As Publish
-                if retain_as_published {
-                    publish.retain
-                } else {
-                    false
-                }
-            } else {
-                false
-            };
-
-            let mut p = publish.clone();
-            p.dup = false;
-            p.retain = retain;
-            p.qos = p.qos.less_value(opts.qos());
-            p.packet_id = None;
-            p.properties.subscription_ids = sub_ids;
-            let (tx, to) = if let Some((tx, to)) = self.tx(&client_id) {
-                (tx, to)
-            } else {
-                log::warn!(
-                    "forwards_to, from:{:?}, to:{:?}, topic_filter:{:?}, topic:{:?}, error: Tx is None",
-                    from,
-                    client_id,
-                    topic_filter,
-                    publish.topic
-                );
-                errs.push((To::from(0, client_id), from.clone(), p, Reason::from_static("Tx is None")));
-                continue;
-            };
-
-            if let Err(e) = tx.unbounded_send(Message::Forward(from.clone(), p)) {
-                log::warn!(
-                    "forwards_to,  from:{:?}, to:{:?}, topic_filter:{:?}, topic:{:?}, error:{:?}",
-                    from,
-                    client_id,
-                    topic_filter,
-                    publish.topic,
-                    e
-                );
-                if let Message::Forward(from, p) = e.into_inner() {
-                    errs.push((to, from, p, Reason::from_static("Connection Tx is closed")));
-                }
-            }
-        }
+        let fanout = FanOut::instance();
+        let errs = if fanout.is_hot(relations.len()) {
+            fanout.forwards(*self, &from, publish, relations).await
+        } else {
+            self._forwards_to(&from, publish, relations, 0).await
+        };
 
         if errs.is_empty() {
             Ok(())
@@ -669,6 +715,16 @@ impl DefaultRouter {
         })
     }
 
+    ///The topic filters of the client, with the id it subscribed with
+    #[inline]
+    pub fn client_relations(&self, client_id: &str) -> Vec<(TopicFilter, Id)> {
+        let client_id = ClientId::from(client_id);
+        self.relations
+            .iter()
+            .filter_map(|rels| rels.value().get(&client_id).map(|(id, _)| (rels.key().clone(), id.clone())))
+            .collect()
+    }
+
     #[inline]
     pub async fn _has_matches(&self, topic: &str) -> Result<bool> {
         let topic = Topic::from_str(topic)?;
@@ -769,6 +825,7 @@ impl DefaultRouter {
     fn _query_subscriptions_filter(
         q: &SubsSearchParams,
         client_id: &str,
+        id: &Id,
         opts: &SubscriptionOptions,
     ) -> bool {
         if let Some(ref q_clientid) = q.clientid {
@@ -777,6 +834,18 @@ impl DefaultRouter {
             }
         }
 
+        if let Some(ref q_clientid_prefix) = q.clientid_prefix {
+            if !client_id.starts_with(q_clientid_prefix.as_str()) {
+                return false;
+            }
+        }
+
+        if let Some(q_node_id) = q.node_id {
+            if q_node_id != id.node_id {
+                return false;
+            }
+        }
+
         if let Some(ref q_qos) = q.qos {
             if *q_qos != opts.qos_value() {
                 return false;
@@ -796,41 +865,55 @@ impl DefaultRouter {
         true
     }
 
+    #[inline]
+    fn _query_subscriptions_topic_filter(q: &SubsSearchParams, topic_filter: &str) -> bool {
+        if let Some(ref q_topic_prefix) = q.topic_prefix {
+            if !topic_filter.starts_with(q_topic_prefix.as_str()) {
+                return false;
+            }
+        }
+        true
+    }
+
+    #[inline]
+    fn _query_subscriptions_collect(
+        q: &SubsSearchParams,
+        topic_filter: &TopicFilter,
+        relations: &HashMap<ClientId, (Id, SubscriptionOptions)>,
+    ) -> Vec<SubsSearchResult> {
+        relations
+            .iter()
+            .filter(|(client_id, (id, opts))| {
+                Self::_query_subscriptions_filter(q, client_id.as_ref(), id, opts)
+            })
+            .map(|(client_id, (id, opts))| SubsSearchResult {
+                node_id: id.node_id,
+                clientid: client_id.clone(),
+                client_addr: id.remote_addr,
+                topic: topic_filter.clone(),
+                opts: opts.clone(),
+            })
+            .collect::<Vec<_>>()
+    }
+
     #[inline]
     async fn _query_subscriptions_for_topic(
         &self,
         q: &SubsSearchParams,
         topic: &str,
     ) -> Vec<SubsSearchResult> {
-        let limit = q._limit;
-        let mut curr: usize = 0;
+        if !Self::_query_subscriptions_topic_filter(q, topic) {
+            return Vec::new();
+        }
         let topic_filter = TopicFilter::from(topic);
-        return self
-            .relations
+        self.relations
             .get(topic)
-            .map(|e| {
-                e.value()
-                    .iter()
-                    .filter(|(client_id, (_id, opts))| {
-                        Self::_query_subscriptions_filter(q, client_id.as_ref(), opts)
-                    })
-                    .filter_map(|(client_id, (id, opts))| {
-                        if curr < limit {
-                            curr += 1;
-                            Some(SubsSearchResult {
-                                node_id: id.node_id,
-                                clientid: client_id.clone(),
-                                client_addr: id.remote_addr,
-                                topic: topic_filter.clone(),
-                                opts: opts.clone(),
-                            })
-                        } else {
-                            None
-                        }
-                    })
-                    .collect::<Vec<_>>()
-            })
-            .unwrap_or_default();
+            .map(|e| Self::_query_subscriptions_collect(q, &topic_filter, e.value()))
+            .unwrap_or_default()
+            .into_iter()
+            .skip(q._offset)
+            .take(q._limit)
+            .collect()
     }
 
     #[inline]
@@ -845,74 +928,34 @@ impl DefaultRouter {
             return Vec::new();
         };
 
-        let limit = q._limit;
-        let mut curr: usize = 0;
-
         self.topics
             .read()
             .await
             .matches(&topic)
             .iter()
             .unique()
-            .flat_map(|(topic_filter, _)| {
-                let topic_filter = topic_filter.to_topic_filter();
+            .map(|(topic_filter, _)| topic_filter.to_topic_filter())
+            .filter(|topic_filter| Self::_query_subscriptions_topic_filter(q, topic_filter))
+            .flat_map(|topic_filter| {
                 if let Some(entry) = self.relations.get(&topic_filter) {
-                    entry
-                        .iter()
-                        .filter(|(client_id, (_id, opts))| {
-                            Self::_query_subscriptions_filter(q, client_id.as_ref(), opts)
-                        })
-                        .filter_map(|(client_id, (id, opts))| {
-                            if curr < limit {
-                                curr += 1;
-                                Some(SubsSearchResult {
-                                    node_id: id.node_id,
-                                    clientid: client_id.clone(),
-                                    client_addr: id.remote_addr,
-                                    topic: topic_filter.clone(),
-                                    opts: opts.clone(),
-                                })
-                            } else {
-                                None
-                            }
-                        })
-                        .collect::<Vec<_>>()
+                    Self::_query_subscriptions_collect(q, &topic_filter, entry.value())
                 } else {
                     Vec::new()
                 }
             })
+            .skip(q._offset)
+            .take(q._limit)
             .collect::<Vec<_>>()
     }
 
     #[inline]
     async fn _query_subscriptions_for_other(&self, q: &SubsSearchParams) -> Vec<SubsSearchResult> {
-        let limit = q._limit;
-        let mut curr: usize = 0;
         self.relations
             .iter()
-            .flat_map(|e| {
-                let topic_filter = e.key();
-                e.value()
-                    .iter()
-                    .filter(|(client_id, (_id, opts))| {
-                        Self::_query_subscriptions_filter(q, client_id.as_ref(), opts)
-                    })
-                    .filter_map(|(client_id, (id, opts))| {
-                        if curr < limit {
-                            curr += 1;
-                            Some(SubsSearchResult {
-                                node_id: id.node_id,
-                                clientid: client_id.clone(),
-                                client_addr: id.remote_addr,
-                                topic: topic_filter.clone(),
-                                opts: opts.clone(),
-                            })
-                        } else {
-                            None
-                        }
-                    })
-                    .collect::<Vec<_>>()
-            })
+            .filter(|e| Self::_query_subscriptions_topic_filter(q, e.key()))
+            .flat_map(|e| Self::_query_subscriptions_collect(q, e.key(), e.value()))
+            .skip(q._offset)
+            .take(q._limit)
             .collect::<Vec<_>>()
     }
 
@@ -1000,6 +1043,31 @@ impl Router for &'static DefaultRouter {
         Ok(remove_ok)
     }
 
+    #[inline]
+    async fn remove_client(&self, client_id: &str) -> Result<usize> {
+        let mut removed = 0;
+        for (topic_filter, id) in self.client_relations(client_id) {
+            if self.remove(&topic_filter, id).await? {
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    #[inline]
+    async fn relations(&self, node_id: NodeId) -> Vec<(TopicFilter, Id)> {
+        self.relations
+            .iter()
+            .flat_map(|rels| {
+                rels.value()
+                    .values()
+                    .filter(|(id, _)| id.node_id == node_id)
+                    .map(|(id, _)| (rels.key().clone(), id.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
     #[inline]
     async fn matches(&self, id: Id, topic: &TopicName) -> Result<SubRelationsMap> {
         Ok(self._matches(id, topic).await?)
@@ -1061,6 +1129,30 @@ impl Router for &'static DefaultRouter {
         self.topics.read().await.values_size()
     }
 
+    #[inline]
+    async fn memory_info(&self) -> serde_json::Value {
+        let (nodes, values, bytes) = {
+            let topics = self.topics.read().await;
+            (topics.nodes_size(), topics.values_size(), topics.memory_size())
+        };
+        let (segments, segments_bytes) = topic::interned_segments();
+        json!({
+            "topic_tree": {
+                "nodes": nodes,
+                "values": values,
+                "bytes": bytes,
+            },
+            "interned_segments": {
+                "count": segments,
+                "bytes": segments_bytes,
+            },
+            "relations": {
+                "topic_filters": self.relations.len(),
+                "routes": self.relations_count.count(),
+            },
+        })
+    }
+
     #[inline]
     fn topics(&self) -> Counter {
         self.topics_count.clone()
@@ -1207,6 +1299,52 @@ impl DefaultRetainStorage {
             .collect::<Vec<(TopicName, Retain)>>();
         Ok(retains)
     }
+
+    #[inline]
+    pub async fn get_message_info(&self, topic_filter: &TopicFilter) -> Result<Vec<RetainInfo>> {
+        let topic = Topic::from_str(topic_filter)?;
+        let infos = self
+            .messages
+            .read()
+            .await
+            .matches(&topic)
+            .drain(..)
+            .filter(|(_, r)| !r.is_expired())
+            .map(|(t, r)| RetainInfo {
+                topic: TopicName::from(t.to_string()),
+                expiry_time_at: r.expiry_time_at(),
+                retain: r.into_value(),
+            })
+            .collect();
+        Ok(infos)
+    }
+
+    ///Removes the messages matching the topic filter, returns the number of the removed messages
+    #[inline]
+    pub async fn remove_message(&self, topic_filter: &TopicFilter) -> Result<usize> {
+        let topic = Topic::from_str(topic_filter)?;
+        let mut messages = self.messages.write().await;
+        let mut removeds = 0;
+        for (t, _) in messages.matches(&topic) {
+            if messages.remove(&t).is_some() {
+                self.retaineds.dec();
+                removeds += 1;
+            }
+        }
+        Ok(removeds)
+    }
+
+    ///The counted and the stored numbers of the messages, the counter is reset if repair is set
+    #[inline]
+    pub async fn check_count(&self, repair: bool) -> (isize, usize) {
+        let messages = self.messages.read().await;
+        let stored = messages.values_size();
+        let counted = self.retaineds.count();
+        if repair && counted != stored as isize {
+            self.retaineds.current_set(stored as isize);
+        }
+        (counted, stored)
+    }
 }
 
 #[async_trait]
@@ -1237,6 +1375,11 @@ impl RetainStorage for &'static DefaultRetainStorage {
     async fn max(&self) -> isize {
         self.retaineds.max()
     }
+
+    #[inline]
+    async fn check_count(&self, repair: bool) -> Result<Option<(isize, usize)>> {
+        Ok(Some(DefaultRetainStorage::check_count(*self, repair).await))
+    }
 }
 
 pub struct DefaultFitterManager {}
@@ -1303,11 +1446,12 @@ impl Fitter for DefaultFitter {
             )));
         }
 
-        if *keep_alive < 6 {
-            Ok(*keep_alive + 3)
+        let timeout = if *keep_alive < 6 {
+            (*keep_alive + 3) as f32
         } else {
-            Ok(((*keep_alive as f32 * self.listen_cfg.keepalive_backoff) * 2.0) as u16)
-        }
+            (*keep_alive as f32 * self.listen_cfg.keepalive_backoff) * 2.0
+        };
+        Ok((timeout * self.listen_cfg.keepalive_grace).min(u16::MAX as f32) as u16)
     }
 
     #[inline]
@@ -1389,11 +1533,18 @@ impl Fitter for DefaultFitter {
 struct HookEntry {
     handler: Box<dyn Handler>,
     enabled: bool,
+    //the plugin registering the handler
+    plugin: Option<String>,
+    //names of the listeners the handler is restricted to, all if empty
+    listeners: Vec<String>,
+    //calls, errors and latencies of the handlers of the plugin for the type
+    stats: Arc<HandlerStats>,
 }
 
 impl HookEntry {
-    fn new(handler: Box<dyn Handler>) -> Self {
-        Self { handler, enabled: false }
+    fn new(typ: Type, handler: Box<dyn Handler>, plugin: Option<String>, listeners: Vec<String>) -> Self {
+        let stats = HookStats::instance().handler(plugin.as_deref(), typ);
+        Self { handler, enabled: false, plugin, listeners, stats }
     }
 }
 
@@ -1413,7 +1564,21 @@ impl DefaultHookManager {
     }
 
     #[inline]
-    async fn add(&self, typ: Type, priority: Priority, handler: Box<dyn Handler>) -> Result<HandlerId> {
+    async fn add(
+        &self,
+        typ: Type,
+        priority: Priority,
+        handler: Box<dyn Handler>,
+        plugin: Option<String>,
+        listeners: Vec<String>,
+    ) -> Result<HandlerId> {
+        //the scope of the plugin in the config, if not given at registration
+        let listeners = match plugin.as_ref() {
+            Some(plugin) if listeners.is_empty() => {
+                Runtime::instance().settings.plugins.listener_scopes.get(plugin).cloned().unwrap_or_default()
+            }
+            _ => listeners,
+        };
         let id = Uuid::new_v4().as_simple().encode_lower(&mut Uuid::encode_buffer()).to_string();
         let type_handlers =
             self.handlers.entry(typ).or_insert(Arc::new(sync::RwLock::new(BTreeMap::default())));
@@ -1423,29 +1588,80 @@ impl DefaultHookManager {
         if contains_key {
             Err(MqttError::from(format!("handler id is repetition, key is {:?}, type is {:?}", key, typ)))
         } else {
-            type_handlers.insert(key, HookEntry::new(handler));
+            type_handlers.insert(key, HookEntry::new(typ, handler, plugin, listeners));
             Ok(id)
         }
     }
 
+    ///There are enabled handlers of the type
+    #[inline]
+    async fn has_handlers(&self, t: Type) -> bool {
+        let type_handlers = { self.handlers.get(&t).map(|h| (*h.value()).clone()) };
+        if let Some(type_handlers) = type_handlers {
+            type_handlers.read().await.values().any(|entry| entry.enabled)
+        } else {
+            false
+        }
+    }
+
     #[inline]
     async fn exec<'a>(&'a self, t: Type, p: Parameter<'a>) -> Option<HookResult> {
         let mut acc = None;
         let type_handlers = { self.handlers.get(&t).map(|h| (*h.value()).clone()) };
         if let Some(type_handlers) = type_handlers {
             let type_handlers = type_handlers.read().await;
+            //the listener of the client, resolved for the first scoped handler, the events not tied to a
+            //client are not restricted, the clients of an unknown listener skip the scoped handlers
+            let mut listener: Option<Option<Listener>> = None;
             for (_, entry) in type_handlers.iter().rev() {
+                if entry.enabled && !entry.listeners.is_empty() && p.id().is_some() {
+                    let in_scope = listener
+                        .get_or_insert_with(|| p.listener())
+                        .as_ref()
+                        .map(|l| entry.listeners.iter().any(|name| *name == l.name))
+                        .unwrap_or(false);
+                    if !in_scope {
+                        continue;
+                    }
+                }
                 if entry.enabled {
-                    let (proceed, new_acc) = entry.handler.hook(&p, acc).await;
+                    //A panicking handler is isolated, its plugin is restarted per the restart policy, the
+                    //result of the previous handlers, as updated in place by the handler, is kept
+                    let now = std::time::Instant::now();
+                    let proceed =
+                        match AssertUnwindSafe(entry.handler.hook(&p, &mut acc)).catch_unwind().await {
+                            Ok(proceed) => {
+                                let error =
+                                    !proceed && matches!(acc, Some(HookResult::GrpcMessageReply(Err(_))));
+                                entry.stats.observe(now.elapsed(), error);
+                                proceed
+                            }
+                            Err(e) => {
+                                entry.stats.observe(now.elapsed(), true);
+                                Self::handler_panicked(t, entry.plugin.clone(), e);
+                                true
+                            }
+                        };
                     if !proceed {
-                        return new_acc;
+                        return acc;
                     }
-                    acc = new_acc;
                 }
             }
         }
         acc
     }
+
+    fn handler_panicked(t: Type, plugin: Option<String>, e: Box<dyn std::any::Any + Send>) {
+        let msg = e
+            .downcast_ref::<&str>()
+            .map(|msg| msg.to_string())
+            .or_else(|| e.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        log::error!("hook handler panicked, type: {:?}, plugin: {:?}, {}", t, plugin, msg);
+        if let Some(plugin) = plugin {
+            tokio::spawn(async move { Runtime::instance().plugins.handler_panicked(&plugin).await });
+        }
+    }
 }
 
 #[async_trait]
@@ -1465,6 +1681,11 @@ impl HookManager for &'static DefaultHookManager {
         self.exec(Type::BeforeStartup, Parameter::BeforeStartup).await;
     }
 
+    #[inline]
+    async fn before_shutdown(&self) {
+        self.exec(Type::BeforeShutdown, Parameter::BeforeShutdown).await;
+    }
+
     #[inline]
     async fn client_connect(&self, connect_info: &ConnectInfo) -> Option<UserProperties> {
         let result = self.exec(Type::ClientConnect, Parameter::ClientConnect(connect_info)).await;
@@ -1476,12 +1697,28 @@ impl HookManager for &'static DefaultHookManager {
         }
     }
 
+    #[inline]
+    async fn client_packet_size_exempt(&self, connect_info: &ConnectInfo) -> bool {
+        matches!(
+            self.exec(Type::ClientPacketSizeExempt, Parameter::ClientPacketSizeExempt(connect_info)).await,
+            Some(HookResult::PacketSizeExempt(true))
+        )
+    }
+
+    #[inline]
+    async fn client_labels(&self, connect_info: &ConnectInfo) -> Vec<String> {
+        match self.exec(Type::ClientLabels, Parameter::ClientLabels(connect_info)).await {
+            Some(HookResult::Labels(labels)) => labels,
+            _ => Vec::new(),
+        }
+    }
+
     #[inline]
     async fn client_authenticate(
         &self,
         connect_info: &ConnectInfo,
         allow_anonymous: bool,
-    ) -> (ConnectAckReason, Superuser) {
+    ) -> (ConnectAckReason, Superuser, Option<AuthInfo>) {
         let proto_ver = connect_info.proto_ver();
         let ok = || match proto_ver {
             MQTT_LEVEL_31 => ConnectAckReason::V3(ConnectAckReasonV3::ConnectionAccepted),
@@ -1492,7 +1729,7 @@ impl HookManager for &'static DefaultHookManager {
 
         log::debug!("{:?} username: {:?}", connect_info.id(), connect_info.username());
         if connect_info.username().is_none() && allow_anonymous {
-            return (ok(), false);
+            return (ok(), false, Some(AuthInfo::new_anonymous()));
         }
 
         let result = self.exec(Type::ClientAuthenticate, Parameter::ClientAuthenticate(connect_info)).await;
@@ -1500,11 +1737,13 @@ impl HookManager for &'static DefaultHookManager {
         let (bad_user_or_pass, not_auth) = match result {
             Some(HookResult::AuthResult(AuthResult::BadUsernameOrPassword)) => (true, false),
             Some(HookResult::AuthResult(AuthResult::NotAuthorized)) => (false, true),
-            Some(HookResult::AuthResult(AuthResult::Allow(superuser))) => return (ok(), superuser),
+            Some(HookResult::AuthResult(AuthResult::Allow(superuser, auth_info))) => {
+                return (ok(), superuser, auth_info)
+            }
             _ => {
                 //or AuthResult::NotFound
                 if allow_anonymous {
-                    return (ok(), false);
+                    return (ok(), false, Some(AuthInfo::new_anonymous()));
                 } else {
                     (false, true)
                 }
@@ -1520,6 +1759,7 @@ impl HookManager for &'static DefaultHookManager {
                     _ => ConnectAckReason::V3(ConnectAckReasonV3::BadUserNameOrPassword),
                 },
                 false,
+                None,
             );
         }
 
@@ -1532,10 +1772,11 @@ impl HookManager for &'static DefaultHookManager {
                     _ => ConnectAckReason::V3(ConnectAckReasonV3::NotAuthorized),
                 },
                 false,
+                None,
             );
         }
 
-        (ok(), false)
+        (ok(), false, None)
     }
 
     ///When sending mqtt:: connectack message
@@ -1555,18 +1796,39 @@ impl HookManager for &'static DefaultHookManager {
     }
 
     #[inline]
-    async fn message_publish(&self, s: Option<&Session>, from: From, publish: &Publish) -> Option<Publish> {
-        let result = self.exec(Type::MessagePublish, Parameter::MessagePublish(s, from, publish)).await;
-        if let Some(HookResult::Publish(publish)) = result {
-            Some(publish)
+    async fn message_publish(
+        &self,
+        s: Option<&Session>,
+        from: From,
+        publish: &Publish,
+    ) -> std::result::Result<Option<Publish>, PublishDrop> {
+        //The idempotency key is assigned before the hooks, the egress bridges
+        let cfg = &Runtime::instance().settings.idempotency;
+        let assigned = if cfg.enable {
+            idempotency::assign(publish, &cfg.property, Runtime::instance().node.id())
         } else {
             None
+        };
+        let publish = assigned.as_ref().unwrap_or(publish);
+        let result =
+            self.exec(Type::MessagePublish, Parameter::MessagePublish(s, from.clone(), publish)).await;
+        match result {
+            Some(HookResult::Publish(publish)) => Ok(Some(publish)),
+            Some(HookResult::PublishDrop(dropped)) => {
+                log::debug!("message dropped by the hook, topic: {}, {:?}", publish.topic, dropped.reason);
+                self.message_dropped(None, from, publish.clone(), dropped.reason.clone()).await;
+                Err(dropped)
+            }
+            _ => Ok(assigned),
         }
     }
 
     ///Publish message Dropped
     #[inline]
     async fn message_dropped(&self, to: Option<To>, from: From, publish: Publish, reason: Reason) {
+        if let Some(to) = to.as_ref() {
+            Journal::instance().record(&to.client_id, || JournalEvent::dropped(&publish, &reason));
+        }
         let _ = self.exec(Type::MessageDropped, Parameter::MessageDropped(to, from, publish, reason)).await;
     }
 
@@ -1576,6 +1838,27 @@ impl HookManager for &'static DefaultHookManager {
         let _ = self.exec(Type::MessageNonsubscribed, Parameter::MessageNonsubscribed(from)).await;
     }
 
+    ///Cluster degraded or restored
+    #[inline]
+    async fn cluster_degraded(&self, degraded: bool, reason: String) {
+        let _ = self.exec(Type::ClusterDegraded, Parameter::ClusterDegraded(degraded, reason)).await;
+    }
+
+    ///Audit record, for the plugins forwarding the audit stream
+    #[inline]
+    async fn audit_record(&self, record: &AuditRecord) {
+        let _ = self.exec(Type::AuditRecord, Parameter::AuditRecord(record)).await;
+    }
+
+    ///The session is loaded from an external store
+    #[inline]
+    async fn session_store_load(&self, id: &Id) -> Option<SessionSnapshot> {
+        match self.exec(Type::SessionStoreLoad, Parameter::SessionStoreLoad(id)).await {
+            Some(HookResult::SessionSnapshot(snapshot)) => Some(*snapshot),
+            _ => None,
+        }
+    }
+
     ///grpc message received
     #[inline]
     async fn grpc_message_received(
@@ -1586,6 +1869,14 @@ impl HookManager for &'static DefaultHookManager {
         let result = self.exec(Type::GrpcMessageReceived, Parameter::GrpcMessageReceived(typ, msg)).await;
         if let Some(HookResult::GrpcMessageReply(reply)) = result {
             reply
+        } else if MessageTypes::instance().get(typ).is_none() {
+            Runtime::instance().metrics.grpc_messages_unclaimed_inc();
+            log::warn!("grpc message of type {} received, the type is not claimed on this node", typ);
+            Ok(grpc::MessageReply::Error(format!(
+                "message type {} is not claimed on node {}, is the plugin using it started there?",
+                typ,
+                Runtime::instance().node.id()
+            )))
         } else {
             Ok(grpc::MessageReply::Success)
         }
@@ -1595,12 +1886,17 @@ impl HookManager for &'static DefaultHookManager {
 pub struct DefaultHookRegister {
     manager: &'static DefaultHookManager,
     type_ids: Arc<DashSet<(Type, (Priority, HandlerId))>>,
+    plugin: Option<String>,
 }
 
 impl DefaultHookRegister {
     #[inline]
     fn new(manager: &'static DefaultHookManager) -> Self {
-        DefaultHookRegister { manager, type_ids: Arc::new(DashSet::default()) }
+        DefaultHookRegister {
+            manager,
+            type_ids: Arc::new(DashSet::default()),
+            plugin: crate::plugin::current_plugin(),
+        }
     }
 
     #[inline]
@@ -1622,7 +1918,19 @@ impl DefaultHookRegister {
 impl Register for DefaultHookRegister {
     #[inline]
     async fn add_priority(&self, typ: Type, priority: Priority, handler: Box<dyn Handler>) {
-        match self.manager.add(typ, priority, handler).await {
+        self.add_scoped(typ, priority, Vec::new(), handler).await;
+    }
+
+    #[inline]
+    async fn add_scoped(
+        &self,
+        typ: Type,
+        priority: Priority,
+        listeners: Vec<String>,
+        handler: Box<dyn Handler>,
+    ) {
+        let plugin = self.plugin.clone().or_else(crate::plugin::current_plugin);
+        match self.manager.add(typ, priority, handler, plugin, listeners).await {
             Ok(id) => {
                 self.type_ids.insert((typ, (priority, id)));
             }
@@ -1660,29 +1968,68 @@ impl DefaultHook {
 impl Hook for DefaultHook {
     #[inline]
     async fn session_created(&self) {
+        Journal::instance().record(&self.s.id.client_id, || JournalEvent::SessionCreated);
         self.manager.exec(Type::SessionCreated, Parameter::SessionCreated(&self.s)).await;
     }
 
     #[inline]
     async fn client_connected(&self) {
+        Journal::instance().record(&self.s.id.client_id, || JournalEvent::connected(&self.s.id));
         let _ = self.manager.exec(Type::ClientConnected, Parameter::ClientConnected(&self.s)).await;
     }
 
     #[inline]
     async fn client_disconnected(&self, r: Reason) {
+        Journal::instance()
+            .record(&self.s.id.client_id, || JournalEvent::Disconnected { reason: r.to_string() });
         let _ = self.manager.exec(Type::ClientDisconnected, Parameter::ClientDisconnected(&self.s, r)).await;
     }
 
     #[inline]
     async fn session_terminated(&self, r: Reason) {
+        Journal::instance()
+            .record(&self.s.id.client_id, || JournalEvent::SessionTerminated { reason: r.to_string() });
         let _ = self.manager.exec(Type::SessionTerminated, Parameter::SessionTerminated(&self.s, r)).await;
     }
 
+    #[inline]
+    async fn session_store_save(&self) {
+        //The snapshot is only taken for the registered stores
+        if !self.manager.has_handlers(Type::SessionStoreSave).await {
+            return;
+        }
+        match SessionSnapshot::new(&self.s).await {
+            Ok(snapshot) => {
+                let _ = self
+                    .manager
+                    .exec(Type::SessionStoreSave, Parameter::SessionStoreSave(&self.s, &snapshot))
+                    .await;
+            }
+            Err(e) => log::warn!("{:?} session snapshot error, {:?}", self.s.id, e),
+        }
+    }
+
+    #[inline]
+    async fn session_store_remove(&self) {
+        let _ = self.manager.exec(Type::SessionStoreRemove, Parameter::SessionStoreRemove(&self.s)).await;
+    }
+
     #[inline]
     async fn client_subscribe_check_acl(&self, sub: &Subscribe) -> Option<SubscribeAclResult> {
         if self.s.superuser().await.unwrap_or_default() {
             return Some(SubscribeAclResult::new_success(sub.opts.qos(), None));
         }
+        if self.s.anonymous() && !anonymous::subscribe_allowed(self.s.listen_cfg(), &sub.topic_filter) {
+            return Some(SubscribeAclResult::new_failure(SubscribeAckReason::NotAuthorized));
+        }
+        let rules = self.s.acl_rules().await;
+        if let Some(allow) = rules.and_then(|rules| rules.check(&sub.topic_filter, ACL_SUBSCRIBE)) {
+            return Some(if allow {
+                SubscribeAclResult::new_success(sub.opts.qos(), None)
+            } else {
+                SubscribeAclResult::new_failure(SubscribeAckReason::NotAuthorized)
+            });
+        }
         let reply = self
             .manager
             .exec(Type::ClientSubscribeCheckAcl, Parameter::ClientSubscribeCheckAcl(&self.s, sub))
@@ -1700,16 +2047,33 @@ impl Hook for DefaultHook {
         if self.s.superuser().await.unwrap_or_default() {
             return PublishAclResult::Allow;
         }
+        if self.s.anonymous() && !anonymous::publish_allowed(self.s.listen_cfg(), publish.topic()) {
+            return PublishAclResult::Rejected(false);
+        }
+        let rules = self.s.acl_rules().await;
+        if let Some(allow) = rules.and_then(|rules| rules.check(publish.topic(), ACL_PUBLISH)) {
+            return if allow { PublishAclResult::Allow } else { PublishAclResult::Rejected(false) };
+        }
+        let cached = AclCache::enabled();
+        if cached {
+            if let Some(r) = AclCache::instance().get_publish(&self.s.id, publish.topic()) {
+                return r;
+            }
+        }
         let result = self
             .manager
             .exec(Type::MessagePublishCheckAcl, Parameter::MessagePublishCheckAcl(&self.s, publish))
             .await;
         log::debug!("{:?} result: {:?}", self.s.id, result);
-        if let Some(HookResult::PublishAclResult(acl_result)) = result {
+        let acl_result = if let Some(HookResult::PublishAclResult(acl_result)) = result {
             acl_result
         } else {
             PublishAclResult::Allow
+        };
+        if cached {
+            AclCache::instance().set_publish(&self.s.id, publish.topic().clone(), acl_result.clone());
         }
+        acl_result
     }
 
     #[inline]
@@ -1723,8 +2087,24 @@ impl Hook for DefaultHook {
         }
     }
 
+    #[inline]
+    async fn session_subscribed_inject(&self, subscribe: &Subscribe) -> Vec<Publish> {
+        match self
+            .manager
+            .exec(Type::SessionSubscribedInject, Parameter::SessionSubscribedInject(&self.s, subscribe))
+            .await
+        {
+            Some(HookResult::Publishes(publishes)) => publishes,
+            _ => Vec::new(),
+        }
+    }
+
     #[inline]
     async fn session_subscribed(&self, subscribe: Subscribe) {
+        Journal::instance().record(&self.s.id.client_id, || JournalEvent::Subscribed {
+            topic_filter: subscribe.topic_filter.to_string(),
+            qos: subscribe.opts.qos().value(),
+        });
         let _ = self
             .manager
             .exec(Type::SessionSubscribed, Parameter::SessionSubscribed(&self.s, subscribe))
@@ -1745,6 +2125,9 @@ impl Hook for DefaultHook {
 
     #[inline]
     async fn session_unsubscribed(&self, unsubscribe: Unsubscribe) {
+        Journal::instance().record(&self.s.id.client_id, || JournalEvent::Unsubscribed {
+            topic_filter: unsubscribe.topic_filter.to_string(),
+        });
         let _ = self
             .manager
             .exec(Type::SessionUnsubscribed, Parameter::SessionUnsubscribed(&self.s, unsubscribe))
@@ -1752,12 +2135,56 @@ impl Hook for DefaultHook {
     }
 
     #[inline]
-    async fn message_publish(&self, from: From, publish: &Publish) -> Option<Publish> {
+    async fn client_connack_props(&self) -> Option<AckProperties> {
+        let result =
+            self.manager.exec(Type::ClientConnackProps, Parameter::ClientConnackProps(&self.s)).await;
+        log::debug!("{:?} result: {:?}", self.s.id, result);
+        if let Some(HookResult::AckProperties(props)) = result {
+            Some(props)
+        } else {
+            None
+        }
+    }
+
+    #[inline]
+    async fn client_suback_props(&self, subs: &[(Subscribe, SubscribeReturn)]) -> Option<AckProperties> {
+        let result =
+            self.manager.exec(Type::ClientSubackProps, Parameter::ClientSubackProps(&self.s, subs)).await;
+        log::debug!("{:?} result: {:?}", self.s.id, result);
+        if let Some(HookResult::AckProperties(props)) = result {
+            Some(props)
+        } else {
+            None
+        }
+    }
+
+    #[inline]
+    async fn client_unsuback_props(&self, unsubs: &[Unsubscribe]) -> Option<AckProperties> {
+        let result = self
+            .manager
+            .exec(Type::ClientUnsubackProps, Parameter::ClientUnsubackProps(&self.s, unsubs))
+            .await;
+        log::debug!("{:?} result: {:?}", self.s.id, result);
+        if let Some(HookResult::AckProperties(props)) = result {
+            Some(props)
+        } else {
+            None
+        }
+    }
+
+    #[inline]
+    async fn message_publish(
+        &self,
+        from: From,
+        publish: &Publish,
+    ) -> std::result::Result<Option<Publish>, PublishDrop> {
+        Journal::instance().record(&self.s.id.client_id, || JournalEvent::publish_in(publish));
         self.manager.message_publish(Some(&self.s), from, publish).await
     }
 
     #[inline]
     async fn message_delivered(&self, from: From, publish: &Publish) -> Option<Publish> {
+        Journal::instance().record(&self.s.id.client_id, || JournalEvent::publish_out(publish));
         let result = self
             .manager
             .exec(Type::MessageDelivered, Parameter::MessageDelivered(&self.s, from, publish))
@@ -1770,13 +2197,27 @@ impl Hook for DefaultHook {
         }
     }
 
+    #[inline]
+    async fn message_delivered_latency(&self, from: From, publish: &Publish, latency: Duration) {
+        let _ = self
+            .manager
+            .exec(
+                Type::MessageDeliveredLatency,
+                Parameter::MessageDeliveredLatency(&self.s, from, publish, latency),
+            )
+            .await;
+    }
+
     #[inline]
     async fn message_acked(&self, from: From, publish: &Publish) {
+        Journal::instance().record(&self.s.id.client_id, || JournalEvent::acked(publish));
         let _ = self.manager.exec(Type::MessageAcked, Parameter::MessageAcked(&self.s, from, publish)).await;
     }
 
     #[inline]
     async fn offline_message(&self, from: From, publish: &Publish) {
+        Journal::instance()
+            .record(&self.s.id.client_id, || JournalEvent::Offline { topic: publish.topic.to_string() });
         let _ =
             self.manager.exec(Type::OfflineMessage, Parameter::OfflineMessage(&self.s, from, publish)).await;
     }
@@ -1821,6 +2262,28 @@ impl Hook for DefaultHook {
         }
         MessageExpiryCheckResult::Expiry
     }
+
+    #[inline]
+    async fn will_message_publish(&self, from: From, publish: Publish) -> Option<Publish> {
+        let result = self
+            .manager
+            .exec(Type::WillMessagePublish, Parameter::WillMessagePublish(&self.s, from, &publish))
+            .await;
+        log::debug!("{:?} result: {:?}", self.s.id, result);
+        match result {
+            Some(HookResult::WillMessageSuppress) => None,
+            Some(HookResult::Publish(publish)) => Some(publish),
+            _ => Some(publish),
+        }
+    }
+
+    #[inline]
+    async fn will_message_dropped(&self, from: From, publish: Publish, reason: Reason) {
+        let _ = self
+            .manager
+            .exec(Type::WillMessageDropped, Parameter::WillMessageDropped(&self.s, from, publish, reason))
+            .await;
+    }
 }
 
 pub struct DefaultSessionManager {}
@@ -2066,3 +2529,71 @@ impl SessionLike for DefaultSession {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+
+    use tokio::sync::RwLock;
+
+    use super::{DefaultHookManager, HookEntry};
+    use crate::broker::hook::{Handler, HookResult, Parameter, ReturnType, Type};
+    use crate::broker::types::{ClientId, ConnectInfo, DashMap, Id};
+
+    struct Labels;
+
+    #[async_trait]
+    impl Handler for Labels {
+        async fn hook(&self, _param: &Parameter, acc: &mut Option<HookResult>) -> ReturnType {
+            *acc = Some(HookResult::Labels(vec!["a".into()]));
+            true
+        }
+    }
+
+    struct Panicking;
+
+    #[async_trait]
+    impl Handler for Panicking {
+        async fn hook(&self, _param: &Parameter, _acc: &mut Option<HookResult>) -> ReturnType {
+            panic!("handler panicked")
+        }
+    }
+
+    #[test]
+    fn test_exec_panic_keeps_result() {
+        let mut type_handlers = BTreeMap::new();
+        for (priority, handler) in [(2, Box::new(Labels) as Box<dyn Handler>), (1, Box::new(Panicking))] {
+            let mut entry = HookEntry::new(Type::BeforeStartup, handler, None, Vec::new());
+            entry.enabled = true;
+            type_handlers.insert((priority, priority.to_string()), entry);
+        }
+        let manager = DefaultHookManager { handlers: Arc::new(DashMap::default()) };
+        manager.handlers.insert(Type::BeforeStartup, Arc::new(RwLock::new(type_handlers)));
+
+        let acc = futures::executor::block_on(manager.exec(Type::BeforeStartup, Parameter::BeforeStartup));
+        assert!(matches!(acc, Some(HookResult::Labels(labels)) if labels == ["a"]));
+    }
+
+    #[test]
+    fn test_exec_scoped_unknown_listener() {
+        let manager = DefaultHookManager { handlers: Arc::new(DashMap::default()) };
+        for typ in [Type::BeforeStartup, Type::ClientLabels] {
+            let mut entry = HookEntry::new(typ, Box::new(Labels), None, vec!["external".into()]);
+            entry.enabled = true;
+            let type_handlers = BTreeMap::from([((0, "0".to_string()), entry)]);
+            manager.handlers.insert(typ, Arc::new(RwLock::new(type_handlers)));
+        }
+
+        //Not tied to a client, not restricted
+        let acc = futures::executor::block_on(manager.exec(Type::BeforeStartup, Parameter::BeforeStartup));
+        assert!(matches!(acc, Some(HookResult::Labels(_))));
+
+        //No local address, the listener of the client is unknown
+        let connect_info = ConnectInfo::from(Id::from(1, ClientId::from("c1")));
+        let acc = futures::executor::block_on(
+            manager.exec(Type::ClientLabels, Parameter::ClientLabels(&connect_info)),
+        );
+        assert!(acc.is_none());
+    }
+}
@@ -0,0 +1,185 @@
+//! Encryption at rest of the payloads persisted by the storage plugins. A sealed payload carries the
+//! id of the key it is encrypted with, so the keys can be rotated: a new key becomes the `key_id`
+//! of the new payloads, the previous keys are kept to decrypt the payloads stored before. The
+//! payloads stored without encryption are read as they are.
+//!
+//! The default provider is AES-256-GCM with the keys of the `encryption` settings, another one is
+//! registered with `Runtime::instance().extends.encryption_mut()`.
+
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce};
+use base64::{engine::general_purpose, Engine as _};
+use once_cell::sync::OnceCell;
+
+use crate::broker::types::{HashMap, Publish};
+use crate::settings::secret;
+use crate::{MqttError, Result, Runtime};
+
+//Marks a sealed payload, followed by the key id length, the key id and the ciphertext
+const MAGIC: [u8; 4] = [0x00, b'R', b'E', 0x01];
+const NONCE_LEN: usize = 12;
+
+pub trait EncryptionProvider: Sync + Send {
+    ///Id of the key encrypting the new payloads, None if encryption is not configured
+    fn key_id(&self) -> Option<String>;
+
+    fn encrypt(&self, key_id: &str, plaintext: &[u8]) -> Result<Vec<u8>>;
+
+    fn decrypt(&self, key_id: &str, ciphertext: &[u8]) -> Result<Vec<u8>>;
+}
+
+///AES-256-GCM, the ciphertext is prefixed with its random nonce
+pub struct DefaultEncryptionProvider {
+    //loaded on first use, the settings are not available when the provider is registered
+    ciphers: OnceCell<(Option<String>, HashMap<String, Aes256Gcm>)>,
+}
+
+impl DefaultEncryptionProvider {
+    #[inline]
+    pub fn instance() -> &'static DefaultEncryptionProvider {
+        static INSTANCE: OnceCell<DefaultEncryptionProvider> = OnceCell::new();
+        INSTANCE.get_or_init(|| Self { ciphers: OnceCell::new() })
+    }
+
+    #[inline]
+    fn ciphers(&self) -> &(Option<String>, HashMap<String, Aes256Gcm>) {
+        self.ciphers.get_or_init(|| {
+            let cfg = &Runtime::instance().settings.encryption;
+            let mut ciphers = HashMap::default();
+            for k in cfg.keys.iter() {
+                match Self::cipher(&k.key) {
+                    Ok(cipher) => {
+                        ciphers.insert(k.id.clone(), cipher);
+                    }
+                    Err(e) => log::error!("encryption key {}, {}", k.id, e),
+                }
+            }
+            let key_id = if cfg.key_id.is_empty() { None } else { Some(cfg.key_id.clone()) };
+            if let Some(key_id) = key_id.as_ref() {
+                if !ciphers.contains_key(key_id) {
+                    log::error!("encryption key {} is not configured", key_id);
+                }
+            }
+            (key_id, ciphers)
+        })
+    }
+
+    #[inline]
+    fn cipher(key: &str) -> Result<Aes256Gcm> {
+        let key = general_purpose::STANDARD
+            .decode(secret::resolve_str(key)?.trim())
+            .map_err(|e| MqttError::from(format!("invalid key, {}", e)))?;
+        Aes256Gcm::new_from_slice(&key).map_err(|_| MqttError::from("invalid key, 32 bytes are required"))
+    }
+
+    #[inline]
+    fn get(&self, key_id: &str) -> Result<&Aes256Gcm> {
+        self.ciphers()
+            .1
+            .get(key_id)
+            .ok_or_else(|| MqttError::from(format!("encryption key {} is not configured", key_id)))
+    }
+}
+
+impl EncryptionProvider for &'static DefaultEncryptionProvider {
+    #[inline]
+    fn key_id(&self) -> Option<String> {
+        self.ciphers().0.clone()
+    }
+
+    #[inline]
+    fn encrypt(&self, key_id: &str, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let nonce: [u8; NONCE_LEN] = rand::random();
+        let ciphertext = self
+            .get(key_id)?
+            .encrypt(Nonce::from_slice(&nonce), plaintext)
+            .map_err(|_| MqttError::from("encrypt error"))?;
+        Ok([nonce.as_slice(), ciphertext.as_slice()].concat())
+    }
+
+    #[inline]
+    fn decrypt(&self, key_id: &str, ciphertext: &[u8]) -> Result<Vec<u8>> {
+        if ciphertext.len() < NONCE_LEN {
+            return Err(MqttError::from("decrypt error, truncated ciphertext"));
+        }
+        let (nonce, ciphertext) = ciphertext.split_at(NONCE_LEN);
+        self.get(key_id)?
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| MqttError::from(format!("decrypt error, key {}", key_id)))
+    }
+}
+
+///Encrypts the payload with the current key
+pub fn seal(provider: &dyn EncryptionProvider, payload: &[u8]) -> Result<Vec<u8>> {
+    let key_id = provider.key_id().ok_or_else(|| MqttError::from("encryption key is not configured"))?;
+    if key_id.len() > u8::MAX as usize {
+        return Err(MqttError::from("encryption key id is too long"));
+    }
+    let ciphertext = provider.encrypt(&key_id, payload)?;
+    let mut sealed = Vec::with_capacity(MAGIC.len() + 1 + key_id.len() + ciphertext.len());
+    sealed.extend_from_slice(&MAGIC);
+    sealed.push(key_id.len() as u8);
+    sealed.extend_from_slice(key_id.as_bytes());
+    sealed.extend_from_slice(&ciphertext);
+    Ok(sealed)
+}
+
+///Decrypts a sealed payload with the key it was sealed with, the others are returned as they are
+pub fn open(provider: &dyn EncryptionProvider, data: &[u8]) -> Result<Vec<u8>> {
+    let rest = match data.strip_prefix(MAGIC.as_slice()) {
+        Some(rest) => rest,
+        None => return Ok(data.to_vec()),
+    };
+    let (len, rest) = rest.split_first().ok_or_else(|| MqttError::from("decrypt error, truncated"))?;
+    if rest.len() < *len as usize {
+        return Err(MqttError::from("decrypt error, truncated"));
+    }
+    let (key_id, ciphertext) = rest.split_at(*len as usize);
+    let key_id = std::str::from_utf8(key_id).map_err(|_| MqttError::from("decrypt error, invalid key id"))?;
+    provider.decrypt(key_id, ciphertext)
+}
+
+///Encrypts the payload of the publish, before it is persisted
+pub async fn seal_publish(publish: &mut Publish) -> Result<()> {
+    let provider = Runtime::instance().extends.encryption().await;
+    publish.payload = seal(provider.as_ref(), &publish.payload)?.into();
+    Ok(())
+}
+
+///Decrypts the payload of a persisted publish
+pub async fn open_publish(publish: &mut Publish) -> Result<()> {
+    let provider = Runtime::instance().extends.encryption().await;
+    publish.payload = open(provider.as_ref(), &publish.payload)?.into();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use aes_gcm::aead::KeyInit;
+    use aes_gcm::Aes256Gcm;
+    use once_cell::sync::OnceCell;
+
+    use super::{open, seal, DefaultEncryptionProvider};
+    use crate::broker::types::HashMap;
+
+    #[test]
+    fn test_seal_open() {
+        let mut ciphers = HashMap::default();
+        ciphers.insert("k1".to_owned(), Aes256Gcm::new_from_slice(&[1u8; 32]).unwrap());
+        ciphers.insert("k2".to_owned(), Aes256Gcm::new_from_slice(&[2u8; 32]).unwrap());
+        let k1: &'static DefaultEncryptionProvider = Box::leak(Box::new(DefaultEncryptionProvider {
+            ciphers: OnceCell::with_value((Some("k1".to_owned()), ciphers.clone())),
+        }));
+        let k2: &'static DefaultEncryptionProvider = Box::leak(Box::new(DefaultEncryptionProvider {
+            ciphers: OnceCell::with_value((Some("k2".to_owned()), ciphers)),
+        }));
+
+        let sealed = seal(&k1, b"payload").unwrap();
+        assert_ne!(sealed.as_slice(), b"payload");
+        //rotated, the payloads sealed with the previous key are still opened
+        assert_eq!(open(&k2, &sealed).unwrap(), b"payload");
+        assert_ne!(seal(&k2, b"payload").unwrap()[5..7], sealed[5..7]);
+        //stored before encryption
+        assert_eq!(open(&k2, b"plain").unwrap(), b"plain");
+    }
+}
@@ -0,0 +1,160 @@
+//! Fan-out of the publishes to many subscribers. A publish matching at least `threshold` local
+//! subscribers, a hot topic, has its subscribers sharded by client id into `shards` tasks of the
+//! broker runtime, delivered in parallel. The other publishes, the cold topics, are delivered inline.
+//! Each shard yields every `yield_batch` deliveries, so the hot topics do not hold the runtime threads
+//! from the cold ones.
+
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+use once_cell::sync::OnceCell;
+
+use crate::broker::default::DefaultShared;
+use crate::broker::types::{From, Publish, Reason, SubRelations, To};
+use crate::Runtime;
+
+pub type Errors = Vec<(To, From, Publish, Reason)>;
+
+#[derive(Default)]
+struct ShardMetrics {
+    count: AtomicU64,
+    //micros
+    last: AtomicU64,
+    max: AtomicU64,
+    total: AtomicU64,
+}
+
+impl ShardMetrics {
+    #[inline]
+    fn record(&self, elapsed: u64) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.last.store(elapsed, Ordering::Relaxed);
+        self.max.fetch_max(elapsed, Ordering::Relaxed);
+        self.total.fetch_add(elapsed, Ordering::Relaxed);
+    }
+
+    #[inline]
+    fn to_json(&self) -> serde_json::Value {
+        let count = self.count.load(Ordering::Relaxed);
+        let total = self.total.load(Ordering::Relaxed);
+        json!({
+            "count": count,
+            "last_us": self.last.load(Ordering::Relaxed),
+            "max_us": self.max.load(Ordering::Relaxed),
+            "avg_us": if count > 0 { total / count } else { 0 },
+        })
+    }
+}
+
+pub struct FanOut {
+    enable: bool,
+    shards: Vec<ShardMetrics>,
+}
+
+impl FanOut {
+    #[inline]
+    pub fn instance() -> &'static Self {
+        static INSTANCE: OnceCell<FanOut> = OnceCell::new();
+        INSTANCE.get_or_init(|| {
+            let cfg = &Runtime::instance().settings.node.fanout;
+            Self {
+                enable: cfg.enable && cfg.shards > 1,
+                shards: (0..cfg.shards).map(|_| ShardMetrics::default()).collect(),
+            }
+        })
+    }
+
+    ///Whether a publish to so many subscribers is delivered by shards
+    #[inline]
+    pub fn is_hot(&self, subscribers: usize) -> bool {
+        self.enable && subscribers >= Runtime::instance().settings.node.fanout.threshold
+    }
+
+    ///Delivers the publish of a hot topic, the shards in parallel
+    pub(crate) async fn forwards(
+        &self,
+        shared: &'static DefaultShared,
+        from: &From,
+        publish: &Publish,
+        relations: SubRelations,
+    ) -> Errors {
+        if !self.enable {
+            return shared._forwards_to(from, publish, relations, 0).await;
+        }
+        let stats = &Runtime::instance().stats;
+        stats.fanout_hot.inc();
+
+        let yield_batch = Runtime::instance().settings.node.fanout.yield_batch;
+        let handles = shard(relations, self.shards.len())
+            .into_iter()
+            .enumerate()
+            .filter(|(_, relations)| !relations.is_empty())
+            .map(|(idx, relations)| {
+                let from = from.clone();
+                let publish = publish.clone();
+                tokio::spawn(async move {
+                    let now = Instant::now();
+                    let errs = shared._forwards_to(&from, &publish, relations, yield_batch).await;
+                    FanOut::instance().shards[idx].record(now.elapsed().as_micros() as u64);
+                    errs
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut errs = Vec::new();
+        for res in futures::future::join_all(handles).await {
+            match res {
+                Ok(shard_errs) => errs.extend(shard_errs),
+                Err(e) => log::error!("fan-out, shard error, {:?}", e),
+            }
+        }
+        stats.fanout_hot.dec();
+        errs
+    }
+
+    ///Delivery latencies of the shards
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "enable": self.enable,
+            "shards": self.shards.iter().map(|s| s.to_json()).collect::<Vec<_>>(),
+        })
+    }
+}
+
+///Splits the subscribers by client id, a client always falls into the same shard
+#[inline]
+fn shard(relations: SubRelations, n: usize) -> Vec<SubRelations> {
+    let n = n.max(1);
+    let mut shards: Vec<SubRelations> = (0..n).map(|_| Vec::with_capacity(relations.len() / n + 1)).collect();
+    for relation in relations {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        relation.1.hash(&mut hasher);
+        shards[(hasher.finish() % n as u64) as usize].push(relation);
+    }
+    shards
+}
+
+#[cfg(test)]
+mod tests {
+    use super::shard;
+    use crate::broker::types::{SubRelations, SubscriptionOptions};
+
+    #[test]
+    fn test_shard() {
+        let relations = (0..100)
+            .map(|i| ("t/#".into(), format!("c{}", i).into(), SubscriptionOptions::default(), None, None))
+            .collect::<SubRelations>();
+        let shards = shard(relations.clone(), 4);
+        assert_eq!(shards.len(), 4);
+        assert_eq!(shards.iter().map(|s| s.len()).sum::<usize>(), 100);
+        //stable across publishes
+        let again = shard(relations, 4);
+        for (a, b) in shards.iter().zip(again.iter()) {
+            assert_eq!(
+                a.iter().map(|r| r.1.clone()).collect::<Vec<_>>(),
+                b.iter().map(|r| r.1.clone()).collect::<Vec<_>>()
+            );
+        }
+    }
+}
@@ -0,0 +1,314 @@
+//! The clients of the protocol gateways, CoAP, MQTT-SN..., are connected as broker sessions.
+//!
+//! A gateway client is registered with the shared session entries like a MQTT client, it is
+//! listed by the clients API, kicked, routed and kept offline by the session storage. The
+//! messages delivered to the session are written to the client by its `GatewaySink`.
+//!
+//! The session state is not `Send`, the gateways serve their clients on the ntex runtime.
+
+use std::fmt;
+use std::num::NonZeroU16;
+use std::sync::Arc;
+
+use ntex_mqtt::v5::codec::RetainHandling;
+
+use crate::broker::anonymous;
+use crate::broker::inflight::MomentStatus;
+use crate::broker::labels::Labels;
+use crate::broker::maintenance::Maintenance;
+use crate::broker::quota::Quota;
+use crate::broker::types::*;
+use crate::settings::listener::Listener;
+use crate::{MqttError, Result, Runtime, Session, SessionState};
+
+///Writes the messages delivered to a gateway session to its client
+pub trait GatewaySink: fmt::Debug {
+    ///The packet id is set for the QoS 1 and 2 messages, they are acknowledged by
+    ///`GatewaySession::acked`, `received` and `completed`
+    fn publish(&self, p: &Publish) -> Result<()>;
+
+    ///Resends the release of a QoS 2 message, the client answers with a complete
+    fn release(&self, packet_id: NonZeroU16) -> Result<()>;
+
+    ///The session is closed, kicked or timed out
+    fn close(&self);
+}
+
+///The connect of a gateway client, translated from its protocol
+#[derive(Debug, Clone)]
+pub struct GatewayConnect {
+    pub id: Id,
+    pub clean_start: bool,
+    ///The session is kept offline for the interval after the disconnect, 0 if clean
+    pub session_expiry_interval: u32,
+    pub keep_alive: u16,
+    pub password: Option<Password>,
+}
+
+impl GatewayConnect {
+    #[inline]
+    fn connect_info(&self) -> ConnectInfo {
+        ConnectInfo::V5(
+            self.id.clone(),
+            Box::new(ConnectV5 {
+                clean_start: self.clean_start,
+                keep_alive: self.keep_alive,
+                session_expiry_interval_secs: if self.session_expiry_interval > 0 {
+                    Some(self.session_expiry_interval)
+                } else {
+                    None
+                },
+                client_id: self.id.client_id.clone(),
+                username: self.id.username.clone(),
+                password: self.password.clone(),
+                ..Default::default()
+            }),
+        )
+    }
+}
+
+///Connects a gateway client as a broker session, the client is authenticated by the
+///client_authenticate hook. Returns the session and the session present.
+pub async fn connect(
+    listen_cfg: Listener,
+    connect: GatewayConnect,
+    sink: Arc<dyn GatewaySink>,
+) -> Result<(GatewaySession, bool)> {
+    let id = connect.id.clone();
+    let connect_info = Arc::new(connect.connect_info());
+    //hook, client connect
+    let _user_props = Runtime::instance().extends.hook_mgr().await.client_connect(&connect_info).await;
+
+    if Runtime::instance().node.is_draining() {
+        return Err(MqttError::from("node is draining"));
+    }
+    if let Some(reason) = Maintenance::instance().refuse_connect() {
+        return Err(MqttError::from(reason));
+    }
+
+    //hook, client authenticate
+    let (ack, superuser, auth_info) = Runtime::instance()
+        .extends
+        .hook_mgr()
+        .await
+        .client_authenticate(&connect_info, listen_cfg.allow_anonymous)
+        .await;
+    if !ack.success() {
+        return Err(MqttError::from(format!("Authentication failed, {:?}", ack)));
+    }
+    let auth_info = auth_info.map(|auth_info| anonymous::limits(&listen_cfg, auth_info));
+
+    //hook, client_labels, with the labels of the auth plugins
+    let labels = Labels::of_connect(&connect_info, auth_info.as_ref()).await;
+    Quota::instance().check(&id, &labels).await?;
+
+    let mut entry = { Runtime::instance().extends.shared().await.entry(id.clone()) }.try_lock().await?;
+
+    // Kick out the current session, if it exists
+    let (session_present, offline_info) =
+        match entry.kick(connect.clean_start, connect.clean_start, false).await? {
+            Some(offline_info) => (!connect.clean_start, Some(offline_info)),
+            None if !connect.clean_start => {
+                //hook, session_store_load, the session may be kept by an external store
+                match Runtime::instance().extends.hook_mgr().await.session_store_load(&id).await {
+                    Some(snapshot) => (true, Some(snapshot.into_offline_info())),
+                    None => (false, None),
+                }
+            }
+            None => (false, None),
+        };
+
+    let connected_at = chrono::Local::now().timestamp_millis();
+    let fitter = Runtime::instance().extends.fitter_mgr().await.create(
+        connect_info.clone(),
+        id.clone(),
+        listen_cfg.clone(),
+    );
+    let created_at =
+        if let Some(ref offline_info) = offline_info { offline_info.created_at } else { connected_at };
+
+    let max_inflight = fitter.max_inflight();
+    let max_mqueue_len = fitter.max_mqueue_len();
+    let session = Session::new(
+        id,
+        max_mqueue_len,
+        listen_cfg,
+        fitter,
+        max_inflight,
+        created_at,
+        connect_info.clone(),
+        session_present,
+        superuser,
+        true,
+        connected_at,
+        SessionSubs::new(),
+        None,
+        offline_info.as_ref().map(|o| o.id.clone()),
+    )
+    .await?;
+
+    if let Some(auth_info) = auth_info {
+        session.set_auth_info(auth_info).await;
+    }
+    session.set_labels(labels);
+
+    let hook = Runtime::instance().extends.hook_mgr().await.hook(&session);
+    let mut keep_alive = connect.keep_alive;
+    let keep_alive = session.fitter.keep_alive(&mut keep_alive)?;
+
+    if offline_info.is_none() {
+        //hook, session created
+        hook.session_created().await;
+    }
+
+    let (state, tx) = SessionState::new(session, Sink::Gateway(sink), hook, 0, 0).start(keep_alive).await;
+    entry.set(state.session.clone(), tx).await?;
+
+    //hook, client connack
+    let _ = Runtime::instance()
+        .extends
+        .hook_mgr()
+        .await
+        .client_connack(connect_info.as_ref(), ConnectAckReason::V5(ConnectAckReasonV5::Success))
+        .await;
+
+    //hook, client connected
+    state.hook.client_connected().await;
+
+    //transfer session state, the offline messages are delivered before the connect returns
+    if let Some(o) = offline_info {
+        if let Err(e) = state.transfer_session_state(connect.clean_start, o).await {
+            log::warn!("{:?} Failed to transfer session state, {}", state.id, e);
+        }
+    }
+
+    Ok((GatewaySession { state }, session_present))
+}
+
+///The broker session of a gateway client
+#[derive(Clone)]
+pub struct GatewaySession {
+    state: SessionState,
+}
+
+impl fmt::Debug for GatewaySession {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "GatewaySession {{ {:?} }}", self.state)
+    }
+}
+
+impl GatewaySession {
+    #[inline]
+    pub fn id(&self) -> &Id {
+        &self.state.id
+    }
+
+    #[inline]
+    pub fn listen_cfg(&self) -> &Listener {
+        self.state.listen_cfg()
+    }
+
+    ///Resets the keep alive of the session, on any packet of the client
+    #[inline]
+    pub fn keepalive(&self, ping: bool) -> Result<()> {
+        self.state.send(Message::Keepalive(ping))
+    }
+
+    #[inline]
+    pub async fn publish(&self, publish: Publish) -> Result<bool> {
+        self.state.publish(publish).await
+    }
+
+    ///The retained messages of the topic filter are delivered if `send_retained`
+    #[inline]
+    pub async fn subscribe(
+        &self,
+        topic_filter: &TopicFilter,
+        qos: QoS,
+        send_retained: bool,
+    ) -> Result<SubscribeReturn> {
+        let shared_subscription_supported =
+            Runtime::instance().extends.shared_subscription().await.is_supported(self.listen_cfg());
+        let opts = SubscriptionOptionsV5 {
+            qos,
+            no_local: false,
+            retain_as_published: false,
+            retain_handling: if send_retained {
+                RetainHandling::AtSubscribe
+            } else {
+                RetainHandling::NoAtSubscribe
+            },
+        };
+        let sub = Subscribe::from_v5(topic_filter, &opts, shared_subscription_supported, None)?;
+        self.state.subscribe(sub).await
+    }
+
+    #[inline]
+    pub async fn unsubscribe(&self, topic_filter: &TopicFilter) -> Result<()> {
+        let shared_subscription_supported =
+            Runtime::instance().extends.shared_subscription().await.is_supported(self.listen_cfg());
+        let unsub = Unsubscribe::from(topic_filter, shared_subscription_supported)?;
+        self.state.unsubscribe(unsub).await
+    }
+
+    ///The QoS 1 message is acknowledged
+    pub async fn acked(&self, packet_id: NonZeroU16) {
+        let mut inflight_win = self.state.inflight_win().write().await;
+        inflight_win.acked(&packet_id.get(), self.state.deliver_queue().len());
+        let iflt_msg = inflight_win.remove(&packet_id.get());
+        drop(inflight_win);
+        self.state.acked();
+        if let Some(iflt_msg) = iflt_msg {
+            //hook, message_ack
+            self.state.hook.message_acked(iflt_msg.from, &iflt_msg.publish).await;
+        }
+    }
+
+    ///The QoS 2 message is received, the release is sent by the sink
+    pub async fn received(&self, packet_id: NonZeroU16) -> Result<()> {
+        self.state.acked();
+        let mut inflight_win = self.state.inflight_win().write().await;
+        inflight_win.acked(&packet_id.get(), self.state.deliver_queue().len());
+        inflight_win.update_status(&packet_id.get(), MomentStatus::UnComplete);
+        drop(inflight_win);
+        if let Some(sink) = self.state.sink.as_ref() {
+            sink.send(Packet::V3(PacketV3::PublishRelease { packet_id }))?;
+        }
+        Ok(())
+    }
+
+    ///The QoS 2 message is completed
+    pub async fn completed(&self, packet_id: NonZeroU16) {
+        let iflt_msg = self.state.inflight_win().write().await.remove(&packet_id.get());
+        self.state.acked();
+        if let Some(iflt_msg) = iflt_msg {
+            //hook, message_ack
+            self.state.hook.message_acked(iflt_msg.from, &iflt_msg.publish).await;
+        }
+    }
+
+    ///The messages queued or not yet acknowledged
+    #[inline]
+    pub async fn pending(&self) -> usize {
+        self.state.deliver_queue().len() + self.state.inflight_win().read().await.len()
+    }
+
+    ///Disconnects the client, the session is kept offline for the session expiry interval,
+    ///the session of the connect if None
+    pub fn disconnect(&self, session_expiry_interval: Option<u32>) -> Result<()> {
+        self.state.send(Message::Disconnect(Disconnect::V5(DisconnectV5 {
+            reason_code: DisconnectReasonCode::NormalDisconnection,
+            session_expiry_interval_secs: session_expiry_interval,
+            server_reference: None,
+            reason_string: None,
+            user_properties: Vec::new(),
+        })))?;
+        self.state.send(Message::Closed(Reason::ConnectDisconnect(None)))
+    }
+
+    ///The client is gone without disconnect, the last will is published
+    #[inline]
+    pub fn closed(&self) -> Result<()> {
+        self.state.send(Message::Closed(Reason::ConnectRemoteClose))
+    }
+}
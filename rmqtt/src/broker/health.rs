@@ -0,0 +1,264 @@
+//! Liveness and readiness probes, GET /healthz and GET /readyz over HTTP, for Kubernetes. The node
+//! is alive as long as it replies. It is ready when the startup is completed, the listeners are bound,
+//! the plugins are healthy, the cluster is available, e.g. the raft cluster has a leader, and the
+//! checks registered by the plugins pass, e.g. the storage plugins ping their backends. A node in
+//! maintenance is not ready. Each check is detailed in the body, the status is 503 if one fails.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use once_cell::sync::OnceCell;
+use rust_box::std_ext::RwLock;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::broker::maintenance::Maintenance;
+use crate::broker::types::HashMap;
+use crate::plugin::PluginHealth;
+use crate::settings::listener::ListenerType;
+use crate::{MqttError, Result, Runtime};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[async_trait]
+pub trait ReadinessCheck: Sync + Send {
+    ///The detail of the check, an error if it fails
+    async fn check(&self) -> Result<serde_json::Value>;
+}
+
+pub struct HealthProbe {
+    started: AtomicBool,
+    //bind errors of the listeners, by type and port
+    listeners: RwLock<HashMap<(ListenerType, u16), Option<String>>>,
+    checks: RwLock<HashMap<String, Arc<dyn ReadinessCheck>>>,
+}
+
+impl HealthProbe {
+    #[inline]
+    pub fn instance() -> &'static Self {
+        static INSTANCE: OnceCell<HealthProbe> = OnceCell::new();
+        INSTANCE.get_or_init(|| Self {
+            started: AtomicBool::new(false),
+            listeners: RwLock::new(HashMap::default()),
+            checks: RwLock::new(HashMap::default()),
+        })
+    }
+
+    ///The startup is completed, the node may become ready
+    #[inline]
+    pub fn set_started(&self) {
+        self.started.store(true, Ordering::SeqCst);
+    }
+
+    ///Records whether the listener is bound, the error if not
+    #[inline]
+    pub fn listener_bound(&self, typ: ListenerType, port: u16, err: Option<String>) {
+        self.listeners.write().insert((typ, port), err);
+    }
+
+    #[inline]
+    pub fn listener_stopped(&self, typ: ListenerType, port: u16) {
+        self.listeners.write().remove(&(typ, port));
+    }
+
+    ///Registers a readiness check, it replaces the check of the same name
+    #[inline]
+    pub fn register<N: Into<String>>(&self, name: N, check: Arc<dyn ReadinessCheck>) {
+        self.checks.write().insert(name.into(), check);
+    }
+
+    #[inline]
+    pub fn unregister(&self, name: &str) {
+        self.checks.write().remove(name);
+    }
+
+    ///The checks registered by the plugins
+    #[inline]
+    pub(crate) fn registered(&self) -> Vec<(String, Arc<dyn ReadinessCheck>)> {
+        self.checks.read().iter().map(|(name, check)| (name.clone(), check.clone())).collect()
+    }
+
+    #[inline]
+    pub fn liveness(&self) -> serde_json::Value {
+        json!({"status": "UP", "node_id": Runtime::instance().node.id()})
+    }
+
+    ///Whether the node is ready, and the detail of each check
+    pub async fn readiness(&self) -> (bool, serde_json::Value) {
+        let mut checks = vec![
+            ("startup", self.check_startup()),
+            ("listeners", self.check_listeners()),
+            ("plugins", Self::check_plugins()),
+            ("cluster", Self::timeout(Self::check_cluster()).await),
+            ("maintenance", Maintenance::instance().check()),
+        ];
+        let registered = self.registered();
+        let registered = futures::future::join_all(
+            registered.iter().map(|(name, check)| async move { (name, Self::timeout(check.check()).await) }),
+        )
+        .await;
+        checks.extend(registered.into_iter().map(|(name, res)| (name.as_str(), res)));
+
+        let ready = checks.iter().all(|(_, res)| res.is_ok());
+        let checks = checks
+            .into_iter()
+            .map(|(name, res)| match res {
+                Ok(detail) => json!({"name": name, "status": "UP", "detail": detail}),
+                Err(e) => json!({"name": name, "status": "DOWN", "error": e.to_string()}),
+            })
+            .collect::<Vec<_>>();
+        (ready, json!({"status": if ready { "UP" } else { "DOWN" }, "checks": checks}))
+    }
+
+    #[inline]
+    pub(crate) async fn timeout<F>(f: F) -> Result<serde_json::Value>
+    where
+        F: std::future::Future<Output = Result<serde_json::Value>>,
+    {
+        tokio::time::timeout(Runtime::instance().settings.health.check_timeout, f)
+            .await
+            .map_err(|_| MqttError::from("check timeout"))?
+    }
+
+    #[inline]
+    fn check_startup(&self) -> Result<serde_json::Value> {
+        if self.started.load(Ordering::SeqCst) {
+            Ok(serde_json::Value::Null)
+        } else {
+            Err(MqttError::from("the startup is not completed"))
+        }
+    }
+
+    fn check_listeners(&self) -> Result<serde_json::Value> {
+        let listeners = self.listeners.read();
+        let mut bound = Vec::new();
+        for (typ, l) in Runtime::instance().settings.listeners.actives() {
+            let port = l.addr.port();
+            match listeners.get(&(typ, port)) {
+                Some(None) => bound.push(format!("{}/{}", typ.as_str(), port)),
+                Some(Some(e)) => {
+                    return Err(MqttError::from(format!(
+                        "{} listener {} bind error, {}",
+                        typ.as_str(),
+                        port,
+                        e
+                    )))
+                }
+                None => {
+                    return Err(MqttError::from(format!("{} listener {} is not bound", typ.as_str(), port)))
+                }
+            }
+        }
+        Ok(json!(bound))
+    }
+
+    fn check_plugins() -> Result<serde_json::Value> {
+        let default_startups = &Runtime::instance().settings.plugins.default_startups;
+        let mut degraded = Vec::new();
+        for entry in Runtime::instance().plugins.iter() {
+            let name = entry.key();
+            if default_startups.contains(name) && !entry.active() {
+                return Err(MqttError::from(format!("plugin {} is not started", name)));
+            }
+            match entry.health() {
+                PluginHealth::Failed => return Err(MqttError::from(format!("plugin {} is failed", name))),
+                PluginHealth::Degraded => degraded.push(name.clone()),
+                PluginHealth::Healthy => {}
+            }
+        }
+        Ok(json!({ "degraded": degraded }))
+    }
+
+    pub(crate) async fn check_cluster() -> Result<serde_json::Value> {
+        let health = Runtime::instance().extends.shared().await.check_health().await?;
+        let status = health.as_ref().and_then(|h| h.get("status")).and_then(|s| s.as_str()).map(String::from);
+        match status.as_deref() {
+            Some("Ok") | None => Ok(health.unwrap_or_default()),
+            Some(status) => Err(MqttError::from(status)),
+        }
+    }
+
+    ///Serves the probes on `health.listen`, if enabled
+    pub fn start(&'static self) {
+        let cfg = &Runtime::instance().settings.health;
+        if !cfg.enable {
+            return;
+        }
+        ntex::rt::spawn(async move {
+            let listener = match TcpListener::bind(cfg.listen).await {
+                Ok(listener) => listener,
+                Err(e) => {
+                    log::error!("health probes, listen on {:?} error, {:?}", cfg.listen, e);
+                    return;
+                }
+            };
+            log::info!("health probes, listening on {:?}", cfg.listen);
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        ntex::rt::spawn(async move {
+                            if let Err(e) = tokio::time::timeout(REQUEST_TIMEOUT, self.serve(stream)).await {
+                                log::debug!("health probes, request timeout, {:?}", e);
+                            }
+                        });
+                    }
+                    Err(e) => log::warn!("health probes, accept error, {:?}", e),
+                }
+            }
+        });
+    }
+
+    async fn serve(&self, mut stream: TcpStream) {
+        let mut buf = [0u8; 1024];
+        let n = match stream.read(&mut buf).await {
+            Ok(n) => n,
+            Err(e) => {
+                log::debug!("health probes, read error, {:?}", e);
+                return;
+            }
+        };
+        let (status, body) = match path(&buf[..n]) {
+            Some("/healthz") => ("200 OK", self.liveness()),
+            Some("/readyz") => match self.readiness().await {
+                (true, body) => ("200 OK", body),
+                (false, body) => ("503 Service Unavailable", body),
+            },
+            _ => ("404 Not Found", json!({"error": "not found"})),
+        };
+        let body = body.to_string();
+        let resp = format!(
+            "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        );
+        if let Err(e) = stream.write_all(resp.as_bytes()).await {
+            log::debug!("health probes, write error, {:?}", e);
+        }
+    }
+}
+
+///Path of a GET request, without the query
+#[inline]
+fn path(req: &[u8]) -> Option<&str> {
+    let line = std::str::from_utf8(req).ok()?.lines().next()?;
+    let mut parts = line.split_whitespace();
+    if parts.next()? != "GET" {
+        return None;
+    }
+    parts.next()?.split('?').next()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::path;
+
+    #[test]
+    fn test_path() {
+        assert_eq!(path(b"GET /readyz HTTP/1.1\r\nHost: localhost\r\n\r\n"), Some("/readyz"));
+        assert_eq!(path(b"GET /healthz?verbose HTTP/1.1\r\n\r\n"), Some("/healthz"));
+        assert_eq!(path(b"POST /readyz HTTP/1.1\r\n\r\n"), None);
+        assert_eq!(path(b"\xff\xfe"), None);
+    }
+}
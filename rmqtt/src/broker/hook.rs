@@ -1,10 +1,15 @@
+use std::time::Duration;
+
+use crate::broker::audit::AuditRecord;
 use crate::broker::inflight::InflightMessage;
+use crate::broker::session::SessionSnapshot;
 use crate::broker::types::*;
-use crate::{grpc, Result, Session};
+use crate::settings::listener::Listener;
+use crate::{grpc, Result, Runtime, Session};
 
 pub type Priority = u32;
 pub type Proceed = bool;
-pub type ReturnType = (Proceed, Option<HookResult>);
+pub type ReturnType = Proceed;
 
 #[async_trait]
 pub trait HookManager: Sync + Send {
@@ -15,6 +20,9 @@ pub trait HookManager: Sync + Send {
     ///Before the server startup
     async fn before_startup(&self);
 
+    ///Before the server shutdown
+    async fn before_shutdown(&self);
+
     ///When a connect message is received
     async fn client_connect(&self, connect_info: &ConnectInfo) -> Option<UserProperties>;
 
@@ -23,7 +31,14 @@ pub trait HookManager: Sync + Send {
         &self,
         connect_info: &ConnectInfo,
         allow_anonymous: bool,
-    ) -> (ConnectAckReason, Superuser);
+    ) -> (ConnectAckReason, Superuser, Option<AuthInfo>);
+
+    ///Whether the client is exempted from the maximum packet size of the listener, see
+    ///`max_packet_size_exempt`
+    async fn client_packet_size_exempt(&self, connect_info: &ConnectInfo) -> bool;
+
+    ///Labels of the session of the client after its authentication, see `broker::labels`
+    async fn client_labels(&self, connect_info: &ConnectInfo) -> Vec<String>;
 
     ///When sending mqtt:: connectack message
     async fn client_connack(
@@ -32,8 +47,14 @@ pub trait HookManager: Sync + Send {
         return_code: ConnectAckReason,
     ) -> ConnectAckReason;
 
-    ///Publish message received
-    async fn message_publish(&self, s: Option<&Session>, from: From, publish: &Publish) -> Option<Publish>;
+    ///Publish message received, the message modified by the handlers if any, or dropped, the
+    ///MessageDropped hook is already executed then
+    async fn message_publish(
+        &self,
+        s: Option<&Session>,
+        from: From,
+        publish: &Publish,
+    ) -> std::result::Result<Option<Publish>, PublishDrop>;
 
     ///Publish message Dropped
     async fn message_dropped(&self, to: Option<To>, from: From, p: Publish, reason: Reason);
@@ -41,6 +62,16 @@ pub trait HookManager: Sync + Send {
     ///Publish message nonsubscribed
     async fn message_nonsubscribed(&self, from: From);
 
+    ///Cluster degraded or restored, the reason of the transition
+    async fn cluster_degraded(&self, degraded: bool, reason: String);
+
+    ///Audit record, for the plugins forwarding the audit stream
+    async fn audit_record(&self, record: &AuditRecord);
+
+    ///The session of a client connecting without clean start is not found, it is loaded from an
+    ///external store
+    async fn session_store_load(&self, id: &Id) -> Option<SessionSnapshot>;
+
     ///grpc message received
     async fn grpc_message_received(
         &self,
@@ -57,6 +88,19 @@ pub trait Register: Sync + Send {
 
     async fn add_priority(&self, typ: Type, priority: Priority, handler: Box<dyn Handler>);
 
+    ///The handler is invoked for the clients of the listeners, by name, all listeners if empty. It is
+    ///skipped for the clients of an unknown listener, the events not tied to a client invoke it regardless
+    async fn add_scoped(
+        &self,
+        typ: Type,
+        priority: Priority,
+        listeners: Vec<String>,
+        handler: Box<dyn Handler>,
+    ) {
+        let _ = listeners;
+        self.add_priority(typ, priority, handler).await;
+    }
+
     async fn start(&self) {}
 
     async fn stop(&self) {}
@@ -64,7 +108,9 @@ pub trait Register: Sync + Send {
 
 #[async_trait]
 pub trait Handler: Sync + Send {
-    async fn hook(&self, param: &Parameter, acc: Option<HookResult>) -> ReturnType;
+    ///`acc` is the result of the previous handlers, a handler replaces or updates it in place, the
+    ///following handlers are not executed if false is returned
+    async fn hook(&self, param: &Parameter, acc: &mut Option<HookResult>) -> ReturnType;
 }
 
 #[async_trait]
@@ -81,6 +127,12 @@ pub trait Hook: Sync + Send {
     ///Session terminated
     async fn session_terminated(&self, r: Reason);
 
+    ///Persistent session changed, subscribed, unsubscribed or gone offline, for the external stores
+    async fn session_store_save(&self);
+
+    ///Session terminated, for the external stores
+    async fn session_store_remove(&self);
+
     ///subscribe check acl
     async fn client_subscribe_check_acl(&self, subscribe: &Subscribe) -> Option<SubscribeAclResult>;
 
@@ -90,6 +142,10 @@ pub trait Hook: Sync + Send {
     ///Subscribe message received
     async fn client_subscribe(&self, subscribe: &Subscribe) -> Option<TopicFilter>;
 
+    ///Subscription succeeded, the messages to deliver to the client for it, a welcome or the current
+    ///config of a device for instance
+    async fn session_subscribed_inject(&self, subscribe: &Subscribe) -> Vec<Publish>;
+
     ///Subscription succeeded
     async fn session_subscribed(&self, subscribe: Subscribe);
 
@@ -99,12 +155,28 @@ pub trait Hook: Sync + Send {
     ///Unsubscribe succeeded
     async fn session_unsubscribed(&self, unsubscribe: Unsubscribe);
 
-    ///Publish message received
-    async fn message_publish(&self, from: From, p: &Publish) -> Option<Publish>;
+    ///MQTT 5, properties of the CONNACK
+    async fn client_connack_props(&self) -> Option<AckProperties>;
+
+    ///MQTT 5, properties of the SUBACK
+    async fn client_suback_props(&self, subs: &[(Subscribe, SubscribeReturn)]) -> Option<AckProperties>;
+
+    ///MQTT 5, properties of the UNSUBACK
+    async fn client_unsuback_props(&self, unsubs: &[Unsubscribe]) -> Option<AckProperties>;
+
+    ///Publish message received, see HookManager::message_publish
+    async fn message_publish(
+        &self,
+        from: From,
+        p: &Publish,
+    ) -> std::result::Result<Option<Publish>, PublishDrop>;
 
     ///Message delivered
     async fn message_delivered(&self, from: From, publish: &Publish) -> Option<Publish>;
 
+    ///Message written to the connection, with its publish to deliver latency
+    async fn message_delivered_latency(&self, from: From, publish: &Publish, latency: Duration);
+
     ///Message acked
     async fn message_acked(&self, from: From, publish: &Publish);
 
@@ -116,15 +188,23 @@ pub trait Hook: Sync + Send {
 
     ///Message expiry check
     async fn message_expiry_check(&self, from: From, publish: &Publish) -> MessageExpiryCheckResult;
+
+    ///Last will message is about to be published, None if it is suppressed
+    async fn will_message_publish(&self, from: From, publish: Publish) -> Option<Publish>;
+
+    ///Last will message is not published
+    async fn will_message_dropped(&self, from: From, publish: Publish, reason: Reason);
 }
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Deserialize, Serialize)]
 pub enum Type {
     BeforeStartup,
+    BeforeShutdown,
 
     SessionCreated,
     SessionTerminated,
     SessionSubscribed,
+    SessionSubscribedInject,
     SessionUnsubscribed,
 
     ClientAuthenticate,
@@ -135,29 +215,48 @@ pub enum Type {
     ClientSubscribe,
     ClientUnsubscribe,
     ClientSubscribeCheckAcl,
+    ClientConnackProps,
+    ClientSubackProps,
+    ClientUnsubackProps,
+    ClientPacketSizeExempt,
+    ClientLabels,
 
     MessagePublishCheckAcl,
     MessagePublish,
     MessageDelivered,
+    MessageDeliveredLatency,
     MessageAcked,
     MessageDropped,
     MessageExpiryCheck,
     MessageNonsubscribed,
 
+    WillMessagePublish,
+    WillMessageDropped,
+
     OfflineMessage,
     OfflineInflightMessages,
 
     GrpcMessageReceived,
+
+    ClusterDegraded,
+
+    AuditRecord,
+
+    SessionStoreSave,
+    SessionStoreRemove,
+    SessionStoreLoad,
 }
 
 impl std::convert::From<&str> for Type {
     fn from(t: &str) -> Type {
         match t {
             "before_startup" => Type::BeforeStartup,
+            "before_shutdown" => Type::BeforeShutdown,
 
             "session_created" => Type::SessionCreated,
             "session_terminated" => Type::SessionTerminated,
             "session_subscribed" => Type::SessionSubscribed,
+            "session_subscribed_inject" => Type::SessionSubscribedInject,
             "session_unsubscribed" => Type::SessionUnsubscribed,
 
             "client_authenticate" => Type::ClientAuthenticate,
@@ -168,32 +267,106 @@ impl std::convert::From<&str> for Type {
             "client_subscribe" => Type::ClientSubscribe,
             "client_unsubscribe" => Type::ClientUnsubscribe,
             "client_subscribe_check_acl" => Type::ClientSubscribeCheckAcl,
+            "client_connack_props" => Type::ClientConnackProps,
+            "client_suback_props" => Type::ClientSubackProps,
+            "client_unsuback_props" => Type::ClientUnsubackProps,
+            "client_packet_size_exempt" => Type::ClientPacketSizeExempt,
+            "client_labels" => Type::ClientLabels,
 
             "message_publish_check_acl" => Type::MessagePublishCheckAcl,
             "message_publish" => Type::MessagePublish,
             "message_delivered" => Type::MessageDelivered,
+            "message_delivered_latency" => Type::MessageDeliveredLatency,
             "message_acked" => Type::MessageAcked,
             "message_dropped" => Type::MessageDropped,
             "message_expiry_check" => Type::MessageExpiryCheck,
             "message_nonsubscribed" => Type::MessageNonsubscribed,
 
+            "will_message_publish" => Type::WillMessagePublish,
+            "will_message_dropped" => Type::WillMessageDropped,
+
             "offline_message" => Type::OfflineMessage,
             "offline_inflight_messages" => Type::OfflineInflightMessages,
 
             "grpc_message_received" => Type::GrpcMessageReceived,
 
+            "cluster_degraded" => Type::ClusterDegraded,
+
+            "audit_record" => Type::AuditRecord,
+
+            "session_store_save" => Type::SessionStoreSave,
+            "session_store_remove" => Type::SessionStoreRemove,
+            "session_store_load" => Type::SessionStoreLoad,
+
             _ => unreachable!("{:?} is not defined", t),
         }
     }
 }
 
+impl Type {
+    ///The name of the type, as in the configs
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Type::BeforeStartup => "before_startup",
+            Type::BeforeShutdown => "before_shutdown",
+
+            Type::SessionCreated => "session_created",
+            Type::SessionTerminated => "session_terminated",
+            Type::SessionSubscribed => "session_subscribed",
+            Type::SessionSubscribedInject => "session_subscribed_inject",
+            Type::SessionUnsubscribed => "session_unsubscribed",
+
+            Type::ClientAuthenticate => "client_authenticate",
+            Type::ClientConnect => "client_connect",
+            Type::ClientConnack => "client_connack",
+            Type::ClientConnected => "client_connected",
+            Type::ClientDisconnected => "client_disconnected",
+            Type::ClientSubscribe => "client_subscribe",
+            Type::ClientUnsubscribe => "client_unsubscribe",
+            Type::ClientSubscribeCheckAcl => "client_subscribe_check_acl",
+            Type::ClientConnackProps => "client_connack_props",
+            Type::ClientSubackProps => "client_suback_props",
+            Type::ClientUnsubackProps => "client_unsuback_props",
+            Type::ClientPacketSizeExempt => "client_packet_size_exempt",
+            Type::ClientLabels => "client_labels",
+
+            Type::MessagePublishCheckAcl => "message_publish_check_acl",
+            Type::MessagePublish => "message_publish",
+            Type::MessageDelivered => "message_delivered",
+            Type::MessageDeliveredLatency => "message_delivered_latency",
+            Type::MessageAcked => "message_acked",
+            Type::MessageDropped => "message_dropped",
+            Type::MessageExpiryCheck => "message_expiry_check",
+            Type::MessageNonsubscribed => "message_nonsubscribed",
+
+            Type::WillMessagePublish => "will_message_publish",
+            Type::WillMessageDropped => "will_message_dropped",
+
+            Type::OfflineMessage => "offline_message",
+            Type::OfflineInflightMessages => "offline_inflight_messages",
+
+            Type::GrpcMessageReceived => "grpc_message_received",
+
+            Type::ClusterDegraded => "cluster_degraded",
+
+            Type::AuditRecord => "audit_record",
+
+            Type::SessionStoreSave => "session_store_save",
+            Type::SessionStoreRemove => "session_store_remove",
+            Type::SessionStoreLoad => "session_store_load",
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum Parameter<'a> {
     BeforeStartup,
+    BeforeShutdown,
 
     SessionCreated(&'a Session),
     SessionTerminated(&'a Session, Reason),
     SessionSubscribed(&'a Session, Subscribe),
+    SessionSubscribedInject(&'a Session, &'a Subscribe),
     SessionUnsubscribed(&'a Session, Unsubscribe),
 
     ClientConnect(&'a ConnectInfo),
@@ -204,29 +377,50 @@ pub enum Parameter<'a> {
     ClientSubscribe(&'a Session, &'a Subscribe),
     ClientUnsubscribe(&'a Session, &'a Unsubscribe),
     ClientSubscribeCheckAcl(&'a Session, &'a Subscribe),
+    ClientConnackProps(&'a Session),
+    ClientSubackProps(&'a Session, &'a [(Subscribe, SubscribeReturn)]),
+    ClientUnsubackProps(&'a Session, &'a [Unsubscribe]),
+    ClientPacketSizeExempt(&'a ConnectInfo),
+    ClientLabels(&'a ConnectInfo),
 
     MessagePublishCheckAcl(&'a Session, &'a Publish),
     MessagePublish(Option<&'a Session>, From, &'a Publish),
     MessageDelivered(&'a Session, From, &'a Publish),
+    ///The publish to deliver latency, see broker::latency
+    MessageDeliveredLatency(&'a Session, From, &'a Publish, Duration),
     MessageAcked(&'a Session, From, &'a Publish),
     MessageDropped(Option<To>, From, Publish, Reason),
     MessageExpiryCheck(&'a Session, From, &'a Publish),
     MessageNonsubscribed(From),
 
+    WillMessagePublish(&'a Session, From, &'a Publish),
+    WillMessageDropped(&'a Session, From, Publish, Reason),
+
     OfflineMessage(&'a Session, From, &'a Publish),
     OfflineInflightMessages(&'a Session, Vec<InflightMessage>),
 
     GrpcMessageReceived(grpc::MessageType, grpc::Message),
+
+    ///Degraded(true) or restored(false), reason
+    ClusterDegraded(bool, String),
+
+    AuditRecord(&'a AuditRecord),
+
+    SessionStoreSave(&'a Session, &'a SessionSnapshot),
+    SessionStoreRemove(&'a Session),
+    SessionStoreLoad(&'a Id),
 }
 
 impl<'a> Parameter<'a> {
     pub fn get_type(&self) -> Type {
         match self {
             Parameter::BeforeStartup => Type::BeforeStartup,
+            Parameter::BeforeShutdown => Type::BeforeShutdown,
 
             Parameter::SessionCreated(_) => Type::SessionCreated,
             Parameter::SessionTerminated(_, _) => Type::SessionTerminated,
             Parameter::SessionSubscribed(_, _) => Type::SessionSubscribed,
+            Parameter::SessionSubscribedInject(_, _) => Type::SessionSubscribedInject,
             Parameter::SessionUnsubscribed(_, _) => Type::SessionUnsubscribed,
 
             Parameter::ClientAuthenticate(_) => Type::ClientAuthenticate,
@@ -237,19 +431,119 @@ impl<'a> Parameter<'a> {
             Parameter::ClientSubscribe(_, _) => Type::ClientSubscribe,
             Parameter::ClientUnsubscribe(_, _) => Type::ClientUnsubscribe,
             Parameter::ClientSubscribeCheckAcl(_, _) => Type::ClientSubscribeCheckAcl,
+            Parameter::ClientConnackProps(_) => Type::ClientConnackProps,
+            Parameter::ClientSubackProps(_, _) => Type::ClientSubackProps,
+            Parameter::ClientUnsubackProps(_, _) => Type::ClientUnsubackProps,
+            Parameter::ClientPacketSizeExempt(_) => Type::ClientPacketSizeExempt,
+            Parameter::ClientLabels(_) => Type::ClientLabels,
 
             Parameter::MessagePublishCheckAcl(_, _) => Type::MessagePublishCheckAcl,
             Parameter::MessagePublish(_, _, _) => Type::MessagePublish,
             Parameter::MessageDelivered(_, _, _) => Type::MessageDelivered,
+            Parameter::MessageDeliveredLatency(_, _, _, _) => Type::MessageDeliveredLatency,
             Parameter::MessageAcked(_, _, _) => Type::MessageAcked,
             Parameter::MessageDropped(_, _, _, _) => Type::MessageDropped,
             Parameter::MessageExpiryCheck(_, _, _) => Type::MessageExpiryCheck,
             Parameter::MessageNonsubscribed(_) => Type::MessageNonsubscribed,
 
+            Parameter::WillMessagePublish(_, _, _) => Type::WillMessagePublish,
+            Parameter::WillMessageDropped(_, _, _, _) => Type::WillMessageDropped,
+
             Parameter::OfflineMessage(_, _, _) => Type::OfflineMessage,
             Parameter::OfflineInflightMessages(_, _) => Type::OfflineInflightMessages,
 
             Parameter::GrpcMessageReceived(_, _) => Type::GrpcMessageReceived,
+
+            Parameter::ClusterDegraded(_, _) => Type::ClusterDegraded,
+
+            Parameter::AuditRecord(_) => Type::AuditRecord,
+
+            Parameter::SessionStoreSave(_, _) => Type::SessionStoreSave,
+            Parameter::SessionStoreRemove(_) => Type::SessionStoreRemove,
+            Parameter::SessionStoreLoad(_) => Type::SessionStoreLoad,
+        }
+    }
+
+    ///The client of the event, none if it is not tied to a client
+    pub fn id(&self) -> Option<&Id> {
+        match self {
+            Parameter::SessionCreated(s)
+            | Parameter::SessionTerminated(s, _)
+            | Parameter::SessionSubscribed(s, _)
+            | Parameter::SessionSubscribedInject(s, _)
+            | Parameter::SessionUnsubscribed(s, _)
+            | Parameter::ClientConnected(s)
+            | Parameter::ClientDisconnected(s, _)
+            | Parameter::ClientSubscribe(s, _)
+            | Parameter::ClientUnsubscribe(s, _)
+            | Parameter::ClientSubscribeCheckAcl(s, _)
+            | Parameter::ClientConnackProps(s)
+            | Parameter::ClientSubackProps(s, _)
+            | Parameter::ClientUnsubackProps(s, _)
+            | Parameter::MessagePublishCheckAcl(s, _)
+            | Parameter::MessageDelivered(s, _, _)
+            | Parameter::MessageDeliveredLatency(s, _, _, _)
+            | Parameter::MessageAcked(s, _, _)
+            | Parameter::MessageExpiryCheck(s, _, _)
+            | Parameter::WillMessagePublish(s, _, _)
+            | Parameter::WillMessageDropped(s, _, _, _)
+            | Parameter::OfflineMessage(s, _, _)
+            | Parameter::OfflineInflightMessages(s, _)
+            | Parameter::SessionStoreSave(s, _)
+            | Parameter::SessionStoreRemove(s) => Some(&s.id),
+            Parameter::ClientConnect(c)
+            | Parameter::ClientConnack(c, _)
+            | Parameter::ClientAuthenticate(c)
+            | Parameter::ClientPacketSizeExempt(c)
+            | Parameter::ClientLabels(c) => Some(c.id()),
+            Parameter::MessagePublish(Some(s), _, _) => Some(&s.id),
+            Parameter::MessagePublish(None, from, _) | Parameter::MessageNonsubscribed(from) => {
+                Some(&from.id)
+            }
+            Parameter::MessageDropped(Some(to), _, _, _) => Some(to),
+            Parameter::MessageDropped(None, from, _, _) => Some(&from.id),
+            Parameter::SessionStoreLoad(id) => Some(*id),
+            Parameter::BeforeStartup
+            | Parameter::BeforeShutdown
+            | Parameter::GrpcMessageReceived(_, _)
+            | Parameter::ClusterDegraded(_, _)
+            | Parameter::AuditRecord(_) => None,
+        }
+    }
+
+    ///The listener the client of the event connected through, that of its session, resolved when the
+    ///session is created, or by the local address of the client for the events without a session
+    pub fn listener(&self) -> Option<Listener> {
+        match self {
+            Parameter::SessionCreated(s)
+            | Parameter::SessionTerminated(s, _)
+            | Parameter::SessionSubscribed(s, _)
+            | Parameter::SessionSubscribedInject(s, _)
+            | Parameter::SessionUnsubscribed(s, _)
+            | Parameter::ClientConnected(s)
+            | Parameter::ClientDisconnected(s, _)
+            | Parameter::ClientSubscribe(s, _)
+            | Parameter::ClientUnsubscribe(s, _)
+            | Parameter::ClientSubscribeCheckAcl(s, _)
+            | Parameter::ClientConnackProps(s)
+            | Parameter::ClientSubackProps(s, _)
+            | Parameter::ClientUnsubackProps(s, _)
+            | Parameter::MessagePublishCheckAcl(s, _)
+            | Parameter::MessagePublish(Some(s), _, _)
+            | Parameter::MessageDelivered(s, _, _)
+            | Parameter::MessageDeliveredLatency(s, _, _, _)
+            | Parameter::MessageAcked(s, _, _)
+            | Parameter::MessageExpiryCheck(s, _, _)
+            | Parameter::WillMessagePublish(s, _, _)
+            | Parameter::WillMessageDropped(s, _, _, _)
+            | Parameter::OfflineMessage(s, _, _)
+            | Parameter::OfflineInflightMessages(s, _)
+            | Parameter::SessionStoreSave(s, _)
+            | Parameter::SessionStoreRemove(s) => Some(s.listen_cfg().clone()),
+            _ => {
+                let port = self.id()?.local_addr?.port();
+                Runtime::instance().settings.listeners.get(port)
+            }
         }
     }
 }
@@ -268,10 +562,25 @@ pub enum HookResult {
     SubscribeAclResult(SubscribeAclResult),
     ///Publish AclResult, for MessagePublishCheckAcl
     PublishAclResult(PublishAclResult),
-    ///Publish, for MessagePublish/MessageDelivered
+    ///Publish, for MessagePublish/MessageDelivered/WillMessagePublish
     Publish(Publish),
+    ///The message is dropped, for MessagePublish, the handler does not pass it on
+    PublishDrop(PublishDrop),
+    ///Suppress the last will message, for WillMessagePublish
+    WillMessageSuppress,
+    ///MQTT 5 ack properties, for ClientConnackProps/ClientSubackProps/ClientUnsubackProps
+    AckProperties(AckProperties),
     ///Message Expiry
     MessageExpiry,
     ///for GrpcMessageReceived
     GrpcMessageReply(Result<grpc::MessageReply>),
+    ///The loaded session, for SessionStoreLoad
+    SessionSnapshot(Box<SessionSnapshot>),
+    ///Whether the client is exempted, for ClientPacketSizeExempt
+    PacketSizeExempt(bool),
+    ///Labels of the session, for ClientLabels, a handler adds its labels to those of the previous ones
+    Labels(Vec<String>),
+    ///Messages to deliver, for SessionSubscribedInject, a handler adds its messages to those of the
+    ///previous ones
+    Publishes(Vec<Publish>),
 }
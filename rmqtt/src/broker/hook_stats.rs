@@ -0,0 +1,181 @@
+//! Execution stats of the hook handlers, to find the plugin slowing down the connect or the publish
+//! path. Each handler call is counted and timed by the hook manager, per plugin and hook type, the
+//! handlers registered outside of a plugin are those of "broker". A panic of the handler, or a gRPC
+//! reply with an error, is counted as an error. The latencies are kept in a histogram of microsecond
+//! buckets, the percentiles are the upper bounds of their buckets.
+//!
+//! The stats are exported with those of the node, `hooks.{plugin}.{type}.*`, and with the attributes
+//! of each plugin, `hooks`.
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use once_cell::sync::OnceCell;
+
+use crate::broker::hook::Type;
+use crate::broker::types::DashMap;
+use crate::HashMap;
+
+///The name of the handlers registered outside of a plugin
+pub const BROKER: &str = "broker";
+
+///Upper bounds of the handler latency buckets, in microseconds, the last bucket is unbounded
+pub const HANDLER_LATENCY_BUCKETS: [u64; 14] =
+    [10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000, 25000, 50000, 100000, 250000];
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct HandlerStats {
+    invocations: AtomicU64,
+    errors: AtomicU64,
+    buckets: [AtomicU64; HANDLER_LATENCY_BUCKETS.len() + 1],
+    //Sum of the latencies, in microseconds
+    sum: AtomicU64,
+}
+
+impl Clone for HandlerStats {
+    fn clone(&self) -> Self {
+        let s = HandlerStats::default();
+        s.add(self);
+        s
+    }
+}
+
+impl fmt::Debug for HandlerStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, r#"{{ "invocations":{}, "errors":{} }}"#, self.invocations(), self.errors())
+    }
+}
+
+impl HandlerStats {
+    #[inline]
+    pub fn observe(&self, latency: Duration, error: bool) {
+        let micros = latency.as_micros().min(u64::MAX as u128) as u64;
+        let idx = HANDLER_LATENCY_BUCKETS
+            .iter()
+            .position(|le| micros <= *le)
+            .unwrap_or(HANDLER_LATENCY_BUCKETS.len());
+        self.buckets[idx].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(micros, Ordering::Relaxed);
+        self.invocations.fetch_add(1, Ordering::Relaxed);
+        if error {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[inline]
+    pub fn invocations(&self) -> u64 {
+        self.invocations.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn errors(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    ///The upper bound of the bucket of the quantile, in microseconds, that of the last bounded bucket
+    ///if the quantile is beyond it, 0 if there is no call
+    pub fn quantile(&self, q: f64) -> u64 {
+        let counts = self.buckets.iter().map(|b| b.load(Ordering::Relaxed)).collect::<Vec<_>>();
+        let count = counts.iter().sum::<u64>();
+        if count == 0 {
+            return 0;
+        }
+        let rank = ((count as f64 * q).ceil() as u64).max(1);
+        let mut cumulative = 0;
+        let idx = counts
+            .iter()
+            .position(|c| {
+                cumulative += c;
+                cumulative >= rank
+            })
+            .unwrap_or(counts.len() - 1);
+        HANDLER_LATENCY_BUCKETS[idx.min(HANDLER_LATENCY_BUCKETS.len() - 1)]
+    }
+
+    #[inline]
+    pub fn add(&self, other: &Self) {
+        for (b, o) in self.buckets.iter().zip(other.buckets.iter()) {
+            b.fetch_add(o.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+        self.sum.fetch_add(other.sum.load(Ordering::Relaxed), Ordering::Relaxed);
+        self.invocations.fetch_add(other.invocations(), Ordering::Relaxed);
+        self.errors.fetch_add(other.errors(), Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "invocations": self.invocations(),
+            "errors": self.errors(),
+            "sum_us": self.sum.load(Ordering::Relaxed),
+            "p50_us": self.quantile(0.5),
+            "p99_us": self.quantile(0.99),
+        })
+    }
+}
+
+pub struct HookStats {
+    //(plugin, hook type) => stats, shared by the handlers of the plugin for the type
+    handlers: DashMap<(String, Type), Arc<HandlerStats>>,
+}
+
+impl HookStats {
+    #[inline]
+    pub fn instance() -> &'static Self {
+        static INSTANCE: OnceCell<HookStats> = OnceCell::new();
+        INSTANCE.get_or_init(|| Self { handlers: DashMap::default() })
+    }
+
+    ///The stats of the handlers of the plugin for the type
+    #[inline]
+    pub(crate) fn handler(&self, plugin: Option<&str>, t: Type) -> Arc<HandlerStats> {
+        let plugin = plugin.unwrap_or(BROKER).to_owned();
+        self.handlers.entry((plugin, t)).or_default().value().clone()
+    }
+
+    ///A copy of the stats, "{plugin}.{type}" => stats
+    pub fn snapshot(&self) -> HashMap<String, HandlerStats> {
+        self.handlers
+            .iter()
+            .map(|entry| {
+                let (plugin, t) = entry.key();
+                (format!("{}.{}", plugin, t.as_str()), entry.value().as_ref().clone())
+            })
+            .collect()
+    }
+
+    ///The stats of the handlers of the plugin, type => stats
+    pub fn plugin_json(&self, plugin: &str) -> serde_json::Value {
+        let obj = self
+            .handlers
+            .iter()
+            .filter(|entry| entry.key().0 == plugin)
+            .map(|entry| (entry.key().1.as_str().to_owned(), entry.value().to_json()))
+            .collect::<serde_json::Map<_, _>>();
+        serde_json::Value::Object(obj)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HandlerStats;
+    use std::time::Duration;
+
+    #[test]
+    fn test_quantile() {
+        let s = HandlerStats::default();
+        assert_eq!(s.quantile(0.5), 0);
+        for _ in 0..98 {
+            s.observe(Duration::from_micros(40), false);
+        }
+        s.observe(Duration::from_micros(800), false);
+        s.observe(Duration::from_secs(1), true);
+        assert_eq!(s.invocations(), 100);
+        assert_eq!(s.errors(), 1);
+        assert_eq!(s.quantile(0.5), 50);
+        assert_eq!(s.quantile(0.99), 1000);
+        assert_eq!(s.quantile(1.0), 250000);
+    }
+}
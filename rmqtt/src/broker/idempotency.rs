@@ -0,0 +1,285 @@
+//! Idempotency keys of the exactly-once bridging. A message is given a key at its first ingress, a
+//! user property kept by the later hops, and the egress bridges remember the keys delivered to skip
+//! the messages delivered again, e.g. the messages resent by an ingress bridge after a reconnection.
+//!
+//! A key is a UUID made of the millisecond timestamp (48 bits), the id of the node assigning it (16
+//! bits) and a sequence (64 bits), so the keys assigned by a node increase. An egress bridge keeps the
+//! keys delivered within a bounded window, and persists the highest key delivered of each node, the
+//! keys up to it are skipped after a restart.
+
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use bytestring::ByteString;
+use rust_box::std_ext::RwLock;
+use uuid::Uuid;
+
+use crate::broker::types::{timestamp_millis, NodeId, Publish};
+use crate::settings::deserialize_duration;
+use crate::{MqttError, Result, Runtime};
+
+static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct IdempotencyKey(u128);
+
+impl IdempotencyKey {
+    #[inline]
+    pub fn generate(node_id: NodeId) -> Self {
+        let ts = (timestamp_millis() as u128) & 0xFFFF_FFFF_FFFF;
+        let seq = SEQUENCE.fetch_add(1, Ordering::Relaxed) as u128;
+        Self(ts << 80 | ((node_id as u128) & 0xFFFF) << 64 | seq)
+    }
+
+    ///Id of the node assigning the key, its lower 16 bits
+    #[inline]
+    pub fn node_id(&self) -> NodeId {
+        ((self.0 >> 64) & 0xFFFF) as NodeId
+    }
+
+    #[inline]
+    pub fn as_u128(&self) -> u128 {
+        self.0
+    }
+}
+
+impl fmt::Display for IdempotencyKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", Uuid::from_u128(self.0).hyphenated())
+    }
+}
+
+impl FromStr for IdempotencyKey {
+    type Err = MqttError;
+
+    #[inline]
+    fn from_str(s: &str) -> Result<Self> {
+        Uuid::parse_str(s).map(|id| Self(id.as_u128())).map_err(|e| MqttError::Msg(e.to_string()))
+    }
+}
+
+///The key of the message, carried by the user property
+#[inline]
+pub fn key(p: &Publish, property: &str) -> Option<IdempotencyKey> {
+    p.properties
+        .user_properties
+        .iter()
+        .find(|(k, _)| k == property)
+        .and_then(|(_, v)| IdempotencyKey::from_str(v).ok())
+}
+
+///A copy of the message with a new key, none if the message has a key
+#[inline]
+pub fn assign(p: &Publish, property: &str, node_id: NodeId) -> Option<Publish> {
+    if p.properties.user_properties.iter().any(|(k, _)| k == property) {
+        return None;
+    }
+    let mut p = p.clone();
+    let key = IdempotencyKey::generate(node_id);
+    p.properties.user_properties.push((ByteString::from(property), ByteString::from(key.to_string())));
+    Some(p)
+}
+
+///Deduplication of an egress bridge
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DedupConfig {
+    ///Effective if the idempotency keys are assigned, idempotency.enable
+    #[serde(default)]
+    pub enable: bool,
+    ///Maximum number of the delivered keys remembered
+    #[serde(default = "DedupConfig::window_default")]
+    pub window: usize,
+    ///File persisting the highest keys delivered, in memory only if not set
+    #[serde(default)]
+    pub hwm_file: Option<String>,
+    #[serde(default = "DedupConfig::persist_interval_default", deserialize_with = "deserialize_duration")]
+    pub persist_interval: Duration,
+}
+
+impl Default for DedupConfig {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            enable: false,
+            window: Self::window_default(),
+            hwm_file: None,
+            persist_interval: Self::persist_interval_default(),
+        }
+    }
+}
+
+impl DedupConfig {
+    fn window_default() -> usize {
+        100_000
+    }
+
+    fn persist_interval_default() -> Duration {
+        Duration::from_secs(1)
+    }
+}
+
+#[derive(Default)]
+struct Delivered {
+    keys: HashSet<u128>,
+    order: VecDeque<u128>,
+    //node id => highest key delivered
+    hwms: BTreeMap<NodeId, u128>,
+    //the highest keys delivered before the restart
+    floors: BTreeMap<NodeId, u128>,
+    dirty: bool,
+}
+
+///Keys delivered by an egress bridge
+pub struct DeliveredKeys {
+    cfg: DedupConfig,
+    property: String,
+    delivered: RwLock<Delivered>,
+    skips: AtomicUsize,
+}
+
+impl DeliveredKeys {
+    ///None if disabled, the persisted high-water marks are loaded
+    pub fn new(cfg: &DedupConfig) -> Result<Option<Arc<Self>>> {
+        let idempotency = &Runtime::instance().settings.idempotency;
+        if !cfg.enable || !idempotency.enable {
+            return Ok(None);
+        }
+        let mut delivered = Delivered::default();
+        if let Some(file) = cfg.hwm_file.as_ref().filter(|f| Path::new(f).exists()) {
+            let hwms: BTreeMap<NodeId, String> = serde_json::from_slice(&std::fs::read(file)?)?;
+            for (node_id, key) in hwms {
+                delivered.floors.insert(node_id, IdempotencyKey::from_str(&key)?.as_u128());
+            }
+            delivered.hwms = delivered.floors.clone();
+        }
+        let keys = Arc::new(Self {
+            cfg: cfg.clone(),
+            property: idempotency.property.clone(),
+            delivered: RwLock::new(delivered),
+            skips: AtomicUsize::new(0),
+        });
+        if keys.cfg.hwm_file.is_some() {
+            Self::start(Arc::downgrade(&keys), keys.cfg.persist_interval);
+        }
+        Ok(Some(keys))
+    }
+
+    //Persists the high-water marks as long as the bridge exists
+    fn start(keys: Weak<Self>, interval: Duration) {
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                let keys = if let Some(keys) = keys.upgrade() { keys } else { break };
+                if let Err(e) = keys.persist() {
+                    log::warn!("persist the delivered idempotency keys error, {:?}", e);
+                }
+            }
+        });
+    }
+
+    #[inline]
+    pub fn key(&self, p: &Publish) -> Option<IdempotencyKey> {
+        key(p, &self.property)
+    }
+
+    ///True if the key was delivered, within the window or before the restart
+    pub fn is_delivered(&self, key: IdempotencyKey) -> bool {
+        let delivered = self.delivered.read();
+        let k = key.as_u128();
+        let yes = delivered.keys.contains(&k)
+            || delivered.floors.get(&key.node_id()).map(|floor| k <= *floor).unwrap_or(false);
+        if yes {
+            self.skips.fetch_add(1, Ordering::Relaxed);
+        }
+        yes
+    }
+
+    pub fn delivered(&self, key: IdempotencyKey) {
+        let mut delivered = self.delivered.write();
+        let delivered = &mut *delivered;
+        let k = key.as_u128();
+        if !delivered.keys.insert(k) {
+            return;
+        }
+        delivered.order.push_back(k);
+        while delivered.order.len() > self.cfg.window.max(1) {
+            if let Some(k) = delivered.order.pop_front() {
+                delivered.keys.remove(&k);
+            }
+        }
+        let hwm = delivered.hwms.entry(key.node_id()).or_default();
+        if k > *hwm {
+            *hwm = k;
+            delivered.dirty = true;
+        }
+    }
+
+    ///Writes the high-water marks if changed
+    pub fn persist(&self) -> Result<()> {
+        let file = if let Some(file) = self.cfg.hwm_file.as_ref() { file } else { return Ok(()) };
+        let hwms = {
+            let mut delivered = self.delivered.write();
+            if !delivered.dirty {
+                return Ok(());
+            }
+            delivered.dirty = false;
+            delivered
+                .hwms
+                .iter()
+                .map(|(node_id, k)| (*node_id, IdempotencyKey(*k).to_string()))
+                .collect::<BTreeMap<_, _>>()
+        };
+        if let Some(dir) = Path::new(file).parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+        let tmp = format!("{}.tmp", file);
+        std::fs::write(&tmp, serde_json::to_vec(&hwms)?)?;
+        std::fs::rename(&tmp, file)?;
+        Ok(())
+    }
+
+    #[inline]
+    pub fn to_json(&self) -> serde_json::Value {
+        let delivered = self.delivered.read();
+        let hwms = delivered
+            .hwms
+            .iter()
+            .map(|(node_id, k)| (node_id.to_string(), json!(IdempotencyKey(*k).to_string())))
+            .collect::<serde_json::Map<_, _>>();
+        json!({
+            "window": delivered.keys.len(),
+            "skips": self.skips.load(Ordering::Relaxed),
+            "hwms": hwms,
+        })
+    }
+}
+
+impl Drop for DeliveredKeys {
+    fn drop(&mut self) {
+        if let Err(e) = self.persist() {
+            log::warn!("persist the delivered idempotency keys error, {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::IdempotencyKey;
+
+    #[test]
+    fn test_key() {
+        let k1 = IdempotencyKey::generate(3);
+        let k2 = IdempotencyKey::generate(3);
+        assert!(k2 > k1);
+        assert_eq!(k1.node_id(), 3);
+        assert_eq!(IdempotencyKey::from_str(&k1.to_string()).unwrap(), k1);
+        assert!(IdempotencyKey::from_str("not-a-uuid").is_err());
+    }
+}
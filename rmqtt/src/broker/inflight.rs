@@ -1,17 +1,18 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, VecDeque};
 use std::sync::atomic::{AtomicU16, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 
-use rust_box::dequemap::DequeBTreeMap as DequeMap;
-
 use crate::broker::queue::OnEventFn;
 use crate::broker::types::{
-    From, Packet, PacketId, PacketV3, PacketV5, Publish, PublishAck2, PublishAck2Reason, TimestampMillis,
-    UserProperties,
+    From, HashMap, Packet, PacketId, PacketV3, PacketV5, Publish, PublishAck2, PublishAck2Reason,
+    TimestampMillis, UserProperties,
 };
+use crate::settings::listener::{ListenerInner, RetryExhausted};
 use crate::{MqttError, Result};
 
-type Queues = DequeMap<PacketId, InflightMessage>;
+type Slots = HashMap<PacketId, Slot>;
 
 #[derive(Debug, Eq, PartialEq, Clone, Copy, Serialize, Deserialize)]
 pub enum MomentStatus {
@@ -74,30 +75,179 @@ impl InflightMessage {
     }
 }
 
+///Redelivery of the unacknowledged messages, the delay of the first redelivery is the retry interval,
+///it is multiplied by the backoff factor after each redelivery, up to max_interval, and randomized
+///by the jitter.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    ///0 means no redelivery
+    pub interval: TimestampMillis,
+    pub backoff: f64,
+    ///0 means unlimited
+    pub max_interval: TimestampMillis,
+    ///Fraction of the delay randomly added or removed, from 0.0 to 1.0
+    pub jitter: f64,
+    ///0 means unlimited
+    pub max_attempts: u32,
+    pub exhausted: RetryExhausted,
+}
+
+impl RetryPolicy {
+    ///The same delay for all the redeliveries, never exhausted
+    #[inline]
+    pub fn fixed(interval: TimestampMillis) -> Self {
+        Self {
+            interval,
+            backoff: 1.0,
+            max_interval: 0,
+            jitter: 0.0,
+            max_attempts: 0,
+            exhausted: RetryExhausted::Keep,
+        }
+    }
+
+    #[inline]
+    pub fn from_listener(cfg: &ListenerInner) -> Self {
+        Self {
+            interval: cfg.message_retry_interval.as_millis() as TimestampMillis,
+            backoff: cfg.message_retry_backoff,
+            max_interval: cfg.message_retry_max_interval.as_millis() as TimestampMillis,
+            jitter: cfg.message_retry_jitter,
+            max_attempts: cfg.message_retry_max_attempts,
+            exhausted: cfg.message_retry_exhausted,
+        }
+    }
+
+    ///Delay of the redelivery after `retries` redeliveries, 0 if there is no redelivery
+    #[inline]
+    pub fn delay(&self, retries: u32) -> TimestampMillis {
+        if self.interval <= 0 {
+            return 0;
+        }
+        let mut delay = self.interval as f64 * self.backoff.max(1.0).powi(retries.min(64) as i32);
+        if self.max_interval > 0 {
+            delay = delay.min(self.max_interval as f64);
+        }
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        if jitter > 0.0 {
+            delay *= 1.0 + jitter * (rand::random::<f64>() * 2.0 - 1.0);
+        }
+        (delay as TimestampMillis).max(1)
+    }
+
+    #[inline]
+    pub fn is_exhausted(&self, retries: u32) -> bool {
+        self.max_attempts > 0 && retries >= self.max_attempts
+    }
+}
+
+///Adaptation of the send quota, the Receive Maximum actually used, to the client. The quota starts at
+///the cap of the window. It is halved, down to min, when the average ack latency exceeds
+///ack_latency or a message times out, and grows by one per quota of acks when the acks are faster
+///than half of ack_latency while the deliver queue is not draining.
+#[derive(Debug, Clone, Copy)]
+pub struct AdaptivePolicy {
+    pub min: usize,
+    pub ack_latency: TimestampMillis,
+}
+
+impl AdaptivePolicy {
+    ///None if the adaptation is disabled on the listener
+    #[inline]
+    pub fn from_listener(cfg: &ListenerInner) -> Option<Self> {
+        if cfg.max_inflight_adaptive {
+            Some(Self {
+                min: cfg.max_inflight_min.max(1) as usize,
+                ack_latency: (cfg.max_inflight_ack_latency.as_millis() as TimestampMillis).max(1),
+            })
+        } else {
+            None
+        }
+    }
+}
+
+///A message popped from the inflight window on timeout
+#[derive(Debug)]
+pub enum Timeout {
+    ///To be redelivered
+    Retry(InflightMessage),
+    ///Redelivered max_attempts times, to be dropped
+    Exhausted(InflightMessage),
+}
+
+///Inflight window, the messages are kept by packet id, acks and status updates are O(1).
+///Besides, two lazily cleaned indexes are kept, the insertion order for `front` and `pop_front`,
+///and the deadline order for `get_timeout` and `pop_front_timeout`. The index entries of removed
+///or updated messages are stale and skipped, the front of both indexes is always valid.
 #[derive(Clone)]
 pub struct Inflight {
     cap: usize,
-    interval: TimestampMillis,
+    adaptive: Option<AdaptivePolicy>,
+    //send quota, the cap if not adaptive
+    quota: usize,
+    //acks since the quota was last changed
+    quota_acks: usize,
+    //average ack latency, in milliseconds
+    ack_latency: TimestampMillis,
+    last_queued: usize,
+    retry: RetryPolicy,
+    expiry_interval: TimestampMillis,
     next: Arc<AtomicU16>,
-    queues: Queues,
+    seq: u64,
+    slots: Slots,
+    order: VecDeque<(u64, PacketId)>,
+    expiries: BinaryHeap<Reverse<(TimestampMillis, u64, PacketId)>>,
+    retransmissions: usize,
+    exhausteds: usize,
     on_push_fn: Option<Arc<dyn OnEventFn>>,
     on_pop_fn: Option<Arc<dyn OnEventFn>>,
 }
 
+#[derive(Clone)]
+struct Slot {
+    seq: u64,
+    msg: InflightMessage,
+    retries: u32,
+    //0 means no timeout
+    deadline: TimestampMillis,
+}
+
 impl Inflight {
     #[inline]
     pub fn new(cap: usize, retry_interval: TimestampMillis, expiry_interval: TimestampMillis) -> Self {
-        let interval = Self::interval(retry_interval, expiry_interval);
         Self {
             cap,
-            interval,
+            adaptive: None,
+            quota: cap,
+            quota_acks: 0,
+            ack_latency: 0,
+            last_queued: 0,
+            retry: RetryPolicy::fixed(retry_interval),
+            expiry_interval,
             next: Arc::new(AtomicU16::new(1)),
-            queues: Queues::default(),
+            seq: 0,
+            slots: Slots::default(),
+            order: VecDeque::new(),
+            expiries: BinaryHeap::new(),
+            retransmissions: 0,
+            exhausteds: 0,
             on_push_fn: None,
             on_pop_fn: None,
         }
     }
 
+    #[inline]
+    pub fn retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    #[inline]
+    pub fn adaptive(mut self, adaptive: Option<AdaptivePolicy>) -> Self {
+        self.adaptive = adaptive;
+        self
+    }
+
     #[inline]
     pub fn on_push<F>(mut self, f: F) -> Self
     where
@@ -127,12 +277,67 @@ impl Inflight {
     }
 
     #[inline]
-    pub fn get_timeout(&self) -> Option<Duration> {
-        if self.interval == 0 {
-            return None;
+    fn is_valid(&self, seq: u64, packet_id: PacketId) -> bool {
+        self.slots.get(&packet_id).map(|s| s.seq == seq).unwrap_or(false)
+    }
+
+    #[inline]
+    fn is_valid_expiry(&self, deadline: TimestampMillis, seq: u64, packet_id: PacketId) -> bool {
+        self.slots.get(&packet_id).map(|s| s.seq == seq && s.deadline == deadline).unwrap_or(false)
+    }
+
+    ///The redelivery deadline of a message updated at `update_time`, 0 means no timeout
+    #[inline]
+    fn deadline(&self, update_time: TimestampMillis, retries: u32) -> TimestampMillis {
+        match Self::interval(self.retry.delay(retries), self.expiry_interval) {
+            0 => 0,
+            interval => update_time + interval,
         }
-        if let Some((_, m)) = self.queues.front() {
-            let mut t = self.interval - (chrono::Local::now().timestamp_millis() - m.update_time);
+    }
+
+    #[inline]
+    fn push_expiry(&mut self, deadline: TimestampMillis, seq: u64, packet_id: PacketId) {
+        if deadline > 0 {
+            self.expiries.push(Reverse((deadline, seq, packet_id)));
+        }
+    }
+
+    ///Drops the stale entries at the front of the indexes, and rebuilds an index that is mostly stale
+    #[inline]
+    fn clean(&mut self) {
+        while let Some((seq, packet_id)) = self.order.front().copied() {
+            if self.is_valid(seq, packet_id) {
+                break;
+            }
+            self.order.pop_front();
+        }
+        while let Some(Reverse((deadline, seq, packet_id))) = self.expiries.peek().copied() {
+            if self.is_valid_expiry(deadline, seq, packet_id) {
+                break;
+            }
+            self.expiries.pop();
+        }
+
+        let max_stales = self.slots.len() * 2 + 16;
+        if self.order.len() > max_stales {
+            let slots = &self.slots;
+            self.order
+                .retain(|(seq, packet_id)| slots.get(packet_id).map(|s| s.seq == *seq).unwrap_or(false));
+        }
+        if self.expiries.len() > max_stales {
+            self.expiries = self
+                .slots
+                .iter()
+                .filter(|(_, s)| s.deadline > 0)
+                .map(|(packet_id, s)| Reverse((s.deadline, s.seq, *packet_id)))
+                .collect();
+        }
+    }
+
+    #[inline]
+    pub fn get_timeout(&self) -> Option<Duration> {
+        if let Some(Reverse((deadline, _, _))) = self.expiries.peek() {
+            let mut t = deadline - chrono::Local::now().timestamp_millis();
             if t < 1 {
                 t = 1;
             }
@@ -143,47 +348,73 @@ impl Inflight {
     }
 
     #[inline]
-    fn front_timeout(&self) -> bool {
-        if self.interval == 0 {
-            return false;
-        }
-        if let Some((_, m)) = self.queues.front() {
-            if m.timeout(self.interval) {
-                return true;
+    fn front_timeout(&self) -> Option<PacketId> {
+        match self.expiries.peek() {
+            Some(Reverse((deadline, _, packet_id)))
+                if *deadline <= chrono::Local::now().timestamp_millis() =>
+            {
+                Some(*packet_id)
             }
+            _ => None,
         }
-        false
     }
 
     #[inline]
     pub fn get(&self, packet_id: PacketId) -> Option<&InflightMessage> {
-        self.queues.get(&packet_id)
+        self.slots.get(&packet_id).map(|s| &s.msg)
     }
 
     #[inline]
     pub fn front(&self) -> Option<(&PacketId, &InflightMessage)> {
-        self.queues.front()
+        self.order.front().and_then(|(_, packet_id)| self.slots.get(packet_id).map(|s| (packet_id, &s.msg)))
+    }
+
+    ///In insertion order
+    #[inline]
+    pub fn iter(&self) -> impl Iterator<Item = (&PacketId, &InflightMessage)> {
+        self.order.iter().filter_map(move |(seq, packet_id)| {
+            self.slots.get(packet_id).filter(|s| s.seq == *seq).map(|s| (packet_id, &s.msg))
+        })
     }
 
     #[inline]
     pub fn pop_front(&mut self) -> Option<InflightMessage> {
-        if let Some(msg) = self.queues.pop_front().map(|(_, m)| m) {
-            if let Some(f) = self.on_pop_fn.as_ref() {
-                f();
-            }
-            Some(msg)
-        } else {
-            None
-        }
+        let packet_id = self.order.front().map(|(_, packet_id)| *packet_id)?;
+        self.remove(&packet_id)
     }
 
+    ///Returns the message with the earliest deadline if it has timed out. A message to be redelivered
+    ///keeps its slot and packet id until the redelivery is pushed back, and is retried again if it is
+    ///not. A message redelivered max_attempts times is dropped, or kept in the window without further
+    ///redelivery.
     #[inline]
-    pub fn pop_front_timeout(&mut self) -> Option<InflightMessage> {
-        if self.front_timeout() {
-            self.pop_front()
-        } else {
-            None
+    pub fn pop_front_timeout(&mut self) -> Option<Timeout> {
+        while let Some(packet_id) = self.front_timeout() {
+            let retries = self.slots.get(&packet_id).map(|s| s.retries).unwrap_or_default();
+            if !self.retry.is_exhausted(retries) {
+                let deadline = self.deadline(chrono::Local::now().timestamp_millis(), retries + 1);
+                let slot = self.slots.get_mut(&packet_id)?;
+                slot.retries = retries + 1;
+                slot.deadline = deadline;
+                let (seq, msg) = (slot.seq, slot.msg.clone());
+                self.push_expiry(deadline, seq, packet_id);
+                self.clean();
+                self.retransmissions += 1;
+                self.shrink();
+                return Some(Timeout::Retry(msg));
+            }
+            self.exhausteds += 1;
+            match self.retry.exhausted {
+                RetryExhausted::Drop => return self.remove(&packet_id).map(Timeout::Exhausted),
+                RetryExhausted::Keep => {
+                    if let Some(slot) = self.slots.get_mut(&packet_id) {
+                        slot.deadline = 0;
+                    }
+                    self.clean();
+                }
+            }
         }
+        None
     }
 
     #[inline]
@@ -192,11 +423,19 @@ impl Inflight {
             if let Some(f) = self.on_push_fn.as_ref() {
                 f();
             }
-            let old = self.queues.insert(packet_id, m);
+            self.seq += 1;
+            let seq = self.seq;
+            //a redelivery replaces its slot and keeps the retries
+            let retries = self.slots.get(&packet_id).map(|s| s.retries).unwrap_or_default();
+            let deadline = self.deadline(m.update_time, retries);
+            self.order.push_back((seq, packet_id));
+            self.push_expiry(deadline, seq, packet_id);
+            let old = self.slots.insert(packet_id, Slot { seq, msg: m, retries, deadline });
             if old.is_some() {
                 if let Some(f) = self.on_pop_fn.as_ref() {
                     f();
                 }
+                self.clean();
             }
         } else {
             log::warn!("packet_id is None, inflight message: {:?}", m);
@@ -205,11 +444,12 @@ impl Inflight {
 
     #[inline]
     pub fn remove(&mut self, packet_id: &PacketId) -> Option<InflightMessage> {
-        if let Some(msg) = self.queues.remove(packet_id) {
+        if let Some(slot) = self.slots.remove(packet_id) {
             if let Some(f) = self.on_pop_fn.as_ref() {
                 f();
             }
-            Some(msg)
+            self.clean();
+            Some(slot.msg)
         } else {
             None
         }
@@ -217,29 +457,103 @@ impl Inflight {
 
     #[inline]
     pub fn update_status(&mut self, packet_id: &PacketId, s: MomentStatus) {
-        if let Some(m) = self.queues.get_mut(packet_id) {
-            m.update_status(s);
+        if let Some(slot) = self.slots.get_mut(packet_id) {
+            slot.msg.update_status(s);
+            //an exhausted message is not redelivered any more
+            let (update_time, retries, seq, timed) =
+                (slot.msg.update_time, slot.retries, slot.seq, slot.deadline > 0);
+            let deadline = if timed { self.deadline(update_time, retries) } else { 0 };
+            if let Some(slot) = self.slots.get_mut(packet_id) {
+                slot.deadline = deadline;
+            }
+            self.push_expiry(deadline, seq, *packet_id);
+            self.clean();
         }
     }
 
     #[inline]
     pub fn len(&self) -> usize {
-        self.queues.len()
+        self.slots.len()
     }
 
     #[inline]
     pub fn is_empty(&self) -> bool {
-        self.queues.is_empty()
+        self.slots.is_empty()
     }
 
     #[inline]
     pub fn exist(&self, packet_id: &PacketId) -> bool {
-        self.queues.contains_key(packet_id)
+        self.slots.contains_key(packet_id)
+    }
+
+    ///Number of the messages redelivered on timeout
+    #[inline]
+    pub fn retransmissions(&self) -> usize {
+        self.retransmissions
+    }
+
+    ///Number of the messages redelivered max_attempts times
+    #[inline]
+    pub fn exhausteds(&self) -> usize {
+        self.exhausteds
     }
 
     #[inline]
     pub fn has_credit(&self) -> bool {
-        (self.cap - self.queues.len()) > 0
+        self.quota > self.slots.len()
+    }
+
+    ///The send quota, adapted to the client, at most the cap
+    #[inline]
+    pub fn quota(&self) -> usize {
+        self.quota
+    }
+
+    #[inline]
+    pub fn cap(&self) -> usize {
+        self.cap
+    }
+
+    ///Average ack latency, in milliseconds, 0 before the first ack
+    #[inline]
+    pub fn ack_latency(&self) -> TimestampMillis {
+        self.ack_latency
+    }
+
+    ///Observes the first ack of a message, PUBACK or PUBREC, before the window is updated, `queued`
+    ///is the length of the deliver queue
+    #[inline]
+    pub fn acked(&mut self, packet_id: &PacketId, queued: usize) {
+        let policy = match self.adaptive {
+            Some(policy) => policy,
+            None => return,
+        };
+        let update_time = match self.slots.get(packet_id) {
+            Some(slot) if slot.msg.status != MomentStatus::UnComplete => slot.msg.update_time,
+            _ => return,
+        };
+        let latency = (chrono::Local::now().timestamp_millis() - update_time).max(0);
+        self.ack_latency = if self.ack_latency == 0 { latency } else { (self.ack_latency * 7 + latency) / 8 };
+        let draining = queued < self.last_queued || queued == 0;
+        self.last_queued = queued;
+        self.quota_acks += 1;
+        if self.quota_acks < self.quota {
+            return;
+        }
+        if self.ack_latency > policy.ack_latency {
+            self.shrink();
+        } else if self.ack_latency <= policy.ack_latency / 2 && !draining && self.quota < self.cap {
+            self.quota += 1;
+            self.quota_acks = 0;
+        }
+    }
+
+    #[inline]
+    fn shrink(&mut self) {
+        if let Some(policy) = self.adaptive {
+            self.quota = (self.quota / 2).max(policy.min).min(self.cap);
+            self.quota_acks = 0;
+        }
     }
 
     #[inline]
@@ -249,7 +563,7 @@ impl Inflight {
             if packet_id == 0 {
                 continue;
             }
-            if !self.queues.contains_key(&packet_id) {
+            if !self.slots.contains_key(&packet_id) {
                 return Ok(packet_id);
             }
         }
@@ -266,3 +580,166 @@ impl Inflight {
         inflight_messages
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU16;
+
+    use super::{AdaptivePolicy, Inflight, InflightMessage, MomentStatus, RetryPolicy, Timeout};
+    use crate::broker::types::{From, Id, Publish, QoS, TimestampMillis};
+    use crate::settings::listener::RetryExhausted;
+
+    fn message(packet_id: u16, update_time: TimestampMillis) -> InflightMessage {
+        let publish = Publish {
+            dup: false,
+            retain: false,
+            qos: QoS::AtLeastOnce,
+            topic: "test/a".into(),
+            packet_id: NonZeroU16::new(packet_id),
+            payload: Default::default(),
+            properties: Default::default(),
+            create_time: update_time,
+        };
+        let from = From::from_custom(Id::new(1, None, None, "c1".into(), None));
+        let mut m = InflightMessage::new(MomentStatus::UnAck, from, publish);
+        m.update_time = update_time;
+        m
+    }
+
+    #[test]
+    fn order() {
+        let mut inflight = Inflight::new(64, 0, 0);
+        for packet_id in 1..=5 {
+            inflight.push_back(message(packet_id, 0));
+        }
+        assert_eq!(inflight.remove(&1).and_then(|m| m.publish.packet_id()), Some(1));
+        assert!(inflight.remove(&3).is_some());
+        assert!(inflight.remove(&3).is_none());
+        assert_eq!(inflight.len(), 3);
+        assert_eq!(inflight.front().map(|(id, _)| *id), Some(2));
+        assert_eq!(inflight.iter().map(|(id, _)| *id).collect::<Vec<_>>(), vec![2, 4, 5]);
+
+        //replaced, moves to the back
+        inflight.push_back(message(2, 0));
+        assert_eq!(inflight.len(), 3);
+        let ids =
+            inflight.to_inflight_messages().iter().filter_map(|m| m.publish.packet_id()).collect::<Vec<_>>();
+        assert_eq!(ids, vec![4, 5, 2]);
+        assert!(inflight.is_empty());
+    }
+
+    #[test]
+    fn expiry() {
+        let now = chrono::Local::now().timestamp_millis();
+        let mut inflight = Inflight::new(64, 1000, 0);
+        inflight.push_back(message(1, now - 2000));
+        inflight.push_back(message(2, now - 3000));
+        inflight.push_back(message(3, now));
+        assert!(inflight.get_timeout().is_some());
+
+        //earliest update_time first, not the insertion order
+        assert_eq!(inflight.pop_front_timeout().and_then(|m| m.publish.packet_id()), Some(2));
+        inflight.update_status(&1, MomentStatus::UnComplete);
+        assert_eq!(inflight.get(1).map(|m| m.status), Some(MomentStatus::UnComplete));
+        assert!(inflight.pop_front_timeout().is_none());
+        assert_eq!(inflight.front().map(|(id, _)| *id), Some(1));
+        //the slot and the packet id of the redelivered message are kept until it is pushed back
+        assert_eq!(inflight.len(), 3);
+        assert!(inflight.exist(&2));
+        assert_eq!(inflight.next_id().ok(), Some(4));
+    }
+
+    #[test]
+    fn resume() {
+        //the QoS 2 phases of a persisted window are restored per packet id
+        let mut m1 = message(1, 0);
+        m1.status = MomentStatus::UnReceived;
+        let mut m2 = message(2, 0);
+        m2.status = MomentStatus::UnComplete;
+        let stored = bincode::serialize(&vec![m1, m2]).unwrap();
+
+        let mut inflight = Inflight::new(64, 0, 0);
+        for m in bincode::deserialize::<Vec<InflightMessage>>(&stored).unwrap() {
+            inflight.push_back(m);
+        }
+        assert_eq!(inflight.get(1).map(|m| m.status), Some(MomentStatus::UnReceived));
+        assert_eq!(inflight.get(2).map(|m| m.status), Some(MomentStatus::UnComplete));
+        assert!(inflight.get(2).and_then(|m| m.release_packet_v3()).is_some());
+        assert_eq!(inflight.next_id().ok(), Some(3));
+    }
+
+    #[test]
+    fn stales() {
+        let mut inflight = Inflight::new(64, 1000, 0);
+        inflight.push_back(message(1, 0));
+        for i in 0..10_000u32 {
+            let packet_id = (i % 1000) as u16 + 2;
+            inflight.push_back(message(packet_id, 0));
+            inflight.update_status(&packet_id, MomentStatus::UnReceived);
+            inflight.remove(&packet_id);
+        }
+        assert_eq!(inflight.len(), 1);
+        assert!(inflight.order.len() <= 18);
+        assert!(inflight.expiries.len() <= 18);
+    }
+
+    #[test]
+    fn retry_policy() {
+        let policy = RetryPolicy { backoff: 2.0, max_interval: 5000, ..RetryPolicy::fixed(1000) };
+        assert_eq!(
+            (0..4).map(|retries| policy.delay(retries)).collect::<Vec<_>>(),
+            vec![1000, 2000, 4000, 5000]
+        );
+        assert_eq!(RetryPolicy::fixed(0).delay(3), 0);
+
+        let now = chrono::Local::now().timestamp_millis();
+        let policy =
+            RetryPolicy { max_attempts: 1, exhausted: RetryExhausted::Drop, ..RetryPolicy::fixed(1000) };
+        let mut inflight = Inflight::new(64, 0, 0).retry_policy(policy);
+        inflight.push_back(message(1, now - 1000));
+        let mut m = match inflight.pop_front_timeout() {
+            Some(Timeout::Retry(m)) => m,
+            _ => unreachable!(),
+        };
+        //redelivered with the same packet id, the retries are kept
+        m.update_time = now - 1000;
+        inflight.push_back(m);
+        assert!(matches!(inflight.pop_front_timeout(), Some(Timeout::Exhausted(_))));
+        assert!(inflight.is_empty());
+        assert_eq!((inflight.retransmissions(), inflight.exhausteds()), (1, 1));
+    }
+
+    #[test]
+    fn adaptive() {
+        let now = chrono::Local::now().timestamp_millis();
+        let policy = AdaptivePolicy { min: 2, ack_latency: 1000 };
+        let mut inflight = Inflight::new(8, 0, 0).adaptive(Some(policy));
+        assert_eq!(inflight.quota(), 8);
+
+        //slow acks, halved once per quota of acks, down to min
+        for packet_id in 1..=16 {
+            inflight.push_back(message(packet_id, now - 3000));
+            inflight.acked(&packet_id, 0);
+            inflight.remove(&packet_id);
+        }
+        assert_eq!(inflight.quota(), 2);
+        inflight.push_back(message(17, now));
+        inflight.push_back(message(18, now));
+        assert!(!inflight.has_credit());
+        inflight.remove(&17);
+        inflight.remove(&18);
+
+        //fast acks with a backlog, grows by one per quota of acks, up to the cap
+        for packet_id in 19..=400 {
+            inflight.push_back(message(packet_id, now));
+            inflight.acked(&packet_id, 100);
+            inflight.remove(&packet_id);
+        }
+        assert_eq!(inflight.quota(), 8);
+
+        let mut inflight = Inflight::new(8, 0, 0);
+        inflight.push_back(message(1, now - 3000));
+        inflight.acked(&1, 0);
+        assert_eq!((inflight.quota(), inflight.ack_latency()), (8, 0));
+    }
+}
@@ -0,0 +1,161 @@
+//! Event journal of the clients, to debug a flaky device without the global debug logs. The events
+//! of the clients matching `journal.client_ids`, exactly or with `*` wildcards, are kept on the node
+//! where they happen, the connections, the subscriptions, the messages in and out, the acks, the drops
+//! with their reason and the state transitions, at most `journal.max_events` per client, the oldest
+//! evicted. The journal of a client outlives its session, it is kept until it is cleared.
+
+use std::collections::VecDeque;
+
+use once_cell::sync::OnceCell;
+
+use crate::broker::types::{timestamp_millis, ClientId, DashMap, Id, Publish, Reason};
+use crate::{NodeId, Runtime, TimestampMillis};
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum JournalEvent {
+    SessionCreated,
+    Connected { remote_addr: Option<String> },
+    Disconnected { reason: String },
+    SessionTerminated { reason: String },
+    Subscribed { topic_filter: String, qos: u8 },
+    Unsubscribed { topic_filter: String },
+    PublishIn { topic: String, qos: u8, packet_id: Option<u16>, payload_len: usize },
+    PublishOut { topic: String, qos: u8, packet_id: Option<u16>, payload_len: usize },
+    Acked { topic: String, packet_id: Option<u16> },
+    //Stored for the offline session
+    Offline { topic: String },
+    Dropped { topic: String, reason: String },
+}
+
+impl JournalEvent {
+    #[inline]
+    pub fn connected(id: &Id) -> Self {
+        JournalEvent::Connected { remote_addr: id.remote_addr.map(|addr| addr.to_string()) }
+    }
+
+    #[inline]
+    pub fn publish_in(p: &Publish) -> Self {
+        JournalEvent::PublishIn {
+            topic: p.topic.to_string(),
+            qos: p.qos.value(),
+            packet_id: p.packet_id.map(|id| id.get()),
+            payload_len: p.payload.len(),
+        }
+    }
+
+    #[inline]
+    pub fn publish_out(p: &Publish) -> Self {
+        JournalEvent::PublishOut {
+            topic: p.topic.to_string(),
+            qos: p.qos.value(),
+            packet_id: p.packet_id.map(|id| id.get()),
+            payload_len: p.payload.len(),
+        }
+    }
+
+    #[inline]
+    pub fn acked(p: &Publish) -> Self {
+        JournalEvent::Acked { topic: p.topic.to_string(), packet_id: p.packet_id.map(|id| id.get()) }
+    }
+
+    #[inline]
+    pub fn dropped(p: &Publish, reason: &Reason) -> Self {
+        JournalEvent::Dropped { topic: p.topic.to_string(), reason: reason.to_string() }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct JournalEntry {
+    pub time: TimestampMillis,
+    pub node_id: NodeId,
+    #[serde(flatten)]
+    pub event: JournalEvent,
+}
+
+pub struct Journal {
+    journals: DashMap<ClientId, VecDeque<JournalEntry>>,
+}
+
+impl Journal {
+    #[inline]
+    pub fn instance() -> &'static Self {
+        static INSTANCE: OnceCell<Journal> = OnceCell::new();
+        INSTANCE.get_or_init(|| Self { journals: DashMap::default() })
+    }
+
+    ///Records the event of the client if it is journaled, the event is built only then
+    #[inline]
+    pub fn record<F>(&self, client_id: &ClientId, event: F)
+    where
+        F: FnOnce() -> JournalEvent,
+    {
+        let cfg = &Runtime::instance().settings.journal;
+        if cfg.client_ids.is_empty() || !cfg.client_ids.iter().any(|pattern| matches(pattern, client_id)) {
+            return;
+        }
+        if !self.journals.contains_key(client_id) && self.journals.len() >= cfg.max_clients {
+            log::debug!("{:?} is not journaled, journal.max_clients is reached", client_id);
+            return;
+        }
+        let entry =
+            JournalEntry { time: timestamp_millis(), node_id: Runtime::instance().node.id(), event: event() };
+        let mut journal = self.journals.entry(client_id.clone()).or_default();
+        while journal.len() >= cfg.max_events.max(1) {
+            journal.pop_front();
+        }
+        journal.push_back(entry);
+    }
+
+    ///The events of the client on this node, the oldest first
+    #[inline]
+    pub fn get(&self, client_id: &str) -> Vec<JournalEntry> {
+        self.journals.get(client_id).map(|journal| journal.iter().cloned().collect()).unwrap_or_default()
+    }
+
+    ///Clears the journal of the client on this node, returns whether there was one
+    #[inline]
+    pub fn clear(&self, client_id: &str) -> bool {
+        self.journals.remove(client_id).is_some()
+    }
+}
+
+//A "*" matches any part of the client id, the other characters match exactly
+fn matches(pattern: &str, client_id: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let mut rest = match client_id.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let parts = parts.collect::<Vec<_>>();
+    let (last, middles) = match parts.split_last() {
+        Some((last, middles)) => (*last, middles),
+        None => return rest.is_empty(),
+    };
+    for part in middles {
+        match rest.find(part) {
+            Some(idx) => rest = &rest[idx + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::matches;
+
+    #[test]
+    fn test_matches() {
+        assert!(matches("dev-42", "dev-42"));
+        assert!(!matches("dev-42", "dev-421"));
+        assert!(matches("dev-*", "dev-421"));
+        assert!(matches("*-eu", "dev-eu"));
+        assert!(matches("dev-*-eu", "dev-4-eu"));
+        assert!(!matches("dev-*-eu", "dev-4-us"));
+        assert!(matches("*", "anything"));
+        assert!(matches("a*b*c", "abc"));
+        assert!(!matches("ab*ba", "aba"));
+    }
+}
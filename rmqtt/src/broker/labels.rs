@@ -0,0 +1,176 @@
+//! Labels of the sessions, to operate on a cohort of clients, the canary ones for instance. The labels
+//! of a session are those of the AuthInfo returned by the auth plugins and those of the `client_labels`
+//! hook at its connection, a plugin may replace them afterwards, `Session::set_labels`. The connected
+//! sessions of this node are indexed by label, to count them, to kick them, and to limit them with
+//! `quota.max_sessions_per_label`, see quota.
+
+use once_cell::sync::OnceCell;
+use rust_box::std_ext::RwLock;
+
+use crate::broker::types::{AuthInfo, ClientId, ConnectInfo, HashMap, Id};
+use crate::Runtime;
+
+pub type Label = String;
+
+#[derive(Default)]
+struct Index {
+    //The connected sessions and their labels
+    sessions: HashMap<ClientId, (Id, Vec<Label>)>,
+    labels: HashMap<Label, Vec<Id>>,
+}
+
+impl Index {
+    fn add(&mut self, id: &Id, labels: &[Label]) {
+        for label in labels {
+            self.labels.entry(label.clone()).or_default().push(id.clone());
+        }
+    }
+
+    fn remove(&mut self, id: &Id, labels: &[Label]) {
+        for label in labels {
+            if let Some(ids) = self.labels.get_mut(label) {
+                ids.retain(|s| !same(s, id));
+                if ids.is_empty() {
+                    self.labels.remove(label);
+                }
+            }
+        }
+    }
+
+    fn connected(&mut self, id: &Id, labels: Vec<Label>) {
+        if let Some((old_id, old)) = self.sessions.remove(&id.client_id) {
+            self.remove(&old_id, &old);
+        }
+        self.add(id, &labels);
+        self.sessions.insert(id.client_id.clone(), (id.clone(), labels));
+    }
+
+    fn disconnected(&mut self, id: &Id) {
+        if !self.sessions.get(&id.client_id).map(|(s, _)| same(s, id)).unwrap_or(false) {
+            return;
+        }
+        if let Some((id, labels)) = self.sessions.remove(&id.client_id) {
+            self.remove(&id, &labels);
+        }
+    }
+
+    fn relabel(&mut self, id: &Id, labels: &[Label]) {
+        let old = match self.sessions.get_mut(&id.client_id) {
+            Some((s, old)) if same(s, id) => std::mem::replace(old, labels.to_vec()),
+            _ => return,
+        };
+        self.remove(id, &old);
+        self.add(id, labels);
+    }
+}
+
+#[inline]
+fn same(a: &Id, b: &Id) -> bool {
+    a.client_id == b.client_id && a.create_time == b.create_time
+}
+
+pub struct Labels {
+    index: RwLock<Index>,
+}
+
+impl Labels {
+    #[inline]
+    pub fn instance() -> &'static Self {
+        static INSTANCE: OnceCell<Labels> = OnceCell::new();
+        INSTANCE.get_or_init(|| Self { index: RwLock::new(Index::default()) })
+    }
+
+    ///The labels sorted and deduplicated, without the empty ones
+    #[inline]
+    pub fn normalize(mut labels: Vec<Label>) -> Vec<Label> {
+        labels.retain(|label| !label.is_empty());
+        labels.sort();
+        labels.dedup();
+        labels
+    }
+
+    ///The labels of a connecting client, those of the auth plugins and of the client_labels hook
+    pub async fn of_connect(connect_info: &ConnectInfo, auth_info: Option<&AuthInfo>) -> Vec<Label> {
+        let mut labels = auth_info.map(|auth_info| auth_info.labels.clone()).unwrap_or_default();
+        //hook, client_labels
+        labels.extend(Runtime::instance().extends.hook_mgr().await.client_labels(connect_info).await);
+        Self::normalize(labels)
+    }
+
+    #[inline]
+    pub(crate) fn connected(&self, id: &Id, labels: Vec<Label>) {
+        self.index.write().connected(id, labels);
+    }
+
+    #[inline]
+    pub(crate) fn disconnected(&self, id: &Id) {
+        self.index.write().disconnected(id);
+    }
+
+    ///Replaces the labels of the session in the index, if it is connected
+    #[inline]
+    pub(crate) fn relabel(&self, id: &Id, labels: &[Label]) {
+        self.index.write().relabel(id, labels);
+    }
+
+    ///The connected sessions of the label on this node
+    #[inline]
+    pub fn local_ids(&self, label: &str) -> Vec<Id> {
+        self.index.read().labels.get(label).cloned().unwrap_or_default()
+    }
+
+    ///The connected sessions of the label on this node except those of the client, and the oldest
+    pub fn local_count(&self, label: &str, client_id: &ClientId) -> (usize, Option<Id>) {
+        let index = self.index.read();
+        let ids = index
+            .labels
+            .get(label)
+            .map(|ids| ids.iter().filter(|s| s.client_id != *client_id).collect::<Vec<_>>())
+            .unwrap_or_default();
+        (ids.len(), ids.iter().min_by_key(|s| s.create_time).map(|s| (*s).clone()))
+    }
+
+    ///The number of the connected sessions of each label on this node
+    #[inline]
+    pub fn local_counts(&self) -> HashMap<Label, usize> {
+        self.index.read().labels.iter().map(|(label, ids)| (label.clone(), ids.len())).collect()
+    }
+
+    ///Kicks the connected sessions of the label on this node, returns those kicked
+    pub async fn kick_local(&self, label: &str) -> Vec<Id> {
+        let mut kickeds = Vec::new();
+        for id in self.local_ids(label) {
+            let mut entry = Runtime::instance().extends.shared().await.entry(id.clone());
+            match entry.kick(true, true, true).await {
+                Ok(Some(_)) => kickeds.push(id),
+                Ok(None) => {}
+                Err(e) => log::warn!("{:?} kick by label {}, error, {:?}", id, label, e),
+            }
+        }
+        kickeds
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Index, Labels};
+    use crate::broker::types::{ClientId, Id};
+
+    #[test]
+    fn test_index() {
+        let id1 = Id::new(1, None, None, ClientId::from_static("c1"), None);
+        let id2 = Id::new(1, None, None, ClientId::from_static("c2"), None);
+        let mut index = Index::default();
+        index.connected(&id1, Labels::normalize(vec!["canary".into(), "".into(), "canary".into()]));
+        index.connected(&id2, vec!["canary".into(), "eu".into()]);
+        assert_eq!(index.labels["canary"].len(), 2);
+
+        index.relabel(&id1, &["eu".into()]);
+        assert_eq!(index.labels["canary"].len(), 1);
+        assert_eq!(index.labels["eu"].len(), 2);
+
+        index.disconnected(&id2);
+        assert!(index.labels.get("canary").is_none());
+        assert_eq!(index.labels["eu"].len(), 1);
+    }
+}
@@ -0,0 +1,66 @@
+//! Publish to deliver latency of the messages. A message is timestamped at its ingress, its create
+//! time, and the latency is measured as it is written to the connection of a subscriber. It is
+//! recorded in the histograms of the QoS of the delivery, passed to the message_delivered_latency
+//! hook, and attached as a user property on the MQTT 5 deliveries if `latency.user_property` is set.
+//!
+//! The create time of a message forwarded by another node is the time of that node, the latency
+//! includes the clock difference of the nodes, a negative latency is recorded as 0. The retained
+//! messages are timestamped as they are delivered to a new subscription.
+
+use std::time::Duration;
+
+use bytestring::ByteString;
+
+use crate::broker::types::{timestamp_millis, Publish, UserProperties};
+use crate::Runtime;
+
+#[inline]
+pub fn enabled() -> bool {
+    Runtime::instance().settings.latency.enable
+}
+
+///The latency of the message from its ingress until now
+#[inline]
+pub fn latency(p: &Publish) -> Duration {
+    Duration::from_millis((timestamp_millis() - p.create_time()).max(0) as u64)
+}
+
+///Records the latency of the delivered message in the stats, and sets the user property carrying it if
+///configured, the value set at a previous delivery attempt is replaced
+#[inline]
+pub fn record(p: &mut Publish) -> Duration {
+    let latency = latency(p);
+    Runtime::instance().stats.observe_delivery_latency(p.qos(), latency);
+    let property = &Runtime::instance().settings.latency.user_property;
+    if !property.is_empty() {
+        set_property(&mut p.properties.user_properties, property, latency);
+    }
+    latency
+}
+
+#[inline]
+fn set_property(props: &mut UserProperties, property: &str, latency: Duration) {
+    let value = ByteString::from(latency.as_millis().to_string());
+    if let Some((_, v)) = props.iter_mut().find(|(k, _)| k == property) {
+        *v = value;
+    } else {
+        props.push((ByteString::from(property), value));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::set_property;
+    use crate::broker::types::UserProperties;
+
+    #[test]
+    fn test_set_property() {
+        let mut props: UserProperties = vec![("k".into(), "v".into())];
+        set_property(&mut props, "broker-latency-ms", Duration::from_millis(12));
+        set_property(&mut props, "broker-latency-ms", Duration::from_millis(34));
+        assert_eq!(props.len(), 2);
+        assert_eq!(props[1].1, "34");
+    }
+}
@@ -0,0 +1,274 @@
+//! Listeners added, modified or removed at runtime. The listener servers run on the main thread, the
+//! commands are sent to it through the channel taken by `commands`. With `listener.watch` enabled,
+//! the config files are polled and the changed listeners are applied.
+
+use once_cell::sync::OnceCell;
+use rust_box::std_ext::RwLock;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::broker::audit::{AuditEvent, AuditLog};
+use crate::broker::packet_size::PacketSize;
+use crate::broker::types::{HashMap, Message, Reason};
+use crate::settings::listener::{Listener, ListenerType};
+use crate::{MqttError, Result, Runtime};
+
+pub enum ListenerCommand {
+    ///Binds the listener
+    Start(ListenerType, Listener, oneshot::Sender<Result<()>>),
+    ///Stops accepting, the server stops gracefully
+    Stop(ListenerType, u16, oneshot::Sender<Result<()>>),
+}
+
+//A listener change found by a reload
+#[derive(Debug)]
+enum ListenerChange {
+    Add(ListenerType, Listener),
+    Modify(ListenerType, Listener),
+    Remove(ListenerType, u16),
+}
+
+pub struct ListenerManager {
+    tx: mpsc::UnboundedSender<ListenerCommand>,
+    rx: RwLock<Option<mpsc::UnboundedReceiver<ListenerCommand>>>,
+}
+
+impl ListenerManager {
+    #[inline]
+    pub fn instance() -> &'static Self {
+        static INSTANCE: OnceCell<ListenerManager> = OnceCell::new();
+        INSTANCE.get_or_init(|| {
+            let (tx, rx) = mpsc::unbounded_channel();
+            Self { tx, rx: RwLock::new(Some(rx)) }
+        })
+    }
+
+    ///Takes the receiver of the commands, for the server running the listeners
+    #[inline]
+    pub fn commands(&self) -> Option<mpsc::UnboundedReceiver<ListenerCommand>> {
+        self.rx.write().take()
+    }
+
+    #[inline]
+    async fn send<F>(&self, f: F) -> Result<()>
+    where
+        F: FnOnce(oneshot::Sender<Result<()>>) -> ListenerCommand,
+    {
+        let (tx, rx) = oneshot::channel();
+        self.tx.send(f(tx)).map_err(|_| MqttError::from("listener command channel is closed"))?;
+        rx.await.map_err(|_| MqttError::from("listener command is canceled"))?
+    }
+
+    ///Adds a listener, it binds immediately
+    pub async fn add(&self, typ: ListenerType, listener: Listener) -> Result<()> {
+        let listeners = &Runtime::instance().settings.listeners;
+        let port = listener.addr.port();
+        if ListenerType::ALL.iter().any(|t| listeners.lookup(*t, port).is_some()) {
+            return Err(MqttError::from(format!("port {} is already listened", port)));
+        }
+        listeners.set(typ, listener.clone());
+        if let Err(e) = self.send(|tx| ListenerCommand::Start(typ, listener, tx)).await {
+            listeners.remove(typ, port);
+            return Err(e);
+        }
+        log::info!("{} listener added, port: {}", typ.as_str(), port);
+        Ok(())
+    }
+
+    ///Modifies a listener, it is restarted with the new config
+    pub async fn modify(&self, typ: ListenerType, listener: Listener) -> Result<()> {
+        let listeners = &Runtime::instance().settings.listeners;
+        let port = listener.addr.port();
+        if listeners.lookup(typ, port).is_none() {
+            return Err(MqttError::from(format!("{} listener is not found, port: {}", typ.as_str(), port)));
+        }
+        self.send(|tx| ListenerCommand::Stop(typ, port, tx)).await?;
+        listeners.set(typ, listener.clone());
+        self.send(|tx| ListenerCommand::Start(typ, listener, tx)).await?;
+        log::info!("{} listener modified, port: {}", typ.as_str(), port);
+        Ok(())
+    }
+
+    ///Removes a listener, it stops accepting. With drain, the clients connected through it are
+    ///disconnected, the sessions go offline.
+    pub async fn remove(&self, typ: ListenerType, port: u16, drain: bool) -> Result<()> {
+        let listeners = &Runtime::instance().settings.listeners;
+        if listeners.remove(typ, port).is_none() {
+            return Err(MqttError::from(format!("{} listener is not found, port: {}", typ.as_str(), port)));
+        }
+        self.send(|tx| ListenerCommand::Stop(typ, port, tx)).await?;
+        if drain {
+            self.drain(port).await;
+        }
+        log::info!("{} listener removed, port: {}, drain: {}", typ.as_str(), port, drain);
+        Ok(())
+    }
+
+    async fn drain(&self, port: u16) {
+        let shared = Runtime::instance().extends.shared().await;
+        for entry in shared.iter() {
+            if entry.id().local_addr.map(|addr| addr.port()) != Some(port) || !entry.is_connected().await {
+                continue;
+            }
+            if let Some(tx) = entry.tx() {
+                if let Err(e) = tx.unbounded_send(Message::Closed(Reason::ServerDraining)) {
+                    log::warn!("{:?} draining, disconnect error, {:?}", entry.id(), e.to_string());
+                }
+            }
+        }
+    }
+
+    ///Reads the listener configs again, the listeners that are new, changed or gone are added,
+    ///modified or removed.
+    pub async fn reload(&self) -> Result<()> {
+        let listeners = &Runtime::instance().settings.listeners;
+        let loadeds: HashMap<(ListenerType, u16), Listener> = Runtime::instance()
+            .settings
+            .load_listeners()?
+            .actives()
+            .into_iter()
+            .map(|(typ, l)| ((typ, l.addr.port()), l))
+            .collect();
+
+        let changes = changes(listeners.actives(), loadeds);
+        //the clients connected through a removed listener stay connected
+        for change in changes.iter() {
+            let res = match change {
+                ListenerChange::Remove(typ, port) => self.remove(*typ, *port, false).await,
+                ListenerChange::Add(typ, l) => self.add(*typ, l.clone()).await,
+                ListenerChange::Modify(typ, l) => self.modify(*typ, l.clone()).await,
+            };
+            if let Err(e) = res {
+                log::warn!("reload, apply listener change error, {:?}, {:?}", change, e);
+            }
+        }
+
+        if !changes.is_empty() {
+            AuditLog::instance().record(AuditEvent::ConfigReloaded { target: "listeners".into() }).await;
+        }
+        Ok(())
+    }
+
+    ///Polls the config files every `listener.watch_interval`, if `listener.watch` is enabled
+    pub fn start_watch(&'static self) {
+        let cfg = &Runtime::instance().settings.listeners;
+        if !cfg.watch {
+            return;
+        }
+        ntex::rt::spawn(async move {
+            loop {
+                tokio::time::sleep(cfg.watch_interval).await;
+                if let Err(e) = self.reload().await {
+                    log::warn!("listener configs reload error, {:?}", e);
+                }
+            }
+        });
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let listeners = Runtime::instance()
+            .settings
+            .listeners
+            .actives()
+            .into_iter()
+            .map(|(typ, l)| {
+                let mut listener = json!({
+                    "type": typ.as_str(),
+                    "name": l.name,
+                    "addr": l.addr.to_string(),
+                    "max_connections": l.max_connections,
+                    "workers": l.workers,
+                    "max_packet_size": l.max_packet_size.as_u32(),
+                    "oversized_packets": PacketSize::instance().count(&l),
+                });
+                if typ == ListenerType::Uds {
+                    listener["path"] = json!(l.path);
+                }
+                listener
+            })
+            .collect::<Vec<_>>();
+        serde_json::Value::Array(listeners)
+    }
+}
+
+//The listeners removed, then those added or modified, from the active listeners to the loaded ones
+fn changes(
+    actives: Vec<(ListenerType, Listener)>,
+    loadeds: HashMap<(ListenerType, u16), Listener>,
+) -> Vec<ListenerChange> {
+    let actives: HashMap<(ListenerType, u16), Listener> =
+        actives.into_iter().map(|(typ, l)| ((typ, l.addr.port()), l)).collect();
+    let mut changes = actives
+        .keys()
+        .filter(|key| !loadeds.contains_key(key))
+        .map(|(typ, port)| ListenerChange::Remove(*typ, *port))
+        .collect::<Vec<_>>();
+    for ((typ, port), l) in loadeds {
+        match actives.get(&(typ, port)) {
+            None => changes.push(ListenerChange::Add(typ, l)),
+            Some(prev) if **prev != *l => changes.push(ListenerChange::Modify(typ, l)),
+            Some(_) => {}
+        }
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::{changes, ListenerChange};
+    use crate::broker::types::HashMap;
+    use crate::settings::listener::{Listener, ListenerInner, ListenerType, Listeners};
+
+    fn listener(port: u16, max_connections: usize) -> Listener {
+        Listener::new(ListenerInner {
+            addr: SocketAddr::from(([127, 0, 0, 1], port)),
+            max_connections,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_reload_with_connections() {
+        let listeners = Listeners::default();
+        listeners.set(ListenerType::Tcp, listener(1883, 10));
+        listeners.set(ListenerType::Tcp, listener(1884, 10));
+        listeners.set(ListenerType::Ws, listener(8080, 10));
+        //the clients connected through 1883 and 1884 look their listener up by port
+        assert_eq!(listeners.get(1883).map(|l| l.max_connections), Some(10));
+        assert_eq!(listeners.get(1884).map(|l| l.max_connections), Some(10));
+
+        let loadeds = vec![
+            (ListenerType::Tcp, listener(1883, 20)),
+            (ListenerType::Tcp, listener(1885, 10)),
+            (ListenerType::Ws, listener(8080, 10)),
+        ]
+        .into_iter()
+        .map(|(typ, l)| ((typ, l.addr.port()), l))
+        .collect::<HashMap<_, _>>();
+        let changes = changes(listeners.actives(), loadeds);
+        assert_eq!(changes.len(), 3);
+        for change in changes {
+            match change {
+                ListenerChange::Remove(typ, port) => {
+                    assert_eq!((typ, port), (ListenerType::Tcp, 1884));
+                    listeners.remove(typ, port);
+                }
+                ListenerChange::Add(typ, l) => {
+                    assert_eq!((typ, l.addr.port()), (ListenerType::Tcp, 1885));
+                    listeners.set(typ, l);
+                }
+                ListenerChange::Modify(typ, l) => {
+                    assert_eq!((typ, l.addr.port(), l.max_connections), (ListenerType::Tcp, 1883, 20));
+                    listeners.set(typ, l);
+                }
+            }
+        }
+
+        //the connections stay open, with the config of the modified listener, or the one of the removed
+        assert_eq!(listeners.get(1883).map(|l| l.max_connections), Some(20));
+        assert_eq!(listeners.get(1884).map(|l| l.max_connections), Some(10));
+        assert!(listeners.lookup(ListenerType::Tcp, 1884).is_none());
+        assert_eq!(listeners.actives().len(), 3);
+    }
+}
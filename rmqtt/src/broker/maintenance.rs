@@ -0,0 +1,302 @@
+//! Maintenance mode of a node, e.g. before an upgrade. The node keeps serving its sessions, but the
+//! new connections are refused, MQTT 5.0 clients redirected with the reason code and the server
+//! reference of `node.redirect`, `/readyz` reports the node not ready so that the load balancers stop
+//! sending it clients, and the shared subscriptions prefer the subscribers of the other nodes.
+//!
+//! The node announces its maintenance to the peers, `Maintenance`. Each peer shadows the routes of
+//! the sessions of the node, pulled by pages like the route warm-up, `RoutesSync`. A message a peer
+//! fails to forward to the node, e.g. while it restarts, is held by the peer, a `ForwardsTo`, or a
+//! `Forwards` matching a shadowed route, at most `node.maintenance.max_held` per node, the oldest
+//! dropped. The held messages are resent in their order when the node leaves maintenance, it
+//! announces it as well when it starts.
+
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicI64, AtomicUsize, Ordering};
+
+use once_cell::sync::OnceCell;
+
+use crate::broker::topic::Topic;
+use crate::broker::types::{timestamp_millis, DashMap, NodeId, TimestampMillis};
+use crate::grpc::{
+    Message, MessageBroadcaster, MessageReply, MessageSender, MessageType, MESSAGE_TYPE_MAINTENANCE,
+    MESSAGE_TYPE_ROUTES_SYNC,
+};
+use crate::{MqttError, Result, Runtime};
+
+type Held = (MessageType, Message);
+
+pub struct Maintenance {
+    //Since when the node is in maintenance, 0 if not
+    since: AtomicI64,
+    //The peers in maintenance, and since when
+    peers: DashMap<NodeId, TimestampMillis>,
+    //The topic filters of the routes of the peers in maintenance
+    shadows: DashMap<NodeId, Vec<Topic>>,
+    //The messages held for the peers in maintenance
+    helds: DashMap<NodeId, VecDeque<Held>>,
+    held_dropped: AtomicUsize,
+    resent: AtomicUsize,
+}
+
+impl Maintenance {
+    #[inline]
+    pub fn instance() -> &'static Self {
+        static INSTANCE: OnceCell<Maintenance> = OnceCell::new();
+        INSTANCE.get_or_init(Self::new)
+    }
+
+    fn new() -> Self {
+        Self {
+            since: AtomicI64::new(0),
+            peers: DashMap::default(),
+            shadows: DashMap::default(),
+            helds: DashMap::default(),
+            held_dropped: AtomicUsize::new(0),
+            resent: AtomicUsize::new(0),
+        }
+    }
+
+    ///Whether this node is in maintenance
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        self.since.load(Ordering::SeqCst) > 0
+    }
+
+    ///Whether the node, this node or a peer, is in maintenance
+    #[inline]
+    pub fn contains(&self, node_id: NodeId) -> bool {
+        if node_id == Runtime::instance().node.id() {
+            self.is_enabled()
+        } else {
+            !self.peers.is_empty() && self.peers.contains_key(&node_id)
+        }
+    }
+
+    ///The reason a new connection is refused, None if the node is not in maintenance
+    #[inline]
+    pub fn refuse_connect(&self) -> Option<&'static str> {
+        self.is_enabled().then_some("node is in maintenance")
+    }
+
+    ///Enters or leaves maintenance, and announces it to the peers. Returns whether it changed.
+    pub async fn set(&self, enable: bool) -> bool {
+        let changed = self.switch(enable);
+        if changed {
+            log::info!("node maintenance {}", if enable { "entered" } else { "left" });
+            self.announce().await;
+        }
+        changed
+    }
+
+    //Enters or leaves maintenance, returns whether it changed
+    fn switch(&self, enable: bool) -> bool {
+        if enable {
+            self.since.compare_exchange(0, timestamp_millis(), Ordering::SeqCst, Ordering::SeqCst).is_ok()
+        } else {
+            self.since.swap(0, Ordering::SeqCst) > 0
+        }
+    }
+
+    ///Announces whether this node is in maintenance to the peers
+    pub async fn announce(&self) {
+        let grpc_clients = Runtime::instance().extends.shared().await.get_grpc_clients();
+        if grpc_clients.is_empty() {
+            return;
+        }
+        let msg = Message::Maintenance(Runtime::instance().node.id(), self.is_enabled());
+        let replys = MessageBroadcaster::new(grpc_clients, MESSAGE_TYPE_MAINTENANCE, msg).join_all().await;
+        for (node_id, reply) in replys {
+            if let Err(e) = reply {
+                log::warn!("announce maintenance to node {}, error, {:?}", node_id, e);
+            }
+        }
+    }
+
+    ///A peer entered or left maintenance
+    pub(crate) async fn announced(&'static self, node_id: NodeId, enable: bool) {
+        if enable {
+            if self.peers.insert(node_id, timestamp_millis()).is_none() {
+                log::info!("node {} entered maintenance", node_id);
+                tokio::spawn(async move {
+                    match self.shadow(node_id).await {
+                        Ok(n) => log::info!("node {} in maintenance, {} topic filters shadowed", node_id, n),
+                        Err(e) => log::warn!("node {} in maintenance, shadow routes error, {:?}", node_id, e),
+                    }
+                });
+            }
+        } else {
+            if self.peers.remove(&node_id).is_some() {
+                log::info!("node {} left maintenance", node_id);
+            }
+            self.shadows.remove(&node_id);
+            if self.helds.contains_key(&node_id) {
+                tokio::spawn(async move { self.resend(node_id).await });
+            }
+        }
+    }
+
+    //Pulls the topic filters of the routes of the peer
+    async fn shadow(&self, node_id: NodeId) -> Result<usize> {
+        let grpc_client = Runtime::instance()
+            .extends
+            .shared()
+            .await
+            .get_grpc_clients()
+            .get(&node_id)
+            .map(|(_, c)| c.clone())
+            .ok_or_else(|| MqttError::from(format!("node {} is not found", node_id)))?;
+        let page_size = Runtime::instance().settings.node.maintenance.page_size;
+        let mut topic_filters = Vec::new();
+        let mut after = None;
+        loop {
+            let msg = Message::RoutesSync(None, after.take(), page_size);
+            let (routes, next) =
+                match MessageSender::new(grpc_client.clone(), MESSAGE_TYPE_ROUTES_SYNC, msg).send().await? {
+                    MessageReply::RoutesSync(routes, next) => (routes, next),
+                    MessageReply::Error(e) => return Err(MqttError::from(e)),
+                    _ => return Err(MqttError::from("unexpected reply")),
+                };
+            for (topic_filter, _, _) in routes {
+                if topic_filters.last() != Some(&topic_filter) {
+                    topic_filters.push(topic_filter);
+                }
+            }
+            after = next;
+            if after.is_none() || !self.peers.contains_key(&node_id) {
+                break;
+            }
+        }
+        let shadows = topic_filters.iter().filter_map(|tf| Topic::from_str(tf).ok()).collect::<Vec<_>>();
+        let n = shadows.len();
+        if self.peers.contains_key(&node_id) {
+            self.shadows.insert(node_id, shadows);
+        }
+        Ok(n)
+    }
+
+    ///Holds a message that failed to be forwarded to the peer, if the peer is in maintenance and the
+    ///message is for its sessions. Returns whether it is held.
+    pub fn hold(&self, node_id: NodeId, typ: MessageType, msg: Message) -> bool {
+        if self.peers.is_empty() || !self.peers.contains_key(&node_id) {
+            return false;
+        }
+        let for_node = match &msg {
+            Message::ForwardsTo(..) => true,
+            Message::Forwards(_, publish) => self
+                .shadows
+                .get(&node_id)
+                .map(|shadows| shadows.iter().any(|tf| tf.matches_str(publish.topic())))
+                .unwrap_or_default(),
+            _ => false,
+        };
+        if !for_node {
+            return false;
+        }
+        let max_held = Runtime::instance().settings.node.maintenance.max_held;
+        let mut helds = self.helds.entry(node_id).or_default();
+        if helds.len() >= max_held {
+            helds.pop_front();
+            self.held_dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        helds.push_back((typ, msg));
+        log::debug!("node {} in maintenance, message held, helds: {}", node_id, helds.len());
+        true
+    }
+
+    //Resends the messages held for the peer, in their order, those left are kept if it fails
+    async fn resend(&self, node_id: NodeId) {
+        let grpc_client = match Runtime::instance()
+            .extends
+            .shared()
+            .await
+            .get_grpc_clients()
+            .get(&node_id)
+            .map(|(_, c)| c.clone())
+        {
+            Some(c) => c,
+            None => {
+                log::warn!("resend the held messages, node {} is not found", node_id);
+                return;
+            }
+        };
+        let mut resent = 0;
+        loop {
+            let held = self.helds.get_mut(&node_id).and_then(|mut helds| helds.pop_front());
+            let (typ, msg) = match held {
+                Some(held) => held,
+                None => break,
+            };
+            if let Err(e) = MessageSender::new(grpc_client.clone(), typ, msg.clone()).send().await {
+                log::warn!("resend the held messages to node {}, error, {:?}", node_id, e);
+                self.helds.entry(node_id).or_default().push_front((typ, msg));
+                break;
+            }
+            resent += 1;
+        }
+        self.helds.remove_if(&node_id, |_, helds| helds.is_empty());
+        self.resent.fetch_add(resent, Ordering::Relaxed);
+        log::info!("node {} left maintenance, {} held messages resent", node_id, resent);
+    }
+
+    ///Readiness check, the node is not ready while in maintenance
+    #[inline]
+    pub fn check(&self) -> Result<serde_json::Value> {
+        if self.is_enabled() {
+            Err(MqttError::from("the node is in maintenance"))
+        } else {
+            Ok(serde_json::Value::Null)
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let peers = self
+            .peers
+            .iter()
+            .map(|entry| {
+                let node_id = *entry.key();
+                json!({
+                    "node_id": node_id,
+                    "since": entry.value(),
+                    "shadowed_topic_filters": self.shadows.get(&node_id).map(|s| s.len()).unwrap_or_default(),
+                })
+            })
+            .collect::<Vec<_>>();
+        let since = self.since.load(Ordering::SeqCst);
+        json!({
+            "node_id": Runtime::instance().node.id(),
+            "enable": since > 0,
+            "since": since,
+            "peers": peers,
+            "helds": self
+                .helds
+                .iter()
+                .map(|entry| (entry.key().to_string(), json!(entry.value().len())))
+                .collect::<serde_json::Map<_, _>>(),
+            "held_dropped": self.held_dropped.load(Ordering::Relaxed),
+            "resent": self.resent.load(Ordering::Relaxed),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Maintenance;
+
+    #[test]
+    fn test_switch() {
+        let maintenance = Maintenance::new();
+        assert_eq!(maintenance.refuse_connect(), None);
+        assert!(maintenance.check().is_ok());
+        //the new connections are refused and /readyz is not ready
+        assert!(maintenance.switch(true));
+        assert!(!maintenance.switch(true));
+        assert!(maintenance.is_enabled());
+        assert_eq!(maintenance.refuse_connect(), Some("node is in maintenance"));
+        assert!(maintenance.check().is_err());
+        assert!(maintenance.switch(false));
+        assert!(!maintenance.switch(false));
+        assert_eq!(maintenance.refuse_connect(), None);
+        assert!(maintenance.check().is_ok());
+    }
+}
@@ -8,6 +8,11 @@ pub struct Metrics {
     client_auth_anonymous: AtomicUsize,
     client_auth_anonymous_error: AtomicUsize,
     client_handshaking_timeout: AtomicUsize,
+    client_connect_overload: AtomicUsize,
+    client_quota_rejected: AtomicUsize,
+    client_quota_kicked: AtomicUsize,
+    client_ws_origin_rejected: AtomicUsize,
+    client_ws_origin_rate_limited: AtomicUsize,
     client_connect: AtomicUsize,
     client_connack: AtomicUsize,
     client_connack_auth_error: AtomicUsize,
@@ -22,6 +27,8 @@ pub struct Metrics {
     client_subscribe_auth_error: AtomicUsize,
     client_publish_auth_error: AtomicUsize,
     client_publish_error: AtomicUsize,
+    client_publish_quota_warned: AtomicUsize,
+    client_publish_quota_exceeded: AtomicUsize,
 
     session_subscribed: AtomicUsize,
     session_unsubscribed: AtomicUsize,
@@ -69,4 +76,17 @@ pub struct Metrics {
     messages_nonsubscribed_lastwill: AtomicUsize,
     messages_nonsubscribed_system: AtomicUsize,
     messages_nonsubscribed_bridge: AtomicUsize,
+
+    grpc_auth_rejected_token: AtomicUsize,
+    grpc_auth_rejected_peer: AtomicUsize,
+    grpc_batch_forwards_sent: AtomicUsize,
+    grpc_batch_forwards_messages: AtomicUsize,
+    grpc_batch_forwards_received: AtomicUsize,
+    grpc_messages_unclaimed: AtomicUsize,
+
+    protocol_bridge_v3_properties_dropped: AtomicUsize,
+    protocol_bridge_v3_properties_enveloped: AtomicUsize,
+    protocol_bridge_v5_envelopes_unwrapped: AtomicUsize,
+    protocol_bridge_v3_suback_failures: AtomicUsize,
+    protocol_bridge_v3_suback_disconnects: AtomicUsize,
 }
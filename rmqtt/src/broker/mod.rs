@@ -2,30 +2,69 @@ use std::convert::From as _f;
 use std::sync::Arc;
 use std::time::Duration;
 
+use ntex::util::Bytes;
 use once_cell::sync::OnceCell;
 
+use crate::broker::maintenance::Maintenance;
 use crate::broker::session::{Session, SessionOfflineInfo};
 use crate::broker::types::*;
-use crate::grpc::{GrpcClients, MessageBroadcaster, MessageReply, MESSAGE_TYPE_MESSAGE_GET};
+use crate::grpc::{
+    GrpcClients, MessageBroadcaster, MessageReply, MessageSender, MESSAGE_TYPE_MESSAGE_ACK,
+    MESSAGE_TYPE_MESSAGE_GET, MESSAGE_TYPE_MESSAGE_GET_PAGE,
+};
 use crate::settings::listener::Listener;
 use crate::stats::Counter;
 use crate::{grpc, MqttError, Result, Runtime};
 
 type HashMap<K, V> = std::collections::HashMap<K, V, ahash::RandomState>;
 
+pub mod acl;
+pub mod acl_cache;
+pub mod alarm;
+pub mod anonymous;
+pub mod audit;
+pub mod clientid;
+pub mod conformance;
+pub mod consistency;
+pub mod datagram;
+pub mod dedup;
 pub mod default;
+pub mod encryption;
 pub mod error;
 pub mod executor;
+pub mod fanout;
 pub mod fitter;
+pub mod gateway;
+pub mod health;
 pub mod hook;
+pub mod hook_stats;
+pub mod idempotency;
 pub mod inflight;
+pub mod journal;
+pub mod labels;
+pub mod latency;
+pub mod listeners;
+pub mod maintenance;
 pub mod metrics;
+pub mod origin;
+pub mod overload;
+pub mod packet_size;
+pub mod protocol_bridge;
+pub mod provision;
+pub mod proxy_protocol;
+pub mod publish_quota;
 pub mod queue;
+pub mod quota;
+pub mod request_response;
 pub mod retain;
+pub mod route_sync;
+pub mod routing;
 pub mod session;
+pub mod shared_queue;
 pub mod stats;
 pub mod topic;
 pub mod types;
+pub mod uds;
 pub mod v3;
 pub mod v5;
 
@@ -102,6 +141,17 @@ pub trait Shared: Sync + Send {
     /// Subscriptions from SubSearchParams
     async fn query_subscriptions(&self, q: SubsSearchParams) -> Vec<SubsSearchResult>;
 
+    /// Subscriptions from SubSearchParams, merged and de-duplicated from all nodes,
+    /// nodes that do not reply within the timeout are skipped
+    #[inline]
+    async fn query_subscriptions_cluster(
+        &self,
+        q: SubsSearchParams,
+        _timeout: Duration,
+    ) -> Vec<SubsSearchResult> {
+        self.query_subscriptions(q).await
+    }
+
     async fn subscriptions_count(&self) -> usize;
 
     ///This node is not included
@@ -160,6 +210,87 @@ pub trait Shared: Sync + Send {
             message_mgr.get(client_id, topic_filter, group).await
         }
     }
+
+    ///The next page of the stored messages, of this node and, if merged on get, of the other nodes,
+    ///with the node of each message. Fetch until the cursor is done.
+    async fn message_load_page(
+        &self,
+        client_id: &str,
+        topic_filter: &str,
+        group: Option<&SharedGroup>,
+        cursor: &mut MessageCursor,
+    ) -> Result<Vec<(NodeId, MsgID, From, Publish)>> {
+        let message_mgr = Runtime::instance().extends.message_mgr().await;
+        let this_node_id = Runtime::instance().node.id();
+        let grpc_clients = if message_mgr.should_merge_on_get() {
+            self.get_grpc_clients()
+        } else {
+            Arc::new(HashMap::default())
+        };
+        cursor.start(std::iter::once(this_node_id).chain(grpc_clients.keys().copied()));
+
+        let limit = Runtime::instance().settings.rpc.messages_page_size;
+        let message_mgr = &message_mgr;
+        let pages = futures::future::join_all(cursor.nodes.clone().into_iter().map(|(node_id, after)| {
+            let grpc_clients = grpc_clients.clone();
+            async move {
+                let page = if node_id == this_node_id {
+                    message_mgr.get_page(client_id, topic_filter, group, after, limit).await
+                } else if let Some((_, client)) = grpc_clients.get(&node_id) {
+                    let msg = grpc::Message::MessageGetPage(
+                        ClientId::from(client_id),
+                        TopicFilter::from(topic_filter),
+                        group.cloned(),
+                        after,
+                        limit,
+                    );
+                    match MessageSender::new(client.clone(), MESSAGE_TYPE_MESSAGE_GET_PAGE, msg).send().await
+                    {
+                        Ok(MessageReply::MessageGetPage(msgs, next)) => Ok((msgs, next)),
+                        Ok(MessageReply::Error(e)) => Err(MqttError::Error(e)),
+                        Ok(_) => unreachable!(),
+                        Err(e) => Err(e),
+                    }
+                } else {
+                    //The node left the cluster
+                    Ok((Vec::new(), None))
+                };
+                (node_id, page)
+            }
+        }))
+        .await;
+
+        let mut msgs = Vec::new();
+        for (node_id, page) in pages {
+            let (page, next) = page?;
+            msgs.extend(page.into_iter().map(|(msg_id, from, publish)| (node_id, msg_id, from, publish)));
+            cursor.advance(node_id, next);
+        }
+        Ok(msgs)
+    }
+
+    ///Acknowledges the stored messages consumed by a shared subscription, to the nodes storing them
+    async fn message_ack(&self, msgs: Vec<(NodeId, MsgID)>) -> Result<()> {
+        let mut nodes: HashMap<NodeId, Vec<MsgID>> = HashMap::default();
+        for (node_id, msg_id) in msgs {
+            nodes.entry(node_id).or_default().push(msg_id);
+        }
+        let this_node_id = Runtime::instance().node.id();
+        let grpc_clients = self.get_grpc_clients();
+        for (node_id, msg_ids) in nodes {
+            if node_id == this_node_id {
+                Runtime::instance().extends.message_mgr().await.ack(&msg_ids).await?;
+            } else if let Some((_, client)) = grpc_clients.get(&node_id) {
+                let msg = grpc::Message::MessageAck(msg_ids);
+                if let MessageReply::Error(e) =
+                    MessageSender::new(client.clone(), MESSAGE_TYPE_MESSAGE_ACK, msg).send().await?
+                {
+                    return Err(MqttError::Error(e));
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
 #[async_trait]
@@ -170,6 +301,18 @@ pub trait Router: Sync + Send {
     /// Remove with id topic filter
     async fn remove(&self, topic_filter: &str, id: Id) -> Result<bool>;
 
+    /// Remove all topic filters of the client, whichever its id, returns the number removed
+    #[inline]
+    async fn remove_client(&self, _client_id: &str) -> Result<usize> {
+        Ok(0)
+    }
+
+    /// The topic filters of the clients of the node, with the id they subscribed with
+    #[inline]
+    async fn relations(&self, _node_id: NodeId) -> Vec<(TopicFilter, Id)> {
+        Vec::new()
+    }
+
     /// Match with id and topic
     async fn matches(&self, id: Id, topic: &TopicName) -> Result<SubRelationsMap>;
 
@@ -211,6 +354,9 @@ pub trait Router: Sync + Send {
 
     ///get subscription relations
     async fn list_relations(&self, top: usize) -> Vec<serde_json::Value>;
+
+    ///Memory used by the topic tree and the subscription relations
+    async fn memory_info(&self) -> serde_json::Value;
 }
 
 #[async_trait]
@@ -221,7 +367,8 @@ pub trait SharedSubscription: Sync + Send {
         listen_cfg.shared_subscription
     }
 
-    ///Shared subscription strategy, select a subscriber, default is "random"
+    ///Shared subscription strategy, select a subscriber, default is "random". The subscribers of the
+    ///nodes in maintenance are selected only if no other one is online.
     #[inline]
     async fn choice(
         &self,
@@ -237,33 +384,57 @@ pub trait SharedSubscription: Sync + Send {
             return None;
         }
 
-        let mut tmp_ncs = ncs
-            .iter()
-            .enumerate()
-            .map(|(idx, (node_id, client_id, _, _, is_online))| (idx, node_id, client_id, is_online))
-            .collect::<Vec<_>>();
+        let maintenance = Maintenance::instance();
+        choice_online(
+            ncs,
+            |node_id| maintenance.contains(node_id),
+            |node_id, client_id| async move {
+                Runtime::instance().extends.router().await.is_online(node_id, &client_id).await
+            },
+        )
+        .await
+    }
+}
 
+//Selects a subscriber at random, online, those of the nodes in maintenance last. An offline one if
+//none is online.
+async fn choice_online<M, O, F>(
+    ncs: &[(NodeId, ClientId, SubscriptionOptions, Option<Vec<SubscriptionIdentifier>>, Option<IsOnline>)],
+    in_maintenance: M,
+    is_online: O,
+) -> Option<(usize, IsOnline)>
+where
+    M: Fn(NodeId) -> bool,
+    O: Fn(NodeId, ClientId) -> F,
+    F: std::future::Future<Output = IsOnline>,
+{
+    let (tmp_ncs, maintenance_ncs): (Vec<_>, Vec<_>) = ncs
+        .iter()
+        .enumerate()
+        .map(|(idx, (node_id, client_id, _, _, is_online))| (idx, node_id, client_id, is_online))
+        .partition(|(_, node_id, _, _)| !in_maintenance(**node_id));
+
+    let mut offline = None;
+    for mut tmp_ncs in [tmp_ncs, maintenance_ncs] {
         while !tmp_ncs.is_empty() {
             let r_idx = if tmp_ncs.len() == 1 { 0 } else { rand::random::<usize>() % tmp_ncs.len() };
 
-            let (idx, node_id, client_id, is_online) = tmp_ncs.remove(r_idx);
+            let (idx, node_id, client_id, online) = tmp_ncs.remove(r_idx);
 
-            let is_online = if let Some(is_online) = is_online {
-                *is_online
+            let online = if let Some(online) = online {
+                *online
             } else {
-                Runtime::instance().extends.router().await.is_online(*node_id, client_id).await
+                is_online(*node_id, client_id.clone()).await
             };
 
-            if is_online {
+            if online {
                 return Some((idx, true));
             }
 
-            if tmp_ncs.is_empty() {
-                return Some((idx, is_online));
-            }
+            offline.get_or_insert(idx);
         }
-        return None;
     }
+    offline.map(|idx| (idx, false))
 }
 
 #[async_trait]
@@ -288,6 +459,54 @@ pub trait RetainStorage: Sync + Send {
     fn stats_merge_mode(&self) -> StatsMergeMode {
         StatsMergeMode::None
     }
+
+    ///The retained messages matching the topic filter, with their metadata
+    #[inline]
+    async fn get_info(&self, topic_filter: &TopicFilter) -> Result<Vec<RetainInfo>> {
+        Ok(self
+            .get(topic_filter)
+            .await?
+            .into_iter()
+            .map(|(topic, retain)| RetainInfo { topic, retain, expiry_time_at: None })
+            .collect())
+    }
+
+    ///A page of the retained messages matching the topic filter, ordered by topic, and the number
+    ///of the matching messages
+    #[inline]
+    async fn list(
+        &self,
+        topic_filter: &TopicFilter,
+        offset: usize,
+        limit: usize,
+    ) -> Result<(usize, Vec<RetainInfo>)> {
+        let mut infos = self.get_info(topic_filter).await?;
+        infos.sort_by(|a, b| a.topic.cmp(&b.topic));
+        let total = infos.len();
+        Ok((total, infos.into_iter().skip(offset).take(limit).collect()))
+    }
+
+    ///Removes the retained messages matching the topic filter, a topic name removes its message,
+    ///returns the number of the removed messages
+    #[inline]
+    async fn remove(&self, topic_filter: &TopicFilter) -> Result<usize> {
+        let retains = self.get(topic_filter).await?;
+        let removeds = retains.len();
+        for (topic, mut retain) in retains {
+            //An empty message removes the retained message
+            retain.publish.payload = Bytes::new();
+            self.set(&topic, retain, None).await?;
+        }
+        Ok(removeds)
+    }
+
+    ///Checks the retained message counter against the stored messages, returns the counted and the
+    ///stored numbers, the counter is reset to the stored number if repair is set. None if the storage
+    ///does not keep a counter
+    #[inline]
+    async fn check_count(&self, _repair: bool) -> Result<Option<(isize, usize)>> {
+        Ok(None)
+    }
 }
 
 #[async_trait]
@@ -326,6 +545,42 @@ pub trait MessageManager: Sync + Send {
         Ok(Vec::new())
     }
 
+    ///A page of the stored messages after the cursor, at most limit, ordered by MsgID, and the cursor of
+    ///the next page, None for the last one. Only the messages of the page are marked as forwarded.
+    ///
+    ///By default the whole result of get is a single page.
+    #[inline]
+    async fn get_page(
+        &self,
+        client_id: &str,
+        topic_filter: &str,
+        group: Option<&SharedGroup>,
+        _after: Option<MsgID>,
+        _limit: usize,
+    ) -> Result<(Vec<(MsgID, From, Publish)>, Option<MsgID>)> {
+        Ok((self.get(client_id, topic_filter, group).await?, None))
+    }
+
+    ///Acknowledges the messages consumed by a shared subscription, see should_ack
+    #[inline]
+    async fn ack(&self, _msg_ids: &[MsgID]) -> Result<()> {
+        Ok(())
+    }
+
+    ///Forgets the stored messages forwarded to the client, so they are not skipped if the client
+    ///subscribes again, returns the number of messages updated
+    #[inline]
+    async fn purge(&self, _client_id: &str) -> Result<usize> {
+        Ok(0)
+    }
+
+    ///Indicate whether the stored messages delivered to a shared subscription are acknowledged,
+    ///e.g. to delete them from the store.
+    #[inline]
+    fn should_ack(&self) -> bool {
+        false
+    }
+
     ///Indicate whether merging data from various nodes is needed during the 'get' operation.
     #[inline]
     fn should_merge_on_get(&self) -> bool {
@@ -359,3 +614,41 @@ impl DefaultMessageManager {
 }
 
 impl MessageManager for &'static DefaultMessageManager {}
+
+#[cfg(test)]
+mod tests {
+    use super::choice_online;
+    use crate::broker::types::{ClientId, IsOnline, NodeId, SubscriptionOptions};
+
+    #[test]
+    fn test_choice_skips_maintenance() {
+        let sub = |node_id: NodeId, client_id: &str, is_online: IsOnline| {
+            (
+                node_id,
+                ClientId::from(client_id.to_owned()),
+                SubscriptionOptions::default(),
+                None,
+                Some(is_online),
+            )
+        };
+        let choice = |ncs: &[_]| {
+            futures::executor::block_on(choice_online(ncs, |node_id| node_id == 1, |_, _| async { false }))
+        };
+        //the subscribers of the node 1, in maintenance, are skipped
+        let ncs = vec![sub(1, "c1", true), sub(1, "c2", true), sub(2, "c3", true)];
+        for _ in 0..16 {
+            assert_eq!(choice(&ncs), Some((2, true)));
+        }
+        //unless no other one is online
+        let ncs = vec![sub(2, "c3", false), sub(1, "c1", true)];
+        assert_eq!(choice(&ncs), Some((1, true)));
+        let ncs = vec![sub(1, "c1", false), sub(2, "c3", false)];
+        assert_eq!(choice(&ncs), Some((1, false)));
+        //the online state looked up if unknown
+        let ncs = vec![(2, ClientId::from_static("c3"), SubscriptionOptions::default(), None, None)];
+        assert_eq!(
+            futures::executor::block_on(choice_online(&ncs, |_| false, |_, _| async { true })),
+            Some((0, true))
+        );
+    }
+}
@@ -0,0 +1,141 @@
+//! Origins of the WebSocket connections, for the listeners facing the browsers. A ws or wss listener
+//! with `allowed_origins` accepts the WebSocket handshake only if its Origin header matches one of them,
+//! exactly or with a `*` wildcard, `https://*.example.com` for instance, the others are rejected with
+//! 403 Forbidden. A handshake without Origin, that of a device rather than a browser, is accepted
+//! unless `origin_required`. With `origin_rate_limit` each origin gets a token bucket of the handshakes
+//! of the listener, the handshakes over it are rejected with 429 Too Many Requests.
+//!
+//! The Origin of a connection is kept during its MQTT handshake, `ConnectInfo::origin`, for the auth
+//! plugins to use in their decisions.
+
+use std::net::SocketAddr;
+
+use once_cell::sync::OnceCell;
+
+use crate::broker::queue::Limiter;
+use crate::broker::types::DashMap;
+use crate::settings::listener::Listener;
+use crate::Runtime;
+
+//The token buckets are reset beyond this number of origins
+const MAX_LIMITERS: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    Missing,
+    NotAllowed,
+    RateLimited,
+}
+
+pub struct Origins {
+    //(remote addr, local addr) => Origin, the connections in their MQTT handshake
+    connecting: DashMap<(SocketAddr, SocketAddr), String>,
+    //(listener port, origin) => token bucket of the handshakes
+    limiters: DashMap<(u16, String), Limiter>,
+}
+
+impl Origins {
+    #[inline]
+    pub fn instance() -> &'static Self {
+        static INSTANCE: OnceCell<Origins> = OnceCell::new();
+        INSTANCE.get_or_init(|| Self { connecting: DashMap::default(), limiters: DashMap::default() })
+    }
+
+    ///Checks the Origin of a WebSocket handshake on the listener
+    pub fn check(&self, listen_cfg: &Listener, origin: Option<&str>) -> Result<(), Rejection> {
+        let metrics = &Runtime::instance().metrics;
+        let origin = match origin {
+            Some(origin) => origin.to_ascii_lowercase(),
+            None if listen_cfg.origin_required => {
+                metrics.client_ws_origin_rejected_inc();
+                return Err(Rejection::Missing);
+            }
+            None => return Ok(()),
+        };
+        if !allowed(&listen_cfg.allowed_origins, &origin) {
+            metrics.client_ws_origin_rejected_inc();
+            return Err(Rejection::NotAllowed);
+        }
+        if let Some((burst, replenish_n_per)) = listen_cfg.origin_rate_limit {
+            let key = (listen_cfg.addr.port(), origin);
+            if self.limiters.len() >= MAX_LIMITERS && !self.limiters.contains_key(&key) {
+                self.limiters.clear();
+            }
+            let ok = self.limiters.entry(key).or_insert_with(|| Limiter::new(burst, replenish_n_per)).check();
+            if !ok {
+                metrics.client_ws_origin_rate_limited_inc();
+                return Err(Rejection::RateLimited);
+            }
+        }
+        Ok(())
+    }
+
+    ///Keeps the Origin of a connection during its MQTT handshake, until the guard is dropped
+    #[inline]
+    pub fn connecting(
+        &'static self,
+        remote_addr: SocketAddr,
+        local_addr: SocketAddr,
+        origin: Option<&str>,
+    ) -> Option<OriginGuard> {
+        let origin = origin?;
+        self.connecting.insert((remote_addr, local_addr), origin.into());
+        Some(OriginGuard { origins: self, key: (remote_addr, local_addr) })
+    }
+
+    ///The Origin of a connection in its MQTT handshake
+    #[inline]
+    pub fn get(&self, remote_addr: SocketAddr, local_addr: SocketAddr) -> Option<String> {
+        self.connecting.get(&(remote_addr, local_addr)).map(|origin| origin.value().clone())
+    }
+}
+
+pub struct OriginGuard {
+    origins: &'static Origins,
+    key: (SocketAddr, SocketAddr),
+}
+
+impl Drop for OriginGuard {
+    #[inline]
+    fn drop(&mut self) {
+        self.origins.connecting.remove(&self.key);
+    }
+}
+
+//No pattern allows all the origins, the patterns are lowercase
+#[inline]
+fn allowed(patterns: &[String], origin: &str) -> bool {
+    patterns.is_empty() || patterns.iter().any(|pattern| matches(&pattern.to_ascii_lowercase(), origin))
+}
+
+//"*" matches all, a "*" in a pattern matches a part of the host, without '/' and ':'
+fn matches(pattern: &str, origin: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == origin,
+        Some(("", "")) => true,
+        Some((prefix, suffix)) => {
+            origin.len() > prefix.len() + suffix.len()
+                && origin.starts_with(prefix)
+                && origin.ends_with(suffix)
+                && !origin[prefix.len()..origin.len() - suffix.len()].contains(['/', ':'])
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::allowed;
+
+    #[test]
+    fn test_allowed() {
+        let patterns = vec!["https://app.example.com".to_owned(), "https://*.Example.org".to_owned()];
+        assert!(allowed(&patterns, "https://app.example.com"));
+        assert!(!allowed(&patterns, "http://app.example.com"));
+        assert!(allowed(&patterns, "https://a.b.example.org"));
+        assert!(!allowed(&patterns, "https://example.org"));
+        assert!(!allowed(&patterns, "https://evil.com/.example.org"));
+        assert!(!allowed(&patterns, "https://evil.com:443.example.org"));
+        assert!(allowed(&["*".to_owned()], "null"));
+        assert!(allowed(&[], "https://any.com"));
+    }
+}
@@ -0,0 +1,148 @@
+//! Overload protection, the CPU load, the event loop lag and the message queues are sampled,
+//! while any of them is above its threshold the overload level goes up by one per sample, and
+//! the first `level` actions of `shed_order` are taken. The level goes down by one per sample
+//! when all of them are below the thresholds times `recover_ratio`. While redirect is taken, the
+//! new connections are refused and `node.redirect.batch` connected clients are redirected per sample.
+
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use once_cell::sync::OnceCell;
+use tokio::time::Instant;
+
+use crate::broker::types::QoS;
+use crate::settings::ShedAction;
+use crate::Runtime;
+
+pub struct Overload {
+    level: AtomicUsize,
+    //millis
+    loop_lag: AtomicU64,
+}
+
+impl Overload {
+    #[inline]
+    pub fn instance() -> &'static Self {
+        static INSTANCE: OnceCell<Overload> = OnceCell::new();
+        INSTANCE.get_or_init(|| Self { level: AtomicUsize::new(0), loop_lag: AtomicU64::new(0) })
+    }
+
+    #[inline]
+    pub fn level(&self) -> usize {
+        self.level.load(Ordering::SeqCst)
+    }
+
+    ///Whether the action is currently taken
+    #[inline]
+    pub fn is_shedding(&self, action: ShedAction) -> bool {
+        let level = self.level();
+        level > 0
+            && Runtime::instance().settings.node.overload.shed_order.iter().take(level).any(|a| *a == action)
+    }
+
+    ///Delays the PUBACK/PUBREC of a QoS 1/2 publish, for slow_puback
+    #[inline]
+    pub async fn slow_puback(&self, qos: QoS) {
+        if qos != QoS::AtMostOnce && self.is_shedding(ShedAction::SlowPuback) {
+            tokio::time::sleep(Runtime::instance().settings.node.overload.puback_delay).await;
+        }
+    }
+
+    ///Delays a QoS 0 delivery, for defer_qos0
+    #[inline]
+    pub async fn defer_qos0(&self, qos: QoS) {
+        if qos == QoS::AtMostOnce && self.is_shedding(ShedAction::DeferQos0) {
+            tokio::time::sleep(Runtime::instance().settings.node.overload.qos0_defer).await;
+        }
+    }
+
+    pub fn start(&'static self) {
+        let cfg = &Runtime::instance().settings.node.overload;
+        if !cfg.enable {
+            return;
+        }
+        ntex::rt::spawn(async move {
+            loop {
+                let now = Instant::now();
+                tokio::time::sleep(cfg.sample_interval).await;
+                let lag = now.elapsed().saturating_sub(cfg.sample_interval);
+                self.loop_lag.store(lag.as_millis() as u64, Ordering::SeqCst);
+                self.update(self.pressure(lag));
+                self.redirect().await;
+            }
+        });
+    }
+
+    ///Redirects a batch of the connected clients, for redirect
+    async fn redirect(&self) {
+        let cfg = &Runtime::instance().settings.node.redirect;
+        if cfg.batch > 0 && self.is_shedding(ShedAction::Redirect) {
+            Runtime::instance()
+                .node
+                .redirect(None, Some(cfg.batch), cfg.reason, cfg.server_reference())
+                .await;
+        }
+    }
+
+    ///The highest ratio of the samples to their thresholds
+    #[inline]
+    fn pressure(&self, lag: Duration) -> f64 {
+        let cfg = &Runtime::instance().settings.node.overload;
+        let mut pressure: f64 = 0.0;
+        if cfg.cpuload > 0.0 {
+            pressure = pressure.max(Runtime::instance().node.cpuload() as f64 / cfg.cpuload as f64);
+        }
+        if !cfg.loop_lag.is_zero() {
+            pressure = pressure.max(lag.as_secs_f64() / cfg.loop_lag.as_secs_f64());
+        }
+        if cfg.message_queues > 0 {
+            let queues = Runtime::instance().stats.message_queues.count().max(0) as f64;
+            pressure = pressure.max(queues / cfg.message_queues as f64);
+        }
+        pressure
+    }
+
+    fn update(&self, pressure: f64) {
+        let cfg = &Runtime::instance().settings.node.overload;
+        let level = self.level();
+        let new_level = if pressure >= 1.0 {
+            (level + 1).min(cfg.shed_order.len())
+        } else if pressure < cfg.recover_ratio as f64 {
+            level.saturating_sub(1)
+        } else {
+            level
+        };
+        if new_level == level {
+            return;
+        }
+        self.level.store(new_level, Ordering::SeqCst);
+        Runtime::instance().stats.overload_level.sets(new_level as isize);
+        if new_level > level {
+            log::warn!(
+                "overload level {} -> {}, pressure: {:.2}, shedding: {:?}",
+                level,
+                new_level,
+                pressure,
+                self.shedding()
+            );
+        } else {
+            log::info!("overload level {} -> {}, pressure: {:.2}", level, new_level, pressure);
+        }
+    }
+
+    #[inline]
+    fn shedding(&self) -> Vec<&'static str> {
+        let level = self.level();
+        Runtime::instance().settings.node.overload.shed_order.iter().take(level).map(|a| a.as_str()).collect()
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "level": self.level(),
+            "loop_lag": self.loop_lag.load(Ordering::SeqCst),
+            "cpuload": Runtime::instance().node.cpuload(),
+            "message_queues": Runtime::instance().stats.message_queues.count(),
+            "shedding": self.shedding(),
+        })
+    }
+}
@@ -0,0 +1,95 @@
+//! Maximum packet size of the listeners. `max_packet_size` is advertised in the CONNACK of MQTT 5
+//! and enforced by the codec. If `max_packet_size_exempt` is larger, it is the codec limit instead,
+//! the clients exempted by the `client_packet_size_exempt` hook may send packets up to it, the
+//! publishes of the other clients are checked against `max_packet_size` and an oversized one
+//! disconnects the client, with Packet Too Large for MQTT 5. The rejections are counted per listener,
+//! those of the codec for MQTT 5 only, the codec closes the MQTT 3.1.1 connections silently.
+
+use bytestring::ByteString;
+use once_cell::sync::OnceCell;
+
+use crate::broker::types::{ConnectInfo, DisconnectReasonCode, Id, Publish, Reason};
+use crate::settings::listener::Listener;
+use crate::{MqttError, Result, Runtime, SessionState};
+
+//Fixed header, at most 5 bytes, and the topic length
+const PUBLISH_HEADER_LEN: usize = 7;
+
+pub struct PacketSize {
+    //oversized packet rejections, by listener name and port
+    counts: dashmap::DashMap<(String, u16), usize>,
+}
+
+impl PacketSize {
+    #[inline]
+    pub fn instance() -> &'static Self {
+        static INSTANCE: OnceCell<PacketSize> = OnceCell::new();
+        INSTANCE.get_or_init(|| Self { counts: dashmap::DashMap::default() })
+    }
+
+    ///The maximum packet size of the codec of the listener, 0 means unlimited
+    #[inline]
+    pub fn codec_limit(listen_cfg: &Listener) -> u32 {
+        let max = listen_cfg.max_packet_size.as_u32();
+        let exempt = listen_cfg.max_packet_size_exempt.as_u32();
+        if max == 0 || exempt == 0 {
+            max
+        } else {
+            max.max(exempt)
+        }
+    }
+
+    ///The maximum packet size checked by the broker for the client, none if the codec limit
+    ///applies or the client is exempted
+    pub async fn limit(listen_cfg: &Listener, connect_info: &ConnectInfo) -> Option<u32> {
+        let max = listen_cfg.max_packet_size.as_u32();
+        if Self::codec_limit(listen_cfg) <= max {
+            return None;
+        }
+        //hook, client_packet_size_exempt
+        let exempted =
+            Runtime::instance().extends.hook_mgr().await.client_packet_size_exempt(connect_info).await;
+        if exempted {
+            log::debug!("{:?} exempted from the maximum packet size, {}", connect_info.id(), max);
+            None
+        } else {
+            Some(max)
+        }
+    }
+
+    ///Counts an oversized packet of the listener
+    #[inline]
+    pub fn record(&self, listen_cfg: &Listener, id: &Id, detail: &str) {
+        *self.counts.entry((listen_cfg.name.clone(), listen_cfg.addr.port())).or_default() += 1;
+        log::warn!("{:?} packet too large, {}", id, detail);
+    }
+
+    ///Checks a publish of the client, an oversized one disconnects it
+    pub async fn check_publish(&self, state: &SessionState, max: u32, publish: &Publish) -> Result<()> {
+        let size = publish_size(publish);
+        if size <= max as usize {
+            return Ok(());
+        }
+        let detail = format!("{} bytes, maximum {}", size, max);
+        self.record(state.listen_cfg(), &state.id, &detail);
+        if let Some(sink) = state.sink.as_ref() {
+            sink.close_with_reason(DisconnectReasonCode::PacketTooLarge, None);
+        }
+        let reason = Reason::ProtocolError(ByteString::from(format!("packet too large, {}", detail)));
+        state.disconnected_reason_add(reason.clone()).await?;
+        Err(MqttError::Reason(reason))
+    }
+
+    ///Oversized packet rejections of the listener
+    #[inline]
+    pub fn count(&self, listen_cfg: &Listener) -> usize {
+        self.counts.get(&(listen_cfg.name.clone(), listen_cfg.addr.port())).map(|c| *c).unwrap_or_default()
+    }
+}
+
+///The size of the publish packet, the properties are not counted, a lower bound for MQTT 5
+#[inline]
+pub fn publish_size(publish: &Publish) -> usize {
+    let packet_id_len = if publish.packet_id.is_some() { 2 } else { 0 };
+    PUBLISH_HEADER_LEN + publish.topic.len() + packet_id_len + publish.payload.len()
+}
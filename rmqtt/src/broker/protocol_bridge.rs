@@ -0,0 +1,171 @@
+//! Normalization of the messages between the MQTT 5 and the MQTT 3 clients of a mixed fleet. MQTT 3
+//! has no properties, the properties of a message delivered to a MQTT 3 client, the user properties,
+//! the content type, the response topic and the correlation data, are dropped, or carried with the
+//! payload by a JSON envelope with `protocol_bridge.v3_properties = "envelope"`. With
+//! `v5_unwrap_envelope` the envelopes published by the MQTT 3 clients are unwrapped for the MQTT 5
+//! clients, the payload and the properties restored. The SUBACK of MQTT 3 has one failure code, 0x80,
+//! the reason codes of `v3_suback_disconnect` close the connection of a MQTT 3 client instead. The
+//! conversions are counted in the metrics, `protocol.bridge.*`.
+//!
+//! An envelope, the payload and the correlation data in base64, the properties not set are omitted:
+//! `{"envelope":"mqtt5","payload":"aGk=","user_properties":[["k","v"]],"content_type":"text/plain",
+//! "response_topic":"a/b","correlation_data":"MQ==","is_utf8_payload":true}`
+
+use std::borrow::Cow;
+
+use base64::{engine::general_purpose, Engine as _};
+use bytes::Bytes;
+use bytestring::ByteString;
+
+use crate::broker::types::{Publish, PublishProperties, SubscribeAckReason};
+use crate::settings::PropertiesPolicy;
+use crate::{MqttError, Result, Runtime};
+
+const ENVELOPE: &str = "mqtt5";
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+struct Envelope {
+    envelope: String,
+    payload: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    user_properties: Vec<(String, String)>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    content_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    response_topic: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    correlation_data: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    is_utf8_payload: Option<bool>,
+}
+
+///The message delivered to a MQTT 3 client, its properties dropped or enveloped
+#[inline]
+pub fn to_v3(p: &Publish) -> Cow<'_, Publish> {
+    if !has_properties(&p.properties) {
+        return Cow::Borrowed(p);
+    }
+    let metrics = &Runtime::instance().metrics;
+    match Runtime::instance().settings.protocol_bridge.v3_properties {
+        PropertiesPolicy::Drop => {
+            metrics.protocol_bridge_v3_properties_dropped_inc();
+            Cow::Borrowed(p)
+        }
+        PropertiesPolicy::Envelope => match wrap(&p.payload, &p.properties) {
+            Ok(payload) => {
+                metrics.protocol_bridge_v3_properties_enveloped_inc();
+                let mut p = p.clone();
+                p.payload = payload;
+                p.properties = PublishProperties::default();
+                Cow::Owned(p)
+            }
+            Err(e) => {
+                log::warn!("{:?} envelope error, {:?}", p.topic, e);
+                metrics.protocol_bridge_v3_properties_dropped_inc();
+                Cow::Borrowed(p)
+            }
+        },
+    }
+}
+
+///The message delivered to a MQTT 5 client, an envelope unwrapped if enabled
+#[inline]
+pub fn to_v5(p: &Publish) -> Cow<'_, Publish> {
+    if !Runtime::instance().settings.protocol_bridge.v5_unwrap_envelope || !p.payload.starts_with(b"{") {
+        return Cow::Borrowed(p);
+    }
+    match unwrap(&p.payload, &p.properties) {
+        Some((payload, properties)) => {
+            Runtime::instance().metrics.protocol_bridge_v5_envelopes_unwrapped_inc();
+            let mut p = p.clone();
+            p.payload = payload;
+            p.properties = properties;
+            Cow::Owned(p)
+        }
+        None => Cow::Borrowed(p),
+    }
+}
+
+///Whether the connection of a MQTT 3 client is closed for the SUBACK reason code, rather than
+///returning the failure 0x80
+#[inline]
+pub fn v3_suback_disconnect(reason: SubscribeAckReason) -> bool {
+    let disconnect =
+        Runtime::instance().settings.protocol_bridge.v3_suback_disconnect.contains(&(reason as u8));
+    if disconnect {
+        Runtime::instance().metrics.protocol_bridge_v3_suback_disconnects_inc();
+    } else {
+        Runtime::instance().metrics.protocol_bridge_v3_suback_failures_inc();
+    }
+    disconnect
+}
+
+//The properties MQTT 3 cannot carry, the others are per hop
+#[inline]
+fn has_properties(props: &PublishProperties) -> bool {
+    !props.user_properties.is_empty()
+        || props.content_type.is_some()
+        || props.response_topic.is_some()
+        || props.correlation_data.is_some()
+}
+
+fn wrap(payload: &Bytes, props: &PublishProperties) -> Result<Bytes> {
+    let envelope = Envelope {
+        envelope: ENVELOPE.into(),
+        payload: general_purpose::STANDARD.encode(payload),
+        user_properties: props.user_properties.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+        content_type: props.content_type.as_ref().map(|v| v.to_string()),
+        response_topic: props.response_topic.as_ref().map(|v| v.to_string()),
+        correlation_data: props.correlation_data.as_ref().map(|v| general_purpose::STANDARD.encode(v)),
+        is_utf8_payload: props.is_utf8_payload,
+    };
+    serde_json::to_vec(&envelope).map(Bytes::from).map_err(|e| MqttError::from(e.to_string()))
+}
+
+//The payload and the properties of an envelope, the per hop properties kept, None if not an envelope
+fn unwrap(payload: &Bytes, props: &PublishProperties) -> Option<(Bytes, PublishProperties)> {
+    let envelope = serde_json::from_slice::<Envelope>(payload).ok().filter(|e| e.envelope == ENVELOPE)?;
+    let payload = general_purpose::STANDARD.decode(envelope.payload).ok()?;
+    let correlation_data = match envelope.correlation_data {
+        Some(data) => Some(Bytes::from(general_purpose::STANDARD.decode(data).ok()?)),
+        None => None,
+    };
+    let props = PublishProperties {
+        correlation_data,
+        content_type: envelope.content_type.map(ByteString::from),
+        user_properties: envelope
+            .user_properties
+            .into_iter()
+            .map(|(k, v)| (ByteString::from(k), ByteString::from(v)))
+            .collect(),
+        is_utf8_payload: envelope.is_utf8_payload,
+        response_topic: envelope.response_topic.map(ByteString::from),
+        ..props.clone()
+    };
+    Some((Bytes::from(payload), props))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{unwrap, wrap};
+    use crate::broker::types::PublishProperties;
+    use bytes::Bytes;
+    use bytestring::ByteString;
+
+    #[test]
+    fn test_envelope() {
+        let props = PublishProperties {
+            user_properties: vec![(ByteString::from("k"), ByteString::from("v"))],
+            content_type: Some(ByteString::from("text/plain")),
+            correlation_data: Some(Bytes::from_static(b"1")),
+            ..Default::default()
+        };
+        let payload = Bytes::from_static(b"hello");
+        let envelope = wrap(&payload, &props).unwrap();
+        assert_eq!(unwrap(&envelope, &PublishProperties::default()), Some((payload, props)));
+        assert_eq!(
+            unwrap(&Bytes::from_static(b"{\"payload\":\"aGk=\"}"), &PublishProperties::default()),
+            None
+        );
+    }
+}
@@ -0,0 +1,316 @@
+//! Bulk export and import of the subscriptions of the persistent sessions, for fleet migrations. The
+//! export lists the persistent sessions of this node with their subscriptions. The import creates
+//! offline persistent sessions on this node with the given client ids and subscriptions, ahead of the
+//! cutover of the devices, they are taken over when the devices connect with a persistent session.
+//! The sessions are created as the migrated ones, the session storage plugin persists them.
+//!
+//! The CSV has a header and a row per subscription, a session without subscriptions has a row with
+//! an empty topic filter: `clientid,username,session_expiry_interval,topic_filter,qos`
+
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+use crate::broker::session::{SessionMigrateInfo, SessionOfflineInfo, SessionState};
+use crate::broker::types::{
+    timestamp_millis, ClientId, ConnectInfo, ConnectV3, ConnectV5, DisconnectInfo, Id, QoS, Subscribe,
+    TopicFilter, UserName,
+};
+use crate::settings::listener::{Listener, ListenerType};
+use crate::{MqttError, Result, Runtime};
+
+const CSV_HEADER: &str = "clientid,username,session_expiry_interval,topic_filter,qos";
+
+///A persistent session and its subscriptions
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ProvisionedSession {
+    pub clientid: ClientId,
+    #[serde(default)]
+    pub username: Option<UserName>,
+    ///Session expiry interval in seconds, as a MQTT 5.0 session, the one of the listener if not set
+    #[serde(default)]
+    pub session_expiry_interval: Option<u32>,
+    #[serde(default)]
+    pub subscriptions: Vec<ProvisionedSubscription>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ProvisionedSubscription {
+    ///With the $share/{group}/ prefix of a shared subscription
+    pub topic_filter: TopicFilter,
+    #[serde(default)]
+    pub qos: u8,
+}
+
+///The persistent sessions of this node
+pub async fn export_local() -> Vec<ProvisionedSession> {
+    let mut sessions = Vec::new();
+    for entry in Runtime::instance().extends.shared().await.iter() {
+        let s = if let Some(s) = entry.session() { s } else { continue };
+        let conn_info = if let Ok(conn_info) = s.connect_info().await { conn_info } else { continue };
+        let session_expiry_interval = match conn_info.as_ref() {
+            ConnectInfo::V3(_, connect) if connect.clean_session => continue,
+            ConnectInfo::V3(_, _) => None,
+            ConnectInfo::V5(_, _) => {
+                let d = s.disconnect().await.unwrap_or_default();
+                let interval = s.fitter.session_expiry_interval(d.as_ref());
+                if interval.is_zero() {
+                    continue;
+                }
+                Some(interval.as_secs().min(u32::MAX as u64) as u32)
+            }
+        };
+        let subs = match s.subscriptions().await {
+            Ok(subs) => subs.read().await.clone(),
+            Err(e) => {
+                log::warn!("{:?} export subscriptions error, {:?}", s.id, e);
+                continue;
+            }
+        };
+        let mut subscriptions = subs
+            .iter()
+            .map(|(topic_filter, opts)| ProvisionedSubscription {
+                topic_filter: match opts.shared_group() {
+                    Some(group) => TopicFilter::from(format!("$share/{}/{}", group, topic_filter)),
+                    None => topic_filter.clone(),
+                },
+                qos: opts.qos_value(),
+            })
+            .collect::<Vec<_>>();
+        subscriptions.sort_by(|a, b| a.topic_filter.cmp(&b.topic_filter));
+        sessions.push(ProvisionedSession {
+            clientid: s.id.client_id.clone(),
+            username: s.id.username.clone(),
+            session_expiry_interval,
+            subscriptions,
+        });
+    }
+    sessions.sort_by(|a, b| a.clientid.cmp(&b.clientid));
+    sessions
+}
+
+///Creates the offline persistent sessions on this node, through the listener of the port, the first
+///TCP listener if not set. A client id with a session in the cluster is skipped with an error.
+///Returns the result of each session.
+pub async fn import(sessions: Vec<ProvisionedSession>, port: Option<u16>) -> Vec<(ClientId, Result<()>)> {
+    let listen_cfg = match listener(port) {
+        Ok(listen_cfg) => listen_cfg,
+        Err(e) => {
+            return sessions.into_iter().map(|s| (s.clientid, Err(MqttError::from(e.to_string())))).collect()
+        }
+    };
+    let mut results = Vec::new();
+    for s in sessions {
+        let client_id = s.clientid.clone();
+        let res = import_one(s, &listen_cfg).await;
+        if let Err(e) = &res {
+            log::warn!("{:?} import session error, {:?}", client_id, e);
+        }
+        results.push((client_id, res));
+    }
+    results
+}
+
+fn listener(port: Option<u16>) -> Result<Listener> {
+    let listeners = &Runtime::instance().settings.listeners;
+    match port {
+        Some(port) => {
+            listeners.get(port).ok_or_else(|| MqttError::from(format!("listener {} is not found", port)))
+        }
+        None => listeners
+            .actives()
+            .into_iter()
+            .filter(|(typ, _)| *typ == ListenerType::Tcp)
+            .map(|(_, l)| l)
+            .min_by_key(|l| l.addr.port())
+            .ok_or_else(|| MqttError::from("no TCP listener")),
+    }
+}
+
+async fn import_one(s: ProvisionedSession, listen_cfg: &Listener) -> Result<()> {
+    if s.clientid.is_empty() {
+        return Err(MqttError::from("clientid is empty"));
+    }
+    let shared = Runtime::instance().extends.shared().await;
+    if shared.exist(&s.clientid) || shared.session_status(&s.clientid).await.is_some() {
+        return Err(MqttError::from("the session already exists"));
+    }
+
+    let mut subscriptions = Vec::new();
+    for sub in s.subscriptions.iter() {
+        let qos = QoS::try_from(sub.qos).map_err(|e| MqttError::from(e.to_string()))?;
+        let sub = Subscribe::from_v3(&sub.topic_filter, qos, listen_cfg.shared_subscription)?;
+        subscriptions.push((sub.topic_filter, sub.opts));
+    }
+
+    let id = Id::new(
+        Runtime::instance().node.id(),
+        Some(listen_cfg.addr),
+        None,
+        s.clientid.clone(),
+        s.username.clone(),
+    );
+    let conn_info = match s.session_expiry_interval {
+        Some(interval) => ConnectInfo::V5(
+            id.clone(),
+            Box::new(ConnectV5 {
+                clean_start: false,
+                session_expiry_interval_secs: Some(interval),
+                client_id: s.clientid.clone(),
+                username: s.username.clone(),
+                ..Default::default()
+            }),
+        ),
+        None => ConnectInfo::V3(
+            id.clone(),
+            ConnectV3 {
+                clean_session: false,
+                client_id: s.clientid.clone(),
+                username: s.username.clone(),
+                ..Default::default()
+            },
+        ),
+    };
+    let now = timestamp_millis();
+    let info = SessionMigrateInfo {
+        conn_info,
+        connected_at: now,
+        disconnect_info: Some(DisconnectInfo::new(now)),
+        offline_info: SessionOfflineInfo {
+            id,
+            subscriptions,
+            offline_messages: Vec::new(),
+            inflight_messages: Vec::new(),
+            created_at: now,
+        },
+    };
+    SessionState::migrate_restart(info).await
+}
+
+///The sessions as CSV, a row per subscription
+pub fn to_csv(sessions: &[ProvisionedSession]) -> String {
+    let mut csv = String::from(CSV_HEADER);
+    csv.push('\n');
+    for s in sessions {
+        let username = s.username.as_deref().unwrap_or_default();
+        let interval = s.session_expiry_interval.map(|i| i.to_string()).unwrap_or_default();
+        let row = |topic_filter: &str, qos: &str| {
+            [s.clientid.as_ref(), username, interval.as_str(), topic_filter, qos]
+                .iter()
+                .map(|field| csv_field(field))
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        if s.subscriptions.is_empty() {
+            csv.push_str(&row("", ""));
+            csv.push('\n');
+        }
+        for sub in s.subscriptions.iter() {
+            csv.push_str(&row(&sub.topic_filter, &sub.qos.to_string()));
+            csv.push('\n');
+        }
+    }
+    csv
+}
+
+///The sessions of the CSV, the rows of a client id are merged, the header is optional
+pub fn from_csv(csv: &str) -> Result<Vec<ProvisionedSession>> {
+    let mut sessions: Vec<ProvisionedSession> = Vec::new();
+    for (n, line) in csv.lines().enumerate() {
+        let line = line.trim_end_matches('\r');
+        if line.trim().is_empty() || (n == 0 && line == CSV_HEADER) {
+            continue;
+        }
+        let fields = csv_split(line);
+        if fields.len() != 5 {
+            return Err(MqttError::from(format!("line {}, 5 fields expected, {}", n + 1, fields.len())));
+        }
+        let interval = if fields[2].is_empty() {
+            None
+        } else {
+            Some(fields[2].parse::<u32>().map_err(|e| MqttError::from(format!("line {}, {}", n + 1, e)))?)
+        };
+        let session = match sessions.iter_mut().rev().find(|s| s.clientid == fields[0]) {
+            Some(s) => s,
+            None => {
+                sessions.push(ProvisionedSession {
+                    clientid: ClientId::from(fields[0].as_str()),
+                    username: Some(UserName::from(fields[1].as_str())).filter(|u| !u.is_empty()),
+                    session_expiry_interval: interval,
+                    subscriptions: Vec::new(),
+                });
+                sessions.last_mut().unwrap()
+            }
+        };
+        if !fields[3].is_empty() {
+            let qos = if fields[4].is_empty() {
+                0
+            } else {
+                fields[4].parse::<u8>().map_err(|e| MqttError::from(format!("line {}, {}", n + 1, e)))?
+            };
+            session
+                .subscriptions
+                .push(ProvisionedSubscription { topic_filter: TopicFilter::from(fields[3].as_str()), qos });
+        }
+    }
+    Ok(sessions)
+}
+
+#[inline]
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+fn csv_split(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{from_csv, to_csv, ProvisionedSession, ProvisionedSubscription};
+
+    #[test]
+    fn test_csv() {
+        let sessions = vec![
+            ProvisionedSession {
+                clientid: "c1".into(),
+                username: Some("u1".into()),
+                session_expiry_interval: Some(3600),
+                subscriptions: vec![
+                    ProvisionedSubscription { topic_filter: "a,b/#".into(), qos: 1 },
+                    ProvisionedSubscription { topic_filter: "$share/g1/c/\"d\"".into(), qos: 2 },
+                ],
+            },
+            ProvisionedSession {
+                clientid: "c2".into(),
+                username: None,
+                session_expiry_interval: None,
+                subscriptions: vec![],
+            },
+        ];
+        let csv = to_csv(&sessions);
+        assert!(csv.contains("c1,u1,3600,\"a,b/#\",1\n"));
+        assert!(csv.contains("c2,,,,\n"));
+        assert_eq!(from_csv(&csv).unwrap(), sessions);
+        assert!(from_csv("c3,u3").is_err());
+    }
+}
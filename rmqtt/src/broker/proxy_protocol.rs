@@ -0,0 +1,122 @@
+//! PROXY protocol v1 (text) and v2 (binary) headers, sent by the load balancers before the client
+//! data, carrying the address of the client. The first byte tells the version, "P" for v1 and "\r"
+//! for v2, MQTT packets never start with them.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use crate::{MqttError, Result};
+
+pub const V1_PREFIX: &[u8] = b"PROXY ";
+///The longest v1 header, "PROXY UNKNOWN ...\r\n"
+pub const V1_MAX_LEN: usize = 107;
+pub const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+pub const V2_HEADER_LEN: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    V1,
+    V2,
+}
+
+impl Version {
+    ///The version of the header starting with the byte, none if it is not a PROXY header
+    #[inline]
+    pub fn detect(first: u8) -> Option<Self> {
+        match first {
+            b'P' => Some(Version::V1),
+            b'\r' => Some(Version::V2),
+            _ => None,
+        }
+    }
+}
+
+///Source address of a v1 header line, with the trailing CRLF, none for "PROXY UNKNOWN"
+pub fn parse_v1(line: &[u8]) -> Result<Option<SocketAddr>> {
+    let line = line
+        .strip_prefix(V1_PREFIX)
+        .and_then(|l| l.strip_suffix(b"\r\n"))
+        .ok_or_else(|| MqttError::from("invalid PROXY v1 header"))?;
+    let line = std::str::from_utf8(line).map_err(|_| MqttError::from("invalid PROXY v1 header"))?;
+    let mut parts = line.split(' ');
+    let ip = match (parts.next(), parts.next()) {
+        (Some("UNKNOWN"), _) => return Ok(None),
+        (Some("TCP4"), Some(ip)) => ip.parse::<Ipv4Addr>().map(IpAddr::V4).ok(),
+        (Some("TCP6"), Some(ip)) => ip.parse::<Ipv6Addr>().map(IpAddr::V6).ok(),
+        _ => None,
+    };
+    let _dst_ip = parts.next();
+    let port = parts.next().and_then(|p| p.parse::<u16>().ok());
+    match (ip, port) {
+        (Some(ip), Some(port)) => Ok(Some(SocketAddr::new(ip, port))),
+        _ => Err(MqttError::from(format!("invalid PROXY v1 header, {}", line))),
+    }
+}
+
+///Length of the v2 address block following the 16-byte header
+pub fn v2_len(header: &[u8; V2_HEADER_LEN]) -> Result<usize> {
+    if &header[..12] != V2_SIGNATURE || header[12] >> 4 != 2 {
+        return Err(MqttError::from("invalid PROXY v2 header"));
+    }
+    Ok(u16::from_be_bytes([header[14], header[15]]) as usize)
+}
+
+///Source address of a v2 header, none for the LOCAL command (the health checks of the load
+///balancer) and the unsupported address families
+pub fn parse_v2(header: &[u8; V2_HEADER_LEN], addrs: &[u8]) -> Result<Option<SocketAddr>> {
+    match header[12] & 0x0F {
+        0x00 => return Ok(None),
+        0x01 => {}
+        c => return Err(MqttError::from(format!("invalid PROXY v2 command, {}", c))),
+    }
+    match header[13] >> 4 {
+        //AF_INET, src ip, dst ip, src port, dst port
+        0x01 if addrs.len() >= 12 => {
+            let ip = Ipv4Addr::new(addrs[0], addrs[1], addrs[2], addrs[3]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), u16::from_be_bytes([addrs[8], addrs[9]]))))
+        }
+        //AF_INET6
+        0x02 if addrs.len() >= 36 => {
+            let mut ip = [0u8; 16];
+            ip.copy_from_slice(&addrs[..16]);
+            Ok(Some(SocketAddr::new(
+                IpAddr::V6(Ipv6Addr::from(ip)),
+                u16::from_be_bytes([addrs[32], addrs[33]]),
+            )))
+        }
+        0x01 | 0x02 => Err(MqttError::from("invalid PROXY v2 address block")),
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_v1, parse_v2, v2_len, Version, V2_HEADER_LEN, V2_SIGNATURE};
+
+    #[test]
+    fn test_v1() {
+        assert_eq!(Version::detect(b'P'), Some(Version::V1));
+        assert_eq!(Version::detect(0x10), None);
+        let addr = parse_v1(b"PROXY TCP4 192.168.0.1 10.0.0.1 56324 1883\r\n").unwrap();
+        assert_eq!(addr, Some("192.168.0.1:56324".parse().unwrap()));
+        let addr = parse_v1(b"PROXY TCP6 2001:db8::1 2001:db8::2 4000 1883\r\n").unwrap();
+        assert_eq!(addr, Some("[2001:db8::1]:4000".parse().unwrap()));
+        assert_eq!(parse_v1(b"PROXY UNKNOWN\r\n").unwrap(), None);
+        assert!(parse_v1(b"PROXY TCP4 192.168.0.1\r\n").is_err());
+    }
+
+    #[test]
+    fn test_v2() {
+        let mut header = [0u8; V2_HEADER_LEN];
+        header[..12].copy_from_slice(V2_SIGNATURE);
+        header[12] = 0x21;
+        header[13] = 0x11;
+        header[14..].copy_from_slice(&12u16.to_be_bytes());
+        assert_eq!(v2_len(&header).unwrap(), 12);
+        let addrs = [192, 168, 0, 1, 10, 0, 0, 1, 0xDC, 0x04, 0x07, 0x5B];
+        assert_eq!(parse_v2(&header, &addrs).unwrap(), Some("192.168.0.1:56324".parse().unwrap()));
+        header[12] = 0x20;
+        assert_eq!(parse_v2(&header, &addrs).unwrap(), None);
+        header[0] = 0;
+        assert!(v2_len(&header).is_err());
+    }
+}
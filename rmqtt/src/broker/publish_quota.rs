@@ -0,0 +1,185 @@
+//! Publish quota of a client, a token bucket of its publishes, `publish_rate_limit` of the listener,
+//! or `anonymous_publish_rate_limit` for an anonymous client, see anonymous. A publish takes a token,
+//! the bucket is refilled at its burst per period. Beyond the share `publish_quota_enforcement` of the
+//! bucket used the publishes are refused, a MQTT 5.0 client is replied Quota Exceeded, the reason 0x97
+//! of the PUBACK or the PUBREC, the publish of a MQTT 3.1.1 client is acknowledged and dropped. From the
+//! share `publish_quota_warning` the acks of a MQTT 5.0 client carry the quota user properties, so that
+//! the firmware can back off before its publishes are refused:
+//!
+//! `quota_limit`, the burst of the bucket, `quota_period`, its period in milliseconds,
+//! `quota_remaining`, the publishes accepted before the refusal, `quota_reset`, the milliseconds until
+//! the bucket is full again, and with Quota Exceeded, `quota_retry_after`, the milliseconds until a
+//! publish is accepted again.
+
+use std::cell::Cell;
+use std::num::NonZeroU32;
+use std::time::{Duration, Instant};
+
+use bytestring::ByteString;
+use ntex_mqtt::v5::codec::PublishAckReason;
+use ntex_mqtt::v5::PublishAck;
+
+use crate::broker::types::AuthInfo;
+use crate::settings::listener::Listener;
+
+//Rounding of the refilled tokens
+const EPSILON: f64 = 1e-9;
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    at: Instant,
+}
+
+#[derive(Debug)]
+pub struct PublishQuota {
+    burst: NonZeroU32,
+    period: Duration,
+    //Tokens refilled per second
+    rate: f64,
+    //Tokens never taken, the share of the bucket beyond publish_quota_enforcement
+    reserved: f64,
+    //Tokens used from which the acks warn, None if no warning
+    warning: Option<f64>,
+    bucket: Cell<Bucket>,
+}
+
+///The quota of a publish, refused if exceeded, a warning otherwise
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuotaFeedback {
+    pub exceeded: bool,
+    pub limit: u32,
+    pub period: Duration,
+    pub remaining: u32,
+    pub reset: Duration,
+    pub retry_after: Option<Duration>,
+}
+
+impl PublishQuota {
+    ///The publish quota of a client, None if not limited
+    #[inline]
+    pub fn new(listen_cfg: &Listener, auth_info: Option<&AuthInfo>) -> Option<Self> {
+        let anonymous = auth_info.map(|a| a.anonymous).unwrap_or(false);
+        let limit = if anonymous {
+            listen_cfg.anonymous_publish_rate_limit.or(listen_cfg.publish_rate_limit)
+        } else {
+            listen_cfg.publish_rate_limit
+        };
+        limit.map(|(burst, period)| {
+            Self::with(
+                burst,
+                period,
+                listen_cfg.publish_quota_warning,
+                listen_cfg.publish_quota_enforcement,
+                Instant::now(),
+            )
+        })
+    }
+
+    fn with(burst: NonZeroU32, period: Duration, warning: f64, enforcement: f64, now: Instant) -> Self {
+        let capacity = burst.get() as f64;
+        //at least a publish is accepted per period
+        let reserved = (capacity * (1.0 - enforcement.clamp(0.0, 1.0))).min(capacity - 1.0);
+        let warning = (warning < enforcement).then(|| capacity * warning.max(0.0));
+        Self {
+            burst,
+            period,
+            rate: capacity / period.as_secs_f64().max(EPSILON),
+            reserved,
+            warning,
+            bucket: Cell::new(Bucket { tokens: capacity, at: now }),
+        }
+    }
+
+    ///Takes a token for a publish, None if the publish is accepted without warning
+    #[inline]
+    pub fn check(&self) -> Option<QuotaFeedback> {
+        self.check_at(Instant::now())
+    }
+
+    fn check_at(&self, now: Instant) -> Option<QuotaFeedback> {
+        let capacity = self.burst.get() as f64;
+        let Bucket { tokens, at } = self.bucket.get();
+        let elapsed = now.saturating_duration_since(at).as_secs_f64();
+        let mut tokens = (tokens + elapsed * self.rate).min(capacity);
+        let exceeded = tokens - 1.0 < self.reserved - EPSILON;
+        if !exceeded {
+            tokens -= 1.0;
+        }
+        self.bucket.set(Bucket { tokens, at: now });
+        if !exceeded && self.warning.map(|w| capacity - tokens < w - EPSILON).unwrap_or(true) {
+            return None;
+        }
+        let secs = |tokens: f64| Duration::from_secs_f64((tokens / self.rate).max(0.0));
+        Some(QuotaFeedback {
+            exceeded,
+            limit: self.burst.get(),
+            period: self.period,
+            remaining: (tokens - self.reserved + EPSILON).max(0.0) as u32,
+            reset: secs(capacity - tokens),
+            retry_after: exceeded.then(|| secs(self.reserved + 1.0 - tokens)),
+        })
+    }
+}
+
+impl QuotaFeedback {
+    pub fn user_properties(&self) -> Vec<(ByteString, ByteString)> {
+        let mut props = vec![
+            (ByteString::from_static("quota_limit"), ByteString::from(self.limit.to_string())),
+            (ByteString::from_static("quota_period"), ByteString::from(self.period.as_millis().to_string())),
+            (ByteString::from_static("quota_remaining"), ByteString::from(self.remaining.to_string())),
+            (ByteString::from_static("quota_reset"), ByteString::from(self.reset.as_millis().to_string())),
+        ];
+        if let Some(retry_after) = self.retry_after {
+            props.push((
+                ByteString::from_static("quota_retry_after"),
+                ByteString::from(retry_after.as_millis().to_string()),
+            ));
+        }
+        props
+    }
+
+    ///The PUBACK or PUBREC of a MQTT 5.0 client, Quota Exceeded if refused
+    pub fn to_ack(&self) -> PublishAck {
+        let props = self.user_properties();
+        if self.exceeded {
+            PublishAck::new(PublishAckReason::QuotaExceeded)
+                .properties(|user_props| user_props.extend(props))
+                .reason(ByteString::from_static("Publish quota exceeded"))
+        } else {
+            PublishAck::new(PublishAckReason::Success).properties(|user_props| user_props.extend(props))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU32;
+    use std::time::{Duration, Instant};
+
+    use super::PublishQuota;
+
+    #[test]
+    fn test_check() {
+        let now = Instant::now();
+        let quota = PublishQuota::with(NonZeroU32::new(10).unwrap(), Duration::from_secs(1), 0.5, 0.8, now);
+        //4 publishes accepted without warning, 4 with, the 2 tokens left are reserved
+        for _ in 0..4 {
+            assert_eq!(quota.check_at(now), None);
+        }
+        for remaining in (0..4).rev() {
+            let feedback = quota.check_at(now).unwrap();
+            assert!(!feedback.exceeded);
+            assert_eq!(feedback.remaining, remaining);
+        }
+        let feedback = quota.check_at(now).unwrap();
+        assert!(feedback.exceeded);
+        assert_eq!(feedback.reset.as_millis(), 800);
+        assert_eq!(feedback.retry_after.map(|d| d.as_millis()), Some(100));
+        assert_eq!(feedback.user_properties().len(), 5);
+        //a token refilled in 100ms
+        let feedback = quota.check_at(now + Duration::from_millis(100)).unwrap();
+        assert!(!feedback.exceeded);
+        assert_eq!(feedback.remaining, 0);
+    }
+}
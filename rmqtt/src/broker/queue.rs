@@ -1,7 +1,7 @@
 use std::num::NonZeroU32;
 use std::pin::Pin;
 use std::rc::Rc;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll};
 use std::time::Duration;
 
@@ -138,6 +138,12 @@ impl Limiter {
         Self { l }
     }
 
+    ///Takes a token, false if the bucket is empty
+    #[inline]
+    pub fn check(&self) -> bool {
+        self.l.check().is_ok()
+    }
+
     #[inline]
     pub fn channel<T>(&self, queue: Arc<Queue<T>>) -> (Sender<T>, Receiver<'_, T>) {
         let (tx, rx) = mpsc::channel::<()>((queue.capacity() as f64 * 1.5) as usize);
@@ -155,6 +161,8 @@ impl Limiter {
 pub struct Queue<T> {
     cap: usize,
     inner: SegQueue<T>,
+    //The earliest value, taken out of the inner queue by peek()
+    head: Mutex<Option<T>>,
     on_push_fn: Option<Arc<dyn OnEventFn>>,
     on_pop_fn: Option<Arc<dyn OnEventFn>>,
 }
@@ -169,7 +177,7 @@ impl<T> Drop for Queue<T> {
 impl<T> Queue<T> {
     #[inline]
     pub fn new(cap: usize) -> Self {
-        Self { cap, inner: SegQueue::new(), on_push_fn: None, on_pop_fn: None }
+        Self { cap, inner: SegQueue::new(), head: Mutex::new(None), on_push_fn: None, on_pop_fn: None }
     }
 
     #[inline]
@@ -190,7 +198,7 @@ impl<T> Queue<T> {
 
     #[inline]
     pub fn push(&self, v: T) -> Result<(), T> {
-        if self.inner.len() > self.cap {
+        if self.len() > self.cap {
             return Err(v);
         }
         if let Some(f) = self.on_push_fn.as_ref() {
@@ -202,7 +210,8 @@ impl<T> Queue<T> {
 
     #[inline]
     pub fn pop(&self) -> Option<T> {
-        if let Some(v) = self.inner.pop() {
+        let v = self.head.lock().unwrap_or_else(|e| e.into_inner()).take().or_else(|| self.inner.pop());
+        if let Some(v) = v {
             if let Some(f) = self.on_pop_fn.as_ref() {
                 f();
             }
@@ -212,6 +221,19 @@ impl<T> Queue<T> {
         }
     }
 
+    ///Inspects the earliest value without removing it
+    #[inline]
+    pub fn peek<F, R>(&self, f: F) -> Option<R>
+    where
+        F: FnOnce(&T) -> R,
+    {
+        let mut head = self.head.lock().unwrap_or_else(|e| e.into_inner());
+        if head.is_none() {
+            *head = self.inner.pop();
+        }
+        head.as_ref().map(f)
+    }
+
     #[inline]
     pub fn capacity(&self) -> usize {
         self.cap
@@ -219,7 +241,8 @@ impl<T> Queue<T> {
 
     #[inline]
     pub fn len(&self) -> usize {
-        self.inner.len()
+        let head = self.head.lock().unwrap_or_else(|e| e.into_inner()).is_some();
+        self.inner.len() + usize::from(head)
     }
 
     #[inline]
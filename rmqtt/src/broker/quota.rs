@@ -0,0 +1,261 @@
+//! Cluster-wide session quotas of the usernames and the tenants. The connected sessions of this node
+//! are tracked by username and by tenant, the username prefix up to `quota.tenant_delimiter`. After the
+//! authentication of a client the sessions of its username and tenant are counted on this node and
+//! queried from the other nodes, a node not replying within `quota.query_timeout` is not counted. Above
+//! the maximum the client is rejected, or the oldest session is kicked, see `quota.action`.
+//!
+//! The sessions of the labels in `quota.max_sessions_per_label` are counted the same way, a client is
+//! checked against the quota of each of its labels, see labels.
+
+use once_cell::sync::OnceCell;
+use rust_box::std_ext::RwLock;
+
+use crate::broker::labels::{Label, Labels};
+use crate::broker::types::{ClientId, HashMap, Id, UserName};
+use crate::grpc::{Message, MessageBroadcaster, MessageReply, MESSAGE_TYPE_QUOTA_COUNT};
+use crate::settings::QuotaAction;
+use crate::{MqttError, Result, Runtime};
+
+///The sessions of a username, of a tenant and of the labels on a node, the client connecting is not
+///counted
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct QuotaCount {
+    pub username: usize,
+    pub tenant: usize,
+    pub oldest_username: Option<Id>,
+    pub oldest_tenant: Option<Id>,
+    pub labels: HashMap<Label, (usize, Option<Id>)>,
+}
+
+impl QuotaCount {
+    #[inline]
+    fn merge(&mut self, other: QuotaCount) {
+        self.username += other.username;
+        self.tenant += other.tenant;
+        self.oldest_username = Self::oldest(self.oldest_username.take(), other.oldest_username);
+        self.oldest_tenant = Self::oldest(self.oldest_tenant.take(), other.oldest_tenant);
+        for (label, (count, oldest)) in other.labels {
+            let entry = self.labels.entry(label).or_default();
+            entry.0 += count;
+            entry.1 = Self::oldest(entry.1.take(), oldest);
+        }
+    }
+
+    #[inline]
+    fn oldest(a: Option<Id>, b: Option<Id>) -> Option<Id> {
+        match (a, b) {
+            (Some(a), Some(b)) => Some(if b.create_time < a.create_time { b } else { a }),
+            (a, b) => a.or(b),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Sessions {
+    usernames: HashMap<UserName, Vec<Id>>,
+    tenants: HashMap<String, Vec<Id>>,
+}
+
+pub struct Quota {
+    sessions: RwLock<Sessions>,
+}
+
+impl Quota {
+    #[inline]
+    pub fn instance() -> &'static Self {
+        static INSTANCE: OnceCell<Quota> = OnceCell::new();
+        INSTANCE.get_or_init(|| Self { sessions: RwLock::new(Sessions::default()) })
+    }
+
+    #[inline]
+    fn enabled() -> bool {
+        let cfg = &Runtime::instance().settings.quota;
+        cfg.max_sessions_per_username > 0 || cfg.max_sessions_per_tenant > 0
+    }
+
+    ///The labels with a quota
+    #[inline]
+    fn quota_labels(labels: &[Label]) -> Vec<Label> {
+        let cfg = &Runtime::instance().settings.quota;
+        labels
+            .iter()
+            .filter(|label| cfg.max_sessions_per_label.contains_key(label.as_str()))
+            .cloned()
+            .collect()
+    }
+
+    ///The tenant of the username, its prefix up to the delimiter
+    #[inline]
+    pub fn tenant(username: &str) -> Option<&str> {
+        let delimiter = &Runtime::instance().settings.quota.tenant_delimiter;
+        if delimiter.is_empty() {
+            return None;
+        }
+        username.split_once(delimiter.as_str()).map(|(tenant, _)| tenant).filter(|t| !t.is_empty())
+    }
+
+    pub(crate) fn connected(&self, id: &Id) {
+        let username = if let Some(username) = id.username.as_ref() { username } else { return };
+        if !Self::enabled() {
+            return;
+        }
+        let mut sessions = self.sessions.write();
+        sessions.usernames.entry(username.clone()).or_default().push(id.clone());
+        if let Some(tenant) = Self::tenant(username) {
+            sessions.tenants.entry(tenant.into()).or_default().push(id.clone());
+        }
+    }
+
+    pub(crate) fn disconnected(&self, id: &Id) {
+        let username = if let Some(username) = id.username.as_ref() { username } else { return };
+        let same = |s: &Id| s.client_id == id.client_id && s.create_time == id.create_time;
+        let mut sessions = self.sessions.write();
+        if let Some(ids) = sessions.usernames.get_mut(username) {
+            ids.retain(|s| !same(s));
+            if ids.is_empty() {
+                sessions.usernames.remove(username);
+            }
+        }
+        if let Some(tenant) = Self::tenant(username) {
+            if let Some(ids) = sessions.tenants.get_mut(tenant) {
+                ids.retain(|s| !same(s));
+                if ids.is_empty() {
+                    sessions.tenants.remove(tenant);
+                }
+            }
+        }
+    }
+
+    ///The sessions of the username, of its tenant and of the labels on this node, except those of the
+    ///client
+    pub fn local_count(&self, client_id: &ClientId, username: Option<&str>, labels: &[Label]) -> QuotaCount {
+        let labels = labels
+            .iter()
+            .map(|label| (label.clone(), Labels::instance().local_count(label, client_id)))
+            .collect();
+        let username = if let Some(username) = username {
+            username
+        } else {
+            return QuotaCount { labels, ..Default::default() };
+        };
+        let sessions = self.sessions.read();
+        let count = |ids: Option<&Vec<Id>>| {
+            let ids = ids.map(|ids| ids.iter().filter(|s| s.client_id != *client_id).collect::<Vec<_>>());
+            let ids = ids.unwrap_or_default();
+            (ids.len(), ids.iter().min_by_key(|s| s.create_time).map(|s| (*s).clone()))
+        };
+        let (username_count, oldest_username) = count(sessions.usernames.get(username));
+        let (tenant, oldest_tenant) =
+            Self::tenant(username).map(|tenant| count(sessions.tenants.get(tenant))).unwrap_or_default();
+        QuotaCount { username: username_count, tenant, oldest_username, oldest_tenant, labels }
+    }
+
+    ///The sessions of the username, of its tenant and of the labels in the cluster, the nodes not
+    ///replying are not counted
+    async fn count(&self, client_id: &ClientId, username: Option<&str>, labels: Vec<Label>) -> QuotaCount {
+        let mut count = self.local_count(client_id, username, &labels);
+        let grpc_clients = Runtime::instance().extends.shared().await.get_grpc_clients();
+        if grpc_clients.is_empty() {
+            return count;
+        }
+        let timeout = Runtime::instance().settings.quota.query_timeout;
+        let name = username.unwrap_or_default();
+        let msg = Message::QuotaCount(client_id.clone(), username.map(UserName::from), labels);
+        let replys = match tokio::time::timeout(
+            timeout,
+            MessageBroadcaster::new(grpc_clients, MESSAGE_TYPE_QUOTA_COUNT, msg).join_all(),
+        )
+        .await
+        {
+            Ok(replys) => replys,
+            Err(_) => {
+                log::warn!("{} quota count, the nodes did not reply in {:?}", name, timeout);
+                return count;
+            }
+        };
+        for (node_id, reply) in replys {
+            match reply {
+                Ok(MessageReply::QuotaCount(c)) => count.merge(c),
+                Ok(r) => {
+                    log::warn!("{} quota count, unexpected reply of node {}, {:?}", name, node_id, r)
+                }
+                Err(e) => log::warn!("{} quota count, node {} error, {:?}", name, node_id, e),
+            }
+        }
+        count
+    }
+
+    ///Checks the quotas of the client and of its labels after its authentication, an error is the
+    ///reason of the refusal
+    pub async fn check(&self, id: &Id, labels: &[Label]) -> Result<()> {
+        let username = id.username.as_deref().filter(|_| Self::enabled());
+        let labels = Self::quota_labels(labels);
+        if username.is_none() && labels.is_empty() {
+            return Ok(());
+        }
+        let cfg = &Runtime::instance().settings.quota;
+        let count = self.count(&id.client_id, username, labels).await;
+        let mut oldests = Vec::new();
+        if cfg.max_sessions_per_username > 0 && count.username >= cfg.max_sessions_per_username {
+            oldests.push((
+                "username".to_owned(),
+                count.username,
+                cfg.max_sessions_per_username,
+                count.oldest_username,
+            ));
+        }
+        if cfg.max_sessions_per_tenant > 0 && count.tenant >= cfg.max_sessions_per_tenant {
+            oldests.push((
+                "tenant".to_owned(),
+                count.tenant,
+                cfg.max_sessions_per_tenant,
+                count.oldest_tenant,
+            ));
+        }
+        for (label, (sessions, oldest)) in count.labels {
+            let max = cfg.max_sessions_per_label.get(&label).copied().unwrap_or_default();
+            if max > 0 && sessions >= max {
+                oldests.push((format!("label {}", label), sessions, max, oldest));
+            }
+        }
+        for (scope, sessions, max, oldest) in oldests {
+            match (cfg.action, oldest) {
+                (QuotaAction::KickOldest, Some(oldest)) => {
+                    log::info!("{:?} {} quota exceeded, {}/{}, kick {:?}", id, scope, sessions, max, oldest);
+                    Runtime::instance().metrics.client_quota_kicked_inc();
+                    let mut entry = Runtime::instance().extends.shared().await.entry(oldest);
+                    entry.kick(true, true, true).await?;
+                }
+                _ => {
+                    Runtime::instance().metrics.client_quota_rejected_inc();
+                    return Err(MqttError::from(format!(
+                        "{} session quota exceeded, {} sessions, maximum {}",
+                        scope, sessions, max
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::QuotaCount;
+    use crate::broker::types::{ClientId, Id};
+
+    #[test]
+    fn test_merge() {
+        let id = Id::new(2, None, None, ClientId::from_static("c1"), None);
+        let mut count = QuotaCount { username: 1, tenant: 2, ..Default::default() };
+        count.labels.insert("canary".into(), (1, None));
+        let mut other =
+            QuotaCount { username: 2, tenant: 3, oldest_username: Some(id), ..Default::default() };
+        other.labels.insert("canary".into(), (2, None));
+        count.merge(other);
+        assert_eq!((count.username, count.tenant), (3, 5));
+        assert_eq!(count.labels["canary"].0, 3);
+        assert_eq!(count.oldest_username.map(|id| id.node_id), Some(2));
+        assert!(count.oldest_tenant.is_none());
+    }
+}
@@ -0,0 +1,192 @@
+//! MQTT 5 request/response, the publisher of a request may be required to be allowed to subscribe
+//! to its response topic, and a response topic prefix may be assigned to the clients in the CONNACK.
+//! The helpers below build requests and responses and correlate them, for the gateway plugins
+//! implementing RPC over MQTT.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use bytestring::ByteString;
+use ntex::util::Bytes;
+use rust_box::std_ext::RwLock;
+use tokio::sync::oneshot;
+
+use crate::broker::types::{
+    timestamp_millis, HashMap, Id, Publish, PublishProperties, QoS, TopicName, UserProperties,
+};
+use crate::settings::listener::Listener;
+use crate::{MqttError, Result, Runtime};
+
+///User property of the CONNACK carrying the assigned response topic prefix
+pub const RESPONSE_TOPIC_PREFIX_PROPERTY: &str = "response-topic-prefix";
+
+///The response topic prefix assigned to the client, None if the listener does not assign one
+#[inline]
+pub fn response_topic_prefix(listen_cfg: &Listener, id: &Id) -> Option<ByteString> {
+    if listen_cfg.response_topic_prefix.is_empty() {
+        return None;
+    }
+    let prefix =
+        listen_cfg.response_topic_prefix.replace("%c", &id.client_id).replace("%u", id.username_ref());
+    Some(ByteString::from(prefix))
+}
+
+///A response topic is a topic name, wildcards are not allowed
+#[inline]
+pub fn is_valid_response_topic(topic: &str) -> bool {
+    !topic.is_empty() && !topic.contains(['+', '#'])
+}
+
+///Builds a request, the responder publishes the response to response_topic with the same
+///correlation data
+#[inline]
+pub fn request(
+    topic: TopicName,
+    payload: Bytes,
+    qos: QoS,
+    response_topic: ByteString,
+    correlation_data: Bytes,
+    user_properties: UserProperties,
+) -> Publish {
+    Publish {
+        dup: false,
+        retain: false,
+        qos,
+        topic,
+        packet_id: None,
+        payload,
+        properties: PublishProperties {
+            response_topic: Some(response_topic),
+            correlation_data: Some(correlation_data),
+            user_properties,
+            ..Default::default()
+        },
+        create_time: timestamp_millis(),
+    }
+}
+
+///Builds the response of the request, None if the request has no response topic
+#[inline]
+pub fn response(request: &Publish, payload: Bytes, user_properties: UserProperties) -> Option<Publish> {
+    let response_topic = request.properties.response_topic.as_ref()?;
+    Some(Publish {
+        dup: false,
+        retain: false,
+        qos: request.qos,
+        topic: response_topic.clone(),
+        packet_id: None,
+        payload,
+        properties: PublishProperties {
+            correlation_data: request.properties.correlation_data.clone(),
+            user_properties,
+            ..Default::default()
+        },
+        create_time: timestamp_millis(),
+    })
+}
+
+///Requests awaiting their responses, keyed by the correlation data
+pub struct PendingRequests {
+    seq: AtomicU64,
+    pendings: RwLock<HashMap<Bytes, oneshot::Sender<Publish>>>,
+}
+
+impl Default for PendingRequests {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PendingRequests {
+    #[inline]
+    pub fn new() -> Self {
+        Self { seq: AtomicU64::new(0), pendings: RwLock::new(HashMap::default()) }
+    }
+
+    ///Registers a request, returns the correlation data to send with it and the receiver of
+    ///the response
+    pub fn register(&self) -> (Bytes, oneshot::Receiver<Publish>) {
+        let seq = self.seq.fetch_add(1, Ordering::SeqCst);
+        let correlation_data =
+            Bytes::from(format!("{}-{}-{}", Runtime::instance().node.id(), timestamp_millis(), seq));
+        let (tx, rx) = oneshot::channel();
+        self.pendings.write().insert(correlation_data.clone(), tx);
+        (correlation_data, rx)
+    }
+
+    ///Hands the response to its request, the response is returned if no request awaits it
+    pub fn complete(&self, response: Publish) -> Option<Publish> {
+        let tx = response
+            .properties
+            .correlation_data
+            .as_ref()
+            .and_then(|correlation_data| self.pendings.write().remove(correlation_data));
+        match tx {
+            Some(tx) => tx.send(response).err(),
+            None => Some(response),
+        }
+    }
+
+    ///Waits for the response, the request is forgotten on timeout
+    pub async fn wait(
+        &self,
+        correlation_data: &Bytes,
+        rx: oneshot::Receiver<Publish>,
+        timeout: Duration,
+    ) -> Result<Publish> {
+        let res = tokio::time::timeout(timeout, rx).await;
+        self.pendings.write().remove(correlation_data);
+        match res {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(_)) => Err(MqttError::from("request canceled")),
+            Err(_) => Err(MqttError::from("request timeout")),
+        }
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.pendings.read().len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.pendings.read().is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ntex::util::Bytes;
+
+    use super::{is_valid_response_topic, request, response};
+    use crate::broker::types::{QoS, UserProperties};
+
+    #[test]
+    fn test_response_of_request() {
+        let req = request(
+            "rpc/echo".into(),
+            Bytes::from_static(b"ping"),
+            QoS::AtLeastOnce,
+            "response/c1/echo".into(),
+            Bytes::from_static(b"1"),
+            UserProperties::default(),
+        );
+        let resp = response(&req, Bytes::from_static(b"pong"), UserProperties::default()).unwrap();
+        assert_eq!(resp.topic, "response/c1/echo");
+        assert_eq!(resp.qos, QoS::AtLeastOnce);
+        assert_eq!(resp.properties.correlation_data, Some(Bytes::from_static(b"1")));
+        assert_eq!(resp.properties.response_topic, None);
+
+        let mut req = req;
+        req.properties.response_topic = None;
+        assert!(response(&req, Bytes::new(), UserProperties::default()).is_none());
+    }
+
+    #[test]
+    fn test_valid_response_topic() {
+        assert!(is_valid_response_topic("response/c1/echo"));
+        assert!(!is_valid_response_topic(""));
+        assert!(!is_valid_response_topic("response/+/echo"));
+        assert!(!is_valid_response_topic("response/#"));
+    }
+}
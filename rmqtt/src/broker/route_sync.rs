@@ -0,0 +1,212 @@
+//! Route table warm-up of a restarted node. Before the listeners are started, the routes of the
+//! sessions of each peer are pulled by pages of topic filters, `RoutesSync`, and added to the local
+//! route table, so that the first publishes of the clients are routed to the whole cluster. The
+//! warm-up may be limited to the topic filters with a prefix.
+//!
+//! A peer serves the routes of its own sessions, a page is a number of topic filters, in their order,
+//! with all their routes. The routes are added to the default router, a route replicated by the
+//! cluster afterwards is not added twice. The progress is logged per page and reported by `to_json`.
+
+use once_cell::sync::OnceCell;
+use rust_box::std_ext::RwLock;
+
+use crate::broker::default::DefaultRouter;
+use crate::broker::types::{timestamp_millis, Id, SubscriptionOptions, TimestampMillis, TopicFilter};
+use crate::broker::Router;
+use crate::grpc::{GrpcClients, Message, MessageReply, MessageSender, MESSAGE_TYPE_ROUTES_SYNC};
+use crate::{MqttError, NodeId, Result, Runtime};
+
+pub type SyncRoute = (TopicFilter, Id, SubscriptionOptions);
+
+#[derive(Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncStatus {
+    #[default]
+    Idle,
+    Running,
+    Completed,
+}
+
+///The progress of the warm-up
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct SyncProgress {
+    pub status: SyncStatus,
+    pub prefix: Option<TopicFilter>,
+    pub started_at: TimestampMillis,
+    pub finished_at: TimestampMillis,
+    ///The peers to pull from, and those pulled completely
+    pub nodes: usize,
+    pub nodes_synced: usize,
+    ///The peers that failed, and the error
+    pub nodes_failed: Vec<(NodeId, String)>,
+    pub pages: usize,
+    ///The routes pulled, and those not in the route table yet
+    pub routes: usize,
+    pub added: usize,
+}
+
+pub struct RouteSync {
+    progress: RwLock<SyncProgress>,
+}
+
+impl RouteSync {
+    #[inline]
+    pub fn instance() -> &'static Self {
+        static INSTANCE: OnceCell<RouteSync> = OnceCell::new();
+        INSTANCE.get_or_init(|| Self { progress: RwLock::new(SyncProgress::default()) })
+    }
+
+    ///A page of the routes of the sessions of this node, at most limit topic filters after the cursor,
+    ///and the cursor of the next page, None for the last one
+    pub fn serve_local(
+        &self,
+        prefix: Option<&str>,
+        after: Option<&TopicFilter>,
+        limit: usize,
+    ) -> (Vec<SyncRoute>, Option<TopicFilter>) {
+        let node_id = Runtime::instance().node.id();
+        let relations = &DefaultRouter::instance().relations;
+        let mut topic_filters = relations
+            .iter()
+            .filter(|entry| {
+                prefix.map(|prefix| entry.key().starts_with(prefix)).unwrap_or(true)
+                    && after.map(|after| entry.key() > after).unwrap_or(true)
+                    && entry.value().values().any(|(id, _)| id.node_id == node_id)
+            })
+            .map(|entry| entry.key().clone())
+            .collect::<Vec<_>>();
+        topic_filters.sort();
+
+        let limit = limit.max(1);
+        let next = if topic_filters.len() > limit {
+            topic_filters.truncate(limit);
+            topic_filters.last().cloned()
+        } else {
+            None
+        };
+
+        let mut routes = Vec::new();
+        for topic_filter in topic_filters {
+            if let Some(rels) = relations.get(&topic_filter) {
+                routes.extend(
+                    rels.values()
+                        .filter(|(id, _)| id.node_id == node_id)
+                        .map(|(id, opts)| (topic_filter.clone(), id.clone(), opts.clone())),
+                );
+            }
+        }
+        (routes, next)
+    }
+
+    ///Pulls the routes of the peers into the route table, the shared subscriptions only if set.
+    ///A peer that fails is skipped and reported. Returns the number of the routes added.
+    pub async fn warmup(
+        &self,
+        grpc_clients: GrpcClients,
+        prefix: Option<TopicFilter>,
+        page_size: usize,
+        shared_only: bool,
+    ) -> usize {
+        *self.progress.write() = SyncProgress {
+            status: SyncStatus::Running,
+            prefix: prefix.clone(),
+            started_at: timestamp_millis(),
+            nodes: grpc_clients.len(),
+            ..Default::default()
+        };
+        log::info!("route warm-up, {} nodes, prefix: {:?}", grpc_clients.len(), prefix);
+
+        for (node_id, (_, grpc_client)) in grpc_clients.iter() {
+            let mut after = None;
+            let res = loop {
+                let msg = Message::RoutesSync(prefix.clone(), after.take(), page_size);
+                let reply =
+                    MessageSender::new(grpc_client.clone(), MESSAGE_TYPE_ROUTES_SYNC, msg).send().await;
+                let (routes, next) = match reply {
+                    Ok(MessageReply::RoutesSync(routes, next)) => (routes, next),
+                    Ok(MessageReply::Error(e)) => break Err(MqttError::from(e)),
+                    Ok(_) => break Err(MqttError::from("unexpected reply")),
+                    Err(e) => break Err(e),
+                };
+                let (received, added) = Self::add_routes(routes, shared_only).await;
+                let (pages, total) = {
+                    let mut progress = self.progress.write();
+                    progress.pages += 1;
+                    progress.routes += received;
+                    progress.added += added;
+                    (progress.pages, progress.routes)
+                };
+                log::debug!(
+                    "route warm-up, node: {}, page: {}, routes: {}, added: {}, total: {}",
+                    node_id,
+                    pages,
+                    received,
+                    added,
+                    total
+                );
+                after = next;
+                if after.is_none() {
+                    break Ok(());
+                }
+            };
+            let mut progress = self.progress.write();
+            match res {
+                Ok(()) => progress.nodes_synced += 1,
+                Err(e) => {
+                    log::warn!("route warm-up, node: {}, error, {:?}", node_id, e);
+                    progress.nodes_failed.push((*node_id, e.to_string()));
+                }
+            }
+        }
+
+        let mut progress = self.progress.write();
+        progress.status = SyncStatus::Completed;
+        progress.finished_at = timestamp_millis();
+        log::info!(
+            "route warm-up completed, nodes: {}/{}, pages: {}, routes: {}, added: {}, cost: {}ms",
+            progress.nodes_synced,
+            progress.nodes,
+            progress.pages,
+            progress.routes,
+            progress.added,
+            progress.finished_at - progress.started_at
+        );
+        progress.added
+    }
+
+    //The number of the routes received and of those added
+    async fn add_routes(routes: Vec<SyncRoute>, shared_only: bool) -> (usize, usize) {
+        let router = DefaultRouter::instance();
+        let node_id = Runtime::instance().node.id();
+        let received = routes.len();
+        let mut added = 0;
+        for (topic_filter, id, opts) in routes {
+            if id.node_id == node_id || (shared_only && opts.shared_group().is_none()) {
+                continue;
+            }
+            let exists = router
+                .relations
+                .get(&topic_filter)
+                .map(|rels| rels.contains_key(&id.client_id))
+                .unwrap_or(false);
+            if exists {
+                continue;
+            }
+            match router.add(&topic_filter, id, opts).await {
+                Ok(()) => added += 1,
+                Err(e) => log::warn!("route warm-up, add {:?} error, {:?}", topic_filter, e),
+            }
+        }
+        (received, added)
+    }
+
+    #[inline]
+    pub fn progress(&self) -> SyncProgress {
+        self.progress.read().clone()
+    }
+
+    #[inline]
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::to_value(self.progress()).unwrap_or_default()
+    }
+}
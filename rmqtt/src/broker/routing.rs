@@ -0,0 +1,146 @@
+//! Routing policies between the listener groups. A delivery is identified by the listener name of
+//! the publisher and the one of the subscriber, e.g. `external` and `internal`, the first rule
+//! matching them and the topic of the publish decides whether the publish is delivered, the
+//! `default` action applies otherwise. The publishes not received by a listener, e.g. those of the
+//! plugins or the admin APIs, only match the rules from "*".
+
+use std::str::FromStr;
+
+use once_cell::sync::OnceCell;
+
+use crate::broker::topic::{Topic, TopicTree};
+use crate::broker::types::{From, Id};
+use crate::settings::RoutingAction;
+use crate::Runtime;
+
+const ANY: &str = "*";
+
+struct Rule {
+    from: String,
+    to: String,
+    topics: Option<TopicTree<()>>,
+    action: RoutingAction,
+}
+
+impl Rule {
+    #[inline]
+    fn is_match(&self, from: Option<&str>, to: Option<&str>, topic: &Topic) -> bool {
+        (self.from == ANY || Some(self.from.as_str()) == from)
+            && (self.to == ANY || Some(self.to.as_str()) == to)
+            && self.topics.as_ref().map(|topics| topics.is_match(topic)).unwrap_or(true)
+    }
+}
+
+pub struct RoutingPolicy {
+    enable: bool,
+    default: RoutingAction,
+    rules: Vec<Rule>,
+}
+
+impl RoutingPolicy {
+    #[inline]
+    pub fn instance() -> &'static Self {
+        static INSTANCE: OnceCell<RoutingPolicy> = OnceCell::new();
+        INSTANCE.get_or_init(|| {
+            let cfg = &Runtime::instance().settings.routing;
+            let rules = cfg
+                .rules
+                .iter()
+                .filter_map(|r| {
+                    let topics = match r.topic.as_ref().map(|tf| Topic::from_str(tf)).transpose() {
+                        Ok(t) => t.map(|t| {
+                            let mut topics = TopicTree::default();
+                            topics.insert(&t, ());
+                            topics
+                        }),
+                        Err(e) => {
+                            log::error!("routing rule {:?}, invalid topic filter, {:?}", r, e);
+                            return None;
+                        }
+                    };
+                    Some(Rule { from: r.from.clone(), to: r.to.clone(), topics, action: r.action })
+                })
+                .collect();
+            Self { enable: cfg.enable, default: cfg.default, rules }
+        })
+    }
+
+    #[inline]
+    fn listener_name(id: &Id) -> Option<String> {
+        let port = id.local_addr?.port();
+        Runtime::instance().settings.listeners.get(port).map(|l| l.name.clone())
+    }
+
+    ///The origin of the publish, its deliveries are checked against the rules, None if the routing
+    ///policies are disabled
+    #[inline]
+    pub fn origin(&'static self, from: &From, topic: &str) -> Option<Origin> {
+        if !self.enable {
+            return None;
+        }
+        Some(Origin { policy: self, from: Self::listener_name(&from.id), topic: Topic::from_str(topic).ok() })
+    }
+
+    #[inline]
+    fn action(&self, from: Option<&str>, to: Option<&str>, topic: &Topic) -> RoutingAction {
+        self.rules.iter().find(|r| r.is_match(from, to, topic)).map(|r| r.action).unwrap_or(self.default)
+    }
+}
+
+pub struct Origin {
+    policy: &'static RoutingPolicy,
+    from: Option<String>,
+    topic: Option<Topic>,
+}
+
+impl Origin {
+    ///Whether the publish may be delivered to the subscriber
+    #[inline]
+    pub fn allowed(&self, to: &Id) -> bool {
+        let action = match self.topic.as_ref() {
+            Some(topic) => {
+                let to = RoutingPolicy::listener_name(to);
+                self.policy.action(self.from.as_deref(), to.as_deref(), topic)
+            }
+            None => self.policy.default,
+        };
+        action == RoutingAction::Allow
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::{RoutingPolicy, Rule};
+    use crate::broker::topic::{Topic, TopicTree};
+    use crate::settings::RoutingAction;
+
+    fn rule(from: &str, to: &str, topic: Option<&str>, action: RoutingAction) -> Rule {
+        let topics = topic.map(|tf| {
+            let mut topics = TopicTree::default();
+            topics.insert(&Topic::from_str(tf).unwrap(), ());
+            topics
+        });
+        Rule { from: from.into(), to: to.into(), topics, action }
+    }
+
+    #[test]
+    fn test_action() {
+        let policy = RoutingPolicy {
+            enable: true,
+            default: RoutingAction::Allow,
+            rules: vec![
+                rule("external", "internal", Some("cmd/#"), RoutingAction::Allow),
+                rule("external", "internal", None, RoutingAction::Deny),
+                rule("*", "audit", None, RoutingAction::Deny),
+            ],
+        };
+        let t = |s: &str| Topic::from_str(s).unwrap();
+        assert_eq!(policy.action(Some("external"), Some("internal"), &t("cmd/1")), RoutingAction::Allow);
+        assert_eq!(policy.action(Some("external"), Some("internal"), &t("data/1")), RoutingAction::Deny);
+        assert_eq!(policy.action(Some("internal"), Some("external"), &t("data/1")), RoutingAction::Allow);
+        assert_eq!(policy.action(None, Some("audit"), &t("data/1")), RoutingAction::Deny);
+        assert_eq!(policy.action(None, Some("internal"), &t("data/1")), RoutingAction::Allow);
+    }
+}
@@ -4,23 +4,42 @@ use std::num::NonZeroU16;
 use std::ops::Deref;
 use std::rc::Rc;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicUsize, Ordering};
 use std::sync::Arc;
 
 #[allow(unused_imports)]
 use bitflags::Flags;
 use bytestring::ByteString;
 use futures::StreamExt;
-use tokio::sync::RwLock;
+use once_cell::sync::Lazy;
+use tokio::sync::{oneshot, Notify, RwLock};
 use tokio::time::{Duration, Instant};
 
 use ntex_mqtt::v5::codec::RetainHandling;
 
+use crate::broker::acl::AclRules;
+use crate::broker::acl_cache::AclCache;
+use crate::broker::audit::{AuditEvent, AuditLog};
+use crate::broker::conformance::Conformance;
+use crate::broker::dedup::Dedup;
 use crate::broker::hook::Hook;
-use crate::broker::inflight::{Inflight, InflightMessage, MomentStatus};
+use crate::broker::inflight::{
+    AdaptivePolicy, Inflight, InflightMessage, MomentStatus, RetryPolicy, Timeout,
+};
+use crate::broker::labels::{Label, Labels};
+use crate::broker::latency;
+use crate::broker::overload::Overload;
+use crate::broker::packet_size::PacketSize;
+use crate::broker::publish_quota::{PublishQuota, QuotaFeedback};
 use crate::broker::queue::{self, Limiter, Policy};
+use crate::broker::quota::Quota;
+use crate::broker::request_response;
+use crate::broker::shared_queue::SharedQueues;
 use crate::broker::types::*;
+use crate::broker::Entry;
 use crate::metrics::Metrics;
 use crate::settings::listener::Listener;
+use crate::settings::ShedAction;
 use crate::{MqttError, Result, Runtime};
 
 #[derive(Clone)]
@@ -32,6 +51,11 @@ pub struct SessionState {
     pub deliver_queue_tx: Option<MessageSender>,
     pub server_topic_aliases: Option<Rc<ServerTopicAliases>>,
     pub client_topic_aliases: Option<Rc<ClientTopicAliases>>,
+    pub dedup: Option<Rc<Dedup>>,
+    ///The maximum packet size checked on the publishes, see packet_size
+    pub max_packet_size: Option<u32>,
+    ///The token bucket of the publishes of the client, see publish_quota
+    pub publish_quota: Option<Rc<PublishQuota>>,
 }
 
 impl fmt::Debug for SessionState {
@@ -67,17 +91,51 @@ impl SessionState {
         };
         log::debug!("server_topic_aliases: {:?}", server_topic_aliases);
         log::debug!("client_topic_aliases: {:?}", client_topic_aliases);
+        Self::with(None, session, Some(sink), hook, server_topic_aliases, client_topic_aliases)
+    }
+
+    //The options, dedup, max_packet_size, publish_quota..., are set by the builder methods
+    #[inline]
+    fn with(
+        tx: Option<Tx>,
+        session: Session,
+        sink: Option<Sink>,
+        hook: Rc<dyn Hook>,
+        server_topic_aliases: Option<Rc<ServerTopicAliases>>,
+        client_topic_aliases: Option<Rc<ClientTopicAliases>>,
+    ) -> Self {
         Self {
-            tx: None,
+            tx,
             session,
-            sink: Some(sink),
+            sink,
             hook,
             deliver_queue_tx: None,
             server_topic_aliases,
             client_topic_aliases,
+            dedup: None,
+            max_packet_size: None,
+            publish_quota: None,
         }
     }
 
+    #[inline]
+    pub(crate) fn dedup(mut self, dedup: Option<Dedup>) -> Self {
+        self.dedup = dedup.map(Rc::new);
+        self
+    }
+
+    #[inline]
+    pub(crate) fn max_packet_size(mut self, max_packet_size: Option<u32>) -> Self {
+        self.max_packet_size = max_packet_size;
+        self
+    }
+
+    #[inline]
+    pub(crate) fn publish_quota(mut self, publish_quota: Option<PublishQuota>) -> Self {
+        self.publish_quota = publish_quota.map(Rc::new);
+        self
+    }
+
     #[inline]
     pub(crate) async fn start(mut self, keep_alive: u16) -> (Self, Tx) {
         log::debug!("{:?} start online event loop", self.id);
@@ -107,6 +165,8 @@ impl SessionState {
 
         ntex::rt::spawn(async move {
             Runtime::instance().stats.connections.inc();
+            Quota::instance().connected(&state.id);
+            Labels::instance().connected(&state.id, state.labels());
 
             let (state, deliver_queue_tx, mut deliver_queue_rx) = state.deliver_queue_channel(&limiter);
 
@@ -178,6 +238,16 @@ impl SessionState {
                                 },
                                 Message::Closed(reason) => {
                                     log::debug!("{:?} Closed({}) message received, reason: {}", state.id, flags.contains(StateFlags::DisconnectReceived), reason);
+                                    match (&reason, state.sink.as_ref()) {
+                                        (Reason::ServerDraining, Some(sink)) => {
+                                            let drain = &Runtime::instance().settings.node.drain;
+                                            sink.close_with_reason(drain.reason.disconnect_code(), drain.server_reference());
+                                        }
+                                        (Reason::ServerRedirect(code, server_reference), Some(sink)) => {
+                                            sink.close_with_reason(code.disconnect_code(), server_reference.clone());
+                                        }
+                                        _ => {}
+                                    }
                                     if !state.disconnected_reason_has().await {
                                         if let Err(e) = state.disconnected_reason_add(reason).await {
                                             log::error!("{:?} disconnected reason add error: {:?}", state.id, e);
@@ -188,6 +258,7 @@ impl SessionState {
                                 Message::Keepalive(ping) => {
                                     log::debug!("{:?} Message::Keepalive ... ", state.id);
                                     keep_alive_delay.as_mut().reset(Instant::now() + keep_alive_interval);
+                                    state.session.touch();
                                     if ping {
                                         flags.insert(StateFlags::Ping);
                                     }
@@ -211,6 +282,9 @@ impl SessionState {
                                     }else{
                                         log::warn!("{:?} Message::Unsubscribe, reply sender is closed", state.id);
                                     }
+                                },
+                                Message::Ping(reply_tx) => {
+                                    let _ = reply_tx.send(());
                                 }
                             }
                         }else{
@@ -223,15 +297,30 @@ impl SessionState {
                     },
 
                     _ = &mut deliver_timeout_delay => {
-                        while let Some(iflt_msg) = state.inflight_win().write().await.pop_front_timeout(){
-                            log::debug!("{:?} has timeout message in inflight: {:?}", state.id, iflt_msg);
-                            if let Err(e) = state.reforward(iflt_msg).await{
-                                log::error!("{:?} redeliver message error, {:?}", state.id, e);
+                        loop {
+                            //the guard is dropped before the redelivery, which takes it again
+                            let timeout = state.inflight_win().write().await.pop_front_timeout();
+                            let timeout = if let Some(timeout) = timeout { timeout } else { break };
+                            match timeout {
+                                Timeout::Retry(iflt_msg) => {
+                                    log::debug!("{:?} has timeout message in inflight: {:?}", state.id, iflt_msg);
+                                    if let Err(e) = state.reforward(iflt_msg).await{
+                                        log::error!("{:?} redeliver message error, {:?}", state.id, e);
+                                    }
+                                }
+                                Timeout::Exhausted(iflt_msg) => {
+                                    log::warn!("{:?} redelivery attempts exhausted, message: {:?}", state.id, iflt_msg.publish);
+                                    Runtime::instance().extends.hook_mgr().await.message_dropped(Some(state.id.clone()), iflt_msg.from, iflt_msg.publish, Reason::MessageRetryExhausted).await;
+                                }
                             }
                         }
                     },
 
-                    deliver_packet = deliver_queue_rx.next(), if state.inflight_win().read().await.has_credit() => {
+                    _ = state.deliveries_released(), if state.deliveries_held() => {
+                        log::debug!("{:?} the deliveries are released", state.id);
+                    },
+
+                    deliver_packet = deliver_queue_rx.next(), if !state.deliveries_held() && state.inflight_win().read().await.has_credit() => {
                         log::debug!("{:?} deliver_packet: {:?}", state.id, deliver_packet);
                         match deliver_packet{
                             Some(Some((from, p))) => {
@@ -272,6 +361,9 @@ impl SessionState {
             );
 
             Runtime::instance().stats.connections.dec();
+            Quota::instance().disconnected(&state.id);
+            Labels::instance().disconnected(&state.id);
+            AclCache::instance().remove(&state.id);
 
             //Setting the disconnected state
             if let Err(e) = state.disconnected_set(None, None).await {
@@ -287,9 +379,14 @@ impl SessionState {
                     }
                     None
                 } else {
+                    state.will_pending(will_delay_interval).await;
                     will_delay_interval
                 }
             } else {
+                if flags.contains(StateFlags::Kicked) && !flags.contains(StateFlags::DisconnectReceived) {
+                    //Taken over by a new connection to this session
+                    state.cancel_last_will().await;
+                }
                 None
             };
 
@@ -316,6 +413,8 @@ impl SessionState {
                 state.clean(state.disconnected_reason_take().await.unwrap_or_default()).await;
             } else {
                 let session_expiry_interval = state.fitter.session_expiry_interval(disconnect.as_ref());
+                //hook, session_store_save, the session goes offline
+                state.hook.session_store_save().await;
                 //hook, offline_inflight_messages
                 let inflight_messages = state.inflight_win().write().await.to_inflight_messages();
                 if !inflight_messages.is_empty() {
@@ -395,6 +494,17 @@ impl SessionState {
                                     if clean_start {
                                         flags.insert(StateFlags::CleanStart);
                                     }
+                                    //The will is still delayed, it is published if the session ends,
+                                    //otherwise the new connection to the session cancels it
+                                    if will_delay_interval.is_some() {
+                                        if clean_start {
+                                            if let Err(e) = state.process_last_will().await {
+                                                log::error!("{:?} process last will error, {:?}", state.id, e);
+                                            }
+                                        } else {
+                                            state.cancel_last_will().await;
+                                        }
+                                    }
                                     break
                                 }else{
                                     log::warn!("{:?} offline Kick sender is closed, to {:?}, clean_start: {}, is_admin: {}", state.id, by_id, clean_start, is_admin);
@@ -437,20 +547,33 @@ impl SessionState {
 
     #[inline]
     pub async fn offline_restart(session: Session, session_expiry_interval: Duration) -> (SessionState, Tx) {
+        Self::_offline_restart(session, session_expiry_interval, None).await
+    }
+
+    ///Rebuilds an offline session stored before a restart of the broker, `pending_will` is the
+    ///remaining delay of its pending will message, None if it has none, it is published at once if
+    ///the delay is over. The will is not decided again from the connect info, it may have been
+    ///published or canceled before the restart.
+    #[inline]
+    pub async fn offline_rebuild(
+        session: Session,
+        session_expiry_interval: Duration,
+        pending_will: Option<Duration>,
+    ) -> (SessionState, Tx) {
+        Self::_offline_restart(session, session_expiry_interval, Some(pending_will)).await
+    }
+
+    async fn _offline_restart(
+        session: Session,
+        session_expiry_interval: Duration,
+        pending_will: Option<Option<Duration>>,
+    ) -> (SessionState, Tx) {
         let hook = Runtime::instance().extends.hook_mgr().await.hook(&session);
 
         let (msg_tx, mut msg_rx) = futures::channel::mpsc::unbounded();
         let msg_tx = SessionTx::new(msg_tx);
 
-        let state = SessionState {
-            tx: Some(msg_tx.clone()),
-            session,
-            sink: None,
-            hook,
-            deliver_queue_tx: None,
-            server_topic_aliases: None,
-            client_topic_aliases: None,
-        };
+        let state = SessionState::with(Some(msg_tx.clone()), session, None, hook, None, None);
 
         let limiter = {
             let (burst, replenish_n_per) = state.fitter.mqueue_rate_limit();
@@ -466,18 +589,30 @@ impl SessionState {
             let clean_session = state.clean_session(disconnect.as_ref()).await;
 
             //Last will message
-            let will_delay_interval = if state.last_will_enable(flags, clean_session) {
-                let will_delay_interval = state.will_delay_interval().await;
-                if clean_session || will_delay_interval.is_none() {
+            let will_delay_interval = match pending_will {
+                Some(Some(will_delay_interval)) if will_delay_interval.is_zero() => {
                     if let Err(e) = state.process_last_will().await {
                         log::error!("{:?} process last will error, {:?}", state.id, e);
                     }
                     None
-                } else {
+                }
+                Some(will_delay_interval) => {
+                    state.will_pending(will_delay_interval).await;
                     will_delay_interval
                 }
-            } else {
-                None
+                None if state.last_will_enable(flags, clean_session) => {
+                    let will_delay_interval = state.will_delay_interval().await;
+                    if clean_session || will_delay_interval.is_none() {
+                        if let Err(e) = state.process_last_will().await {
+                            log::error!("{:?} process last will error, {:?}", state.id, e);
+                        }
+                        None
+                    } else {
+                        state.will_pending(will_delay_interval).await;
+                        will_delay_interval
+                    }
+                }
+                None => None,
             };
 
             Self::offline_start(
@@ -498,6 +633,81 @@ impl SessionState {
         (state1, msg_tx)
     }
 
+    ///Rebuilds a session migrated from another node as an offline session of this node, the
+    ///subscriptions, inflight and queued messages are restored as in a takeover by kick.
+    #[inline]
+    pub async fn migrate_restart(info: SessionMigrateInfo) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        MIGRATE_TX.unbounded_send((info, tx)).map_err(|e| MqttError::from(e.to_string()))?;
+        rx.await.map_err(|e| MqttError::from(e.to_string()))?
+    }
+
+    async fn _migrate_restart(info: SessionMigrateInfo) -> Result<()> {
+        let from = &info.offline_info.id;
+        let id = Id::new(
+            Runtime::instance().node.id(),
+            from.local_addr,
+            from.remote_addr,
+            from.client_id.clone(),
+            from.username.clone(),
+        );
+        let listen_cfg = id
+            .local_addr
+            .and_then(|addr| Runtime::instance().settings.listeners.get(addr.port()))
+            .ok_or_else(|| {
+                MqttError::from(format!("listener config is not found, local addr is {:?}", id.local_addr))
+            })?;
+
+        let mut entry = Runtime::instance().extends.shared().await.entry(id.clone());
+        if entry.session().is_some() {
+            log::warn!("{:?} migrate session, the session already exists, migrated state is dropped", id);
+            return Ok(());
+        }
+
+        let conn_info = Arc::new(info.conn_info);
+        let fitter = Runtime::instance().extends.fitter_mgr().await.create(
+            conn_info.clone(),
+            id.clone(),
+            listen_cfg.clone(),
+        );
+        let disconnect_info = info.disconnect_info.unwrap_or_else(|| DisconnectInfo::new(timestamp_millis()));
+        let session_expiry_interval = fitter
+            .session_expiry_interval(disconnect_info.mqtt_disconnect.as_ref())
+            .as_millis() as TimestampMillis
+            - (timestamp_millis() - disconnect_info.disconnected_at);
+        if session_expiry_interval <= 0 {
+            log::debug!("{:?} migrate session, the session is expired", id);
+            return Ok(());
+        }
+
+        let max_inflight = fitter.max_inflight();
+        let max_mqueue_len = fitter.max_mqueue_len();
+        let session = Session::new(
+            id,
+            max_mqueue_len,
+            listen_cfg,
+            fitter,
+            max_inflight,
+            info.offline_info.created_at,
+            conn_info,
+            false,
+            false,
+            false,
+            info.connected_at,
+            SessionSubs::new(),
+            Some(disconnect_info),
+            None,
+        )
+        .await?;
+
+        let (state, msg_tx) =
+            Self::offline_restart(session.clone(), Duration::from_millis(session_expiry_interval as u64))
+                .await;
+        entry.set(session, msg_tx).await?;
+        state.transfer_session_state(false, info.offline_info).await?;
+        state.session.persist().await
+    }
+
     #[inline]
     #[allow(clippy::type_complexity)]
     fn deliver_queue_channel(
@@ -567,41 +777,114 @@ impl SessionState {
         self.connect_info().await.ok()?.last_will().and_then(|lw| lw.will_delay_interval())
     }
 
+    #[inline]
+    async fn last_will(&self) -> Result<Option<Publish>> {
+        match self.connect_info().await {
+            Ok(conn_info) => conn_info.last_will().map(Publish::try_from).transpose(),
+            Err(_) => Ok(None),
+        }
+    }
+
+    ///Records the due time of the pending will with the session, so that a stored session rebuilt
+    ///after a restart publishes it at that time, None once it is published or canceled
+    #[inline]
+    async fn will_pending(&self, will_delay_interval: Option<Duration>) {
+        let due_at = will_delay_interval
+            .map(|d| chrono::Local::now().timestamp_millis() + d.as_millis() as TimestampMillis);
+        if let Err(e) = self.will_pending_set(due_at).await {
+            log::warn!("{:?} set pending will error, {:?}", self.id, e);
+        }
+    }
+
+    ///The will is not published, the client reconnected within the will delay interval
+    #[inline]
+    async fn cancel_last_will(&self) {
+        self.will_pending(None).await;
+        match self.last_will().await {
+            Ok(Some(p)) => {
+                log::debug!("{:?} last will canceled, publish: {:?}", self.id, p);
+                //hook, will_message_dropped
+                self.hook
+                    .will_message_dropped(
+                        From::from_lastwill(self.id.clone()),
+                        p,
+                        Reason::WillMessageCanceled,
+                    )
+                    .await;
+            }
+            Ok(None) => {}
+            Err(e) => log::warn!("{:?} last will error, {:?}", self.id, e),
+        }
+    }
+
     #[inline]
     async fn process_last_will(&self) -> Result<()> {
-        if let Ok(conn_info) = self.connect_info().await {
-            if let Some(lw) = conn_info.last_will() {
-                let p = Publish::try_from(lw)?;
-                let from = From::from_lastwill(self.id.clone());
-                //hook, message_publish
-                let p = self.hook.message_publish(from.clone(), &p).await.unwrap_or(p);
-                log::debug!("process_last_will, publish: {:?}", p);
+        self.will_pending(None).await;
+        if let Some(p) = self.last_will().await? {
+            let from = From::from_lastwill(self.id.clone());
+            //hook, will_message_publish
+            let p = match self.hook.will_message_publish(from.clone(), p.clone()).await {
+                Some(p) => p,
+                None => {
+                    log::debug!("{:?} last will suppressed, publish: {:?}", self.id, p);
+                    //hook, will_message_dropped
+                    self.hook.will_message_dropped(from, p, Reason::WillMessageSuppressed).await;
+                    return Ok(());
+                }
+            };
+            //hook, message_publish
+            let p = match self.hook.message_publish(from.clone(), &p).await {
+                Ok(modified) => modified.unwrap_or(p),
+                Err(_) => return Ok(()),
+            };
+            log::debug!("process_last_will, publish: {:?}", p);
 
-                let listen_cfg = self.listen_cfg();
+            let listen_cfg = self.listen_cfg();
 
-                let message_storage_available = Runtime::instance().extends.message_mgr().await.enable();
+            let message_storage_available = Runtime::instance().extends.message_mgr().await.enable();
 
-                let message_expiry_interval =
-                    if message_storage_available || (listen_cfg.retain_available && p.retain()) {
-                        Some(self.fitter.message_expiry_interval(&p))
-                    } else {
-                        None
-                    };
+            let message_expiry_interval =
+                if message_storage_available || (listen_cfg.retain_available && p.retain()) {
+                    Some(self.fitter.message_expiry_interval(&p))
+                } else {
+                    None
+                };
 
-                Self::forwards(
-                    from,
-                    p,
-                    listen_cfg.retain_available,
-                    message_storage_available,
-                    message_expiry_interval,
-                )
-                .await?;
-            }
+            Self::forwards(
+                from,
+                p,
+                listen_cfg.retain_available,
+                message_storage_available,
+                message_expiry_interval,
+            )
+            .await?;
         }
 
         Ok(())
     }
 
+    ///The response topic is valid, under the assigned response topic prefix or allowed by the
+    ///subscribe ACL
+    async fn response_topic_allowed(&self, response_topic: &ByteString) -> bool {
+        if !request_response::is_valid_response_topic(response_topic) {
+            return false;
+        }
+        if let Some(prefix) = request_response::response_topic_prefix(self.listen_cfg(), &self.id) {
+            if response_topic.starts_with(&*prefix) {
+                return true;
+            }
+        }
+        let sub = match Subscribe::from_v3(response_topic, QoS::AtMostOnce, false) {
+            Ok(sub) => sub,
+            Err(_) => return false,
+        };
+        //hook, client_subscribe_check_acl
+        match self.hook.client_subscribe_check_acl(&sub).await {
+            Some(acl_result) => acl_result.success().is_some(),
+            None => true,
+        }
+    }
+
     #[inline]
     async fn send_retain_messages(&self, retains: Vec<(TopicName, Retain)>, qos: QoS) -> Result<()> {
         for (topic, mut retain) in retains {
@@ -635,6 +918,40 @@ impl SessionState {
         Ok(())
     }
 
+    //The messages are delivered as the retained ones, queued to the session and acknowledged per
+    //their QoS, at most that of the subscription
+    async fn send_inject_messages(&self, publishes: Vec<Publish>, qos: QoS) {
+        let from = From::from_system(self.id.clone());
+        for mut p in publishes {
+            if p.topic.is_empty() {
+                log::warn!("{:?} injected message without topic, dropped", self.id);
+                continue;
+            }
+            p.dup = false;
+            p.qos = p.qos.less_value(qos);
+            p.packet_id = None;
+            p.create_time = chrono::Local::now().timestamp_millis();
+
+            log::debug!("{:?} inject publish: {:?}", self.id, p);
+
+            if let Err((from, p, reason)) = Runtime::instance()
+                .extends
+                .shared()
+                .await
+                .entry(self.id.clone())
+                .publish(from.clone(), p)
+                .await
+            {
+                Runtime::instance()
+                    .extends
+                    .hook_mgr()
+                    .await
+                    .message_dropped(Some(self.id.clone()), from, p, reason)
+                    .await;
+            }
+        }
+    }
+
     #[inline]
     async fn send_storaged_messages(
         &self,
@@ -643,21 +960,40 @@ impl SessionState {
         group: Option<&SharedGroup>,
         excludeds: Option<Vec<(NodeId, MsgID)>>,
     ) -> Result<()> {
-        let storaged_messages = Runtime::instance()
-            .extends
-            .shared()
-            .await
-            .message_load(&self.id.client_id, topic_filter, group)
-            .await?;
-        log::debug!(
-            "{:?} storaged_messages: {:?}, topic_filter: {}, group: {:?}, excludeds: {:?}",
-            self.id,
-            storaged_messages.len(),
-            topic_filter,
-            group,
-            excludeds
-        );
-        self._send_storaged_messages(storaged_messages, qos, excludeds).await?;
+        //The messages consumed by a shared subscription may be acknowledged, page by page
+        let should_ack = group.is_some() && Runtime::instance().extends.message_mgr().await.should_ack();
+        let mut cursor = MessageCursor::default();
+        while !cursor.is_done() {
+            let storaged_messages = Runtime::instance()
+                .extends
+                .shared()
+                .await
+                .message_load_page(&self.id.client_id, topic_filter, group, &mut cursor)
+                .await?;
+            log::debug!(
+                "{:?} storaged_messages: {:?}, topic_filter: {}, group: {:?}, excludeds: {:?}",
+                self.id,
+                storaged_messages.len(),
+                topic_filter,
+                group,
+                excludeds
+            );
+            let acks = if should_ack {
+                storaged_messages.iter().map(|(node_id, msg_id, _, _)| (*node_id, *msg_id)).collect()
+            } else {
+                Vec::new()
+            };
+            let storaged_messages = storaged_messages
+                .into_iter()
+                .map(|(_, msg_id, from, publish)| (msg_id, from, publish))
+                .collect();
+            self._send_storaged_messages(storaged_messages, qos, excludeds.as_deref()).await?;
+            if !acks.is_empty() {
+                if let Err(e) = Runtime::instance().extends.shared().await.message_ack(acks).await {
+                    log::warn!("{:?} acknowledge the stored messages error, {:?}", self.id, e);
+                }
+            }
+        }
         Ok(())
     }
 
@@ -666,7 +1002,7 @@ impl SessionState {
         &self,
         storaged_messages: Vec<(MsgID, From, Publish)>,
         qos: QoS,
-        excludeds: Option<Vec<(NodeId, MsgID)>>,
+        excludeds: Option<&[(NodeId, MsgID)]>,
     ) -> Result<()> {
         for (msg_id, from, mut publish) in storaged_messages {
             log::debug!(
@@ -720,9 +1056,18 @@ impl SessionState {
             return Err(err);
         };
 
+        //overload, QoS 0 deliveries are deferred
+        Overload::instance().defer_qos0(publish.qos()).await;
+
         //hook, message_expiry_check
         let expiry_check_res = self.hook.message_expiry_check(from.clone(), &publish).await;
         if expiry_check_res.is_expiry() {
+            //an expired redelivery releases the slot kept for it
+            if publish.dup() {
+                if let Some(packet_id) = publish.packet_id() {
+                    self.inflight_win().write().await.remove(&packet_id);
+                }
+            }
             Runtime::instance()
                 .extends
                 .hook_mgr()
@@ -740,7 +1085,10 @@ impl SessionState {
         }
 
         //hook, message_delivered
-        let publish = self.hook.message_delivered(from.clone(), &publish).await.unwrap_or(publish);
+        let mut publish = self.hook.message_delivered(from.clone(), &publish).await.unwrap_or(publish);
+
+        //publish to deliver latency
+        let delivery_latency = if latency::enabled() { Some(latency::record(&mut publish)) } else { None };
 
         //send message
         sink.publish(
@@ -750,6 +1098,11 @@ impl SessionState {
         )
         .await?; //@TODO ... at exception, send hook and or store message
 
+        //hook, message_delivered_latency
+        if let Some(delivery_latency) = delivery_latency {
+            self.hook.message_delivered_latency(from.clone(), &publish, delivery_latency).await;
+        }
+
         //cache messages to inflight window
         let moment_status = match publish.qos() {
             QoS::AtLeastOnce => Some(MomentStatus::UnAck),
@@ -766,13 +1119,15 @@ impl SessionState {
     #[inline]
     pub async fn reforward(&self, mut iflt_msg: InflightMessage) -> Result<()> {
         match iflt_msg.status {
-            MomentStatus::UnAck => {
+            MomentStatus::UnAck | MomentStatus::UnReceived => {
                 iflt_msg.publish.set_dup(true);
-                self.forward(iflt_msg.from, iflt_msg.publish).await;
-            }
-            MomentStatus::UnReceived => {
-                iflt_msg.publish.set_dup(true);
-                self.forward(iflt_msg.from, iflt_msg.publish).await;
+                //The redelivery is sent at once, not queued behind the window its slot is kept in,
+                //it replaces the slot with the same packet id
+                if self.sink.is_some() {
+                    self.deliver(iflt_msg.from, iflt_msg.publish).await?;
+                } else {
+                    self.forward(iflt_msg.from, iflt_msg.publish).await;
+                }
             }
             MomentStatus::UnComplete => {
                 let expiry_check_res =
@@ -785,6 +1140,9 @@ impl SessionState {
                         iflt_msg.from,
                         iflt_msg.publish
                     );
+                    if let Some(packet_id) = iflt_msg.publish.packet_id() {
+                        self.inflight_win().write().await.remove(&packet_id);
+                    }
                     return Ok(());
                 }
 
@@ -806,6 +1164,7 @@ impl SessionState {
                 let release_packet = match sink {
                     Sink::V3(_) => iflt_msg.release_packet_v3(),
                     Sink::V5(_) => iflt_msg.release_packet_v5(),
+                    Sink::Gateway(_) => iflt_msg.release_packet_v3(),
                 };
                 if let Some(release_packet) = release_packet {
                     sink.send(release_packet)?;
@@ -844,20 +1203,49 @@ impl SessionState {
         ret
     }
 
-    #[inline]
-    async fn _subscribe(&self, mut sub: Subscribe) -> Result<SubscribeReturn> {
+    ///Checks the subscription limits of the listener, or of the AuthInfo returned by the auth
+    ///plugins, a resubscription to an existing topic filter is not counted
+    async fn check_subscribe_limits(&self, sub: &Subscribe) -> Result<Option<SubscribeAckReason>> {
         let listen_cfg = self.listen_cfg();
+        let auth_info = self.auth_info().await.unwrap_or_default();
+        let max_subscriptions = auth_info.max_subscriptions.unwrap_or(listen_cfg.max_subscriptions);
+        let max_wildcards =
+            auth_info.max_wildcard_subscriptions.unwrap_or(listen_cfg.max_wildcard_subscriptions);
+        let max_multilevels = auth_info
+            .max_multilevel_wildcard_subscriptions
+            .unwrap_or(listen_cfg.max_multilevel_wildcard_subscriptions);
+        let max_topic_levels = auth_info.max_topic_levels.unwrap_or(listen_cfg.max_topic_levels);
+
+        if max_topic_levels > 0 && Topic::from_str(&sub.topic_filter)?.len() > max_topic_levels {
+            return Ok(Some(SubscribeAckReason::TopicFilterInvalid));
+        }
 
-        if listen_cfg.max_subscriptions > 0
-            && (self.subscriptions().await?.len().await >= listen_cfg.max_subscriptions)
-        {
-            return Err(MqttError::TooManySubscriptions);
+        let subs = self.subscriptions().await?;
+        if subs.read().await.contains_key(&sub.topic_filter) {
+            return Ok(None);
         }
+        if max_subscriptions > 0 && subs.len().await >= max_subscriptions {
+            return Ok(Some(SubscribeAckReason::QuotaExceeded));
+        }
+        let is_wildcard = sub.topic_filter.contains(['+', '#']);
+        if is_wildcard && (max_wildcards > 0 || max_multilevels > 0) {
+            let (wildcards, multilevels) = subs.wildcard_len().await;
+            if (max_wildcards > 0 && wildcards >= max_wildcards)
+                || (max_multilevels > 0 && sub.topic_filter.contains('#') && multilevels >= max_multilevels)
+            {
+                return Ok(Some(SubscribeAckReason::QuotaExceeded));
+            }
+        }
+        Ok(None)
+    }
 
-        if listen_cfg.max_topic_levels > 0
-            && Topic::from_str(&sub.topic_filter)?.len() > listen_cfg.max_topic_levels
-        {
-            return Err(MqttError::TooManyTopicLevels);
+    #[inline]
+    async fn _subscribe(&self, mut sub: Subscribe) -> Result<SubscribeReturn> {
+        let listen_cfg = self.listen_cfg();
+
+        if let Some(ack_reason) = self.check_subscribe_limits(&sub).await? {
+            log::debug!("{:?} subscribe limits exceeded, {:?}, {:?}", self.id, sub.topic_filter, ack_reason);
+            return Ok(SubscribeReturn::new_failure(ack_reason));
         }
 
         sub.opts.set_qos(sub.opts.qos().less_value(listen_cfg.max_qos_allowed));
@@ -877,6 +1265,13 @@ impl SessionState {
             if let Some(qos) = acl_result.success() {
                 sub.opts.set_qos(sub.opts.qos().less_value(qos))
             } else {
+                AuditLog::instance()
+                    .record(AuditEvent::AclDenied {
+                        id: self.id.clone(),
+                        action: "subscribe".into(),
+                        topic: sub.topic_filter.to_string(),
+                    })
+                    .await;
                 return Ok(acl_result);
             }
         }
@@ -888,30 +1283,31 @@ impl SessionState {
         if let Some(qos) = sub_ret.success() {
             //send retain messages
             let excludeds = if listen_cfg.retain_available {
-                //MQTT V5: Retain Handling
-                let send_retain_enable = match sub.opts.retain_handling() {
-                    Some(RetainHandling::AtSubscribe) => true,
-                    Some(RetainHandling::AtSubscribeNew) => sub_ret.prev_opts.is_none(),
-                    Some(RetainHandling::NoAtSubscribe) => false,
-                    None => true, //MQTT V3
-                };
+                //Retain Handling, MQTT V3 by the listener
+                let send_retain_enable =
+                    match sub.opts.retain_handling().unwrap_or(listen_cfg.retain_handling_v3) {
+                        RetainHandling::AtSubscribe => true,
+                        RetainHandling::AtSubscribeNew => sub_ret.prev_opts.is_none(),
+                        RetainHandling::NoAtSubscribe => false,
+                    };
                 log::debug!(
                     "send_retain_enable: {}, sub_ret.prev_opts: {:?}",
                     send_retain_enable,
                     sub_ret.prev_opts
                 );
-                let excludeds = if send_retain_enable {
-                    let retain_messages =
-                        Runtime::instance().extends.retain().await.get(&sub.topic_filter).await?;
-                    let excludeds = retain_messages
-                        .iter()
-                        .filter_map(|(_, r)| r.msg_id.map(|msg_id| (r.from.node_id, msg_id)))
-                        .collect::<Vec<_>>();
-                    self.send_retain_messages(retain_messages, qos).await?;
-                    excludeds
-                } else {
-                    Vec::new()
-                };
+                let excludeds =
+                    if send_retain_enable && !Overload::instance().is_shedding(ShedAction::PauseRetain) {
+                        let retain_messages =
+                            Runtime::instance().extends.retain().await.get(&sub.topic_filter).await?;
+                        let excludeds = retain_messages
+                            .iter()
+                            .filter_map(|(_, r)| r.msg_id.map(|msg_id| (r.from.node_id, msg_id)))
+                            .collect::<Vec<_>>();
+                        self.send_retain_messages(retain_messages, qos).await?;
+                        excludeds
+                    } else {
+                        Vec::new()
+                    };
 
                 log::debug!("{:?} excludeds: {:?}", self.id, excludeds);
                 Some(excludeds)
@@ -925,8 +1321,24 @@ impl SessionState {
                     .await?;
             }
 
+            //the messages queued of the shared group while no member was online
+            if let Some(group) = sub.opts.shared_group().filter(|g| SharedQueues::enabled(g)) {
+                SharedQueues::instance().replay(sub.topic_filter.clone(), group.clone());
+            }
+
+            //hook, session_subscribed_inject, the messages of the plugins for the new subscription
+            let injects = self.hook.session_subscribed_inject(&sub).await;
+            if !injects.is_empty() {
+                self.send_inject_messages(injects, qos).await;
+            }
+
             //hook, session_subscribed
             self.hook.session_subscribed(sub).await;
+
+            //hook, session_store_save
+            if !self.clean_session(None).await {
+                self.hook.session_store_save().await;
+            }
         }
 
         Ok(sub_ret)
@@ -946,13 +1358,24 @@ impl SessionState {
         if ok {
             //hook, session_unsubscribed
             self.hook.session_unsubscribed(unsub).await;
+
+            //hook, session_store_save
+            if !self.clean_session(None).await {
+                self.hook.session_store_save().await;
+            }
         }
         Ok(())
     }
 
     #[inline]
     pub async fn publish_v3(&self, publish: &v3::Publish) -> Result<bool> {
-        match self.publish(Publish::from(publish)).await {
+        let p = Publish::from(publish);
+        //the publish over the quota of a MQTT 3.1.1 client is acknowledged and dropped
+        if self.publish_quota_check().map(|f| f.exceeded).unwrap_or(false) {
+            self.publish_quota_exceeded(p).await;
+            return Ok(true);
+        }
+        match self.publish(p).await {
             Err(e) => {
                 Metrics::instance().client_publish_error_inc();
                 if let Err(e) =
@@ -970,8 +1393,9 @@ impl SessionState {
         }
     }
 
+    ///The quota feedback is the ack of the publish, see publish_quota
     #[inline]
-    pub async fn publish_v5(&self, publish: &v5::Publish) -> Result<bool> {
+    pub async fn publish_v5(&self, publish: &v5::Publish) -> Result<(bool, Option<QuotaFeedback>)> {
         match self._publish_v5(publish).await {
             Err(e) => {
                 Metrics::instance().client_publish_error_inc();
@@ -982,36 +1406,112 @@ impl SessionState {
                 }
                 Err(e)
             }
-            Ok(false) => {
+            Ok((false, feedback)) => {
                 Metrics::instance().client_publish_error_inc();
-                Ok(false)
+                Ok((false, feedback))
             }
-            Ok(true) => Ok(true),
+            Ok((true, feedback)) => Ok((true, feedback)),
         }
     }
 
     #[inline]
-    async fn _publish_v5(&self, publish: &v5::Publish) -> Result<bool> {
+    async fn _publish_v5(&self, publish: &v5::Publish) -> Result<(bool, Option<QuotaFeedback>)> {
         log::debug!("{:?} publish: {:?}", self.id, publish);
         let mut p = Publish::from(publish);
         if let Some(client_topic_aliases) = &self.client_topic_aliases {
             p.topic = client_topic_aliases.set_and_get(p.properties.topic_alias, p.topic).await?;
         }
-        self.publish(p).await
+        let feedback = self.publish_quota_check();
+        if feedback.as_ref().map(|f| f.exceeded).unwrap_or(false) {
+            self.publish_quota_exceeded(p).await;
+            return Ok((true, feedback));
+        }
+        self.publish(p).await.map(|ok| (ok, feedback))
+    }
+
+    //Takes a token of the publish quota, None if accepted without warning
+    #[inline]
+    fn publish_quota_check(&self) -> Option<QuotaFeedback> {
+        let feedback = self.publish_quota.as_ref().and_then(|quota| quota.check());
+        match feedback.as_ref() {
+            Some(f) if f.exceeded => Metrics::instance().client_publish_quota_exceeded_inc(),
+            Some(_) => Metrics::instance().client_publish_quota_warned_inc(),
+            None => {}
+        }
+        feedback
+    }
+
+    #[inline]
+    async fn publish_quota_exceeded(&self, publish: Publish) {
+        log::debug!("{:?} publish quota exceeded, topic: {}", self.id, publish.topic);
+        let from = From::from_custom(self.id.clone());
+        //hook, Message dropped
+        Runtime::instance()
+            .extends
+            .hook_mgr()
+            .await
+            .message_dropped(None, from, publish, Reason::from_static("Publish quota exceeded"))
+            .await;
     }
 
     #[inline]
-    async fn publish(&self, publish: Publish) -> Result<bool> {
+    pub(crate) async fn publish(&self, publish: Publish) -> Result<bool> {
         let from = From::from_custom(self.id.clone());
 
-        //hook, message_publish
-        let publish = self.hook.message_publish(from.clone(), &publish).await.unwrap_or(publish);
+        if self.listen_cfg().strict_conformance {
+            Conformance::instance().check_publish(self, &publish).await?;
+        }
+
+        if let Some(max_packet_size) = self.max_packet_size {
+            PacketSize::instance().check_publish(self, max_packet_size, &publish).await?;
+        }
+
+        //dedup, the duplicate is acknowledged and dropped
+        if let Some(dedup) = self.dedup.as_ref() {
+            if dedup.is_duplicate(&publish) {
+                log::debug!("{:?} duplicate message dropped, topic: {}", self.id, publish.topic);
+                Runtime::instance().stats.dedup_hits.inc();
+                //hook, Message dropped
+                Runtime::instance()
+                    .extends
+                    .hook_mgr()
+                    .await
+                    .message_dropped(None, from, publish, Reason::MessageDuplicate)
+                    .await;
+                return Ok(true);
+            }
+        }
+
+        //hook, message_publish, the message dropped by a handler is acknowledged, or refused as by the ACL
+        //check
+        let publish = match self.hook.message_publish(from.clone(), &publish).await {
+            Ok(modified) => modified.unwrap_or(publish),
+            Err(PublishDrop { refused: None, .. }) => return Ok(true),
+            Err(PublishDrop { refused: Some(disconnect), reason }) => {
+                Metrics::instance().client_publish_auth_error_inc();
+                return if disconnect {
+                    Err(MqttError::from(format!(
+                        "Publish Refused, reason: hook::message_publish() -> Dropped(Disconnect), {:?}",
+                        reason
+                    )))
+                } else {
+                    Ok(false)
+                };
+            }
+        };
 
         //hook, message_publish_check_acl
         let acl_result = self.hook.message_publish_check_acl(&publish).await;
         log::debug!("{:?} acl_result: {:?}", self.id, acl_result);
         if let PublishAclResult::Rejected(disconnect) = acl_result {
             Metrics::instance().client_publish_auth_error_inc();
+            AuditLog::instance()
+                .record(AuditEvent::AclDenied {
+                    id: self.id.clone(),
+                    action: "publish".into(),
+                    topic: publish.topic.to_string(),
+                })
+                .await;
             //hook, Message dropped
             Runtime::instance()
                 .extends
@@ -1030,6 +1530,24 @@ impl SessionState {
 
         let listen_cfg = self.listen_cfg();
 
+        //MQTT 5 request, the publisher must be allowed to subscribe to the response topic
+        if listen_cfg.response_topic_acl {
+            if let Some(response_topic) = publish.properties.response_topic.as_ref() {
+                if !self.response_topic_allowed(response_topic).await {
+                    log::debug!("{:?} response topic refused, {}", self.id, response_topic);
+                    Metrics::instance().client_publish_auth_error_inc();
+                    //hook, Message dropped
+                    Runtime::instance()
+                        .extends
+                        .hook_mgr()
+                        .await
+                        .message_dropped(None, from, publish, Reason::PublishRefused)
+                        .await;
+                    return Ok(false);
+                }
+            }
+        }
+
         let message_storage_available = Runtime::instance().extends.message_mgr().await.enable();
 
         let message_expiry_interval =
@@ -1164,6 +1682,9 @@ impl SessionState {
         //hook, session terminated
         self.hook.session_terminated(reason).await;
 
+        //hook, session_store_remove
+        self.hook.session_store_remove().await;
+
         //clear session, and unsubscribe
         let mut entry = Runtime::instance().extends.shared().await.entry(self.id.clone());
         if let Some(true) = entry.id_same() {
@@ -1209,27 +1730,87 @@ impl SessionState {
             }
         }
 
+        //Shared groups whose messages queued while no member was online are replayed
+        let shared_groups = if clear_subscriptions {
+            Vec::new()
+        } else {
+            offline_info
+                .subscriptions
+                .iter()
+                .filter_map(|(tf, opts)| opts.shared_group().map(|group| (tf.clone(), group.clone())))
+                .filter(|(_, group)| SharedQueues::enabled(group))
+                .collect::<Vec<_>>()
+        };
+
         //Subscription transfer from previous session
         if !clear_subscriptions {
             self.subscriptions_extend(offline_info.subscriptions).await?;
         }
 
-        //Send previous session unacked messages
-        while let Some(msg) = offline_info.inflight_messages.pop() {
-            if !matches!(msg.status, MomentStatus::UnComplete) {
-                if let Err(e) = self.reforward(msg).await {
-                    log::warn!("transfer_session_state, reforward error, {:?}", e);
-                }
+        //The retransmission to a connected client is paced
+        let mut pacer = if self.sink.is_some() { Some(ResumePacer::new(self.listen_cfg())) } else { None };
+        //The messages are delivered at once, within the window, ahead of the messages held in the deliver
+        //queue, see hold_deliveries
+        let mut held = self.sink.is_some() && self.deliveries_held();
+
+        //Send previous session unacked messages, in their order and with their packet ids. A QoS 2
+        //message awaiting PUBREC is republished, one awaiting PUBCOMP has its PUBREL resent.
+        for msg in offline_info.inflight_messages.drain(..) {
+            if matches!(msg.status, MomentStatus::UnComplete) && self.sink.is_none() {
+                //Not connected, the PUBREL is resent when the client resumes
+                self.inflight_win().write().await.push_back(msg);
+                continue;
+            }
+            if let Some(pacer) = pacer.as_mut() {
+                pacer.wait(self).await;
+            }
+            if held && !self.wait_credit().await {
+                held = false;
+            }
+            if let Err(e) = self.reforward(msg).await {
+                log::warn!("transfer_session_state, reforward error, {:?}", e);
             }
         }
 
         //Send offline messages
         while let Some((from, p)) = offline_info.offline_messages.pop() {
-            self.forward(from, p).await;
+            if let Some(pacer) = pacer.as_mut() {
+                pacer.wait(self).await;
+            }
+            if held && !self.wait_credit().await {
+                held = false;
+            }
+            if held {
+                if let Err(e) = self.deliver(from, p).await {
+                    log::warn!("transfer_session_state, deliver error, {:?}", e);
+                }
+            } else {
+                self.forward(from, p).await;
+            }
+        }
+
+        for (tf, group) in shared_groups {
+            SharedQueues::instance().replay(tf, group);
         }
         Ok(())
     }
 
+    //Waits for a free slot of the inflight window, the slots of the expired messages are released
+    //without an acknowledgement. False if the client is disconnected meanwhile.
+    async fn wait_credit(&self) -> bool {
+        loop {
+            let notified = self.ack_notify.notified();
+            if self.inflight_win().read().await.has_credit() {
+                return true;
+            }
+            if tokio::time::timeout(self.listen_cfg().message_retry_interval, notified).await.is_err()
+                && !self.connected().await.unwrap_or_default()
+            {
+                return false;
+            }
+        }
+    }
+
     #[inline]
     async fn clean_session(&self, d: Option<&Disconnect>) -> bool {
         let connect_info = self.connect_info().await;
@@ -1245,6 +1826,59 @@ impl SessionState {
     }
 }
 
+///Paces the retransmission of the previous session messages after a reconnection
+struct ResumePacer {
+    delay: Option<Duration>,
+    interval: Option<Duration>,
+    //waits for the first acknowledgement, at most the timeout
+    first_ack_timeout: Option<Duration>,
+    //the acknowledgements before the first message, one of an earlier message is not waited for
+    acks: usize,
+    sent: usize,
+    next: Instant,
+}
+
+impl ResumePacer {
+    #[inline]
+    fn new(listen_cfg: &Listener) -> Self {
+        Self {
+            delay: Some(listen_cfg.resume_delay).filter(|d| !d.is_zero()),
+            interval: Some(listen_cfg.resume_rate).filter(|r| *r > 0).map(|r| Duration::from_secs(1) / r),
+            first_ack_timeout: if listen_cfg.resume_wait_first_ack {
+                Some(listen_cfg.message_retry_interval)
+            } else {
+                None
+            },
+            acks: 0,
+            sent: 0,
+            next: Instant::now(),
+        }
+    }
+
+    async fn wait(&mut self, s: &SessionState) {
+        if let Some(delay) = self.delay.take() {
+            tokio::time::sleep(delay).await;
+            self.next = Instant::now();
+        }
+        if self.sent == 0 {
+            self.acks = s.acks.load(Ordering::SeqCst);
+        }
+        if self.sent == 1 {
+            if let Some(timeout) = self.first_ack_timeout.take() {
+                if !s.wait_acked(self.acks, timeout).await {
+                    log::debug!("{:?} resume, the first message is not acknowledged in {:?}", s.id, timeout);
+                }
+                self.next = Instant::now();
+            }
+        }
+        if let Some(interval) = self.interval {
+            tokio::time::sleep_until(self.next).await;
+            self.next = self.next.max(Instant::now()) + interval;
+        }
+        self.sent += 1;
+    }
+}
+
 impl Deref for SessionState {
     type Target = Session;
     #[inline]
@@ -1275,6 +1909,135 @@ impl std::fmt::Debug for SessionOfflineInfo {
     }
 }
 
+///Session state transferred to another node by the session migration
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SessionMigrateInfo {
+    pub conn_info: ConnectInfo,
+    pub connected_at: TimestampMillis,
+    pub disconnect_info: Option<DisconnectInfo>,
+    pub offline_info: SessionOfflineInfo,
+}
+
+impl SessionMigrateInfo {
+    ///Takes the session out of this node, a connected client is kicked
+    #[inline]
+    pub async fn take(entry: &mut dyn Entry) -> Result<Option<Self>> {
+        let s = if let Some(s) = entry.session() {
+            s
+        } else {
+            return Ok(None);
+        };
+        let conn_info = s.connect_info().await?.as_ref().clone();
+        let connected_at = s.connected_at().await?;
+        let disconnect_info = if s.connected().await? {
+            None
+        } else {
+            Some(DisconnectInfo {
+                disconnected_at: s.disconnected_at().await?,
+                reasons: s.disconnected_reasons().await?,
+                mqtt_disconnect: s.disconnect().await?,
+            })
+        };
+        drop(s);
+        Ok(entry.kick(false, true, true).await?.map(|offline_info| Self {
+            conn_info,
+            connected_at,
+            disconnect_info,
+            offline_info,
+        }))
+    }
+}
+
+///Session state passed to the session store hooks, for the plugins persisting the sessions. The
+///queued messages are not included, they are passed by the offline_message hook.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SessionSnapshot {
+    pub id: Id,
+    pub conn_info: ConnectInfo,
+    pub created_at: TimestampMillis,
+    pub connected_at: TimestampMillis,
+    pub disconnect_info: Option<DisconnectInfo>,
+    pub subscriptions: Subscriptions,
+    pub inflight_messages: Vec<InflightMessage>,
+}
+
+impl SessionSnapshot {
+    ///Snapshot of the session, it is left unchanged
+    pub async fn new(s: &Session) -> Result<Self> {
+        let disconnect_info = if s.connected().await? {
+            None
+        } else {
+            Some(DisconnectInfo {
+                disconnected_at: s.disconnected_at().await?,
+                reasons: s.disconnected_reasons().await?,
+                mqtt_disconnect: s.disconnect().await?,
+            })
+        };
+        let subscriptions = s
+            .subscriptions()
+            .await?
+            .read()
+            .await
+            .iter()
+            .map(|(tf, opts)| (tf.clone(), opts.clone()))
+            .collect();
+        let inflight_messages = s.inflight_win().read().await.iter().map(|(_, m)| m.clone()).collect();
+        Ok(Self {
+            id: s.id.clone(),
+            conn_info: s.connect_info().await?.as_ref().clone(),
+            created_at: s.created_at().await?,
+            connected_at: s.connected_at().await?,
+            disconnect_info,
+            subscriptions,
+            inflight_messages,
+        })
+    }
+
+    #[inline]
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(self).map_err(anyhow::Error::new)?)
+    }
+
+    #[inline]
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        Ok(bincode::deserialize::<Self>(data).map_err(anyhow::Error::new)?)
+    }
+
+    ///The state taken over by the new connection of the client
+    #[inline]
+    pub fn into_offline_info(self) -> SessionOfflineInfo {
+        SessionOfflineInfo {
+            id: self.id,
+            subscriptions: self.subscriptions,
+            offline_messages: Vec::new(),
+            inflight_messages: self.inflight_messages,
+            created_at: self.created_at,
+        }
+    }
+}
+
+type MigrateChanType = (SessionMigrateInfo, oneshot::Sender<Result<()>>);
+
+//The offline session worker is not Send, migrated sessions are rebuilt on a local runtime
+//Key of the AuthInfo in Session::extra_attrs
+const AUTH_INFO_KEY: &str = "auth_info";
+//Key of the compiled AuthInfo::rules in Session::extra_attrs
+const ACL_RULES_KEY: &str = "acl_rules";
+
+static MIGRATE_TX: Lazy<futures::channel::mpsc::UnboundedSender<MigrateChanType>> = Lazy::new(|| {
+    let (tx, mut rx) = futures::channel::mpsc::unbounded::<MigrateChanType>();
+    std::thread::spawn(move || {
+        let local_rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let local_set = tokio::task::LocalSet::new();
+        local_set.block_on(&local_rt, async {
+            while let Some((info, res_tx)) = rx.next().await {
+                let _ = res_tx.send(SessionState::_migrate_restart(info).await);
+            }
+        });
+    });
+    tx
+});
+
 #[derive(Clone)]
 pub struct Session(Arc<_Session>);
 
@@ -1291,6 +2054,19 @@ pub struct _Session {
     pub id: Id,
     pub fitter: FitterType,
     pub extra_attrs: RwLock<ExtraAttrs>,
+    //Time of the last packet received from the client
+    last_active: AtomicI64,
+    //Number of the acknowledgements of the client, the waiters are notified of each one, paces the
+    //resume after a reconnection
+    acks: AtomicUsize,
+    ack_notify: Notify,
+    //The deliveries from the deliver queue are held while the previous session is resumed
+    deliveries_held: AtomicBool,
+    deliveries_released: Notify,
+    //Authenticated as an anonymous client, see anonymous
+    anonymous: AtomicBool,
+    //Labels of the session, see labels
+    labels: rust_box::std_ext::RwLock<Vec<Label>>,
 }
 
 impl Deref for _Session {
@@ -1304,6 +2080,9 @@ impl Deref for _Session {
 impl Drop for _Session {
     fn drop(&mut self) {
         Runtime::instance().stats.sessions.dec();
+        if self.anonymous.load(Ordering::SeqCst) {
+            Runtime::instance().stats.sessions_anonymous.dec();
+        }
         let id = self.id.clone();
         let s = self.inner.clone();
         tokio::spawn(async move {
@@ -1314,6 +2093,17 @@ impl Drop for _Session {
     }
 }
 
+///Releases the deliveries held by Session::hold_deliveries
+pub(crate) struct DeliveriesHold(Session);
+
+impl Drop for DeliveriesHold {
+    fn drop(&mut self) {
+        self.0.deliveries_held.store(false, Ordering::SeqCst);
+        //the permit is kept if the session loop is not waiting yet
+        self.0.deliveries_released.notify_one();
+    }
+}
+
 impl std::fmt::Debug for Session {
     #[inline]
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -1354,6 +2144,8 @@ impl Session {
             Runtime::instance().stats.message_queues.dec();
         });
         let out_inflight = Inflight::new(max_inflight, message_retry_interval, message_expiry_interval)
+            .retry_policy(RetryPolicy::from_listener(&listen_cfg))
+            .adaptive(AdaptivePolicy::from_listener(&listen_cfg))
             .on_push(|| {
                 Runtime::instance().stats.out_inflights.inc();
             })
@@ -1387,7 +2179,125 @@ impl Session {
                 last_id,
             )
             .await?;
-        Ok(Self(Arc::new(_Session { inner: session_like, id, fitter, extra_attrs })))
+        Ok(Self(Arc::new(_Session {
+            inner: session_like,
+            id,
+            fitter,
+            extra_attrs,
+            last_active: AtomicI64::new(timestamp_millis()),
+            acks: AtomicUsize::new(0),
+            ack_notify: Notify::new(),
+            deliveries_held: AtomicBool::new(false),
+            deliveries_released: Notify::new(),
+            anonymous: AtomicBool::new(false),
+            labels: rust_box::std_ext::RwLock::new(Vec::new()),
+        })))
+    }
+
+    #[inline]
+    pub fn last_active(&self) -> TimestampMillis {
+        self.last_active.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub(crate) fn touch(&self) {
+        self.last_active.store(timestamp_millis(), Ordering::Relaxed);
+    }
+
+    ///The client acknowledged a message, PUBACK, PUBREC or PUBCOMP, after its inflight slot is updated
+    #[inline]
+    pub(crate) fn acked(&self) {
+        self.acks.fetch_add(1, Ordering::SeqCst);
+        self.ack_notify.notify_waiters();
+    }
+
+    ///Waits for an acknowledgement after the given number of acknowledgements, false if timed out
+    async fn wait_acked(&self, acks: usize, timeout: Duration) -> bool {
+        tokio::time::timeout(timeout, async {
+            loop {
+                //created before the check, the future is notified of any later acknowledgement
+                let notified = self.ack_notify.notified();
+                if self.acks.load(Ordering::SeqCst) > acks {
+                    break;
+                }
+                notified.await;
+            }
+        })
+        .await
+        .is_ok()
+    }
+
+    ///Holds the deliveries from the deliver queue until the guard is dropped, the messages of the
+    ///previous session are resumed ahead of the messages routed meanwhile
+    #[inline]
+    pub(crate) fn hold_deliveries(&self) -> DeliveriesHold {
+        self.deliveries_held.store(true, Ordering::SeqCst);
+        DeliveriesHold(self.clone())
+    }
+
+    #[inline]
+    pub(crate) fn deliveries_held(&self) -> bool {
+        self.deliveries_held.load(Ordering::SeqCst)
+    }
+
+    #[inline]
+    async fn deliveries_released(&self) {
+        self.deliveries_released.notified().await
+    }
+
+    ///The limits returned by the auth plugins, kept in extra_attrs
+    #[inline]
+    pub async fn auth_info(&self) -> Option<AuthInfo> {
+        self.extra_attrs.read().await.get::<AuthInfo>(AUTH_INFO_KEY).cloned()
+    }
+
+    #[inline]
+    pub async fn set_auth_info(&self, auth_info: AuthInfo) {
+        if auth_info.anonymous && !self.anonymous.swap(true, Ordering::SeqCst) {
+            Runtime::instance().stats.sessions_anonymous.inc();
+        }
+        let mut extra_attrs = self.extra_attrs.write().await;
+        if !auth_info.rules.is_empty() {
+            let username = self.id.username.as_ref().map(|u| u.as_ref());
+            let rules = AclRules::compile(&auth_info.rules, &self.id.client_id, username);
+            extra_attrs.insert(ACL_RULES_KEY.into(), Arc::new(rules));
+        } else {
+            extra_attrs.remove(ACL_RULES_KEY);
+        }
+        extra_attrs.insert(AUTH_INFO_KEY.into(), auth_info);
+        //The cached decisions were made with the previous AuthInfo and rules
+        AclCache::instance().remove(&self.id);
+    }
+
+    ///The ACL rules of the AuthInfo, compiled when it is attached
+    #[inline]
+    pub async fn acl_rules(&self) -> Option<Arc<AclRules>> {
+        self.extra_attrs.read().await.get::<Arc<AclRules>>(ACL_RULES_KEY).cloned()
+    }
+
+    ///Authenticated as an anonymous client
+    #[inline]
+    pub fn anonymous(&self) -> bool {
+        self.anonymous.load(Ordering::SeqCst)
+    }
+
+    #[inline]
+    pub fn labels(&self) -> Vec<Label> {
+        self.labels.read().clone()
+    }
+
+    #[inline]
+    pub fn has_label(&self, label: &str) -> bool {
+        self.labels.read().iter().any(|l| l == label)
+    }
+
+    ///Replaces the labels of the session, those of a connected session are indexed again
+    #[inline]
+    pub fn set_labels(&self, labels: Vec<Label>) {
+        let labels = Labels::normalize(labels);
+        let mut current = self.labels.write();
+        Labels::instance().relabel(&self.id, &labels);
+        *current = labels;
     }
 
     #[inline]
@@ -1431,13 +2341,18 @@ impl Session {
             (0, Vec::new())
         };
 
+        let (inflights, inflight_quota) = {
+            let inflight_win = self.inflight_win().read().await;
+            (inflight_win.len(), inflight_win.quota())
+        };
         let data = json!({
             "subscriptions": {
                 "count": count,
                 "topic_filters": subs,
             },
             "queues": self.deliver_queue().len(),
-            "inflights": self.inflight_win().read().await.len(),
+            "inflights": inflights,
+            "inflight_quota": inflight_quota,
             "created_at": self.created_at().await.unwrap_or_default(),
         });
         data
@@ -1466,6 +2381,12 @@ pub trait SessionManager: Sync + Send {
 
         last_id: Option<Id>,
     ) -> Result<Arc<dyn SessionLike>>;
+
+    ///Deletes the persisted sessions of the client, returns the number deleted
+    #[inline]
+    async fn purge(&self, _client_id: &str) -> Result<usize> {
+        Ok(0)
+    }
 }
 
 #[async_trait]
@@ -1514,4 +2435,18 @@ pub trait SessionLike: Sync + Send {
 
     #[inline]
     async fn keepalive(&self, _ping: IsPing) {}
+
+    ///Writes the offline session created on this node, a migrated or imported one, to the session
+    ///storage
+    #[inline]
+    async fn persist(&self) -> Result<()> {
+        Ok(())
+    }
+
+    ///The will message of the offline session is pending, to be published at `due_at` unless the
+    ///client reconnects, None once it is published or canceled. Kept by the session storage.
+    #[inline]
+    async fn will_pending_set(&self, _due_at: Option<TimestampMillis>) -> Result<()> {
+        Ok(())
+    }
 }
@@ -0,0 +1,333 @@
+//! Offline queue of the shared subscription groups. When no member of a group is online, a message
+//! of the group is held by the queue of the group instead of the offline member chosen to receive
+//! it, and the messages are replayed round-robin to the members online on this node when a member
+//! reconnects or subscribes. The groups queued are selected by `shared_queue.groups` prefixes.
+//!
+//! A queue is appended to a file of `shared_queue.dir`, length-prefixed bincode records after a
+//! header naming the group and the topic filter, the queues left by the previous run are replayed as
+//! well. A queue holds at most `shared_queue.max_messages`, the message of a full queue is sent to
+//! the offline member as before.
+//!
+//! The queue of a group is held by the node of the offline member chosen, the members connecting to
+//! the other nodes of a cluster do not replay it.
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use once_cell::sync::OnceCell;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::fs::OpenOptions;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+use crate::broker::default::{DefaultRouter, DefaultShared};
+use crate::broker::routing::RoutingPolicy;
+use crate::broker::types::{DashMap, From, Message, Publish, QoSEx, Reason, SharedGroup, TopicFilter};
+use crate::{MqttError, Result, Runtime};
+
+type Key = (SharedGroup, TopicFilter);
+
+pub struct SharedQueues {
+    queues: DashMap<Key, Arc<GroupQueue>>,
+}
+
+impl SharedQueues {
+    #[inline]
+    pub fn instance() -> &'static Self {
+        static INSTANCE: OnceCell<SharedQueues> = OnceCell::new();
+        INSTANCE.get_or_init(|| {
+            let queues = DashMap::default();
+            let cfg = &Runtime::instance().settings.shared_queue;
+            if cfg.enable {
+                if let Err(e) = Self::load(&cfg.dir, &queues) {
+                    log::warn!("shared queues {:?}, load error, {:?}", cfg.dir, e);
+                }
+            }
+            Self { queues }
+        })
+    }
+
+    ///Whether the messages of the group are queued when no member is online
+    #[inline]
+    pub fn enabled(group: &str) -> bool {
+        let cfg = &Runtime::instance().settings.shared_queue;
+        cfg.enable && (cfg.groups.is_empty() || cfg.groups.iter().any(|prefix| group.starts_with(prefix)))
+    }
+
+    fn load(dir: &str, queues: &DashMap<Key, Arc<GroupQueue>>) -> Result<()> {
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().map(|ext| ext != "queue").unwrap_or(true) {
+                continue;
+            }
+            match GroupQueue::load(&path) {
+                Ok((key, q)) => {
+                    if q.len() > 0 {
+                        log::info!("shared queue {:?}, {} messages left by the previous run", key, q.len());
+                    }
+                    queues.insert(key, Arc::new(q));
+                }
+                Err(e) => log::warn!("shared queue {:?}, load error, {:?}", path, e),
+            }
+        }
+        Ok(())
+    }
+
+    ///Queues the message of the group
+    pub async fn push(
+        &self,
+        topic_filter: &TopicFilter,
+        group: &SharedGroup,
+        from: &From,
+        p: &Publish,
+    ) -> Result<()> {
+        let key = (group.clone(), topic_filter.clone());
+        let q = match self.queues.get(&key).map(|q| q.value().clone()) {
+            Some(q) => q,
+            None => {
+                let dir = &Runtime::instance().settings.shared_queue.dir;
+                let q = Arc::new(GroupQueue::create(dir, &key)?);
+                self.queues.entry(key).or_insert(q).value().clone()
+            }
+        };
+        if q.len() >= Runtime::instance().settings.shared_queue.max_messages {
+            return Err(MqttError::from("shared queue is full"));
+        }
+        q.push(&(from, p)).await
+    }
+
+    ///Replays the messages queued of the group in the background
+    #[inline]
+    pub fn replay(&'static self, topic_filter: TopicFilter, group: SharedGroup) {
+        let key = (group, topic_filter);
+        if self.queues.get(&key).map(|q| q.len() == 0).unwrap_or(true) {
+            return;
+        }
+        tokio::spawn(async move {
+            match self._replay(&key).await {
+                Ok(0) => {}
+                Ok(n) => log::debug!("shared queue {:?}, {} messages replayed", key, n),
+                Err(e) => log::warn!("shared queue {:?}, replay error, {:?}", key, e),
+            }
+        });
+    }
+
+    async fn _replay(&self, key: &Key) -> Result<usize> {
+        let (group, topic_filter) = key;
+        let q = if let Some(q) = self.queues.get(key).map(|q| q.value().clone()) { q } else { return Ok(0) };
+
+        let node_id = Runtime::instance().node.id();
+        let members = DefaultRouter::instance()
+            .relations
+            .get(topic_filter)
+            .map(|rels| {
+                rels.iter()
+                    .filter(|(_, (id, opts))| id.node_id == node_id && opts.shared_group() == Some(group))
+                    .map(|(client_id, (_, opts))| (client_id.clone(), opts.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        let mut onlines = Vec::new();
+        {
+            let router = Runtime::instance().extends.router().await;
+            for (client_id, opts) in members {
+                if !router.is_online(node_id, &client_id).await {
+                    continue;
+                }
+                if let Some((tx, to)) = DefaultShared::instance().tx(&client_id) {
+                    onlines.push((tx, to, opts));
+                }
+            }
+        }
+        if onlines.is_empty() {
+            return Ok(0);
+        }
+
+        let msgs = q.take::<(From, Publish)>().await?;
+        let n = msgs.len();
+        for (i, (from, mut p)) in msgs.into_iter().enumerate() {
+            //the next member the routing policy allows
+            let origin = RoutingPolicy::instance().origin(&from, &p.topic);
+            let member = (0..onlines.len())
+                .map(|j| &onlines[(i + j) % onlines.len()])
+                .find(|(_, to, _)| origin.as_ref().map(|o| o.allowed(to)).unwrap_or(true));
+            let (tx, to, opts) = if let Some(member) = member {
+                member
+            } else {
+                //hook, message_dropped
+                Runtime::instance()
+                    .extends
+                    .hook_mgr()
+                    .await
+                    .message_dropped(None, from, p, Reason::from_static("Denied by the routing policy"))
+                    .await;
+                continue;
+            };
+            p.dup = false;
+            p.retain = opts.retain_flag(p.retain);
+            p.qos = p.qos.less_value(opts.qos());
+            p.packet_id = None;
+            if let Err(e) = tx.unbounded_send(Message::Forward(from, p)) {
+                if let Message::Forward(from, p) = e.into_inner() {
+                    //hook, message_dropped
+                    Runtime::instance()
+                        .extends
+                        .hook_mgr()
+                        .await
+                        .message_dropped(
+                            Some(to.clone()),
+                            from,
+                            p,
+                            Reason::from_static("Connection Tx is closed"),
+                        )
+                        .await;
+                }
+            }
+        }
+        Ok(n)
+    }
+
+    ///The number of the messages queued
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.queues.iter().map(|q| q.len()).sum()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+struct GroupQueue {
+    path: PathBuf,
+    //length of the header record
+    header_len: u64,
+    len: AtomicUsize,
+    lock: Mutex<()>,
+}
+
+impl GroupQueue {
+    fn create(dir: &str, key: &Key) -> Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let path = PathBuf::from(dir).join(format!("{:016x}.queue", fnv1a(key)));
+        if path.exists() {
+            let (k, q) = Self::load(&path)?;
+            if &k == key {
+                return Ok(q);
+            }
+            return Err(MqttError::from(format!("{:?} is the queue of {:?}", path, k)));
+        }
+        let header = encode(key)?;
+        std::fs::write(&path, &header)?;
+        Ok(Self { path, header_len: header.len() as u64, len: AtomicUsize::new(0), lock: Mutex::new(()) })
+    }
+
+    fn load(path: &Path) -> Result<(Key, Self)> {
+        let data = std::fs::read(path)?;
+        let mut records = decode_all(&data);
+        let (header_len, key) = match records.next() {
+            Some((header_len, header)) => {
+                (header_len, bincode::deserialize::<Key>(header).map_err(anyhow::Error::new)?)
+            }
+            None => return Err(MqttError::from("no header")),
+        };
+        let len = records.count();
+        Ok((
+            key,
+            Self { path: path.to_path_buf(), header_len, len: AtomicUsize::new(len), lock: Mutex::new(()) },
+        ))
+    }
+
+    #[inline]
+    fn len(&self) -> usize {
+        self.len.load(Ordering::SeqCst)
+    }
+
+    async fn push<T: Serialize>(&self, v: &T) -> Result<()> {
+        let record = encode(v)?;
+        let _lock = self.lock.lock().await;
+        let mut file = OpenOptions::new().append(true).open(&self.path).await?;
+        file.write_all(&record).await?;
+        self.len.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    ///Takes all the records, the file is truncated to the header
+    async fn take<T: DeserializeOwned>(&self) -> Result<Vec<T>> {
+        let _lock = self.lock.lock().await;
+        let data = tokio::fs::read(&self.path).await?;
+        let file = OpenOptions::new().write(true).open(&self.path).await?;
+        file.set_len(self.header_len).await?;
+        self.len.store(0, Ordering::SeqCst);
+
+        let mut vals = Vec::new();
+        for (_, record) in decode_all(&data).skip(1) {
+            match bincode::deserialize::<T>(record) {
+                Ok(v) => vals.push(v),
+                Err(e) => log::warn!("shared queue {:?}, decode error, {:?}", self.path, e),
+            }
+        }
+        Ok(vals)
+    }
+}
+
+#[inline]
+fn encode<T: Serialize + ?Sized>(v: &T) -> Result<Vec<u8>> {
+    let data = bincode::serialize(v).map_err(anyhow::Error::new)?;
+    let mut buf = Vec::with_capacity(data.len() + 4);
+    buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    buf.extend_from_slice(&data);
+    Ok(buf)
+}
+
+//The length and the data of each complete record
+#[inline]
+fn decode_all(mut data: &[u8]) -> impl Iterator<Item = (u64, &[u8])> + '_ {
+    std::iter::from_fn(move || {
+        if data.len() < 4 {
+            return None;
+        }
+        let len = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+        if data.len() < 4 + len {
+            return None;
+        }
+        let record = &data[4..4 + len];
+        data = &data[4 + len..];
+        Some(((4 + len) as u64, record))
+    })
+}
+
+//Stable across the runs, the file name of a queue
+#[inline]
+fn fnv1a((group, topic_filter): &Key) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in group.as_bytes().iter().chain(&[0]).chain(topic_filter.as_bytes()) {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_all, encode, fnv1a};
+
+    #[test]
+    fn test_records() {
+        let mut data = encode(&("g1", "foo/#")).unwrap();
+        data.extend(encode(&1u32).unwrap());
+        data.extend(&[0, 0, 0, 9, 1]);
+        let records = decode_all(&data).collect::<Vec<_>>();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1], (8, &[1u8, 0, 0, 0][..]));
+        assert_ne!(fnv1a(&("g1".into(), "foo/#".into())), fnv1a(&("g1f".into(), "oo/#".into())));
+    }
+}
@@ -1,10 +1,13 @@
 use std::fmt;
-use std::sync::atomic::{AtomicIsize, Ordering};
+use std::sync::atomic::{AtomicIsize, AtomicU64, Ordering};
+use std::time::Duration;
 
 use ntex_mqtt::{handshakings, in_inflights};
 use once_cell::sync::OnceCell;
 
 use crate::broker::executor::{get_active_count, get_rate};
+use crate::broker::hook_stats::{HandlerStats, HookStats};
+use crate::broker::types::QoS;
 #[cfg(feature = "debug")]
 use crate::runtime::TaskExecStats;
 use crate::{HashMap, NodeId, Runtime, StatsMergeMode};
@@ -146,6 +149,73 @@ impl Counter {
     }
 }
 
+///Upper bounds of the latency histogram buckets, in milliseconds, the last bucket is unbounded
+pub const LATENCY_BUCKETS: [u64; 12] = [1, 2, 5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000];
+
+///Latency histogram with the fixed buckets of LATENCY_BUCKETS
+#[derive(Serialize, Deserialize, Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    //Sum of the latencies, in milliseconds
+    sum: AtomicU64,
+}
+
+impl Clone for Histogram {
+    fn clone(&self) -> Self {
+        let h = Histogram::default();
+        h.add(self);
+        h
+    }
+}
+
+impl fmt::Debug for Histogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, r#"{{ "count":{}, "sum":{} }}"#, self.count(), self.sum())
+    }
+}
+
+impl Histogram {
+    #[inline]
+    pub fn observe(&self, latency: Duration) {
+        let millis = latency.as_millis().min(u64::MAX as u128) as u64;
+        let idx = LATENCY_BUCKETS.iter().position(|le| millis <= *le).unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[idx].fetch_add(1, Ordering::SeqCst);
+        self.sum.fetch_add(millis, Ordering::SeqCst);
+    }
+
+    #[inline]
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().map(|b| b.load(Ordering::SeqCst)).sum()
+    }
+
+    #[inline]
+    pub fn sum(&self) -> u64 {
+        self.sum.load(Ordering::SeqCst)
+    }
+
+    #[inline]
+    pub fn add(&self, other: &Self) {
+        for (b, o) in self.buckets.iter().zip(other.buckets.iter()) {
+            b.fetch_add(o.load(Ordering::SeqCst), Ordering::SeqCst);
+        }
+        self.sum.fetch_add(other.sum(), Ordering::SeqCst);
+    }
+
+    ///The count, the sum and the cumulative count of each bucket, "{prefix}.le_{bound}" and
+    ///"{prefix}.le_inf", as the flat keys of the stats
+    #[inline]
+    pub fn to_json(&self, prefix: &str, obj: &mut serde_json::Map<String, serde_json::Value>) {
+        let mut cumulative = 0;
+        for (idx, b) in self.buckets.iter().enumerate() {
+            cumulative += b.load(Ordering::SeqCst);
+            let le = LATENCY_BUCKETS.get(idx).map(|le| le.to_string()).unwrap_or_else(|| "inf".into());
+            obj.insert(format!("{}.le_{}", prefix, le), json!(cumulative));
+        }
+        obj.insert(format!("{}.count", prefix), json!(cumulative));
+        obj.insert(format!("{}.sum", prefix), json!(self.sum()));
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Stats {
     pub handshakings: Counter,
@@ -153,6 +223,8 @@ pub struct Stats {
     pub handshakings_rate: Counter,
     pub connections: Counter,
     pub sessions: Counter,
+    //The sessions of the anonymous clients, see broker::anonymous
+    pub sessions_anonymous: Counter,
     pub subscriptions: Counter,
     pub subscriptions_shared: Counter,
     pub message_queues: Counter,
@@ -161,6 +233,13 @@ pub struct Stats {
     pub forwards: Counter,
     pub message_storages: Counter,
     pub retaineds: Counter,
+    pub overload_level: Counter,
+    pub dedup_hits: Counter,
+    pub fanout_hot: Counter,
+    //Publish to deliver latency of each QoS, see broker::latency
+    pub delivery_latency: [Histogram; 3],
+    //Hook handler stats of this node, "{plugin}.{type}", see broker::hook_stats
+    hooks: HashMap<String, HandlerStats>,
 
     topics_map: HashMap<NodeId, Counter>,
     routes_map: HashMap<NodeId, Counter>,
@@ -191,6 +270,7 @@ impl Stats {
             handshakings_rate: Counter::new(),
             connections: Counter::new(),
             sessions: Counter::new(),
+            sessions_anonymous: Counter::new(),
             subscriptions: Counter::new(),
             subscriptions_shared: Counter::new(),
             message_queues: Counter::new(),
@@ -199,6 +279,11 @@ impl Stats {
             forwards: Counter::new(),
             message_storages: Counter::new(),
             retaineds: Counter::new(),
+            overload_level: Counter::new(),
+            dedup_hits: Counter::new(),
+            fanout_hot: Counter::new(),
+            delivery_latency: Default::default(),
+            hooks: HashMap::default(),
 
             topics_map: HashMap::default(),
             routes_map: HashMap::default(),
@@ -278,6 +363,7 @@ impl Stats {
             handshakings_rate: self.handshakings_rate.clone(),
             connections: self.connections.clone(),
             sessions: self.sessions.clone(),
+            sessions_anonymous: self.sessions_anonymous.clone(),
             subscriptions: self.subscriptions.clone(),
             subscriptions_shared: self.subscriptions_shared.clone(),
             message_queues: self.message_queues.clone(),
@@ -285,6 +371,11 @@ impl Stats {
             in_inflights: self.in_inflights.clone(),
             forwards: self.forwards.clone(),
             message_storages: self.message_storages.clone(),
+            overload_level: self.overload_level.clone(),
+            dedup_hits: self.dedup_hits.clone(),
+            fanout_hot: self.fanout_hot.clone(),
+            delivery_latency: self.delivery_latency.clone(),
+            hooks: HookStats::instance().snapshot(),
 
             retaineds,
             topics_map,
@@ -314,6 +405,7 @@ impl Stats {
         self.handshakings_rate.add(&other.handshakings_rate);
         self.connections.add(&other.connections);
         self.sessions.add(&other.sessions);
+        self.sessions_anonymous.add(&other.sessions_anonymous);
         self.subscriptions.add(&other.subscriptions);
         self.subscriptions_shared.add(&other.subscriptions_shared);
         self.message_queues.add(&other.message_queues);
@@ -322,6 +414,15 @@ impl Stats {
         self.forwards.add(&other.forwards);
         self.message_storages.add(&other.message_storages);
         self.retaineds.merge(&other.retaineds);
+        self.overload_level.add(&other.overload_level);
+        self.dedup_hits.add(&other.dedup_hits);
+        self.fanout_hot.add(&other.fanout_hot);
+        for (h, o) in self.delivery_latency.iter().zip(other.delivery_latency.iter()) {
+            h.add(o);
+        }
+        for (key, o) in other.hooks {
+            self.hooks.entry(key).or_default().add(&o);
+        }
 
         self.topics_map.extend(other.topics_map);
         self.routes_map.extend(other.routes_map);
@@ -352,6 +453,12 @@ impl Stats {
         }
     }
 
+    ///Records the publish to deliver latency of a message of the QoS
+    #[inline]
+    pub fn observe_delivery_latency(&self, qos: QoS, latency: Duration) {
+        self.delivery_latency[qos.value() as usize].observe(latency);
+    }
+
     #[allow(unused_mut)]
     #[inline]
     pub async fn to_json(&self) -> serde_json::Value {
@@ -369,6 +476,8 @@ impl Stats {
             "connections.max": self.connections.max(),
             "sessions.count": self.sessions.count(),
             "sessions.max": self.sessions.max(),
+            "sessions_anonymous.count": self.sessions_anonymous.count(),
+            "sessions_anonymous.max": self.sessions_anonymous.max(),
             "subscriptions.count": self.subscriptions.count(),
             "subscriptions.max": self.subscriptions.max(),
             "subscriptions_shared.count": self.subscriptions_shared.count(),
@@ -386,6 +495,11 @@ impl Stats {
             "forwards.max": self.forwards.max(),
             "message_storages.count": self.message_storages.count(),
             "message_storages.max": self.message_storages.max(),
+            "overload_level.count": self.overload_level.count(),
+            "overload_level.max": self.overload_level.max(),
+            "dedup_hits.count": self.dedup_hits.count(),
+            "fanout_hot.count": self.fanout_hot.count(),
+            "fanout_hot.max": self.fanout_hot.max(),
 
             "topics.count": topics.count(),
             "topics.max": topics.max(),
@@ -393,6 +507,19 @@ impl Stats {
             "routes.max": routes.max(),
         });
 
+        if let Some(obj) = json_val.as_object_mut() {
+            for (qos, h) in self.delivery_latency.iter().enumerate() {
+                h.to_json(&format!("delivery_latency.qos{}", qos), obj);
+            }
+            for (key, h) in self.hooks.iter() {
+                if let Some(vals) = h.to_json().as_object() {
+                    for (name, val) in vals {
+                        obj.insert(format!("hooks.{}.{}", key, name), val.clone());
+                    }
+                }
+            }
+        }
+
         #[cfg(feature = "debug")]
         {
             if let Some(obj) = json_val.as_object_mut() {
@@ -1,23 +1,161 @@
+use std::cmp::Ordering;
 use std::fmt;
 use std::fmt::Debug;
 use std::hash::Hash;
+use std::sync::Arc;
 
-use serde::de::Deserialize;
+use once_cell::sync::Lazy;
+use serde::de::{Deserialize, Deserializer};
 use serde::ser::Serialize;
 
-use crate::broker::types::TopicFilter;
+use crate::broker::types::{DashMap, TopicFilter};
 
-type HashMap<K, V> = std::collections::HashMap<K, V, ahash::RandomState>;
 type ValueSet<K> = std::collections::BTreeSet<K>;
 
 pub type Level = ntex_mqtt::TopicLevel;
 pub type Topic = ntex_mqtt::Topic;
 pub type TopicTree<V> = Node<V>;
 
+//The interned level strings, shared by all the trees, an entry is released when the last segment
+//referencing it is dropped
+static SEGMENTS: Lazy<DashMap<Arc<str>, ()>> = Lazy::new(DashMap::default);
+
+#[inline]
+fn intern(s: &str) -> Arc<str> {
+    if let Some(entry) = SEGMENTS.get(s) {
+        return entry.key().clone();
+    }
+    SEGMENTS.entry(Arc::from(s)).or_insert(()).key().clone()
+}
+
+///Number and bytes of the interned level strings
+#[inline]
+pub fn interned_segments() -> (usize, usize) {
+    //two segments of the same string dropped at the same time may both see the other one, the entry
+    //left behind is referenced by SEGMENTS only
+    SEGMENTS.retain(|k, _| Arc::strong_count(k) > 1);
+    SEGMENTS.iter().fold((0, 0), |(count, bytes), entry| (count + 1, bytes + entry.key().len()))
+}
+
+///A level of the tree, the same as Level with the strings interned
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(from = "Level", into = "Level")]
+pub enum Segment {
+    Blank,
+    SingleWildcard,
+    MultiWildcard,
+    Normal(Arc<str>),
+    Metadata(Arc<str>),
+}
+
+impl Segment {
+    #[inline]
+    fn rank(&self) -> u8 {
+        match self {
+            Segment::Blank => 0,
+            Segment::SingleWildcard => 1,
+            Segment::MultiWildcard => 2,
+            Segment::Normal(_) => 3,
+            Segment::Metadata(_) => 4,
+        }
+    }
+
+    ///Compares with a level in the order of the segments, without interning it
+    #[inline]
+    fn cmp_level(&self, l: &Level) -> Ordering {
+        let (rank, s) = match l {
+            Level::Blank => (0, ""),
+            Level::SingleWildcard => (1, ""),
+            Level::MultiWildcard => (2, ""),
+            Level::Normal(s) => (3, s.as_str()),
+            Level::Metadata(s) => (4, s.as_str()),
+        };
+        match self {
+            Segment::Normal(seg) | Segment::Metadata(seg) => {
+                self.rank().cmp(&rank).then_with(|| seg.as_ref().cmp(s))
+            }
+            _ => self.rank().cmp(&rank),
+        }
+    }
+}
+
+//The interned string is released if no other segment references it, whichever way the segment
+//is dropped, removed from a tree, with a dropped tree or merged into another branch
+impl Drop for Segment {
+    #[inline]
+    fn drop(&mut self) {
+        if let Segment::Normal(s) | Segment::Metadata(s) = self {
+            //referenced by SEGMENTS and this segment only
+            SEGMENTS.remove_if(s.as_ref(), |k, _| Arc::strong_count(k) <= 2);
+        }
+    }
+}
+
+impl std::convert::From<Level> for Segment {
+    #[inline]
+    fn from(l: Level) -> Self {
+        match l {
+            Level::Blank => Segment::Blank,
+            Level::SingleWildcard => Segment::SingleWildcard,
+            Level::MultiWildcard => Segment::MultiWildcard,
+            Level::Normal(s) => Segment::Normal(intern(&s)),
+            Level::Metadata(s) => Segment::Metadata(intern(&s)),
+        }
+    }
+}
+
+impl std::convert::From<Segment> for Level {
+    #[inline]
+    fn from(s: Segment) -> Self {
+        match &s {
+            Segment::Blank => Level::Blank,
+            Segment::SingleWildcard => Level::SingleWildcard,
+            Segment::MultiWildcard => Level::MultiWildcard,
+            Segment::Normal(s) => Level::Normal(s.to_string()),
+            Segment::Metadata(s) => Level::Metadata(s.to_string()),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct Node<V: Ord> {
     values: ValueSet<V>,
-    branches: HashMap<Level, Node<V>>,
+    //Sorted by segment, looked up by binary search, kept at capacity of their length
+    #[serde(deserialize_with = "deserialize_branches")]
+    branches: Vec<(Segment, Node<V>)>,
+}
+
+//The branches of a snapshot are not trusted to be sorted, a snapshot of a node before the branches
+//were sorted holds them in the order of a HashMap, with the same encoding
+fn deserialize_branches<'de, D, V>(deserializer: D) -> Result<Vec<(Segment, Node<V>)>, D::Error>
+where
+    D: Deserializer<'de>,
+    V: Ord + Deserialize<'de>,
+{
+    let mut branches = Vec::<(Segment, Node<V>)>::deserialize(deserializer)?;
+    sort_branches(&mut branches);
+    Ok(branches)
+}
+
+//Sorts the branches by segment, the branches of the same segment are merged
+fn sort_branches<V: Ord>(branches: &mut Vec<(Segment, Node<V>)>) {
+    if branches.windows(2).all(|w| w[0].0 < w[1].0) {
+        return;
+    }
+    branches.sort_by(|(a, _), (b, _)| a.cmp(b));
+    let mut sorted: Vec<(Segment, Node<V>)> = Vec::with_capacity(branches.len());
+    for (s, n) in branches.drain(..) {
+        match sorted.last_mut() {
+            Some((last, node)) if *last == s => {
+                node.values.extend(n.values);
+                node.branches.extend(n.branches);
+                sort_branches(&mut node.branches);
+            }
+            _ => sorted.push((s, n)),
+        }
+    }
+    sorted.shrink_to_fit();
+    *branches = sorted;
 }
 
 impl<V> Default for Node<V>
@@ -26,7 +164,7 @@ where
 {
     #[inline]
     fn default() -> Node<V> {
-        Self { values: ValueSet::default(), branches: HashMap::default() }
+        Self { values: ValueSet::default(), branches: Vec::new() }
     }
 }
 
@@ -45,20 +183,36 @@ where
 {
     #[inline]
     pub fn insert(&mut self, topic_filter: &Topic, value: V) -> bool {
-        let mut path = topic_filter.levels().clone();
-        path.reverse();
-        self._insert(path, value)
+        self._insert(topic_filter.levels(), value)
     }
 
     #[inline]
-    fn _insert(&mut self, mut path: Vec<Level>, value: V) -> bool {
-        if let Some(first) = path.pop() {
-            self.branches.entry(first).or_default()._insert(path, value)
+    fn _insert(&mut self, path: &[Level], value: V) -> bool {
+        if let Some(first) = path.first() {
+            self.branch_or_insert(first)._insert(&path[1..], value)
         } else {
             self.values.insert(value)
         }
     }
 
+    #[inline]
+    fn position(&self, l: &Level) -> std::result::Result<usize, usize> {
+        self.branches.binary_search_by(|(s, _)| s.cmp_level(l))
+    }
+
+    #[inline]
+    fn branch_or_insert(&mut self, l: &Level) -> &mut Node<V> {
+        let idx = match self.position(l) {
+            Ok(idx) => idx,
+            Err(idx) => {
+                self.branches.reserve_exact(1);
+                self.branches.insert(idx, (Segment::from(l.clone()), Node::default()));
+                idx
+            }
+        };
+        &mut self.branches[idx].1
+    }
+
     #[inline]
     pub fn remove(&mut self, topic_filter: &Topic, value: &V) -> bool {
         self._remove(topic_filter.levels().as_ref(), value)
@@ -69,11 +223,14 @@ where
         if path.is_empty() {
             self.values.remove(value)
         } else {
-            let t = &path[0];
-            if let Some(x) = self.branches.get_mut(t) {
+            if let Ok(idx) = self.position(&path[0]) {
+                let x = &mut self.branches[idx].1;
                 let res = x._remove(&path[1..], value);
                 if x.values.is_empty() && x.branches.is_empty() {
-                    self.branches.remove(t);
+                    self.branches.remove(idx);
+                    if self.branches.capacity() > self.branches.len() * 2 {
+                        self.branches.shrink_to_fit();
+                    }
                 }
                 res
             } else {
@@ -82,6 +239,24 @@ where
         }
     }
 
+    ///Removes the value from all the topic filters, returns the number of the removed entries
+    #[inline]
+    pub fn remove_all(&mut self, value: &V) -> usize {
+        let mut removed = usize::from(self.values.remove(value));
+        let branches = std::mem::take(&mut self.branches);
+        self.branches.reserve_exact(branches.len());
+        for (seg, mut n) in branches {
+            removed += n.remove_all(value);
+            if !n.values.is_empty() || !n.branches.is_empty() {
+                self.branches.push((seg, n));
+            }
+        }
+        if self.branches.capacity() > self.branches.len() * 2 {
+            self.branches.shrink_to_fit();
+        }
+        removed
+    }
+
     #[inline]
     pub fn is_match(&self, topic: &Topic) -> bool {
         self.matches(topic).first().is_some()
@@ -155,41 +330,51 @@ where
 
     #[inline]
     pub fn values_size(&self) -> usize {
-        let len: usize = self.branches.values().map(|n| n.values_size()).sum();
+        let len: usize = self.branches.iter().map(|(_, n)| n.values_size()).sum();
         self.values.len() + len
     }
 
     #[inline]
     pub fn nodes_size(&self) -> usize {
-        let len: usize = self.branches.values().map(|n| n.nodes_size()).sum();
+        let len: usize = self.branches.iter().map(|(_, n)| n.nodes_size()).sum();
         self.branches.len() + len
     }
 
+    ///Estimated heap bytes of the nodes and the values, the interned strings are not included, see
+    ///interned_segments
+    #[inline]
+    pub fn memory_size(&self) -> usize {
+        let branches: usize = self.branches.iter().map(|(_, n)| n.memory_size()).sum();
+        self.branches.capacity() * std::mem::size_of::<(Segment, Node<V>)>()
+            + self.values.len() * std::mem::size_of::<V>()
+            + branches
+    }
+
     #[inline]
     pub fn values(&self) -> &ValueSet<V> {
         &self.values
     }
 
     #[inline]
-    pub fn children(&self) -> &HashMap<Level, Node<V>> {
+    pub fn children(&self) -> &[(Segment, Node<V>)] {
         &self.branches
     }
 
     #[inline]
     pub fn child(&self, l: &Level) -> Option<&Node<V>> {
-        self.branches.get(l)
+        self.position(l).ok().map(|idx| &self.branches[idx].1)
     }
 
     #[inline]
     pub fn list(&self, top: usize) -> Vec<String> {
         let mut out = Vec::new();
-        let parent = Level::Blank;
+        let parent = Segment::Blank;
         self._list(&mut out, &parent, top, 0);
         out
     }
 
     #[inline]
-    fn _list(&self, out: &mut Vec<String>, _parent: &Level, top: usize, depth: usize) {
+    fn _list(&self, out: &mut Vec<String>, _parent: &Segment, top: usize, depth: usize) {
         if top == 0 {
             return;
         }
@@ -308,7 +493,7 @@ where
     fn prepare(&mut self) {
         if self.path.is_empty() {
             //Match parent #
-            if let Some(b_node) = self.node.branches.get(&Level::MultiWildcard) {
+            if let Some(b_node) = self.node.child(&Level::MultiWildcard) {
                 if !b_node.values.is_empty() {
                     let mut sub_path = self.sub_path.clone().unwrap();
                     sub_path.push(&Level::MultiWildcard);
@@ -323,11 +508,11 @@ where
             if !(self.sub_path.as_ref().unwrap().is_empty()
                 && !matches!(self.path[0], Level::Blank)
                 && self.path[0].is_metadata()
-                && (self.node.branches.contains_key(&Level::MultiWildcard)
-                    || self.node.branches.contains_key(&Level::SingleWildcard)))
+                && (self.node.child(&Level::MultiWildcard).is_some()
+                    || self.node.child(&Level::SingleWildcard).is_some()))
             {
                 //Multilayer matching
-                if let Some(b_node) = self.node.branches.get(&Level::MultiWildcard) {
+                if let Some(b_node) = self.node.child(&Level::MultiWildcard) {
                     if !b_node.values.is_empty() {
                         let mut sub_path = self.sub_path.clone().unwrap();
                         sub_path.push(&Level::MultiWildcard);
@@ -336,7 +521,7 @@ where
                 }
 
                 //Single layer matching
-                if let Some(b_node) = self.node.branches.get(&Level::SingleWildcard) {
+                if let Some(b_node) = self.node.child(&Level::SingleWildcard) {
                     let mut sub_path = self.sub_path.clone().unwrap();
                     sub_path.push(&Level::SingleWildcard);
                     self.sub_iters.push(MatchedIter::new(b_node, &self.path[1..], sub_path));
@@ -344,7 +529,7 @@ where
             }
 
             //Precise matching
-            if let Some(b_node) = self.node.branches.get(&self.path[0]) {
+            if let Some(b_node) = self.node.child(&self.path[0]) {
                 let mut sub_path = self.sub_path.take().unwrap();
                 sub_path.push(&self.path[0]);
                 self.sub_iters.push(MatchedIter::new(b_node, &self.path[1..], sub_path));
@@ -390,9 +575,10 @@ where
 #[cfg(test)]
 mod tests {
     use std::str::FromStr;
+    use std::sync::Arc;
 
     use super::super::NodeId;
-    use super::{Topic, TopicTree, VecToString};
+    use super::{Segment, Topic, TopicTree, VecToString, SEGMENTS};
 
     fn match_one(topics: &TopicTree<NodeId>, topic: &str, vs: &[NodeId]) -> bool {
         let mut matcheds = 0;
@@ -506,6 +692,37 @@ mod tests {
         assert!(match_one(&topics, "/x/y/z/2", &[1, 2, 1, 2, 3]));
     }
 
+    #[test]
+    fn interned_segments() {
+        let mut a: TopicTree<()> = TopicTree::default();
+        let mut b: TopicTree<()> = TopicTree::default();
+        let t = Topic::from_str("interned/segment/test").unwrap();
+        a.insert(&t, ());
+        b.insert(&t, ());
+        let (Segment::Normal(sa), Segment::Normal(sb)) = (&a.children()[0].0, &b.children()[0].0) else {
+            unreachable!()
+        };
+        assert!(Arc::ptr_eq(sa, sb));
+
+        assert!(a.remove(&t, &()));
+        assert!(SEGMENTS.contains_key("interned"));
+        assert!(b.remove(&t, &()));
+        assert!(!SEGMENTS.contains_key("interned"));
+        assert!(b.children().is_empty());
+    }
+
+    #[test]
+    fn dropped_tree_segments() {
+        let mut a: TopicTree<()> = TopicTree::default();
+        a.insert(&Topic::from_str("dropped/tree/segment").unwrap(), ());
+        let b: TopicTree<()> = bincode::deserialize(&bincode::serialize(&a).unwrap()).unwrap();
+        drop(a);
+        assert!(SEGMENTS.contains_key("dropped"));
+        drop(b);
+        assert!(!SEGMENTS.contains_key("dropped"));
+        assert!(!SEGMENTS.contains_key("segment"));
+    }
+
     #[test]
     fn topic() {
         let mut topics: TopicTree<()> = TopicTree::default();
@@ -519,4 +736,49 @@ mod tests {
         let topics: TopicTree<()> = bincode::deserialize(&bincode::serialize(&topics).unwrap()).unwrap();
         assert_eq!(val_size, topics.values_size());
     }
+
+    #[test]
+    fn remove_all() {
+        let mut topics: TopicTree<NodeId> = TopicTree::default();
+        topics.insert(&Topic::from_str("a/b").unwrap(), 1);
+        topics.insert(&Topic::from_str("a/b").unwrap(), 2);
+        topics.insert(&Topic::from_str("a/+").unwrap(), 1);
+        topics.insert(&Topic::from_str("removed/all/#").unwrap(), 1);
+        assert_eq!(topics.remove_all(&1), 3);
+        assert_eq!(topics.remove_all(&1), 0);
+        assert!(match_one(&topics, "a/b", &[2]));
+        assert!(!topics.is_match(&Topic::from_str("removed/all/x").unwrap()));
+        assert!(!SEGMENTS.contains_key("removed"));
+        assert_eq!(topics.children().len(), 1);
+    }
+
+    #[test]
+    fn unsorted_branches() {
+        let mut topics: TopicTree<NodeId> = TopicTree::default();
+        topics.insert(&Topic::from_str("a/b").unwrap(), 1);
+        topics.insert(&Topic::from_str("c/+").unwrap(), 2);
+        topics.insert(&Topic::from_str("x/#").unwrap(), 3);
+        topics.insert(&Topic::from_str("x/y").unwrap(), 4);
+
+        //the branches in reverse order, and a segment twice
+        let mut snapshot = serde_json::to_value(&topics).unwrap();
+        let branches = snapshot["branches"].as_array_mut().unwrap();
+        branches.reverse();
+        let mut dup = serde_json::to_value(TopicTree::<NodeId>::default()).unwrap();
+        dup["values"] = serde_json::json!([5]);
+        let x = branches[0][0].clone();
+        branches.push(serde_json::json!([x, dup]));
+        branches[0][1]["branches"].as_array_mut().unwrap().reverse();
+
+        let topics: TopicTree<NodeId> = serde_json::from_value(snapshot).unwrap();
+        let segments = topics.children().iter().map(|(s, _)| s.clone()).collect::<Vec<_>>();
+        let mut sorted = segments.clone();
+        sorted.sort();
+        sorted.dedup();
+        assert_eq!(segments, sorted);
+        assert!(match_one(&topics, "a/b", &[1]));
+        assert!(match_one(&topics, "c/d", &[2]));
+        assert!(match_one(&topics, "x/y", &[3, 4]));
+        assert!(match_one(&topics, "x", &[3, 5]));
+    }
 }
@@ -22,6 +22,7 @@ use bytestring::ByteString;
 use get_size::GetSize;
 use itertools::Itertools;
 
+use bytes::BytesMut;
 use ntex::util::Bytes;
 
 use ntex_mqtt::error::SendPacketError;
@@ -45,9 +46,15 @@ pub use ntex_mqtt::v5::{
 };
 use ntex_mqtt::TopicLevel;
 
+use crate::broker::acl::AclRule;
 use crate::broker::fitter::Fitter;
+use crate::broker::gateway::GatewaySink;
 use crate::broker::inflight::Inflight;
+use crate::broker::origin::Origins;
+use crate::broker::protocol_bridge;
 use crate::broker::queue::{Queue, Sender};
+use crate::broker::uds::{PeerCred, UdsPeers};
+use crate::settings::DrainReason;
 use crate::{MqttError, Result, Runtime};
 
 pub type NodeId = u64;
@@ -263,6 +270,36 @@ impl ConnectInfo {
         }
     }
 
+    ///User properties of the CONNECT packet, empty for MQTT 3
+    #[inline]
+    pub fn user_properties(&self) -> &[UserProperty] {
+        match self {
+            ConnectInfo::V3(_, _) => &[],
+            ConnectInfo::V5(_, conn_info) => &conn_info.user_properties,
+        }
+    }
+
+    ///Value of the first user property of the CONNECT packet with the name
+    #[inline]
+    pub fn user_property(&self, name: &str) -> Option<&ByteString> {
+        self.user_properties().iter().find(|(k, _)| k == name).map(|(_, v)| v)
+    }
+
+    ///Origin header of the WebSocket handshake, available during the MQTT handshake, the auth for instance
+    #[inline]
+    pub fn origin(&self) -> Option<String> {
+        let id = self.id();
+        Origins::instance().get(id.remote_addr?, id.local_addr?)
+    }
+
+    ///Credentials of the peer process of a Unix domain socket connection, available during the MQTT
+    ///handshake, the auth for instance
+    #[inline]
+    pub fn peer_cred(&self) -> Option<PeerCred> {
+        let id = self.id();
+        UdsPeers::instance().get(id.remote_addr?, id.local_addr?)
+    }
+
     #[inline]
     pub fn clean_start(&self) -> bool {
         match self {
@@ -347,15 +384,63 @@ pub enum PublishAclResult {
     Rejected(IsDisconnect),
 }
 
+///A message dropped by a MessagePublish handler, it is passed to the MessageDropped hook with the reason
+#[derive(Debug, Clone)]
+pub struct PublishDrop {
+    pub reason: Reason,
+    ///The publish of a client is refused as by the ACL check, the client is disconnected if set. If
+    ///not refused the publish is acknowledged
+    pub refused: Option<IsDisconnect>,
+}
+
+impl PublishDrop {
+    #[inline]
+    pub fn new(reason: Reason) -> Self {
+        Self { reason, refused: None }
+    }
+
+    #[inline]
+    pub fn refused(reason: Reason, disconnect: IsDisconnect) -> Self {
+        Self { reason, refused: Some(disconnect) }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum AuthResult {
-    Allow(Superuser),
+    Allow(Superuser, Option<AuthInfo>),
     ///User is not found
     NotFound,
     BadUsernameOrPassword,
     NotAuthorized,
 }
 
+///Returned by the auth plugins, the limits override those of the listener, 0 is unlimited
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuthInfo {
+    pub max_subscriptions: Option<usize>,
+    pub max_wildcard_subscriptions: Option<usize>,
+    pub max_multilevel_wildcard_subscriptions: Option<usize>,
+    pub max_topic_levels: Option<usize>,
+    //Overrides the dedup switch of the listener
+    pub dedup: Option<bool>,
+    //The client is anonymous, see broker::anonymous
+    #[serde(default)]
+    pub anonymous: bool,
+    //Labels of the session, see broker::labels
+    #[serde(default)]
+    pub labels: Vec<String>,
+    //ACL rules of the client, see broker::acl
+    #[serde(default)]
+    pub rules: Vec<AclRule>,
+}
+
+impl AuthInfo {
+    #[inline]
+    pub fn new_anonymous() -> Self {
+        Self { anonymous: true, ..Default::default() }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum MessageExpiryCheckResult {
     Expiry,
@@ -377,6 +462,15 @@ impl MessageExpiryCheckResult {
     }
 }
 
+///MQTT 5 properties added by the plugins to a CONNACK, SUBACK or UNSUBACK
+#[derive(Debug, Clone, Default)]
+pub struct AckProperties {
+    pub user_properties: UserProperties,
+    pub reason_string: Option<ByteString>,
+    ///CONNACK only, the keep alive the client must use, still limited by min_keepalive and max_keepalive
+    pub server_keepalive_sec: Option<u16>,
+}
+
 //key is TopicFilter
 pub type SharedSubRelations = HashMap<TopicFilter, Vec<(SharedGroup, NodeId, ClientId, QoS, IsOnline)>>;
 //In other nodes
@@ -517,6 +611,16 @@ impl SubscriptionOptions {
         }
     }
 
+    ///RETAIN flag of a message forwarded to an established subscription, kept only if MQTT V5
+    ///Retain As Published is set. The retained messages sent at subscribe always have it set.
+    #[inline]
+    pub fn retain_flag(&self, retain: bool) -> bool {
+        match self {
+            SubscriptionOptions::V3(_) => false,
+            SubscriptionOptions::V5(opts) => opts.retain_as_published && retain,
+        }
+    }
+
     #[inline]
     pub fn subscription_identifier(&self) -> Option<NonZeroU32> {
         match self {
@@ -1035,6 +1139,8 @@ impl<'a> Serialize for LastWill<'a> {
 pub enum Sink {
     V3(MqttSinkV3),
     V5(MqttSinkV5),
+    ///The client of a protocol gateway, see gateway
+    Gateway(Arc<dyn GatewaySink>),
 }
 
 impl Sink {
@@ -1045,6 +1151,29 @@ impl Sink {
                 s.close();
             }
             Sink::V5(s) => s.close(),
+            Sink::Gateway(s) => s.close(),
+        }
+    }
+
+    ///Sends DISCONNECT with the reason code and server reference to MQTT 5.0 clients, then closes
+    #[inline]
+    pub(crate) fn close_with_reason(
+        &self,
+        reason_code: DisconnectReasonCode,
+        server_reference: Option<ByteString>,
+    ) {
+        match self {
+            Sink::V3(s) => {
+                s.close();
+            }
+            Sink::V5(s) => s.close_with_reason(DisconnectV5 {
+                reason_code,
+                session_expiry_interval_secs: None,
+                server_reference,
+                reason_string: None,
+                user_properties: Vec::new(),
+            }),
+            Sink::Gateway(s) => s.close(),
         }
     }
 
@@ -1056,8 +1185,11 @@ impl Sink {
         server_topic_aliases: Option<&Rc<ServerTopicAliases>>,
     ) -> Result<()> {
         let pkt = match self {
-            Sink::V3(_) => p.into_v3(),
-            Sink::V5(_) => p.into_v5(message_expiry_interval, server_topic_aliases).await,
+            Sink::V3(_) => protocol_bridge::to_v3(p).into_v3(),
+            Sink::V5(_) => {
+                protocol_bridge::to_v5(p).into_v5(message_expiry_interval, server_topic_aliases).await
+            }
+            Sink::Gateway(s) => return s.publish(p),
         };
         self.send(pkt)
     }
@@ -1079,6 +1211,12 @@ impl Sink {
                     return Err(MqttError::from(SendPacketError::Disconnected));
                 }
             }
+            //only the releases of the QoS 2 messages are sent to a gateway client
+            Sink::Gateway(s) => {
+                if let Packet::V3(PacketV3::PublishRelease { packet_id }) = p {
+                    s.release(packet_id)?;
+                }
+            }
         }
         Ok(())
     }
@@ -1369,6 +1507,48 @@ impl Publish {
     pub fn set_packet_id(&mut self, packet_id: PacketId) {
         self.packet_id = NonZeroU16::new(packet_id)
     }
+
+    ///For the plugins that change the payload in place. The payload buffer is shared between the
+    ///hooks, the storage and the deliveries, it is taken over with no copy when this publish holds
+    ///the only reference, and copied otherwise.
+    #[inline]
+    pub fn into_mutable(mut self) -> PublishMut {
+        let payload = std::mem::take(&mut self.payload);
+        let payload = payload.try_into_mut().unwrap_or_else(|payload| BytesMut::from(payload.as_ref()));
+        PublishMut { publish: self, payload }
+    }
+}
+
+///A publish with a mutable payload, see `Publish::into_mutable`
+#[derive(Debug)]
+pub struct PublishMut {
+    //The payload is taken out
+    publish: Publish,
+    payload: BytesMut,
+}
+
+impl PublishMut {
+    #[inline]
+    pub fn payload_mut(&mut self) -> &mut BytesMut {
+        &mut self.payload
+    }
+
+    #[inline]
+    pub fn topic_mut(&mut self) -> &mut TopicName {
+        &mut self.publish.topic
+    }
+
+    #[inline]
+    pub fn properties_mut(&mut self) -> &mut PublishProperties {
+        &mut self.publish.properties
+    }
+
+    #[inline]
+    pub fn freeze(self) -> Publish {
+        let mut publish = self.publish;
+        publish.payload = self.payload.freeze();
+        publish
+    }
 }
 
 #[derive(GetSize, Debug, Clone, Copy, Deserialize, Serialize)]
@@ -1681,6 +1861,31 @@ pub struct Retain {
     pub publish: Publish,
 }
 
+///A retained message and its metadata, for the management of the retained messages
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RetainInfo {
+    pub topic: TopicName,
+    pub retain: Retain,
+    ///None if the message does not expire
+    pub expiry_time_at: Option<TimestampMillis>,
+}
+
+impl RetainInfo {
+    #[inline]
+    pub fn to_json(&self) -> serde_json::Value {
+        let publish = &self.retain.publish;
+        json!({
+            "topic": self.topic,
+            "qos": publish.qos.value(),
+            "payload": general_purpose::STANDARD.encode(&publish.payload),
+            "payload_size": publish.payload.len(),
+            "create_time": publish.create_time,
+            "expiry_time_at": self.expiry_time_at,
+            "from": self.retain.from.to_json(),
+        })
+    }
+}
+
 pub type MsgID = usize;
 
 #[derive(Debug, Clone, Deserialize, Serialize, GetSize)]
@@ -1732,6 +1937,42 @@ impl StoredMessage {
     }
 }
 
+///The position of a paged fetch of the stored messages, the cursor of each node not yet exhausted
+#[derive(Debug, Clone, Default)]
+pub struct MessageCursor {
+    started: bool,
+    pub(crate) nodes: HashMap<NodeId, Option<MsgID>>,
+}
+
+impl MessageCursor {
+    #[inline]
+    pub(crate) fn start(&mut self, node_ids: impl Iterator<Item = NodeId>) {
+        if !self.started {
+            self.started = true;
+            self.nodes.extend(node_ids.map(|node_id| (node_id, None)));
+        }
+    }
+
+    ///Moves the cursor of the node, None once the node has no more pages
+    #[inline]
+    pub(crate) fn advance(&mut self, node_id: NodeId, next: Option<MsgID>) {
+        match next {
+            Some(next) => {
+                self.nodes.insert(node_id, Some(next));
+            }
+            None => {
+                self.nodes.remove(&node_id);
+            }
+        }
+    }
+
+    ///All pages were fetched
+    #[inline]
+    pub fn is_done(&self) -> bool {
+        self.started && self.nodes.is_empty()
+    }
+}
+
 #[derive(Debug)]
 pub enum Message {
     Forward(From, Publish),
@@ -1741,6 +1982,8 @@ pub enum Message {
     Keepalive(IsPing),
     Subscribe(Subscribe, oneshot::Sender<Result<SubscribeReturn>>),
     Unsubscribe(Unsubscribe, oneshot::Sender<Result<()>>),
+    //Liveness check of the connection task, answered from the event loop
+    Ping(oneshot::Sender<()>),
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -1750,19 +1993,35 @@ pub struct SessionStatus {
     pub handshaking: bool,
 }
 
+///What was removed from a node by purging a session
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PurgeReport {
+    ///The client was online and kicked
+    pub kicked: bool,
+    pub routes: usize,
+    pub sessions: usize,
+    ///The stored messages no longer marked as forwarded to the client
+    pub messages: usize,
+}
+
 #[derive(Deserialize, Serialize, Debug, Default, Clone)]
 pub struct SubsSearchParams {
     #[serde(default)]
     pub _limit: usize,
+    #[serde(default)]
+    pub _offset: usize,
     pub clientid: Option<String>,
+    pub clientid_prefix: Option<String>,
     pub topic: Option<String>,
+    pub topic_prefix: Option<String>,
     //value is 0,1,2
     pub qos: Option<u8>,
     pub share: Option<SharedGroup>,
+    pub node_id: Option<NodeId>,
     pub _match_topic: Option<String>,
 }
 
-#[derive(Deserialize, Serialize, Debug, Default)]
+#[derive(Deserialize, Serialize, Debug, Default, Clone)]
 pub struct SubsSearchResult {
     pub node_id: NodeId,
     pub clientid: ClientId,
@@ -1917,6 +2176,14 @@ impl SessionSubs {
         self.subs.read().await.len()
     }
 
+    ///Number of the wildcard subscriptions, and of those with a multi-level wildcard
+    #[inline]
+    pub async fn wildcard_len(&self) -> (usize, usize) {
+        self.subs.read().await.iter().fold((0, 0), |(wildcards, multilevels), (tf, _)| {
+            (wildcards + tf.contains(['+', '#']) as usize, multilevels + tf.contains('#') as usize)
+        })
+    }
+
     #[inline]
     pub async fn shared_len(&self) -> usize {
         self.subs.read().await.iter().filter(|(_, opts)| opts.has_shared_group()).count()
@@ -2049,6 +2316,11 @@ impl ExtraAttrs {
         self.attrs.insert(key, Box::new(value));
     }
 
+    #[inline]
+    pub fn remove(&mut self, key: &str) {
+        self.attrs.remove(key);
+    }
+
     #[inline]
     pub fn get<T: Any + Sync + Send>(&self, key: &str) -> Option<&T> {
         self.attrs.get(key).and_then(|v| v.downcast_ref::<T>())
@@ -2101,6 +2373,14 @@ impl<V> TimedValue<V> {
     pub fn is_expired(&self) -> bool {
         self.1.map(|e| Instant::now() >= e).unwrap_or(false)
     }
+
+    ///The expiration time, in milliseconds, None if the value does not expire
+    pub fn expiry_time_at(&self) -> Option<TimestampMillis> {
+        self.1.map(|e| {
+            chrono::Local::now().timestamp_millis()
+                + e.saturating_duration_since(Instant::now()).as_millis() as i64
+        })
+    }
 }
 
 impl<V> PartialEq for TimedValue<V>
@@ -2177,6 +2457,14 @@ pub enum Reason {
     Reasons(Vec<Reason>),
     #[default]
     Unknown,
+    ServerDraining,
+    ConnectZombie,
+    MessageDuplicate,
+    WillMessageCanceled,
+    WillMessageSuppressed,
+    MessageRetryExhausted,
+    ///Redirected to another server, the reason code and server reference sent to MQTT 5.0 clients
+    ServerRedirect(DrainReason, Option<ByteString>),
 }
 
 impl Reason {
@@ -2296,6 +2584,25 @@ impl Display for Reason {
             Reason::Unknown => {
                 "Unknown" //unknown
             }
+            Reason::ServerDraining => {
+                "ServerDraining" //the node is draining
+            }
+            Reason::ConnectZombie => {
+                "Zombie" //no activity and the connection task does not respond, reaped
+            }
+            Reason::MessageDuplicate => {
+                "MessageDuplicate" //the client has published the same message recently
+            }
+            Reason::WillMessageCanceled => {
+                "WillMessageCanceled" //the client reconnected within the will delay interval
+            }
+            Reason::WillMessageSuppressed => {
+                "WillMessageSuppressed" //suppressed by the will_message_publish hook
+            }
+            Reason::MessageRetryExhausted => {
+                "MessageRetryExhausted" //redelivered message_retry_max_attempts times without ack
+            }
+            Reason::ServerRedirect(reason, _) => return write!(f, "ServerRedirect({})", reason.as_str()),
         };
         write!(f, "{}", r)
     }
@@ -2508,3 +2815,85 @@ fn test_reason() {
     ]);
     assert_eq!(reasons.to_string(), "PublishRefused,Kicked,MessageExpiration");
 }
+
+#[test]
+fn test_publish_into_mutable() {
+    let publish = Publish {
+        dup: false,
+        retain: false,
+        qos: QoS::AtMostOnce,
+        topic: TopicName::from("test/a"),
+        packet_id: None,
+        payload: Bytes::from(vec![1u8, 2, 3]),
+        properties: PublishProperties::default(),
+        create_time: 0,
+    };
+    let ptr = publish.payload.as_ptr();
+
+    //Shared with a clone, copied on write
+    let mut p = publish.clone().into_mutable();
+    p.payload_mut()[0] = 9;
+    let p = p.freeze();
+    assert_eq!(p.payload.as_ref(), &[9, 2, 3]);
+    assert_ne!(publish.payload.as_ptr(), p.payload.as_ptr());
+    assert_eq!(publish.payload.as_ref(), &[1, 2, 3]);
+
+    //The only reference, taken over with no copy
+    let mut p = publish.into_mutable();
+    p.payload_mut()[0] = 8;
+    let p = p.freeze();
+    assert_eq!(p.payload.as_ptr(), ptr);
+    assert_eq!(p.payload.as_ref(), &[8, 2, 3]);
+}
+
+#[test]
+fn test_session_sub_map_persistence() {
+    let mut subs = SessionSubMap::default();
+    subs.insert(
+        TopicFilter::from("test/v5/#"),
+        SubscriptionOptions::V5(SubOptionsV5 {
+            qos: QoS::ExactlyOnce,
+            shared_group: Some(SharedGroup::from("g1")),
+            no_local: true,
+            retain_as_published: true,
+            retain_handling: RetainHandling::NoAtSubscribe,
+            id: NonZeroU32::new(42),
+        }),
+    );
+    subs.insert(
+        TopicFilter::from("test/v3/+"),
+        SubscriptionOptions::V3(SubOptionsV3 { qos: QoS::AtLeastOnce, shared_group: None }),
+    );
+
+    let data = bincode::serialize(&subs).unwrap();
+    let restored: SessionSubMap = bincode::deserialize(&data).unwrap();
+    assert_eq!(restored, subs);
+
+    let opts = restored.get("test/v5/#").unwrap();
+    assert_eq!(opts.subscription_identifier(), NonZeroU32::new(42));
+    assert_eq!(opts.no_local(), Some(true));
+    assert_eq!(opts.retain_as_published(), Some(true));
+    assert_eq!(opts.retain_handling(), Some(RetainHandling::NoAtSubscribe));
+}
+
+#[test]
+fn test_retain_flag() {
+    let v3 = SubscriptionOptions::V3(SubOptionsV3 { qos: QoS::AtMostOnce, shared_group: None });
+    assert!(!v3.retain_flag(true));
+    assert!(!v3.retain_flag(false));
+
+    let v5 = |retain_as_published| {
+        SubscriptionOptions::V5(SubOptionsV5 {
+            qos: QoS::AtMostOnce,
+            shared_group: None,
+            no_local: false,
+            retain_as_published,
+            retain_handling: RetainHandling::AtSubscribe,
+            id: None,
+        })
+    };
+    assert!(v5(true).retain_flag(true));
+    assert!(!v5(true).retain_flag(false));
+    assert!(!v5(false).retain_flag(true));
+    assert!(!v5(false).retain_flag(false));
+}
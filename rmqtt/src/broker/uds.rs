@@ -0,0 +1,158 @@
+//! Unix domain socket listeners, MQTT over UDS for the sidecar apps on the same host, without the
+//! overhead of the TCP loopback. The socket is created at `path`, with the file mode `permissions`,
+//! a stale socket left at the path is replaced, one in use is not. The `addr` of the listener is not bound, its port
+//! identifies the listener in the configs, the stats and the sessions, it is the local address of
+//! the connections. They have no IP address, each gets a distinct loopback address, 127.x.y.z:port
+//! from a counter, as its remote address, for the features keyed by the client address.
+//!
+//! The credentials of the peer process, uid, gid and pid, are kept during the MQTT handshake,
+//! `ConnectInfo::peer_cred`, for the auth plugins to use in their decisions.
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use once_cell::sync::OnceCell;
+
+use crate::broker::types::DashMap;
+#[cfg(unix)]
+use crate::settings::listener::Listener;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerCred {
+    pub uid: u32,
+    pub gid: u32,
+    pub pid: Option<i32>,
+}
+
+#[cfg(unix)]
+impl From<tokio::net::unix::UCred> for PeerCred {
+    #[inline]
+    fn from(cred: tokio::net::unix::UCred) -> Self {
+        Self { uid: cred.uid(), gid: cred.gid(), pid: cred.pid() }
+    }
+}
+
+pub struct UdsPeers {
+    //(remote addr, local addr) => credentials, the connections in their MQTT handshake
+    connecting: DashMap<(SocketAddr, SocketAddr), PeerCred>,
+    next: AtomicU64,
+}
+
+impl UdsPeers {
+    #[inline]
+    pub fn instance() -> &'static Self {
+        static INSTANCE: OnceCell<UdsPeers> = OnceCell::new();
+        INSTANCE.get_or_init(|| Self { connecting: DashMap::default(), next: AtomicU64::new(1) })
+    }
+
+    ///The remote address of a new connection
+    #[inline]
+    pub fn next_remote_addr(&self) -> SocketAddr {
+        remote_addr(self.next.fetch_add(1, Ordering::Relaxed))
+    }
+
+    ///Keeps the credentials of a connection during its MQTT handshake, until the guard is dropped
+    #[inline]
+    pub fn connecting(
+        &'static self,
+        remote_addr: SocketAddr,
+        local_addr: SocketAddr,
+        cred: Option<PeerCred>,
+    ) -> Option<PeerGuard> {
+        let cred = cred?;
+        self.connecting.insert((remote_addr, local_addr), cred);
+        Some(PeerGuard { peers: self, key: (remote_addr, local_addr) })
+    }
+
+    ///The credentials of the peer of a connection in its MQTT handshake
+    #[inline]
+    pub fn get(&self, remote_addr: SocketAddr, local_addr: SocketAddr) -> Option<PeerCred> {
+        self.connecting.get(&(remote_addr, local_addr)).map(|cred| *cred.value())
+    }
+}
+
+pub struct PeerGuard {
+    peers: &'static UdsPeers,
+    key: (SocketAddr, SocketAddr),
+}
+
+impl Drop for PeerGuard {
+    #[inline]
+    fn drop(&mut self) {
+        self.peers.connecting.remove(&self.key);
+    }
+}
+
+///Binds the socket of the listener, a stale socket at the path, one no process is listening on, is
+///removed first, a socket in use is an error
+#[cfg(unix)]
+pub fn bind(listen_cfg: &Listener) -> std::io::Result<std::os::unix::net::UnixListener> {
+    use std::io::{Error, ErrorKind};
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    if listen_cfg.path.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidInput, "uds listener, path is not set"));
+    }
+    if let Ok(meta) = std::fs::symlink_metadata(&listen_cfg.path) {
+        if !meta.file_type().is_socket() {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!("uds listener, {} exists and is not a socket", listen_cfg.path),
+            ));
+        }
+        if std::os::unix::net::UnixStream::connect(&listen_cfg.path).is_ok() {
+            return Err(Error::new(
+                ErrorKind::AddrInUse,
+                format!("uds listener, {} is in use by another process", listen_cfg.path),
+            ));
+        }
+        std::fs::remove_file(&listen_cfg.path)?;
+    }
+    let listener = std::os::unix::net::UnixListener::bind(&listen_cfg.path)?;
+    if let Some(mode) = listen_cfg.permissions {
+        std::fs::set_permissions(&listen_cfg.path, std::fs::Permissions::from_mode(mode))?;
+    }
+    Ok(listener)
+}
+
+//The low 16 bits are the port, the next 24 bits the last three bytes of the loopback address
+#[inline]
+fn remote_addr(n: u64) -> SocketAddr {
+    let ip = Ipv4Addr::new(127, (n >> 32) as u8, (n >> 24) as u8, (n >> 16) as u8);
+    SocketAddr::from((ip, n as u16))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::remote_addr;
+
+    #[test]
+    fn test_remote_addr() {
+        assert_eq!(remote_addr(1).to_string(), "127.0.0.0:1");
+        assert_eq!(remote_addr(65536).to_string(), "127.0.0.1:0");
+        assert_eq!(remote_addr((1 << 40) + 5).to_string(), "127.0.0.0:5");
+        assert_ne!(remote_addr(0x01_0203_0405), remote_addr(0x01_0203_0406));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_bind_socket_in_use() {
+        use crate::settings::listener::{Listener, ListenerInner};
+
+        let path = std::env::temp_dir().join(format!("rmqtt-uds-test-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listen_cfg =
+            Listener::new(ListenerInner { path: path.to_string_lossy().into(), ..Default::default() });
+
+        let listener = super::bind(&listen_cfg).unwrap();
+        let err = super::bind(&listen_cfg).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AddrInUse);
+
+        //The socket file is left behind, stale
+        drop(listener);
+        assert!(path.exists());
+        let listener = super::bind(&listen_cfg).unwrap();
+        drop(listener);
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -4,12 +4,24 @@ use std::net::SocketAddr;
 use std::sync::Arc;
 
 use rust_box::task_exec_queue::LocalSpawnExt;
-use uuid::Uuid;
 
+use crate::broker::anonymous;
+use crate::broker::audit::{AuditEvent, AuditLog};
+use crate::broker::clientid::ClientIdPolicies;
+use crate::broker::conformance::{self, Conformance};
+use crate::broker::dedup::Dedup;
 use crate::broker::executor::get_handshake_exec;
+use crate::broker::labels::Labels;
+use crate::broker::maintenance::Maintenance;
+use crate::broker::overload::Overload;
+use crate::broker::packet_size::PacketSize;
+use crate::broker::protocol_bridge;
+use crate::broker::publish_quota::PublishQuota;
+use crate::broker::quota::Quota;
 use crate::broker::{inflight::MomentStatus, types::*};
 use crate::runtime::Runtime;
 use crate::settings::listener::Listener;
+use crate::settings::{ShedAction, Violation, ViolationAction};
 use crate::{MqttError, Result, Session, SessionState};
 
 #[inline]
@@ -50,10 +62,22 @@ pub async fn handshake<Io: 'static>(
         listen_cfg
     );
 
-    if handshake.packet().client_id.is_empty() {
-        if handshake.packet().clean_session {
-            handshake.packet_mut().client_id =
-                ClientId::from(Uuid::new_v4().as_simple().encode_lower(&mut Uuid::encode_buffer()).to_owned())
+    //The fixed identity of the anonymous mode
+    if listen_cfg.anonymous_mode && !listen_cfg.anonymous_username.is_empty() {
+        handshake.packet_mut().username = Some(UserName::from(listen_cfg.anonymous_username.as_str()));
+        handshake.packet_mut().password = None;
+    }
+
+    let assigned_client_id = if handshake.packet().client_id.is_empty() {
+        let username = handshake.packet().username.as_deref();
+        let client_id = if handshake.packet().clean_session {
+            ClientIdPolicies::instance().get(username).assign(&listen_cfg, username)
+        } else {
+            None
+        };
+        if let Some(client_id) = client_id {
+            handshake.packet_mut().client_id = client_id;
+            true
         } else {
             log::info!(
                 "{:?} Connection Refused, handshake error, reason: invalid client id",
@@ -67,7 +91,9 @@ pub async fn handshake<Io: 'static>(
             );
             return Ok(ConnectAckReason::V3(ConnectAckReasonV3::IdentifierRejected).v3_error_ack(handshake));
         }
-    }
+    } else {
+        false
+    };
 
     let id = Id::new(
         Runtime::instance().node.id(),
@@ -80,7 +106,7 @@ pub async fn handshake<Io: 'static>(
     Runtime::instance().stats.handshakings.max_max(handshake.handshakings());
 
     let exec = get_handshake_exec(local_addr.port(), listen_cfg.clone());
-    match _handshake(id.clone(), listen_cfg, handshake).spawn(&exec).result().await {
+    match _handshake(id.clone(), listen_cfg, handshake, assigned_client_id).spawn(&exec).result().await {
         Ok(Ok(res)) => Ok(res),
         Ok(Err(e)) => {
             log::warn!("{:?} Connection Refused, handshake error, reason: {:?}", id, e.to_string());
@@ -100,29 +126,97 @@ async fn _handshake<Io: 'static>(
     id: Id,
     listen_cfg: Listener,
     mut handshake: v3::Handshake<Io>,
+    is_assigned_client_id: bool,
 ) -> Result<v3::HandshakeAck<Io, SessionState>, MqttError> {
     let connect_info = Arc::new(ConnectInfo::V3(id.clone(), handshake.packet().clone()));
 
     //hook, client connect
     let _ = Runtime::instance().extends.hook_mgr().await.client_connect(&connect_info).await;
 
-    if listen_cfg.max_clientid_len > 0 && id.client_id.len() > listen_cfg.max_clientid_len {
+    if Runtime::instance().node.is_draining() {
+        return Ok(refused_ack(
+            handshake,
+            &connect_info,
+            ConnectAckReasonV3::ServiceUnavailable,
+            "node is draining".into(),
+        )
+        .await);
+    }
+
+    if let Some(reason) = Maintenance::instance().refuse_connect() {
+        return Ok(refused_ack(
+            handshake,
+            &connect_info,
+            ConnectAckReasonV3::ServiceUnavailable,
+            reason.into(),
+        )
+        .await);
+    }
+
+    //MQTT 3 has no server reference, the redirected clients are refused as well
+    if Overload::instance().is_shedding(ShedAction::RejectConnect)
+        || Overload::instance().is_shedding(ShedAction::Redirect)
+    {
+        Runtime::instance().metrics.client_connect_overload_inc();
+        return Ok(refused_ack(
+            handshake,
+            &connect_info,
+            ConnectAckReasonV3::ServiceUnavailable,
+            "server is overloaded".into(),
+        )
+        .await);
+    }
+
+    if !is_assigned_client_id {
+        let policy = ClientIdPolicies::instance().get(id.username.as_deref());
+        if let Err(e) = policy.validate(&listen_cfg, &id.client_id) {
+            return Ok(refused_ack(
+                handshake,
+                &connect_info,
+                ConnectAckReasonV3::IdentifierRejected,
+                e.to_string(),
+            )
+            .await);
+        }
+    }
+
+    if listen_cfg.strict_conformance
+        && !is_assigned_client_id
+        && !conformance::is_valid_client_id(&id.client_id)
+        && Conformance::instance().record(
+            &listen_cfg,
+            &id,
+            Violation::OversizedClientId,
+            &format!("client_id length: {}", id.client_id.len()),
+        ) == ViolationAction::Disconnect
+    {
         return Ok(refused_ack(
             handshake,
             &connect_info,
             ConnectAckReasonV3::IdentifierRejected,
-            "client_id is too long".into(),
+            "client_id is longer than 23 bytes".into(),
         )
         .await);
     }
 
-    //hook, client authenticate
-    let (ack, superuser) = Runtime::instance()
-        .extends
-        .hook_mgr()
-        .await
-        .client_authenticate(&connect_info, listen_cfg.allow_anonymous)
-        .await;
+    //hook, client authenticate, bypassed in the anonymous mode
+    let (ack, superuser, auth_info) = if listen_cfg.anonymous_mode {
+        (ConnectAckReason::V3(ConnectAckReasonV3::ConnectionAccepted), false, Some(AuthInfo::new_anonymous()))
+    } else {
+        Runtime::instance()
+            .extends
+            .hook_mgr()
+            .await
+            .client_authenticate(&connect_info, listen_cfg.allow_anonymous)
+            .await
+    };
+    let auth_info = auth_info.map(|auth_info| anonymous::limits(&listen_cfg, auth_info));
+    let event = if ack.success() {
+        AuditEvent::AuthSuccess { id: id.clone(), superuser }
+    } else {
+        AuditEvent::AuthFailure { id: id.clone(), reason: format!("{:?}", ack) }
+    };
+    AuditLog::instance().record(event).await;
     if !ack.success() {
         if let ConnectAckReason::V3(ack) = ack {
             return Ok(refused_ack(handshake, &connect_info, ack, "Authentication failed".into()).await);
@@ -131,6 +225,19 @@ async fn _handshake<Io: 'static>(
         }
     }
 
+    //hook, client_labels, with the labels of the auth plugins
+    let labels = Labels::of_connect(&connect_info, auth_info.as_ref()).await;
+
+    if let Err(e) = Quota::instance().check(&id, &labels).await {
+        return Ok(refused_ack(
+            handshake,
+            &connect_info,
+            ConnectAckReasonV3::ServiceUnavailable,
+            e.to_string(),
+        )
+        .await);
+    }
+
     let sink = handshake.sink();
     let packet = handshake.packet_mut();
 
@@ -160,6 +267,13 @@ async fn _handshake<Io: 'static>(
                 .await);
             }
             Ok(Some(offline_info)) => (!packet.clean_session, Some(offline_info)),
+            Ok(None) if !packet.clean_session => {
+                //hook, session_store_load, the session may be kept by an external store
+                match Runtime::instance().extends.hook_mgr().await.session_store_load(&id).await {
+                    Some(snapshot) => (true, Some(snapshot.into_offline_info())),
+                    None => (false, None),
+                }
+            }
             Ok(None) => (false, None),
         };
 
@@ -207,6 +321,13 @@ async fn _handshake<Io: 'static>(
         }
     };
 
+    let dedup = Dedup::new(session.listen_cfg(), auth_info.as_ref());
+    let publish_quota = PublishQuota::new(session.listen_cfg(), auth_info.as_ref());
+    if let Some(auth_info) = auth_info {
+        session.set_auth_info(auth_info).await;
+    }
+    session.set_labels(labels);
+
     let keep_alive = match session.fitter.keep_alive(&mut packet.keep_alive) {
         Ok(keep_alive) => keep_alive,
         Err(e) => {
@@ -227,7 +348,13 @@ async fn _handshake<Io: 'static>(
         hook.session_created().await;
     }
 
-    let (state, tx) = SessionState::new(session, Sink::V3(sink), hook, 0, 0).start(keep_alive).await;
+    let max_packet_size = PacketSize::limit(session.listen_cfg(), connect_info.as_ref()).await;
+    let (state, tx) = SessionState::new(session, Sink::V3(sink), hook, 0, 0)
+        .dedup(dedup)
+        .max_packet_size(max_packet_size)
+        .publish_quota(publish_quota)
+        .start(keep_alive)
+        .await;
     if let Err(e) = entry.set(state.session.clone(), tx).await {
         return Ok(refused_ack(
             handshake,
@@ -253,7 +380,10 @@ async fn _handshake<Io: 'static>(
     if let Some(o) = offline_info {
         let state1 = state.clone();
         let clean_session = packet.clean_session;
+        //the messages routed to the session are delivered after the previous ones
+        let hold = state.hold_deliveries();
         ntex::rt::spawn(async move {
+            let _hold = hold;
             if let Err(e) = state1.transfer_session_state(clean_session, o).await {
                 log::warn!("{:?} Failed to transfer session state, {}", state1.id, e);
             }
@@ -270,10 +400,15 @@ async fn subscribes(
     let shared_subscription_supported =
         Runtime::instance().extends.shared_subscription().await.is_supported(state.listen_cfg());
     for mut sub in subs.iter_mut() {
+        if state.listen_cfg().strict_conformance {
+            Conformance::instance().check_subscribe(state, sub.topic(), false).await?;
+        }
         let s = Subscribe::from_v3(sub.topic(), sub.qos(), shared_subscription_supported)?;
         let sub_ret = state.subscribe(s).await?;
         if let Some(qos) = sub_ret.success() {
             sub.confirm(qos)
+        } else if protocol_bridge::v3_suback_disconnect(sub_ret.ack_reason) {
+            return Err(MqttError::from(format!("subscribe failed, {:?}", sub_ret.ack_reason)));
         } else {
             sub.fail()
         }
@@ -360,6 +495,7 @@ pub async fn publish(state: v3::Session<SessionState>, pub_msg: v3::PublishMessa
 
     match pub_msg {
         v3::PublishMessage::Publish(publish) => {
+            let qos = publish.qos();
             let publish_fut = async move {
                 if let Err(e) = state.publish_v3(&publish).await {
                     log::warn!(
@@ -381,18 +517,29 @@ pub async fn publish(state: v3::Session<SessionState>, pub_msg: v3::PublishMessa
             } else {
                 publish_fut.await?;
             }
+            Overload::instance().slow_puback(qos).await;
         }
         v3::PublishMessage::PublishAck(packet_id) => {
-            if let Some(iflt_msg) = state.inflight_win().write().await.remove(&packet_id.get()) {
+            let mut inflight_win = state.inflight_win().write().await;
+            inflight_win.acked(&packet_id.get(), state.deliver_queue().len());
+            let iflt_msg = inflight_win.remove(&packet_id.get());
+            drop(inflight_win);
+            state.acked();
+            if let Some(iflt_msg) = iflt_msg {
                 //hook, message_ack
                 state.hook.message_acked(iflt_msg.from, &iflt_msg.publish).await;
             }
         }
         v3::PublishMessage::PublishReceived(packet_id) => {
-            state.inflight_win().write().await.update_status(&packet_id.get(), MomentStatus::UnComplete);
+            state.acked();
+            let mut inflight_win = state.inflight_win().write().await;
+            inflight_win.acked(&packet_id.get(), state.deliver_queue().len());
+            inflight_win.update_status(&packet_id.get(), MomentStatus::UnComplete);
         }
         v3::PublishMessage::PublishComplete(packet_id) => {
-            if let Some(iflt_msg) = state.inflight_win().write().await.remove(&packet_id.get()) {
+            let iflt_msg = state.inflight_win().write().await.remove(&packet_id.get());
+            state.acked();
+            if let Some(iflt_msg) = iflt_msg {
                 //hook, message_ack
                 state.hook.message_acked(iflt_msg.from, &iflt_msg.publish).await;
             }
@@ -3,15 +3,28 @@ use std::convert::From as _f;
 use std::net::SocketAddr;
 use std::sync::Arc;
 
+use ntex_mqtt::error::{DecodeError, ProtocolError};
 use ntex_mqtt::v5::codec::{Auth, PublishAckReason};
 use ntex_mqtt::v5::PublishAck;
 use ntex_mqtt::v5::PublishResult;
 use rust_box::task_exec_queue::LocalSpawnExt;
-use uuid::Uuid;
 
+use crate::broker::anonymous;
+use crate::broker::audit::{AuditEvent, AuditLog};
+use crate::broker::clientid::ClientIdPolicies;
+use crate::broker::conformance::{self, Conformance};
+use crate::broker::dedup::Dedup;
 use crate::broker::executor::get_handshake_exec;
+use crate::broker::labels::Labels;
+use crate::broker::maintenance::Maintenance;
+use crate::broker::overload::Overload;
+use crate::broker::packet_size::PacketSize;
+use crate::broker::publish_quota::PublishQuota;
+use crate::broker::quota::Quota;
+use crate::broker::request_response::{self, RESPONSE_TOPIC_PREFIX_PROPERTY};
 use crate::broker::{inflight::MomentStatus, types::*};
 use crate::settings::listener::Listener;
+use crate::settings::{ShedAction, Violation, ViolationAction};
 use crate::{MqttError, Result, Runtime, Session, SessionState};
 
 #[inline]
@@ -37,6 +50,20 @@ async fn refused_ack<Io>(
     new_ack_code.v5_error_ack(handshake)
 }
 
+//Refused with the server reference, where the client should reconnect to
+#[inline]
+async fn redirected_ack<Io>(
+    handshake: v5::Handshake<Io>,
+    connect_info: &ConnectInfo,
+    ack_code: ConnectAckReasonV5,
+    server_reference: Option<ByteString>,
+    reason: String,
+) -> v5::HandshakeAck<Io, SessionState> {
+    refused_ack(handshake, connect_info, ack_code, reason)
+        .await
+        .with(|ack: &mut v5::codec::ConnectAck| ack.server_reference = server_reference)
+}
+
 #[inline]
 pub async fn handshake<Io: 'static>(
     listen_cfg: Listener,
@@ -52,10 +79,20 @@ pub async fn handshake<Io: 'static>(
         listen_cfg
     );
 
+    //The fixed identity of the anonymous mode
+    if listen_cfg.anonymous_mode && !listen_cfg.anonymous_username.is_empty() {
+        handshake.packet_mut().username = Some(UserName::from(listen_cfg.anonymous_username.as_str()));
+        handshake.packet_mut().password = None;
+    }
+
     let assigned_client_id = if handshake.packet().client_id.is_empty() {
-        handshake.packet_mut().client_id =
-            ClientId::from(Uuid::new_v4().as_simple().encode_lower(&mut Uuid::encode_buffer()).to_owned());
-        true
+        let policy = ClientIdPolicies::instance().get(handshake.packet().username.as_deref());
+        if let Some(client_id) = policy.assign(&listen_cfg, handshake.packet().username.as_deref()) {
+            handshake.packet_mut().client_id = client_id;
+            true
+        } else {
+            false
+        }
     } else {
         false
     };
@@ -98,12 +135,82 @@ pub async fn _handshake<Io: 'static>(
     //hook, client connect
     let _user_props = Runtime::instance().extends.hook_mgr().await.client_connect(&connect_info).await;
 
-    if listen_cfg.max_clientid_len > 0 && id.client_id.len() > listen_cfg.max_clientid_len {
+    if Runtime::instance().node.is_draining() {
+        let drain = &Runtime::instance().settings.node.drain;
+        return Ok(redirected_ack(
+            handshake,
+            &connect_info,
+            drain.reason.connack_code(),
+            drain.server_reference(),
+            "node is draining".into(),
+        )
+        .await);
+    }
+
+    if let Some(reason) = Maintenance::instance().refuse_connect() {
+        let redirect = &Runtime::instance().settings.node.redirect;
+        return Ok(redirected_ack(
+            handshake,
+            &connect_info,
+            redirect.reason.connack_code(),
+            redirect.server_reference(),
+            reason.into(),
+        )
+        .await);
+    }
+
+    if Overload::instance().is_shedding(ShedAction::Redirect) {
+        let redirect = &Runtime::instance().settings.node.redirect;
+        Runtime::instance().metrics.client_connect_overload_inc();
+        return Ok(redirected_ack(
+            handshake,
+            &connect_info,
+            redirect.reason.connack_code(),
+            redirect.server_reference(),
+            "server is overloaded, redirected".into(),
+        )
+        .await);
+    }
+
+    if Overload::instance().is_shedding(ShedAction::RejectConnect) {
+        Runtime::instance().metrics.client_connect_overload_inc();
+        return Ok(refused_ack(
+            handshake,
+            &connect_info,
+            ConnectAckReasonV5::ServerBusy,
+            "server is overloaded".into(),
+        )
+        .await);
+    }
+
+    if !is_assigned_client_id {
+        let policy = ClientIdPolicies::instance().get(id.username.as_deref());
+        if let Err(e) = policy.validate(&listen_cfg, &id.client_id) {
+            return Ok(refused_ack(
+                handshake,
+                &connect_info,
+                ConnectAckReasonV5::ClientIdentifierNotValid,
+                e.to_string(),
+            )
+            .await);
+        }
+    }
+
+    if listen_cfg.strict_conformance
+        && !is_assigned_client_id
+        && !conformance::is_valid_client_id(&id.client_id)
+        && Conformance::instance().record(
+            &listen_cfg,
+            &id,
+            Violation::OversizedClientId,
+            &format!("client_id length: {}", id.client_id.len()),
+        ) == ViolationAction::Disconnect
+    {
         return Ok(refused_ack(
             handshake,
             &connect_info,
             ConnectAckReasonV5::ClientIdentifierNotValid,
-            "client_id is too long".into(),
+            "client_id is longer than 23 bytes".into(),
         )
         .await);
     }
@@ -119,13 +226,24 @@ pub async fn _handshake<Io: 'static>(
         .await);
     }
 
-    //hook, client authenticate
-    let (ack, superuser) = Runtime::instance()
-        .extends
-        .hook_mgr()
-        .await
-        .client_authenticate(&connect_info, listen_cfg.allow_anonymous)
-        .await;
+    //hook, client authenticate, bypassed in the anonymous mode
+    let (ack, superuser, auth_info) = if listen_cfg.anonymous_mode {
+        (ConnectAckReason::V5(ConnectAckReasonV5::Success), false, Some(AuthInfo::new_anonymous()))
+    } else {
+        Runtime::instance()
+            .extends
+            .hook_mgr()
+            .await
+            .client_authenticate(&connect_info, listen_cfg.allow_anonymous)
+            .await
+    };
+    let auth_info = auth_info.map(|auth_info| anonymous::limits(&listen_cfg, auth_info));
+    let event = if ack.success() {
+        AuditEvent::AuthSuccess { id: id.clone(), superuser }
+    } else {
+        AuditEvent::AuthFailure { id: id.clone(), reason: format!("{:?}", ack) }
+    };
+    AuditLog::instance().record(event).await;
     if !ack.success() {
         if let ConnectAckReason::V5(ack) = ack {
             return Ok(refused_ack(handshake, &connect_info, ack, "Authentication failed".into()).await);
@@ -134,6 +252,15 @@ pub async fn _handshake<Io: 'static>(
         }
     }
 
+    //hook, client_labels, with the labels of the auth plugins
+    let labels = Labels::of_connect(&connect_info, auth_info.as_ref()).await;
+
+    if let Err(e) = Quota::instance().check(&id, &labels).await {
+        return Ok(
+            refused_ack(handshake, &connect_info, ConnectAckReasonV5::QuotaExceeded, e.to_string()).await
+        );
+    }
+
     let sink = handshake.sink();
     let packet = handshake.packet_mut();
 
@@ -163,6 +290,13 @@ pub async fn _handshake<Io: 'static>(
                 .await);
             }
             Ok(Some(offline_info)) => (!packet.clean_start, Some(offline_info)),
+            Ok(None) if !packet.clean_start => {
+                //hook, session_store_load, the session may be kept by an external store
+                match Runtime::instance().extends.hook_mgr().await.session_store_load(&id).await {
+                    Some(snapshot) => (true, Some(snapshot.into_offline_info())),
+                    None => (false, None),
+                }
+            }
             Ok(None) => (false, None),
         };
 
@@ -210,6 +344,25 @@ pub async fn _handshake<Io: 'static>(
         }
     };
 
+    let dedup = Dedup::new(session.listen_cfg(), auth_info.as_ref());
+    let publish_quota = PublishQuota::new(session.listen_cfg(), auth_info.as_ref());
+    if let Some(auth_info) = auth_info {
+        session.set_auth_info(auth_info).await;
+    }
+    session.set_labels(labels);
+
+    let hook = Runtime::instance().extends.hook_mgr().await.hook(&session);
+
+    //hook, client_connack_props
+    let ack_props = hook.client_connack_props().await;
+    if let Some(server_keepalive_sec) = ack_props.as_ref().and_then(|props| props.server_keepalive_sec) {
+        if session.listen_cfg().honor_server_keepalive {
+            packet.keep_alive = server_keepalive_sec;
+        } else {
+            log::debug!("{:?} server keep alive {} ignored", session.id, server_keepalive_sec);
+        }
+    }
+
     let keep_alive = match session.fitter.keep_alive(&mut packet.keep_alive) {
         Ok(keep_alive) => keep_alive,
         Err(e) => {
@@ -223,17 +376,19 @@ pub async fn _handshake<Io: 'static>(
         }
     };
 
-    let hook = Runtime::instance().extends.hook_mgr().await.hook(&session);
-
     if offline_info.is_none() {
         //hook, session created
         hook.session_created().await;
     }
 
+    let max_packet_size = PacketSize::limit(session.listen_cfg(), connect_info.as_ref()).await;
     let client_topic_alias_max = session.fitter.max_client_topic_aliases();
     let server_topic_alias_max = session.fitter.max_server_topic_aliases();
     let (state, tx) =
         SessionState::new(session, Sink::V5(sink), hook, server_topic_alias_max, client_topic_alias_max)
+            .dedup(dedup)
+            .max_packet_size(max_packet_size)
+            .publish_quota(publish_quota)
             .start(keep_alive)
             .await;
 
@@ -262,7 +417,10 @@ pub async fn _handshake<Io: 'static>(
     if let Some(o) = offline_info {
         let state1 = state.clone();
         let clean_start = packet.clean_start;
+        //the messages routed to the session are delivered after the previous ones
+        let hold = state.hold_deliveries();
         ntex::rt::spawn(async move {
+            let _hold = hold;
             if let Err(e) = state1.transfer_session_state(clean_start, o).await {
                 log::warn!("{:?} Failed to transfer session state, {}", state1.id, e);
             }
@@ -281,10 +439,14 @@ pub async fn _handshake<Io: 'static>(
         Some(state.listen_cfg().max_qos_allowed)
     };
     let retain_available = Runtime::instance().extends.retain().await.is_supported(state.listen_cfg());
-    let max_server_packet_size = state.listen_cfg().max_packet_size.as_u32();
+    //The exempted clients may send packets up to the codec limit, 0 means unlimited
+    let max_server_packet_size =
+        state.max_packet_size.unwrap_or_else(|| PacketSize::codec_limit(state.listen_cfg()));
     let shared_subscription_available =
         Runtime::instance().extends.shared_subscription().await.is_supported(state.listen_cfg());
     let assigned_client_id = if is_assigned_client_id { Some(state.id.client_id.clone()) } else { None };
+    let response_topic_prefix = request_response::response_topic_prefix(state.listen_cfg(), &state.id);
+    let request_response_info = packet.request_response_info;
     Ok(handshake.ack(state).keep_alive(keep_alive).with(|ack: &mut v5::codec::ConnectAck| {
         ack.session_present = session_present;
         ack.server_keepalive_sec = Some(server_keepalive_sec);
@@ -292,12 +454,23 @@ pub async fn _handshake<Io: 'static>(
         ack.receive_max = Some(max_inflight);
         ack.max_qos = max_qos;
         ack.retain_available = Some(retain_available);
-        ack.max_packet_size = Some(max_server_packet_size);
+        ack.max_packet_size = if max_server_packet_size > 0 { Some(max_server_packet_size) } else { None };
         ack.assigned_client_id = assigned_client_id;
         ack.topic_alias_max = client_topic_alias_max;
         ack.wildcard_subscription_available = Some(true);
         ack.subscription_identifiers_available = Some(true);
         ack.shared_subscription_available = Some(shared_subscription_available);
+        if let Some(prefix) = response_topic_prefix {
+            ack.user_properties
+                .push((ByteString::from_static(RESPONSE_TOPIC_PREFIX_PROPERTY), prefix.clone()));
+            if request_response_info {
+                ack.response_info = Some(prefix);
+            }
+        }
+        if let Some(props) = ack_props {
+            ack.user_properties.extend(props.user_properties);
+            ack.reason_string = props.reason_string;
+        }
         log::debug!("{:?} handshake.ack: {:?}", id, ack);
     }))
 }
@@ -309,27 +482,48 @@ async fn subscribes(
     let shared_subscription_supported =
         Runtime::instance().extends.shared_subscription().await.is_supported(state.listen_cfg());
     let sub_id = subs.packet().id;
+    let mut sub_rets = Vec::new();
     for mut sub in subs.iter_mut() {
+        if state.listen_cfg().strict_conformance {
+            Conformance::instance().check_subscribe(state, sub.topic(), sub.options().no_local).await?;
+        }
         let s = Subscribe::from_v5(sub.topic(), sub.options(), shared_subscription_supported, sub_id)?;
-        let sub_ret = state.subscribe(s).await?;
+        let sub_ret = state.subscribe(s.clone()).await?;
         if let Some(qos) = sub_ret.success() {
             sub.confirm(qos)
         } else {
-            sub.fail(sub_ret.into_inner())
+            sub.fail(sub_ret.ack_reason)
         }
+        sub_rets.push((s, sub_ret));
+    }
+    //hook, client_suback_props
+    if let Some(props) = state.hook.client_suback_props(&sub_rets).await {
+        if let Some(reason) = props.reason_string {
+            subs = subs.ack_reason(reason);
+        }
+        subs = subs.ack_properties(|user_props| user_props.extend(props.user_properties));
     }
     Ok(subs.ack())
 }
 
 async fn unsubscribes(
     state: &v5::Session<SessionState>,
-    unsubs: v5::control::Unsubscribe,
+    mut unsubs: v5::control::Unsubscribe,
 ) -> Result<v5::ControlResult> {
     let shared_subscription_supported =
         Runtime::instance().extends.shared_subscription().await.is_supported(state.listen_cfg());
+    let mut unsubscribes = Vec::new();
     for topic_filter in unsubs.iter() {
         let unsub = Unsubscribe::from(topic_filter, shared_subscription_supported)?;
-        state.unsubscribe(unsub).await?;
+        state.unsubscribe(unsub.clone()).await?;
+        unsubscribes.push(unsub);
+    }
+    //hook, client_unsuback_props
+    if let Some(props) = state.hook.client_unsuback_props(&unsubscribes).await {
+        if let Some(reason) = props.reason_string {
+            unsubs = unsubs.ack_reason(reason);
+        }
+        unsubs = unsubs.ack_properties(|user_props| user_props.extend(props.user_properties));
     }
     Ok(unsubs.ack())
 }
@@ -399,6 +593,17 @@ pub async fn control_message<E: std::fmt::Debug>(
             err.ack(DisconnectReasonCode::ServerBusy)
         }
         v5::ControlMessage::ProtocolError(protocol_error) => {
+            if matches!(protocol_error.get_ref(), ProtocolError::Decode(DecodeError::MaxSizeExceeded)) {
+                PacketSize::instance().record(state.listen_cfg(), &state.id, "above the codec limit");
+            }
+            if state.listen_cfg().strict_conformance {
+                Conformance::instance().record(
+                    state.listen_cfg(),
+                    &state.id,
+                    Violation::MalformedPacket,
+                    &format!("{:?}", protocol_error.get_ref()),
+                );
+            }
             if let Err(e) = state.send(Message::Closed(Reason::ProtocolError(ByteString::from(format!(
                 "{:?}",
                 protocol_error.get_ref()
@@ -423,40 +628,61 @@ pub async fn publish(
 
     match pub_msg {
         v5::PublishMessage::Publish(publish) => {
+            let qos = publish.qos();
             let publish_fut = async move {
-                if let Err(e) = state.publish_v5(&publish).await {
-                    log::warn!(
-                        "{:?} Publish failed, reason: {:?}",
-                        state.id,
-                        state.disconnected_reason().await
-                    );
-                    Err(e)
-                } else {
-                    Ok(())
+                match state.publish_v5(&publish).await {
+                    Err(e) => {
+                        log::warn!(
+                            "{:?} Publish failed, reason: {:?}",
+                            state.id,
+                            state.disconnected_reason().await
+                        );
+                        Err(e)
+                    }
+                    Ok((_, feedback)) => Ok(feedback),
                 }
             };
-            if Runtime::instance().is_busy() {
+            let feedback = if Runtime::instance().is_busy() {
                 Runtime::local_exec()
                     .spawn(publish_fut)
                     .result()
                     .await
-                    .map_err(|e| MqttError::from(e.to_string()))??;
+                    .map_err(|e| MqttError::from(e.to_string()))??
             } else {
-                publish_fut.await?;
-            }
-            return Ok(PublishResult::PublishAck(PublishAck::new(PublishAckReason::Success)));
+                publish_fut.await?
+            };
+            //the publish over the quota is replied Quota Exceeded, the acks near it carry the quota
+            let ack = match feedback {
+                Some(feedback) if feedback.exceeded => {
+                    return Ok(PublishResult::PublishAck(feedback.to_ack()));
+                }
+                Some(feedback) => feedback.to_ack(),
+                None => PublishAck::new(PublishAckReason::Success),
+            };
+            Overload::instance().slow_puback(qos).await;
+            return Ok(PublishResult::PublishAck(ack));
         }
         v5::PublishMessage::PublishAck(ref ack) => {
-            if let Some(iflt_msg) = state.inflight_win().write().await.remove(&ack.packet_id.get()) {
+            let mut inflight_win = state.inflight_win().write().await;
+            inflight_win.acked(&ack.packet_id.get(), state.deliver_queue().len());
+            let iflt_msg = inflight_win.remove(&ack.packet_id.get());
+            drop(inflight_win);
+            state.acked();
+            if let Some(iflt_msg) = iflt_msg {
                 //hook, message_ack
                 state.hook.message_acked(iflt_msg.from, &iflt_msg.publish).await;
             }
         }
         v5::PublishMessage::PublishReceived(ref ack) => {
-            state.inflight_win().write().await.update_status(&ack.packet_id.get(), MomentStatus::UnComplete);
+            state.acked();
+            let mut inflight_win = state.inflight_win().write().await;
+            inflight_win.acked(&ack.packet_id.get(), state.deliver_queue().len());
+            inflight_win.update_status(&ack.packet_id.get(), MomentStatus::UnComplete);
         }
         v5::PublishMessage::PublishComplete(ref ack2) => {
-            if let Some(iflt_msg) = state.inflight_win().write().await.remove(&ack2.packet_id.get()) {
+            let iflt_msg = state.inflight_win().write().await.remove(&ack2.packet_id.get());
+            state.acked();
+            if let Some(iflt_msg) = iflt_msg {
                 //hook, message_ack
                 state.hook.message_acked(iflt_msg.from, &iflt_msg.publish).await;
             }
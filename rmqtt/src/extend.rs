@@ -5,6 +5,7 @@ use crate::broker::{
         DefaultFitterManager, DefaultHookManager, DefaultRetainStorage, DefaultRouter, DefaultSessionManager,
         DefaultShared, DefaultSharedSubscription,
     },
+    encryption::{DefaultEncryptionProvider, EncryptionProvider},
     fitter::FitterManager,
     hook::HookManager,
     session::SessionManager,
@@ -22,6 +23,7 @@ pub struct Manager {
     shared_subscription: RwLock<Box<dyn SharedSubscription>>,
     session_mgr: RwLock<Box<dyn SessionManager>>,
     message_mgr: RwLock<Box<dyn MessageManager>>,
+    encryption: RwLock<Box<dyn EncryptionProvider>>,
 }
 
 impl Manager {
@@ -36,6 +38,7 @@ impl Manager {
             shared_subscription: RwLock::new(Box::new(DefaultSharedSubscription::instance())),
             session_mgr: RwLock::new(Box::new(DefaultSessionManager::instance())),
             message_mgr: RwLock::new(Box::new(DefaultMessageManager::instance())),
+            encryption: RwLock::new(Box::new(DefaultEncryptionProvider::instance())),
         }
     }
 
@@ -113,4 +116,14 @@ impl Manager {
     pub async fn message_mgr_mut(&self) -> RwLockWriteGuard<'_, Box<dyn MessageManager>> {
         self.message_mgr.write().await
     }
+
+    #[inline]
+    pub async fn encryption(&self) -> RwLockReadGuard<'_, Box<dyn EncryptionProvider>> {
+        self.encryption.read().await
+    }
+
+    #[inline]
+    pub async fn encryption_mut(&self) -> RwLockWriteGuard<'_, Box<dyn EncryptionProvider>> {
+        self.encryption.write().await
+    }
 }
@@ -0,0 +1,145 @@
+//! Authentication of the gRPC requests of the nodes, for the clusters spanning untrusted networks.
+//!
+//! With `rpc.tls_cert` the server and the clients use TLS, the certificates of the peers are verified
+//! with the CA of the cluster `rpc.tls_ca_cert`, and the server requires the client certificates,
+//! mutual TLS, unless `rpc.tls_verify_client` is off. A client sends the shared token `rpc.auth_token`
+//! with each request, the server rejects the requests without it. A client sends its node id as well,
+//! with `rpc.check_peer` the server rejects the requests of a node that is not in the node list of the
+//! cluster, or that come from an address other than the one of that node. The requests rejected are
+//! counted in the metrics, `grpc.auth.rejected.token` and `grpc.auth.rejected.peer`.
+
+use std::net::IpAddr;
+
+use once_cell::sync::OnceCell;
+use tonic::metadata::{Ascii, MetadataValue};
+use tonic::transport::{Certificate, ClientTlsConfig, Identity, ServerTlsConfig};
+use tonic::{Request, Status};
+
+use crate::settings::secret;
+use crate::{MqttError, NodeId, Result, Runtime};
+
+const AUTHORIZATION: &str = "authorization";
+const NODE_ID: &str = "x-rmqtt-node-id";
+
+//"Bearer <token>", None if no token is configured
+fn bearer() -> Result<Option<&'static MetadataValue<Ascii>>> {
+    static BEARER: OnceCell<Option<MetadataValue<Ascii>>> = OnceCell::new();
+    BEARER
+        .get_or_try_init(|| {
+            let token = &Runtime::instance().settings.rpc.auth_token;
+            if token.is_empty() {
+                return Ok(None);
+            }
+            let token = secret::resolve_str(token)?;
+            MetadataValue::try_from(format!("Bearer {}", token.trim()))
+                .map(Some)
+                .map_err(|e| MqttError::from(format!("rpc.auth_token, {}", e)))
+        })
+        .map(|bearer| bearer.as_ref())
+}
+
+///Adds the token and the node id to a request to a peer
+#[inline]
+pub(crate) fn authorize<T>(req: &mut Request<T>) -> Result<()> {
+    if let Some(bearer) = bearer()? {
+        req.metadata_mut().insert(AUTHORIZATION, bearer.clone());
+    }
+    req.metadata_mut().insert(NODE_ID, MetadataValue::from(Runtime::instance().node.id()));
+    Ok(())
+}
+
+///Checks a request received from a peer, the error is the rejection
+pub(crate) async fn check<T>(req: &Request<T>) -> std::result::Result<(), Status> {
+    if let Some(bearer) = bearer().map_err(|e| Status::internal(e.to_string()))? {
+        if req.metadata().get(AUTHORIZATION) != Some(bearer) {
+            Runtime::instance().metrics.grpc_auth_rejected_token_inc();
+            log::warn!("gRPC request from {:?} rejected, invalid token", req.remote_addr());
+            return Err(Status::unauthenticated("invalid token"));
+        }
+    }
+    if Runtime::instance().settings.rpc.check_peer {
+        if let Err(e) = check_peer(req).await {
+            Runtime::instance().metrics.grpc_auth_rejected_peer_inc();
+            log::warn!("gRPC request from {:?} rejected, {}", req.remote_addr(), e);
+            return Err(Status::permission_denied(e.to_string()));
+        }
+    }
+    Ok(())
+}
+
+//The node of the request is in the node list and the request comes from its address
+async fn check_peer<T>(req: &Request<T>) -> Result<()> {
+    let node_id = req
+        .metadata()
+        .get(NODE_ID)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<NodeId>().ok())
+        .ok_or_else(|| MqttError::from("no node id"))?;
+    let remote_ip = req.remote_addr().map(|addr| addr.ip()).ok_or_else(|| MqttError::from("no address"))?;
+    let addr = Runtime::instance()
+        .extends
+        .shared()
+        .await
+        .get_grpc_clients()
+        .get(&node_id)
+        .map(|(addr, _)| addr.clone())
+        .ok_or_else(|| MqttError::from(format!("node {} is not in the node list", node_id)))?;
+    let ips = tokio::net::lookup_host(addr.as_ref()).await?.map(|a| a.ip()).collect::<Vec<IpAddr>>();
+    if ips.contains(&remote_ip) {
+        Ok(())
+    } else {
+        Err(MqttError::from(format!("node {} is {}, not {}", node_id, addr, remote_ip)))
+    }
+}
+
+//The certificate and the key of this node, and the CA of the cluster, None if TLS is not configured
+fn tls_files() -> Result<Option<(Identity, Certificate)>> {
+    let rpc = &Runtime::instance().settings.rpc;
+    match (&rpc.tls_cert, &rpc.tls_key, &rpc.tls_ca_cert) {
+        (None, None, None) => Ok(None),
+        (Some(cert), Some(key), Some(ca_cert)) => Ok(Some((
+            Identity::from_pem(std::fs::read(cert)?, std::fs::read(key)?),
+            Certificate::from_pem(std::fs::read(ca_cert)?),
+        ))),
+        _ => Err(MqttError::from("rpc.tls_cert, rpc.tls_key and rpc.tls_ca_cert are all required")),
+    }
+}
+
+///The TLS config of the server, None if TLS is not configured
+pub(crate) fn server_tls() -> Result<Option<ServerTlsConfig>> {
+    let (identity, ca_cert) = if let Some(files) = tls_files()? { files } else { return Ok(None) };
+    let tls = ServerTlsConfig::new().identity(identity);
+    if Runtime::instance().settings.rpc.tls_verify_client {
+        Ok(Some(tls.client_ca_root(ca_cert)))
+    } else {
+        Ok(Some(tls))
+    }
+}
+
+///The TLS config of a client of the peer, None if TLS is not configured
+pub(crate) fn client_tls(server_addr: &str) -> Result<Option<ClientTlsConfig>> {
+    let (identity, ca_cert) = if let Some(files) = tls_files()? { files } else { return Ok(None) };
+    let domain_name =
+        Runtime::instance().settings.rpc.tls_domain_name.clone().unwrap_or_else(|| host(server_addr).into());
+    Ok(Some(ClientTlsConfig::new().domain_name(domain_name).ca_certificate(ca_cert).identity(identity)))
+}
+
+//The host of ip:port, host:port or [ipv6]:port
+#[inline]
+fn host(addr: &str) -> &str {
+    let host = addr.rsplit_once(':').map(|(host, _)| host).unwrap_or(addr);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::host;
+
+    #[test]
+    fn test_host() {
+        assert_eq!(host("10.0.0.1:5363"), "10.0.0.1");
+        assert_eq!(host("node1.cluster:5363"), "node1.cluster");
+        assert_eq!(host("[::1]:5363"), "::1");
+        assert_eq!(host("node1"), "node1");
+    }
+}
@@ -1,21 +1,101 @@
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 
 //use tokio::sync::mpsc::{
 //    unbounded_channel as channel, UnboundedReceiver as Receiver, UnboundedSender as Sender,
 //};
-use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::mpsc::{channel, Receiver, Sender, WeakSender};
 use tokio::sync::oneshot::Sender as OneshotSender;
-use tokio::sync::RwLock;
+use tokio::sync::{Notify, RwLock};
+use tokio::time::Instant;
 use tonic::transport::{Channel, Endpoint};
 
-use crate::{MqttError, Result, Runtime};
+use crate::settings::QueueOverflowPolicy;
+use crate::{MqttError, Reason, Result, Runtime};
 
+use super::auth;
 use super::pb::{self, node_service_client::NodeServiceClient};
+use super::spill::SpillQueue;
 use super::{Message, MessageReply, MessageType};
 
 type NodeServiceClientType = NodeServiceClient<Channel>;
+type ChannelMessage = (MessageType, Message, OneshotSender<Result<MessageReply>>);
+
+//The reply sender of a message, those of the messages coalesced into a BatchForwards
+enum Replier {
+    One(OneshotSender<Result<MessageReply>>),
+    Batch(Vec<OneshotSender<Result<MessageReply>>>),
+}
+
+impl Replier {
+    fn reply(self, reply: Result<MessageReply>) {
+        match self {
+            Replier::One(r_tx) => Self::send(r_tx, reply),
+            Replier::Batch(r_txs) => match reply {
+                Ok(MessageReply::BatchForwards(replys)) if replys.len() == r_txs.len() => {
+                    for (r_tx, reply) in r_txs.into_iter().zip(replys) {
+                        Self::send(r_tx, Ok(reply));
+                    }
+                }
+                Ok(MessageReply::Error(e)) => Self::send_err(r_txs, &e),
+                Err(e) => Self::send_err(r_txs, &e.to_string()),
+                Ok(reply) => {
+                    Self::send_err(r_txs, &format!("unexpected reply of BatchForwards, {:?}", reply))
+                }
+            },
+        }
+    }
+
+    #[inline]
+    fn send_err(r_txs: Vec<OneshotSender<Result<MessageReply>>>, e: &str) {
+        for r_tx in r_txs {
+            Self::send(r_tx, Err(MqttError::from(e)));
+        }
+    }
+
+    #[inline]
+    fn send(r_tx: OneshotSender<Result<MessageReply>>, reply: Result<MessageReply>) {
+        if !r_tx.is_closed() {
+            if let Err(r) = r_tx.send(reply) {
+                log::warn!("Failed to return result, reply message: {:?}", r);
+            }
+        }
+    }
+}
+
+#[inline]
+fn is_forward(msg: &Message) -> bool {
+    matches!(msg, Message::Forwards(..) | Message::ForwardsTo(..))
+}
+
+///Congestion of the send queue, raised above the high watermark and cleared at the low watermark
+#[derive(Default)]
+struct Congestion {
+    congested: AtomicBool,
+    cleared: Notify,
+    dropped: AtomicUsize,
+}
+
+impl Congestion {
+    #[inline]
+    fn update(&self, queue_len: usize, endpoint: &Endpoint) {
+        let rpc = &Runtime::instance().settings.rpc;
+        if queue_len >= rpc.queue_high_watermark {
+            if !self.congested.swap(true, Ordering::SeqCst) {
+                log::warn!("gRPC queue congested, {:?}, queue len: {}", endpoint.uri(), queue_len);
+            }
+        } else if queue_len <= rpc.queue_low_watermark && self.congested.swap(false, Ordering::SeqCst) {
+            log::info!("gRPC queue congestion cleared, {:?}, queue len: {}", endpoint.uri(), queue_len);
+            self.cleared.notify_waiters();
+        }
+    }
+
+    #[inline]
+    fn is_congested(&self) -> bool {
+        self.congested.load(Ordering::SeqCst)
+    }
+}
 
 #[derive(Clone)]
 pub struct NodeGrpcClient {
@@ -23,7 +103,9 @@ pub struct NodeGrpcClient {
     active_tasks: Arc<AtomicUsize>,
     channel_tasks: Arc<AtomicUsize>,
     endpoint: Endpoint,
-    tx: Sender<(MessageType, Message, OneshotSender<Result<MessageReply>>)>,
+    tx: Sender<ChannelMessage>,
+    congestion: Arc<Congestion>,
+    spill: Option<Arc<SpillQueue>>,
 }
 
 impl NodeGrpcClient {
@@ -32,19 +114,42 @@ impl NodeGrpcClient {
     pub async fn new(server_addr: &str) -> Result<Self> {
         log::debug!("rpc.client_timeout: {:?}", Runtime::instance().settings.rpc.client_timeout);
         let concurrency_limit = Runtime::instance().settings.rpc.client_concurrency_limit + 1;
-        let endpoint = Channel::from_shared(format!("http://{}", server_addr))
+        let tls = auth::client_tls(server_addr)?;
+        let scheme = if tls.is_some() { "https" } else { "http" };
+        let endpoint = Channel::from_shared(format!("{}://{}", scheme, server_addr))
             .map(|endpoint| {
                 endpoint
                     .concurrency_limit(concurrency_limit)
                     .timeout(Runtime::instance().settings.rpc.client_timeout)
             })
             .map_err(anyhow::Error::new)?;
+        let endpoint = match tls {
+            Some(tls) => endpoint.tls_config(tls).map_err(anyhow::Error::new)?,
+            None => endpoint,
+        };
         let active_tasks = Arc::new(AtomicUsize::new(0));
         let channel_tasks = Arc::new(AtomicUsize::new(0));
         let grpc_client = Arc::new(RwLock::new(None));
+        let rpc = &Runtime::instance().settings.rpc;
+        let spill = if rpc.queue_overflow_policy == QueueOverflowPolicy::Spill {
+            Some(Arc::new(SpillQueue::new(&rpc.queue_spill_dir, server_addr, rpc.queue_spill_max)?))
+        } else {
+            None
+        };
         let (tx, rx) = channel(100_000);
-        let c = Self { grpc_client, active_tasks, channel_tasks, endpoint, tx };
+        let c = Self {
+            grpc_client,
+            active_tasks,
+            channel_tasks,
+            endpoint,
+            tx,
+            congestion: Arc::new(Congestion::default()),
+            spill,
+        };
         c.start(rx);
+        if let Some(spill) = &c.spill {
+            Self::start_spill(spill.clone(), c.congestion.clone(), c.channel_tasks.clone(), c.tx.downgrade());
+        }
         Ok(c)
     }
 
@@ -58,6 +163,24 @@ impl NodeGrpcClient {
         self.channel_tasks.load(Ordering::SeqCst)
     }
 
+    ///The send queue is above the high watermark and has not yet fallen to the low watermark
+    #[inline]
+    pub fn is_congested(&self) -> bool {
+        self.congestion.is_congested()
+    }
+
+    ///Number of the messages dropped by the overflow policy
+    #[inline]
+    pub fn dropped(&self) -> usize {
+        self.congestion.dropped.load(Ordering::SeqCst)
+    }
+
+    ///Number of the messages in the spill queue
+    #[inline]
+    pub fn spilled(&self) -> usize {
+        self.spill.as_ref().map(|s| s.len()).unwrap_or_default()
+    }
+
     #[inline]
     async fn _connect(endpoint: &Endpoint) -> Result<NodeServiceClientType> {
         let channel =
@@ -81,12 +204,22 @@ impl NodeGrpcClient {
 
     #[inline]
     pub async fn batch_send_message(&self, typ: MessageType, msg: Message) -> Result<MessageReply> {
+        self.congestion.update(self.channel_tasks(), &self.endpoint);
+        let msg = if self.is_congested() {
+            match self.overflow(typ, msg).await? {
+                Some(msg) => msg,
+                None => return Ok(MessageReply::Success),
+            }
+        } else {
+            msg
+        };
+
         let (r_tx, r_rx) = tokio::sync::oneshot::channel::<Result<MessageReply>>();
-        self.tx
-            .send((typ, msg, r_tx))
-            .await
-            .map(|_| self.channel_tasks.fetch_add(1, Ordering::SeqCst))
-            .map_err(|e| anyhow::Error::msg(e.to_string()))?;
+        self.channel_tasks.fetch_add(1, Ordering::SeqCst);
+        if let Err(e) = self.tx.send((typ, msg, r_tx)).await {
+            self.channel_tasks.fetch_sub(1, Ordering::SeqCst);
+            return Err(anyhow::Error::msg(e.to_string()).into());
+        }
         let reply = r_rx.await.map_err(anyhow::Error::new)??;
         let reply = match reply {
             MessageReply::Error(e) => return Err(MqttError::from(e)),
@@ -100,6 +233,53 @@ impl NodeGrpcClient {
         self.batch_send_message(typ, msg).await
     }
 
+    ///Applies the overflow policy to a message sent to the congested peer, returns the message
+    ///when it can be queued, None when it has been spilled. Only the forwarded messages are
+    ///dropped or spilled, the others wait, a spilled message is not replied by the peer.
+    async fn overflow(&self, typ: MessageType, msg: Message) -> Result<Option<Message>> {
+        let rpc = &Runtime::instance().settings.rpc;
+        match (rpc.queue_overflow_policy, &msg, &self.spill) {
+            (QueueOverflowPolicy::Drop, Message::Forwards(..) | Message::ForwardsTo(..), _) => {
+                Err(self.drop_message(msg).await)
+            }
+            (QueueOverflowPolicy::Spill, Message::ForwardsTo(..), Some(spill)) => {
+                if let Err(e) = spill.push(typ, &msg).await {
+                    log::warn!("{:?} spill error, {:?}", self.endpoint.uri(), e);
+                    Err(self.drop_message(msg).await)
+                } else {
+                    Ok(None)
+                }
+            }
+            _ => {
+                let deadline = Instant::now() + rpc.queue_block_timeout;
+                loop {
+                    let cleared = self.congestion.cleared.notified();
+                    if !self.is_congested() {
+                        return Ok(Some(msg));
+                    }
+                    if tokio::time::timeout_at(deadline, cleared).await.is_err() {
+                        return Err(MqttError::from("gRPC queue is congested, send timeout"));
+                    }
+                }
+            }
+        }
+    }
+
+    #[inline]
+    async fn drop_message(&self, msg: Message) -> MqttError {
+        self.congestion.dropped.fetch_add(1, Ordering::SeqCst);
+        if let Message::Forwards(from, publish) | Message::ForwardsTo(from, publish, _) = msg {
+            //hook, message_dropped
+            Runtime::instance()
+                .extends
+                .hook_mgr()
+                .await
+                .message_dropped(None, from, publish, Reason::MessageQueueFull)
+                .await;
+        }
+        MqttError::from("gRPC queue is congested, message dropped")
+    }
+
     #[inline]
     async fn inner_send_message(&self, typ: MessageType, msg: Message) -> Result<MessageReply> {
         let mut grpc_client = self.connect().await?;
@@ -115,10 +295,9 @@ impl NodeGrpcClient {
         typ: MessageType,
         msg: Message,
     ) -> Result<MessageReply> {
-        let response = c
-            .send_message(tonic::Request::new(pb::Message { typ, data: msg.encode()? }))
-            .await
-            .map_err(anyhow::Error::new)?;
+        let mut req = tonic::Request::new(pb::Message { typ, data: msg.encode()? });
+        auth::authorize(&mut req)?;
+        let response = c.send_message(req).await.map_err(anyhow::Error::new)?;
         log::trace!("response: {:?}", response);
         let message_reply = response.into_inner();
         MessageReply::decode(&message_reply.data)
@@ -142,38 +321,54 @@ impl NodeGrpcClient {
         msgs: Vec<(MessageType, Message)>,
     ) -> Result<Vec<MessageReply>> {
         let data = bincode::serialize(&msgs).map_err(anyhow::Error::new)?;
-        let response = c
-            .batch_send_messages(tonic::Request::new(pb::BatchMessages { data }))
-            .await
-            .map_err(anyhow::Error::new)?;
+        let mut req = tonic::Request::new(pb::BatchMessages { data });
+        auth::authorize(&mut req)?;
+        let response = c.batch_send_messages(req).await.map_err(anyhow::Error::new)?;
         log::trace!("response: {:?}", response);
         let message_reply = response.into_inner();
 
         Ok(bincode::deserialize::<Vec<MessageReply>>(&message_reply.data).map_err(anyhow::Error::new)?)
     }
 
-    fn start(&self, mut rx: Receiver<(MessageType, Message, OneshotSender<Result<MessageReply>>)>) {
+    fn start(&self, mut rx: Receiver<ChannelMessage>) {
         let endpoint = self.endpoint.clone();
         let client = self.clone();
         let channel_tasks = self.channel_tasks.clone();
         tokio::task::spawn(async move {
             let mut merger_msgs = Vec::new();
             let mut merger_txs = Vec::new();
-            let batch_size = Runtime::instance().settings.rpc.batch_size;
+            let rpc = &Runtime::instance().settings.rpc;
+            let batch_size = rpc.batch_size;
+            let forward_batch_window = rpc.forward_batch_window;
+            let forward_batch_size = rpc.forward_batch_size.max(1);
             while let Some((typ, msg, r_tx)) = rx.recv().await {
                 channel_tasks.fetch_sub(1, Ordering::SeqCst);
+                client.congestion.update(client.channel_tasks(), &endpoint);
                 log::debug!("recv, type: {}, message: {:?}", typ, msg);
-                merger_msgs.push((typ, msg));
-                merger_txs.push(r_tx);
-                while merger_msgs.len() < batch_size {
-                    match tokio::time::timeout(Duration::from_millis(0), rx.recv()).await {
-                        Ok(Some((typ, msg, r_tx))) => {
-                            channel_tasks.fetch_sub(1, Ordering::SeqCst);
-                            log::debug!("try_recv, type: {}, message: {:?}", typ, msg);
-                            merger_msgs.push((typ, msg));
-                            merger_txs.push(r_tx);
+                //A forwarded message opens the coalescing window, the others take the messages queued
+                let mut forwards = 0;
+                let mut deadline = None;
+                let mut next = Some((typ, msg, r_tx));
+                while let Some((typ, msg, r_tx)) = next.take() {
+                    if is_forward(&msg) {
+                        forwards += 1;
+                        if deadline.is_none() && !forward_batch_window.is_zero() {
+                            deadline = Some(Instant::now() + forward_batch_window);
                         }
-                        _ => break,
+                    }
+                    merger_msgs.push((typ, msg));
+                    merger_txs.push(r_tx);
+                    if merger_msgs.len() >= batch_size
+                        || (deadline.is_some() && forwards >= forward_batch_size)
+                    {
+                        break;
+                    }
+                    let until = deadline.unwrap_or_else(Instant::now);
+                    if let Ok(Some((typ, msg, r_tx))) = tokio::time::timeout_at(until, rx.recv()).await {
+                        channel_tasks.fetch_sub(1, Ordering::SeqCst);
+                        client.congestion.update(client.channel_tasks(), &endpoint);
+                        log::debug!("try_recv, type: {}, message: {:?}", typ, msg);
+                        next = Some((typ, msg, r_tx));
                     }
                 }
                 log::debug!(
@@ -184,50 +379,124 @@ impl NodeGrpcClient {
                 //merge and send
                 let msgs = std::mem::take(&mut merger_msgs);
                 let r_txs = std::mem::take(&mut merger_txs);
+                let (msgs, repliers) = if forwards > 1 && deadline.is_some() {
+                    Self::coalesce(msgs, r_txs)
+                } else {
+                    (msgs, r_txs.into_iter().map(Replier::One).collect())
+                };
 
                 if client.active_tasks() < Runtime::instance().settings.rpc.client_concurrency_limit {
-                    tokio::task::spawn(Self::_send(client.clone(), msgs, r_txs));
+                    tokio::task::spawn(Self::_send(client.clone(), msgs, repliers));
                 } else {
-                    Self::_send(client.clone(), msgs, r_txs).await;
+                    Self::_send(client.clone(), msgs, repliers).await;
                 }
             }
             log::info!("exit NodeGrpcClient, {:?}", endpoint);
         });
     }
 
+    //The forwarded messages of each message type are coalesced into a BatchForwards, in their order,
+    //the others are kept
+    fn coalesce(
+        msgs: Vec<(MessageType, Message)>,
+        r_txs: Vec<OneshotSender<Result<MessageReply>>>,
+    ) -> (Vec<(MessageType, Message)>, Vec<Replier>) {
+        let mut outs = Vec::new();
+        let mut repliers = Vec::new();
+        #[allow(clippy::type_complexity)]
+        let mut batches: Vec<(MessageType, Vec<Message>, Vec<OneshotSender<Result<MessageReply>>>)> =
+            Vec::new();
+        for ((typ, msg), r_tx) in msgs.into_iter().zip(r_txs) {
+            if !is_forward(&msg) {
+                outs.push((typ, msg));
+                repliers.push(Replier::One(r_tx));
+                continue;
+            }
+            match batches.iter_mut().find(|(t, _, _)| *t == typ) {
+                Some((_, fmsgs, f_txs)) => {
+                    fmsgs.push(msg);
+                    f_txs.push(r_tx);
+                }
+                None => batches.push((typ, vec![msg], vec![r_tx])),
+            }
+        }
+        let metrics = &Runtime::instance().metrics;
+        for (typ, mut fmsgs, mut f_txs) in batches {
+            if fmsgs.len() == 1 {
+                outs.push((typ, fmsgs.remove(0)));
+                repliers.push(Replier::One(f_txs.remove(0)));
+                continue;
+            }
+            metrics.grpc_batch_forwards_sent_inc();
+            for _ in 0..fmsgs.len() {
+                metrics.grpc_batch_forwards_messages_inc();
+            }
+            outs.push((typ, Message::BatchForwards(fmsgs)));
+            repliers.push(Replier::Batch(f_txs));
+        }
+        (outs, repliers)
+    }
+
+    ///Resends the spilled messages while the peer is not congested, exits with the client
+    fn start_spill(
+        spill: Arc<SpillQueue>,
+        congestion: Arc<Congestion>,
+        channel_tasks: Arc<AtomicUsize>,
+        tx: WeakSender<ChannelMessage>,
+    ) {
+        tokio::task::spawn(async move {
+            let batch_size = Runtime::instance().settings.rpc.batch_size;
+            loop {
+                if spill.len() == 0 || congestion.is_congested() {
+                    let _ = tokio::time::timeout(Duration::from_secs(1), congestion.cleared.notified()).await;
+                    if tx.upgrade().is_none() {
+                        break;
+                    }
+                    continue;
+                }
+                let msgs = match spill.pop(batch_size).await {
+                    Ok(msgs) => msgs,
+                    Err(e) => {
+                        log::warn!("spill queue pop error, {:?}", e);
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                };
+                let tx = match tx.upgrade() {
+                    Some(tx) => tx,
+                    None => break,
+                };
+                for (typ, msg) in msgs {
+                    //The reply is not needed
+                    let (r_tx, _) = tokio::sync::oneshot::channel();
+                    channel_tasks.fetch_add(1, Ordering::SeqCst);
+                    if tx.send((typ, msg, r_tx)).await.is_err() {
+                        channel_tasks.fetch_sub(1, Ordering::SeqCst);
+                    }
+                }
+            }
+        });
+    }
+
     async fn _send(
         client: NodeGrpcClient,
         mut msgs: Vec<(MessageType, Message)>,
-        mut r_txs: Vec<OneshotSender<Result<MessageReply>>>,
+        mut repliers: Vec<Replier>,
     ) {
         if msgs.len() == 1 {
             let (typ, msg) = msgs.remove(0);
-            let r_tx = r_txs.remove(0);
             let reply = client.inner_send_message(typ, msg).await;
-            if !r_tx.is_closed() {
-                if let Err(r) = r_tx.send(reply) {
-                    log::warn!("Failed to return result, reply message: {:?}", r);
-                }
-            }
+            repliers.remove(0).reply(reply);
         } else {
             match client.inner_batch_send_messages(msgs).await {
                 Err(e) => {
-                    for r_tx in r_txs {
-                        if !r_tx.is_closed() {
-                            if let Err(r) = r_tx.send(Err(MqttError::from(e.to_string()))) {
-                                log::warn!("Failed to return result, reply error message: {:?}", r);
-                            }
-                        }
+                    for replier in repliers {
+                        replier.reply(Err(MqttError::from(e.to_string())));
                     }
                 }
-                Ok(mut replys) => {
-                    for msg in replys.drain(..) {
-                        let r_tx = r_txs.remove(0);
-                        if !r_tx.is_closed() {
-                            if let Err(r) = r_tx.send(Ok(msg)) {
-                                log::warn!("Failed to return result, reply message: {:?}", r);
-                            }
-                        }
+                Ok(replys) => {
+                    for (replier, reply) in repliers.into_iter().zip(replys) {
+                        replier.reply(Ok(reply));
                     }
                 }
             }
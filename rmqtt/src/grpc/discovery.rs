@@ -0,0 +1,379 @@
+//! Cluster node discovery, the cluster plugins find the nodes by DNS records or by the
+//! Kubernetes endpoints of a headless service instead of the static address lists.
+//!
+//! The node id of a discovered node is derived from the ordinal suffix of its host or pod
+//! name, "rmqtt-2" is node 3, the same as `node.id_from = "ordinal"`.
+
+use std::future::Future;
+use std::time::Duration;
+
+use hickory_resolver::TokioAsyncResolver;
+use tokio::time::Instant;
+
+use crate::settings::{deserialize_duration, ordinal_node_id, NodeAddr};
+use crate::{Addr, NodeId, Result, Runtime};
+
+use super::GrpcClients;
+
+type HashMap<K, V> = std::collections::HashMap<K, V, ahash::RandomState>;
+
+const K8S_NAMESPACE_FILE: &str = "/var/run/secrets/kubernetes.io/serviceaccount/namespace";
+
+///Discovered nodes, node id and host, sorted by node id
+pub type Nodes = Vec<(NodeId, String)>;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiscoveryType {
+    #[default]
+    Static,
+    Dns,
+    K8s,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DiscoveryConfig {
+    //static, dns or k8s, static uses node_grpc_addrs and raft_peer_addrs
+    #[serde(default, rename = "type")]
+    pub typ: DiscoveryType,
+    //gRPC port of the discovered nodes
+    #[serde(default = "DiscoveryConfig::grpc_port_default")]
+    pub grpc_port: u16,
+    //Raft port of the discovered nodes, rmqtt-cluster-raft only
+    #[serde(default = "DiscoveryConfig::raft_port_default")]
+    pub raft_port: u16,
+    //At startup, waits until this many nodes are discovered, this node included
+    #[serde(default = "DiscoveryConfig::expected_nodes_default")]
+    pub expected_nodes: usize,
+    #[serde(default = "DiscoveryConfig::startup_timeout_default", deserialize_with = "deserialize_duration")]
+    pub startup_timeout: Duration,
+    //Polling interval
+    #[serde(default = "DiscoveryConfig::interval_default", deserialize_with = "deserialize_duration")]
+    pub interval: Duration,
+    #[serde(default)]
+    pub dns: DnsConfig,
+    #[serde(default)]
+    pub k8s: K8sConfig,
+}
+
+impl Default for DiscoveryConfig {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            typ: DiscoveryType::default(),
+            grpc_port: Self::grpc_port_default(),
+            raft_port: Self::raft_port_default(),
+            expected_nodes: Self::expected_nodes_default(),
+            startup_timeout: Self::startup_timeout_default(),
+            interval: Self::interval_default(),
+            dns: DnsConfig::default(),
+            k8s: K8sConfig::default(),
+        }
+    }
+}
+
+impl DiscoveryConfig {
+    fn grpc_port_default() -> u16 {
+        5363
+    }
+
+    fn raft_port_default() -> u16 {
+        6003
+    }
+
+    fn expected_nodes_default() -> usize {
+        1
+    }
+
+    fn startup_timeout_default() -> Duration {
+        Duration::from_secs(60)
+    }
+
+    fn interval_default() -> Duration {
+        Duration::from_secs(10)
+    }
+
+    #[inline]
+    pub fn is_enable(&self) -> bool {
+        !matches!(self.typ, DiscoveryType::Static)
+    }
+
+    pub async fn discover(&self) -> Result<Nodes> {
+        let mut nodes = match self.typ {
+            DiscoveryType::Static => Vec::new(),
+            DiscoveryType::Dns => self.dns.discover().await?,
+            DiscoveryType::K8s => self.k8s.discover().await?,
+        };
+        nodes.sort();
+        nodes.dedup_by_key(|(id, _)| *id);
+        Ok(nodes)
+    }
+
+    ///Polls until the expected nodes, this node included, are discovered, or the startup
+    ///timeout elapses
+    pub async fn wait_for_nodes(&self) -> Result<Nodes> {
+        let node_id = Runtime::instance().node.id();
+        let deadline = Instant::now() + self.startup_timeout;
+        loop {
+            let timeout = Instant::now() >= deadline;
+            match self.discover().await {
+                Ok(nodes)
+                    if nodes.len() >= self.expected_nodes && nodes.iter().any(|(id, _)| *id == node_id) =>
+                {
+                    log::info!("discovered nodes: {:?}", nodes);
+                    return Ok(nodes);
+                }
+                Ok(nodes) if timeout => {
+                    log::warn!(
+                        "discovery timeout, discovered nodes: {:?}, expected: {}",
+                        nodes,
+                        self.expected_nodes
+                    );
+                    return Ok(nodes);
+                }
+                Ok(nodes) => {
+                    log::info!("discovered nodes: {:?}, expected: {}", nodes, self.expected_nodes);
+                }
+                Err(e) if timeout => return Err(e),
+                Err(e) => log::warn!("discovery error, {:?}", e),
+            }
+            tokio::time::sleep(self.interval.min(Duration::from_secs(2))).await;
+        }
+    }
+
+    ///Polls the nodes at the interval in the background, on_change is called when they change
+    pub fn watch<F, Fut>(self, mut nodes: Nodes, on_change: F) -> tokio::task::JoinHandle<()>
+    where
+        F: Fn(Nodes) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send,
+    {
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(self.interval).await;
+                match self.discover().await {
+                    Ok(new_nodes) if new_nodes != nodes => {
+                        log::info!("discovered nodes changed, {:?} => {:?}", nodes, new_nodes);
+                        nodes.clone_from(&new_nodes);
+                        on_change(new_nodes).await;
+                    }
+                    Ok(_) => {}
+                    Err(e) => log::warn!("discovery error, {:?}", e),
+                }
+            }
+        })
+    }
+
+    #[inline]
+    pub fn grpc_addrs(&self, nodes: &Nodes) -> Vec<NodeAddr> {
+        Self::node_addrs(nodes, self.grpc_port)
+    }
+
+    #[inline]
+    pub fn raft_addrs(&self, nodes: &Nodes) -> Vec<NodeAddr> {
+        Self::node_addrs(nodes, self.raft_port)
+    }
+
+    #[inline]
+    fn node_addrs(nodes: &Nodes, port: u16) -> Vec<NodeAddr> {
+        nodes
+            .iter()
+            .map(|(id, host)| {
+                let addr = if host.contains(':') {
+                    format!("[{}]:{}", host, port)
+                } else {
+                    format!("{}:{}", host, port)
+                };
+                NodeAddr { id: *id, addr: Addr::from(addr) }
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DnsRecord {
+    //The targets of the SRV records, e.g. of a headless service
+    #[default]
+    Srv,
+    //The A/AAAA record of each ordinal, the name contains "{ordinal}"
+    A,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DnsConfig {
+    //SRV name, e.g. "_grpc._tcp.rmqtt-headless.default.svc.cluster.local", or A name
+    //template, e.g. "rmqtt-{ordinal}.rmqtt-headless.default.svc.cluster.local"
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub record: DnsRecord,
+    //Ordinals 0..max_nodes are resolved for the A name template
+    #[serde(default = "DnsConfig::max_nodes_default")]
+    pub max_nodes: usize,
+}
+
+impl Default for DnsConfig {
+    #[inline]
+    fn default() -> Self {
+        Self { name: String::default(), record: DnsRecord::default(), max_nodes: Self::max_nodes_default() }
+    }
+}
+
+impl DnsConfig {
+    fn max_nodes_default() -> usize {
+        16
+    }
+
+    async fn discover(&self) -> Result<Nodes> {
+        let resolver = TokioAsyncResolver::tokio_from_system_conf().map_err(anyhow::Error::new)?;
+        let mut nodes = Vec::new();
+        match self.record {
+            DnsRecord::Srv => {
+                let srvs = resolver.srv_lookup(self.name.as_str()).await.map_err(anyhow::Error::new)?;
+                for srv in srvs.iter() {
+                    let host = srv.target().to_utf8().trim_end_matches('.').to_string();
+                    match ordinal_node_id(&host) {
+                        Some(id) => nodes.push((id, host)),
+                        None => log::warn!("no ordinal in the SRV target, {}", host),
+                    }
+                }
+            }
+            DnsRecord::A => {
+                if !self.name.contains("{ordinal}") {
+                    return Err(
+                        anyhow::anyhow!("the A name template has no {{ordinal}}, {}", self.name).into()
+                    );
+                }
+                for ordinal in 0..self.max_nodes {
+                    let host = self.name.replace("{ordinal}", &ordinal.to_string());
+                    if resolver.lookup_ip(host.as_str()).await.is_ok() {
+                        nodes.push((ordinal as NodeId + 1, host));
+                    }
+                }
+            }
+        }
+        Ok(nodes)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct K8sConfig {
+    #[serde(default = "K8sConfig::api_server_default")]
+    pub api_server: String,
+    //The namespace of the pod when empty
+    #[serde(default)]
+    pub namespace: String,
+    //Headless service of the StatefulSet
+    #[serde(default)]
+    pub service: String,
+    #[serde(default = "K8sConfig::token_file_default")]
+    pub token_file: String,
+    #[serde(default = "K8sConfig::ca_file_default")]
+    pub ca_file: String,
+    //The pods join the cluster before they are ready
+    #[serde(default = "K8sConfig::include_not_ready_default")]
+    pub include_not_ready: bool,
+}
+
+impl Default for K8sConfig {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            api_server: Self::api_server_default(),
+            namespace: String::default(),
+            service: String::default(),
+            token_file: Self::token_file_default(),
+            ca_file: Self::ca_file_default(),
+            include_not_ready: Self::include_not_ready_default(),
+        }
+    }
+}
+
+impl K8sConfig {
+    fn api_server_default() -> String {
+        "https://kubernetes.default.svc".into()
+    }
+
+    fn token_file_default() -> String {
+        "/var/run/secrets/kubernetes.io/serviceaccount/token".into()
+    }
+
+    fn ca_file_default() -> String {
+        "/var/run/secrets/kubernetes.io/serviceaccount/ca.crt".into()
+    }
+
+    fn include_not_ready_default() -> bool {
+        true
+    }
+
+    async fn discover(&self) -> Result<Nodes> {
+        let namespace = if self.namespace.is_empty() {
+            tokio::fs::read_to_string(K8S_NAMESPACE_FILE).await?.trim().to_string()
+        } else {
+            self.namespace.clone()
+        };
+        let token = tokio::fs::read_to_string(&self.token_file).await?;
+
+        let mut builder = reqwest::Client::builder().timeout(Duration::from_secs(10));
+        if let Ok(ca) = tokio::fs::read(&self.ca_file).await {
+            builder = builder
+                .add_root_certificate(reqwest::Certificate::from_pem(&ca).map_err(anyhow::Error::new)?);
+        }
+        let client = builder.build().map_err(anyhow::Error::new)?;
+        let url = format!(
+            "{}/api/v1/namespaces/{}/endpoints/{}",
+            self.api_server.trim_end_matches('/'),
+            namespace,
+            self.service
+        );
+        let endpoints = client
+            .get(url)
+            .bearer_auth(token.trim())
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status())
+            .map_err(anyhow::Error::new)?
+            .json::<serde_json::Value>()
+            .await
+            .map_err(anyhow::Error::new)?;
+
+        let mut nodes = Vec::new();
+        for subset in endpoints["subsets"].as_array().into_iter().flatten() {
+            let mut addresses = subset["addresses"].as_array().into_iter().flatten().collect::<Vec<_>>();
+            if self.include_not_ready {
+                addresses.extend(subset["notReadyAddresses"].as_array().into_iter().flatten());
+            }
+            for address in addresses {
+                let name = address["targetRef"]["name"].as_str().or_else(|| address["hostname"].as_str());
+                match (name.and_then(ordinal_node_id), address["ip"].as_str()) {
+                    (Some(id), Some(ip)) => nodes.push((id, ip.to_string())),
+                    _ => log::warn!("no ordinal in the endpoint address, {}", address),
+                }
+            }
+        }
+        Ok(nodes)
+    }
+}
+
+///Rebuilds the gRPC clients for the node addresses, the clients of the unchanged nodes are kept.
+///Returns None when nothing changed, or when no other node is left, the last known nodes are kept.
+pub async fn update_grpc_clients(
+    grpc_clients: &GrpcClients,
+    node_addrs: &[NodeAddr],
+) -> Result<Option<GrpcClients>> {
+    let node_id = Runtime::instance().node.id();
+    let mut new_grpc_clients = HashMap::default();
+    for node_addr in node_addrs.iter().filter(|n| n.id != node_id) {
+        let client = match grpc_clients.get(&node_addr.id) {
+            Some((addr, c)) if *addr == node_addr.addr => c.clone(),
+            _ => Runtime::instance().node.new_grpc_client(&node_addr.addr).await?,
+        };
+        new_grpc_clients.insert(node_addr.id, (node_addr.addr.clone(), client));
+    }
+    let changed = new_grpc_clients.len() != grpc_clients.len()
+        || new_grpc_clients.iter().any(|(id, (addr, _))| grpc_clients.get(id).map(|(a, _)| a) != Some(addr));
+    if !changed || new_grpc_clients.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(std::sync::Arc::new(new_grpc_clients)))
+}
@@ -0,0 +1,155 @@
+//! Registry of the gRPC message types. The plugins exchanging messages between the nodes pick their
+//! message type in their configs, two plugins with the same type would each receive the messages
+//! of the other. A plugin claims its types at init, with a name, a claim overlapping the types of
+//! another owner is refused, the types of the broker are claimed by "broker". A message of a type
+//! not claimed on the node, that no hook handler replies to, is answered with an error naming the
+//! type rather than an empty success.
+
+use std::ops::RangeInclusive;
+
+use once_cell::sync::OnceCell;
+use rust_box::std_ext::RwLock;
+
+use super::{
+    MessageType, MESSAGE_TYPE_CONSISTENCY, MESSAGE_TYPE_MAINTENANCE, MESSAGE_TYPE_MESSAGE_ACK,
+    MESSAGE_TYPE_MESSAGE_GET, MESSAGE_TYPE_MESSAGE_GET_PAGE, MESSAGE_TYPE_PLUGIN, MESSAGE_TYPE_PURGE_SESSION,
+    MESSAGE_TYPE_QUOTA_COUNT, MESSAGE_TYPE_RETAINS_GET, MESSAGE_TYPE_RETAIN_MANAGE, MESSAGE_TYPE_ROUTES_SYNC,
+    MESSAGE_TYPE_SESSION_MIGRATE,
+};
+use crate::{MqttError, Result};
+
+///The owner of the message types of the broker
+pub const BROKER: &str = "broker";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MessageTypeClaim {
+    ///The plugin, or "broker"
+    pub owner: String,
+    pub name: String,
+    pub start: MessageType,
+    ///Inclusive
+    pub end: MessageType,
+}
+
+impl MessageTypeClaim {
+    #[inline]
+    fn overlaps(&self, types: &RangeInclusive<MessageType>) -> bool {
+        self.start <= *types.end() && *types.start() <= self.end
+    }
+
+    #[inline]
+    fn contains(&self, typ: MessageType) -> bool {
+        self.start <= typ && typ <= self.end
+    }
+}
+
+pub struct MessageTypes {
+    claims: RwLock<Vec<MessageTypeClaim>>,
+}
+
+impl MessageTypes {
+    #[inline]
+    pub fn instance() -> &'static Self {
+        static INSTANCE: OnceCell<MessageTypes> = OnceCell::new();
+        INSTANCE.get_or_init(|| {
+            let types = Self { claims: RwLock::new(Vec::new()) };
+            for (name, typ) in [
+                ("message_get", MESSAGE_TYPE_MESSAGE_GET),
+                ("session_migrate", MESSAGE_TYPE_SESSION_MIGRATE),
+                ("retains_get", MESSAGE_TYPE_RETAINS_GET),
+                ("message_get_page", MESSAGE_TYPE_MESSAGE_GET_PAGE),
+                ("message_ack", MESSAGE_TYPE_MESSAGE_ACK),
+                ("purge_session", MESSAGE_TYPE_PURGE_SESSION),
+                ("plugin", MESSAGE_TYPE_PLUGIN),
+                ("quota_count", MESSAGE_TYPE_QUOTA_COUNT),
+                ("retain_manage", MESSAGE_TYPE_RETAIN_MANAGE),
+                ("consistency", MESSAGE_TYPE_CONSISTENCY),
+                ("routes_sync", MESSAGE_TYPE_ROUTES_SYNC),
+                ("maintenance", MESSAGE_TYPE_MAINTENANCE),
+            ] {
+                //the types of the broker are distinct
+                let _ = types.claim(BROKER, name, typ);
+            }
+            types
+        })
+    }
+
+    ///Claims a message type for the owner, see claim_range
+    #[inline]
+    pub fn claim(&self, owner: &str, name: &str, typ: MessageType) -> Result<()> {
+        self.claim_range(owner, name, typ..=typ)
+    }
+
+    ///Claims the message types for the owner. The types claimed by the owner before under the name are
+    ///replaced, a claim overlapping the types of another owner, or of another name of the owner, is an
+    ///error.
+    pub fn claim_range(&self, owner: &str, name: &str, types: RangeInclusive<MessageType>) -> Result<()> {
+        if types.is_empty() {
+            return Err(MqttError::from(format!(
+                "message types of {} {:?} are empty, {}..={}",
+                owner,
+                name,
+                types.start(),
+                types.end()
+            )));
+        }
+        let mut claims = self.claims.write();
+        if let Some(other) =
+            claims.iter().find(|c| c.overlaps(&types) && (c.owner != owner || c.name != name))
+        {
+            return Err(MqttError::from(format!(
+                "message types of {} {:?}, {}..={}, conflict with those of {} {:?}, {}..={}, \
+                 change the message_type of one of them",
+                owner,
+                name,
+                types.start(),
+                types.end(),
+                other.owner,
+                other.name,
+                other.start,
+                other.end
+            )));
+        }
+        claims.retain(|c| c.owner != owner || c.name != name);
+        claims.push(MessageTypeClaim {
+            owner: owner.into(),
+            name: name.into(),
+            start: *types.start(),
+            end: *types.end(),
+        });
+        claims.sort_by_key(|c| c.start);
+        Ok(())
+    }
+
+    ///The claim of the message type
+    #[inline]
+    pub fn get(&self, typ: MessageType) -> Option<MessageTypeClaim> {
+        self.claims.read().iter().find(|c| c.contains(typ)).cloned()
+    }
+
+    ///The claims, ordered by type
+    #[inline]
+    pub fn claims(&self) -> Vec<MessageTypeClaim> {
+        self.claims.read().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MessageTypes;
+    use rust_box::std_ext::RwLock;
+
+    #[test]
+    fn test_claim() {
+        let types = MessageTypes { claims: RwLock::new(Vec::new()) };
+        assert!(types.claim("rmqtt-http-api", "api", 99).is_ok());
+        assert!(types.claim("rmqtt-http-api", "api", 99).is_ok());
+        assert!(types.claim("rmqtt-cluster-broadcast", "cluster", 99).is_err());
+        assert!(types.claim_range("rmqtt-x", "x", 90..=100).is_err());
+        assert!(types.claim_range("rmqtt-x", "x", 100..=110).is_ok());
+        assert!(types.claim_range("rmqtt-x", "x", 120..=119).is_err());
+        assert_eq!(types.get(105).map(|c| c.owner), Some("rmqtt-x".into()));
+        assert_eq!(types.get(111), None);
+        assert_eq!(types.claims().iter().map(|c| c.start).collect::<Vec<_>>(), vec![99, 100]);
+    }
+}
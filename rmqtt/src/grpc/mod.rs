@@ -6,32 +6,54 @@ use futures::FutureExt;
 
 use client::NodeGrpcClient;
 
-use crate::broker::session::SessionOfflineInfo;
+use crate::broker::consistency::ConsistencyReport;
+use crate::broker::quota::QuotaCount;
+use crate::broker::route_sync::SyncRoute;
+use crate::broker::session::{SessionMigrateInfo, SessionOfflineInfo};
 use crate::broker::types::{
-    CleanStart, ClearSubscriptions, From, Id, IsAdmin, NodeId, Publish, Retain, Route, SessionStatus,
-    SubsSearchParams, SubsSearchResult, TopicFilter, TopicName,
+    CleanStart, ClearSubscriptions, From, Id, IsAdmin, NodeId, Publish, PurgeReport, Retain, RetainInfo,
+    Route, SessionStatus, SubsSearchParams, SubsSearchResult, TopicFilter, TopicName, UserName,
 };
+use crate::plugin::PluginInfo;
 use crate::{
     Addr, ClientId, MsgID, Result, SharedGroup, SubRelations, SubRelationsMap, SubscriptionClientIds,
 };
 
+mod auth;
 pub mod client;
+pub mod discovery;
+pub mod message_types;
+pub mod retains;
 pub mod server;
+mod spill;
 
 #[allow(dead_code)]
 pub(crate) mod pb {
     include!(concat!(env!("OUT_DIR"), "/pb.rs"));
 }
 
-///Reserved within 1000
+///Reserved within 1000, the types of the plugins are claimed, see message_types
 pub type MessageType = u64;
 
 pub const MESSAGE_TYPE_MESSAGE_GET: u64 = 22;
+pub const MESSAGE_TYPE_SESSION_MIGRATE: u64 = 23;
+pub const MESSAGE_TYPE_RETAINS_GET: u64 = 24;
+pub const MESSAGE_TYPE_MESSAGE_GET_PAGE: u64 = 25;
+pub const MESSAGE_TYPE_MESSAGE_ACK: u64 = 26;
+pub const MESSAGE_TYPE_PURGE_SESSION: u64 = 27;
+pub const MESSAGE_TYPE_PLUGIN: u64 = 28;
+pub const MESSAGE_TYPE_QUOTA_COUNT: u64 = 29;
+pub const MESSAGE_TYPE_RETAIN_MANAGE: u64 = 30;
+pub const MESSAGE_TYPE_CONSISTENCY: u64 = 31;
+pub const MESSAGE_TYPE_ROUTES_SYNC: u64 = 32;
+pub const MESSAGE_TYPE_MAINTENANCE: u64 = 33;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum Message {
     Forwards(From, Publish),
     ForwardsTo(From, Publish, SubRelations),
+    ///The Forwards and ForwardsTo messages to a peer coalesced, see client::NodeGrpcClient
+    BatchForwards(Vec<Message>),
     Kick(Id, CleanStart, ClearSubscriptions, IsAdmin),
     GetRetains(TopicFilter),
     SubscriptionsSearch(SubsSearchParams),
@@ -44,6 +66,40 @@ pub enum Message {
     SessionStatus(ClientId),
     MessageGet(ClientId, TopicFilter, Option<SharedGroup>),
     Data(Vec<u8>),
+    SessionMigrate(Vec<SessionMigrateInfo>),
+    ///The retained messages after the continuation token, see retains
+    GetRetainsChunk(TopicFilter, Option<TopicName>),
+    ///The stored messages after the cursor, at most the page size
+    MessageGetPage(ClientId, TopicFilter, Option<SharedGroup>, Option<MsgID>, usize),
+    ///The stored messages consumed by a shared subscription
+    MessageAck(Vec<MsgID>),
+    ///Purges the session of the client from the node, see Node::purge_session
+    PurgeSession(ClientId),
+    ///Plugin management of the node, see plugin::Manager::broadcast
+    PluginList,
+    PluginStart(String),
+    PluginStop(String),
+    PluginLoadConfig(String),
+    ///The name of the plugin and the message, json data
+    PluginSend(String, Vec<u8>),
+    ///The sessions of the username, of its tenant and of the labels on the node, except those of the
+    ///client
+    QuotaCount(ClientId, Option<UserName>, Vec<String>),
+    ///A page of the retained messages of the node matching the topic filter, the offset and the limit
+    RetainList(TopicFilter, usize, usize),
+    ///Removes the retained messages of the node matching the topic filter
+    RetainRemove(TopicFilter),
+    ///Audits the node, repairing the inconsistencies if set, see Consistency::check
+    ConsistencyCheck(bool),
+    ///The clients connected on the node
+    ConsistencyConnected(Vec<ClientId>),
+    ///Kicks the stale offline sessions of the clients from the node
+    ConsistencyRemoveStale(Vec<ClientId>),
+    ///A page of the routes of the sessions of the node, the topic filter prefix, the cursor and the
+    ///page size, see RouteSync::serve_local
+    RoutesSync(Option<TopicFilter>, Option<TopicFilter>, usize),
+    ///The node entered or left maintenance, see Maintenance::announced
+    Maintenance(NodeId, bool),
 }
 
 impl Message {
@@ -57,10 +113,12 @@ impl Message {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum MessageReply {
     Success,
     Forwards(SubRelationsMap, SubscriptionClientIds),
+    ///The replies of the messages of a BatchForwards, in order
+    BatchForwards(Vec<MessageReply>),
     Error(String),
     Kick(Option<SessionOfflineInfo>),
     GetRetains(Vec<(TopicName, Retain)>),
@@ -74,6 +132,29 @@ pub enum MessageReply {
     SessionStatus(Option<SessionStatus>),
     MessageGet(Vec<(MsgID, From, Publish)>),
     Data(Vec<u8>),
+    SessionMigrate(usize),
+    ///A chunk, the continuation token of the next chunk, None for the last one, and whether the
+    ///retained messages were truncated
+    GetRetainsChunk(Vec<(TopicName, Retain)>, Option<TopicName>, bool),
+    ///A page and the cursor of the next page, None for the last one
+    MessageGetPage(Vec<(MsgID, From, Publish)>, Option<MsgID>),
+    PurgeSession(PurgeReport),
+    PluginList(Vec<PluginInfo>),
+    ///Whether the plugin is stopped
+    PluginStop(bool),
+    ///The reply of the plugin, json data
+    PluginSend(Vec<u8>),
+    QuotaCount(QuotaCount),
+    ///The number of the matching messages and the page
+    RetainList(usize, Vec<RetainInfo>),
+    ///The number of the removed messages
+    RetainRemove(usize),
+    ConsistencyCheck(ConsistencyReport),
+    ConsistencyConnected(Vec<ClientId>),
+    ///The number of the kicked sessions
+    ConsistencyRemoveStale(usize),
+    ///A page of the routes and the cursor of the next page, None for the last one
+    RoutesSync(Vec<SyncRoute>, Option<TopicFilter>),
 }
 
 impl MessageReply {
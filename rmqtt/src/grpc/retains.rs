@@ -0,0 +1,164 @@
+//! Retained messages of the other nodes, requested chunk by chunk. A `#` subscription may match
+//! more retained messages than a gRPC message can carry, the node replies them in chunks of at
+//! most `rpc.retains_chunk_size` bytes, ordered by topic, with the last topic as the continuation
+//! token of the next request. At most `rpc.retains_max` messages are replied, the rest are
+//! truncated.
+
+use crate::broker::types::{HashMap, NodeId, Retain, TopicFilter, TopicName};
+use crate::{MqttError, Result, Runtime};
+
+use super::client::NodeGrpcClient;
+use super::{GrpcClients, Message, MessageReply, MESSAGE_TYPE_RETAINS_GET};
+
+///The retained messages and whether they were truncated
+pub type Retains = (Vec<(TopicName, Retain)>, bool);
+
+///Serves a chunk request, the retained messages after the continuation token
+pub(crate) async fn serve(topic_filter: &TopicFilter, after: Option<TopicName>) -> MessageReply {
+    match Runtime::instance().extends.retain().await.get(topic_filter).await {
+        Err(e) => MessageReply::Error(e.to_string()),
+        Ok(retains) => {
+            let rpc = &Runtime::instance().settings.rpc;
+            chunk(retains, after, rpc.retains_chunk_size.as_usize(), rpc.retains_max)
+        }
+    }
+}
+
+#[inline]
+fn chunk(
+    mut retains: Vec<(TopicName, Retain)>,
+    after: Option<TopicName>,
+    chunk_size: usize,
+    max: usize,
+) -> MessageReply {
+    retains.sort_by(|(a, _), (b, _)| a.cmp(b));
+    //The messages already replied, by the position of the continuation token
+    let skip = match after.as_ref() {
+        Some(after) => retains.partition_point(|(topic, _)| topic <= after),
+        None => 0,
+    };
+    let (limit, truncated) =
+        if max > 0 && retains.len() > max { (max, true) } else { (retains.len(), false) };
+
+    let mut size = 0;
+    let mut chunk = Vec::new();
+    for (topic, retain) in retains.into_iter().take(limit).skip(skip) {
+        let len = topic.len() + retain.publish.payload.len();
+        if !chunk.is_empty() && size + len > chunk_size {
+            let next = chunk.last().map(|(topic, _): &(TopicName, Retain)| topic.clone());
+            return MessageReply::GetRetainsChunk(chunk, next, false);
+        }
+        size += len;
+        chunk.push((topic, retain));
+    }
+    MessageReply::GetRetainsChunk(chunk, None, truncated)
+}
+
+///The retained messages of the node, the chunks are requested until the last one
+pub async fn get(client: &NodeGrpcClient, topic_filter: &TopicFilter) -> Result<Retains> {
+    let mut retains = Vec::new();
+    let mut after = None;
+    loop {
+        let msg = Message::GetRetainsChunk(topic_filter.clone(), after.take());
+        match client.send_message(MESSAGE_TYPE_RETAINS_GET, msg).await? {
+            MessageReply::GetRetainsChunk(chunk, next, truncated) => {
+                retains.extend(chunk);
+                match next {
+                    Some(next) => after = Some(next),
+                    None => return Ok((retains, truncated)),
+                }
+            }
+            MessageReply::Error(e) => return Err(MqttError::from(e)),
+            reply => return Err(MqttError::from(format!("unexpected reply, {:?}", reply))),
+        }
+    }
+}
+
+///The retained messages of the nodes, merged, the latest message of a topic is kept. The nodes
+///that fail are skipped and returned with their errors.
+pub async fn get_all(
+    grpc_clients: &GrpcClients,
+    topic_filter: &TopicFilter,
+) -> (Retains, Vec<(NodeId, MqttError)>) {
+    let futs = grpc_clients
+        .iter()
+        .map(|(node_id, (_, client))| async move { (*node_id, get(client, topic_filter).await) });
+    let mut merged: HashMap<TopicName, Retain> = HashMap::default();
+    let mut truncated = false;
+    let mut errs = Vec::new();
+    for (node_id, res) in futures::future::join_all(futs).await {
+        match res {
+            Ok((retains, t)) => {
+                truncated |= t;
+                for (topic, retain) in retains {
+                    match merged.get(&topic) {
+                        Some(prev) if prev.publish.create_time >= retain.publish.create_time => {}
+                        _ => {
+                            merged.insert(topic, retain);
+                        }
+                    }
+                }
+            }
+            Err(e) => errs.push((node_id, e)),
+        }
+    }
+    ((merged.into_iter().collect(), truncated), errs)
+}
+
+#[cfg(test)]
+mod tests {
+    use ntex::util::Bytes;
+
+    use super::chunk;
+    use crate::broker::types::{From, Id, Publish, QoS, Retain, TopicName};
+    use crate::grpc::MessageReply;
+
+    fn retain(topic: &str, len: usize) -> (TopicName, Retain) {
+        let publish = Publish {
+            dup: false,
+            retain: true,
+            qos: QoS::AtMostOnce,
+            topic: topic.into(),
+            packet_id: None,
+            payload: Bytes::from(vec![0u8; len]),
+            properties: Default::default(),
+            create_time: 0,
+        };
+        (
+            topic.into(),
+            Retain {
+                msg_id: None,
+                from: From::from_custom(Id::new(1, None, None, "c1".into(), None)),
+                publish,
+            },
+        )
+    }
+
+    #[test]
+    fn test_chunk() {
+        let retains = vec![retain("c", 10), retain("a", 10), retain("b", 10), retain("d", 10)];
+        match chunk(retains.clone(), None, 25, 0) {
+            MessageReply::GetRetainsChunk(c, next, truncated) => {
+                assert_eq!(c.iter().map(|(t, _)| t.as_ref()).collect::<Vec<_>>(), vec!["a", "b"]);
+                assert_eq!(next.as_deref(), Some("b"));
+                assert!(!truncated);
+            }
+            _ => unreachable!(),
+        }
+        match chunk(retains.clone(), Some("b".into()), 25, 0) {
+            MessageReply::GetRetainsChunk(c, next, _) => {
+                assert_eq!(c.iter().map(|(t, _)| t.as_ref()).collect::<Vec<_>>(), vec!["c", "d"]);
+                assert_eq!(next, None);
+            }
+            _ => unreachable!(),
+        }
+        match chunk(retains, None, 1000, 3) {
+            MessageReply::GetRetainsChunk(c, next, truncated) => {
+                assert_eq!(c.len(), 3);
+                assert_eq!(next, None);
+                assert!(truncated);
+            }
+            _ => unreachable!(),
+        }
+    }
+}
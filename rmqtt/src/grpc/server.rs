@@ -4,13 +4,23 @@ use std::sync::Arc;
 use once_cell::sync::Lazy;
 use tonic::{transport, Response};
 
+use crate::broker::consistency::Consistency;
+use crate::broker::maintenance::Maintenance;
+use crate::broker::quota::Quota;
+use crate::broker::route_sync::RouteSync;
+use crate::broker::session::SessionState;
 use crate::{Result, Runtime};
 
 use super::pb::{
     self,
     node_service_server::{NodeService, NodeServiceServer},
 };
-use super::{Message, MessageReply, MessageType, MESSAGE_TYPE_MESSAGE_GET};
+use super::{
+    auth, retains, Message, MessageReply, MessageType, MESSAGE_TYPE_CONSISTENCY, MESSAGE_TYPE_MAINTENANCE,
+    MESSAGE_TYPE_MESSAGE_ACK, MESSAGE_TYPE_MESSAGE_GET, MESSAGE_TYPE_MESSAGE_GET_PAGE, MESSAGE_TYPE_PLUGIN,
+    MESSAGE_TYPE_PURGE_SESSION, MESSAGE_TYPE_QUOTA_COUNT, MESSAGE_TYPE_RETAINS_GET,
+    MESSAGE_TYPE_RETAIN_MANAGE, MESSAGE_TYPE_ROUTES_SYNC, MESSAGE_TYPE_SESSION_MIGRATE,
+};
 
 pub struct Server {}
 
@@ -24,16 +34,19 @@ impl Server {
 
         let rpccfg = Runtime::instance().settings.rpc.clone();
 
-        //NodeServiceServer::with_interceptor(RmqttNodeService::default(), Self::check_auth)
-
+        let tls = auth::server_tls()?;
         log::info!(
-            "gRPC server is listening on tcp://{:?}, reuseaddr: {}, reuseport: {}",
+            "gRPC server is listening on {}://{:?}, reuseaddr: {}, reuseport: {}",
+            if tls.is_some() { "tls" } else { "tcp" },
             rpccfg.server_addr,
             rpccfg.reuseaddr,
             rpccfg.reuseport
         );
-        let server =
-            transport::Server::builder().add_service(NodeServiceServer::new(NodeGrpcService::default()));
+        let mut builder = transport::Server::builder();
+        if let Some(tls) = tls {
+            builder = builder.tls_config(tls).map_err(anyhow::Error::new)?;
+        }
+        let server = builder.add_service(NodeServiceServer::new(NodeGrpcService::default()));
 
         if rpccfg.reuseaddr || rpccfg.reuseport {
             let listener = tokio_stream::wrappers::TcpListenerStream::new(tokio::net::TcpListener::from_std(
@@ -46,17 +59,6 @@ impl Server {
         Ok(())
     }
 
-    // fn check_auth(req: Request<()>) -> std::result::Result<Request<()>, Status> {
-    //     log::debug!("check_auth, req: {:?}", req);
-    //
-    //     let token = MetadataValue::from_str(Runtime::instance().settings.node.cookie.as_str())
-    //         .map_err(|e| Status::new(Code::Unauthenticated, e.to_string()))?;
-    //     match req.metadata().get("authorization") {
-    //         Some(t) if token == t => Ok(req),
-    //         _ => Err(Status::unauthenticated("No valid auth token")),
-    //     }
-    // }
-
     #[inline]
     pub fn bind(
         laddr: std::net::SocketAddr,
@@ -95,6 +97,114 @@ impl NodeGrpcService {
                     Ok(msgs) => Ok(MessageReply::MessageGet(msgs)),
                 }
             }
+            (
+                MESSAGE_TYPE_MESSAGE_GET_PAGE,
+                Message::MessageGetPage(client_id, topic_filter, group, after, limit),
+            ) => {
+                match Runtime::instance()
+                    .extends
+                    .message_mgr()
+                    .await
+                    .get_page(&client_id, &topic_filter, group.as_ref(), after, limit)
+                    .await
+                {
+                    Err(e) => Ok(MessageReply::Error(e.to_string())),
+                    Ok((msgs, next)) => Ok(MessageReply::MessageGetPage(msgs, next)),
+                }
+            }
+            (MESSAGE_TYPE_MESSAGE_ACK, Message::MessageAck(msg_ids)) => {
+                match Runtime::instance().extends.message_mgr().await.ack(&msg_ids).await {
+                    Err(e) => Ok(MessageReply::Error(e.to_string())),
+                    Ok(()) => Ok(MessageReply::Success),
+                }
+            }
+            (MESSAGE_TYPE_PURGE_SESSION, Message::PurgeSession(client_id)) => {
+                match Runtime::instance().node.purge_session_local(&client_id).await {
+                    Err(e) => Ok(MessageReply::Error(e.to_string())),
+                    Ok(report) => Ok(MessageReply::PurgeSession(report)),
+                }
+            }
+            (
+                MESSAGE_TYPE_PLUGIN,
+                msg @ (Message::PluginList
+                | Message::PluginStart(_)
+                | Message::PluginStop(_)
+                | Message::PluginLoadConfig(_)
+                | Message::PluginSend(_, _)),
+            ) => Ok(Runtime::instance().plugins.execute(msg).await),
+            (MESSAGE_TYPE_QUOTA_COUNT, Message::QuotaCount(client_id, username, labels)) => {
+                Ok(MessageReply::QuotaCount(Quota::instance().local_count(
+                    &client_id,
+                    username.as_deref(),
+                    &labels,
+                )))
+            }
+            (MESSAGE_TYPE_RETAIN_MANAGE, Message::RetainList(topic_filter, offset, limit)) => {
+                match Runtime::instance().extends.retain().await.list(&topic_filter, offset, limit).await {
+                    Err(e) => Ok(MessageReply::Error(e.to_string())),
+                    Ok((total, infos)) => Ok(MessageReply::RetainList(total, infos)),
+                }
+            }
+            (MESSAGE_TYPE_RETAIN_MANAGE, Message::RetainRemove(topic_filter)) => {
+                match Runtime::instance().extends.retain().await.remove(&topic_filter).await {
+                    Err(e) => Ok(MessageReply::Error(e.to_string())),
+                    Ok(removeds) => Ok(MessageReply::RetainRemove(removeds)),
+                }
+            }
+            (MESSAGE_TYPE_CONSISTENCY, Message::ConsistencyCheck(repair)) => {
+                match Consistency::instance().check_local(repair).await {
+                    Err(e) => Ok(MessageReply::Error(e.to_string())),
+                    Ok(report) => Ok(MessageReply::ConsistencyCheck(report)),
+                }
+            }
+            (MESSAGE_TYPE_CONSISTENCY, Message::ConsistencyConnected(client_ids)) => {
+                Ok(MessageReply::ConsistencyConnected(
+                    Consistency::instance().connected_local(&client_ids).await,
+                ))
+            }
+            (MESSAGE_TYPE_CONSISTENCY, Message::ConsistencyRemoveStale(client_ids)) => {
+                Ok(MessageReply::ConsistencyRemoveStale(
+                    Consistency::instance().remove_stale_local(&client_ids).await,
+                ))
+            }
+            (MESSAGE_TYPE_ROUTES_SYNC, Message::RoutesSync(prefix, after, limit)) => {
+                let (routes, next) =
+                    RouteSync::instance().serve_local(prefix.as_deref(), after.as_ref(), limit);
+                Ok(MessageReply::RoutesSync(routes, next))
+            }
+            (MESSAGE_TYPE_MAINTENANCE, Message::Maintenance(node_id, enable)) => {
+                Maintenance::instance().announced(node_id, enable).await;
+                Ok(MessageReply::Success)
+            }
+            (MESSAGE_TYPE_SESSION_MIGRATE, Message::SessionMigrate(infos)) => {
+                let mut migrated = 0;
+                for info in infos {
+                    let id = info.offline_info.id.clone();
+                    match SessionState::migrate_restart(info).await {
+                        Ok(()) => migrated += 1,
+                        Err(e) => log::warn!("{:?} migrate session error, {:?}", id, e),
+                    }
+                }
+                Ok(MessageReply::SessionMigrate(migrated))
+            }
+            (MESSAGE_TYPE_RETAINS_GET, Message::GetRetainsChunk(topic_filter, after)) => {
+                Ok(retains::serve(&topic_filter, after).await)
+            }
+            (_, Message::BatchForwards(msgs)) => {
+                Runtime::instance().metrics.grpc_batch_forwards_received_inc();
+                let hook_mgr = Runtime::instance().extends.hook_mgr().await;
+                //in their order, that of the messages of a publisher
+                let mut replys = Vec::with_capacity(msgs.len());
+                for msg in msgs {
+                    replys.push(
+                        hook_mgr
+                            .grpc_message_received(typ, msg)
+                            .await
+                            .unwrap_or_else(|e| MessageReply::Error(e.to_string())),
+                    );
+                }
+                Ok(MessageReply::BatchForwards(replys))
+            }
             (_, msg) => Runtime::instance().extends.hook_mgr().await.grpc_message_received(typ, msg).await,
         }
     }
@@ -108,6 +218,7 @@ impl NodeService for NodeGrpcService {
         request: tonic::Request<pb::Message>,
     ) -> Result<tonic::Response<pb::MessageReply>, tonic::Status> {
         log::trace!("request: {:?}", request);
+        auth::check(&request).await?;
         let req = request.into_inner();
         let msg = Message::decode(&req.data)?;
         ACTIVE_REQUEST_COUNT.fetch_add(1, Ordering::SeqCst);
@@ -122,6 +233,7 @@ impl NodeService for NodeGrpcService {
         request: tonic::Request<pb::BatchMessages>,
     ) -> Result<tonic::Response<pb::BatchMessagesReply>, tonic::Status> {
         log::trace!("request: {:?}", request);
+        auth::check(&request).await?;
         let req = request.into_inner();
         let msgs = bincode::deserialize::<Vec<(MessageType, Message)>>(&req.data)
             .map_err(|e| tonic::Status::unavailable(e.to_string()))?;
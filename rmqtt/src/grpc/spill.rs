@@ -0,0 +1,106 @@
+//! Disk-backed spill queue of a gRPC client, the messages forwarded to a congested peer are
+//! appended to a file and resent after the congestion clears. The records are length-prefixed
+//! bincode, a queue left by the previous run is resent as well.
+
+use std::io::SeekFrom;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::Mutex;
+
+use crate::{MqttError, Result};
+
+use super::{Message, MessageType};
+
+pub(crate) struct SpillQueue {
+    path: PathBuf,
+    max: usize,
+    len: AtomicUsize,
+    //read offset
+    offset: Mutex<u64>,
+}
+
+impl SpillQueue {
+    pub(crate) fn new(dir: &str, server_addr: &str, max: usize) -> Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let name = server_addr.replace([':', '/', '[', ']'], "_");
+        let path = PathBuf::from(dir).join(format!("{}.spill", name));
+        let len = match std::fs::read(&path) {
+            Ok(data) => Self::count(&data),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
+        };
+        if len > 0 {
+            log::info!("spill queue {:?}, {} messages left by the previous run", path, len);
+        }
+        Ok(Self { path, max, len: AtomicUsize::new(len), offset: Mutex::new(0) })
+    }
+
+    #[inline]
+    fn count(mut data: &[u8]) -> usize {
+        let mut n = 0;
+        while data.len() >= 4 {
+            let len = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+            if data.len() < 4 + len {
+                break;
+            }
+            data = &data[4 + len..];
+            n += 1;
+        }
+        n
+    }
+
+    #[inline]
+    pub(crate) fn len(&self) -> usize {
+        self.len.load(Ordering::SeqCst)
+    }
+
+    pub(crate) async fn push(&self, typ: MessageType, msg: &Message) -> Result<()> {
+        if self.len() >= self.max {
+            return Err(MqttError::from("spill queue is full"));
+        }
+        let data = bincode::serialize(&(typ, msg)).map_err(anyhow::Error::new)?;
+        let mut buf = Vec::with_capacity(data.len() + 4);
+        buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        buf.extend_from_slice(&data);
+
+        let _offset = self.offset.lock().await;
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path).await?;
+        file.write_all(&buf).await?;
+        self.len.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    ///Takes at most limit messages, the file is truncated once all have been taken
+    pub(crate) async fn pop(&self, limit: usize) -> Result<Vec<(MessageType, Message)>> {
+        let mut offset = self.offset.lock().await;
+        let mut file = OpenOptions::new().read(true).write(true).open(&self.path).await?;
+        let file_len = file.metadata().await?.len();
+        file.seek(SeekFrom::Start(*offset)).await?;
+
+        let mut msgs = Vec::new();
+        while msgs.len() < limit && *offset + 4 <= file_len {
+            let len = file.read_u32().await? as u64;
+            if *offset + 4 + len > file_len {
+                break;
+            }
+            let mut data = vec![0; len as usize];
+            file.read_exact(&mut data).await?;
+            *offset += 4 + len;
+            match bincode::deserialize::<(MessageType, Message)>(&data) {
+                Ok(msg) => msgs.push(msg),
+                Err(e) => log::warn!("spill queue {:?}, decode error, {:?}", self.path, e),
+            }
+            self.len.fetch_sub(1, Ordering::SeqCst);
+        }
+
+        if *offset + 4 > file_len {
+            file.set_len(0).await?;
+            *offset = 0;
+            self.len.store(0, Ordering::SeqCst);
+        }
+        Ok(msgs)
+    }
+}
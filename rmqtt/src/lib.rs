@@ -30,6 +30,8 @@ pub use rand;
 pub use reqwest;
 pub use rust_box;
 pub use scc;
+pub use schemars;
+pub use socket2;
 pub use structopt;
 pub use tokio;
 pub use tokio_cron_scheduler;
@@ -1,11 +1,17 @@
 use std::fs::{File, OpenOptions};
 use std::io::{self, Write};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 
+use once_cell::sync::Lazy;
+use rust_box::std_ext::RwLock;
 pub use slog::Logger;
 use slog::{o, Drain, Record};
 use slog_scope::GlobalLoggerGuard;
 use slog_term::{CountingWriter, RecordDecorator, ThreadSafeTimestampFn};
 
+use crate::broker::types::{timestamp_millis, TimestampMillis};
 use crate::{MqttError, Result, Runtime};
 
 use super::settings::log::{Level, To};
@@ -16,7 +22,9 @@ use super::settings::log::{Level, To};
 /// in the `Runtime` instance. It also initializes `slog_stdlog` with the log level specified in
 /// the `Runtime` settings.
 pub fn logger_init() -> GlobalLoggerGuard {
-    let level = slog_log_to_level(Runtime::instance().settings.log.level.inner());
+    let level = Runtime::instance().settings.log.level.inner();
+    LogLevels::instance().set_base(level);
+    let level = slog_log_to_level(level);
     let logger = Runtime::instance().logger.clone();
     // Make sure to save the guard, see documentation for more information
     let guard = slog_scope::set_global_logger(logger.clone());
@@ -83,7 +91,7 @@ pub fn config_logger(filename: String, to: To, level: Level) -> slog::Logger {
             .use_custom_header_print(print_msg_header)
             .build()
             .fuse();
-        Some(stdout_drain.filter_level(level.inner()).fuse())
+        Some(stdout_drain.filter(move |r| LogLevels::instance().is_enabled(r, level.inner())).fuse())
     } else {
         None
     };
@@ -104,7 +112,7 @@ pub fn config_logger(filename: String, to: To, level: Level) -> slog::Logger {
             .build()
             .fuse();
 
-        Some(file_drain.filter_level(level.inner()).fuse())
+        Some(file_drain.filter(move |r| LogLevels::instance().is_enabled(r, level.inner())).fuse())
     } else {
         None
     };
@@ -126,3 +134,164 @@ fn open_file(filename: &str) -> Result<File> {
         .open(filename)
         .map_err(|e| MqttError::from(format!("logger file config error, filename: {}, {:?}", filename, e)))
 }
+
+static LOG_LEVELS: Lazy<LogLevels> = Lazy::new(LogLevels::default);
+
+///Log level of a module (log target) set at runtime
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LogLevelOverride {
+    pub target: String,
+    pub level: String,
+    ///The level reverts to the configured one after it, 0 is never
+    pub expires_at: TimestampMillis,
+}
+
+struct Overrides {
+    base: slog::Level,
+    //(target, level, expires_at), the longest targets first
+    levels: Vec<(String, slog::Level, TimestampMillis)>,
+}
+
+///Log level overrides of the modules, e.g. rmqtt::grpc=debug or rmqtt-http-api=trace, the longest
+///matching target applies
+pub struct LogLevels {
+    any: AtomicBool,
+    overrides: RwLock<Overrides>,
+}
+
+impl Default for LogLevels {
+    fn default() -> Self {
+        Self {
+            any: AtomicBool::new(false),
+            overrides: RwLock::new(Overrides { base: slog::Level::Info, levels: Vec::new() }),
+        }
+    }
+}
+
+impl LogLevels {
+    #[inline]
+    pub fn instance() -> &'static LogLevels {
+        &LOG_LEVELS
+    }
+
+    #[inline]
+    fn set_base(&self, level: slog::Level) {
+        self.overrides.write().base = level;
+        self.update();
+    }
+
+    ///Sets the level of the target, the plugin names are the crate names, "-" is read as "_"
+    pub fn set(&'static self, target: &str, level: &str, expiry: Option<Duration>) -> Result<()> {
+        let target = target.trim().replace('-', "_");
+        if target.is_empty() {
+            return Err(MqttError::from("log target is empty"));
+        }
+        let level = slog::Level::from_str(level)
+            .map_err(|_| MqttError::from(format!("invalid log level, {}", level)))?;
+        let expires_at = expiry.map(|e| timestamp_millis() + e.as_millis() as TimestampMillis).unwrap_or(0);
+        {
+            let mut overrides = self.overrides.write();
+            overrides.levels.retain(|(t, _, _)| *t != target);
+            overrides.levels.push((target, level, expires_at));
+            overrides.levels.sort_by(|(a, _, _), (b, _, _)| b.len().cmp(&a.len()));
+        }
+        self.update();
+        if let Some(expiry) = expiry {
+            tokio::spawn(async move {
+                tokio::time::sleep(expiry).await;
+                self.update();
+            });
+        }
+        Ok(())
+    }
+
+    ///Removes the override of the target, all if none
+    pub fn remove(&self, target: Option<&str>) {
+        {
+            let mut overrides = self.overrides.write();
+            match target.map(|t| t.trim().replace('-', "_")) {
+                Some(target) => overrides.levels.retain(|(t, _, _)| *t != target),
+                None => overrides.levels.clear(),
+            }
+        }
+        self.update();
+    }
+
+    ///The level of the longest target matching, none if not overridden
+    pub fn level(&self, target: &str) -> Option<slog::Level> {
+        let now = timestamp_millis();
+        self.overrides
+            .read()
+            .levels
+            .iter()
+            .filter(|(_, _, expires_at)| *expires_at == 0 || *expires_at > now)
+            .find(|(t, _, _)| {
+                target
+                    .strip_prefix(t.as_str())
+                    .map(|rest| rest.is_empty() || rest.starts_with("::"))
+                    .unwrap_or(false)
+            })
+            .map(|(_, level, _)| *level)
+    }
+
+    #[inline]
+    fn is_enabled(&self, r: &Record, base: slog::Level) -> bool {
+        if !self.any.load(Ordering::Relaxed) {
+            return r.level().is_at_least(base);
+        }
+        let target = if r.tag().is_empty() { r.module() } else { r.tag() };
+        r.level().is_at_least(self.level(target).unwrap_or(base))
+    }
+
+    pub fn overrides(&self) -> Vec<LogLevelOverride> {
+        let now = timestamp_millis();
+        self.overrides
+            .read()
+            .levels
+            .iter()
+            .filter(|(_, _, expires_at)| *expires_at == 0 || *expires_at > now)
+            .map(|(target, level, expires_at)| LogLevelOverride {
+                target: target.clone(),
+                level: level.as_str().to_lowercase(),
+                expires_at: *expires_at,
+            })
+            .collect()
+    }
+
+    //Drops the expired overrides, the log crate filters by the most verbose level
+    fn update(&self) {
+        let now = timestamp_millis();
+        let mut overrides = self.overrides.write();
+        overrides.levels.retain(|(_, _, expires_at)| *expires_at == 0 || *expires_at > now);
+        self.any.store(!overrides.levels.is_empty(), Ordering::Relaxed);
+        let max = overrides.levels.iter().map(|(_, level, _)| *level).fold(overrides.base, |max, level| {
+            if level.is_at_least(max) {
+                max
+            } else {
+                level
+            }
+        });
+        log::set_max_level(slog_log_to_level(max).to_level_filter());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LogLevels;
+
+    #[test]
+    fn test_log_levels() {
+        let levels: &'static LogLevels = Box::leak(Box::default());
+        levels.set("rmqtt::grpc", "debug", None).unwrap();
+        levels.set("rmqtt-http-api", "trace", None).unwrap();
+        levels.set("rmqtt::grpc::client", "error", None).unwrap();
+        assert!(levels.set("rmqtt", "verbose", None).is_err());
+        assert_eq!(levels.level("rmqtt::grpc::server"), Some(slog::Level::Debug));
+        assert_eq!(levels.level("rmqtt::grpc::client"), Some(slog::Level::Error));
+        assert_eq!(levels.level("rmqtt::grpcx"), None);
+        assert_eq!(levels.level("rmqtt_http_api::api"), Some(slog::Level::Trace));
+        levels.remove(Some("rmqtt::grpc"));
+        assert_eq!(levels.level("rmqtt::grpc::server"), None);
+        assert_eq!(levels.overrides().len(), 2);
+    }
+}
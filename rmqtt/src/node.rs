@@ -1,14 +1,25 @@
 use std::ops::Deref;
-use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
 use std::time::{Duration, Instant};
 
+use bytestring::ByteString;
 use once_cell::sync::Lazy;
 use rust_box::std_ext::RwLock;
 use systemstat::Platform;
+use tokio::sync::oneshot;
 
+use crate::broker::maintenance::Maintenance;
+use crate::broker::session::{SessionMigrateInfo, SessionState};
+use crate::broker::types::{timestamp_millis, ClientId, Id, Message, PurgeReport, Reason, TimestampMillis};
 use crate::grpc::client::NodeGrpcClient;
 use crate::grpc::server::Server;
-use crate::{NodeId, Result, Runtime};
+use crate::grpc::{
+    Message as GrpcMessage, MessageBroadcaster, MessageReply, MessageSender, MESSAGE_TYPE_PURGE_SESSION,
+    MESSAGE_TYPE_SESSION_MIGRATE,
+};
+use crate::logger::{LogLevelOverride, LogLevels};
+use crate::settings::DrainReason;
+use crate::{MqttError, NodeId, Result, Runtime};
 
 #[allow(dead_code)]
 mod version {
@@ -18,11 +29,16 @@ mod version {
 pub struct Node {
     pub start_time: chrono::DateTime<chrono::Local>,
     cpuload: AtomicI64,
+    draining: AtomicBool,
 }
 
 impl Node {
     pub(crate) fn new() -> Self {
-        Self { start_time: chrono::Local::now(), cpuload: AtomicI64::new(0) }
+        Self {
+            start_time: chrono::Local::now(),
+            cpuload: AtomicI64::new(0),
+            draining: AtomicBool::new(false),
+        }
     }
 
     #[inline]
@@ -64,7 +80,288 @@ impl Node {
 
     #[inline]
     pub async fn status(&self) -> NodeStatus {
-        NodeStatus::Running
+        if self.is_draining() {
+            NodeStatus::Draining
+        } else if Maintenance::instance().is_enabled() {
+            NodeStatus::Maintenance
+        } else {
+            NodeStatus::Running
+        }
+    }
+
+    #[inline]
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    ///Drain mode, new connections are refused, the inflight messages of the connected clients get a
+    ///grace period to complete, then the clients are disconnected, MQTT 5.0 clients with the
+    ///Server Moved or Use Another Server reason code and the server reference.
+    pub async fn drain(&self) {
+        if self.draining.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let cfg = &Runtime::instance().settings.node.drain;
+        let shared = Runtime::instance().extends.shared().await;
+        log::info!("node is draining, connections: {}", Runtime::instance().stats.connections.count());
+
+        let deadline = Instant::now() + cfg.grace_period;
+        loop {
+            let mut inflights = 0;
+            for entry in shared.iter() {
+                if let Some(s) = entry.session() {
+                    inflights += s.inflight_win().read().await.len();
+                }
+            }
+            if inflights == 0 || Instant::now() >= deadline {
+                log::info!("node is draining, inflight messages: {}", inflights);
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        for entry in shared.iter() {
+            if !entry.is_connected().await {
+                continue;
+            }
+            if let Some(tx) = entry.tx() {
+                if let Err(e) = tx.unbounded_send(Message::Closed(Reason::ServerDraining)) {
+                    log::warn!("{:?} draining, disconnect error, {:?}", entry.id(), e.to_string());
+                }
+            }
+        }
+
+        //Sessions go offline, the session storage keeps them
+        let deadline = Instant::now() + cfg.disconnect_timeout;
+        while Runtime::instance().stats.connections.count() > 0 && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        log::info!("node drained, connections: {}", Runtime::instance().stats.connections.count());
+    }
+
+    ///Disconnects the connected clients of this node, those of the client ids if given, at most
+    ///limit if given, MQTT 5.0 clients with the Use Another Server or Server Moved reason code and
+    ///the server reference, where they should reconnect to. Returns the number of clients redirected.
+    pub async fn redirect(
+        &self,
+        client_ids: Option<&[ClientId]>,
+        limit: Option<usize>,
+        reason: DrainReason,
+        server_reference: Option<ByteString>,
+    ) -> usize {
+        let shared = Runtime::instance().extends.shared().await;
+        let mut redirecteds = 0;
+        for entry in shared.iter() {
+            if limit.map(|limit| redirecteds >= limit).unwrap_or_default() {
+                break;
+            }
+            if let Some(client_ids) = client_ids {
+                if !client_ids.contains(&entry.id().client_id) {
+                    continue;
+                }
+            }
+            if !entry.is_connected().await {
+                continue;
+            }
+            if let Some(tx) = entry.tx() {
+                let reason = Reason::ServerRedirect(reason, server_reference.clone());
+                if let Err(e) = tx.unbounded_send(Message::Closed(reason)) {
+                    log::warn!("{:?} redirect, disconnect error, {:?}", entry.id(), e.to_string());
+                } else {
+                    redirecteds += 1;
+                }
+            }
+        }
+        log::info!("{} clients redirected, server reference: {:?}", redirecteds, server_reference);
+        redirecteds
+    }
+
+    ///Moves the sessions of this node to the target node, batch by batch. The connected clients are
+    ///kicked, the subscriptions, inflight and queued messages are rebuilt on the target node as offline
+    ///sessions, which the clients take over when reconnecting to the cluster. A batch that fails to
+    ///send is rebuilt on this node again. Returns the number of sessions migrated.
+    pub async fn migrate_sessions(&self, target_node: NodeId, batch_size: usize) -> Result<usize> {
+        if target_node == self.id() {
+            return Err(MqttError::from("the target node is the current node"));
+        }
+        let shared = Runtime::instance().extends.shared().await;
+        let grpc_client = shared
+            .get_grpc_clients()
+            .get(&target_node)
+            .map(|(_, c)| c.clone())
+            .ok_or_else(|| MqttError::from(format!("node {} is not found", target_node)))?;
+
+        let ids = shared.iter().map(|entry| entry.id()).collect::<Vec<_>>();
+        log::info!("migrate sessions to node {}, sessions: {}", target_node, ids.len());
+        let mut migrated = 0;
+        for ids in ids.chunks(batch_size.max(1)) {
+            let mut infos = Vec::new();
+            for id in ids {
+                let mut entry = match shared.entry(id.clone()).try_lock().await {
+                    Ok(entry) => entry,
+                    Err(e) => {
+                        log::warn!("{:?} migrate session, try_lock error, {:?}", id, e);
+                        continue;
+                    }
+                };
+                match SessionMigrateInfo::take(entry.as_mut()).await {
+                    Ok(Some(info)) => infos.push(info),
+                    Ok(None) => {}
+                    Err(e) => log::warn!("{:?} migrate session error, {:?}", id, e),
+                }
+            }
+            if infos.is_empty() {
+                continue;
+            }
+
+            let count = infos.len();
+            let msg = GrpcMessage::SessionMigrate(infos.clone());
+            match MessageSender::new(grpc_client.clone(), MESSAGE_TYPE_SESSION_MIGRATE, msg).send().await {
+                Ok(MessageReply::SessionMigrate(n)) => {
+                    log::info!("migrate sessions to node {}, {}/{}", target_node, n, count);
+                    migrated += n;
+                }
+                reply => {
+                    let e = match reply {
+                        Ok(MessageReply::Error(e)) => MqttError::from(e),
+                        Ok(r) => MqttError::from(format!("unexpected reply, {:?}", r)),
+                        Err(e) => e,
+                    };
+                    log::warn!(
+                        "migrate sessions to node {} error, {:?}, rebuild {} sessions",
+                        target_node,
+                        e,
+                        count
+                    );
+                    for info in infos {
+                        let id = info.offline_info.id.clone();
+                        if let Err(e) = SessionState::migrate_restart(info).await {
+                            log::warn!("{:?} rebuild session error, {:?}", id, e);
+                        }
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(migrated)
+    }
+
+    ///Deletes a stuck session everywhere, it is purged from this node and from the other nodes of
+    ///the cluster, see `purge_session_local`. Returns what was removed from each node, or its error.
+    pub async fn purge_session(&self, client_id: &str) -> Vec<(NodeId, Result<PurgeReport>)> {
+        let mut reports = vec![(self.id(), self.purge_session_local(client_id).await)];
+        let grpc_clients = Runtime::instance().extends.shared().await.get_grpc_clients();
+        if grpc_clients.is_empty() {
+            return reports;
+        }
+        let msg = GrpcMessage::PurgeSession(ClientId::from(client_id));
+        let replys = MessageBroadcaster::new(grpc_clients, MESSAGE_TYPE_PURGE_SESSION, msg).join_all().await;
+        for (node_id, reply) in replys {
+            let report = match reply {
+                Ok(MessageReply::PurgeSession(report)) => Ok(report),
+                Ok(MessageReply::Error(e)) => Err(MqttError::from(e)),
+                Ok(r) => Err(MqttError::from(format!("unexpected reply, {:?}", r))),
+                Err(e) => Err(e),
+            };
+            reports.push((node_id, report));
+        }
+        reports
+    }
+
+    ///Purges the session of the client from this node: the client is kicked if its session is on
+    ///this node, then the routes left behind, the stored session and the forwarded marks of the
+    ///stored messages are removed.
+    pub async fn purge_session_local(&self, client_id: &str) -> Result<PurgeReport> {
+        let mut report = PurgeReport::default();
+        let mut entry =
+            Runtime::instance().extends.shared().await.entry(Id::from(self.id(), ClientId::from(client_id)));
+        if entry.session().is_some() {
+            report.kicked = entry.kick(true, true, true).await?.is_some();
+        }
+        report.routes = Runtime::instance().extends.router().await.remove_client(client_id).await?;
+        report.sessions = Runtime::instance().extends.session_mgr().await.purge(client_id).await?;
+        report.messages = Runtime::instance().extends.message_mgr().await.purge(client_id).await?;
+        log::info!("{} session purged, {:?}", client_id, report);
+        Ok(report)
+    }
+
+    ///Keepalive backstop, periodically reaps the zombie sessions, see `reap_zombies`
+    pub fn start_reaper(&'static self) {
+        let cfg = &Runtime::instance().settings.node.reaper;
+        if !cfg.enable {
+            return;
+        }
+        ntex::rt::spawn(async move {
+            loop {
+                tokio::time::sleep(cfg.interval).await;
+                let reaped = self.reap_zombies().await;
+                if reaped > 0 {
+                    log::warn!("{} zombie sessions reaped", reaped);
+                }
+            }
+        });
+    }
+
+    ///A connected session without any activity for longer than keepalive * keepalive_factor is
+    ///pinged through its connection task, the connection task stuck, e.g. on a half-open TCP
+    ///connection, does not answer, then the session is terminated with Reason::ConnectZombie.
+    ///Returns the number of sessions reaped.
+    pub async fn reap_zombies(&self) -> usize {
+        let cfg = &Runtime::instance().settings.node.reaper;
+        let shared = Runtime::instance().extends.shared().await;
+        let now = timestamp_millis();
+
+        let mut suspects = Vec::new();
+        for entry in shared.iter() {
+            let (s, tx) = match (entry.session(), entry.tx()) {
+                (Some(s), Some(tx)) => (s, tx),
+                _ => continue,
+            };
+            if !s.connected().await.unwrap_or_default() {
+                continue;
+            }
+            let keep_alive = match s.connect_info().await {
+                Ok(conn_info) => conn_info.keep_alive(),
+                Err(_) => continue,
+            };
+            if keep_alive == 0 {
+                continue;
+            }
+            let max_idle = (keep_alive as f64 * 1000.0 * cfg.keepalive_factor as f64) as TimestampMillis;
+            if now - s.last_active() > max_idle {
+                suspects.push((s, tx));
+            }
+        }
+
+        let mut reaped = 0;
+        for (s, tx) in suspects {
+            let (ping_tx, ping_rx) = oneshot::channel();
+            if tx.unbounded_send(Message::Ping(ping_tx)).is_ok()
+                && tokio::time::timeout(cfg.ping_timeout, ping_rx).await.is_ok()
+            {
+                log::debug!("{:?} no activity, but the connection task is alive", s.id);
+                continue;
+            }
+            log::warn!("{:?} zombie session, last active at {}, terminate it", s.id, s.last_active());
+            //Exits the event loop if it ever wakes up
+            let _ = tx.unbounded_send(Message::Closed(Reason::ConnectZombie));
+            if let Err(e) = s.disconnected_set(None, Some(Reason::ConnectZombie)).await {
+                log::warn!("{:?} zombie session, disconnected set error, {:?}", s.id, e);
+            }
+            //hook, session terminated
+            Runtime::instance()
+                .extends
+                .hook_mgr()
+                .await
+                .hook(&s)
+                .session_terminated(Reason::ConnectZombie)
+                .await;
+            if let Err(e) = shared.entry(s.id.clone()).remove_with(&s.id).await {
+                log::warn!("{:?} zombie session, failed to remove the session, {:?}", s.id, e);
+            }
+            reaped += 1;
+        }
+        reaped
     }
 
     #[inline]
@@ -122,6 +419,7 @@ impl Node {
             node_name: Runtime::instance().extends.shared().await.node_name(node_id),
             uptime: self.uptime(),
             version: version::VERSION.to_string(),
+            log_levels: LogLevels::instance().overrides(),
         }
     }
 
@@ -229,6 +527,8 @@ pub struct NodeInfo {
     pub node_name: String,
     pub uptime: String,
     pub version: String,
+    #[serde(default)]
+    pub log_levels: Vec<LogLevelOverride>,
 }
 
 impl NodeInfo {
@@ -254,7 +554,8 @@ impl NodeInfo {
             "node_id":  self.node_id,
             "node_name":  self.node_name,
             "uptime":  self.uptime,
-            "version":  self.version
+            "version":  self.version,
+            "log_levels":  self.log_levels
         })
     }
 }
@@ -265,6 +566,8 @@ pub enum NodeStatus {
     Running,
     Stop,
     Error(String),
+    Draining,
+    Maintenance,
 }
 
 #[inline]
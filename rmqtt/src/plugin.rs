@@ -4,8 +4,13 @@ use std::future::Future;
 use dashmap::iter::Iter;
 use dashmap::mapref::one::{Ref, RefMut};
 
-use crate::{MqttError, Result};
+use crate::broker::audit::{AuditEvent, AuditLog};
+use crate::broker::hook_stats::HookStats;
+use crate::grpc::{Message, MessageBroadcaster, MessageReply, MESSAGE_TYPE_PLUGIN};
+use crate::settings::secret;
+use crate::{MqttError, NodeId, Result, Runtime};
 
+pub mod bus;
 type DashMap<K, V> = dashmap::DashMap<K, V, ahash::RandomState>;
 pub type EntryRef<'a> = Ref<'a, String, Entry, ahash::RandomState>;
 pub type EntryRefMut<'a> = RefMut<'a, String, Entry, ahash::RandomState>;
@@ -51,6 +56,13 @@ pub trait Plugin: PackageInfo + Send + Sync {
         Err(MqttError::from("unimplemented!"))
     }
 
+    ///JSON Schema of the config, the config is validated against it before it is reloaded. Usually
+    ///generated, `serde_json::to_value(schemars::schema_for!(PluginConfig)).ok()`
+    #[inline]
+    async fn config_schema(&self) -> Option<serde_json::Value> {
+        None
+    }
+
     #[inline]
     async fn start(&mut self) -> Result<()> {
         Ok(())
@@ -66,6 +78,8 @@ pub trait Plugin: PackageInfo + Send + Sync {
         serde_json::Value::Null
     }
 
+    ///Untyped point-to-point messages, the message bus, `Manager::bus`, is typed and supports
+    ///broadcast
     #[inline]
     async fn send(&self, _msg: serde_json::Value) -> Result<serde_json::Value> {
         Ok(serde_json::Value::Null)
@@ -117,6 +131,28 @@ pub type DynPluginResult = BoxFuture<Result<DynPlugin>>;
 pub type DynPlugin = Box<dyn Plugin>;
 pub type DynPluginFn = Box<dyn PluginFn>;
 
+tokio::task_local! {
+    //The plugin being created, initialized or started, its hook handlers are attributed to it
+    static CURRENT_PLUGIN: String;
+}
+
+///The plugin being created, initialized or started by the plugin manager
+#[inline]
+pub(crate) fn current_plugin() -> Option<String> {
+    CURRENT_PLUGIN.try_with(|name| name.clone()).ok()
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PluginHealth {
+    #[default]
+    Healthy,
+    ///A hook handler panicked, the plugin is restarted
+    Degraded,
+    ///The restarts are exhausted, the plugin is stopped
+    Failed,
+}
+
 pub struct Entry {
     inited: bool,
     active: bool,
@@ -124,6 +160,10 @@ pub struct Entry {
     immutable: bool,
     plugin: Option<DynPlugin>,
     plugin_f: Option<DynPluginFn>,
+    health: PluginHealth,
+    //restarts after the panics of its hook handlers
+    retries: usize,
+    restarting: bool,
 }
 
 impl Entry {
@@ -142,6 +182,11 @@ impl Entry {
         self.immutable
     }
 
+    #[inline]
+    pub fn health(&self) -> PluginHealth {
+        self.health
+    }
+
     #[inline]
     async fn plugin(&self) -> Result<&dyn Plugin> {
         if let Some(plugin) = &self.plugin {
@@ -167,7 +212,18 @@ impl Entry {
     #[inline]
     pub async fn to_info(&self, name: &str) -> Result<PluginInfo> {
         if let Ok(plugin) = self.plugin().await {
-            let attrs = serde_json::to_vec(&plugin.attrs().await)?;
+            let mut attrs = plugin.attrs().await;
+            secret::redact(&mut attrs);
+            //calls, errors and latencies of the hook handlers of the plugin
+            let hooks = HookStats::instance().plugin_json(name);
+            match attrs {
+                serde_json::Value::Object(ref mut obj) => {
+                    obj.insert("hooks".into(), hooks);
+                }
+                serde_json::Value::Null => attrs = json!({ "hooks": hooks }),
+                _ => {}
+            }
+            let attrs = serde_json::to_vec(&attrs)?;
             Ok(PluginInfo {
                 name: plugin.name().to_owned(),
                 version: Some(plugin.version().to_owned()),
@@ -180,6 +236,7 @@ impl Entry {
                 inited: self.inited,
                 active: self.active,
                 immutable: self.immutable,
+                health: self.health,
                 attrs,
             })
         } else {
@@ -188,6 +245,7 @@ impl Entry {
                 inited: self.inited,
                 active: self.active,
                 immutable: self.immutable,
+                health: self.health,
                 ..Default::default()
             })
         }
@@ -207,6 +265,8 @@ pub struct PluginInfo {
     pub inited: bool,
     pub active: bool,
     pub immutable: bool,
+    #[serde(default)]
+    pub health: PluginHealth,
     pub attrs: Vec<u8>, //json data
 }
 
@@ -230,6 +290,7 @@ impl PluginInfo {
             "inited": self.inited,
             "active": self.active,
             "immutable": self.immutable,
+            "health": self.health,
             "attrs": attrs,
         }))
     }
@@ -237,11 +298,13 @@ impl PluginInfo {
 
 pub struct Manager {
     plugins: DashMap<String, Entry>,
+    ///Message bus between the plugins
+    pub bus: bus::MessageBus,
 }
 
 impl Manager {
     pub(crate) fn new() -> Self {
-        Self { plugins: DashMap::default() }
+        Self { plugins: DashMap::default(), bus: bus::MessageBus::new() }
     }
 
     ///Register a Plugin
@@ -261,34 +324,78 @@ impl Manager {
         }
 
         let (plugin, plugin_f) = if default_startup {
-            let mut plugin = plugin_f().await?;
-            plugin.init().await?;
-            plugin.start().await?;
+            let plugin = CURRENT_PLUGIN
+                .scope(name.clone(), async {
+                    let mut plugin = plugin_f().await?;
+                    plugin.init().await?;
+                    plugin.start().await?;
+                    Ok::<_, MqttError>(plugin)
+                })
+                .await?;
             (Some(plugin), None)
         } else {
             let boxed_f: Box<dyn PluginFn> = Box::new(plugin_f);
             (None, Some(boxed_f))
         };
 
-        let entry = Entry { inited: default_startup, active: default_startup, immutable, plugin, plugin_f };
-        self.plugins.insert(name, entry);
+        let entry = Entry {
+            inited: default_startup,
+            active: default_startup,
+            immutable,
+            plugin,
+            plugin_f,
+            health: PluginHealth::Healthy,
+            retries: 0,
+            restarting: false,
+        };
+        self.plugins.insert(name.clone(), entry);
+        if default_startup {
+            AuditLog::instance().record(AuditEvent::PluginStarted { name }).await;
+        }
         Ok(())
     }
 
-    ///Return Config
+    ///Return Config, the resolved secrets are redacted
     pub async fn get_config(&self, name: &str) -> Result<serde_json::Value> {
         if let Some(entry) = self.get(name) {
-            entry.plugin().await?.get_config().await
+            let mut cfg = entry.plugin().await?.get_config().await?;
+            secret::redact(&mut cfg);
+            Ok(cfg)
+        } else {
+            Err(MqttError::from(format!("{} the plug-in does not exist", name)))
+        }
+    }
+
+    ///Return the JSON Schema of the config, None if the plugin does not provide one
+    pub async fn config_schema(&self, name: &str) -> Result<Option<serde_json::Value>> {
+        if let Some(entry) = self.get(name) {
+            Ok(entry.plugin().await?.config_schema().await)
         } else {
             Err(MqttError::from(format!("{} the plug-in does not exist", name)))
         }
     }
 
+    ///Validates the config of the plugin, its config file and environment variables, against the
+    ///schema of the plugin. The errors are reported with the path of the invalid values.
+    pub async fn validate_config(&self, name: &str) -> Result<()> {
+        let schema = match self.config_schema(name).await? {
+            Some(schema) => schema,
+            None => return Ok(()),
+        };
+        let cfg = Runtime::instance().settings.plugins.load_raw_config(name)?;
+        validate_config(name, &schema, &cfg)
+    }
+
     ///Load Config
     pub async fn load_config(&self, name: &str) -> Result<()> {
+        self.validate_config(name).await?;
         if let Some(mut entry) = self.get_mut(name)? {
             if entry.inited {
                 entry.plugin_mut().await?.load_config().await?;
+                drop(entry);
+                AuditLog::instance()
+                    .record(AuditEvent::ConfigReloaded { target: format!("plugin:{}", name) })
+                    .await;
                 Ok(())
             } else {
                 Err(MqttError::from("the plug-in is not initialized"))
@@ -301,13 +408,20 @@ impl Manager {
     ///Start a Plugin
     pub async fn start(&self, name: &str) -> Result<()> {
         if let Some(mut entry) = self.get_mut(name)? {
+            //started again by the operator
+            if entry.health == PluginHealth::Failed {
+                entry.health = PluginHealth::Healthy;
+                entry.retries = 0;
+            }
             if !entry.inited {
-                entry.plugin_mut().await?.init().await?;
+                CURRENT_PLUGIN.scope(name.into(), async { entry.plugin_mut().await?.init().await }).await?;
                 entry.inited = true;
             }
             if !entry.active {
-                entry.plugin_mut().await?.start().await?;
+                CURRENT_PLUGIN.scope(name.into(), async { entry.plugin_mut().await?.start().await }).await?;
                 entry.active = true;
+                drop(entry);
+                AuditLog::instance().record(AuditEvent::PluginStarted { name: name.into() }).await;
             }
             Ok(())
         } else {
@@ -321,6 +435,10 @@ impl Manager {
             if entry.active {
                 let stopped = entry.plugin_mut().await?.stop().await?;
                 entry.active = !stopped;
+                drop(entry);
+                if stopped {
+                    AuditLog::instance().record(AuditEvent::PluginStopped { name: name.into() }).await;
+                }
                 Ok(stopped)
             } else {
                 Err(MqttError::from(format!("{} the plug-in is not started", name)))
@@ -330,6 +448,45 @@ impl Manager {
         }
     }
 
+    ///A hook handler of the plugin panicked, the plugin is stopped and restarted after the backoff, at
+    ///most `plugins.restart_max_retries` times, then it stays stopped and is marked failed.
+    pub(crate) async fn handler_panicked(&self, name: &str) {
+        let cfg = &Runtime::instance().settings.plugins;
+        let retries = match self.plugins.get_mut(name) {
+            Some(mut entry) if entry.active && !entry.restarting => {
+                entry.retries += 1;
+                entry.restarting = true;
+                entry.health = if entry.retries > cfg.restart_max_retries {
+                    PluginHealth::Failed
+                } else {
+                    PluginHealth::Degraded
+                };
+                entry.retries
+            }
+            _ => return,
+        };
+
+        if let Err(e) = self.stop(name).await {
+            log::warn!("plugin {} panicked, stop error, {:?}", name, e);
+        }
+        if retries > cfg.restart_max_retries {
+            log::error!("plugin {} failed, the restarts are exhausted, retries: {}", name, retries - 1);
+        } else {
+            let backoff = cfg.restart_backoff.saturating_mul(1 << (retries - 1).min(16));
+            log::warn!("plugin {} panicked, restart in {:?}, retries: {}", name, backoff, retries);
+            tokio::time::sleep(backoff).await;
+            if let Err(e) = self.start(name).await {
+                log::error!("plugin {} restart error, {:?}", name, e);
+                if let Some(mut entry) = self.plugins.get_mut(name) {
+                    entry.health = PluginHealth::Failed;
+                }
+            }
+        }
+        if let Some(mut entry) = self.plugins.get_mut(name) {
+            entry.restarting = false;
+        }
+    }
+
     ///Plugin is active
     pub fn is_active(&self, name: &str) -> bool {
         if let Some(entry) = self.plugins.get(name) {
@@ -370,4 +527,60 @@ impl Manager {
     pub fn iter(&self) -> EntryIter {
         self.plugins.iter()
     }
+
+    ///Runs a plugin management message, PluginList, PluginStart, PluginStop, PluginLoadConfig or
+    ///PluginSend, on this node, the errors are replied with MessageReply::Error
+    pub async fn execute(&self, msg: Message) -> MessageReply {
+        let reply = match msg {
+            Message::PluginList => {
+                let mut infos = Vec::new();
+                for entry in self.iter() {
+                    match entry.to_info(entry.key()).await {
+                        Ok(info) => infos.push(info),
+                        Err(e) => return MessageReply::Error(e.to_string()),
+                    }
+                }
+                Ok(MessageReply::PluginList(infos))
+            }
+            Message::PluginStart(name) => self.start(&name).await.map(|_| MessageReply::Success),
+            Message::PluginStop(name) => self.stop(&name).await.map(MessageReply::PluginStop),
+            Message::PluginLoadConfig(name) => self.load_config(&name).await.map(|_| MessageReply::Success),
+            Message::PluginSend(name, msg) => {
+                async {
+                    let reply = self.send(&name, serde_json::from_slice(&msg)?).await?;
+                    Ok::<_, MqttError>(MessageReply::PluginSend(serde_json::to_vec(&reply)?))
+                }
+                .await
+            }
+            _ => Err(MqttError::from(format!("unsupported plugin message, {:?}", msg))),
+        };
+        reply.unwrap_or_else(|e| MessageReply::Error(e.to_string()))
+    }
+
+    ///Runs a plugin management message on this node and on the other nodes of the cluster, see
+    ///`execute`. Returns the reply of each node, the reply of this node first.
+    pub async fn broadcast(&self, msg: Message) -> Vec<(NodeId, Result<MessageReply>)> {
+        let mut replys = vec![(Runtime::instance().node.id(), Ok(self.execute(msg.clone()).await))];
+        let grpc_clients = Runtime::instance().extends.shared().await.get_grpc_clients();
+        if !grpc_clients.is_empty() {
+            replys.extend(MessageBroadcaster::new(grpc_clients, MESSAGE_TYPE_PLUGIN, msg).join_all().await);
+        }
+        replys
+    }
+}
+
+#[inline]
+fn validate_config(name: &str, schema: &serde_json::Value, cfg: &serde_json::Value) -> Result<()> {
+    let schema = jsonschema::JSONSchema::compile(schema)
+        .map_err(|e| MqttError::from(format!("{} invalid config schema, {}", name, e)))?;
+    if let Err(errors) = schema.validate(cfg) {
+        let errors = errors
+            .map(|e| {
+                let path = e.instance_path.to_string();
+                format!("{}: {}", if path.is_empty() { "/" } else { path.as_str() }, e)
+            })
+            .collect::<Vec<_>>();
+        return Err(MqttError::from(format!("{} invalid config, {}", name, errors.join("; "))));
+    }
+    Ok(())
 }
@@ -0,0 +1,323 @@
+//! Message bus between the plugins. A topic is typed, the publisher and the subscribers of a topic
+//! agree on the message type, a topic subscribed with another type is rejected. A message published
+//! to a topic is broadcast to all of its subscribers, a request topic is served by one responder and
+//! its requests are answered within a timeout.
+//!
+//! Each subscriber has a bounded queue of `plugins.bus_capacity` messages, `publish` waits for room
+//! in the queues of the slow subscribers, `try_publish` skips them.
+//!
+//! ```ignore
+//! let mut sub = runtime.plugins.bus.subscribe(&bus::RULE_OUTPUT)?;
+//! while let Some(msg) = sub.recv().await {
+//!     log::info!("rule: {}, output: {}", msg.rule, msg.output);
+//! }
+//! ```
+
+use std::any::{type_name, Any, TypeId};
+use std::borrow::Cow;
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rust_box::std_ext::RwLock;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::broker::types::HashMap;
+use crate::{MqttError, Result, Runtime};
+
+///Output of the rule engine rules with a bus action
+pub const RULE_OUTPUT: Topic<RuleOutput> = Topic::from_static("rule_output");
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RuleOutput {
+    pub rule: String,
+    pub output: serde_json::Value,
+}
+
+///A broadcast topic, messages of type T
+pub struct Topic<T> {
+    name: Cow<'static, str>,
+    _t: PhantomData<fn() -> T>,
+}
+
+impl<T> Topic<T> {
+    #[inline]
+    pub const fn from_static(name: &'static str) -> Self {
+        Self { name: Cow::Borrowed(name), _t: PhantomData }
+    }
+
+    #[inline]
+    pub fn new<N: Into<Cow<'static, str>>>(name: N) -> Self {
+        Self { name: name.into(), _t: PhantomData }
+    }
+
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+///A request topic, requests of type Req answered with Resp
+pub struct RequestTopic<Req, Resp> {
+    name: Cow<'static, str>,
+    _t: PhantomData<fn() -> (Req, Resp)>,
+}
+
+impl<Req, Resp> RequestTopic<Req, Resp> {
+    #[inline]
+    pub const fn from_static(name: &'static str) -> Self {
+        Self { name: Cow::Borrowed(name), _t: PhantomData }
+    }
+
+    #[inline]
+    pub fn new<N: Into<Cow<'static, str>>>(name: N) -> Self {
+        Self { name: name.into(), _t: PhantomData }
+    }
+
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+///Messages of a subscribed topic, the subscription ends when it is dropped
+pub struct Subscription<T> {
+    rx: mpsc::Receiver<Arc<T>>,
+}
+
+impl<T> Subscription<T> {
+    ///None once the bus is gone
+    #[inline]
+    pub async fn recv(&mut self) -> Option<Arc<T>> {
+        self.rx.recv().await
+    }
+
+    #[inline]
+    pub fn try_recv(&mut self) -> Option<Arc<T>> {
+        self.rx.try_recv().ok()
+    }
+}
+
+///A request to answer, the requester gets an error if it is dropped unanswered
+pub struct Request<Req, Resp> {
+    pub body: Req,
+    tx: oneshot::Sender<Resp>,
+}
+
+impl<Req, Resp> Request<Req, Resp> {
+    ///Returns false if the requester no longer waits for the response
+    #[inline]
+    pub fn respond(self, resp: Resp) -> bool {
+        self.tx.send(resp).is_ok()
+    }
+}
+
+///Requests of a served request topic, the topic is released when it is dropped
+pub struct Requests<Req, Resp> {
+    rx: mpsc::Receiver<Request<Req, Resp>>,
+}
+
+impl<Req, Resp> Requests<Req, Resp> {
+    #[inline]
+    pub async fn recv(&mut self) -> Option<Request<Req, Resp>> {
+        self.rx.recv().await
+    }
+}
+
+struct TopicEntry {
+    type_id: TypeId,
+    type_name: &'static str,
+    //Vec<mpsc::Sender<Arc<T>>> for broadcast topics, mpsc::Sender<Request<Req, Resp>> for
+    //request topics
+    senders: Box<dyn Any + Send + Sync>,
+}
+
+impl TopicEntry {
+    #[inline]
+    fn new<T: 'static, S: Any + Send + Sync>(senders: S) -> Self {
+        Self { type_id: TypeId::of::<T>(), type_name: type_name::<T>(), senders: Box::new(senders) }
+    }
+
+    #[inline]
+    fn downcast_mut<T: 'static, S: 'static>(&mut self, topic: &str) -> Result<&mut S> {
+        if self.type_id != TypeId::of::<T>() {
+            return Err(MqttError::from(format!(
+                "bus topic {} is of type {}, not {}",
+                topic,
+                self.type_name,
+                type_name::<T>()
+            )));
+        }
+        self.senders.downcast_mut::<S>().ok_or_else(|| MqttError::from("bus topic type mismatch"))
+    }
+}
+
+#[derive(Default)]
+pub struct MessageBus {
+    topics: RwLock<HashMap<String, TopicEntry>>,
+    request_topics: RwLock<HashMap<String, TopicEntry>>,
+}
+
+impl MessageBus {
+    #[inline]
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    #[inline]
+    fn capacity() -> usize {
+        Runtime::instance().settings.plugins.bus_capacity.max(1)
+    }
+
+    ///Subscribes to the topic
+    pub fn subscribe<T: Send + Sync + 'static>(&self, topic: &Topic<T>) -> Result<Subscription<T>> {
+        let (tx, rx) = mpsc::channel(Self::capacity());
+        let mut topics = self.topics.write();
+        let entry = topics
+            .entry(topic.name().to_owned())
+            .or_insert_with(|| TopicEntry::new::<T, _>(Vec::<mpsc::Sender<Arc<T>>>::new()));
+        let senders = entry.downcast_mut::<T, Vec<mpsc::Sender<Arc<T>>>>(topic.name())?;
+        senders.retain(|s| !s.is_closed());
+        senders.push(tx);
+        Ok(Subscription { rx })
+    }
+
+    #[inline]
+    fn subscribers<T: Send + Sync + 'static>(&self, topic: &Topic<T>) -> Result<Vec<mpsc::Sender<Arc<T>>>> {
+        let mut topics = self.topics.write();
+        if let Some(entry) = topics.get_mut(topic.name()) {
+            let senders = entry.downcast_mut::<T, Vec<mpsc::Sender<Arc<T>>>>(topic.name())?;
+            senders.retain(|s| !s.is_closed());
+            Ok(senders.clone())
+        } else {
+            Ok(Vec::new())
+        }
+    }
+
+    ///Broadcasts the message, waits for room in the queues of the subscribers. Returns the number of
+    ///subscribers the message is delivered to.
+    pub async fn publish<T: Send + Sync + 'static>(&self, topic: &Topic<T>, msg: T) -> Result<usize> {
+        let msg = Arc::new(msg);
+        let mut delivered = 0;
+        for tx in self.subscribers(topic)? {
+            if tx.send(msg.clone()).await.is_ok() {
+                delivered += 1;
+            }
+        }
+        Ok(delivered)
+    }
+
+    ///Broadcasts the message, the subscribers whose queue is full miss it. Returns the number of
+    ///subscribers the message is delivered to.
+    pub fn try_publish<T: Send + Sync + 'static>(&self, topic: &Topic<T>, msg: T) -> Result<usize> {
+        let msg = Arc::new(msg);
+        let mut delivered = 0;
+        for tx in self.subscribers(topic)? {
+            match tx.try_send(msg.clone()) {
+                Ok(()) => delivered += 1,
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    log::warn!("bus topic {}, the queue of a subscriber is full", topic.name())
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => {}
+            }
+        }
+        Ok(delivered)
+    }
+
+    ///Serves the request topic, a topic has one responder at a time
+    pub fn serve<Req, Resp>(&self, topic: &RequestTopic<Req, Resp>) -> Result<Requests<Req, Resp>>
+    where
+        Req: Send + 'static,
+        Resp: Send + 'static,
+    {
+        let mut topics = self.request_topics.write();
+        if let Some(entry) = topics.get_mut(topic.name()) {
+            let tx = entry.downcast_mut::<(Req, Resp), mpsc::Sender<Request<Req, Resp>>>(topic.name())?;
+            if !tx.is_closed() {
+                return Err(MqttError::from(format!("bus topic {} is already served", topic.name())));
+            }
+        }
+        let (tx, rx) = mpsc::channel(Self::capacity());
+        topics.insert(topic.name().to_owned(), TopicEntry::new::<(Req, Resp), _>(tx));
+        Ok(Requests { rx })
+    }
+
+    ///Sends the request to the responder of the topic and waits for the response
+    pub async fn request<Req, Resp>(
+        &self,
+        topic: &RequestTopic<Req, Resp>,
+        body: Req,
+        timeout: Duration,
+    ) -> Result<Resp>
+    where
+        Req: Send + 'static,
+        Resp: Send + 'static,
+    {
+        let responder = {
+            let mut topics = self.request_topics.write();
+            match topics.get_mut(topic.name()) {
+                Some(entry) => {
+                    entry.downcast_mut::<(Req, Resp), mpsc::Sender<Request<Req, Resp>>>(topic.name())?.clone()
+                }
+                None => return Err(MqttError::from(format!("bus topic {} is not served", topic.name()))),
+            }
+        };
+        let (tx, rx) = oneshot::channel();
+        let res = tokio::time::timeout(timeout, async move {
+            responder
+                .send(Request { body, tx })
+                .await
+                .map_err(|_| MqttError::from(format!("bus topic {} is not served", topic.name())))?;
+            rx.await.map_err(|_| MqttError::from("request canceled"))
+        })
+        .await;
+        match res {
+            Ok(res) => res,
+            Err(_) => Err(MqttError::from("request timeout")),
+        }
+    }
+
+    ///Topics and request topics with their message types
+    pub fn to_json(&self) -> serde_json::Value {
+        let topics = self
+            .topics
+            .read()
+            .iter()
+            .map(|(name, entry)| (name.clone(), json!({ "type": entry.type_name })))
+            .collect::<serde_json::Map<_, _>>();
+        let request_topics = self
+            .request_topics
+            .read()
+            .iter()
+            .map(|(name, entry)| (name.clone(), json!({ "type": entry.type_name })))
+            .collect::<serde_json::Map<_, _>>();
+        json!({
+            "topics": topics,
+            "request_topics": request_topics,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::sync::mpsc;
+
+    use super::{MessageBus, Topic, TopicEntry};
+
+    #[test]
+    fn test_topic_type_mismatch() {
+        let bus = MessageBus::new();
+        let topic = Topic::<String>::from_static("test");
+        let other = Topic::<u64>::from_static("test");
+        assert!(bus.subscribers(&topic).unwrap().is_empty());
+
+        let (tx, _rx) = mpsc::channel::<Arc<String>>(1);
+        bus.topics.write().insert("test".into(), TopicEntry::new::<String, _>(vec![tx]));
+        assert_eq!(bus.subscribers(&topic).unwrap().len(), 1);
+        assert!(bus.subscribers(&other).is_err());
+        assert_eq!(bus.try_publish(&topic, "hello".into()).unwrap(), 1);
+        assert_eq!(bus.try_publish(&topic, "world".into()).unwrap(), 0);
+    }
+}
@@ -101,7 +101,10 @@ impl fmt::Debug for Runtime {
 
 pub async fn scheduler_init() -> Result<()> {
     //Execute every 5 seconds
-    if Runtime::instance().settings.node.busy.check_enable {
+    //The CPU load is used by the busy check and the overload protection
+    if Runtime::instance().settings.node.busy.check_enable
+        || Runtime::instance().settings.node.overload.enable
+    {
         let async_job_5 = tokio_cron_scheduler::Job::new_async("*/3 * * * * *", move |_uuid, _l| {
             Box::pin(async move {
                 Runtime::instance().node.update_cpuload().await;
@@ -1,3 +1,4 @@
+use std::fmt;
 use std::net::SocketAddr;
 use std::num::{NonZeroU16, NonZeroU32};
 use std::ops::Deref;
@@ -5,18 +6,165 @@ use std::str::FromStr;
 use std::sync::Arc;
 use std::time::Duration;
 
+use ntex_mqtt::v5::codec::RetainHandling;
+use rust_box::std_ext::RwLock;
 use serde::de::{self, Deserialize, Deserializer};
 
 use crate::broker::types::QoS;
 
-use super::{deserialize_addr, deserialize_duration, to_duration, Bytesize};
+use super::{deserialize_addr, deserialize_duration, deserialize_duration_option, to_duration, Bytesize};
 
 type HashMap<K, V> = std::collections::HashMap<K, V, ahash::RandomState>;
 
 type Port = u16;
 
+///Action on a QoS 1/2 message redelivered message_retry_max_attempts times
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RetryExhausted {
+    ///Kept in the inflight window without further redelivery, until acknowledged or the client reconnects
+    Keep,
+    ///Removed from the inflight window, the message_dropped hook is called
+    Drop,
+}
+
+///PROXY protocol header expected before the client data, v1 or v2
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyProtocol {
+    Off,
+    ///Used if sent, direct connections are also accepted
+    Optional,
+    ///Connections without the header are closed
+    Required,
+}
+
+///Characters allowed in the client ids
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientIdCharset {
+    #[default]
+    Any,
+    ///0-9, a-z and A-Z, those the MQTT servers must accept
+    Alphanumeric,
+    ///Printable ASCII characters, without spaces
+    Printable,
+}
+
+///Regular expression the client ids must match
+#[derive(Debug, Clone)]
+pub struct ClientIdPattern(regex::Regex);
+
+impl Deref for ClientIdPattern {
+    type Target = regex::Regex;
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl PartialEq for ClientIdPattern {
+    #[inline]
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl<'de> Deserialize<'de> for ClientIdPattern {
+    #[inline]
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let v = String::deserialize(deserializer)?;
+        regex::Regex::new(&v)
+            .map(ClientIdPattern)
+            .map_err(|e| de::Error::custom(format!("clientid_pattern, {}, {}", v, e)))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ListenerType {
+    Tcp,
+    Tls,
+    Ws,
+    Wss,
+    ///Unix domain socket
+    Uds,
+}
+
+impl ListenerType {
+    pub const ALL: [ListenerType; 5] =
+        [ListenerType::Tcp, ListenerType::Tls, ListenerType::Ws, ListenerType::Wss, ListenerType::Uds];
+
+    #[inline]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ListenerType::Tcp => "tcp",
+            ListenerType::Tls => "tls",
+            ListenerType::Ws => "ws",
+            ListenerType::Wss => "wss",
+            ListenerType::Uds => "uds",
+        }
+    }
+}
+
+impl FromStr for ListenerType {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "tcp" => Ok(ListenerType::Tcp),
+            "tls" => Ok(ListenerType::Tls),
+            "ws" => Ok(ListenerType::Ws),
+            "wss" => Ok(ListenerType::Wss),
+            "uds" => Ok(ListenerType::Uds),
+            _ => Err(format!("unknown listener type, {}", s)),
+        }
+    }
+}
+
+//Listeners added, modified or removed at runtime, they take precedence over the startup configs
+#[derive(Debug, Default)]
+struct RuntimeListeners {
+    //None if removed
+    overrides: HashMap<(ListenerType, Port), Option<Listener>>,
+    //Configs of the removed or replaced listeners, for the sessions created through them
+    historicals: HashMap<Port, Listener>,
+}
+
+#[derive(Clone)]
+struct SharedRuntimeListeners(Arc<RwLock<RuntimeListeners>>);
+
+impl Default for SharedRuntimeListeners {
+    fn default() -> Self {
+        Self(Arc::new(RwLock::new(RuntimeListeners::default())))
+    }
+}
+
+impl Deref for SharedRuntimeListeners {
+    type Target = RwLock<RuntimeListeners>;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl fmt::Debug for SharedRuntimeListeners {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let runtime = self.read();
+        write!(f, "{:?}", &*runtime)
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Default)]
 pub struct Listeners {
+    //Watch the config files, the listeners are added, modified or removed as they change
+    #[serde(default)]
+    pub watch: bool,
+
+    #[serde(default = "Listeners::watch_interval_default", deserialize_with = "deserialize_duration")]
+    pub watch_interval: Duration,
+
     #[serde(rename = "tcp")]
     #[serde(default)]
     _tcps: HashMap<String, ListenerInner>,
@@ -33,6 +181,10 @@ pub struct Listeners {
     #[serde(default)]
     _wsss: HashMap<String, ListenerInner>,
 
+    #[serde(rename = "uds")]
+    #[serde(default)]
+    _udss: HashMap<String, ListenerInner>,
+
     #[serde(default, skip)]
     pub tcps: HashMap<Port, Listener>,
     #[serde(default, skip)]
@@ -41,6 +193,11 @@ pub struct Listeners {
     pub wss: HashMap<Port, Listener>,
     #[serde(default, skip)]
     pub wsss: HashMap<Port, Listener>,
+    #[serde(default, skip)]
+    pub udss: HashMap<Port, Listener>,
+
+    #[serde(default, skip)]
+    runtime: SharedRuntimeListeners,
 }
 
 impl Listeners {
@@ -73,43 +230,114 @@ impl Listeners {
                 self.wsss.insert(inner.addr.port(), Listener::new(inner));
             }
         }
+
+        for (name, mut inner) in self._udss.drain() {
+            if inner.enable {
+                inner.name = name;
+                self.udss.insert(inner.addr.port(), Listener::new(inner));
+            }
+        }
+    }
+
+    #[inline]
+    fn watch_interval_default() -> Duration {
+        Duration::from_secs(5)
+    }
+
+    #[inline]
+    fn startups(&self, typ: ListenerType) -> &HashMap<Port, Listener> {
+        match typ {
+            ListenerType::Tcp => &self.tcps,
+            ListenerType::Tls => &self.tlss,
+            ListenerType::Ws => &self.wss,
+            ListenerType::Wss => &self.wsss,
+            ListenerType::Uds => &self.udss,
+        }
+    }
+
+    ///The active listener config of the port
+    #[inline]
+    pub fn lookup(&self, typ: ListenerType, port: u16) -> Option<Listener> {
+        if let Some(l) = self.runtime.read().overrides.get(&(typ, port)) {
+            return l.clone();
+        }
+        self.startups(typ).get(&port).cloned()
+    }
+
+    ///All active listener configs
+    pub fn actives(&self) -> Vec<(ListenerType, Listener)> {
+        let runtime = self.runtime.read();
+        let mut actives = Vec::new();
+        for typ in ListenerType::ALL {
+            for (port, l) in self.startups(typ) {
+                if !runtime.overrides.contains_key(&(typ, *port)) {
+                    actives.push((typ, l.clone()));
+                }
+            }
+        }
+        for ((typ, _), l) in runtime.overrides.iter() {
+            if let Some(l) = l {
+                actives.push((*typ, l.clone()));
+            }
+        }
+        actives
+    }
+
+    ///Adds or replaces a listener at runtime, the replaced config is kept as a historical one
+    pub fn set(&self, typ: ListenerType, listener: Listener) {
+        let port = listener.addr.port();
+        let prev = self.lookup(typ, port);
+        let mut runtime = self.runtime.write();
+        if let Some(prev) = prev {
+            runtime.historicals.insert(port, prev);
+        }
+        runtime.overrides.insert((typ, port), Some(listener));
+    }
+
+    ///Removes a listener at runtime, the config is kept as a historical one
+    pub fn remove(&self, typ: ListenerType, port: u16) -> Option<Listener> {
+        let prev = self.lookup(typ, port)?;
+        let mut runtime = self.runtime.write();
+        runtime.historicals.insert(port, prev.clone());
+        runtime.overrides.insert((typ, port), None);
+        Some(prev)
     }
 
     #[inline]
     pub fn tcp(&self, port: u16) -> Option<Listener> {
-        self.tcps.get(&port).cloned()
+        self.lookup(ListenerType::Tcp, port)
     }
 
     #[inline]
     pub fn tls(&self, port: u16) -> Option<Listener> {
-        self.tlss.get(&port).cloned()
+        self.lookup(ListenerType::Tls, port)
     }
 
     #[inline]
     pub fn ws(&self, port: u16) -> Option<Listener> {
-        self.wss.get(&port).cloned()
+        self.lookup(ListenerType::Ws, port)
     }
 
     #[inline]
     pub fn wss(&self, port: u16) -> Option<Listener> {
-        self.wsss.get(&port).cloned()
+        self.lookup(ListenerType::Wss, port)
+    }
+
+    #[inline]
+    pub fn uds(&self, port: u16) -> Option<Listener> {
+        self.lookup(ListenerType::Uds, port)
     }
 
+    ///The listener config of the port, the one of a removed listener if it is no longer active, for
+    ///the sessions created through it
     #[inline]
     pub fn get(&self, port: u16) -> Option<Listener> {
-        if let Some(l) = self.tcp(port) {
-            return Some(l);
-        }
-        if let Some(l) = self.tls(port) {
-            return Some(l);
-        }
-        if let Some(l) = self.ws(port) {
-            return Some(l);
-        }
-        if let Some(l) = self.wss(port) {
-            return Some(l);
+        for typ in ListenerType::ALL {
+            if let Some(l) = self.lookup(typ, port) {
+                return Some(l);
+            }
         }
-        None
+        self.runtime.read().historicals.get(&port).cloned()
     }
 
     #[inline]
@@ -126,7 +354,7 @@ pub struct Listener {
 
 impl Listener {
     #[inline]
-    fn new(inner: ListenerInner) -> Self {
+    pub fn new(inner: ListenerInner) -> Self {
         Self { inner: Arc::new(inner) }
     }
 }
@@ -138,7 +366,7 @@ impl Deref for Listener {
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct ListenerInner {
     #[serde(default)]
     pub name: String,
@@ -154,6 +382,10 @@ pub struct ListenerInner {
     pub max_handshaking_limit: usize,
     #[serde(default = "ListenerInner::max_packet_size_default")]
     pub max_packet_size: Bytesize,
+    //The maximum packet size of the clients exempted by the client_packet_size_exempt hook, the codec
+    //limit of the listener if it is larger than max_packet_size, 0 means no exemption
+    #[serde(default)]
+    pub max_packet_size_exempt: Bytesize,
     #[serde(default = "ListenerInner::backlog_default")]
     pub backlog: i32,
     #[serde(default = "ListenerInner::reuseaddr_default")]
@@ -162,6 +394,54 @@ pub struct ListenerInner {
     pub reuseport: Option<bool>,
     #[serde(default = "ListenerInner::allow_anonymous_default")]
     pub allow_anonymous: bool,
+    //Every client is anonymous, the auth plugins are bypassed, see broker::anonymous
+    #[serde(default)]
+    pub anonymous_mode: bool,
+    //Username of the clients in the anonymous mode, empty keeps the one of the client
+    #[serde(default)]
+    pub anonymous_username: String,
+    //Limits of the anonymous clients, those of the listener if not set, 0 is unlimited
+    #[serde(default)]
+    pub anonymous_max_subscriptions: Option<usize>,
+    #[serde(default)]
+    pub anonymous_max_wildcard_subscriptions: Option<usize>,
+    #[serde(default)]
+    pub anonymous_max_topic_levels: Option<usize>,
+    //Token bucket of the publishes of an anonymous client, "burst,period", unlimited if not set
+    #[serde(default, deserialize_with = "ListenerInner::deserialize_anonymous_publish_rate_limit")]
+    pub anonymous_publish_rate_limit: Option<(NonZeroU32, Duration)>,
+    //Token bucket of the publishes of each client, "burst,period", unlimited if not set, the anonymous
+    //clients get anonymous_publish_rate_limit if set
+    #[serde(default, deserialize_with = "ListenerInner::deserialize_publish_rate_limit")]
+    pub publish_rate_limit: Option<(NonZeroU32, Duration)>,
+    //Share of the publish token bucket used from which the acks of the MQTT 5.0 clients carry the quota
+    //user properties, a warning the client can back off on, none if not below publish_quota_enforcement
+    #[serde(default = "ListenerInner::publish_quota_warning_default")]
+    pub publish_quota_warning: f64,
+    //Share of the publish token bucket used beyond which the publishes are refused, the MQTT 5.0 clients
+    //are replied Quota Exceeded with the quota user properties, those of the MQTT 3.1.1 clients dropped
+    #[serde(default = "ListenerInner::publish_quota_enforcement_default")]
+    pub publish_quota_enforcement: f64,
+    //Topic prefixes the anonymous clients may publish to and subscribe to, all if empty
+    #[serde(default)]
+    pub anonymous_publish_topics: Vec<String>,
+    #[serde(default)]
+    pub anonymous_subscribe_topics: Vec<String>,
+    //Origins allowed of the WebSocket handshakes, exact or with a "*" wildcard, all if empty (ws, wss)
+    #[serde(default)]
+    pub allowed_origins: Vec<String>,
+    //Whether the WebSocket handshakes without Origin are rejected
+    #[serde(default)]
+    pub origin_required: bool,
+    //Token bucket of the WebSocket handshakes of each origin, "burst,period", unlimited if not set
+    #[serde(default, deserialize_with = "ListenerInner::deserialize_origin_rate_limit")]
+    pub origin_rate_limit: Option<(NonZeroU32, Duration)>,
+    //Path of the Unix domain socket (uds), the port of addr is not bound, it identifies the listener
+    #[serde(default)]
+    pub path: String,
+    //File mode of the socket, octal, "660" for instance, that of the umask if not set (uds)
+    #[serde(default, deserialize_with = "ListenerInner::deserialize_permissions")]
+    pub permissions: Option<u32>,
     #[serde(default = "ListenerInner::min_keepalive_default")]
     pub min_keepalive: u16,
     #[serde(default = "ListenerInner::max_keepalive_default")]
@@ -170,8 +450,36 @@ pub struct ListenerInner {
     pub allow_zero_keepalive: bool,
     #[serde(default = "ListenerInner::keepalive_backoff_default")]
     pub keepalive_backoff: f32,
+    //Multiplies the time a client may stay silent before the keep alive timeout, tolerance for
+    //jittery links, 1.0 is the MQTT keep alive enforcement
+    #[serde(default = "ListenerInner::keepalive_grace_default")]
+    pub keepalive_grace: f32,
+    //Whether the Server Keep Alive set by the plugins in the CONNACK properties is applied (MQTT 5)
+    #[serde(default = "ListenerInner::honor_server_keepalive_default")]
+    pub honor_server_keepalive: bool,
+    //TCP keepalive of the connections, enabled if the idle time is set. The probes are sent every
+    //interval once the connection is idle, it is closed after count unanswered probes, the system
+    //defaults are used for the interval and the count that are not set
+    #[serde(default, deserialize_with = "deserialize_duration_option")]
+    pub tcp_keepalive_idle: Option<Duration>,
+    #[serde(default, deserialize_with = "deserialize_duration_option")]
+    pub tcp_keepalive_interval: Option<Duration>,
+    #[serde(default)]
+    pub tcp_keepalive_count: Option<u32>,
     #[serde(default = "ListenerInner::max_inflight_default")]
     pub max_inflight: NonZeroU16,
+    //Adapts the send quota of each client, from max_inflight down to max_inflight_min, to its ack
+    //latency and its queue growth
+    #[serde(default)]
+    pub max_inflight_adaptive: bool,
+    #[serde(default = "ListenerInner::max_inflight_min_default")]
+    pub max_inflight_min: u16,
+    //Average ack latency above which the quota is halved
+    #[serde(
+        default = "ListenerInner::max_inflight_ack_latency_default",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub max_inflight_ack_latency: Duration,
     #[serde(default = "ListenerInner::handshake_timeout_default", deserialize_with = "deserialize_duration")]
     pub handshake_timeout: Duration,
     #[serde(default = "ListenerInner::max_mqueue_len_default")]
@@ -185,6 +493,20 @@ pub struct ListenerInner {
     #[serde(default = "ListenerInner::max_clientid_len_default")]
     pub max_clientid_len: usize,
 
+    //Client id rules of the default client id policy, see broker::clientid
+    #[serde(default)]
+    pub clientid_min_len: usize,
+    #[serde(default, deserialize_with = "ListenerInner::deserialize_clientid_charset")]
+    pub clientid_charset: ClientIdCharset,
+    #[serde(default)]
+    pub clientid_pattern: Option<ClientIdPattern>,
+    //Assigns a client id to the clients connecting without one, otherwise they are refused
+    #[serde(default = "ListenerInner::clientid_assign_default")]
+    pub clientid_assign: bool,
+    //Prefix of the assigned client ids
+    #[serde(default)]
+    pub clientid_prefix: String,
+
     #[serde(
         default = "ListenerInner::max_qos_allowed_default",
         deserialize_with = "ListenerInner::deserialize_max_qos_allowed"
@@ -197,6 +519,13 @@ pub struct ListenerInner {
     #[serde(default = "ListenerInner::retain_available_default")]
     pub retain_available: bool,
 
+    //Retain Handling of the MQTT 3.1.1 subscriptions, at_subscribe, at_subscribe_new or no_at_subscribe
+    #[serde(
+        default = "ListenerInner::retain_handling_v3_default",
+        deserialize_with = "ListenerInner::deserialize_retain_handling"
+    )]
+    pub retain_handling_v3: RetainHandling,
+
     #[serde(
         default = "ListenerInner::session_expiry_interval_default",
         deserialize_with = "deserialize_duration"
@@ -209,15 +538,108 @@ pub struct ListenerInner {
     )]
     pub message_retry_interval: Duration,
 
+    //The retry interval is multiplied by the factor after each redelivery, 1.0 is a fixed interval
+    #[serde(default = "ListenerInner::message_retry_backoff_default")]
+    pub message_retry_backoff: f64,
+
+    //Maximum retry interval, 0 is unlimited
+    #[serde(
+        default = "ListenerInner::message_retry_max_interval_default",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub message_retry_max_interval: Duration,
+
+    //Fraction of the retry interval randomly added or removed, from 0.0 to 1.0
+    #[serde(default)]
+    pub message_retry_jitter: f64,
+
+    //Maximum number of redeliveries of a message, 0 is unlimited
+    #[serde(default)]
+    pub message_retry_max_attempts: u32,
+
+    //Action on a message redelivered max_attempts times, keep or drop
+    #[serde(
+        default = "ListenerInner::message_retry_exhausted_default",
+        deserialize_with = "ListenerInner::deserialize_retry_exhausted"
+    )]
+    pub message_retry_exhausted: RetryExhausted,
+
     #[serde(
         default = "ListenerInner::message_expiry_interval_default",
         deserialize_with = "deserialize_duration"
     )]
     pub message_expiry_interval: Duration,
 
+    //Delay before the inflight and offline messages of the previous session are retransmitted
+    //after a reconnection (clean_start=false)
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub resume_delay: Duration,
+
+    //Messages per second retransmitted after a reconnection, 0 is unlimited
+    #[serde(default)]
+    pub resume_rate: u32,
+
+    //Retransmits the rest after the client acknowledges the first message, or the
+    //message_retry_interval elapses
+    #[serde(default)]
+    pub resume_wait_first_ack: bool,
+
     #[serde(default = "ListenerInner::max_subscriptions_default")]
     pub max_subscriptions: usize,
 
+    //Maximum number of the subscriptions with wildcards, "+" or "#", 0 is unlimited
+    #[serde(default)]
+    pub max_wildcard_subscriptions: usize,
+
+    //Maximum number of the subscriptions with the multi-level wildcard "#", 0 is unlimited
+    #[serde(default)]
+    pub max_multilevel_wildcard_subscriptions: usize,
+
+    //Drops the duplicate publishes of a client, e.g. caused by bridge loops or broken QoS 2 clients
+    #[serde(default)]
+    pub dedup: bool,
+
+    //Maximum number of the recent messages remembered per session
+    #[serde(default = "ListenerInner::dedup_max_default")]
+    pub dedup_max: usize,
+
+    #[serde(default = "ListenerInner::dedup_ttl_default", deserialize_with = "deserialize_duration")]
+    pub dedup_ttl: Duration,
+
+    //User property carrying the client message id (MQTT 5.0), the content hash is used without it
+    #[serde(default)]
+    pub dedup_key_property: String,
+
+    //The publisher of a request (MQTT 5.0) must be allowed to subscribe to its response topic
+    #[serde(default)]
+    pub response_topic_acl: bool,
+
+    //Response topic prefix assigned to the clients in the CONNACK (MQTT 5.0), %c is the client id
+    //and %u the username, empty is not assigned
+    #[serde(default)]
+    pub response_topic_prefix: String,
+
+    //Checks the packets for the MQTT spec violations, see the conformance settings
+    #[serde(default)]
+    pub strict_conformance: bool,
+
+    //PROXY protocol of the load balancer in front, off, optional or required
+    #[serde(
+        default = "ListenerInner::proxy_protocol_default",
+        deserialize_with = "ListenerInner::deserialize_proxy_protocol"
+    )]
+    pub proxy_protocol: ProxyProtocol,
+
+    #[serde(
+        default = "ListenerInner::proxy_protocol_timeout_default",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub proxy_protocol_timeout: Duration,
+
+    //Connections closed before sending any data, the TCP health checks, are not logged
+    #[serde(default)]
+    pub silent_health_probes: bool,
+
     #[serde(default = "ListenerInner::shared_subscription_default")]
     pub shared_subscription: bool,
 
@@ -240,26 +662,77 @@ impl Default for ListenerInner {
             max_connections: ListenerInner::max_connections_default(),
             max_handshaking_limit: ListenerInner::max_handshaking_limit_default(),
             max_packet_size: ListenerInner::max_packet_size_default(),
+            max_packet_size_exempt: Bytesize::default(),
             reuseaddr: ListenerInner::reuseaddr_default(),
             reuseport: ListenerInner::reuseport_default(),
             backlog: ListenerInner::backlog_default(),
             allow_anonymous: ListenerInner::allow_anonymous_default(),
+            anonymous_mode: false,
+            anonymous_username: String::default(),
+            anonymous_max_subscriptions: None,
+            anonymous_max_wildcard_subscriptions: None,
+            anonymous_max_topic_levels: None,
+            anonymous_publish_rate_limit: None,
+            publish_rate_limit: None,
+            publish_quota_warning: ListenerInner::publish_quota_warning_default(),
+            publish_quota_enforcement: ListenerInner::publish_quota_enforcement_default(),
+            anonymous_publish_topics: Vec::new(),
+            anonymous_subscribe_topics: Vec::new(),
+            allowed_origins: Vec::new(),
+            origin_required: false,
+            origin_rate_limit: None,
+            path: String::new(),
+            permissions: None,
             min_keepalive: ListenerInner::min_keepalive_default(),
             max_keepalive: ListenerInner::max_keepalive_default(),
             allow_zero_keepalive: ListenerInner::allow_zero_keepalive_default(),
             keepalive_backoff: ListenerInner::keepalive_backoff_default(),
+            keepalive_grace: ListenerInner::keepalive_grace_default(),
+            honor_server_keepalive: ListenerInner::honor_server_keepalive_default(),
+            tcp_keepalive_idle: None,
+            tcp_keepalive_interval: None,
+            tcp_keepalive_count: None,
             max_inflight: ListenerInner::max_inflight_default(),
+            max_inflight_adaptive: false,
+            max_inflight_min: ListenerInner::max_inflight_min_default(),
+            max_inflight_ack_latency: ListenerInner::max_inflight_ack_latency_default(),
             handshake_timeout: ListenerInner::handshake_timeout_default(),
             max_mqueue_len: ListenerInner::max_mqueue_len_default(),
             mqueue_rate_limit: ListenerInner::mqueue_rate_limit_default(),
             max_clientid_len: ListenerInner::max_clientid_len_default(),
+            clientid_min_len: 0,
+            clientid_charset: ClientIdCharset::default(),
+            clientid_pattern: None,
+            clientid_assign: ListenerInner::clientid_assign_default(),
+            clientid_prefix: String::new(),
             max_qos_allowed: ListenerInner::max_qos_allowed_default(),
             max_topic_levels: ListenerInner::max_topic_levels_default(),
             retain_available: ListenerInner::retain_available_default(),
+            retain_handling_v3: ListenerInner::retain_handling_v3_default(),
             session_expiry_interval: ListenerInner::session_expiry_interval_default(),
             message_retry_interval: ListenerInner::message_retry_interval_default(),
+            message_retry_backoff: ListenerInner::message_retry_backoff_default(),
+            message_retry_max_interval: ListenerInner::message_retry_max_interval_default(),
+            message_retry_jitter: 0.0,
+            message_retry_max_attempts: 0,
+            message_retry_exhausted: ListenerInner::message_retry_exhausted_default(),
             message_expiry_interval: ListenerInner::message_expiry_interval_default(),
+            resume_delay: Duration::ZERO,
+            resume_rate: 0,
+            resume_wait_first_ack: false,
             max_subscriptions: ListenerInner::max_subscriptions_default(),
+            max_wildcard_subscriptions: 0,
+            max_multilevel_wildcard_subscriptions: 0,
+            dedup: false,
+            dedup_max: ListenerInner::dedup_max_default(),
+            dedup_ttl: ListenerInner::dedup_ttl_default(),
+            dedup_key_property: String::default(),
+            response_topic_acl: false,
+            response_topic_prefix: String::default(),
+            strict_conformance: false,
+            proxy_protocol: ListenerInner::proxy_protocol_default(),
+            proxy_protocol_timeout: ListenerInner::proxy_protocol_timeout_default(),
+            silent_health_probes: false,
             shared_subscription: ListenerInner::shared_subscription_default(),
             max_topic_aliases: 0,
             cross_certificate: ListenerInner::cross_certificate_default(),
@@ -326,10 +799,34 @@ impl ListenerInner {
         0.75
     }
     #[inline]
+    fn keepalive_grace_default() -> f32 {
+        1.0
+    }
+    #[inline]
+    fn honor_server_keepalive_default() -> bool {
+        true
+    }
+    #[inline]
+    fn publish_quota_warning_default() -> f64 {
+        0.8
+    }
+    #[inline]
+    fn publish_quota_enforcement_default() -> f64 {
+        1.0
+    }
+    #[inline]
     fn max_inflight_default() -> NonZeroU16 {
         NonZeroU16::new(16).unwrap()
     }
     #[inline]
+    fn max_inflight_min_default() -> u16 {
+        1
+    }
+    #[inline]
+    fn max_inflight_ack_latency_default() -> Duration {
+        Duration::from_millis(500)
+    }
+    #[inline]
     fn handshake_timeout_default() -> Duration {
         Duration::from_secs(15)
     }
@@ -346,6 +843,10 @@ impl ListenerInner {
         65535
     }
     #[inline]
+    fn clientid_assign_default() -> bool {
+        true
+    }
+    #[inline]
     fn max_qos_allowed_default() -> QoS {
         QoS::ExactlyOnce
     }
@@ -366,6 +867,33 @@ impl ListenerInner {
         Duration::from_secs(30)
     }
     #[inline]
+    fn message_retry_backoff_default() -> f64 {
+        1.0
+    }
+    #[inline]
+    fn message_retry_max_interval_default() -> Duration {
+        Duration::from_secs(300)
+    }
+    #[inline]
+    fn message_retry_exhausted_default() -> RetryExhausted {
+        RetryExhausted::Keep
+    }
+    #[inline]
+    fn deserialize_retry_exhausted<'de, D>(deserializer: D) -> Result<RetryExhausted, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let v = String::deserialize(deserializer)?;
+        match v.to_ascii_lowercase().as_str() {
+            "keep" => Ok(RetryExhausted::Keep),
+            "drop" => Ok(RetryExhausted::Drop),
+            _ => Err(de::Error::custom(format!(
+                "message_retry_exhausted, only keep and drop are supported, {}",
+                v
+            ))),
+        }
+    }
+    #[inline]
     fn message_expiry_interval_default() -> Duration {
         Duration::from_secs(300)
     }
@@ -374,9 +902,58 @@ impl ListenerInner {
         0
     }
     #[inline]
+    fn dedup_max_default() -> usize {
+        1000
+    }
+    #[inline]
+    fn dedup_ttl_default() -> Duration {
+        Duration::from_secs(30)
+    }
+    #[inline]
     fn shared_subscription_default() -> bool {
         true
     }
+    #[inline]
+    fn proxy_protocol_default() -> ProxyProtocol {
+        ProxyProtocol::Off
+    }
+    #[inline]
+    fn proxy_protocol_timeout_default() -> Duration {
+        Duration::from_secs(5)
+    }
+    #[inline]
+    fn deserialize_proxy_protocol<'de, D>(deserializer: D) -> Result<ProxyProtocol, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let v = String::deserialize(deserializer)?;
+        match v.to_ascii_lowercase().as_str() {
+            "off" => Ok(ProxyProtocol::Off),
+            "optional" => Ok(ProxyProtocol::Optional),
+            "required" => Ok(ProxyProtocol::Required),
+            _ => Err(de::Error::custom(format!(
+                "proxy_protocol, only off, optional and required are supported, {}",
+                v
+            ))),
+        }
+    }
+
+    #[inline]
+    fn deserialize_clientid_charset<'de, D>(deserializer: D) -> Result<ClientIdCharset, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let v = String::deserialize(deserializer)?;
+        match v.to_ascii_lowercase().as_str() {
+            "any" => Ok(ClientIdCharset::Any),
+            "alphanumeric" => Ok(ClientIdCharset::Alphanumeric),
+            "printable" => Ok(ClientIdCharset::Printable),
+            _ => Err(de::Error::custom(format!(
+                "clientid_charset, only any, alphanumeric and printable are supported, {}",
+                v
+            ))),
+        }
+    }
 
     #[inline]
     pub fn handshake_timeout(&self) -> u16 {
@@ -388,26 +965,96 @@ impl ListenerInner {
         }
     }
 
+    ///TCP keepalive of the connections, none if it is not enabled
+    #[inline]
+    pub fn tcp_keepalive(&self) -> Option<socket2::TcpKeepalive> {
+        let keepalive = socket2::TcpKeepalive::new().with_time(self.tcp_keepalive_idle?);
+        #[cfg(any(
+            target_os = "linux",
+            target_os = "android",
+            target_os = "macos",
+            target_os = "freebsd",
+            windows
+        ))]
+        let keepalive = match self.tcp_keepalive_interval {
+            Some(interval) => keepalive.with_interval(interval),
+            None => keepalive,
+        };
+        #[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "freebsd"))]
+        let keepalive = match self.tcp_keepalive_count {
+            Some(count) => keepalive.with_retries(count),
+            None => keepalive,
+        };
+        Some(keepalive)
+    }
+
     #[inline]
     fn deserialize_mqueue_rate_limit<'de, D>(deserializer: D) -> Result<(NonZeroU32, Duration), D::Error>
     where
         D: Deserializer<'de>,
     {
         let v = String::deserialize(deserializer)?;
+        Self::parse_rate_limit("mqueue_rate_limit", &v).map_err(de::Error::custom)
+    }
+
+    #[inline]
+    fn deserialize_anonymous_publish_rate_limit<'de, D>(
+        deserializer: D,
+    ) -> Result<Option<(NonZeroU32, Duration)>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let v = String::deserialize(deserializer)?;
+        Self::parse_rate_limit("anonymous_publish_rate_limit", &v).map(Some).map_err(de::Error::custom)
+    }
+
+    #[inline]
+    fn deserialize_publish_rate_limit<'de, D>(
+        deserializer: D,
+    ) -> Result<Option<(NonZeroU32, Duration)>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let v = String::deserialize(deserializer)?;
+        Self::parse_rate_limit("publish_rate_limit", &v).map(Some).map_err(de::Error::custom)
+    }
+
+    #[inline]
+    fn deserialize_origin_rate_limit<'de, D>(
+        deserializer: D,
+    ) -> Result<Option<(NonZeroU32, Duration)>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let v = String::deserialize(deserializer)?;
+        Self::parse_rate_limit("origin_rate_limit", &v).map(Some).map_err(de::Error::custom)
+    }
+
+    #[inline]
+    fn deserialize_permissions<'de, D>(deserializer: D) -> Result<Option<u32>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let v = String::deserialize(deserializer)?;
+        match u32::from_str_radix(v.trim_start_matches("0o"), 8) {
+            Ok(mode) if mode <= 0o7777 => Ok(Some(mode)),
+            _ => Err(de::Error::custom(format!("permissions, an octal file mode is expected, {}", v))),
+        }
+    }
+
+    //"burst,period"
+    fn parse_rate_limit(name: &str, v: &str) -> Result<(NonZeroU32, Duration), String> {
         let pair: Vec<&str> = v.split(',').collect();
         if pair.len() == 2 {
             let burst = NonZeroU32::from_str(pair[0])
-                .map_err(|e| de::Error::custom(format!("mqueue_rate_limit, burst format error, {:?}", e)))?;
+                .map_err(|e| format!("{}, burst format error, {:?}", name, e))?;
             let replenish_n_per = to_duration(pair[1]);
             if replenish_n_per.as_millis() == 0 {
-                return Err(de::Error::custom(format!(
-                    "mqueue_rate_limit, value format error, {}",
-                    pair.join(",")
-                )));
+                return Err(format!("{}, value format error, {}", name, pair.join(",")));
             }
             Ok((burst, replenish_n_per))
         } else {
-            Err(de::Error::custom(format!("mqueue_rate_limit, value format error, {}", pair.join(","))))
+            Err(format!("{}, value format error, {}", name, pair.join(",")))
         }
     }
     #[inline]
@@ -424,6 +1071,29 @@ impl ListenerInner {
         Ok(qos)
     }
     #[inline]
+    fn retain_handling_v3_default() -> RetainHandling {
+        RetainHandling::AtSubscribe
+    }
+    #[inline]
+    fn deserialize_retain_handling<'de, D>(deserializer: D) -> Result<RetainHandling, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let v = String::deserialize(deserializer)?;
+        let retain_handling = match v.to_ascii_lowercase().as_str() {
+            "at_subscribe" => RetainHandling::AtSubscribe,
+            "at_subscribe_new" => RetainHandling::AtSubscribeNew,
+            "no_at_subscribe" => RetainHandling::NoAtSubscribe,
+            _ => {
+                return Err(de::Error::custom(format!(
+                    "retain_handling_v3, only at_subscribe, at_subscribe_new and no_at_subscribe are supported, {}",
+                    v
+                )))
+            }
+        };
+        Ok(retain_handling)
+    }
+    #[inline]
     fn cross_certificate_default() -> bool {
         false
     }
@@ -3,6 +3,8 @@ use std::str::FromStr;
 
 use serde::de::{self, Deserialize, Deserializer};
 
+use super::Bytesize;
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct Log {
     #[serde(default = "Log::to_default")]
@@ -13,6 +15,8 @@ pub struct Log {
     pub dir: String,
     #[serde(default = "Log::file_default")]
     pub file: String,
+    #[serde(default)]
+    pub audit: Audit,
 }
 
 impl Default for Log {
@@ -23,6 +27,7 @@ impl Default for Log {
             level: Self::level_default(),
             dir: Self::dir_default(),
             file: Self::file_default(),
+            audit: Audit::default(),
         }
     }
 }
@@ -46,15 +51,109 @@ impl Log {
     }
     #[inline]
     pub fn filename(&self) -> String {
-        let file = &self.file;
-        if file.is_empty() {
-            return "".into();
-        }
-        if self.dir.is_empty() {
-            return file.to_owned();
+        filename(&self.dir, &self.file)
+    }
+}
+
+#[inline]
+fn filename(dir: &str, file: &str) -> String {
+    if file.is_empty() {
+        return "".into();
+    }
+    if dir.is_empty() {
+        return file.to_owned();
+    }
+    let dir = dir.trim_end_matches(|c| c == '/' || c == '\\');
+    format!("{}/{}", dir, file)
+}
+
+//Audit log of the security-relevant events, JSON records
+#[derive(Debug, Clone, Deserialize)]
+pub struct Audit {
+    #[serde(default)]
+    pub enable: bool,
+    #[serde(default = "Audit::to_default")]
+    pub to: AuditTo,
+    #[serde(default = "Audit::dir_default")]
+    pub dir: String,
+    #[serde(default = "Audit::file_default")]
+    pub file: String,
+    //The file is rotated when it exceeds max_size, max_files rotated files are kept
+    #[serde(default = "Audit::max_size_default")]
+    pub max_size: Bytesize,
+    #[serde(default = "Audit::max_files_default")]
+    pub max_files: usize,
+    //Unix socket of the syslog daemon
+    #[serde(default = "Audit::syslog_addr_default")]
+    pub syslog_addr: String,
+}
+
+impl Default for Audit {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            enable: false,
+            to: Self::to_default(),
+            dir: Self::dir_default(),
+            file: Self::file_default(),
+            max_size: Self::max_size_default(),
+            max_files: Self::max_files_default(),
+            syslog_addr: Self::syslog_addr_default(),
         }
-        let dir = self.dir.trim_end_matches(|c| c == '/' || c == '\\');
-        format!("{}/{}", dir, file)
+    }
+}
+
+impl Audit {
+    #[inline]
+    fn to_default() -> AuditTo {
+        AuditTo::File
+    }
+    #[inline]
+    fn dir_default() -> String {
+        "/var/log/rmqtt".into()
+    }
+    #[inline]
+    fn file_default() -> String {
+        "audit.log".into()
+    }
+    #[inline]
+    fn max_size_default() -> Bytesize {
+        Bytesize::from("100M")
+    }
+    #[inline]
+    fn max_files_default() -> usize {
+        10
+    }
+    #[inline]
+    fn syslog_addr_default() -> String {
+        "/dev/log".into()
+    }
+    #[inline]
+    pub fn filename(&self) -> String {
+        filename(&self.dir, &self.file)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditTo {
+    Off,
+    File,
+    Syslog,
+}
+
+impl<'de> Deserialize<'de> for AuditTo {
+    #[inline]
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let to = match (String::deserialize(deserializer)?).to_ascii_lowercase().as_str() {
+            "off" => AuditTo::Off,
+            "file" => AuditTo::File,
+            "syslog" => AuditTo::Syslog,
+            _ => return Err(de::Error::custom("audit log to must be off, file or syslog")),
+        };
+        Ok(to)
     }
 }
 
@@ -7,7 +7,7 @@ use std::sync::Arc;
 use std::time::Duration;
 
 use chrono::LocalResult;
-use config::{Config, File, Source};
+use config::{Config, File, Source, Value, ValueKind};
 use once_cell::sync::OnceCell;
 use serde::de::{self, Deserialize, Deserializer};
 use serde::ser::Serializer;
@@ -16,13 +16,14 @@ use serde::Serialize;
 use crate::{Addr, MqttError, NodeId, Result};
 
 pub use self::listener::Listener;
-use self::listener::Listeners;
+pub use self::listener::Listeners;
 use self::log::Log;
 pub use self::options::Options;
 
 pub mod listener;
 pub mod log;
 pub mod options;
+pub mod secret;
 
 static SETTINGS: OnceCell<Settings> = OnceCell::new();
 
@@ -46,6 +47,30 @@ pub struct Inner {
     pub plugins: Plugins,
     #[serde(default)]
     pub mqtt: Mqtt,
+    #[serde(default)]
+    pub routing: Routing,
+    #[serde(default)]
+    pub encryption: Encryption,
+    #[serde(default)]
+    pub health: Health,
+    #[serde(default)]
+    pub conformance: Conformance,
+    #[serde(default)]
+    pub idempotency: Idempotency,
+    #[serde(default)]
+    pub alarm: Alarm,
+    #[serde(default)]
+    pub quota: Quota,
+    #[serde(default)]
+    pub acl_cache: AclCache,
+    #[serde(default)]
+    pub latency: Latency,
+    #[serde(default)]
+    pub shared_queue: SharedQueue,
+    #[serde(default)]
+    pub protocol_bridge: ProtocolBridge,
+    #[serde(default)]
+    pub journal: Journal,
     #[serde(default, skip)]
     pub opts: Options,
 }
@@ -58,7 +83,8 @@ impl Deref for Settings {
 }
 
 impl Settings {
-    fn new(opts: Options) -> Result<Self> {
+    #[inline]
+    fn config(opts: &Options) -> Result<Config> {
         let mut builder = Config::builder()
             .add_source(File::with_name("/etc/rmqtt/rmqtt").required(false))
             .add_source(File::with_name("/etc/rmqtt").required(false))
@@ -73,8 +99,12 @@ impl Settings {
         if let Some(cfg) = opts.cfg_name.as_ref() {
             builder = builder.add_source(File::with_name(cfg).required(false));
         }
+        Ok(builder.build()?)
+    }
 
-        let mut inner: Inner = builder.build()?.try_deserialize()?;
+    fn new(opts: Options) -> Result<Self> {
+        let mut inner: Inner = Self::config(&opts)?.try_deserialize()?;
+        inner.node.init_id()?;
 
         inner.listeners.init();
         if inner.listeners.tcps.is_empty() && inner.listeners.tlss.is_empty() {
@@ -101,6 +131,13 @@ impl Settings {
         SETTINGS.get().unwrap()
     }
 
+    ///Reads the listener configs again, for the hot reload of the listeners
+    pub fn load_listeners(&self) -> Result<Listeners> {
+        let mut listeners: Listeners = Self::config(&self.opts)?.get("listener")?;
+        listeners.init();
+        Ok(listeners)
+    }
+
     #[inline]
     pub fn init(opts: Options) -> &'static Self {
         SETTINGS.set(Settings::new(opts).unwrap()).unwrap();
@@ -223,21 +260,135 @@ impl Task {
 pub struct Node {
     #[serde(default)]
     pub id: NodeId,
+    //Where the node id comes from, config, ordinal or file
+    #[serde(default)]
+    pub id_from: NodeIdFrom,
+    //Identity file holding the node id, for id_from = "file"
+    #[serde(default = "Node::id_file_default")]
+    pub id_file: String,
     #[serde(default = "Node::cookie_default")]
     pub cookie: String,
     // #[serde(default = "Node::crash_dump_default")]
     // pub crash_dump: String,
     #[serde(default)]
     pub busy: Busy,
+    #[serde(default)]
+    pub drain: Drain,
+    #[serde(default)]
+    pub redirect: Redirect,
+    #[serde(default)]
+    pub reaper: Reaper,
+    #[serde(default)]
+    pub overload: Overload,
+    #[serde(default)]
+    pub fanout: FanOut,
+    #[serde(default)]
+    pub maintenance: Maintenance,
 }
 
 impl Node {
+    fn id_file_default() -> String {
+        "/var/lib/rmqtt/node.id".into()
+    }
+
     fn cookie_default() -> String {
         "rmqttsecretcookie".into()
     }
     // fn crash_dump_default() -> String {
     //     "/var/log/rmqtt/crash.dump".into()
     // }
+
+    ///Derives the node id according to id_from
+    fn init_id(&mut self) -> Result<()> {
+        match self.id_from {
+            NodeIdFrom::Config => {}
+            NodeIdFrom::Ordinal => {
+                let hostname = std::env::var("HOSTNAME")
+                    .ok()
+                    .filter(|h| !h.is_empty())
+                    .or_else(|| std::fs::read_to_string("/etc/hostname").ok().map(|h| h.trim().to_string()))
+                    .unwrap_or_default();
+                self.id = ordinal_node_id(&hostname)
+                    .ok_or_else(|| MqttError::from(format!("no ordinal in the host name, {:?}", hostname)))?;
+            }
+            NodeIdFrom::File => {
+                let path = std::path::Path::new(&self.id_file);
+                if path.exists() {
+                    self.id = NodeId::from_str(std::fs::read_to_string(path)?.trim())
+                        .map_err(MqttError::ParseIntError)?;
+                } else {
+                    //A new identity, kept across restarts
+                    self.id = (rand::random::<u32>() as NodeId).max(1);
+                    if let Some(dir) = path.parent() {
+                        std::fs::create_dir_all(dir)?;
+                    }
+                    std::fs::write(path, self.id.to_string())?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NodeIdFrom {
+    #[default]
+    Config,
+    //The ordinal suffix of the host name plus one, "rmqtt-2" is node 3, for StatefulSet pods
+    Ordinal,
+    File,
+}
+
+impl<'de> Deserialize<'de> for NodeIdFrom {
+    #[inline]
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let from = match (String::deserialize(deserializer)?).to_ascii_lowercase().as_str() {
+            "config" => NodeIdFrom::Config,
+            "ordinal" => NodeIdFrom::Ordinal,
+            "file" => NodeIdFrom::File,
+            f => return Err(de::Error::custom(format!("invalid node id from, {}", f))),
+        };
+        Ok(from)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum QueueOverflowPolicy {
+    //Waits until the queue falls to the low watermark, at most queue_block_timeout
+    #[default]
+    Block,
+    //Drops the forwarded messages, the message_dropped hook is triggered
+    Drop,
+    //Appends the forwarded messages to a disk-backed queue, sent after the congestion clears
+    Spill,
+}
+
+impl<'de> Deserialize<'de> for QueueOverflowPolicy {
+    #[inline]
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let policy = match (String::deserialize(deserializer)?).to_ascii_lowercase().as_str() {
+            "block" => QueueOverflowPolicy::Block,
+            "drop" => QueueOverflowPolicy::Drop,
+            "spill" => QueueOverflowPolicy::Spill,
+            p => return Err(de::Error::custom(format!("invalid queue overflow policy, {}", p))),
+        };
+        Ok(policy)
+    }
+}
+
+///Node id of a host name with an ordinal suffix, the ordinal plus one, "rmqtt-2" or
+///"rmqtt-2.rmqtt-headless.default.svc" is node 3
+#[inline]
+pub fn ordinal_node_id(hostname: &str) -> Option<NodeId> {
+    let name = hostname.split('.').next()?;
+    let (_, ordinal) = name.rsplit_once('-')?;
+    NodeId::from_str(ordinal).ok().map(|o| o + 1)
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -288,6 +439,397 @@ impl Busy {
     }
 }
 
+#[derive(Debug, Clone, Deserialize)]
+pub struct Drain {
+    //Reason code sent to MQTT 5.0 clients when the node is draining, use_another_server or server_moved
+    #[serde(default)]
+    pub reason: DrainReason,
+    //Server Reference sent to MQTT 5.0 clients, where the clients should reconnect to
+    #[serde(default)]
+    pub server_reference: String,
+    //Grace period for the inflight QoS 1/2 message flows to complete before disconnecting
+    #[serde(default = "Drain::grace_period_default", deserialize_with = "deserialize_duration")]
+    pub grace_period: Duration,
+    //Maximum time waiting for the disconnected sessions to go offline
+    #[serde(default = "Drain::disconnect_timeout_default", deserialize_with = "deserialize_duration")]
+    pub disconnect_timeout: Duration,
+}
+
+impl Default for Drain {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            reason: DrainReason::default(),
+            server_reference: String::default(),
+            grace_period: Self::grace_period_default(),
+            disconnect_timeout: Self::disconnect_timeout_default(),
+        }
+    }
+}
+
+impl Drain {
+    fn grace_period_default() -> Duration {
+        Duration::from_secs(30)
+    }
+
+    fn disconnect_timeout_default() -> Duration {
+        Duration::from_secs(10)
+    }
+
+    #[inline]
+    pub fn server_reference(&self) -> Option<bytestring::ByteString> {
+        if self.server_reference.is_empty() {
+            None
+        } else {
+            Some(bytestring::ByteString::from(self.server_reference.as_str()))
+        }
+    }
+}
+
+///Redirection of the clients to other servers, by the redirect admin command or the redirect shed
+///action of the overload protection
+#[derive(Debug, Clone, Deserialize)]
+pub struct Redirect {
+    //Reason code sent to MQTT 5.0 clients, use_another_server or server_moved
+    #[serde(default)]
+    pub reason: DrainReason,
+    //Server Reference sent to MQTT 5.0 clients, where the clients should reconnect to
+    #[serde(default)]
+    pub server_reference: String,
+    //Connected clients redirected per overload sample while redirect is shed, 0 is only the new
+    //connections refused
+    #[serde(default = "Redirect::batch_default")]
+    pub batch: usize,
+}
+
+impl Default for Redirect {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            reason: DrainReason::default(),
+            server_reference: String::default(),
+            batch: Self::batch_default(),
+        }
+    }
+}
+
+impl Redirect {
+    fn batch_default() -> usize {
+        100
+    }
+
+    #[inline]
+    pub fn server_reference(&self) -> Option<bytestring::ByteString> {
+        if self.server_reference.is_empty() {
+            None
+        } else {
+            Some(bytestring::ByteString::from(self.server_reference.as_str()))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Reaper {
+    #[serde(default = "Reaper::enable_default")]
+    pub enable: bool,
+    //Interval of scanning the connected sessions
+    #[serde(default = "Reaper::interval_default", deserialize_with = "deserialize_duration")]
+    pub interval: Duration,
+    //A session is suspected when there is no activity for longer than keepalive * keepalive_factor
+    #[serde(default = "Reaper::keepalive_factor_default")]
+    pub keepalive_factor: f32,
+    //A suspected session whose connection task does not answer the ping within this time is reaped
+    #[serde(default = "Reaper::ping_timeout_default", deserialize_with = "deserialize_duration")]
+    pub ping_timeout: Duration,
+}
+
+impl Default for Reaper {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            enable: Self::enable_default(),
+            interval: Self::interval_default(),
+            keepalive_factor: Self::keepalive_factor_default(),
+            ping_timeout: Self::ping_timeout_default(),
+        }
+    }
+}
+
+impl Reaper {
+    fn enable_default() -> bool {
+        true
+    }
+
+    fn interval_default() -> Duration {
+        Duration::from_secs(30)
+    }
+
+    fn keepalive_factor_default() -> f32 {
+        3.0
+    }
+
+    fn ping_timeout_default() -> Duration {
+        Duration::from_secs(5)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Overload {
+    #[serde(default)]
+    pub enable: bool,
+    //Interval of sampling the CPU load, the event loop lag and the message queues
+    #[serde(default = "Overload::sample_interval_default", deserialize_with = "deserialize_duration")]
+    pub sample_interval: Duration,
+    //Thresholds, 0 is not checked
+    #[serde(default = "Overload::cpuload_default")]
+    pub cpuload: f32,
+    #[serde(default = "Overload::loop_lag_default", deserialize_with = "deserialize_duration")]
+    pub loop_lag: Duration,
+    #[serde(default = "Overload::message_queues_default")]
+    pub message_queues: usize,
+    //The level goes down when every sample is below the thresholds times recover_ratio
+    #[serde(default = "Overload::recover_ratio_default")]
+    pub recover_ratio: f32,
+    //Shedding actions, one more is taken per sample while overloaded, in this order
+    #[serde(default = "Overload::shed_order_default")]
+    pub shed_order: Vec<ShedAction>,
+    //Delay of each QoS 0 delivery, for defer_qos0
+    #[serde(default = "Overload::qos0_defer_default", deserialize_with = "deserialize_duration")]
+    pub qos0_defer: Duration,
+    //Delay of PUBACK/PUBREC to the publishers, for slow_puback
+    #[serde(default = "Overload::puback_delay_default", deserialize_with = "deserialize_duration")]
+    pub puback_delay: Duration,
+}
+
+impl Default for Overload {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            enable: false,
+            sample_interval: Self::sample_interval_default(),
+            cpuload: Self::cpuload_default(),
+            loop_lag: Self::loop_lag_default(),
+            message_queues: Self::message_queues_default(),
+            recover_ratio: Self::recover_ratio_default(),
+            shed_order: Self::shed_order_default(),
+            qos0_defer: Self::qos0_defer_default(),
+            puback_delay: Self::puback_delay_default(),
+        }
+    }
+}
+
+impl Overload {
+    fn sample_interval_default() -> Duration {
+        Duration::from_secs(1)
+    }
+
+    fn cpuload_default() -> f32 {
+        90.0
+    }
+
+    fn loop_lag_default() -> Duration {
+        Duration::from_millis(200)
+    }
+
+    fn message_queues_default() -> usize {
+        1_000_000
+    }
+
+    fn recover_ratio_default() -> f32 {
+        0.8
+    }
+
+    fn shed_order_default() -> Vec<ShedAction> {
+        vec![
+            ShedAction::PauseRetain,
+            ShedAction::DeferQos0,
+            ShedAction::RejectConnect,
+            ShedAction::SlowPuback,
+        ]
+    }
+
+    fn qos0_defer_default() -> Duration {
+        Duration::from_millis(10)
+    }
+
+    fn puback_delay_default() -> Duration {
+        Duration::from_millis(100)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FanOut {
+    #[serde(default)]
+    pub enable: bool,
+    //Tasks delivering a hot publish, its subscribers are sharded across them
+    #[serde(default = "FanOut::shards_default")]
+    pub shards: usize,
+    //A publish to at least threshold local subscribers is a hot one
+    #[serde(default = "FanOut::threshold_default")]
+    pub threshold: usize,
+    //A shard yields to the other tasks every yield_batch deliveries, 0 never yields
+    #[serde(default = "FanOut::yield_batch_default")]
+    pub yield_batch: usize,
+}
+
+impl Default for FanOut {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            enable: false,
+            shards: Self::shards_default(),
+            threshold: Self::threshold_default(),
+            yield_batch: Self::yield_batch_default(),
+        }
+    }
+}
+
+impl FanOut {
+    fn shards_default() -> usize {
+        4
+    }
+
+    fn threshold_default() -> usize {
+        10_000
+    }
+
+    fn yield_batch_default() -> usize {
+        256
+    }
+}
+
+///Maintenance mode, see broker::maintenance
+#[derive(Debug, Clone, Deserialize)]
+pub struct Maintenance {
+    //Messages held by a peer for a node in maintenance, the forwards to the node that failed
+    #[serde(default = "Maintenance::max_held_default")]
+    pub max_held: usize,
+    //Topic filters per page when the peers pull the routes of the node
+    #[serde(default = "Maintenance::page_size_default")]
+    pub page_size: usize,
+}
+
+impl Default for Maintenance {
+    #[inline]
+    fn default() -> Self {
+        Self { max_held: Self::max_held_default(), page_size: Self::page_size_default() }
+    }
+}
+
+impl Maintenance {
+    fn max_held_default() -> usize {
+        100_000
+    }
+
+    fn page_size_default() -> usize {
+        1000
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShedAction {
+    //Retained messages are not sent on subscribe
+    PauseRetain,
+    //QoS 0 deliveries are delayed
+    DeferQos0,
+    //New connections are refused with Server Busy
+    RejectConnect,
+    //PUBACK/PUBREC to the publishers are delayed
+    SlowPuback,
+    //New connections are refused and connected clients are disconnected, with the reason code and
+    //the server reference of node.redirect
+    Redirect,
+}
+
+impl ShedAction {
+    #[inline]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ShedAction::PauseRetain => "pause_retain",
+            ShedAction::DeferQos0 => "defer_qos0",
+            ShedAction::RejectConnect => "reject_connect",
+            ShedAction::SlowPuback => "slow_puback",
+            ShedAction::Redirect => "redirect",
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ShedAction {
+    #[inline]
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let action = match (String::deserialize(deserializer)?).to_ascii_lowercase().as_str() {
+            "pause_retain" => ShedAction::PauseRetain,
+            "defer_qos0" => ShedAction::DeferQos0,
+            "reject_connect" => ShedAction::RejectConnect,
+            "slow_puback" => ShedAction::SlowPuback,
+            "redirect" => ShedAction::Redirect,
+            a => return Err(de::Error::custom(format!("invalid shed action, {}", a))),
+        };
+        Ok(action)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DrainReason {
+    #[default]
+    UseAnotherServer,
+    ServerMoved,
+}
+
+impl DrainReason {
+    #[inline]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DrainReason::UseAnotherServer => "use_another_server",
+            DrainReason::ServerMoved => "server_moved",
+        }
+    }
+
+    #[inline]
+    pub fn connack_code(&self) -> ntex_mqtt::v5::codec::ConnectAckReason {
+        match self {
+            DrainReason::UseAnotherServer => ntex_mqtt::v5::codec::ConnectAckReason::UseAnotherServer,
+            DrainReason::ServerMoved => ntex_mqtt::v5::codec::ConnectAckReason::ServerMoved,
+        }
+    }
+
+    #[inline]
+    pub fn disconnect_code(&self) -> ntex_mqtt::v5::codec::DisconnectReasonCode {
+        match self {
+            DrainReason::UseAnotherServer => ntex_mqtt::v5::codec::DisconnectReasonCode::UseAnotherServer,
+            DrainReason::ServerMoved => ntex_mqtt::v5::codec::DisconnectReasonCode::ServerMoved,
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for DrainReason {
+    #[inline]
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let reason = match (String::deserialize(deserializer)?).to_ascii_lowercase().as_str() {
+            "use_another_server" => DrainReason::UseAnotherServer,
+            "server_moved" => DrainReason::ServerMoved,
+            r => return Err(de::Error::custom(format!("invalid drain reason, {}", r))),
+        };
+        Ok(reason)
+    }
+}
+
+impl Serialize for DrainReason {
+    #[inline]
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct Rpc {
     #[serde(default = "Rpc::server_addr_default", deserialize_with = "deserialize_addr")]
@@ -311,6 +853,60 @@ pub struct Rpc {
     //#Maximum number of messages sent in batch
     #[serde(default = "Rpc::batch_size_default")]
     pub batch_size: usize,
+    //The forwarded messages to a peer are coalesced into a BatchForwards message for at most the window,
+    //or forward_batch_size messages, 0 to disable
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub forward_batch_window: Duration,
+    #[serde(default = "Rpc::forward_batch_size_default")]
+    pub forward_batch_size: usize,
+
+    //The send queue of a peer is congested above the high watermark, until it falls to the low watermark
+    #[serde(default = "Rpc::queue_high_watermark_default")]
+    pub queue_high_watermark: usize,
+    #[serde(default = "Rpc::queue_low_watermark_default")]
+    pub queue_low_watermark: usize,
+    //Policy of the messages sent to a congested peer
+    #[serde(default)]
+    pub queue_overflow_policy: QueueOverflowPolicy,
+    #[serde(default = "Rpc::queue_block_timeout_default", deserialize_with = "deserialize_duration")]
+    pub queue_block_timeout: Duration,
+    //Directory of the spill queues, one file per peer
+    #[serde(default = "Rpc::queue_spill_dir_default")]
+    pub queue_spill_dir: String,
+    //Maximum number of messages in a spill queue, the excess is dropped
+    #[serde(default = "Rpc::queue_spill_max_default")]
+    pub queue_spill_max: usize,
+    //Maximum size of a chunk of the retained messages replied to the other nodes
+    #[serde(default = "Rpc::retains_chunk_size_default")]
+    pub retains_chunk_size: Bytesize,
+    //Maximum number of the retained messages replied to the other nodes, the rest are truncated,
+    //0 is unlimited
+    #[serde(default = "Rpc::retains_max_default")]
+    pub retains_max: usize,
+    //Maximum number of the stored messages fetched at a time from a node, by a reattached subscriber
+    #[serde(default = "Rpc::messages_page_size_default")]
+    pub messages_page_size: usize,
+    //Shared token of the nodes, sent with each request and required by the server, empty to disable,
+    //see grpc::auth
+    #[serde(default)]
+    pub auth_token: String,
+    //The server rejects the requests of the nodes not in the node list, or not from their address
+    #[serde(default)]
+    pub check_peer: bool,
+    //TLS of the server and the clients, the certificate and the key of this node and the CA of the
+    //cluster, disabled if not set
+    #[serde(default)]
+    pub tls_cert: Option<String>,
+    #[serde(default)]
+    pub tls_key: Option<String>,
+    #[serde(default)]
+    pub tls_ca_cert: Option<String>,
+    //The server requires the client certificates, mutual TLS
+    #[serde(default = "Rpc::tls_verify_client_default")]
+    pub tls_verify_client: bool,
+    //The name in the certificates of the peers, the host of their address if not set
+    #[serde(default)]
+    pub tls_domain_name: Option<String>,
 }
 
 impl Default for Rpc {
@@ -320,10 +916,28 @@ impl Default for Rpc {
             reuseaddr: Self::reuseaddr_default(),
             reuseport: Self::reuseport_default(),
             batch_size: Self::batch_size_default(),
+            forward_batch_window: Duration::ZERO,
+            forward_batch_size: Self::forward_batch_size_default(),
             server_addr: Self::server_addr_default(),
             server_workers: Self::server_workers_default(),
             client_concurrency_limit: Self::client_concurrency_limit_default(),
             client_timeout: Self::client_timeout_default(),
+            queue_high_watermark: Self::queue_high_watermark_default(),
+            queue_low_watermark: Self::queue_low_watermark_default(),
+            queue_overflow_policy: QueueOverflowPolicy::default(),
+            queue_block_timeout: Self::queue_block_timeout_default(),
+            queue_spill_dir: Self::queue_spill_dir_default(),
+            queue_spill_max: Self::queue_spill_max_default(),
+            retains_chunk_size: Self::retains_chunk_size_default(),
+            retains_max: Self::retains_max_default(),
+            messages_page_size: Self::messages_page_size_default(),
+            auth_token: String::default(),
+            check_peer: false,
+            tls_cert: None,
+            tls_key: None,
+            tls_ca_cert: None,
+            tls_verify_client: Self::tls_verify_client_default(),
+            tls_domain_name: None,
         }
     }
 }
@@ -338,6 +952,9 @@ impl Rpc {
     fn batch_size_default() -> usize {
         128
     }
+    fn forward_batch_size_default() -> usize {
+        64
+    }
     fn server_addr_default() -> SocketAddr {
         ([0, 0, 0, 0], 5363).into()
     }
@@ -350,14 +967,69 @@ impl Rpc {
     fn client_timeout_default() -> Duration {
         Duration::from_secs(5)
     }
+    fn queue_high_watermark_default() -> usize {
+        80_000
+    }
+    fn queue_low_watermark_default() -> usize {
+        50_000
+    }
+    fn queue_block_timeout_default() -> Duration {
+        Duration::from_secs(5)
+    }
+    fn queue_spill_dir_default() -> String {
+        "/var/lib/rmqtt/spill".into()
+    }
+    fn queue_spill_max_default() -> usize {
+        1_000_000
+    }
+    fn retains_chunk_size_default() -> Bytesize {
+        Bytesize::from(1024 * 1024)
+    }
+    fn retains_max_default() -> usize {
+        100_000
+    }
+    fn messages_page_size_default() -> usize {
+        1000
+    }
+    fn tls_verify_client_default() -> bool {
+        true
+    }
 }
 
-#[derive(Default, Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct Plugins {
     #[serde(default = "Plugins::dir_default")]
     pub dir: String,
     #[serde(default)]
     pub default_startups: Vec<String>,
+    //A plugin whose hook handler panics is restarted, at most restart_max_retries times, then it
+    //is stopped and marked failed
+    #[serde(default = "Plugins::restart_max_retries_default")]
+    pub restart_max_retries: usize,
+    //Delay before the restart, doubled on each retry
+    #[serde(default = "Plugins::restart_backoff_default", deserialize_with = "deserialize_duration")]
+    pub restart_backoff: Duration,
+    //Queue capacity of each subscriber of the plugin message bus
+    #[serde(default = "Plugins::bus_capacity_default")]
+    pub bus_capacity: usize,
+    //The hook handlers of a plugin are restricted to the listeners, by name, e.g. the JWT plugin
+    //on external and the HTTP plugin on internal. A scope given at registration takes precedence
+    #[serde(default)]
+    pub listener_scopes: std::collections::HashMap<String, Vec<String>>,
+}
+
+impl Default for Plugins {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            dir: Self::dir_default(),
+            default_startups: Vec::default(),
+            restart_max_retries: Self::restart_max_retries_default(),
+            restart_backoff: Self::restart_backoff_default(),
+            bus_capacity: Self::bus_capacity_default(),
+            listener_scopes: std::collections::HashMap::default(),
+        }
+    }
 }
 
 impl Plugins {
@@ -365,6 +1037,18 @@ impl Plugins {
         "./plugins/".into()
     }
 
+    fn restart_max_retries_default() -> usize {
+        3
+    }
+
+    fn restart_backoff_default() -> Duration {
+        Duration::from_secs(1)
+    }
+
+    fn bus_capacity_default() -> usize {
+        1024
+    }
+
     pub fn load_config<'de, T: serde::Deserialize<'de>>(&self, name: &str) -> Result<T> {
         let (cfg, _) = self.load_config_with_required(name, true, &[])?;
         Ok(cfg)
@@ -405,6 +1089,22 @@ impl Plugins {
         Ok(cfg)
     }
 
+    ///The config of the plugin as it is in its config file and environment variables, without
+    ///defaults, for validation
+    pub fn load_raw_config(&self, name: &str) -> Result<serde_json::Value> {
+        let dir = self.dir.trim_end_matches(|c| c == '/' || c == '\\');
+        let s = Config::builder()
+            .add_source(File::with_name(&format!("{}/{}", dir, name)).required(false))
+            .add_source(
+                config::Environment::with_prefix(&format!("rmqtt_plugin_{}", name.replace('-', "_")))
+                    .try_parsing(true),
+            )
+            .build()?;
+        let mut cfg = Value::new(None, ValueKind::Table(s.collect()?));
+        secret::resolve(&mut cfg)?;
+        Ok(cfg.try_deserialize::<serde_json::Value>()?)
+    }
+
     fn load_config_with_required<'de, T: serde::Deserialize<'de>>(
         &self,
         name: &str,
@@ -425,19 +1125,480 @@ impl Plugins {
         builder = builder.add_source(env);
 
         let s = builder.build()?;
-        let count = s.collect()?.len();
-        Ok((s.try_deserialize::<T>()?, count == 0))
+        let cfg = s.collect()?;
+        let count = cfg.len();
+        //secret references, ${env:VAR}, ${file:/path} ...
+        let mut cfg = Value::new(None, ValueKind::Table(cfg));
+        secret::resolve(&mut cfg)?;
+        Ok((cfg.try_deserialize::<T>()?, count == 0))
     }
 }
 
 #[derive(Debug, Clone, Default, Deserialize)]
 pub struct Mqtt {}
 
+///Routing policies between the listener groups, by the listener names
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Routing {
+    #[serde(default)]
+    pub enable: bool,
+    //Action of the deliveries matching no rule
+    #[serde(default)]
+    pub default: RoutingAction,
+    //The first matching rule applies
+    #[serde(default)]
+    pub rules: Vec<RoutingRule>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RoutingRule {
+    //Listener name of the publisher, "*" is any listener
+    pub from: String,
+    //Listener name of the subscriber, "*" is any listener
+    pub to: String,
+    //Topic filter of the publishes, all publishes if not set
+    #[serde(default)]
+    pub topic: Option<String>,
+    pub action: RoutingAction,
+}
+
+///Liveness and readiness probes, served over HTTP
+#[derive(Debug, Clone, Deserialize)]
+pub struct Health {
+    #[serde(default)]
+    pub enable: bool,
+    #[serde(default = "Health::listen_default", deserialize_with = "deserialize_addr")]
+    pub listen: SocketAddr,
+    //A readiness check not completed within this time is failed
+    #[serde(default = "Health::check_timeout_default", deserialize_with = "deserialize_duration")]
+    pub check_timeout: Duration,
+}
+
+impl Default for Health {
+    #[inline]
+    fn default() -> Self {
+        Self { enable: false, listen: Self::listen_default(), check_timeout: Self::check_timeout_default() }
+    }
+}
+
+impl Health {
+    fn listen_default() -> SocketAddr {
+        ([0, 0, 0, 0], 6070).into()
+    }
+
+    fn check_timeout_default() -> Duration {
+        Duration::from_secs(3)
+    }
+}
+
+///Idempotency keys of the exactly-once bridging, assigned to the messages at their first ingress
+#[derive(Debug, Clone, Deserialize)]
+pub struct Idempotency {
+    #[serde(default)]
+    pub enable: bool,
+    //Name of the user property carrying the key
+    #[serde(default = "Idempotency::property_default")]
+    pub property: String,
+}
+
+impl Default for Idempotency {
+    #[inline]
+    fn default() -> Self {
+        Self { enable: false, property: Self::property_default() }
+    }
+}
+
+impl Idempotency {
+    fn property_default() -> String {
+        "idempotency-key".into()
+    }
+}
+
+///Alarms of the node, published to $SYS/brokers/{node}/alarms by rmqtt-sys-topic
+#[derive(Debug, Clone, Deserialize)]
+pub struct Alarm {
+    #[serde(default = "Alarm::enable_default")]
+    pub enable: bool,
+    #[serde(default = "Alarm::check_interval_default", deserialize_with = "deserialize_duration")]
+    pub check_interval: Duration,
+    //Maximum number of the alarm events kept
+    #[serde(default = "Alarm::history_max_default")]
+    pub history_max: usize,
+    //Used memory to total memory ratio raising high_memory, 0 is disabled
+    #[serde(default = "Alarm::memory_high_watermark_default")]
+    pub memory_high_watermark: f32,
+    //Open file descriptors to their limit ratio raising fd_exhaustion, 0 is disabled
+    #[serde(default = "Alarm::fd_high_watermark_default")]
+    pub fd_high_watermark: f32,
+    //Queued messages raising queue_overload, the overload level above 0 raises it as well, 0 is disabled
+    #[serde(default)]
+    pub message_queues: isize,
+}
+
+impl Default for Alarm {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            enable: Self::enable_default(),
+            check_interval: Self::check_interval_default(),
+            history_max: Self::history_max_default(),
+            memory_high_watermark: Self::memory_high_watermark_default(),
+            fd_high_watermark: Self::fd_high_watermark_default(),
+            message_queues: 0,
+        }
+    }
+}
+
+impl Alarm {
+    fn enable_default() -> bool {
+        true
+    }
+
+    fn check_interval_default() -> Duration {
+        Duration::from_secs(10)
+    }
+
+    fn history_max_default() -> usize {
+        1000
+    }
+
+    fn memory_high_watermark_default() -> f32 {
+        0.9
+    }
+
+    fn fd_high_watermark_default() -> f32 {
+        0.9
+    }
+}
+
+///Cluster-wide session quotas of the usernames and the tenants, see broker::quota
+#[derive(Debug, Clone, Deserialize)]
+pub struct Quota {
+    //Maximum connected sessions of a username in the cluster, 0 is unlimited
+    #[serde(default)]
+    pub max_sessions_per_username: usize,
+    //Maximum connected sessions of a tenant in the cluster, 0 is unlimited
+    #[serde(default)]
+    pub max_sessions_per_tenant: usize,
+    //Maximum connected sessions of a label in the cluster, by label, see broker::labels
+    #[serde(default)]
+    pub max_sessions_per_label: std::collections::HashMap<String, usize>,
+    //The tenant is the username prefix up to the delimiter, no tenants if empty
+    #[serde(default = "Quota::tenant_delimiter_default")]
+    pub tenant_delimiter: String,
+    //Action when a quota is exceeded, reject or kick_oldest
+    #[serde(default)]
+    pub action: QuotaAction,
+    //The nodes not replying to the session count within the timeout are not counted
+    #[serde(default = "Quota::query_timeout_default", deserialize_with = "deserialize_duration")]
+    pub query_timeout: Duration,
+}
+
+impl Default for Quota {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            max_sessions_per_username: 0,
+            max_sessions_per_tenant: 0,
+            max_sessions_per_label: std::collections::HashMap::default(),
+            tenant_delimiter: Self::tenant_delimiter_default(),
+            action: QuotaAction::default(),
+            query_timeout: Self::query_timeout_default(),
+        }
+    }
+}
+
+impl Quota {
+    fn tenant_delimiter_default() -> String {
+        "/".into()
+    }
+
+    fn query_timeout_default() -> Duration {
+        Duration::from_secs(3)
+    }
+}
+
+///ACL decision cache of the hook manager, see broker::acl_cache
+#[derive(Debug, Clone, Deserialize)]
+pub struct AclCache {
+    #[serde(default)]
+    pub enable: bool,
+    #[serde(default = "AclCache::ttl_default", deserialize_with = "deserialize_duration")]
+    pub ttl: Duration,
+    //Maximum number of the decisions cached of a client, 0 is unlimited
+    #[serde(default = "AclCache::max_per_client_default")]
+    pub max_per_client: usize,
+}
+
+impl Default for AclCache {
+    #[inline]
+    fn default() -> Self {
+        Self { enable: false, ttl: Self::ttl_default(), max_per_client: Self::max_per_client_default() }
+    }
+}
+
+impl AclCache {
+    fn ttl_default() -> Duration {
+        Duration::from_secs(60)
+    }
+
+    fn max_per_client_default() -> usize {
+        100
+    }
+}
+
+///Publish to deliver latency of the messages, see broker::latency
+#[derive(Debug, Clone, Deserialize, Default)]
+pub struct Latency {
+    #[serde(default)]
+    pub enable: bool,
+    //Name of the user property carrying the latency in milliseconds on the MQTT 5 deliveries, empty is
+    //not attached
+    #[serde(default)]
+    pub user_property: String,
+}
+
+///Offline queue of the shared subscription groups, see broker::shared_queue
+#[derive(Debug, Clone, Deserialize)]
+pub struct SharedQueue {
+    #[serde(default)]
+    pub enable: bool,
+    //Prefixes of the names of the groups queued, empty is all the groups
+    #[serde(default)]
+    pub groups: Vec<String>,
+    //Maximum number of the messages queued of a group
+    #[serde(default = "SharedQueue::max_messages_default")]
+    pub max_messages: usize,
+    #[serde(default = "SharedQueue::dir_default")]
+    pub dir: String,
+}
+
+impl Default for SharedQueue {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            enable: false,
+            groups: Vec::new(),
+            max_messages: Self::max_messages_default(),
+            dir: Self::dir_default(),
+        }
+    }
+}
+
+impl SharedQueue {
+    fn max_messages_default() -> usize {
+        10_000
+    }
+
+    fn dir_default() -> String {
+        "/var/lib/rmqtt/shared_queue".into()
+    }
+}
+
+///Normalization of the messages between the MQTT 5 and the MQTT 3 clients, see broker::protocol_bridge
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ProtocolBridge {
+    //The properties of a message delivered to a MQTT 3 client are dropped or carried by an envelope
+    #[serde(default)]
+    pub v3_properties: PropertiesPolicy,
+    //The envelopes published by the MQTT 3 clients are unwrapped for the MQTT 5 clients
+    #[serde(default)]
+    pub v5_unwrap_envelope: bool,
+    //The SUBACK reason codes that close the connection of a MQTT 3 client instead of the failure 0x80
+    #[serde(default)]
+    pub v3_suback_disconnect: Vec<u8>,
+}
+
+///Event journal of the clients, see broker::journal
+#[derive(Debug, Clone, Deserialize)]
+pub struct Journal {
+    //The client ids journaled, exact or with "*" wildcards, empty to disable
+    #[serde(default)]
+    pub client_ids: Vec<String>,
+    //Maximum number of the events kept of a client, the oldest are evicted
+    #[serde(default = "Journal::max_events_default")]
+    pub max_events: usize,
+    //Maximum number of the clients journaled on the node, the others are not journaled
+    #[serde(default = "Journal::max_clients_default")]
+    pub max_clients: usize,
+}
+
+impl Default for Journal {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            client_ids: Vec::new(),
+            max_events: Self::max_events_default(),
+            max_clients: Self::max_clients_default(),
+        }
+    }
+}
+
+impl Journal {
+    fn max_events_default() -> usize {
+        200
+    }
+
+    fn max_clients_default() -> usize {
+        1000
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PropertiesPolicy {
+    #[default]
+    Drop,
+    Envelope,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum QuotaAction {
+    //The new session is refused with Quota Exceeded
+    #[default]
+    Reject,
+    //The oldest session is kicked
+    KickOldest,
+}
+
+impl<'de> Deserialize<'de> for QuotaAction {
+    #[inline]
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let action = match (String::deserialize(deserializer)?).to_ascii_lowercase().as_str() {
+            "reject" => QuotaAction::Reject,
+            "kick_oldest" => QuotaAction::KickOldest,
+            a => return Err(de::Error::custom(format!("invalid quota action, {}", a))),
+        };
+        Ok(action)
+    }
+}
+
+///Strict MQTT protocol conformance, of the listeners with `strict_conformance`
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Conformance {
+    //Action of each violation, log if not set
+    #[serde(default)]
+    pub actions: std::collections::HashMap<Violation, ViolationAction>,
+}
+
+impl Conformance {
+    #[inline]
+    pub fn action(&self, v: Violation) -> ViolationAction {
+        self.actions.get(&v).copied().unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Violation {
+    ///A topic name or filter with U+0000, a control character or a non-character [MQTT-1.5.4-2]
+    InvalidUtf8Topic,
+    ///A client id longer than 23 bytes [MQTT-3.1.3-5]
+    OversizedClientId,
+    ///The DUP flag set on a QoS 0 publish [MQTT-3.3.1-2]
+    Qos0Dup,
+    ///The No Local option set on a shared subscription [MQTT-3.8.3-4]
+    SharedNoLocal,
+    ///Rejected by the codec, e.g. reserved bits set, the client is always disconnected
+    MalformedPacket,
+}
+
+impl Violation {
+    #[inline]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Violation::InvalidUtf8Topic => "invalid_utf8_topic",
+            Violation::OversizedClientId => "oversized_client_id",
+            Violation::Qos0Dup => "qos0_dup",
+            Violation::SharedNoLocal => "shared_no_local",
+            Violation::MalformedPacket => "malformed_packet",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ViolationAction {
+    ///Counted and logged
+    #[default]
+    Log,
+    ///Counted, logged, and the client is disconnected with the reason code of the violation
+    Disconnect,
+    ///Counted only
+    Count,
+}
+
+impl<'de> Deserialize<'de> for ViolationAction {
+    #[inline]
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let action = match (String::deserialize(deserializer)?).to_ascii_lowercase().as_str() {
+            "log" => ViolationAction::Log,
+            "disconnect" => ViolationAction::Disconnect,
+            "count" => ViolationAction::Count,
+            a => return Err(de::Error::custom(format!("invalid violation action, {}", a))),
+        };
+        Ok(action)
+    }
+}
+
+///Keys of the payloads encrypted at rest by the storage plugins
+#[derive(Clone, Default, Deserialize)]
+pub struct Encryption {
+    //Id of the key encrypting the new payloads, the other keys only decrypt
+    #[serde(default)]
+    pub key_id: String,
+    #[serde(default)]
+    pub keys: Vec<EncryptionKey>,
+}
+
+impl fmt::Debug for Encryption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Encryption")
+            .field("key_id", &self.key_id)
+            .field("keys", &self.keys.iter().map(|k| k.id.as_str()).collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+#[derive(Clone, Deserialize)]
+pub struct EncryptionKey {
+    pub id: String,
+    //Base64 of the 256-bit key, or a secret reference, ${env:VAR} or ${file:/path}
+    pub key: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RoutingAction {
+    #[default]
+    Allow,
+    Deny,
+}
+
+impl<'de> Deserialize<'de> for RoutingAction {
+    #[inline]
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let action = match (String::deserialize(deserializer)?).to_ascii_lowercase().as_str() {
+            "allow" => RoutingAction::Allow,
+            "deny" => RoutingAction::Deny,
+            a => return Err(de::Error::custom(format!("invalid routing action, {}", a))),
+        };
+        Ok(action)
+    }
+}
+
 const BYTESIZE_K: usize = 1024;
 const BYTESIZE_M: usize = 1048576;
 const BYTESIZE_G: usize = 1073741824;
 
-#[derive(Clone, Copy, Default)]
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
 pub struct Bytesize(usize);
 
 impl Bytesize {
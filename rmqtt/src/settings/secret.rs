@@ -0,0 +1,142 @@
+//! Secret references in the plugin configs, a string value of the form `${env:VAR}`, `${file:/path}`
+//! or `${<scheme>:<reference>}` is replaced by the secret when the config is loaded. The schemes
+//! other than env and file, e.g. vault, are resolved by the resolvers registered with
+//! `register_resolver`. The resolved secrets are redacted from the configs and the attributes of
+//! the plugins shown by the management APIs.
+
+use std::collections::BTreeSet;
+
+use config::{Value, ValueKind};
+use once_cell::sync::Lazy;
+use rust_box::std_ext::RwLock;
+
+use crate::broker::types::HashMap;
+use crate::{MqttError, Result};
+
+pub const REDACTED: &str = "******";
+
+pub type Resolver = Box<dyn Fn(&str) -> Result<String> + Send + Sync>;
+
+static RESOLVERS: Lazy<RwLock<HashMap<String, Resolver>>> = Lazy::new(|| RwLock::new(HashMap::default()));
+
+//The resolved secrets, for redaction
+static SECRETS: Lazy<RwLock<BTreeSet<String>>> = Lazy::new(|| RwLock::new(BTreeSet::new()));
+
+///Registers the resolver of the references `${<scheme>:<reference>}`, it gets the reference
+#[inline]
+pub fn register_resolver<F>(scheme: &str, resolver: F)
+where
+    F: Fn(&str) -> Result<String> + Send + Sync + 'static,
+{
+    RESOLVERS.write().insert(scheme.to_owned(), Box::new(resolver));
+}
+
+///The scheme and the reference of a secret reference
+#[inline]
+fn parse(s: &str) -> Option<(&str, &str)> {
+    s.strip_prefix("${")?.strip_suffix('}')?.split_once(':')
+}
+
+fn resolve_ref(scheme: &str, reference: &str) -> Result<String> {
+    match scheme {
+        "env" => std::env::var(reference)
+            .map_err(|e| MqttError::from(format!("secret ${{env:{}}}, {}", reference, e))),
+        "file" => std::fs::read_to_string(reference)
+            .map(|s| s.trim_end_matches(['\r', '\n']).to_owned())
+            .map_err(|e| MqttError::from(format!("secret ${{file:{}}}, {}", reference, e))),
+        _ => match RESOLVERS.read().get(scheme) {
+            Some(resolver) => resolver(reference),
+            None => Err(MqttError::from(format!("secret ${{{}:...}}, no resolver for {}", scheme, scheme))),
+        },
+    }
+}
+
+///The secret of a secret reference, other strings are returned as they are
+pub(crate) fn resolve_str(s: &str) -> Result<String> {
+    match parse(s) {
+        Some((scheme, reference)) => {
+            let secret = resolve_ref(scheme, reference)?;
+            if !secret.is_empty() {
+                SECRETS.write().insert(secret.clone());
+            }
+            Ok(secret)
+        }
+        None => Ok(s.to_owned()),
+    }
+}
+
+///Replaces the secret references of the config value, recursively
+pub(crate) fn resolve(value: &mut Value) -> Result<()> {
+    match &mut value.kind {
+        ValueKind::String(s) => {
+            if let Some((scheme, reference)) = parse(s) {
+                let secret = resolve_ref(scheme, reference)?;
+                if !secret.is_empty() {
+                    SECRETS.write().insert(secret.clone());
+                }
+                *s = secret;
+            }
+        }
+        ValueKind::Table(table) => {
+            for v in table.values_mut() {
+                resolve(v)?;
+            }
+        }
+        ValueKind::Array(array) => {
+            for v in array.iter_mut() {
+                resolve(v)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+///Replaces the resolved secrets in the JSON value, recursively
+pub fn redact(value: &mut serde_json::Value) {
+    let secrets = SECRETS.read();
+    if secrets.is_empty() {
+        return;
+    }
+    _redact(&secrets, value)
+}
+
+fn _redact(secrets: &BTreeSet<String>, value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::String(s) => {
+            if secrets.iter().any(|secret| s.contains(secret.as_str())) {
+                *s = REDACTED.into();
+            }
+        }
+        serde_json::Value::Array(array) => array.iter_mut().for_each(|v| _redact(secrets, v)),
+        serde_json::Value::Object(obj) => obj.values_mut().for_each(|v| _redact(secrets, v)),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use config::{Value, ValueKind};
+
+    use super::{parse, redact, resolve, REDACTED};
+
+    #[test]
+    fn test_resolve_and_redact() {
+        assert_eq!(parse("${env:HOME}"), Some(("env", "HOME")));
+        assert_eq!(parse("${vault:secret/data/mqtt#password}"), Some(("vault", "secret/data/mqtt#password")));
+        assert_eq!(parse("plain"), None);
+
+        std::env::set_var("RMQTT_TEST_SECRET", "s3cr3t-password");
+        let mut v = Value::new(None, ValueKind::String("${env:RMQTT_TEST_SECRET}".into()));
+        resolve(&mut v).unwrap();
+        assert_eq!(v.into_string().unwrap(), "s3cr3t-password");
+
+        let mut cfg =
+            serde_json::json!({"password": "s3cr3t-password", "url": "amqp://u:s3cr3t-password@h", "n": 1});
+        redact(&mut cfg);
+        assert_eq!(cfg, serde_json::json!({"password": REDACTED, "url": REDACTED, "n": 1}));
+
+        let mut v = Value::new(None, ValueKind::String("${nope:x}".into()));
+        assert!(resolve(&mut v).is_err());
+    }
+}